@@ -0,0 +1,83 @@
+// What recv_udp does with the client list for each packet from a known
+// peer, from 1 and 4 threads at once standing in for receive tasks: the
+// original single RwLock, write locked on every packet, against the sharded
+// Clients, which only takes its shard's read lock when the peer's address
+// is already known. Each thread receives from its own 64 peers.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mptun::clients::Clients;
+
+const PEERS_PER_THREAD: usize = 64;
+
+fn peer(thread: usize, index: usize) -> (IpAddr, SocketAddr) {
+    let tun_ip = IpAddr::V4(Ipv4Addr::new(10, 0, thread as u8, index as u8));
+    (tun_ip, SocketAddr::new([192, 0, 2, thread as u8].into(), 4000 + index as u16))
+}
+
+trait ClientList: Sync {
+    fn received(&self, tun_ip: IpAddr, addr: SocketAddr);
+}
+
+struct SingleLock(RwLock<HashMap<IpAddr, Vec<SocketAddr>>>);
+
+impl ClientList for SingleLock {
+    fn received(&self, tun_ip: IpAddr, addr: SocketAddr) {
+        let mut clients = self.0.write().unwrap();
+        let client = clients.entry(tun_ip).or_default();
+        if !client.contains(&addr) {
+            client.push(addr);
+        }
+    }
+}
+
+impl ClientList for Clients {
+    fn received(&self, tun_ip: IpAddr, addr: SocketAddr) {
+        if self.with(&tun_ip, |client| client.contains(&addr)) != Some(true) {
+            self.upsert(tun_ip, None, |client, _| if !client.contains(&addr) {
+                client.push(addr);
+            });
+        }
+    }
+}
+
+fn run(list: &impl ClientList, threads: usize, packets: u64) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            scope.spawn(move || {
+                for packet in 0..packets as usize {
+                    let (tun_ip, addr) = peer(thread, packet % PEERS_PER_THREAD);
+                    list.received(black_box(tun_ip), black_box(addr));
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn client_list(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client_list_received");
+    for threads in [1, 4] {
+        group.throughput(Throughput::Elements(threads as u64));
+        let single = SingleLock(RwLock::new(HashMap::new()));
+        let sharded = Clients::default();
+        // Every peer known already, as for nearly all packets
+        run(&single, threads, PEERS_PER_THREAD as u64);
+        run(&sharded, threads, PEERS_PER_THREAD as u64);
+
+        group.bench_with_input(BenchmarkId::new("single_lock", threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| run(&single, threads, iters))
+        });
+        group.bench_with_input(BenchmarkId::new("sharded", threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| run(&sharded, threads, iters))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, client_list);
+criterion_main!(benches);
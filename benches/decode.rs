@@ -0,0 +1,30 @@
+// Datagrams decoded per second: decode_packet, which copies the payload into
+// an owned Packet as recv_udp used to, against decode_packet_ref, which
+// borrows it from the datagram. For a 1300 byte data packet in each wire
+// format.
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mptun::messages::{self, Messages, Packet, WireFormat};
+
+const PAYLOAD_LEN: usize = 1300;
+
+fn decode(c: &mut Criterion) {
+    let packet = Messages::Packet(Packet { seq: 1, bytes: Bytes::from(vec![0x5a; PAYLOAD_LEN]) });
+    let mut group = c.benchmark_group("decode_datagram");
+    group.throughput(Throughput::Bytes(PAYLOAD_LEN as u64));
+    for format in [WireFormat::Bincode, WireFormat::Compact] {
+        let datagram = messages::encode_packet(&packet, format).unwrap();
+        let name = format!("{:?}", format);
+        group.bench_with_input(BenchmarkId::new("owned", &name), &datagram, |b, datagram| {
+            b.iter(|| messages::decode_packet(black_box(datagram), format, u64::MAX).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("borrowed", &name), &datagram, |b, datagram| {
+            b.iter(|| messages::decode_packet_ref(black_box(datagram), format, u64::MAX).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
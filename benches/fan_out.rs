@@ -0,0 +1,135 @@
+// CPU time per packet of sending TUN packets to one peer in failover mode
+// over 1, 4 and 16 links: that of a tunnel on a multi-threaded runtime and
+// of a peer counting the datagrams it receives.
+// Only the active link sends, so with the other links' send tasks left
+// idle the cost should barely grow with the number of links. The tunnel
+// keeps at most WINDOW packets in flight, so none are lost to a full
+// socket buffer.
+
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use etherparse::PacketBuilder;
+use tokio::sync::mpsc;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{PathMode, SendDevice, SettingsFileBuilder};
+
+const PAYLOAD_LEN: usize = 1200;
+const WINDOW: usize = 128;
+
+fn cpu_time(clock: libc::clockid_t) -> Duration {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Cannot fail for these clocks
+    unsafe { libc::clock_gettime(clock, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+// CPU time used so far by every thread of the process but the calling one,
+// which only waits on the sink. The thread's is read first, so the
+// process's includes all of it.
+fn tunnel_cpu_time() -> Duration {
+    let own = cpu_time(libc::CLOCK_THREAD_CPUTIME_ID);
+    cpu_time(libc::CLOCK_PROCESS_CPUTIME_ID) - own
+}
+
+// A peer counting the datagrams it receives, until dropped
+struct Sink {
+    port: u16,
+    received: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>
+}
+
+impl Sink {
+    fn start() -> Sink {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let (received, stop) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(false)));
+        let (counted, stopped) = (received.clone(), stop.clone());
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            while !stopped.load(Ordering::Relaxed) {
+                if socket.recv(&mut buf).is_ok() {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        Sink { port, received, stop }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// A tunnel sending over `links` loopback addresses, with the sink as its peer
+fn start_tunnel(runtime: &tokio::runtime::Runtime, links: u8, sink: &Sink) -> (Arc<Multipathtunnel>, mpsc::UnboundedSender<Bytes>) {
+    let peer: IpAddr = [10, 0, 0, 2].into();
+    let mut settings = SettingsFileBuilder::new([10, 0, 0, 1].into())
+        .remote([127, 0, 0, 1].into(), sink.port, peer)
+        .path_mode(PathMode::Failover);
+    for link in 1..=links {
+        settings = settings.add_send_device(SendDevice::new([127, 0, 0, link].into(), 0));
+    }
+    let tunnel = Arc::new(runtime.block_on(async { Multipathtunnel::new(settings.build().unwrap()) }).unwrap());
+    let (to_tunnel_tx, mut to_tunnel_rx) = mpsc::unbounded_channel();
+    let running = tunnel.clone();
+    runtime.spawn(async move {
+        let (channels, run) = running.run_with_channels();
+        let forward = async {
+            while let Some(packet) = to_tunnel_rx.recv().await {
+                if channels.to_tunnel.send(packet).is_err() {
+                    return
+                }
+            }
+        };
+        tokio::select! {
+            _ = run => {},
+            _ = forward => {}
+        }
+    });
+    (tunnel, to_tunnel_tx)
+}
+
+fn fan_out(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+        .udp(4000, 5000)
+        .write(&mut packet, &[0x5a; PAYLOAD_LEN])
+        .unwrap();
+    let packet = Bytes::from(packet);
+
+    let mut group = c.benchmark_group("fan_out");
+    group.throughput(Throughput::Elements(1));
+    for links in [1, 4, 16] {
+        let sink = Sink::start();
+        let (tunnel, to_tunnel) = start_tunnel(&runtime, links, &sink);
+        group.bench_function(links.to_string(), |b| b.iter_custom(|iters| {
+            let (packets, base) = (iters as usize, sink.received.load(Ordering::Relaxed));
+            let (start, cpu_start) = (Instant::now(), tunnel_cpu_time());
+            for sent in 0..packets {
+                while sent - (sink.received.load(Ordering::Relaxed) - base) >= WINDOW {
+                    assert!(start.elapsed() < Duration::from_secs(30), "packets were lost");
+                    std::thread::yield_now();
+                }
+                to_tunnel.send(packet.clone()).unwrap();
+            }
+            while sink.received.load(Ordering::Relaxed) - base < packets {
+                assert!(start.elapsed() < Duration::from_secs(30), "packets were lost");
+                std::thread::yield_now();
+            }
+            tunnel_cpu_time() - cpu_start
+        }));
+        tunnel.shutdown();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
@@ -0,0 +1,133 @@
+// Cost per packet of getting a TUN packet ready for the wire: the original
+// path, which allocated for the packet, the compressed payload and the
+// encoding, against the current one, read_tun's PacketReader splitting
+// packets off a shared read chunk and send_udp's DatagramEncoder encoding
+// into buffers reused across packets. The allocations per packet are
+// counted once before timing.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use tokio::io::{AsyncRead, ReadBuf};
+use mptun::datagram::{compress_prepend_size_into, DatagramEncoder, Framing};
+use mptun::messages::{self, Messages, Packet, WireFormat};
+use mptun::tasks::PacketReader;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const PACKET_LEN: usize = 1400;
+
+const FRAMING: Framing<'static> = Framing { wire_format: WireFormat::Bincode, session_epoch: None, cipher: None, checksum: false, max_datagram_size: None };
+
+fn tun_packet() -> Vec<u8> {
+    (0..PACKET_LEN).map(|index| (index % 251) as u8).collect()
+}
+
+// A TUN that has the same packet ready for every read
+struct RepeatingTun(Vec<u8>);
+
+impl AsyncRead for RepeatingTun {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        buf.put_slice(&self.0);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// read_tun copying the packet out of its stack buffer, and send_udp
+// compressing and serializing into fresh buffers
+fn allocating(read: &[u8], seq: usize) -> Vec<u8> {
+    let bytes = read.to_vec();
+    let compressed = lz4_flex::compress_prepend_size(&bytes);
+    messages::encode_packet(&Messages::Packet(Packet { seq, bytes: Bytes::from(compressed) }), WireFormat::Bincode).unwrap()
+}
+
+// The buffers read_tun and send_udp keep across packets
+#[derive(Default)]
+struct Reusing {
+    reader: PacketReader,
+    compressed: Vec<u8>,
+    encoder: DatagramEncoder
+}
+
+impl Reusing {
+    async fn packet(&mut self, tun: &mut RepeatingTun, seq: usize) -> usize {
+        let bytes = self.reader.read(tun).await.unwrap().unwrap();
+        compress_prepend_size_into(&bytes, &mut self.compressed);
+        self.encoder.encode(seq, None, None, &self.compressed, &FRAMING).unwrap();
+        self.encoder.wire_len()
+    }
+}
+
+const COUNTED_PACKETS: usize = 10_000;
+
+// Let reused buffers grow for `warm_up` packets, then count over the others
+fn allocations_per_packet(warm_up: usize, run: impl FnOnce(&mut dyn FnMut())) -> f64 {
+    let mut seen = 0;
+    let mut before = 0;
+    run(&mut || {
+        seen += 1;
+        if seen == warm_up {
+            before = ALLOCATIONS.load(Ordering::Relaxed);
+        }
+    });
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / COUNTED_PACKETS as f64
+}
+
+fn hot_path(c: &mut Criterion) {
+    const WARM_UP: usize = 100;
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let read = tun_packet();
+    let mut tun = RepeatingTun(read.clone());
+
+    let allocating_count = allocations_per_packet(WARM_UP, |tick| {
+        for seq in 0..WARM_UP + COUNTED_PACKETS {
+            tick();
+            drop(black_box(allocating(&read, seq)));
+        }
+    });
+    let reusing_count = allocations_per_packet(WARM_UP, |tick| runtime.block_on(async {
+        let mut reusing = Reusing::default();
+        for seq in 0..WARM_UP + COUNTED_PACKETS {
+            tick();
+            black_box(reusing.packet(&mut tun, seq).await);
+        }
+    }));
+    println!("Allocations per packet: {:.3} allocating, {:.3} reusing", allocating_count, reusing_count);
+    assert!(reusing_count < allocating_count);
+
+    let mut group = c.benchmark_group("tun_to_wire");
+    group.throughput(Throughput::Elements(1));
+    let mut seq = 0;
+    group.bench_function("allocating", |b| b.iter(|| {
+        seq += 1;
+        black_box(allocating(&read, seq))
+    }));
+    let mut reusing = Reusing::default();
+    group.bench_function("reusing", |b| b.iter(|| {
+        seq += 1;
+        black_box(runtime.block_on(reusing.packet(&mut tun, seq)))
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, hot_path);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+// Datagrams received per second: one recv_from per datagram, as recv_udp
+// does without recv_batch, against RecvBatch taking 8 and 64 per recvmmsg
+// call. Each round queues 64 datagrams on a loopback socket first, and only
+// receiving them is timed.
+
+use std::net::UdpSocket as StdSocket;
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mptun::batch::RecvBatch;
+use socket2::{Domain, Socket, Type};
+use tokio::net::UdpSocket;
+
+const DATAGRAM_LEN: usize = 1200;
+const ROUND: usize = 64;
+const BUFFER_LEN: usize = 65535;
+
+fn receiver() -> UdpSocket {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+    socket.set_recv_buffer_size(4 << 20).unwrap();
+    socket.bind(&"127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap().into()).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    UdpSocket::from_std(socket.into()).unwrap()
+}
+
+// Time `receive` takes over `rounds` rounds of ROUND queued datagrams
+fn timed(rounds: u64, sender: &StdSocket, socket: &UdpSocket, mut receive: impl FnMut()) -> Duration {
+    let datagram = vec![0x5a; DATAGRAM_LEN];
+    let target = socket.local_addr().unwrap();
+    let mut total = Duration::ZERO;
+    for _ in 0..rounds {
+        for _ in 0..ROUND {
+            sender.send_to(&datagram, target).unwrap();
+        }
+        let start = Instant::now();
+        receive();
+        total += start.elapsed();
+    }
+    total
+}
+
+fn recv_batch(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let socket = runtime.block_on(async { receiver() });
+    let sender = StdSocket::bind("127.0.0.1:0").unwrap();
+    let mut buf = vec![0; BUFFER_LEN];
+
+    let mut group = c.benchmark_group("recv_datagrams");
+    group.throughput(Throughput::Elements(ROUND as u64));
+    group.bench_function("recv_from", |b| b.iter_custom(|rounds| timed(rounds, &sender, &socket, || runtime.block_on(async {
+        for _ in 0..ROUND {
+            assert_eq!(socket.recv_from(&mut buf).await.unwrap().0, DATAGRAM_LEN);
+        }
+    }))));
+    for size in [8, 64] {
+        let mut batch = RecvBatch::new(size, BUFFER_LEN);
+        group.bench_with_input(BenchmarkId::new("recvmmsg", size), &size, |b, _| b.iter_custom(|rounds| timed(rounds, &sender, &socket, || runtime.block_on(async {
+            for _ in 0..ROUND {
+                assert_eq!(batch.recv_into(&socket, &mut buf).await.unwrap().0, DATAGRAM_LEN);
+            }
+        }))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, recv_batch);
+criterion_main!(benches);
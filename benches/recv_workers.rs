@@ -0,0 +1,118 @@
+// Receive throughput of one socket with 1, 2 and 4 recv_udp workers: the
+// time for a tunnel on a multi-threaded runtime to decode and deliver data
+// packets that four peers send it over loopback. The peers keep at most
+// WINDOW packets in flight, so none are lost to a full socket buffer.
+// Workers only add throughput with cores to spare for them.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use etherparse::PacketBuilder;
+use mptun::messages::{self, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+const PEERS: usize = 4;
+const PAYLOAD_LEN: usize = 1200;
+const WINDOW: usize = 512;
+
+// A tunnel counting the packets it delivers
+struct Receiver {
+    addr: SocketAddr,
+    delivered: Arc<AtomicUsize>,
+    tunnel: Arc<Multipathtunnel>
+}
+
+impl Receiver {
+    fn start(runtime: &tokio::runtime::Runtime, workers: usize) -> Receiver {
+        let mut device = SendDevice::new([127, 0, 0, 1].into(), 0);
+        device.recv_workers = Some(workers);
+        device.so_rcvbuf = Some(4 << 20);
+        let settings = SettingsFileBuilder::new([10, 0, 0, 2].into()).add_send_device(device).build().unwrap();
+        let tunnel = Arc::new(runtime.block_on(async { Multipathtunnel::new(settings) }).unwrap());
+        let addr = tunnel.handle().paths()[0].local_addr;
+
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let (running, counted) = (tunnel.clone(), delivered.clone());
+        runtime.spawn(async move {
+            let (mut channels, run) = running.run_with_channels();
+            let count = async {
+                while channels.from_tunnel.recv().await.is_some() {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                }
+            };
+            tokio::select! {
+                _ = run => {},
+                _ = count => {}
+            }
+        });
+        Receiver { addr, delivered, tunnel }
+    }
+}
+
+// A peer sending data packets from its own tunnel address, numbered on
+// across iterations so none look like duplicates
+struct Peer {
+    socket: UdpSocket,
+    packet: Vec<u8>,
+    seq: usize
+}
+
+impl Peer {
+    fn new(index: usize) -> Peer {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 1, index as u8 + 1], [10, 0, 0, 2], 64)
+            .udp(4000, 5000)
+            .write(&mut packet, &[0x5a; PAYLOAD_LEN])
+            .unwrap();
+        Peer { socket: UdpSocket::bind("127.0.0.1:0").unwrap(), packet: lz4_flex::compress_prepend_size(&packet), seq: 0 }
+    }
+
+    fn send(&mut self, count: usize, to: SocketAddr, sent: &AtomicUsize, delivered: &AtomicUsize, base: usize) {
+        let mut datagram = Vec::new();
+        for _ in 0..count {
+            while sent.load(Ordering::Relaxed) - (delivered.load(Ordering::Relaxed) - base) >= WINDOW {
+                std::thread::yield_now();
+            }
+            self.seq += 1;
+            messages::encode_data_into(self.seq, None, None, None, &self.packet, WireFormat::Bincode, &mut datagram).unwrap();
+            self.socket.send_to(&datagram, to).unwrap();
+            sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn recv_workers(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    let mut group = c.benchmark_group("recv_workers");
+    group.throughput(Throughput::Elements(1));
+
+    for workers in [1, 2, 4] {
+        let receiver = Receiver::start(&runtime, workers);
+        let mut peers: Vec<Peer> = (0..PEERS).map(Peer::new).collect();
+        group.bench_function(workers.to_string(), |b| b.iter_custom(|iters| {
+            let (packets, base) = (iters as usize, receiver.delivered.load(Ordering::Relaxed));
+            let sent = AtomicUsize::new(0);
+            let start = Instant::now();
+            std::thread::scope(|scope| {
+                for (index, peer) in peers.iter_mut().enumerate() {
+                    let share = packets / PEERS + usize::from(index < packets % PEERS);
+                    let (sent, delivered, addr) = (&sent, &receiver.delivered, receiver.addr);
+                    scope.spawn(move || peer.send(share, addr, sent, delivered, base));
+                }
+            });
+            while receiver.delivered.load(Ordering::Relaxed) - base < packets {
+                assert!(start.elapsed() < Duration::from_secs(30), "packets were lost");
+                std::thread::yield_now();
+            }
+            start.elapsed()
+        }));
+        receiver.tunnel.shutdown();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, recv_workers);
+criterion_main!(benches);
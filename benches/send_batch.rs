@@ -0,0 +1,43 @@
+// Datagrams sent per second over loopback: one send_to per datagram, as
+// send_udp does without send_batch, against SendBatch flushing 8 and 32
+// datagrams per sendmmsg call. The receiving socket isn't read, the kernel
+// drops what doesn't fit its buffer.
+
+use std::net::IpAddr;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mptun::batch::{SendBatch, SendRecord};
+use tokio::net::UdpSocket;
+
+const DATAGRAM_LEN: usize = 1200;
+
+fn send_batch(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let socket = runtime.block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+    // Open until the end, so nothing sent to it is refused
+    let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = sink.local_addr().unwrap();
+    let datagram = vec![0x5a; DATAGRAM_LEN];
+    let record = |seq| SendRecord { seq, size: DATAGRAM_LEN, tun_ip: IpAddr::from([10, 0, 0, 2]), peer: None, target };
+
+    let mut group = c.benchmark_group("send_datagrams");
+    for size in [8, 32] {
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("send_to", size), &size, |b, &size| b.iter(|| runtime.block_on(async {
+            for _ in 0..size {
+                socket.send_to(&datagram, target).await.unwrap();
+            }
+        })));
+        let mut batch = SendBatch::new(size);
+        group.bench_with_input(BenchmarkId::new("sendmmsg", size), &size, |b, &size| b.iter(|| runtime.block_on(async {
+            for seq in 0..size {
+                batch.push(record(seq), &[&datagram]);
+            }
+            batch.flush(&socket, |_, result: std::io::Result<usize>| assert_eq!(result.unwrap(), DATAGRAM_LEN)).await;
+        })));
+    }
+    group.finish();
+    drop(sink);
+}
+
+criterion_group!(benches, send_batch);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+// TUN read throughput with 1, 2 and 4 queues: the time for a tunnel on a
+// multi-threaded runtime to read packets spread over its queues, one
+// read_tun task each, and send them to a peer counting the datagrams it
+// receives. The packets are fed at most WINDOW in flight, so none are lost
+// to a full socket buffer. More queues only add throughput with cores to
+// spare for their readers.
+
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use etherparse::PacketBuilder;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+use mptun::tun::{memory_tun, MemoryTunPeer};
+
+const PAYLOAD_LEN: usize = 1200;
+const WINDOW: usize = 128;
+
+// A peer counting the datagrams it receives, until dropped
+struct Sink {
+    port: u16,
+    received: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>
+}
+
+impl Sink {
+    fn start() -> Sink {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let (received, stop) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(false)));
+        let (counted, stopped) = (received.clone(), stop.clone());
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            while !stopped.load(Ordering::Relaxed) {
+                if socket.recv(&mut buf).is_ok() {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        Sink { port, received, stop }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// A tunnel reading `count` TUN queues, with the sink as its peer
+fn start_tunnel(runtime: &tokio::runtime::Runtime, count: usize, sink: &Sink) -> (Arc<Multipathtunnel>, Vec<MemoryTunPeer>) {
+    let peer: IpAddr = [10, 0, 0, 2].into();
+    let settings = SettingsFileBuilder::new([10, 0, 0, 1].into())
+        .add_send_device(SendDevice::new([127, 0, 0, 1].into(), 0))
+        .remote([127, 0, 0, 1].into(), sink.port, peer)
+        .tun_queues(count)
+        .build()
+        .unwrap();
+    let tunnel = Arc::new(runtime.block_on(async { Multipathtunnel::new(settings) }).unwrap());
+    let (tuns, queues): (Vec<_>, Vec<_>) = (0..count).map(|_| memory_tun()).unzip();
+    let running = tunnel.clone();
+    runtime.spawn(async move { running.run_with_tun_queues(tuns).await });
+    (tunnel, queues)
+}
+
+fn tun_queues(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    // Flows told apart by source port, as a multi-queue TUN spreads them
+    let packets: Vec<Bytes> = (0..4).map(|flow| {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .udp(4000 + flow, 5000)
+            .write(&mut packet, &[0x5a; PAYLOAD_LEN])
+            .unwrap();
+        Bytes::from(packet)
+    }).collect();
+
+    let mut group = c.benchmark_group("tun_queues");
+    group.throughput(Throughput::Elements(1));
+    for count in [1, 2, 4] {
+        let sink = Sink::start();
+        let (tunnel, queues) = start_tunnel(&runtime, count, &sink);
+        group.bench_function(count.to_string(), |b| b.iter_custom(|iters| {
+            let (total, base) = (iters as usize, sink.received.load(Ordering::Relaxed));
+            let start = Instant::now();
+            for sent in 0..total {
+                while sent - (sink.received.load(Ordering::Relaxed) - base) >= WINDOW {
+                    assert!(start.elapsed() < Duration::from_secs(30), "packets were lost");
+                    std::thread::yield_now();
+                }
+                let flow = sent % packets.len();
+                queues[flow % count].to_tunnel.send(packets[flow].clone()).unwrap();
+            }
+            while sink.received.load(Ordering::Relaxed) - base < total {
+                assert!(start.elapsed() < Duration::from_secs(30), "packets were lost");
+                std::thread::yield_now();
+            }
+            start.elapsed()
+        }));
+        tunnel.shutdown();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tun_queues);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+// Two tunnels in one process, each fed by the application instead of a TUN
+// device. A packet injected into one comes out of the other.
+
+use std::time::Duration;
+use bytes::Bytes;
+use etherparse::PacketBuilder;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+fn tunnel(tun_ip: [u8; 4], port: u16, peer_tun_ip: [u8; 4], peer_port: u16) -> Multipathtunnel {
+    let settings = SettingsFileBuilder::new(tun_ip.into())
+        .add_send_device(SendDevice::new([127, 0, 0, 1].into(), port))
+        .remote([127, 0, 0, 1].into(), peer_port, peer_tun_ip.into())
+        .build()
+        .unwrap();
+    Multipathtunnel::new(settings).unwrap()
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let left = tunnel([10, 0, 0, 1], 47001, [10, 0, 0, 2], 47002);
+    let right = tunnel([10, 0, 0, 2], 47002, [10, 0, 0, 1], 47001);
+    let (left_channels, left_run) = left.run_with_channels();
+    let (mut right_channels, right_run) = right.run_with_channels();
+
+    let exchange = async {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .udp(4000, 5000)
+            .write(&mut packet, b"hello")
+            .unwrap();
+        left_channels.to_tunnel.send(Bytes::from(packet)).unwrap();
+
+        match tokio::time::timeout(Duration::from_secs(1), right_channels.from_tunnel.recv()).await {
+            Ok(Some(packet)) => println!("Received {} bytes: {:?}", packet.len(), &packet[packet.len() - 5..]),
+            _ => println!("Nothing received")
+        }
+    };
+
+    tokio::select! {
+        _ = exchange => {},
+        result = left_run => println!("Left tunnel stopped: {:?}", result),
+        result = right_run => println!("Right tunnel stopped: {:?}", result)
+    }
+}
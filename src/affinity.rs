@@ -0,0 +1,71 @@
+// Runtimes whose threads only run on some CPUs, e.g. the cores of the NUMA
+// node a send device's NIC is attached to, so its tasks stay near the
+// device's memory and interrupts. See `Multipathtunnel::with_device_runtime`.
+
+use std::io;
+use tokio::runtime::{Builder, Runtime};
+
+/// Restrict the calling thread to `cpus`, by CPU number.
+pub fn pin_current_thread(cpus: &[usize]) -> io::Result<()> {
+    // Zeroed is the empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("CPU {} is beyond the {} a CPU set holds", cpu, libc::CPU_SETSIZE)))
+        }
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+/// A multi-threaded runtime named after `name`, with a worker for each of
+/// `cpus` and every thread pinned to all of them. A thread that can't be
+/// pinned runs unpinned, with a warning.
+pub fn pinned_runtime(name: &str, cpus: &[usize]) -> io::Result<Runtime> {
+    let pinned = cpus.to_vec();
+    let thread_name = format!("mptun-{}", name);
+    Builder::new_multi_thread()
+        .worker_threads(cpus.len().max(1))
+        .thread_name(thread_name.clone())
+        .on_thread_start(move || {
+            if let Err(err) = pin_current_thread(&pinned) {
+                eprintln!("Failed to pin a thread of {} to CPUs {:?}: {}", thread_name, pinned, err);
+            }
+        })
+        .enable_all()
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The CPUs the calling thread may run on
+    fn allowed_cpus() -> Vec<usize> {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) }, 0);
+        (0..libc::CPU_SETSIZE as usize).filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) }).collect()
+    }
+
+    #[test]
+    fn a_pinned_thread_runs_on_its_cpus_only() {
+        let allowed = std::thread::spawn(|| {
+            pin_current_thread(&[0]).unwrap();
+            allowed_cpus()
+        }).join().unwrap();
+        assert_eq!(allowed, [0]);
+
+        assert_eq!(pin_current_thread(&[libc::CPU_SETSIZE as usize]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn runtime_threads_are_named_and_pinned() {
+        let runtime = pinned_runtime("test", &[0]).unwrap();
+        let (name, allowed) = runtime.block_on(async {
+            tokio::spawn(async { (std::thread::current().name().map(str::to_string), allowed_cpus()) }).await.unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("mptun-test"));
+        assert_eq!(allowed, [0]);
+    }
+}
@@ -0,0 +1,213 @@
+// Batched sending and receiving with sendmmsg and recvmmsg, so a busy task
+// makes one syscall per batch of datagrams instead of one per datagram.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use socket2::SockAddr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+// Most messages the kernel takes in one sendmmsg call
+const UIO_MAXIOV: usize = 1024;
+
+/// One packet sent to one target, as reported once its datagrams went out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendRecord {
+    pub seq: usize,
+    // Size of the inner packet
+    pub size: usize,
+    pub tun_ip: IpAddr,
+    // Peer the target belongs to, the hub when relaying through one. `None`
+    // for packets sent to every peer.
+    pub peer: Option<IpAddr>,
+    pub target: SocketAddr
+}
+
+/// Datagrams queued for one sendmmsg call. Buffers are kept between
+/// flushes, so a batch stops allocating once it has been filled.
+#[derive(Debug)]
+pub struct SendBatch {
+    max_packets: usize,
+    buffers: Vec<Vec<u8>>,
+    addrs: Vec<SockAddr>,
+    // Index of the record each queued datagram belongs to
+    owners: Vec<usize>,
+    records: Vec<(SendRecord, Range<usize>)>
+}
+
+impl SendBatch {
+    pub fn new(max_packets: usize) -> SendBatch {
+        SendBatch {
+            max_packets,
+            buffers: Vec::new(),
+            addrs: Vec::new(),
+            owners: Vec::new(),
+            records: Vec::new()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Whether `max_packets` packets are queued and the batch should be flushed.
+    pub fn is_full(&self) -> bool {
+        self.records.len() >= self.max_packets
+    }
+
+    /// Queue copies of `datagrams`, all of one packet, for `record.target`.
+    pub fn push(&mut self, record: SendRecord, datagrams: &[&[u8]]) {
+        let first = self.owners.len();
+        for datagram in datagrams {
+            match self.buffers.get_mut(self.owners.len()) {
+                Some(buffer) => {
+                    buffer.clear();
+                    buffer.extend_from_slice(datagram);
+                },
+                None => self.buffers.push(datagram.to_vec())
+            }
+            self.addrs.push(SockAddr::from(record.target));
+            self.owners.push(self.records.len());
+        }
+        self.records.push((record, first..self.owners.len()));
+    }
+
+    /// Send everything queued and call `sent` with each packet's result: the
+    /// bytes sent, or the error that stopped its datagrams. A datagram that
+    /// fails only fails its own packet, the rest of the batch is still sent.
+    pub async fn flush(&mut self, socket: &UdpSocket, mut sent: impl FnMut(&SendRecord, io::Result<usize>)) {
+        let mut results: Vec<io::Result<usize>> = self.records.iter().map(|_| Ok(0)).collect();
+        let mut next = 0;
+        while next < self.owners.len() {
+            if let Err(err) = socket.writable().await {
+                for owner in &self.owners[next..] {
+                    results[*owner] = Err(io::Error::new(err.kind(), err.to_string()));
+                }
+                break
+            }
+
+            let range = next..self.owners.len();
+            match socket.try_io(Interest::WRITABLE, || sendmmsg(socket, &self.buffers[range.clone()], &self.addrs[range.clone()])) {
+                Ok(count) => {
+                    for index in next..next + count {
+                        if let Ok(bytes) = &mut results[self.owners[index]] {
+                            *bytes += self.buffers[index].len();
+                        }
+                    }
+                    next += count;
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // The error is for the first datagram not sent. Skip the rest of its packet.
+                    let owner = self.owners[next];
+                    next = self.records[owner].1.end;
+                    results[owner] = Err(err);
+                }
+            }
+        }
+
+        for ((record, _), result) in self.records.iter().zip(results) {
+            sent(record, result);
+        }
+        self.addrs.clear();
+        self.owners.clear();
+        self.records.clear();
+    }
+}
+
+fn sendmmsg(socket: &impl AsRawFd, datagrams: &[Vec<u8>], addrs: &[SockAddr]) -> io::Result<usize> {
+    let mut iovecs: Vec<libc::iovec> = datagrams.iter()
+        .map(|datagram| libc::iovec { iov_base: datagram.as_ptr() as *mut libc::c_void, iov_len: datagram.len() })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(addrs)
+        .map(|(iovec, addr)| {
+            // Zeroed for the fields not set, like the control message and flags
+            let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            header.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = addr.len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+
+    let count = headers.len().min(UIO_MAXIOV);
+    let ret = unsafe { libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, 0) };
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret as usize) }
+}
+
+/// Datagrams received by one recvmmsg call, handed out one at a time.
+#[derive(Debug)]
+pub struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    names: Vec<libc::sockaddr_storage>,
+    // Length and source of the datagrams in the first buffers
+    received: Vec<(usize, SocketAddr)>,
+    next: usize
+}
+
+impl RecvBatch {
+    /// A batch of up to `size` datagrams of at most `buffer_len` bytes each.
+    pub fn new(size: usize, buffer_len: usize) -> RecvBatch {
+        RecvBatch {
+            buffers: vec![vec![0; buffer_len]; size.clamp(1, UIO_MAXIOV)],
+            names: vec![unsafe { std::mem::zeroed() }; size.clamp(1, UIO_MAXIOV)],
+            received: Vec::new(),
+            next: 0
+        }
+    }
+
+    /// The next datagram received, swapped into `buf`, which must be
+    /// `buffer_len` bytes long. A new batch is received once the last one
+    /// is used up. Cancel safe like `UdpSocket::recv_from`.
+    pub async fn recv_into(&mut self, socket: &UdpSocket, buf: &mut Vec<u8>) -> io::Result<(usize, SocketAddr)> {
+        while self.next >= self.received.len() {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || recvmmsg(socket, &mut self.buffers, &mut self.names)) {
+                Ok(datagrams) => {
+                    self.received.clear();
+                    for (index, (len, name_len)) in datagrams.into_iter().enumerate() {
+                        // A UDP socket only receives from IP addresses
+                        let source = unsafe { SockAddr::new(self.names[index], name_len) }.as_socket();
+                        self.received.push((len, source.unwrap_or_else(|| ([0, 0, 0, 0], 0).into())));
+                    }
+                    self.next = 0;
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            }
+        }
+
+        let (len, source) = self.received[self.next];
+        std::mem::swap(buf, &mut self.buffers[self.next]);
+        self.next += 1;
+        Ok((len, source))
+    }
+}
+
+// Length and source address length of each datagram received
+fn recvmmsg(socket: &impl AsRawFd, buffers: &mut [Vec<u8>], names: &mut [libc::sockaddr_storage]) -> io::Result<Vec<(usize, libc::socklen_t)>> {
+    let mut iovecs: Vec<libc::iovec> = buffers.iter_mut()
+        .map(|buffer| libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(names.iter_mut())
+        .map(|(iovec, name)| {
+            let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            header.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+
+    let ret = unsafe {
+        libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), headers.len() as libc::c_uint, 0, std::ptr::null_mut())
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(headers[..ret as usize].iter().map(|header| (header.msg_len as usize, header.msg_hdr.msg_namelen)).collect())
+}
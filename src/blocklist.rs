@@ -0,0 +1,85 @@
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use crate::cidr::Cidr;
+
+/// Source addresses whose datagrams are dropped on arrival, before they are
+/// decrypted or can add a peer. Starts from the blocked_sources setting and
+/// can be changed while the tunnel runs.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    entries: RwLock<Vec<Cidr>>
+}
+
+impl Blocklist {
+    pub fn new(entries: impl IntoIterator<Item = Cidr>) -> Blocklist {
+        let blocklist = Blocklist::default();
+        blocklist.set(entries);
+        blocklist
+    }
+
+    /// Replace every entry.
+    pub fn set(&self, entries: impl IntoIterator<Item = Cidr>) {
+        let mut list: Vec<Cidr> = Vec::new();
+        for entry in entries {
+            if !list.contains(&entry) {
+                list.push(entry);
+            }
+        }
+        *self.entries.write().unwrap() = list;
+    }
+
+    /// Returns false if `entry` was already blocked.
+    pub fn block(&self, entry: Cidr) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        if entries.contains(&entry) {
+            return false
+        }
+        entries.push(entry);
+        true
+    }
+
+    /// Remove `entry`, as it was blocked. Addresses it covers stay blocked
+    /// if another entry covers them too. Returns false if it wasn't blocked.
+    pub fn unblock(&self, entry: &Cidr) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        let len = entries.len();
+        entries.retain(|blocked| blocked != entry);
+        entries.len() != len
+    }
+
+    /// Whether `ip` is blocked. IPv4 senders seen on a dual-stack socket as
+    /// IPv4-mapped IPv6 addresses match IPv4 entries.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let canonical = ip.to_canonical();
+        self.entries.read().unwrap().iter().any(|entry| entry.contains(ip) || entry.contains(&canonical))
+    }
+
+    pub fn entries(&self) -> Vec<Cidr> {
+        self.entries.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_mapped_senders_match_ipv4_entries() {
+        let blocklist = Blocklist::new(["203.0.113.0/24".parse().unwrap()]);
+        assert!(blocklist.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(blocklist.contains(&"::ffff:203.0.113.7".parse().unwrap()));
+        assert!(!blocklist.contains(&"::ffff:198.51.100.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn an_address_stays_blocked_while_another_entry_covers_it() {
+        let (subnet, host): (Cidr, Cidr) = ("10.0.0.0/8".parse().unwrap(), "10.1.2.3/32".parse().unwrap());
+        let blocklist = Blocklist::new([subnet, host, host]);
+        assert_eq!(blocklist.entries(), vec![subnet, host]);
+        assert!(blocklist.unblock(&host));
+        assert!(blocklist.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(blocklist.unblock(&subnet));
+        assert!(!blocklist.contains(&"10.1.2.3".parse().unwrap()));
+    }
+}
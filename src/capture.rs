@@ -0,0 +1,109 @@
+// Records of the data packets each path sends and receives, for debugging
+// multipath behaviour. Written to a file as JSON lines, one record per
+// line, with the inner packet in hex so it can be replayed.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use bytes::Bytes;
+use serde::{Serialize, Serializer};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast;
+
+// Records buffered per subscriber before the oldest are dropped
+pub const CAPTURE_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Direction {
+    Sent,
+    Received
+}
+
+/// One data packet sent to or received from one peer address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaptureRecord {
+    // Microseconds since the Unix epoch
+    pub timestamp_us: u64,
+    // Name of the send device
+    pub path: String,
+    pub direction: Direction,
+    pub seq: usize,
+    // The peer's TUN address. Unspecified for packets sent to every peer.
+    pub tun_ip: IpAddr,
+    // Address the packet was sent to or received from
+    pub peer: SocketAddr,
+    // The inner IP packet, before compression and encryption
+    #[serde(serialize_with = "to_hex")]
+    pub packet: Bytes
+}
+
+fn to_hex<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    serializer.serialize_str(&hex)
+}
+
+/// Sender side of the capture. Lossy like `PacketEvents`, and records are
+/// only built while something is subscribed.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    sender: broadcast::Sender<CaptureRecord>
+}
+
+impl Capture {
+    pub fn new(capacity: usize) -> Capture {
+        let (sender, _) = broadcast::channel(capacity);
+        Capture { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CaptureRecord> {
+        self.sender.subscribe()
+    }
+
+    pub fn is_observed(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn record(&self, record: CaptureRecord) {
+        // Only fails when there are no subscribers
+        let _ = self.sender.send(record);
+    }
+}
+
+/// Append the records of `capture` to the file at `path` as JSON lines,
+/// until `still_wanted` turns false. Checked as records come in, so a
+/// capture turned off is only left once the next record arrives. The file
+/// is flushed whenever no record is waiting.
+pub async fn write_records(capture: &Capture, path: &Path, still_wanted: impl Fn() -> bool) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let mut writer = BufWriter::new(file);
+    let mut records = capture.subscribe();
+    let mut line = Vec::new();
+    loop {
+        let received = match records.try_recv() {
+            Err(broadcast::error::TryRecvError::Empty) => {
+                writer.flush().await?;
+                records.recv().await
+            },
+            Ok(record) => Ok(record),
+            Err(broadcast::error::TryRecvError::Lagged(missed)) => Err(broadcast::error::RecvError::Lagged(missed)),
+            Err(broadcast::error::TryRecvError::Closed) => Err(broadcast::error::RecvError::Closed)
+        };
+        let record = match received {
+            Ok(record) => record,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!("Capture to {} fell behind, {} records lost", path.display(), missed);
+                continue
+            },
+            Err(broadcast::error::RecvError::Closed) => return writer.flush().await
+        };
+        if !still_wanted() {
+            return writer.flush().await
+        }
+
+        line.clear();
+        serde_json::to_writer(&mut line, &record)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+}
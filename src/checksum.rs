@@ -0,0 +1,71 @@
+// A CRC-32 (IEEE 802.3, as in zlib) after each datagram, when checksum is
+// set, for links without encryption whose middleboxes flip bits the UDP
+// checksum doesn't catch, or don't set one at all. It only detects
+// accidents: anyone can compute it for a datagram they made up.
+
+// Appended, big endian, after everything else including the encryption
+pub const CHECKSUM_LEN: usize = 4;
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| (crc >> 8) ^ TABLE[((crc ^ *byte as u32) & 0xff) as usize])
+}
+
+/// Append the checksum of `datagram` to it.
+pub fn append(datagram: &mut Vec<u8>) {
+    let crc = crc32(datagram);
+    datagram.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// The length of `datagram` without its checksum, or `None` if it doesn't
+/// end in the checksum of the rest.
+pub fn verify(datagram: &[u8]) -> Option<usize> {
+    let len = datagram.len().checked_sub(CHECKSUM_LEN)?;
+    let (data, crc) = datagram.split_at(len);
+    if crc32(data).to_be_bytes() == crc { Some(len) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn appended_checksums_verify_until_a_byte_changes() {
+        let mut datagram = b"a datagram".to_vec();
+        append(&mut datagram);
+        assert_eq!(datagram.len(), 10 + CHECKSUM_LEN);
+        assert_eq!(verify(&datagram), Some(10));
+
+        for index in 0..datagram.len() {
+            let mut flipped = datagram.clone();
+            flipped[index] ^= 0x01;
+            assert_eq!(verify(&flipped), None, "byte {} flipped", index);
+        }
+        assert_eq!(verify(&datagram[..CHECKSUM_LEN - 1]), None);
+    }
+}
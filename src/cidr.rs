@@ -0,0 +1,150 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde::Deserialize;
+
+/// An IPv4 or IPv6 subnet, written as e.g. `10.1.0.0/16` or `fd00::/64`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8
+}
+
+impl Cidr {
+    /// Fails if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Cidr> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return None
+        }
+        Some(Cidr { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The address with the host part of `ip` in the subnet `to`. `None` if
+    /// `ip` isn't in this subnet, or `to` differs in family or prefix length.
+    pub fn translate(&self, ip: &IpAddr, to: &Cidr) -> Option<IpAddr> {
+        if !self.contains(ip) || to.prefix_len != self.prefix_len {
+            return None
+        }
+        match (ip, to.addr) {
+            (IpAddr::V4(ip), IpAddr::V4(net)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                Some(IpAddr::V4((u32::from(net) & mask | u32::from(*ip) & !mask).into()))
+            },
+            (IpAddr::V6(ip), IpAddr::V6(net)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                Some(IpAddr::V6((u128::from(net) & mask | u128::from(*ip) & !mask).into()))
+            },
+            _ => None
+        }
+    }
+
+    /// Whether `ip` is in the subnet. Addresses of the other family never are.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(|| format!("`{}` is missing a /prefix length", s))?;
+        let addr: IpAddr = addr.parse().map_err(|err| format!("`{}`: {}", s, err))?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|err| format!("`{}`: {}", s, err))?;
+        Cidr::new(addr, prefix_len).ok_or_else(|| format!("`{}`: prefix length too long for the address", s))
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Cidr, String> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_subnets_contain_their_addresses_only() {
+        let subnet = cidr("10.1.0.0/16");
+        assert!(subnet.contains(&ip("10.1.0.1")));
+        assert!(subnet.contains(&ip("10.1.255.255")));
+        assert!(!subnet.contains(&ip("10.2.0.1")));
+        assert!(!subnet.contains(&ip("::ffff:10.1.0.1")));
+    }
+
+    #[test]
+    fn ipv6_subnets_contain_their_addresses_only() {
+        let subnet = cidr("fd00::/64");
+        assert!(subnet.contains(&ip("fd00::1")));
+        assert!(subnet.contains(&ip("fd00::ffff:1:2:3")));
+        assert!(!subnet.contains(&ip("fd00:0:0:1::1")));
+        assert!(!subnet.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn zero_and_full_length_prefixes() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(cidr("10.0.0.1/32").contains(&ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(&ip("10.0.0.2")));
+        assert!(!cidr("fd00::1/128").contains(&ip("fd00::2")));
+    }
+
+    #[test]
+    fn translation_keeps_the_host_part() {
+        assert_eq!(cidr("10.0.0.0/24").translate(&ip("10.0.0.7"), &cidr("10.99.1.0/24")), Some(ip("10.99.1.7")));
+        assert_eq!(cidr("fd00::/64").translate(&ip("fd00::1:7"), &cidr("fd99::/64")), Some(ip("fd99::1:7")));
+        assert_eq!(cidr("10.0.0.0/24").translate(&ip("10.0.1.7"), &cidr("10.99.1.0/24")), None);
+        assert_eq!(cidr("10.0.0.0/24").translate(&ip("10.0.0.7"), &cidr("10.99.0.0/16")), None);
+        assert_eq!(cidr("10.0.0.0/24").translate(&ip("10.0.0.7"), &cidr("fd99::/24")), None);
+    }
+
+    #[test]
+    fn malformed_subnets_are_refused() {
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!(serde_json::from_str::<Cidr>(r#""10.0.0.0/x""#).is_err());
+        assert_eq!(serde_json::from_str::<Cidr>(r#""10.0.0.0/8""#).unwrap(), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("fd00::/64").to_string(), "fd00::/64");
+    }
+}
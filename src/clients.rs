@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::nat;
+
+// Receive tasks for peers in different shards don't contend on a lock
+const CLIENT_SHARDS: usize = 16;
+
+type Shard = RwLock<HashMap<IpAddr, Vec<SocketAddr>>>;
+
+/// Known peer addresses by TUN address, sharded by the low bits of the TUN
+/// address, which is where addresses in one subnet differ. Each shard has
+/// its own lock, and an operation on one peer only takes its shard's.
+/// Operations over all peers lock one shard at a time, so they don't see a
+/// single consistent snapshot. A reverse index finds the peer at an address
+/// without going through the shards; it is updated under the shard's lock.
+#[derive(Debug)]
+pub struct Clients {
+    shards: Vec<Shard>,
+    len: AtomicUsize,
+    // The peers at each address, in the order they got it
+    by_addr: RwLock<HashMap<SocketAddr, Vec<IpAddr>>>
+}
+
+impl Default for Clients {
+    fn default() -> Clients {
+        Clients {
+            shards: (0..CLIENT_SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            len: AtomicUsize::new(0),
+            by_addr: RwLock::new(HashMap::new())
+        }
+    }
+}
+
+impl Clients {
+    fn shard(&self, tun_ip: &IpAddr) -> &Shard {
+        let bits = match tun_ip {
+            IpAddr::V4(ip) => u32::from(*ip) as usize,
+            IpAddr::V6(ip) => u128::from(*ip) as usize
+        };
+        &self.shards[bits % self.shards.len()]
+    }
+
+    // Point the addresses `tun_ip` gained at it in the reverse index, and
+    // forget those it lost. Called with its shard's write lock held.
+    fn reindex(&self, tun_ip: IpAddr, before: &[SocketAddr], after: &[SocketAddr]) {
+        if before == after {
+            return
+        }
+        let mut by_addr = self.by_addr.write().unwrap();
+        for addr in before.iter().filter(|addr| !after.contains(addr)) {
+            if let Entry::Occupied(mut peers) = by_addr.entry(*addr) {
+                peers.get_mut().retain(|peer| *peer != tun_ip);
+                if peers.get().is_empty() {
+                    peers.remove();
+                }
+            }
+        }
+        for addr in after.iter().filter(|addr| !before.contains(addr)) {
+            by_addr.entry(*addr).or_default().push(tun_ip);
+        }
+    }
+
+    /// Number of known peers.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, tun_ip: &IpAddr) -> bool {
+        self.shard(tun_ip).read().unwrap().contains_key(tun_ip)
+    }
+
+    /// Call `f` with the addresses of `tun_ip`, if it is known, under its shard's read lock.
+    pub fn with<R>(&self, tun_ip: &IpAddr, f: impl FnOnce(&[SocketAddr]) -> R) -> Option<R> {
+        self.shard(tun_ip).read().unwrap().get(tun_ip).map(|addrs| f(addrs))
+    }
+
+    /// Call `f` with the addresses of `tun_ip` under its shard's write lock.
+    /// An unknown peer is added with no addresses first, unless that would
+    /// make more than `max` peers. Returns `None` if it wasn't added. The
+    /// flag passed to `f` tells whether the peer was just added.
+    pub fn upsert<R>(&self, tun_ip: IpAddr, max: Option<usize>, f: impl FnOnce(&mut Vec<SocketAddr>, bool) -> R) -> Option<R> {
+        let mut shard = self.shard(&tun_ip).write().unwrap();
+        if let Some(addrs) = shard.get_mut(&tun_ip) {
+            let before = addrs.clone();
+            let result = f(addrs, false);
+            normalize(addrs);
+            self.reindex(tun_ip, &before, addrs);
+            return Some(result)
+        }
+
+        // Reserve the slot first, so peers added to other shards at the same time can't overshoot
+        let reserved = self.len.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| match max {
+            Some(max) if len >= max => None,
+            _ => Some(len + 1)
+        });
+        if reserved.is_err() {
+            return None
+        }
+        let addrs = shard.entry(tun_ip).or_default();
+        let result = f(addrs, true);
+        normalize(addrs);
+        self.reindex(tun_ip, &[], addrs);
+        Some(result)
+    }
+
+    /// Replace the addresses of `tun_ip`, returning the previous ones.
+    pub fn insert(&self, tun_ip: IpAddr, mut addrs: Vec<SocketAddr>) -> Option<Vec<SocketAddr>> {
+        normalize(&mut addrs);
+        let mut shard = self.shard(&tun_ip).write().unwrap();
+        self.reindex(tun_ip, shard.get(&tun_ip).map_or(&[], Vec::as_slice), &addrs);
+        let previous = shard.insert(tun_ip, addrs);
+        if previous.is_none() {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+        previous
+    }
+
+    pub fn remove(&self, tun_ip: &IpAddr) -> Option<Vec<SocketAddr>> {
+        let mut shard = self.shard(tun_ip).write().unwrap();
+        let removed = shard.remove(tun_ip);
+        if let Some(addrs) = &removed {
+            self.reindex(*tun_ip, addrs, &[]);
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Call `f` with every peer, one shard read lock at a time.
+    pub fn for_each(&self, mut f: impl FnMut(&IpAddr, &[SocketAddr])) {
+        for shard in &self.shards {
+            for (tun_ip, addrs) in shard.read().unwrap().iter() {
+                f(tun_ip, addrs);
+            }
+        }
+    }
+
+    /// Call `f` with every peer, one shard write lock at a time.
+    pub fn for_each_mut(&self, mut f: impl FnMut(&IpAddr, &mut Vec<SocketAddr>)) {
+        for shard in &self.shards {
+            for (tun_ip, addrs) in shard.write().unwrap().iter_mut() {
+                let before = addrs.clone();
+                f(tun_ip, addrs);
+                normalize(addrs);
+                self.reindex(*tun_ip, &before, addrs);
+            }
+        }
+    }
+
+    /// Whether any peer is known at `addr`.
+    pub fn contains_addr(&self, addr: &SocketAddr) -> bool {
+        self.tun_ip_of(addr).is_some()
+    }
+
+    /// The peer known at `addr`, if any.
+    pub fn tun_ip_of(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        self.by_addr.read().unwrap().get(&canonical_addr(*addr)).and_then(|peers| peers.first().copied())
+    }
+
+    /// Every known peer address.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        self.for_each(|_, peer_addrs| addrs.extend_from_slice(peer_addrs));
+        addrs
+    }
+
+    pub fn snapshot(&self) -> HashMap<IpAddr, Vec<SocketAddr>> {
+        let mut snapshot = HashMap::new();
+        self.for_each(|tun_ip, addrs| {
+            snapshot.insert(*tun_ip, addrs.to_vec());
+        });
+        snapshot
+    }
+}
+
+/// `addr` with an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`), as a
+/// dual-stack socket reports IPv4 senders, in its IPv4 form. Peer addresses
+/// are kept in that form, so a peer isn't known twice over one address.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr
+        },
+        SocketAddr::V4(_) => addr
+    }
+}
+
+// Put mapped addresses in their IPv4 form, dropping those then repeated
+fn normalize(addrs: &mut Vec<SocketAddr>) {
+    if addrs.iter().any(|addr| canonical_addr(*addr) != *addr) {
+        for addr in addrs.iter_mut() {
+            *addr = canonical_addr(*addr);
+        }
+        nat::canonicalize_targets(addrs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> IpAddr {
+        [10, 0, 0, 2].into()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn mapped_addresses_are_kept_in_their_ipv4_form() {
+        let clients = Clients::default();
+        clients.insert(peer(), vec![addr("[::ffff:10.1.2.3]:5"), addr("10.1.2.3:5"), addr("[2001:db8::1]:5")]);
+        assert_eq!(clients.snapshot()[&peer()], [addr("10.1.2.3:5"), addr("[2001:db8::1]:5")]);
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.3:5")), Some(peer()));
+        assert_eq!(clients.tun_ip_of(&addr("[::ffff:10.1.2.3]:5")), Some(peer()));
+        assert_eq!(clients.tun_ip_of(&addr("[::ffff:10.1.2.3]:6")), None);
+    }
+
+    #[test]
+    fn addresses_learned_in_mapped_form_are_not_added_twice() {
+        let clients = Clients::default();
+        clients.upsert(peer(), None, |addrs, _| addrs.push(addr("10.1.2.3:5")));
+        clients.upsert(peer(), None, |addrs, _| addrs.push(addr("[::ffff:10.1.2.3]:5")));
+        clients.for_each_mut(|_, addrs| addrs.push(addr("[::ffff:10.1.2.4]:5")));
+        assert_eq!(clients.snapshot()[&peer()], [addr("10.1.2.3:5"), addr("10.1.2.4:5")]);
+        assert_eq!(clients.len(), 1);
+    }
+
+    #[test]
+    fn the_peer_at_an_address_follows_its_changes() {
+        let clients = Clients::default();
+        let other: IpAddr = [10, 0, 0, 3].into();
+        clients.upsert(peer(), None, |addrs, _| addrs.push(addr("10.1.2.3:5")));
+        clients.insert(other, vec![addr("10.1.2.3:5"), addr("10.1.2.4:5")]);
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.3:5")), Some(peer()));
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.4:5")), Some(other));
+
+        // Once the first peer lets go of a shared address, it's the other's
+        clients.for_each_mut(|tun_ip, addrs| if *tun_ip == peer() { addrs.clear() });
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.3:5")), Some(other));
+        clients.insert(other, vec![addr("10.1.2.5:5")]);
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.3:5")), None);
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.5:5")), Some(other));
+        clients.remove(&other);
+        assert_eq!(clients.tun_ip_of(&addr("10.1.2.5:5")), None);
+    }
+}
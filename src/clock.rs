@@ -0,0 +1,170 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Source of time for everything timer driven (keep-alives, link timeouts,
+/// reaping silent peers). `SystemClock` is used normally, `MockClock` lets
+/// tests step time by hand.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)))
+    }
+}
+
+/// A clock that only moves when `advance` is called. Sleepers wake up as soon
+/// as the mock time passes their deadline.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    changed: watch::Sender<Duration>,
+    // Deadlines of the sleepers waiting, so tests can tell when those woken went back to sleep
+    parked: Arc<Mutex<Vec<Instant>>>
+}
+
+// Keeps a sleeper's deadline in `MockClock::parked` until it is done or dropped
+struct Parked {
+    parked: Arc<Mutex<Vec<Instant>>>,
+    deadline: Instant
+}
+
+impl Drop for Parked {
+    fn drop(&mut self) {
+        let mut parked = self.parked.lock().unwrap();
+        if let Some(index) = parked.iter().position(|deadline| *deadline == self.deadline) {
+            parked.swap_remove(index);
+        }
+    }
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        let (changed, _) = watch::channel(Duration::ZERO);
+        MockClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            changed,
+            parked: Arc::default()
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed += by;
+        self.changed.send_replace(*elapsed);
+    }
+
+    /// Number of sleepers waiting for a time still ahead. Once those an
+    /// `advance` woke are asleep again, it is back where it was before.
+    pub fn sleepers(&self) -> usize {
+        let now = self.now();
+        self.parked.lock().unwrap().iter().filter(|deadline| **deadline > now).count()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let mut changed = self.changed.subscribe();
+        Box::pin(async move {
+            self.parked.lock().unwrap().push(deadline);
+            let _parked = Parked { parked: self.parked.clone(), deadline };
+            while self.now() < deadline {
+                if changed.changed().await.is_err() {
+                    return
+                }
+            }
+        })
+    }
+}
+
+/// Like `tokio::time::interval`, driven by a `Clock`. The first tick completes immediately.
+pub struct Interval {
+    clock: SharedClock,
+    period: Duration,
+    next: Instant
+}
+
+impl Interval {
+    pub fn new(clock: SharedClock, period: Duration) -> Interval {
+        let next = clock.now();
+        Interval { clock, period, next }
+    }
+
+    pub async fn tick(&mut self) -> Instant {
+        self.clock.sleep_until(self.next).await;
+        let tick = self.next;
+        self.next += self.period;
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn interval_ticks_once_per_period_of_mock_time() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let mut interval = Interval::new(clock.clone(), Duration::from_secs(2));
+        assert_eq!(interval.tick().await, start);
+
+        let mut tick = Box::pin(interval.tick());
+        clock.advance(Duration::from_secs(1));
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut tick).await.is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tick.await, start + Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn sleepers_woken_by_an_advance_are_no_longer_counted() {
+        let clock = MockClock::new();
+        let (mut soon, mut later) = (clock.sleep_until(clock.now() + Duration::from_secs(1)), clock.sleep_until(clock.now() + Duration::from_secs(2)));
+        assert!(futures::poll!(&mut soon).is_pending());
+        assert!(futures::poll!(&mut later).is_pending());
+        assert_eq!(clock.sleepers(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.sleepers(), 1);
+        soon.await;
+        drop(later);
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[tokio::test]
+    async fn interval_catches_up_after_a_long_advance() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let mut interval = Interval::new(clock.clone(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(3));
+        for tick in 0..4 {
+            assert_eq!(interval.tick().await, start + Duration::from_secs(tick));
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::net::{IpAddr, SocketAddr};
+use bytes::Bytes;
+use tokio::sync::broadcast;
+
+// Control messages buffered per subscriber before the oldest are dropped
+pub const CONTROL_CAPACITY: usize = 64;
+
+/// A control message received from a peer. These share the data sockets
+/// but are handed to the application instead of being written to the TUN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub tun_ip: IpAddr,
+    // Peer address the message came from
+    pub from: SocketAddr,
+    // Name of the send device it was received on
+    pub path: String,
+    pub payload: Bytes
+}
+
+/// Sender side of the received control messages. Lossy like `PacketEvents`,
+/// and messages received without a subscriber are dropped.
+#[derive(Debug, Clone)]
+pub struct ControlMessages {
+    sender: broadcast::Sender<ControlMessage>
+}
+
+impl ControlMessages {
+    pub fn new(capacity: usize) -> ControlMessages {
+        let (sender, _) = broadcast::channel(capacity);
+        ControlMessages { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ControlMessage> {
+        self.sender.subscribe()
+    }
+
+    /// Hand `message` to the subscribers. Returns false if there were none.
+    pub fn deliver(&self, message: ControlMessage) -> bool {
+        self.sender.send(message).is_ok()
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+
+use crate::error::TunnelError;
+use crate::settings::EncryptionSettings;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+
+/// Bytes encryption adds to every datagram.
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+// The random part of a nonce, the rest is a counter
+const SESSION_LEN: usize = NONCE_LEN - 8;
+
+/// Datagrams a sender may have sealed since the newest one received that
+/// are still taken. Older ones are dropped as replays.
+pub const REPLAY_WINDOW: u64 = 4096;
+// Senders a replay window tracks before the one idle longest is forgotten
+const MAX_REPLAY_SENDERS: usize = 1024;
+
+/// XChaCha20-Poly1305 with a pre-shared key. Datagrams are sent as
+/// nonce || ciphertext || tag. The nonce is a random prefix, drawn once per
+/// cipher, and a counter, so a receiver can tell a datagram it saw before.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    session: [u8; SESSION_LEN],
+    // Shared by the clones, which send under the same session
+    counter: Arc<AtomicU64>
+}
+
+// Keeps the key out of logs
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LEN]) -> Cipher {
+        let mut session = [0; SESSION_LEN];
+        session.copy_from_slice(&XChaCha20Poly1305::generate_nonce(&mut OsRng)[..SESSION_LEN]);
+        Cipher { aead: XChaCha20Poly1305::new(key.into()), session, counter: Arc::default() }
+    }
+
+    /// Load and validate the key from the settings: either `key` or the
+    /// contents of `key_file`, as 64 hex digits.
+    pub fn from_settings(settings: &EncryptionSettings) -> Result<Cipher, TunnelError> {
+        let hex = match (&settings.key, &settings.key_file) {
+            (Some(key), None) => key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|source| TunnelError::KeyFile { path: path.clone(), source })?,
+            (Some(_), Some(_)) => return Err(TunnelError::InvalidKey("only one of `key` and `key_file` may be set".to_string())),
+            (None, None) => return Err(TunnelError::InvalidKey("one of `key` or `key_file` must be set".to_string()))
+        };
+
+        Ok(Cipher::new(&parse_key(hex.trim())?))
+    }
+
+    /// Encrypt `plaintext` into `out`, replacing its contents.
+    pub fn seal_into(&self, plaintext: &[u8], out: &mut Vec<u8>) {
+        let mut nonce = XNonce::default();
+        nonce[..SESSION_LEN].copy_from_slice(&self.session);
+        nonce[SESSION_LEN..].copy_from_slice(&self.counter.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        out.clear();
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(plaintext);
+        let tag = self.aead.encrypt_in_place_detached(&nonce, b"", &mut out[NONCE_LEN..])
+            .expect("datagram too large to encrypt");
+        out.extend_from_slice(&tag);
+    }
+
+    /// Decrypt a datagram in place. Returns the plaintext, or `None` if the
+    /// datagram is truncated or fails authentication.
+    pub fn open_in_place<'a>(&self, datagram: &'a mut [u8]) -> Option<&'a [u8]> {
+        if !self.try_open(datagram) {
+            return None
+        }
+        Some(&datagram[NONCE_LEN..datagram.len() - TAG_LEN])
+    }
+
+    // Decrypt in place if the datagram authenticates. Left untouched otherwise,
+    // since the tag is checked before anything is decrypted.
+    fn try_open(&self, datagram: &mut [u8]) -> bool {
+        if datagram.len() < ENCRYPTION_OVERHEAD {
+            return false
+        }
+
+        let (nonce, rest) = datagram.split_at_mut(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+        self.aead.decrypt_in_place_detached(XNonce::from_slice(nonce), b"", ciphertext, Tag::from_slice(tag)).is_ok()
+    }
+}
+
+/// Which key a datagram was, or should be, encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyId {
+    Plaintext,
+    Global,
+    // The key of the peer with this TUN IP
+    Peer(IpAddr)
+}
+
+/// The global key and the keys of peers that have their own. A peer's own
+/// key is used for everything sent to and received from it, the other peers
+/// share the global one. Without either, datagrams are sent in the clear.
+#[derive(Debug, Clone, Default)]
+pub struct Keys {
+    global: Option<Cipher>,
+    peers: Arc<HashMap<IpAddr, Cipher>>
+}
+
+impl Keys {
+    pub fn new(global: Option<Cipher>, peers: HashMap<IpAddr, Cipher>) -> Keys {
+        Keys { global, peers: Arc::new(peers) }
+    }
+
+    pub fn from_settings(global: Option<&EncryptionSettings>, peers: Option<&HashMap<IpAddr, EncryptionSettings>>) -> Result<Keys, TunnelError> {
+        let global = global.map(Cipher::from_settings).transpose()?;
+        let peers = peers.into_iter().flatten()
+            .map(|(tun_ip, settings)| Ok((*tun_ip, Cipher::from_settings(settings)?)))
+            .collect::<Result<HashMap<_, _>, TunnelError>>()?;
+        Ok(Keys::new(global, peers))
+    }
+
+    /// Whether anything is encrypted.
+    pub fn is_enabled(&self) -> bool {
+        self.global.is_some() || !self.peers.is_empty()
+    }
+
+    pub fn has_peer_keys(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    pub fn global(&self) -> Option<&Cipher> {
+        self.global.as_ref()
+    }
+
+    /// The key to use with the peer `tun_ip`.
+    pub fn id_for(&self, tun_ip: &IpAddr) -> KeyId {
+        if self.peers.contains_key(tun_ip) {
+            KeyId::Peer(*tun_ip)
+        } else if self.global.is_some() {
+            KeyId::Global
+        } else {
+            KeyId::Plaintext
+        }
+    }
+
+    pub fn cipher(&self, id: KeyId) -> Option<&Cipher> {
+        match id {
+            KeyId::Plaintext => None,
+            KeyId::Global => self.global.as_ref(),
+            KeyId::Peer(tun_ip) => self.peers.get(&tun_ip)
+        }
+    }
+
+    pub fn for_peer(&self, tun_ip: &IpAddr) -> Option<&Cipher> {
+        self.cipher(self.id_for(tun_ip))
+    }
+
+    /// Decrypt a datagram in place with the key of `sender`, the peer it
+    /// came from, if known. For unknown senders the global key and then each
+    /// peer's key is tried, or the datagram taken as plaintext without a
+    /// global key. Returns the plaintext and the key that opened it.
+    pub fn open_in_place<'a>(&self, datagram: &'a mut [u8], sender: Option<IpAddr>) -> Option<(&'a [u8], KeyId)> {
+        let id = match sender {
+            Some(sender) => self.id_for(&sender),
+            None => {
+                let candidates = self.global.iter().map(|cipher| (KeyId::Global, cipher))
+                    .chain(self.peers.iter().map(|(tun_ip, cipher)| (KeyId::Peer(*tun_ip), cipher)));
+                let mut opened = None;
+                for (id, cipher) in candidates {
+                    if cipher.try_open(datagram) {
+                        opened = Some(id);
+                        break
+                    }
+                }
+                match opened {
+                    Some(id) => return Some((&datagram[NONCE_LEN..datagram.len() - TAG_LEN], id)),
+                    None if self.global.is_none() => KeyId::Plaintext,
+                    None => return None
+                }
+            }
+        };
+        match self.cipher(id) {
+            Some(cipher) => cipher.open_in_place(datagram).map(|plaintext| (plaintext, id)),
+            None => Some((datagram, id))
+        }
+    }
+}
+
+/// The nonces of the datagrams opened so far, per key and sender session, to
+/// drop datagrams that are opened again. The counter of each session must
+/// be within `REPLAY_WINDOW` of its newest. A session forgotten to make room
+/// starts over, so its old datagrams can get through once more.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    senders: HashMap<(KeyId, [u8; SESSION_LEN]), SenderWindow>,
+    // Bumped on every datagram, to tell which sender was idle longest
+    datagrams: u64
+}
+
+#[derive(Debug)]
+struct SenderWindow {
+    newest: u64,
+    // Counters seen within the window, a bit each at the counter modulo its size
+    seen: Box<[u64; (REPLAY_WINDOW / 64) as usize]>,
+    last_used: u64
+}
+
+impl SenderWindow {
+    fn new(counter: u64) -> SenderWindow {
+        SenderWindow { newest: counter, seen: Box::new([0; (REPLAY_WINDOW / 64) as usize]), last_used: 0 }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter > self.newest {
+            // The bits of the counters passed over are those of counters a window older
+            if counter - self.newest >= REPLAY_WINDOW {
+                self.seen.fill(0);
+            } else {
+                for passed in self.newest + 1..=counter {
+                    let (word, bit) = SenderWindow::bit(passed);
+                    self.seen[word] &= !bit;
+                }
+            }
+            self.newest = counter;
+        } else if self.newest - counter >= REPLAY_WINDOW {
+            return false
+        }
+
+        let (word, bit) = SenderWindow::bit(counter);
+        let new = self.seen[word] & bit == 0;
+        self.seen[word] |= bit;
+        new
+    }
+}
+
+impl ReplayWindow {
+    /// Whether the datagram opened with the key `id` and sealed with `nonce`
+    /// wasn't opened before, remembering it if so.
+    pub fn accept(&mut self, id: KeyId, nonce: &[u8; NONCE_LEN]) -> bool {
+        let mut session = [0; SESSION_LEN];
+        session.copy_from_slice(&nonce[..SESSION_LEN]);
+        let mut counter = [0; NONCE_LEN - SESSION_LEN];
+        counter.copy_from_slice(&nonce[SESSION_LEN..]);
+        let counter = u64::from_le_bytes(counter);
+
+        if !self.senders.contains_key(&(id, session)) && self.senders.len() >= MAX_REPLAY_SENDERS {
+            let idle = self.senders.iter().min_by_key(|(_, window)| window.last_used).map(|(sender, _)| *sender);
+            if let Some(idle) = idle {
+                self.senders.remove(&idle);
+            }
+        }
+        self.datagrams += 1;
+        let window = self.senders.entry((id, session)).or_insert_with(|| SenderWindow::new(counter));
+        window.last_used = self.datagrams;
+        window.accept(counter)
+    }
+}
+
+fn parse_key(hex: &str) -> Result<[u8; KEY_LEN], TunnelError> {
+    if hex.len() != 2 * KEY_LEN {
+        return Err(TunnelError::InvalidKey(format!("expected {} hex digits, got {}", 2 * KEY_LEN, hex.len())))
+    }
+
+    let mut key = [0; KEY_LEN];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| TunnelError::InvalidKey("key is not hex encoded".to_string()))?;
+        *byte = digits;
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn settings(key: Option<&str>, key_file: Option<&str>) -> EncryptionSettings {
+        EncryptionSettings { key: key.map(str::to_string), key_file: key_file.map(Into::into) }
+    }
+
+    fn invalid_key(settings: &EncryptionSettings) -> String {
+        match Cipher::from_settings(settings) {
+            Err(TunnelError::InvalidKey(reason)) => reason,
+            other => panic!("expected an invalid key, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn key_is_64_hex_digits() {
+        assert_eq!(parse_key(KEY).unwrap()[..4], [0, 1, 2, 3]);
+        assert_eq!(parse_key(&KEY.to_uppercase()).unwrap()[31], 0x1f);
+        assert!(invalid_key(&settings(Some(&KEY[2..]), None)).contains("expected 64 hex digits, got 62"));
+        assert!(invalid_key(&settings(Some(&KEY.replace('a', "g")), None)).contains("not hex"));
+        // Multibyte characters of the right length in bytes
+        assert!(invalid_key(&settings(Some(&format!("é{}", &KEY[2..])), None)).contains("not hex"));
+    }
+
+    #[test]
+    fn exactly_one_key_source_is_required() {
+        assert!(invalid_key(&settings(None, None)).contains("must be set"));
+        assert!(invalid_key(&settings(Some(KEY), Some("/key"))).contains("only one"));
+    }
+
+    #[test]
+    fn key_file_is_read_and_trimmed() {
+        let path = std::env::temp_dir().join(format!("mptun-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", KEY)).unwrap();
+        assert!(Cipher::from_settings(&settings(None, path.to_str())).is_ok());
+        std::fs::remove_file(&path).unwrap();
+
+        match Cipher::from_settings(&settings(None, path.to_str())) {
+            Err(TunnelError::KeyFile { path: missing, .. }) => assert_eq!(missing, path),
+            other => panic!("expected a key file error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn sealed_datagrams_open_only_unmodified_and_with_the_key() {
+        let cipher = Cipher::new(&parse_key(KEY).unwrap());
+        let mut sealed = Vec::new();
+        cipher.seal_into(b"payload", &mut sealed);
+        assert_eq!(sealed.len(), b"payload".len() + ENCRYPTION_OVERHEAD);
+        assert_eq!(cipher.open_in_place(&mut sealed.clone()), Some(&b"payload"[..]));
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert_eq!(cipher.open_in_place(&mut tampered), None);
+        assert_eq!(Cipher::new(&[7; KEY_LEN]).open_in_place(&mut sealed.clone()), None);
+        assert_eq!(cipher.open_in_place(&mut sealed[..ENCRYPTION_OVERHEAD - 1].to_vec()), None);
+    }
+
+    fn sealed(cipher: &Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        cipher.seal_into(plaintext, &mut sealed);
+        sealed
+    }
+
+    // The global key and a key of its own for 10.0.0.1
+    fn two_keys() -> (Keys, Cipher, Cipher) {
+        let (global, own) = (Cipher::new(&parse_key(KEY).unwrap()), Cipher::new(&[7; KEY_LEN]));
+        let keys = Keys::new(Some(global.clone()), HashMap::from([(IpAddr::from([10, 0, 0, 1]), own.clone())]));
+        (keys, global, own)
+    }
+
+    #[test]
+    fn a_peer_with_its_own_key_uses_it_and_the_others_share_the_global_one() {
+        let (keys, _, _) = two_keys();
+        let (own, other) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 3]));
+        assert_eq!(keys.id_for(&own), KeyId::Peer(own));
+        assert_eq!(keys.id_for(&other), KeyId::Global);
+        assert_eq!(Keys::new(None, HashMap::new()).id_for(&other), KeyId::Plaintext);
+        assert!(keys.is_enabled() && keys.has_peer_keys());
+    }
+
+    #[test]
+    fn known_senders_are_opened_with_their_key_only() {
+        let (keys, global, own) = two_keys();
+        let peer = Some(IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(keys.open_in_place(&mut sealed(&own, b"own"), peer), Some((&b"own"[..], KeyId::Peer([10, 0, 0, 1].into()))));
+        // Sealed with the global key, which this peer no longer uses
+        assert_eq!(keys.open_in_place(&mut sealed(&global, b"global"), peer), None);
+        assert_eq!(keys.open_in_place(&mut sealed(&own, b"own"), Some([10, 0, 0, 3].into())), None);
+    }
+
+    #[test]
+    fn unknown_senders_get_a_try_of_every_key() {
+        let (keys, global, own) = two_keys();
+        assert_eq!(keys.open_in_place(&mut sealed(&global, b"global"), None), Some((&b"global"[..], KeyId::Global)));
+        assert_eq!(keys.open_in_place(&mut sealed(&own, b"own"), None), Some((&b"own"[..], KeyId::Peer([10, 0, 0, 1].into()))));
+        assert_eq!(keys.open_in_place(&mut sealed(&Cipher::new(&[9; KEY_LEN]), b"neither"), None), None);
+        // Plaintext only goes when there's no global key
+        assert_eq!(keys.open_in_place(&mut b"plain".to_vec(), None), None);
+        let peer_keys_only = Keys::new(None, HashMap::from([(IpAddr::from([10, 0, 0, 1]), own)]));
+        assert_eq!(peer_keys_only.open_in_place(&mut b"plain".to_vec(), None), Some((&b"plain"[..], KeyId::Plaintext)));
+    }
+
+    #[test]
+    fn nonces_are_fresh_per_datagram() {
+        let cipher = Cipher::new(&parse_key(KEY).unwrap());
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        cipher.seal_into(b"payload", &mut first);
+        cipher.clone().seal_into(b"payload", &mut second);
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+        // Another cipher with the same key starts a session of its own
+        assert_ne!(first[..SESSION_LEN], sealed(&Cipher::new(&parse_key(KEY).unwrap()), b"payload")[..SESSION_LEN]);
+    }
+
+    fn nonce(datagram: &[u8]) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&datagram[..NONCE_LEN]);
+        nonce
+    }
+
+    #[test]
+    fn a_datagram_is_only_accepted_once() {
+        let cipher = Cipher::new(&parse_key(KEY).unwrap());
+        let mut window = ReplayWindow::default();
+        let datagrams: Vec<_> = (0..3).map(|_| sealed(&cipher, b"payload")).collect();
+        // Out of order within the window is fine
+        for datagram in [&datagrams[1], &datagrams[0], &datagrams[2]] {
+            assert!(window.accept(KeyId::Global, &nonce(datagram)));
+        }
+        for datagram in &datagrams {
+            assert!(!window.accept(KeyId::Global, &nonce(datagram)));
+        }
+        // Opened with another key, it's another sender's
+        assert!(window.accept(KeyId::Peer([10, 0, 0, 1].into()), &nonce(&datagrams[0])));
+    }
+
+    #[test]
+    fn datagrams_older_than_the_window_are_dropped() {
+        let cipher = Cipher::new(&parse_key(KEY).unwrap());
+        let mut window = ReplayWindow::default();
+        let old = sealed(&cipher, b"old");
+        let late = sealed(&cipher, b"late");
+        assert!(window.accept(KeyId::Global, &nonce(&old)));
+        let mut newest = Vec::new();
+        for _ in 0..REPLAY_WINDOW - 1 {
+            cipher.seal_into(b"newer", &mut newest);
+        }
+        assert!(window.accept(KeyId::Global, &nonce(&newest)));
+        // A whole window older than the newest, and one counter short of that
+        assert!(!window.accept(KeyId::Global, &nonce(&old)));
+        assert!(window.accept(KeyId::Global, &nonce(&late)));
+        assert!(!window.accept(KeyId::Global, &nonce(&late)));
+    }
+
+    #[test]
+    fn the_sender_idle_longest_makes_room() {
+        let mut window = ReplayWindow::default();
+        let ciphers: Vec<_> = (0..=MAX_REPLAY_SENDERS).map(|_| Cipher::new(&parse_key(KEY).unwrap())).collect();
+        let first = sealed(&ciphers[0], b"first");
+        assert!(window.accept(KeyId::Global, &nonce(&first)));
+        for cipher in &ciphers[1..] {
+            assert!(window.accept(KeyId::Global, &nonce(&sealed(cipher, b"other"))));
+        }
+        assert_eq!(window.senders.len(), MAX_REPLAY_SENDERS);
+        // Forgotten, so its datagram is taken again
+        assert!(window.accept(KeyId::Global, &nonce(&first)));
+    }
+}
@@ -0,0 +1,235 @@
+// Turns a TUN packet into the datagrams send_udp puts on the wire: the
+// compressed data message, sealed and checksummed when enabled, and split
+// into fragments when it doesn't fit one datagram. The buffers are kept
+// across packets, so the common case allocates nothing.
+
+use std::ops::Deref;
+use lz4_flex::compress_into;
+use lz4_flex::block::get_maximum_output_size;
+
+use crate::checksum::{self, CHECKSUM_LEN};
+use crate::crypto::{Cipher, ENCRYPTION_OVERHEAD};
+use crate::fragment;
+use crate::jitter;
+use crate::messages::{self, PathStamp, WireFormat};
+
+/// How every datagram of a packet is framed on one path.
+#[derive(Debug, Clone, Copy)]
+pub struct Framing<'a> {
+    pub wire_format: WireFormat,
+    pub session_epoch: Option<u32>,
+    pub cipher: Option<&'a Cipher>,
+    pub checksum: bool,
+    // Messages that don't fit this many bytes, framing included, are fragmented
+    pub max_datagram_size: Option<usize>
+}
+
+impl Framing<'_> {
+    /// Bytes added to each datagram after encoding.
+    pub fn overhead(&self) -> usize {
+        let sealing = if self.cipher.is_some() { ENCRYPTION_OVERHEAD } else { 0 };
+        sealing + if self.checksum { CHECKSUM_LEN } else { 0 }
+    }
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    Encode(bincode::Error),
+    // The maximum datagram size is too small to fragment the message into
+    TooSmallToFragment(usize)
+}
+
+/// The datagrams of one packet, see `DatagramEncoder`.
+#[derive(Debug)]
+pub struct DatagramEncoder {
+    encoded: Vec<u8>,
+    sealed: Vec<u8>,
+    fragments: Vec<Vec<u8>>,
+    fragmented: bool,
+    // Of the next fragmented packet. Not its sequence number, which a
+    // hub's own packets and those it forwards may share.
+    fragment_id: u64
+}
+
+impl Default for DatagramEncoder {
+    fn default() -> DatagramEncoder {
+        DatagramEncoder {
+            encoded: Vec::new(),
+            sealed: Vec::new(),
+            fragments: Vec::new(),
+            fragmented: false,
+            // Not those of an earlier run, whose fragments the peer may still hold
+            fragment_id: jitter::timestamp_now()
+        }
+    }
+}
+
+impl DatagramEncoder {
+    /// Encode `compressed`, from `compress_prepend_size_into`, as the data
+    /// message `seq` framed by `framing`, replacing the previous datagrams.
+    pub fn encode(&mut self, seq: usize, timestamp: Option<u64>, stamp: Option<PathStamp>, compressed: &[u8], framing: &Framing) -> Result<(), EncodeError> {
+        messages::encode_data_into(seq, framing.session_epoch, timestamp, stamp, compressed, framing.wire_format, &mut self.encoded)
+            .map_err(EncodeError::Encode)?;
+        let overhead = framing.overhead();
+        self.fragmented = match framing.max_datagram_size {
+            Some(max) if self.encoded.len() + overhead > max => {
+                if !fragment::split_into(&self.encoded, self.fragment_id, max.saturating_sub(overhead), &mut self.fragments) {
+                    return Err(EncodeError::TooSmallToFragment(max))
+                }
+                self.fragment_id = self.fragment_id.wrapping_add(1);
+                for fragment in self.fragments.iter_mut() {
+                    frame(fragment, &mut self.sealed, framing);
+                }
+                true
+            },
+            _ => {
+                frame(&mut self.encoded, &mut self.sealed, framing);
+                false
+            }
+        };
+        Ok(())
+    }
+
+    pub fn is_fragmented(&self) -> bool {
+        self.fragmented
+    }
+
+    /// The datagrams of the last packet encoded.
+    pub fn datagrams(&self) -> Datagrams<'_> {
+        if self.fragmented {
+            Datagrams::Fragments(self.fragments.iter().map(Vec::as_slice).collect())
+        } else {
+            Datagrams::One([&self.encoded])
+        }
+    }
+
+    /// Bytes on the wire for the last packet encoded.
+    pub fn wire_len(&self) -> usize {
+        if self.fragmented {
+            self.fragments.iter().map(Vec::len).sum()
+        } else {
+            self.encoded.len()
+        }
+    }
+}
+
+/// The datagrams making up one packet. Only fragmented packets need a list of them.
+#[derive(Debug)]
+pub enum Datagrams<'a> {
+    One([&'a [u8]; 1]),
+    Fragments(Vec<&'a [u8]>)
+}
+
+impl<'a> Deref for Datagrams<'a> {
+    type Target = [&'a [u8]];
+
+    fn deref(&self) -> &[&'a [u8]] {
+        match self {
+            Datagrams::One(one) => one,
+            Datagrams::Fragments(fragments) => fragments
+        }
+    }
+}
+
+// Seal `datagram` in place, through the `sealed` scratch buffer, and append its checksum
+fn frame(datagram: &mut Vec<u8>, sealed: &mut Vec<u8>, framing: &Framing) {
+    if let Some(cipher) = framing.cipher {
+        cipher.seal_into(datagram, sealed);
+        std::mem::swap(datagram, sealed);
+    }
+    if framing.checksum {
+        checksum::append(datagram);
+    }
+}
+
+/// Same output as `lz4_flex::compress_prepend_size`, written into a reusable buffer.
+pub fn compress_prepend_size_into(input: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.resize(4 + get_maximum_output_size(input.len()), 0);
+    out[..4].copy_from_slice(&(input.len() as u32).to_le_bytes());
+    // Cannot fail, the output is sized for the worst case
+    let compressed_len = compress_into(input, &mut out[4..]).unwrap();
+    out.truncate(4 + compressed_len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::Reassembler;
+    use crate::messages::Messages;
+
+    fn framing(max_datagram_size: Option<usize>) -> Framing<'static> {
+        Framing { wire_format: WireFormat::Bincode, session_epoch: None, cipher: None, checksum: false, max_datagram_size }
+    }
+
+    // Barely compressible bytes, too many for one 1000 byte datagram
+    fn noise(seed: u32) -> Vec<u8> {
+        (0..3000u32).map(|index| (index.wrapping_add(seed).wrapping_mul(2_654_435_761) >> 24) as u8).collect()
+    }
+
+    #[test]
+    fn a_packet_that_fits_is_one_datagram_of_its_data_message() {
+        let mut compressed = Vec::new();
+        compress_prepend_size_into(b"payload", &mut compressed);
+        let mut encoder = DatagramEncoder::default();
+        encoder.encode(7, None, None, &compressed, &framing(None)).unwrap();
+
+        assert!(!encoder.is_fragmented());
+        assert_eq!(encoder.datagrams().len(), 1);
+        assert_eq!(encoder.wire_len(), encoder.datagrams()[0].len());
+        match messages::decode_packet(encoder.datagrams()[0], WireFormat::Bincode, u64::MAX).unwrap() {
+            Messages::Packet(packet) => {
+                assert_eq!(packet.seq, 7);
+                assert_eq!(lz4_flex::decompress_size_prepended(&packet.bytes).unwrap(), b"payload");
+            },
+            message => panic!("{:?}", message)
+        }
+    }
+
+    #[test]
+    fn a_packet_too_large_for_a_datagram_is_fragmented() {
+        let mut compressed = Vec::new();
+        let noise: Vec<u8> = (0..3000u32).map(|index| (index.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        compress_prepend_size_into(&noise, &mut compressed);
+        let mut encoder = DatagramEncoder::default();
+        encoder.encode(1, None, None, &compressed, &framing(Some(1000))).unwrap();
+
+        assert!(encoder.is_fragmented());
+        assert!(encoder.datagrams().len() > 1);
+        assert!(encoder.datagrams().iter().all(|datagram| datagram.len() <= 1000));
+        assert!(matches!(encoder.encode(1, None, None, &compressed, &framing(Some(8))), Err(EncodeError::TooSmallToFragment(8))));
+    }
+
+    #[test]
+    fn packets_sharing_a_seq_are_reassembled_apart() {
+        // A hub's own packet and one it forwards, numbered alike by their senders
+        let (own, forwarded) = (noise(1), noise(2));
+        let mut encoder = DatagramEncoder::default();
+        let mut compressed = Vec::new();
+        let mut fragments = Vec::new();
+        for payload in [&own, &forwarded] {
+            compress_prepend_size_into(payload, &mut compressed);
+            encoder.encode(5, None, None, &compressed, &framing(Some(1000))).unwrap();
+            fragments.push(encoder.datagrams().iter().map(|datagram| datagram.to_vec()).collect::<Vec<_>>());
+        }
+
+        // Their fragments arrive interleaved from the one address
+        let source = "127.0.0.1:4000".parse().unwrap();
+        let mut reassembler = Reassembler::new(usize::MAX);
+        let now = std::time::Instant::now();
+        let mut messages = Vec::new();
+        for index in 0..fragments[0].len().max(fragments[1].len()) {
+            for fragment in fragments.iter().filter_map(|fragments| fragments.get(index)) {
+                messages.extend(reassembler.push(source, fragment, now).unwrap());
+            }
+        }
+
+        let payloads: Vec<Vec<u8>> = messages.iter()
+            .map(|message| match messages::decode_packet(message, WireFormat::Bincode, u64::MAX).unwrap() {
+                Messages::Packet(packet) => lz4_flex::decompress_size_prepended(&packet.bytes).unwrap(),
+                message => panic!("{:?}", message)
+            })
+            .collect();
+        assert_eq!(payloads, [own, forwarded]);
+    }
+}
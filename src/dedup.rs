@@ -0,0 +1,123 @@
+use std::collections::{HashSet, VecDeque};
+
+/// Remembers the last `window` sequence numbers delivered, to drop copies of
+/// a packet that arrive over other links. Unlike ordering by sequence number,
+/// this lets late but new packets through.
+#[derive(Debug)]
+pub struct DedupWindow {
+    window: usize,
+    seen: HashSet<usize>,
+    // Delivery order, oldest first, for eviction
+    order: VecDeque<usize>,
+    highest: Option<usize>
+}
+
+impl DedupWindow {
+    pub fn new(window: usize) -> DedupWindow {
+        DedupWindow {
+            window: window.max(1),
+            seen: HashSet::with_capacity(window),
+            order: VecDeque::with_capacity(window),
+            highest: None
+        }
+    }
+
+    /// Whether `seq` was seen within the window.
+    pub fn contains(&self, seq: usize) -> bool {
+        self.seen.contains(&seq)
+    }
+
+    /// Highest sequence number recorded, including ones evicted from the window.
+    pub fn highest(&self) -> Option<usize> {
+        self.highest
+    }
+
+    /// Record `seq`. Returns false if it was already seen within the window.
+    pub fn insert(&mut self, seq: usize) -> bool {
+        if !self.seen.insert(seq) {
+            return false
+        }
+
+        self.highest = Some(self.highest.map_or(seq, |highest| highest.max(seq)));
+        self.order.push_back(seq);
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_within_the_window_are_refused() {
+        let mut window = DedupWindow::new(4);
+        assert!(window.insert(1));
+        assert!(!window.insert(1));
+        assert!(window.contains(1));
+        assert!(!window.contains(2));
+    }
+
+    #[test]
+    fn late_but_new_packets_get_through() {
+        let mut window = DedupWindow::new(4);
+        assert!(window.insert(5));
+        assert!(window.insert(3));
+        assert!(window.insert(4));
+        assert!(!window.insert(3));
+        assert_eq!(window.highest(), Some(5));
+    }
+
+    #[test]
+    fn oldest_delivered_is_evicted_first() {
+        let mut window = DedupWindow::new(3);
+        for seq in [10, 2, 30] {
+            window.insert(seq);
+        }
+        assert!(window.insert(40));
+        // Evicted by delivery order, not by value
+        assert!(!window.contains(10));
+        assert!(window.contains(2));
+        assert!(window.insert(10));
+        // Beyond the window, so only `highest` remembers it
+        assert_eq!(window.highest(), Some(40));
+    }
+
+    #[test]
+    fn only_copy_arriving_far_behind_is_delivered() {
+        let mut window = DedupWindow::new(4096);
+        assert!(window.insert(1));
+        for seq in 3..3000 {
+            assert!(window.insert(seq));
+        }
+        assert!(window.insert(2));
+        assert!(!window.insert(2));
+    }
+
+    #[test]
+    fn window_keeps_its_size_over_many_wraps() {
+        let mut window = DedupWindow::new(8);
+        for seq in 0..1000 {
+            assert!(window.insert(seq));
+        }
+        assert!((992..1000).all(|seq| window.contains(seq)));
+        assert!(!window.contains(991));
+        assert_eq!(window.seen.len(), 8);
+        assert_eq!(window.order.len(), 8);
+    }
+
+    #[test]
+    fn sequence_numbers_wrapping_around_are_new() {
+        let mut window = DedupWindow::new(8);
+        for seq in [usize::MAX - 1, usize::MAX, 0, 1] {
+            assert!(window.insert(seq));
+        }
+        for seq in [usize::MAX - 1, usize::MAX, 0, 1] {
+            assert!(!window.insert(seq));
+        }
+    }
+}
@@ -0,0 +1,508 @@
+// Works out once per packet what the send tasks need to know about it,
+// instead of in each of them: where it's going, which peer carries it,
+// which address families reach that peer and which links send it under
+// the path mode. Packets every send task would drop are dropped here, and
+// counted once. The rest go only into the queues of the links sending
+// them, see `PathQueue`.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use bytes::Bytes;
+use etherparse::{InternetSlice, SlicedPacket};
+
+use crate::clients::Clients;
+use crate::crypto::KeyId;
+use crate::flowcontrol::FlowControl;
+use crate::flows::{FlowKey, FlowTracker};
+use crate::ipfrag;
+use crate::messages::Packet;
+use crate::path::{self, Path, Paths};
+use crate::pathqueue::PathQueue;
+use crate::settings::{PathMode, UnparseablePolicy};
+use crate::stats::Stats;
+use crate::tasks::{TaskConfig, TunPacket};
+use crate::transport::Families;
+
+/// What the dispatcher found out about a packet for the send tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    // Parsed destination TUN IP. None for packets sent to every peer.
+    pub destination: Option<IpAddr>,
+    // The peer the packet is for, or the hub relaying it
+    pub peer: Option<IpAddr>,
+    pub inner_tos: Option<u8>,
+    pub flow: Option<FlowKey>,
+    // Stamped on the packet: ours, or the original sender's for packets the hub forwards
+    pub session_epoch: Option<u32>
+}
+
+// A running send task, as the dispatcher sees it
+#[derive(Debug)]
+struct Subscriber {
+    queue: Weak<PathQueue>,
+    path: Arc<Path>,
+    // Of the task's socket. Packets for peers it reaches no address of aren't queued.
+    families: Families,
+    // Share of packets owed to the link while it ramps up after recovering
+    ramp_credit: f64
+}
+
+// How the links sending one packet are picked
+struct Selection<'a> {
+    mode: PathMode,
+    // The links the peer may be sent to on that aren't paused
+    sendable: &'a [Arc<Path>],
+    // The one link failover, weighted and flow hash mode send on
+    chosen: Option<&'a Arc<Path>>,
+    // One of the first packets of a new flow, which every link sends in failover mode
+    new_flow: bool,
+    seq: usize,
+    read_at: Instant
+}
+
+impl Selection<'_> {
+    // Whether `path` sends the packet: None if not, else with when a
+    // fastest mode backup copy is due
+    fn send_on(&self, path: &Arc<Path>, ramp_credit: &mut f64, config: &TaskConfig) -> Option<Option<Instant>> {
+        if !self.sendable.iter().any(|sendable| Arc::ptr_eq(sendable, path)) {
+            return None
+        }
+        let is_chosen = self.chosen.is_some_and(|chosen| Arc::ptr_eq(chosen, path));
+        match self.mode {
+            PathMode::Failover if self.new_flow => {},
+            PathMode::Failover | PathMode::Weighted | PathMode::FlowHash if !is_chosen => return None,
+            PathMode::Failover | PathMode::Weighted | PathMode::FlowHash => {},
+            // The lowest RTT link sends right away, the others only back it
+            // up once it has had a head start
+            PathMode::Fastest if !path::is_among_best(self.sendable, path, 1) => {
+                return config.backup_delay.map(|delay| Some(self.read_at + delay))
+            },
+            PathMode::Fastest => {},
+            PathMode::Redundant => {
+                // Limited redundancy only sends on the best few links
+                if config.redundancy.is_some_and(|redundancy| !path::is_among_best(self.sendable, path, redundancy)) {
+                    return None
+                }
+                // A link with a send_fraction copies only some packets, while
+                // another link that is up carries them all
+                let has_other_up = path::has_other_up(self.sendable, path);
+                if !path.sends_seq(self.seq) && has_other_up {
+                    return None
+                }
+                // A recovered link takes a growing share of the copies, spread
+                // evenly, while another link that is up carries them all
+                let share = config.recovery_ramp.map_or(1.0, |window| path.ramp_share(window, self.read_at));
+                if share < 1.0 && has_other_up {
+                    *ramp_credit += share;
+                    if *ramp_credit < 1.0 {
+                        return None
+                    }
+                    *ramp_credit -= 1.0;
+                }
+            }
+        }
+        Some(None)
+    }
+}
+
+/// Hands packets from the TUN, the hub and the unknown peer queue to the
+/// send tasks of the links sending them, each with its `Route`.
+#[derive(Debug)]
+pub struct Dispatcher {
+    queue_capacity: usize,
+    // Of the running send tasks. Those of stopped ones are left out on the next packet.
+    queues: Mutex<Vec<Subscriber>>,
+    client_list: Arc<Clients>,
+    paths: Paths,
+    stats: Arc<Stats>,
+    flow_control: Arc<FlowControl>,
+    config: TaskConfig,
+    // Only failover mode needs to single out new flows, redundant mode duplicates everything
+    flow_tracker: Option<Mutex<FlowTracker>>
+}
+
+impl Dispatcher {
+    pub fn new(queue_capacity: usize, client_list: Arc<Clients>, paths: Paths, stats: Arc<Stats>, flow_control: Arc<FlowControl>, config: TaskConfig) -> Dispatcher {
+        let any_failover = config.path_mode == PathMode::Failover || config.peer_path_modes.values().any(|mode| *mode == PathMode::Failover);
+        let flow_tracker = config.new_flow_duplicate_packets
+            .filter(|_| any_failover)
+            .map(|packets| Mutex::new(FlowTracker::new(packets)));
+        Dispatcher { queue_capacity, queues: Mutex::new(Vec::new()), client_list, paths, stats, flow_control, config, flow_tracker }
+    }
+
+    /// A queue of the packets `path` sends, from now on until it is
+    /// dropped, for the send task of a socket of `families`. Closed by
+    /// `close`, or once the dispatcher is dropped.
+    pub fn subscribe(&self, path: Arc<Path>, families: Families) -> Arc<PathQueue> {
+        let queue = Arc::new(PathQueue::new(self.queue_capacity, path.clone()));
+        self.queues.lock().unwrap().push(Subscriber { queue: Arc::downgrade(&queue), path, families, ramp_credit: 0.0 });
+        queue
+    }
+
+    /// Route `packet`, read at `read_at` by the tunnel clock, and hand it to
+    /// the send tasks of the links sending it. Returns false if it was
+    /// dropped, or no send task took it, e.g. a reload removed every device.
+    pub fn dispatch(&self, packet: Packet, read_at: Instant) -> bool {
+        self.dispatch_with_epoch(packet, read_at, self.config.session_epoch)
+    }
+
+    /// Like `dispatch`, for a packet the hub forwards from a peer of
+    /// session `session_epoch`, which it keeps on its way to the next.
+    pub fn dispatch_forwarded(&self, packet: Packet, read_at: Instant, session_epoch: Option<u32>) -> bool {
+        self.dispatch_with_epoch(packet, read_at, session_epoch)
+    }
+
+    fn dispatch_with_epoch(&self, mut packet: Packet, read_at: Instant, session_epoch: Option<u32>) -> bool {
+        let parsed = match SlicedPacket::from_ip(&packet.bytes) {
+            Err(value) => Err(format!("{:?}", value)),
+            Ok(value) => {
+                let flow = FlowKey::from_packet(&value);
+                let new_flow = match (&self.flow_tracker, flow) {
+                    (Some(tracker), Some(flow)) => tracker.lock().unwrap().observe(flow, read_at),
+                    _ => false
+                };
+
+                match value.ip {
+                    Some(InternetSlice::Ipv4(ipheader)) => {
+                        Ok((IpAddr::V4(ipheader.destination_addr()), (ipheader.dcp() << 2) | ipheader.ecn(), new_flow, flow))
+                    },
+                    Some(InternetSlice::Ipv6(ipheader, _)) => {
+                        Ok((IpAddr::V6(ipheader.destination_addr()), ipheader.traffic_class(), new_flow, flow))
+                    },
+                    None => Err("no IP header".to_string())
+                }
+            }
+        };
+        // No destination means the packet goes to every known peer
+        let (destination, inner_tos, new_flow, flow) = match (parsed, self.config.unparseable_policy) {
+            (Ok((tun_ip, inner_tos, new_flow, flow)), _) => (Some(tun_ip), Some(inner_tos), new_flow, flow),
+            (Err(_), UnparseablePolicy::DropSilently) => return false,
+            (Err(err), UnparseablePolicy::DropAndCount) => {
+                eprintln!("Error extracting senders TUN IP: {}", err);
+                self.stats.tx_unparseable.fetch_add(1, Ordering::Relaxed);
+                return false
+            },
+            (Err(_), UnparseablePolicy::BroadcastToAllPeers) => (None, None, false, None)
+        };
+
+        // Packets without a destination can't be in the allowlist either
+        if let Some(allowed) = &self.config.allowed_destinations {
+            if !destination.is_some_and(|ip| allowed.iter().any(|cidr| cidr.contains(&ip))) {
+                self.stats.tx_filtered.fetch_add(1, Ordering::Relaxed);
+                return false
+            }
+        }
+
+        if self.config.decrement_ttl && destination.is_some() {
+            // The bytes may be shared with the TUN read buffer, change a copy
+            let mut bytes = packet.bytes.to_vec();
+            if !decrement_ttl(&mut bytes) {
+                self.stats.tx_ttl_expired.fetch_add(1, Ordering::Relaxed);
+                return false
+            }
+            packet.bytes = Bytes::from(bytes);
+        }
+
+        if let Some(rewritten) = self.config.source_nat.as_ref().and_then(|source_nat| source_nat.outbound(&packet.bytes)) {
+            packet.bytes = Bytes::from(rewritten);
+        }
+
+        let (peer, families) = match destination {
+            Some(destination) => {
+                let peer = match self.config.fallback_peer {
+                    Some(fallback) if !self.client_list.contains(&destination) => fallback,
+                    _ => destination
+                };
+                match self.client_list.with(&peer, Families::of_addrs) {
+                    // Return paths the peer announced may be of another family
+                    Some(families) => (Some(peer), families.union(self.config.return_paths.with(&peer, read_at, Families::of_addrs).unwrap_or(Families::NONE))),
+                    None => {
+                        eprintln!("I don't know any destinations for: {}. Perhaps it has not been discovered yet?", destination);
+                        return false
+                    }
+                }
+            },
+            None => {
+                let mut families = Families::NONE;
+                self.client_list.for_each(|peer, addrs| {
+                    // Sealed with the global key, which peers with their own don't accept
+                    if self.config.keys.id_for(peer) != KeyId::Peer(*peer) {
+                        families = families.union(Families::of_addrs(addrs));
+                    }
+                });
+                (None, families)
+            }
+        };
+
+        // Picked here once, so the links agree on who sends the packet. A
+        // peer with send paths only gets packets from those links, and the
+        // path mode picks among them alone.
+        let tun_ip = destination.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let mode = self.config.peer_path_modes.get(&tun_ip).copied().unwrap_or(self.config.path_mode);
+        // A peer that asked us to slow down gets one copy instead of one per link
+        let mode = match mode {
+            PathMode::Redundant | PathMode::Fastest if self.flow_control.is_throttled(&tun_ip, read_at) => PathMode::Failover,
+            mode => mode
+        };
+        let paths = self.paths.read().unwrap();
+        let send_paths = peer.and_then(|peer| self.config.peer_send_paths.get(&peer));
+        let sendable = path::sendable(&paths, send_paths.map(Vec::as_slice));
+        let chosen = match mode {
+            // The packet's length stands in for its datagrams', which are
+            // only encoded by the send task
+            PathMode::Failover => path::active_path_with_budget(&sendable, packet.bytes.len(), read_at),
+            PathMode::Weighted => path::weighted_path(&sendable),
+            // Packets without a 5-tuple, like IPv6 ones, take the active link
+            PathMode::FlowHash => match &flow {
+                Some(flow) => path::flow_path(&sendable, flow),
+                None => path::active_path(&sendable)
+            },
+            PathMode::Redundant | PathMode::Fastest => None
+        };
+        let selection = Selection { mode, sendable: &sendable, chosen, new_flow, seq: packet.seq, read_at };
+
+        let route = Route { destination, peer, inner_tos, flow, session_epoch };
+        let mut queued = false;
+        self.queues.lock().unwrap().retain_mut(|subscriber| {
+            let Some(queue) = subscriber.queue.upgrade() else { return false };
+            if !subscriber.families.overlaps(&families) {
+                return true
+            }
+            if let Some(backup_at) = selection.send_on(&subscriber.path, &mut subscriber.ramp_credit, &self.config) {
+                queue.push(TunPacket { packet: packet.clone(), read_at, route, backup_at });
+                queued = true;
+            }
+            true
+        });
+        queued
+    }
+
+    /// Close the send tasks' queues, so each stops once it has sent what is
+    /// queued. Packets dispatched afterwards are still queued.
+    pub fn close(&self) {
+        for queue in self.queues.lock().unwrap().iter().filter_map(|subscriber| subscriber.queue.upgrade()) {
+            queue.close();
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Decrement the TTL / hop limit of an IP packet already parsed by etherparse,
+// updating the IPv4 header checksum. Returns false if it would reach zero.
+fn decrement_ttl(packet: &mut [u8]) -> bool {
+    match packet[0] >> 4 {
+        4 => {
+            if packet[8] <= 1 {
+                return false
+            }
+            packet[8] -= 1;
+            let header_len = usize::from(packet[0] & 0xf) * 4;
+            packet[10..12].copy_from_slice(&[0, 0]);
+            let checksum = ipfrag::header_checksum(&packet[..header_len]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            true
+        },
+        6 => {
+            if packet[7] <= 1 {
+                return false
+            }
+            packet[7] -= 1;
+            true
+        },
+        _ => true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+    use std::time::Duration;
+    use etherparse::PacketBuilder;
+    use crate::multipathtunnel::Multipathtunnel;
+    use crate::settings::{SendDevice, SettingsFileBuilder};
+    use super::*;
+
+    const PEER: [u8; 4] = [10, 0, 0, 2];
+    const V4: Families = Families { v4: true, v6: false };
+
+    fn link(iface: &str, priority: u8, send_fraction: f64) -> Arc<Path> {
+        Arc::new(Path::new(iface.to_string(), "127.0.0.1:0".parse().unwrap(), priority, 1.0, send_fraction, None, None))
+    }
+
+    fn links(count: u8) -> Vec<Arc<Path>> {
+        (0..count).map(|index| link(&format!("link{}", index), index, 1.0)).collect()
+    }
+
+    // A dispatcher for `paths` with one queue each, and the peer known
+    fn routing(paths: &[Arc<Path>], configure: impl FnOnce(&mut TaskConfig)) -> (Dispatcher, Vec<Arc<PathQueue>>) {
+        let settings = SettingsFileBuilder::new([10, 0, 0, 1].into())
+            .add_send_device(SendDevice::new([127, 0, 0, 1].into(), 0))
+            .build()
+            .unwrap();
+        let mut config = Multipathtunnel::new(settings.clone()).unwrap().task_config(&settings);
+        configure(&mut config);
+        let client_list = Arc::new(Clients::default());
+        client_list.upsert(PEER.into(), None, |addrs, _| addrs.push("127.0.0.1:5000".parse().unwrap()));
+        let dispatcher = Dispatcher::new(64, client_list, Arc::new(RwLock::new(paths.to_vec())), Arc::default(), Arc::new(FlowControl::new(None)), config);
+        let queues = paths.iter().map(|path| dispatcher.subscribe(path.clone(), V4)).collect();
+        (dispatcher, queues)
+    }
+
+    // A UDP packet for the peer, its flow told apart by the source port
+    fn packet(seq: usize, source_port: u16) -> Packet {
+        let mut bytes = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], PEER, 64).udp(source_port, 5000).write(&mut bytes, b"payload").unwrap();
+        Packet { seq, bytes: Bytes::from(bytes) }
+    }
+
+    fn dispatch_all(dispatcher: &Dispatcher, count: usize, source_port: impl Fn(usize) -> u16) {
+        let now = Instant::now();
+        for seq in 0..count {
+            assert!(dispatcher.dispatch(packet(seq, source_port(seq)), now));
+        }
+    }
+
+    // The packets each queue holds, taken out of it
+    fn drained(queues: &[Arc<PathQueue>]) -> Vec<Vec<TunPacket>> {
+        queues.iter().map(|queue| std::iter::from_fn(|| queue.try_pop()).collect()).collect()
+    }
+
+    fn counts(queues: &[Arc<PathQueue>]) -> Vec<usize> {
+        drained(queues).iter().map(Vec::len).collect()
+    }
+
+    #[tokio::test]
+    async fn failover_queues_packets_for_the_active_link_only() {
+        let paths = links(3);
+        let (dispatcher, queues) = routing(&paths, |config| config.path_mode = PathMode::Failover);
+        dispatch_all(&dispatcher, 10, |_| 4000);
+        assert_eq!(counts(&queues), [10, 0, 0]);
+
+        let at = Instant::now();
+        paths[0].ping_sent(at);
+        assert!(paths[0].check_timeout(at + Duration::from_secs(10), Duration::from_secs(3)));
+        dispatch_all(&dispatcher, 10, |_| 4000);
+        assert_eq!(counts(&queues), [0, 10, 0]);
+    }
+
+    #[tokio::test]
+    async fn failover_queues_the_first_packets_of_a_new_flow_for_every_link() {
+        let paths = links(3);
+        let (dispatcher, queues) = routing(&paths, |config| {
+            config.path_mode = PathMode::Failover;
+            config.new_flow_duplicate_packets = Some(2);
+        });
+        dispatch_all(&dispatcher, 5, |_| 4000);
+        assert_eq!(counts(&queues), [5, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn redundant_queues_for_every_link_that_sends_the_packet() {
+        let paths = vec![link("fiber", 0, 1.0), link("lte", 1, 0.5), link("paused", 2, 1.0)];
+        paths[2].set_paused(true);
+        let (dispatcher, queues) = routing(&paths, |config| config.path_mode = PathMode::Redundant);
+        dispatch_all(&dispatcher, 10, |_| 4000);
+        assert_eq!(counts(&queues), [10, 5, 0]);
+
+        let paths = links(3);
+        let (dispatcher, queues) = routing(&paths, |config| {
+            config.path_mode = PathMode::Redundant;
+            config.redundancy = Some(2);
+        });
+        dispatch_all(&dispatcher, 10, |_| 4000);
+        assert_eq!(counts(&queues), [10, 10, 0]);
+    }
+
+    #[tokio::test]
+    async fn weighted_and_flow_hash_queue_each_packet_for_one_link() {
+        let paths = links(2);
+        let (dispatcher, queues) = routing(&paths, |config| config.path_mode = PathMode::Weighted);
+        dispatch_all(&dispatcher, 10, |_| 4000);
+        assert_eq!(counts(&queues), [5, 5]);
+
+        let (dispatcher, queues) = routing(&paths, |config| config.path_mode = PathMode::FlowHash);
+        for flow in 0..20 {
+            dispatch_all(&dispatcher, 3, |_| 4000 + flow);
+            let counts = counts(&queues);
+            assert_eq!(counts.iter().sum::<usize>(), 3);
+            assert!(counts.contains(&3), "flow {} split over the links: {:?}", flow, counts);
+        }
+    }
+
+    #[tokio::test]
+    async fn fastest_queues_backups_with_when_they_are_due() {
+        let paths = links(2);
+        let delay = Duration::from_millis(50);
+        let (dispatcher, queues) = routing(&paths, |config| {
+            config.path_mode = PathMode::Fastest;
+            config.backup_delay = Some(delay);
+        });
+        let read_at = Instant::now();
+        assert!(dispatcher.dispatch(packet(0, 4000), read_at));
+        let drained = drained(&queues);
+        assert_eq!(drained[0].iter().map(|packet| packet.backup_at).collect::<Vec<_>>(), [None]);
+        assert_eq!(drained[1].iter().map(|packet| packet.backup_at).collect::<Vec<_>>(), [Some(read_at + delay)]);
+
+        let (dispatcher, queues) = routing(&paths, |config| {
+            config.path_mode = PathMode::Fastest;
+            config.backup_delay = None;
+        });
+        dispatch_all(&dispatcher, 10, |_| 4000);
+        assert_eq!(counts(&queues), [10, 0]);
+    }
+
+    #[tokio::test]
+    async fn links_that_reach_no_address_of_the_peer_get_nothing() {
+        let paths = links(1);
+        let (dispatcher, _) = routing(&[], |config| config.path_mode = PathMode::Redundant);
+        dispatcher.paths.write().unwrap().push(paths[0].clone());
+        let v6 = dispatcher.subscribe(paths[0].clone(), Families { v4: false, v6: true });
+        assert!(!dispatcher.dispatch(packet(0, 4000), Instant::now()));
+        assert!(v6.try_pop().is_none());
+    }
+
+    fn ipv4_packet(ttl: u8) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], ttl).udp(4000, 5000).write(&mut packet, b"payload").unwrap();
+        packet
+    }
+
+    #[test]
+    fn ipv4_ttl_is_decremented_with_a_valid_checksum() {
+        let mut packet = ipv4_packet(10);
+        assert!(decrement_ttl(&mut packet));
+
+        assert_eq!(packet[8], 9);
+        // A header with a correct checksum sums to zero
+        assert_eq!(ipfrag::header_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[20..], &ipv4_packet(10)[20..]);
+    }
+
+    #[test]
+    fn ipv4_packets_with_ttl_one_are_dropped() {
+        let mut packet = ipv4_packet(1);
+        assert!(!decrement_ttl(&mut packet));
+        assert_eq!(packet, ipv4_packet(1));
+    }
+
+    #[test]
+    fn ipv6_hop_limit_is_decremented() {
+        let packet = |hop_limit| {
+            let mut packet = Vec::new();
+            PacketBuilder::ipv6([0xfd; 16], [0xfe; 16], hop_limit).udp(4000, 5000).write(&mut packet, b"payload").unwrap();
+            packet
+        };
+        let mut forwarded = packet(10);
+        assert!(decrement_ttl(&mut forwarded));
+        assert_eq!(forwarded, packet(9));
+
+        assert!(!decrement_ttl(&mut packet(1)));
+    }
+}
@@ -0,0 +1,76 @@
+// Loss made on purpose, for testing how the peers and the redundancy
+// features cope with it without a lossy network. Each send task decides per
+// packet and peer address, after pacing and rate limits, so a dropped
+// packet used up the link as if it was lost on the way. The random drops
+// come from a seeded generator, so a run with the same traffic drops the
+// same packets.
+
+use crate::settings::DropInjectionSettings;
+
+#[derive(Debug)]
+pub struct DropInjector {
+    every: Option<u64>,
+    probability: f64,
+    // Packets decided on so far
+    packets: u64,
+    // SplitMix64 state
+    state: u64
+}
+
+impl DropInjector {
+    pub fn new(settings: &DropInjectionSettings) -> DropInjector {
+        DropInjector {
+            every: settings.every,
+            probability: settings.probability.unwrap_or(0.0),
+            packets: 0,
+            state: settings.seed.unwrap_or(0)
+        }
+    }
+
+    /// Whether to drop the next packet.
+    pub fn drop_next(&mut self) -> bool {
+        self.packets += 1;
+        if self.every.is_some_and(|every| self.packets.is_multiple_of(every)) {
+            return true
+        }
+        self.probability > 0.0 && self.next_f64() < self.probability
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dropped(settings: DropInjectionSettings, packets: u64) -> Vec<u64> {
+        let mut injector = DropInjector::new(&settings);
+        (1..=packets).filter(|_| injector.drop_next()).collect()
+    }
+
+    #[test]
+    fn every_nth_packet_is_dropped() {
+        let every_third = DropInjectionSettings { every: Some(3), probability: None, seed: None };
+        assert_eq!(dropped(every_third, 10), [3, 6, 9]);
+    }
+
+    #[test]
+    fn random_drops_repeat_with_their_seed() {
+        let seeded = |seed| DropInjectionSettings { every: None, probability: Some(0.25), seed: Some(seed) };
+        let drops = dropped(seeded(7), 4000);
+        assert_eq!(drops, dropped(seeded(7), 4000));
+        assert_ne!(drops, dropped(seeded(8), 4000));
+        assert!((900..1100).contains(&drops.len()), "{} dropped", drops.len());
+
+        assert!(dropped(DropInjectionSettings { every: None, probability: Some(0.0), seed: None }, 1000).is_empty());
+        assert_eq!(dropped(DropInjectionSettings { every: None, probability: Some(1.0), seed: None }, 1000).len(), 1000);
+    }
+}
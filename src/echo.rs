@@ -0,0 +1,94 @@
+// On demand checks that a link carries traffic both ways. An echo request
+// with a fresh tag goes out over one link, the peer sends it straight back
+// from the socket it arrived on, and the round trip is timed. Unlike
+// keep-alive replies, echoes are matched to their request, so the result
+// is the link's alone.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Outcome of `Multipathtunnel::probe_path`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    // The peer at `from` echoed the request back over the link
+    Echoed { from: SocketAddr, rtt: Duration },
+    // No echo came back over the link in time
+    TimedOut,
+    // There is no send device of that name
+    UnknownPath,
+    // No known peer address is reachable from the link
+    NoPeer
+}
+
+#[derive(Debug)]
+struct Waiting {
+    iface: String,
+    reply: oneshot::Sender<(SocketAddr, Instant)>
+}
+
+/// Echo requests waiting for their reply.
+#[derive(Debug, Default)]
+pub struct Echoes {
+    next_tag: AtomicU64,
+    waiting: Mutex<HashMap<u64, Waiting>>
+}
+
+impl Echoes {
+    /// A tag for an echo request over the link `iface`, and where its reply's
+    /// sender and arrival time turn up.
+    pub fn register(&self, iface: &str) -> (u64, oneshot::Receiver<(SocketAddr, Instant)>) {
+        let tag = self.next_tag.fetch_add(1, Ordering::Relaxed);
+        let (reply, received) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(tag, Waiting { iface: iface.to_string(), reply });
+        (tag, received)
+    }
+
+    /// Match the reply `tag` received on `iface` from `from`. Replies that
+    /// came back over another link, or after their request was given up,
+    /// don't count. Returns false for those.
+    pub fn answered(&self, tag: u64, iface: &str, from: SocketAddr, at: Instant) -> bool {
+        let mut waiting = self.waiting.lock().unwrap();
+        match waiting.get(&tag) {
+            Some(request) if request.iface == iface => {},
+            _ => return false
+        }
+        let request = waiting.remove(&tag).unwrap();
+        request.reply.send((from, at)).is_ok()
+    }
+
+    /// Give up waiting for the reply to `tag`.
+    pub fn cancel(&self, tag: u64) {
+        self.waiting.lock().unwrap().remove(&tag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_count_only_for_their_request_and_link() {
+        let echoes = Echoes::default();
+        let (from, at) = ("127.0.0.1:5000".parse().unwrap(), Instant::now());
+        let (first, mut first_reply) = echoes.register("fiber");
+        let (second, _second_reply) = echoes.register("lte");
+        assert_ne!(first, second);
+
+        // Back over another link, or for no request at all
+        assert!(!echoes.answered(first, "lte", from, at));
+        assert!(!echoes.answered(u64::MAX, "fiber", from, at));
+        assert!(first_reply.try_recv().is_err());
+
+        assert!(echoes.answered(first, "fiber", from, at));
+        assert_eq!(first_reply.try_recv().unwrap(), (from, at));
+        // Each request is answered once
+        assert!(!echoes.answered(first, "fiber", from, at));
+
+        echoes.cancel(second);
+        assert!(!echoes.answered(second, "lte", from, at));
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::task::JoinError;
+use crate::cidr::Cidr;
+
+/// Settings that are well formed but don't make sense together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsError {
+    NoSendDevices,
+    // keep_alive is on without keep_alive_interval or keep_alive_interval_ms
+    MissingKeepAliveInterval,
+    // The keep-alive interval is 0, which would send keep-alives in a busy loop
+    ZeroKeepAliveInterval,
+    // snapshot.interval is 0, which would write snapshots in a busy loop
+    ZeroSnapshotInterval,
+    // remote_resolve_interval is 0, which would resolve remote_host in a busy loop
+    ZeroResolveInterval,
+    // A path_health count is 0, a link must miss or answer at least one keep-alive to change
+    ZeroPathHealthCount(&'static str),
+    // A port that is sent to is 0
+    ZeroPort(&'static str),
+    // No send device has the address family of this remote address
+    UnreachableFamily(SocketAddr),
+    // redundancy is 0, which would send nothing
+    ZeroRedundancy,
+    // udp_listen_port and udp_listen_port_max don't make a range of ports
+    BadPortRange(u16, u16),
+    // A weight is not above 0, or the auto_weights bounds or adapt rate are out of range
+    BadWeight(&'static str),
+    // mirror is set without a collector or pcap file
+    EmptyMirror,
+    // A source_nat mapping between subnets of different families or prefix lengths
+    MismatchedSourceNat(Cidr, Cidr),
+    // peer_paths names a send device that doesn't exist
+    UnknownDevice(String),
+    // A send device has drop_injection without allow_drop_injection
+    DropInjectionNotAllowed(String),
+    // A send device's drop_injection drops every 0th packet, or has a probability outside 0 to 1
+    BadDropInjection(String),
+    // max_payload_len is below the TUN MTU, so full sized packets would be refused
+    MaxPayloadBelowMtu(usize, usize),
+    // A send device's send_fraction is outside 0 to 1
+    BadSendFraction(String),
+    // A send device's cpus is empty, leaving its tasks nowhere to run
+    NoCpus(String)
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::NoSendDevices => write!(f, "at least one send device is required"),
+            SettingsError::MissingKeepAliveInterval => write!(f, "keep_alive requires keep_alive_interval or keep_alive_interval_ms"),
+            SettingsError::ZeroKeepAliveInterval => write!(f, "the keep-alive interval must be at least 1"),
+            SettingsError::ZeroSnapshotInterval => write!(f, "snapshot.interval must be at least 1"),
+            SettingsError::ZeroResolveInterval => write!(f, "remote_resolve_interval must be at least 1"),
+            SettingsError::ZeroPathHealthCount(field) => write!(f, "path_health.{} must be at least 1", field),
+            SettingsError::ZeroPort(field) => write!(f, "{} must not be 0", field),
+            SettingsError::UnreachableFamily(addr) => write!(f, "no send device has the address family of remote address {}", addr),
+            SettingsError::ZeroRedundancy => write!(f, "redundancy must be at least 1"),
+            SettingsError::BadPortRange(first, last) => write!(f, "listen ports {} to {} are not a range, the first must be at least 1 and at most the last", first, last),
+            SettingsError::BadWeight(field) => write!(f, "{} is out of range: weights must be above 0, min_weight at most max_weight, and adapt_rate above 0 and at most 1", field),
+            SettingsError::EmptyMirror => write!(f, "mirror requires a collector or a pcap file"),
+            SettingsError::MismatchedSourceNat(original, mapped) => write!(f, "source_nat can't map {} to {}, the subnets must be of one family and prefix length", original, mapped),
+            SettingsError::UnknownDevice(name) => write!(f, "peer_paths names {}, which is not a send device", name),
+            SettingsError::DropInjectionNotAllowed(name) => write!(f, "send device {} has drop_injection, which needs allow_drop_injection", name),
+            SettingsError::BadDropInjection(name) => write!(f, "drop_injection of send device {} is out of range: every must be at least 1 and probability from 0 to 1", name),
+            SettingsError::MaxPayloadBelowMtu(max, mtu) => write!(f, "max_payload_len of {} is below the TUN MTU of {}", max, mtu),
+            SettingsError::BadSendFraction(name) => write!(f, "send_fraction of send device {} must be from 0 to 1", name),
+            SettingsError::NoCpus(name) => write!(f, "cpus of send device {} must list at least one CPU", name)
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// Errors setting up a tunnel.
+#[derive(Debug)]
+pub enum TunnelError {
+    Settings(SettingsError),
+    // The encryption settings don't yield a usable key
+    InvalidKey(String),
+    KeyFile { path: PathBuf, source: io::Error },
+    // The TUN device couldn't be created
+    Tun(Box<dyn std::error::Error + Send + Sync>),
+    // A tunnel task panicked. Holds how every task ended.
+    TasksFailed(Vec<TaskReport>),
+    // Not one send device could be bound. Holds why each failed.
+    NoDeviceBound(Vec<io::Error>)
+}
+
+impl std::fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelError::Settings(err) => write!(f, "invalid settings: {}", err),
+            TunnelError::InvalidKey(reason) => write!(f, "invalid encryption key: {}", reason),
+            TunnelError::KeyFile { path, source } => write!(f, "failed to read key file `{}`: {}", path.display(), source),
+            TunnelError::Tun(err) => write!(f, "failed to create TUN device: {}", err),
+            TunnelError::TasksFailed(reports) => {
+                write!(f, "tunnel tasks failed:")?;
+                for report in reports {
+                    write!(f, " {}: {};", report.task, report.outcome)?;
+                }
+                Ok(())
+            },
+            TunnelError::NoDeviceBound(errors) => {
+                write!(f, "no send device could be bound:")?;
+                for err in errors {
+                    write!(f, " {};", err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for TunnelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TunnelError::Settings(err) => Some(err),
+            TunnelError::KeyFile { source, .. } => Some(source),
+            TunnelError::Tun(err) => Some(err.as_ref()),
+            _ => None
+        }
+    }
+}
+
+impl From<SettingsError> for TunnelError {
+    fn from(err: SettingsError) -> TunnelError {
+        TunnelError::Settings(err)
+    }
+}
+
+/// How a tunnel task ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskOutcome {
+    Finished,
+    Cancelled,
+    Panicked(String)
+}
+
+impl From<Result<(), JoinError>> for TaskOutcome {
+    fn from(result: Result<(), JoinError>) -> TaskOutcome {
+        match result {
+            Ok(()) => TaskOutcome::Finished,
+            Err(err) if err.is_cancelled() => TaskOutcome::Cancelled,
+            Err(err) => {
+                let panic = err.into_panic();
+                let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                TaskOutcome::Panicked(message)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TaskOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskOutcome::Finished => write!(f, "finished"),
+            TaskOutcome::Cancelled => write!(f, "cancelled"),
+            TaskOutcome::Panicked(message) => write!(f, "panicked: {}", message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskReport {
+    pub task: &'static str,
+    pub outcome: TaskOutcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn outcomes_tell_finished_cancelled_and_panicked_apart() {
+        assert_eq!(TaskOutcome::from(tokio::spawn(async {}).await), TaskOutcome::Finished);
+
+        let pending = tokio::spawn(std::future::pending::<()>());
+        pending.abort();
+        assert_eq!(TaskOutcome::from(pending.await), TaskOutcome::Cancelled);
+
+        let literal = tokio::spawn(async { panic!("literal") });
+        assert_eq!(TaskOutcome::from(literal.await), TaskOutcome::Panicked("literal".to_string()));
+        let formatted = tokio::spawn(async { panic!("formatted {}", 1) });
+        assert_eq!(TaskOutcome::from(formatted.await), TaskOutcome::Panicked("formatted 1".to_string()));
+        let other = tokio::spawn(async { std::panic::panic_any(7) });
+        assert_eq!(TaskOutcome::from(other.await), TaskOutcome::Panicked("unknown panic".to_string()));
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::sync::broadcast;
+
+// Events buffered per subscriber before the oldest are dropped
+pub const PACKET_EVENTS_CAPACITY: usize = 1024;
+pub const EVENTS_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendResult {
+    Sent(usize),
+    Failed(io::ErrorKind)
+}
+
+/// A send decision made by `send_udp`, one per packet and target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketEvent {
+    pub seq: usize,
+    // Size of the inner packet
+    pub size: usize,
+    // Unspecified for packets broadcast because their destination couldn't be parsed
+    pub tun_ip: IpAddr,
+    pub destination: SocketAddr,
+    // Name of the send device the packet went out on
+    pub path: String,
+    pub result: SendResult
+}
+
+/// Sender side of the packet event stream. Backed by a broadcast channel, so a
+/// lagging subscriber loses the oldest events instead of slowing the data path.
+#[derive(Debug, Clone)]
+pub struct PacketEvents {
+    sender: broadcast::Sender<PacketEvent>
+}
+
+impl PacketEvents {
+    pub fn new(capacity: usize) -> PacketEvents {
+        let (sender, _) = broadcast::channel(capacity);
+        PacketEvents { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PacketEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Whether anyone is listening. Lets callers skip building events.
+    pub fn is_observed(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn emit(&self, event: PacketEvent) {
+        // Only fails when there are no subscribers
+        let _ = self.sender.send(event);
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    // Payload larger than the configured maximum
+    Oversized,
+    // Datagram that couldn't be decoded
+    Malformed,
+    // Payload that couldn't be decompressed
+    Decompress,
+    // Packet from the TUN over the MTU that couldn't be fragmented
+    TunOversized,
+    // The sending peer's inbound queue was full
+    InboundQueueFull,
+    // An unknown peer while max_clients peers are known
+    ClientLimit,
+    // A source address on the blocklist
+    Blocked,
+    // A datagram whose checksum didn't match, with checksum set
+    BadChecksum,
+    // An encrypted datagram received before
+    Replayed
+}
+
+/// Lifecycle events of the tunnel, the consumable form of its log messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // A peer, or a new address of a known peer, was learned
+    ClientDiscovered { tun_ip: IpAddr, addr: SocketAddr },
+    // A peer address was dropped, e.g. after the peer moved
+    ClientExpired { tun_ip: IpAddr, addr: SocketAddr },
+    PathDown { iface: String },
+    PathUp { iface: String },
+    PacketDropped { reason: DropReason },
+    // Reading or writing the TUN failed for good, the tunnel is shutting down
+    TunDown { error: String },
+    // The TUN MTU was changed underneath the tunnel, e.g. with `ip link set mtu`
+    TunMtuChanged { mtu: usize }
+}
+
+/// Sender side of the lifecycle event stream. Lossy like `PacketEvents`.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>
+}
+
+impl Events {
+    pub fn new(capacity: usize) -> Events {
+        let (sender, _) = broadcast::channel(capacity);
+        Events { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Only fails when there are no subscribers
+        let _ = self.sender.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn event(seq: usize) -> PacketEvent {
+        PacketEvent {
+            seq,
+            size: 100,
+            tun_ip: [10, 0, 0, 2].into(),
+            destination: "127.0.0.1:4000".parse().unwrap(),
+            path: "eth0".to_string(),
+            result: SendResult::Sent(120)
+        }
+    }
+
+    #[test]
+    fn lagging_subscriber_loses_the_oldest_events() {
+        let events = PacketEvents::new(4);
+        let mut slow = events.subscribe();
+        // Never blocks, however far behind the subscriber is
+        for seq in 0..10 {
+            events.emit(event(seq));
+        }
+
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Lagged(6)));
+        for seq in 6..10 {
+            assert_eq!(slow.try_recv().unwrap().seq, seq);
+        }
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn events_are_only_observed_while_subscribed() {
+        let events = PacketEvents::new(4);
+        assert!(!events.is_observed());
+        // Dropped without a subscriber, and doesn't fail
+        events.emit(event(0));
+
+        let mut subscriber = events.subscribe();
+        assert!(events.is_observed());
+        events.emit(event(1));
+        assert_eq!(subscriber.try_recv().unwrap(), event(1));
+
+        drop(subscriber);
+        assert!(!events.is_observed());
+    }
+}
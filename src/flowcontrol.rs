@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Longest a peer can make us slow down for with one request
+const MAX_SLOW_DOWN: Duration = Duration::from_secs(60);
+
+/// When to ask a peer to slow down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowDownConfig {
+    // Drops of the peer's packets within one window that trigger a request
+    pub threshold: u32,
+    pub window: Duration,
+    // How long the peer is asked to slow down for
+    pub duration: Duration
+}
+
+#[derive(Debug)]
+struct DropWindow {
+    start: Instant,
+    drops: u32,
+    signalled: bool
+}
+
+/// Feedback between peers about inbound overload. Counts the packets of
+/// each peer dropped here, and tracks the peers that asked us to slow down.
+#[derive(Debug, Default)]
+pub struct FlowControl {
+    // Unset when this side never asks peers to slow down
+    slow_down: Option<SlowDownConfig>,
+    drops: Mutex<HashMap<IpAddr, DropWindow>>,
+    // Peers that asked us to slow down, until when
+    throttled: RwLock<HashMap<IpAddr, Instant>>
+}
+
+impl FlowControl {
+    pub fn new(slow_down: Option<SlowDownConfig>) -> FlowControl {
+        FlowControl { slow_down, ..FlowControl::default() }
+    }
+
+    /// Count a packet from `tun_ip` dropped for want of queue space. Returns
+    /// how long to ask the peer to slow down for when the drops cross the
+    /// threshold, at most once per window.
+    pub fn dropped(&self, tun_ip: IpAddr, now: Instant) -> Option<Duration> {
+        let config = self.slow_down?;
+        let mut drops = self.drops.lock().unwrap();
+        // Windows are only looked at while drops happen, so old ones are cleared out here
+        drops.retain(|_, window| now.saturating_duration_since(window.start) < config.window);
+        let window = drops.entry(tun_ip).or_insert(DropWindow { start: now, drops: 0, signalled: false });
+        window.drops += 1;
+        if window.signalled || window.drops < config.threshold {
+            return None
+        }
+        window.signalled = true;
+        Some(config.duration)
+    }
+
+    /// Slow down sending to `tun_ip` for `duration`, capped at a minute.
+    pub fn throttle(&self, tun_ip: IpAddr, duration: Duration, now: Instant) {
+        let mut throttled = self.throttled.write().unwrap();
+        throttled.retain(|_, until| *until > now);
+        throttled.insert(tun_ip, now + duration.min(MAX_SLOW_DOWN));
+    }
+
+    /// Whether `tun_ip` asked us to slow down and the time hasn't run out yet.
+    pub fn is_throttled(&self, tun_ip: &IpAddr, now: Instant) -> bool {
+        self.throttled.read().unwrap().get(tun_ip).is_some_and(|until| now < *until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: SlowDownConfig = SlowDownConfig { threshold: 3, window: Duration::from_secs(1), duration: Duration::from_secs(2) };
+
+    fn peer() -> IpAddr {
+        [10, 0, 0, 1].into()
+    }
+
+    #[test]
+    fn drops_past_the_threshold_signal_once_per_window() {
+        let flow_control = FlowControl::new(Some(CONFIG));
+        let start = Instant::now();
+        assert_eq!(flow_control.dropped(peer(), start), None);
+        assert_eq!(flow_control.dropped(peer(), start), None);
+        assert_eq!(flow_control.dropped(peer(), start), Some(CONFIG.duration));
+        assert_eq!(flow_control.dropped(peer(), start + Duration::from_millis(500)), None);
+
+        // A new window counts from zero again
+        let next = start + CONFIG.window;
+        assert_eq!(flow_control.dropped(peer(), next), None);
+        assert_eq!(flow_control.dropped(peer(), next), None);
+        assert_eq!(flow_control.dropped(peer(), next), Some(CONFIG.duration));
+    }
+
+    #[test]
+    fn drops_are_counted_per_peer() {
+        let flow_control = FlowControl::new(Some(CONFIG));
+        let now = Instant::now();
+        flow_control.dropped(peer(), now);
+        flow_control.dropped(peer(), now);
+        assert_eq!(flow_control.dropped([10, 0, 0, 3].into(), now), None);
+    }
+
+    #[test]
+    fn without_a_config_no_peer_is_asked() {
+        let flow_control = FlowControl::new(None);
+        let now = Instant::now();
+        assert!((0..100).all(|_| flow_control.dropped(peer(), now).is_none()));
+    }
+
+    #[test]
+    fn throttling_runs_out_and_is_capped() {
+        let flow_control = FlowControl::new(None);
+        let now = Instant::now();
+        flow_control.throttle(peer(), Duration::from_secs(2), now);
+        assert!(flow_control.is_throttled(&peer(), now + Duration::from_secs(1)));
+        assert!(!flow_control.is_throttled(&peer(), now + Duration::from_secs(2)));
+        assert!(!flow_control.is_throttled(&[10, 0, 0, 3].into(), now));
+
+        flow_control.throttle(peer(), Duration::from_secs(3600), now);
+        assert!(flow_control.is_throttled(&peer(), now + MAX_SLOW_DOWN - Duration::from_secs(1)));
+        assert!(!flow_control.is_throttled(&peer(), now + MAX_SLOW_DOWN));
+    }
+}
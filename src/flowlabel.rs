@@ -0,0 +1,154 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+use serde::Deserialize;
+
+use crate::flows::FlowKey;
+
+const FLOW_LABEL_MASK: u32 = 0x000f_ffff;
+
+// Flow label manager request, from linux/in6.h
+#[repr(C)]
+struct FlowLabelReq {
+    dst: libc::in6_addr,
+    label: u32,
+    action: u8,
+    share: u8,
+    flags: u16,
+    expires: u16,
+    linger: u16,
+    pad: u32
+}
+
+const IPV6_FL_A_GET: u8 = 0;
+const IPV6_FL_F_CREATE: u16 = 1;
+// Shared with the other send sockets of this process
+const IPV6_FL_S_PROCESS: u8 = 2;
+
+/// Flow label put on outer IPv6 datagrams.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowLabelMode {
+    // The same label on every datagram
+    Fixed(u32),
+    // A label hashed from the inner 5-tuple, stable per inner flow
+    InnerFlow
+}
+
+/// The 20 bit label for a datagram. `None` means no label is set.
+pub fn label_for(mode: FlowLabelMode, flow: Option<&FlowKey>) -> Option<u32> {
+    let label = match (mode, flow) {
+        (FlowLabelMode::Fixed(label), _) => label & FLOW_LABEL_MASK,
+        (FlowLabelMode::InnerFlow, Some(flow)) => {
+            let mut hasher = DefaultHasher::new();
+            flow.hash(&mut hasher);
+            // 0 means "no label", so keep hashed labels away from it
+            (hasher.finish() as u32 & FLOW_LABEL_MASK).max(1)
+        },
+        (FlowLabelMode::InnerFlow, None) => return None
+    };
+
+    if label == 0 { None } else { Some(label) }
+}
+
+/// Let datagrams sent on `socket` carry the flow label of their destination address.
+pub fn enable_flowinfo_send(socket: &impl AsRawFd) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    setsockopt(socket, libc::IPV6_FLOWINFO_SEND, &on)
+}
+
+/// Linux only sends labels the socket holds a lease on. Take one for `label` toward `dst`.
+pub fn lease(socket: &impl AsRawFd, dst: Ipv6Addr, label: u32) -> io::Result<()> {
+    let req = FlowLabelReq {
+        dst: libc::in6_addr { s6_addr: dst.octets() },
+        label: label.to_be(),
+        action: IPV6_FL_A_GET,
+        share: IPV6_FL_S_PROCESS,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0
+    };
+
+    match setsockopt(socket, libc::IPV6_FLOWLABEL_MGR, &req) {
+        // Already leased by this socket
+        Err(err) if err.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+        result => result
+    }
+}
+
+/// `addr` with its flow info set to `label`, for IPv6 destinations.
+pub fn with_label(addr: SocketAddr, label: u32) -> SocketAddr {
+    match addr {
+        // The kernel reads sin6_flowinfo in network byte order
+        SocketAddr::V6(v6) => SocketAddr::V6(SocketAddrV6::new(*v6.ip(), v6.port(), label.to_be(), v6.scope_id())),
+        v4 => v4
+    }
+}
+
+fn setsockopt<T>(socket: &impl AsRawFd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            option,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t
+        )
+    };
+
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(source_port: u16) -> FlowKey {
+        FlowKey {
+            source: [10, 0, 0, 1].into(),
+            destination: [10, 0, 0, 2].into(),
+            protocol: 17,
+            source_port,
+            destination_port: 53
+        }
+    }
+
+    #[test]
+    fn fixed_label_is_masked_to_20_bits() {
+        assert_eq!(label_for(FlowLabelMode::Fixed(0x12345), None), Some(0x12345));
+        assert_eq!(label_for(FlowLabelMode::Fixed(0xf12345), Some(&flow(1))), Some(0x12345));
+        // Only zero bits left, so no label
+        assert_eq!(label_for(FlowLabelMode::Fixed(0x100000), None), None);
+    }
+
+    #[test]
+    fn inner_flow_labels_are_stable_per_flow() {
+        let label = label_for(FlowLabelMode::InnerFlow, Some(&flow(1000))).unwrap();
+        assert!(label > 0 && label <= FLOW_LABEL_MASK);
+        assert_eq!(label_for(FlowLabelMode::InnerFlow, Some(&flow(1000))), Some(label));
+
+        let labels: std::collections::HashSet<u32> = (0..100)
+            .filter_map(|port| label_for(FlowLabelMode::InnerFlow, Some(&flow(port))))
+            .collect();
+        assert!(labels.len() > 90, "{} distinct labels", labels.len());
+        // Packets the flow of which isn't known go unlabeled
+        assert_eq!(label_for(FlowLabelMode::InnerFlow, None), None);
+    }
+
+    #[test]
+    fn labels_go_into_ipv6_flow_info_in_network_order() {
+        let v6: SocketAddr = "[::1]:4000".parse().unwrap();
+        match with_label(v6, 0x12345) {
+            SocketAddr::V6(labeled) => {
+                assert_eq!(u32::from_be(labeled.flowinfo()), 0x12345);
+                assert_eq!(labeled.port(), 4000);
+            },
+            v4 => panic!("{} isn't IPv6", v4)
+        }
+
+        let v4: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(with_label(v4, 0x12345), v4);
+    }
+}
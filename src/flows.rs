@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use etherparse::{SlicedPacket, InternetSlice, TransportSlice};
+
+// A flow idle for this long is considered new when it shows up again
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Upper bound on tracked flows, so a port scan can't grow the table without bound
+const MAX_TRACKED_FLOWS: usize = 4096;
+
+/// Inner IPv4 5-tuple. Ports are 0 for protocols without them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub source_port: u16,
+    pub destination_port: u16
+}
+
+impl FlowKey {
+    pub fn from_packet(packet: &SlicedPacket) -> Option<FlowKey> {
+        let ip = match &packet.ip {
+            Some(InternetSlice::Ipv4(ip)) => ip,
+            _ => return None
+        };
+
+        let (source_port, destination_port) = match &packet.transport {
+            Some(TransportSlice::Tcp(tcp)) => (tcp.source_port(), tcp.destination_port()),
+            Some(TransportSlice::Udp(udp)) => (udp.source_port(), udp.destination_port()),
+            None => (0, 0)
+        };
+
+        Some(FlowKey {
+            source: ip.source_addr(),
+            destination: ip.destination_addr(),
+            protocol: ip.protocol(),
+            source_port,
+            destination_port
+        })
+    }
+}
+
+#[derive(Debug)]
+struct FlowState {
+    packets: u32,
+    last_seen: Instant
+}
+
+/// Counts packets per inner flow to pick out the first packets of new flows.
+#[derive(Debug)]
+pub struct FlowTracker {
+    initial_packets: u32,
+    flows: HashMap<FlowKey, FlowState>
+}
+
+impl FlowTracker {
+    pub fn new(initial_packets: u32) -> FlowTracker {
+        FlowTracker {
+            initial_packets,
+            flows: HashMap::new()
+        }
+    }
+
+    /// Record a packet of `flow`. Returns true if it's one of the first
+    /// `initial_packets` packets of the flow.
+    pub fn observe(&mut self, flow: FlowKey, now: Instant) -> bool {
+        if self.flows.len() >= MAX_TRACKED_FLOWS && !self.flows.contains_key(&flow) {
+            self.flows.retain(|_, state| now.saturating_duration_since(state.last_seen) < FLOW_IDLE_TIMEOUT);
+            if self.flows.len() >= MAX_TRACKED_FLOWS {
+                self.flows.clear();
+            }
+        }
+
+        let state = self.flows.entry(flow).or_insert(FlowState { packets: 0, last_seen: now });
+        if now.saturating_duration_since(state.last_seen) >= FLOW_IDLE_TIMEOUT {
+            state.packets = 0;
+        }
+        state.last_seen = now;
+        state.packets = state.packets.saturating_add(1);
+
+        state.packets <= self.initial_packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(source_port: u16) -> FlowKey {
+        FlowKey {
+            source: Ipv4Addr::new(10, 0, 0, 1),
+            destination: Ipv4Addr::new(10, 0, 0, 2),
+            protocol: 6,
+            source_port,
+            destination_port: 443
+        }
+    }
+
+    #[test]
+    fn only_the_first_packets_of_a_flow_are_initial() {
+        let mut tracker = FlowTracker::new(2);
+        let now = Instant::now();
+        let initial: Vec<bool> = (0..4).map(|_| tracker.observe(flow(1000), now)).collect();
+        assert_eq!(initial, vec![true, true, false, false]);
+        // Each flow counts on its own
+        assert!(tracker.observe(flow(1001), now));
+    }
+
+    #[test]
+    fn idle_flow_is_new_again() {
+        let mut tracker = FlowTracker::new(1);
+        let start = Instant::now();
+        assert!(tracker.observe(flow(1000), start));
+        assert!(!tracker.observe(flow(1000), start + FLOW_IDLE_TIMEOUT - Duration::from_secs(1)));
+        // Idle counts from the last packet
+        assert!(!tracker.observe(flow(1000), start + FLOW_IDLE_TIMEOUT + Duration::from_secs(1)));
+        assert!(tracker.observe(flow(1000), start + FLOW_IDLE_TIMEOUT * 3));
+    }
+
+    #[test]
+    fn flow_table_is_bounded() {
+        let mut tracker = FlowTracker::new(1);
+        let now = Instant::now();
+        for port in 0..=MAX_TRACKED_FLOWS as u16 {
+            tracker.observe(flow(port), now);
+        }
+        assert!(tracker.flows.len() <= MAX_TRACKED_FLOWS);
+    }
+
+    #[test]
+    fn flow_key_is_the_inner_five_tuple() {
+        let mut packet = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .udp(1000, 443)
+            .write(&mut packet, b"hi")
+            .unwrap();
+        let key = FlowKey::from_packet(&SlicedPacket::from_ip(&packet).unwrap()).unwrap();
+        assert_eq!(key, FlowKey { protocol: 17, ..flow(1000) });
+    }
+}
@@ -0,0 +1,245 @@
+// Application layer fragmentation of encoded messages larger than the
+// configured datagram size, e.g. with a jumbo TUN MTU over a smaller underlay.
+//
+// A fragment is an 11 byte header (marker, index, count, 8 byte big endian id)
+// followed by a slice of the encoded message. The marker can't start a message
+// in either wire format. Each fragment is encrypted on its own.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const FRAGMENT_MARKER: u8 = 0xff;
+pub const FRAGMENT_HEADER_LEN: usize = 11;
+
+// Incomplete messages are dropped after this long, e.g. when a fragment was lost
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+// Incomplete messages held at once across all sources. The oldest is dropped to make room.
+pub const MAX_PENDING_MESSAGES: usize = 256;
+
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&FRAGMENT_MARKER)
+}
+
+/// Split `message` into fragments of at most `max_len` bytes, header included,
+/// replacing the contents of `out`. Returns false if `max_len` leaves no room
+/// for payload or the message would need more than 255 fragments.
+pub fn split_into(message: &[u8], id: u64, max_len: usize, out: &mut Vec<Vec<u8>>) -> bool {
+    out.clear();
+    let chunk_len = match max_len.checked_sub(FRAGMENT_HEADER_LEN) {
+        Some(chunk_len) if chunk_len > 0 => chunk_len,
+        _ => return false
+    };
+    let count = match u8::try_from(message.len().div_ceil(chunk_len)) {
+        Ok(count) if count > 0 => count,
+        _ => return false
+    };
+
+    for (index, chunk) in message.chunks(chunk_len).enumerate() {
+        let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+        fragment.push(FRAGMENT_MARKER);
+        fragment.push(index as u8);
+        fragment.push(count);
+        fragment.extend_from_slice(&id.to_be_bytes());
+        fragment.extend_from_slice(chunk);
+        out.push(fragment);
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    Truncated,
+    // Index out of range, or a count disagreeing with earlier fragments
+    Inconsistent,
+    // The reassembled message would exceed the size limit
+    SizeLimit
+}
+
+impl std::fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FragmentError::Truncated => write!(f, "fragment shorter than header"),
+            FragmentError::Inconsistent => write!(f, "fragment index or count inconsistent"),
+            FragmentError::SizeLimit => write!(f, "reassembled message exceeds size limit")
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+    len: usize,
+    started: Instant
+}
+
+/// Puts fragmented messages back together, per source address and id.
+#[derive(Debug)]
+pub struct Reassembler {
+    max_message_len: usize,
+    pending: HashMap<(SocketAddr, u64), Partial>
+}
+
+impl Reassembler {
+    pub fn new(max_message_len: usize) -> Reassembler {
+        Reassembler {
+            max_message_len,
+            pending: HashMap::new()
+        }
+    }
+
+    /// Add a fragment from `source`. Returns the whole message once its last
+    /// fragment arrives. Duplicate fragments are ignored.
+    pub fn push(&mut self, source: SocketAddr, fragment: &[u8], now: Instant) -> Result<Option<Vec<u8>>, FragmentError> {
+        if fragment.len() < FRAGMENT_HEADER_LEN {
+            return Err(FragmentError::Truncated)
+        }
+        let index = usize::from(fragment[1]);
+        let count = usize::from(fragment[2]);
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&fragment[3..FRAGMENT_HEADER_LEN]);
+        let key = (source, u64::from_be_bytes(id));
+        let payload = &fragment[FRAGMENT_HEADER_LEN..];
+
+        if index >= count {
+            return Err(FragmentError::Inconsistent)
+        }
+
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING_MESSAGES {
+            let oldest = self.pending.iter().min_by_key(|(_, partial)| partial.started).map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+
+        let partial = self.pending.entry(key).or_insert_with(|| Partial {
+            parts: vec![None; count],
+            received: 0,
+            len: 0,
+            started: now
+        });
+        if partial.parts.len() != count {
+            self.pending.remove(&key);
+            return Err(FragmentError::Inconsistent)
+        }
+        if partial.parts[index].is_some() {
+            return Ok(None)
+        }
+
+        partial.len += payload.len();
+        if partial.len > self.max_message_len {
+            self.pending.remove(&key);
+            return Err(FragmentError::SizeLimit)
+        }
+        partial.parts[index] = Some(payload.to_vec());
+        partial.received += 1;
+
+        if partial.received < count {
+            return Ok(None)
+        }
+
+        let partial = self.pending.remove(&key).unwrap();
+        let mut message = Vec::with_capacity(partial.len);
+        for part in partial.parts.into_iter().flatten() {
+            message.extend_from_slice(&part);
+        }
+        Ok(Some(message))
+    }
+
+    /// Drop messages still incomplete after `REASSEMBLY_TIMEOUT`, returning how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|_, partial| now.saturating_duration_since(partial.started) <= REASSEMBLY_TIMEOUT);
+        before - self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn fragments_fit_and_reassemble_in_any_order() {
+        let message = message(1000);
+        let mut fragments = Vec::new();
+        assert!(split_into(&message, 7, 300, &mut fragments));
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 300 && is_fragment(fragment)));
+
+        let mut reassembler = Reassembler::new(4096);
+        let now = Instant::now();
+        for fragment in fragments[1..].iter().rev() {
+            assert_eq!(reassembler.push(source(), fragment, now), Ok(None));
+        }
+        // A duplicate changes nothing
+        assert_eq!(reassembler.push(source(), &fragments[3], now), Ok(None));
+        assert_eq!(reassembler.push(source(), &fragments[0], now), Ok(Some(message)));
+    }
+
+    #[test]
+    fn message_missing_a_fragment_expires() {
+        let mut fragments = Vec::new();
+        assert!(split_into(&message(1000), 1, 300, &mut fragments));
+        let mut reassembler = Reassembler::new(4096);
+        let now = Instant::now();
+        for fragment in fragments.iter().filter(|fragment| fragment[1] != 2) {
+            assert_eq!(reassembler.push(source(), fragment, now), Ok(None));
+        }
+
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT), 0);
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT + Duration::from_millis(1)), 1);
+        // What arrives too late starts over
+        assert_eq!(reassembler.push(source(), &fragments[2], now + REASSEMBLY_TIMEOUT * 2), Ok(None));
+    }
+
+    #[test]
+    fn fragments_of_different_sources_or_ids_stay_apart() {
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        assert!(split_into(&message(500), 1, 300, &mut first));
+        assert!(split_into(&message(500), 2, 300, &mut second));
+        let other: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let mut reassembler = Reassembler::new(4096);
+        let now = Instant::now();
+
+        assert_eq!(reassembler.push(source(), &first[0], now), Ok(None));
+        assert_eq!(reassembler.push(source(), &second[1], now), Ok(None));
+        assert_eq!(reassembler.push(other, &first[1], now), Ok(None));
+        assert_eq!(reassembler.push(source(), &first[1], now), Ok(Some(message(500))));
+    }
+
+    #[test]
+    fn too_small_or_too_many_fragments_are_refused() {
+        let mut fragments = Vec::new();
+        assert!(!split_into(&message(10), 1, FRAGMENT_HEADER_LEN, &mut fragments));
+        assert!(!split_into(&message(256), 1, FRAGMENT_HEADER_LEN + 1, &mut fragments));
+        assert!(split_into(&message(255), 1, FRAGMENT_HEADER_LEN + 1, &mut fragments));
+        assert_eq!(fragments.len(), 255);
+    }
+
+    #[test]
+    fn malformed_or_oversized_fragments_are_errors() {
+        let mut fragments = Vec::new();
+        assert!(split_into(&message(1000), 1, 300, &mut fragments));
+        let mut reassembler = Reassembler::new(600);
+        let now = Instant::now();
+
+        assert_eq!(reassembler.push(source(), &fragments[0][..5], now), Err(FragmentError::Truncated));
+        let mut out_of_range = fragments[0].clone();
+        out_of_range[1] = 4;
+        assert_eq!(reassembler.push(source(), &out_of_range, now), Err(FragmentError::Inconsistent));
+        for fragment in &fragments[..2] {
+            assert_eq!(reassembler.push(source(), fragment, now), Ok(None));
+        }
+        assert_eq!(reassembler.push(source(), &fragments[2], now), Err(FragmentError::SizeLimit));
+    }
+}
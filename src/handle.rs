@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::multipathtunnel::ClientList;
+use crate::path::{Health, Paths};
+use crate::stats::{PeerTraffic, Stats};
+use crate::rate::Rate;
+use crate::pathseq::LinkLoss;
+
+/// Snapshot of one path (send device) and its measured properties.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PathInfo {
+    pub name: String,
+    pub local_addr: SocketAddr,
+    // Peer addresses this path sends to
+    pub remote_addrs: Vec<SocketAddr>,
+    pub health: Health,
+    pub priority: u8,
+    // Share of the packets in weighted path mode, configured or derived with auto_weights
+    pub weight: f64,
+    pub rtt: Option<Duration>,
+    // Smoothed keep-alive RTT and its variation, as TCP keeps them
+    pub srtt: Option<Duration>,
+    pub rttvar: Option<Duration>,
+    // Went down and up again too often lately, see path_health
+    pub flapping: bool,
+    // Paused with Multipathtunnel::pause_path
+    pub paused: bool,
+    // Fraction of unanswered keep-alives
+    pub loss: Option<f64>,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    // Packets dropped because the link fell a full send queue behind
+    pub tx_queue_dropped: u64,
+    // Packets that waited longer than max_packet_age_ms to be sent
+    pub tx_stale: u64,
+    // Packets drop_injection dropped on purpose
+    pub tx_injected_drops: u64,
+    // Smoothed rates, sampled every second
+    pub tx_rate: Rate,
+    pub rx_rate: Rate,
+    // Largest datagram known to get through, when probing the path MTU
+    pub pmtu: Option<usize>,
+    // From timestamped packets received, when the peer sends them. The delay
+    // includes the offset between the clocks.
+    pub one_way_delay_us: Option<i64>,
+    pub jitter: Option<Duration>,
+    // Fraction of the packets delivered from this path that were behind a
+    // later one from the same sender, and the 99th percentile and maximum of
+    // how many sequence numbers behind. A reorder buffer of max_packets at
+    // the p99 depth puts 99% of them back in order.
+    pub out_of_order: Option<f64>,
+    pub reorder_depth_p99: Option<usize>,
+    pub reorder_depth_max: Option<usize>,
+    // Id stamped on packets with path_sequence, and the loss on each of the
+    // peers' paths to this one when they stamp theirs
+    pub path_id: u16,
+    pub link_losses: Vec<LinkLoss>
+}
+
+/// Snapshot of one known peer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerInfo {
+    pub tun_ip: IpAddr,
+    pub addrs: Vec<PeerAddr>,
+    // Keep-alive RTTs are measured per path, not per peer. This is the
+    // lowest over the paths that are up.
+    pub rtt: Option<Duration>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerAddr {
+    pub addr: SocketAddr,
+    // Time since anything was received from the address, if ever
+    pub last_seen: Option<Duration>
+}
+
+/// Useful bytes against bytes on the wire, in each direction. Raw bytes are
+/// the datagrams sent and received on all paths, with the tunnel's framing.
+/// Goodput is the inner packets, each counted once: read from the TUN for
+/// the send tasks, or delivered after the copies from other paths were
+/// dropped. The overhead is raw over goodput, about the number of paths a
+/// packet is sent on in redundant mode, less for payloads that compress
+/// well. `None` before any goodput.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Throughput {
+    pub tx_goodput_bytes: u64,
+    pub tx_raw_bytes: u64,
+    pub tx_overhead: Option<f64>,
+    pub rx_goodput_bytes: u64,
+    pub rx_raw_bytes: u64,
+    pub rx_overhead: Option<f64>
+}
+
+impl Throughput {
+    pub fn new(tx_goodput_bytes: u64, tx_raw_bytes: u64, rx_goodput_bytes: u64, rx_raw_bytes: u64) -> Throughput {
+        let overhead = |raw: u64, goodput: u64| if goodput == 0 { None } else { Some(raw as f64 / goodput as f64) };
+        Throughput {
+            tx_goodput_bytes,
+            tx_raw_bytes,
+            tx_overhead: overhead(tx_raw_bytes, tx_goodput_bytes),
+            rx_goodput_bytes,
+            rx_raw_bytes,
+            rx_overhead: overhead(rx_raw_bytes, rx_goodput_bytes)
+        }
+    }
+}
+
+/// Overall state of a tunnel, for liveness probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HealthStatus {
+    Healthy,
+    // Some paths are down, but traffic can still get through the others
+    Degraded,
+    // The TUN tasks aren't running, or no path is up
+    Unhealthy
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathHealth {
+    pub name: String,
+    pub health: Health
+}
+
+/// Health of a tunnel: its paths' keep-alive state, its peers and its TUN tasks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub paths: Vec<PathHealth>,
+    pub clients: usize,
+    // Whether the tasks reading and writing the TUN are running
+    pub tun_running: bool
+}
+
+impl HealthReport {
+    pub fn new(paths: Vec<PathHealth>, clients: usize, tun_running: bool) -> HealthReport {
+        let down = paths.iter().filter(|path| path.health == Health::Down).count();
+        let status = if !tun_running || down == paths.len() {
+            HealthStatus::Unhealthy
+        } else if down > 0 {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+        HealthReport { status, paths, clients, tun_running }
+    }
+}
+
+/// Cheaply cloneable view into a tunnel, usable while `run` is in progress.
+#[derive(Clone)]
+pub struct TunnelHandle {
+    paths: Paths,
+    client_list: ClientList,
+    stats: Arc<Stats>,
+    // The counters as of the last stats_since_last, shared by clones
+    last_read: Arc<Stats>
+}
+
+impl TunnelHandle {
+    pub(crate) fn new(paths: Paths, client_list: ClientList, stats: Arc<Stats>) -> TunnelHandle {
+        TunnelHandle { paths, client_list, stats, last_read: Arc::new(Stats::default()) }
+    }
+
+    /// Tunnel wide counters, e.g. dropped packets and the reorder buffer depth.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// The tunnel wide and per-peer counters accumulated since the previous
+    /// call on this handle or its clones, or since the tunnel started on the
+    /// first call. The tunnel's own counters keep counting up, so `stats`
+    /// and other handles are unaffected.
+    pub fn stats_since_last(&self) -> Stats {
+        self.stats.since(&self.last_read)
+    }
+
+    /// Data packets and bytes sent to and received from each known peer.
+    pub fn peer_stats(&self) -> HashMap<IpAddr, PeerTraffic> {
+        self.stats.peers.snapshot()
+    }
+
+    /// Goodput against raw bytes over all paths. The raw bytes include
+    /// keep-alives and probes, and only paths that still exist.
+    pub fn throughput(&self) -> Throughput {
+        let (tx_raw_bytes, rx_raw_bytes) = self.paths.read().unwrap().iter()
+            .fold((0, 0), |(tx, rx), path| {
+                (tx + path.counters.tx_bytes.load(Ordering::Relaxed), rx + path.counters.rx_bytes.load(Ordering::Relaxed))
+            });
+        Throughput::new(
+            self.stats.tx_goodput_bytes.load(Ordering::Relaxed),
+            tx_raw_bytes,
+            self.stats.rx_goodput_bytes.load(Ordering::Relaxed),
+            rx_raw_bytes
+        )
+    }
+
+    /// Addresses the send devices are bound to, including the ports picked
+    /// for a listen port of 0 or a range, to tell peers.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.paths.read().unwrap().iter().map(|path| path.local_addr).collect()
+    }
+
+    /// Known peer addresses by TUN address.
+    pub fn clients(&self) -> HashMap<IpAddr, Vec<SocketAddr>> {
+        self.client_list.snapshot()
+    }
+
+    /// All current paths. Every path sends to every known peer address.
+    pub fn paths(&self) -> Vec<PathInfo> {
+        let mut remote_addrs = self.client_list.addrs();
+        remote_addrs.sort();
+        remote_addrs.dedup();
+
+        self.paths.read().unwrap().iter()
+            .map(|path| {
+                let (tx_rate, rx_rate) = path.rates();
+                let (out_of_order, reorder_depth_p99, reorder_depth_max) = path.out_of_order();
+                PathInfo {
+                    name: path.iface.clone(),
+                    local_addr: path.local_addr,
+                    remote_addrs: remote_addrs.clone(),
+                    health: path.health(),
+                    priority: path.priority,
+                    weight: path.weight(),
+                    rtt: path.rtt(),
+                    srtt: path.srtt(),
+                    rttvar: path.rttvar(),
+                    flapping: path.is_flapping(),
+                    paused: path.is_paused(),
+                    loss: path.counters.keepalive_loss(),
+                    tx_packets: path.counters.tx_packets.load(Ordering::Relaxed),
+                    tx_bytes: path.counters.tx_bytes.load(Ordering::Relaxed),
+                    rx_packets: path.counters.rx_packets.load(Ordering::Relaxed),
+                    rx_bytes: path.counters.rx_bytes.load(Ordering::Relaxed),
+                    tx_queue_dropped: path.counters.tx_queue_dropped.load(Ordering::Relaxed),
+                    tx_stale: path.counters.tx_stale.load(Ordering::Relaxed),
+                    tx_injected_drops: path.counters.tx_injected_drops.load(Ordering::Relaxed),
+                    tx_rate,
+                    rx_rate,
+                    pmtu: path.pmtu(),
+                    one_way_delay_us: path.one_way_delay_us(),
+                    jitter: path.jitter(),
+                    out_of_order,
+                    reorder_depth_p99,
+                    reorder_depth_max,
+                    path_id: path.id,
+                    link_losses: path.link_losses()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(health: &[Health]) -> Vec<PathHealth> {
+        health.iter().enumerate().map(|(index, &health)| PathHealth { name: format!("eth{}", index), health }).collect()
+    }
+
+    #[test]
+    fn clones_share_where_stats_since_last_left_off() {
+        let stats = Arc::new(Stats::default());
+        let handle = TunnelHandle::new(Paths::default(), ClientList::default(), stats.clone());
+        stats.forwarded.fetch_add(2, Ordering::Relaxed);
+        assert_eq!(handle.clone().stats_since_last().forwarded.load(Ordering::Relaxed), 2);
+        assert_eq!(handle.stats_since_last().forwarded.load(Ordering::Relaxed), 0);
+        assert_eq!(handle.stats().forwarded.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn all_paths_up_is_healthy() {
+        assert_eq!(HealthReport::new(paths(&[Health::Up, Health::Up]), 1, true).status, HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn some_paths_down_is_degraded() {
+        let report = HealthReport::new(paths(&[Health::Up, Health::Down]), 1, true);
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert_eq!(report.paths[1].health, Health::Down);
+    }
+
+    #[test]
+    fn all_paths_down_or_stopped_tun_tasks_are_unhealthy() {
+        assert_eq!(HealthReport::new(paths(&[Health::Down, Health::Down]), 1, true).status, HealthStatus::Unhealthy);
+        assert_eq!(HealthReport::new(paths(&[Health::Up, Health::Up]), 1, false).status, HealthStatus::Unhealthy);
+        assert_eq!(HealthReport::new(vec![], 0, true).status, HealthStatus::Unhealthy);
+    }
+}
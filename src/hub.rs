@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::SharedClock;
+use crate::dedup::DedupWindow;
+use crate::dispatch::Dispatcher;
+use crate::messages::Packet;
+
+// Sequence numbers remembered per source to forward each packet only once
+const FORWARD_DEDUP_WINDOW: usize = 4096;
+
+/// Relays packets between peers in hub mode. Packets received for another
+/// known peer are handed to the send tasks as if read from the TUN, keeping
+/// the original sender's sequence number and session epoch.
+pub struct Forwarder {
+    dispatcher: Arc<Dispatcher>,
+    clock: SharedClock,
+    // Packets arrive once per link in redundant mode, per source TUN IP
+    forwarded: Mutex<HashMap<IpAddr, DedupWindow>>
+}
+
+impl Forwarder {
+    pub fn new(dispatcher: Arc<Dispatcher>, clock: SharedClock) -> Forwarder {
+        Forwarder {
+            dispatcher,
+            clock,
+            forwarded: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Forward `packet` from `source`, of its session `session_epoch`.
+    /// Returns false if it was a copy of one already forwarded.
+    pub fn forward(&self, source: IpAddr, packet: Packet, session_epoch: Option<u32>) -> bool {
+        let fresh = self.forwarded.lock().unwrap()
+            .entry(source)
+            .or_insert_with(|| DedupWindow::new(FORWARD_DEDUP_WINDOW))
+            .insert(packet.seq);
+        if fresh {
+            // Dropped, and not counted, if the dispatcher can't route it
+            self.dispatcher.dispatch_forwarded(packet, self.clock.now(), session_epoch);
+        }
+        fresh
+    }
+
+    pub fn forget(&self, source: &IpAddr) {
+        self.forwarded.lock().unwrap().remove(source);
+    }
+}
@@ -0,0 +1,216 @@
+// Destination unreachable messages for packets read from the TUN that no
+// peer can take, written back to the TUN so the sending application fails
+// fast instead of waiting for a reply that never comes.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::ipfrag::header_checksum;
+
+const ICMP: u8 = 1;
+const ICMPV6: u8 = 58;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_HOST_UNREACHABLE: u8 = 1;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_ADDR_UNREACHABLE: u8 = 3;
+// An ICMPv6 error carries as much of the packet as fits the minimum IPv6 MTU
+const IPV6_MIN_MTU: usize = 1280;
+/// Length of the longest message `unreachable` makes.
+pub const MAX_UNREACHABLE_LEN: usize = IPV6_MIN_MTU;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const ICMP_HEADER_LEN: usize = 8;
+
+/// Destination address of an IP packet.
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= IPV4_HEADER_LEN => Some(IpAddr::V4(ipv4_at(packet, 16))),
+        6 if packet.len() >= IPV6_HEADER_LEN => Some(IpAddr::V6(ipv6_at(packet, 24))),
+        _ => None
+    }
+}
+
+/// An ICMP host unreachable, or ICMPv6 address unreachable, answering
+/// `packet`, sent from `router`. If `router` is of the other address family
+/// the unreachable destination is used instead. `None` for packets that
+/// must not be answered with an error: ICMP errors themselves, IPv4
+/// fragments after the first, and packets from or to no single host.
+pub fn unreachable(packet: &[u8], router: IpAddr) -> Option<Vec<u8>> {
+    match packet.first()? >> 4 {
+        4 => unreachable_v4(packet, router),
+        6 => unreachable_v6(packet, router),
+        _ => None
+    }
+}
+
+fn unreachable_v4(packet: &[u8], router: IpAddr) -> Option<Vec<u8>> {
+    let header_len = usize::from(packet.first()? & 0x0f) * 4;
+    if header_len < IPV4_HEADER_LEN || packet.len() < header_len {
+        return None
+    }
+    let source = ipv4_at(packet, 12);
+    let destination = ipv4_at(packet, 16);
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    let is_icmp_error = packet[9] == ICMP && packet.get(header_len).is_some_and(|icmp_type| ![0, 8, 13, 14].contains(icmp_type));
+    if fragment_offset != 0 || is_icmp_error || is_v4_group(source) || is_v4_group(destination) || source.is_unspecified() {
+        return None
+    }
+    let router = match router {
+        IpAddr::V4(router) => router,
+        IpAddr::V6(_) => destination
+    };
+
+    // The original header and the first 8 bytes of its payload
+    let quoted = &packet[..packet.len().min(header_len + 8)];
+    let total_len = IPV4_HEADER_LEN + ICMP_HEADER_LEN + quoted.len();
+    let mut reply = Vec::with_capacity(total_len);
+    reply.extend_from_slice(&[0x45, 0]);
+    reply.extend_from_slice(&(total_len as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0, 64, ICMP, 0, 0]);
+    reply.extend_from_slice(&router.octets());
+    reply.extend_from_slice(&source.octets());
+    let checksum = header_checksum(&reply);
+    reply[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    reply.extend_from_slice(&[ICMP_DEST_UNREACHABLE, ICMP_HOST_UNREACHABLE, 0, 0, 0, 0, 0, 0]);
+    reply.extend_from_slice(quoted);
+    let checksum = header_checksum(&reply[IPV4_HEADER_LEN..]);
+    reply[IPV4_HEADER_LEN + 2..IPV4_HEADER_LEN + 4].copy_from_slice(&checksum.to_be_bytes());
+    Some(reply)
+}
+
+fn unreachable_v6(packet: &[u8], router: IpAddr) -> Option<Vec<u8>> {
+    if packet.len() < IPV6_HEADER_LEN {
+        return None
+    }
+    let source = ipv6_at(packet, 8);
+    let destination = ipv6_at(packet, 24);
+    // Types below 128 are errors. Extension headers before an ICMPv6 error aren't looked through.
+    let is_icmp_error = packet[6] == ICMPV6 && packet.get(IPV6_HEADER_LEN).is_some_and(|icmp_type| *icmp_type < 128);
+    if is_icmp_error || source.is_multicast() || destination.is_multicast() || source.is_unspecified() {
+        return None
+    }
+    let router = match router {
+        IpAddr::V6(router) => router,
+        IpAddr::V4(_) => destination
+    };
+
+    let quoted = &packet[..packet.len().min(IPV6_MIN_MTU - IPV6_HEADER_LEN - ICMP_HEADER_LEN)];
+    let icmp_len = ICMP_HEADER_LEN + quoted.len();
+    let mut reply = Vec::with_capacity(IPV6_HEADER_LEN + icmp_len);
+    reply.extend_from_slice(&[0x60, 0, 0, 0]);
+    reply.extend_from_slice(&(icmp_len as u16).to_be_bytes());
+    reply.extend_from_slice(&[ICMPV6, 64]);
+    reply.extend_from_slice(&router.octets());
+    reply.extend_from_slice(&source.octets());
+    reply.extend_from_slice(&[ICMPV6_DEST_UNREACHABLE, ICMPV6_ADDR_UNREACHABLE, 0, 0, 0, 0, 0, 0]);
+    reply.extend_from_slice(quoted);
+
+    // The checksum covers a pseudo header of the addresses, length and next header
+    let mut pseudo = Vec::with_capacity(40 + icmp_len);
+    pseudo.extend_from_slice(&reply[8..IPV6_HEADER_LEN]);
+    pseudo.extend_from_slice(&(icmp_len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, ICMPV6]);
+    pseudo.extend_from_slice(&reply[IPV6_HEADER_LEN..]);
+    let checksum = header_checksum(&pseudo);
+    reply[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].copy_from_slice(&checksum.to_be_bytes());
+    Some(reply)
+}
+
+fn is_v4_group(addr: Ipv4Addr) -> bool {
+    addr.is_multicast() || addr.is_broadcast()
+}
+
+fn ipv4_at(packet: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(packet[offset], packet[offset + 1], packet[offset + 2], packet[offset + 3])
+}
+
+fn ipv6_at(packet: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&packet[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+    use super::*;
+
+    fn udp_v4(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4(source, destination, 64).udp(4000, 5000).write(&mut packet, &[7; 100]).unwrap();
+        packet
+    }
+
+    fn udp_v6(source: Ipv6Addr, destination: Ipv6Addr, len: usize) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv6(source.octets(), destination.octets(), 64).udp(4000, 5000).write(&mut packet, &vec![7; len]).unwrap();
+        packet
+    }
+
+    // The RFC 1071 sum over data that includes its checksum
+    fn sums_to_zero(data: &[u8]) -> bool {
+        header_checksum(data) == 0
+    }
+
+    #[test]
+    fn ipv4_unreachable_quotes_the_header_and_eight_bytes() {
+        let packet = udp_v4([10, 0, 0, 1], [10, 0, 0, 9]);
+        let reply = unreachable(&packet, [10, 0, 0, 2].into()).unwrap();
+
+        assert_eq!(destination(&reply), Some([10, 0, 0, 1].into()));
+        assert_eq!(ipv4_at(&reply, 12), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(u16::from_be_bytes([reply[2], reply[3]]) as usize, reply.len());
+        assert_eq!(reply[9], ICMP);
+        assert_eq!(&reply[20..22], &[ICMP_DEST_UNREACHABLE, ICMP_HOST_UNREACHABLE]);
+        assert_eq!(&reply[28..], &packet[..28]);
+        assert!(sums_to_zero(&reply[..20]));
+        assert!(sums_to_zero(&reply[20..]));
+    }
+
+    #[test]
+    fn ipv6_unreachable_has_a_valid_pseudo_header_checksum() {
+        let (source, peer): (Ipv6Addr, Ipv6Addr) = ("fd00::1".parse().unwrap(), "fd00::9".parse().unwrap());
+        let packet = udp_v6(source, peer, 100);
+        let reply = unreachable(&packet, "fd00::2".parse::<Ipv6Addr>().unwrap().into()).unwrap();
+
+        assert_eq!(destination(&reply), Some(source.into()));
+        assert_eq!(reply[6], ICMPV6);
+        assert_eq!(&reply[40..42], &[ICMPV6_DEST_UNREACHABLE, ICMPV6_ADDR_UNREACHABLE]);
+        assert_eq!(&reply[48..], &packet[..]);
+        let mut pseudo = reply[8..40].to_vec();
+        pseudo.extend_from_slice(&((reply.len() - 40) as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, ICMPV6]);
+        pseudo.extend_from_slice(&reply[40..]);
+        assert!(sums_to_zero(&pseudo));
+    }
+
+    #[test]
+    fn ipv6_unreachable_fits_the_minimum_mtu() {
+        let packet = udp_v6("fd00::1".parse().unwrap(), "fd00::9".parse().unwrap(), 1400);
+        assert_eq!(unreachable(&packet, "fd00::2".parse::<Ipv6Addr>().unwrap().into()).unwrap().len(), IPV6_MIN_MTU);
+    }
+
+    #[test]
+    fn a_router_of_the_other_family_is_replaced_by_the_destination() {
+        let reply = unreachable(&udp_v4([10, 0, 0, 1], [10, 0, 0, 9]), "fd00::2".parse::<Ipv6Addr>().unwrap().into()).unwrap();
+        assert_eq!(ipv4_at(&reply, 12), Ipv4Addr::new(10, 0, 0, 9));
+    }
+
+    #[test]
+    fn errors_fragments_and_group_addresses_get_no_answer() {
+        let router: IpAddr = [10, 0, 0, 2].into();
+        let error = unreachable(&udp_v4([10, 0, 0, 1], [10, 0, 0, 9]), router).unwrap();
+        assert_eq!(unreachable(&error, router), None);
+
+        let mut fragment = udp_v4([10, 0, 0, 1], [10, 0, 0, 9]);
+        fragment[7] = 1;
+        assert_eq!(unreachable(&fragment, router), None);
+
+        assert_eq!(unreachable(&udp_v4([10, 0, 0, 1], [224, 0, 0, 1]), router), None);
+        assert_eq!(unreachable(&udp_v4([10, 0, 0, 1], [255, 255, 255, 255]), router), None);
+        assert_eq!(unreachable(&udp_v4([0, 0, 0, 0], [10, 0, 0, 9]), router), None);
+        assert_eq!(unreachable(&udp_v6("fd00::1".parse().unwrap(), "ff02::1".parse().unwrap(), 10), router), None);
+        assert_eq!(unreachable(&[0x45, 0, 0], router), None);
+        assert_eq!(unreachable(b"not ip", router), None);
+    }
+}
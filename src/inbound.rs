@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+use crate::messages::Packet;
+use crate::path::Path;
+
+// Packets buffered per source before further packets from it are dropped
+pub const INBOUND_QUEUE_CAPACITY: usize = 1024;
+
+// A packet, its sender's session epoch and the path it was received on, if any
+type Queued = (Packet, Option<u32>, Option<Arc<Path>>);
+type Popped = (IpAddr, Packet, Option<u32>, Option<Arc<Path>>);
+
+#[derive(Debug, Default)]
+struct QueueState {
+    queues: HashMap<IpAddr, VecDeque<Queued>>,
+    // Sources with queued packets, in the order they are served
+    ready: VecDeque<IpAddr>
+}
+
+/// Received packets on their way to the TUN, queued per source peer and
+/// served round robin, so a flooding peer only fills its own queue.
+#[derive(Debug)]
+pub struct InboundQueues {
+    capacity: usize,
+    state: Mutex<QueueState>,
+    notify: Notify,
+    closed: AtomicBool
+}
+
+impl InboundQueues {
+    pub fn new(capacity: usize) -> InboundQueues {
+        InboundQueues {
+            capacity,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false)
+        }
+    }
+
+    /// Queue a packet from `source` of session `epoch`, received on `path`.
+    /// Returns false, dropping it, if that source's queue is full.
+    pub fn push(&self, source: IpAddr, packet: Packet, epoch: Option<u32>, path: Option<Arc<Path>>) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            let queue = state.queues.entry(source).or_default();
+            if queue.len() >= self.capacity {
+                return false
+            }
+            queue.push_back((packet, epoch, path));
+            if queue.len() == 1 {
+                state.ready.push_back(source);
+            }
+        }
+
+        self.notify.notify_one();
+        true
+    }
+
+    /// Drop everything queued from `source`.
+    pub fn remove(&self, source: &IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        if state.queues.remove(source).is_some() {
+            state.ready.retain(|ready| ready != source);
+        }
+    }
+
+    /// Let `pop` return `None` once everything queued has been taken.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    /// The next packet, its source, its session epoch and the path it came
+    /// on, taking one from each source in turn. `None` once closed and empty.
+    pub async fn pop(&self) -> Option<Popped> {
+        loop {
+            if let Some(packet) = self.try_pop() {
+                return Some(packet)
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<Popped> {
+        let mut state = self.state.lock().unwrap();
+        let source = state.ready.pop_front()?;
+        let queue = state.queues.get_mut(&source)?;
+        let (packet, epoch, path) = queue.pop_front()?;
+
+        if queue.is_empty() {
+            state.queues.remove(&source);
+        } else {
+            state.ready.push_back(source);
+        }
+        Some((source, packet, epoch, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    const LOUD: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const QUIET: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 3));
+
+    fn packet(seq: usize) -> Packet {
+        Packet { seq, bytes: Bytes::new() }
+    }
+
+    fn pop_all(queues: &InboundQueues) -> Vec<(IpAddr, usize)> {
+        std::iter::from_fn(|| queues.try_pop()).map(|(source, packet, _, _)| (source, packet.seq)).collect()
+    }
+
+    #[test]
+    fn sources_are_served_in_turn() {
+        let queues = InboundQueues::new(INBOUND_QUEUE_CAPACITY);
+        for seq in 0..100 {
+            assert!(queues.push(LOUD, packet(seq), None, None));
+        }
+        assert!(queues.push(QUIET, packet(1000), None, None));
+
+        // The quiet peer waits behind one packet, not a hundred
+        let popped = pop_all(&queues);
+        assert_eq!(popped[..3], [(LOUD, 0), (QUIET, 1000), (LOUD, 1)]);
+        assert_eq!(popped.len(), 101);
+        // Each source in its own order
+        let loud: Vec<usize> = popped.iter().filter(|(source, _)| *source == LOUD).map(|(_, seq)| *seq).collect();
+        assert_eq!(loud, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_queue_only_drops_its_own_source() {
+        let queues = InboundQueues::new(4);
+        for seq in 0..4 {
+            assert!(queues.push(LOUD, packet(seq), None, None));
+        }
+        assert!(!queues.push(LOUD, packet(4), None, None));
+        assert!(queues.push(QUIET, packet(1000), None, None));
+        assert_eq!(pop_all(&queues).len(), 5);
+        // Room again once served
+        assert!(queues.push(LOUD, packet(5), None, None));
+    }
+
+    #[test]
+    fn removed_source_is_skipped() {
+        let queues = InboundQueues::new(4);
+        queues.push(LOUD, packet(0), None, None);
+        queues.push(QUIET, packet(1), None, None);
+        queues.remove(&LOUD);
+        assert_eq!(pop_all(&queues), vec![(QUIET, 1)]);
+    }
+
+    #[tokio::test]
+    async fn pop_drains_before_reporting_closed() {
+        let queues = Arc::new(InboundQueues::new(4));
+        let waiting = tokio::spawn({
+            let queues = queues.clone();
+            async move { queues.pop().await.map(|(source, packet, _, _)| (source, packet.seq)) }
+        });
+        tokio::task::yield_now().await;
+        queues.push(QUIET, packet(7), None, None);
+        assert_eq!(waiting.await.unwrap(), Some((QUIET, 7)));
+
+        queues.push(LOUD, packet(8), None, None);
+        queues.close();
+        assert_eq!(queues.pop().await.map(|(_, packet, _, _)| packet.seq), Some(8));
+        assert!(queues.pop().await.is_none());
+    }
+}
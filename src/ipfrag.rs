@@ -0,0 +1,143 @@
+// IPv4 fragmentation of packets read from the TUN that exceed its MTU,
+// e.g. when the host hands us an unsegmented GSO packet.
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const OFFSET_MASK: u16 = 0x1fff;
+
+/// Split an IPv4 packet into fragments of at most `mtu` bytes.
+///
+/// Returns `None` if the packet isn't a well formed IPv4 packet, has the
+/// don't fragment flag set, or `mtu` leaves no room for payload. The full
+/// header, including options, is repeated in every fragment.
+pub fn fragment_ipv4(packet: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None
+    }
+
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if header_len < 20 || total_len < header_len || total_len > packet.len() {
+        return None
+    }
+
+    let flags_offset = u16::from_be_bytes([packet[6], packet[7]]);
+    if flags_offset & FLAG_DONT_FRAGMENT != 0 {
+        return None
+    }
+
+    // Fragment payloads must be multiples of 8 bytes, except the last
+    let chunk_len = mtu.checked_sub(header_len)? / 8 * 8;
+    if chunk_len == 0 {
+        return None
+    }
+
+    // The packet may itself be a fragment, keep its position and MF flag
+    let base_offset = usize::from(flags_offset & OFFSET_MASK) * 8;
+    let more_after = flags_offset & FLAG_MORE_FRAGMENTS != 0;
+
+    let header = &packet[..header_len];
+    let payload = &packet[header_len..total_len];
+    let mut fragments = Vec::with_capacity(payload.len() / chunk_len + 1);
+
+    for (i, chunk) in payload.chunks(chunk_len).enumerate() {
+        let last = (i + 1) * chunk_len >= payload.len();
+        let offset = ((base_offset + i * chunk_len) / 8) as u16;
+        let flags = if !last || more_after { FLAG_MORE_FRAGMENTS } else { 0 };
+
+        let mut fragment = Vec::with_capacity(header_len + chunk.len());
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(chunk);
+
+        fragment[2..4].copy_from_slice(&((header_len + chunk.len()) as u16).to_be_bytes());
+        fragment[6..8].copy_from_slice(&(flags | offset).to_be_bytes());
+        fragment[10..12].copy_from_slice(&[0, 0]);
+        let checksum = header_checksum(&fragment[..header_len]);
+        fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        fragments.push(fragment);
+    }
+
+    Some(fragments)
+}
+
+/// RFC 791 header checksum, computed with the checksum field zeroed.
+pub fn header_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An IPv4 header without DF, followed by a payload of `len` counting bytes
+    fn packet(len: usize, flags_offset: u16) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0x12, 0x34, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        packet[2..4].copy_from_slice(&((20 + len) as u16).to_be_bytes());
+        packet[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+        packet.extend((0..len).map(|i| i as u8));
+        packet
+    }
+
+    fn flags_offset(fragment: &[u8]) -> u16 {
+        u16::from_be_bytes([fragment[6], fragment[7]])
+    }
+
+    #[test]
+    fn fragments_fit_the_mtu_and_reassemble() {
+        let original = packet(3000, 0);
+        let fragments = fragment_ipv4(&original, 1000).unwrap();
+
+        // 976 bytes, the largest multiple of 8 after the header
+        assert_eq!(fragments.len(), 4);
+        let mut payload = Vec::new();
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= 1000);
+            assert_eq!(usize::from(u16::from_be_bytes([fragment[2], fragment[3]])), fragment.len());
+            assert_eq!(usize::from(flags_offset(fragment) & OFFSET_MASK) * 8, payload.len());
+            assert_eq!(flags_offset(fragment) & FLAG_MORE_FRAGMENTS != 0, i < 3);
+            assert_eq!(header_checksum(&fragment[..20]), 0);
+            assert_eq!(fragment[4..6], original[4..6]);
+            payload.extend_from_slice(&fragment[20..]);
+        }
+        assert_eq!(payload, original[20..]);
+    }
+
+    #[test]
+    fn fragmenting_a_fragment_keeps_its_offset_and_more_flag() {
+        // The middle of a larger packet, at byte 800
+        let fragments = fragment_ipv4(&packet(1600, FLAG_MORE_FRAGMENTS | 100), 820).unwrap();
+
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(flags_offset(&fragments[0]), FLAG_MORE_FRAGMENTS | 100);
+        assert_eq!(flags_offset(&fragments[1]), FLAG_MORE_FRAGMENTS | 200);
+    }
+
+    #[test]
+    fn packets_that_cannot_be_fragmented_are_refused() {
+        assert_eq!(fragment_ipv4(&packet(3000, FLAG_DONT_FRAGMENT), 1000), None);
+        // Truncated: the total length claims more than was read
+        assert_eq!(fragment_ipv4(&packet(3000, 0)[..2000], 1000), None);
+        // No room for payload after the header
+        assert_eq!(fragment_ipv4(&packet(3000, 0), 27), None);
+        let mut ipv6 = packet(3000, 0);
+        ipv6[0] = 0x60;
+        assert_eq!(fragment_ipv4(&ipv6, 1000), None);
+    }
+
+    #[test]
+    fn checksum_of_a_valid_header_is_zero() {
+        let mut header = packet(0, 0);
+        let checksum = header_checksum(&header);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(header_checksum(&header), 0);
+    }
+}
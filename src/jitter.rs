@@ -0,0 +1,103 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Microseconds since the Unix epoch, the unit of data packet timestamps.
+pub fn timestamp_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_micros() as u64)
+}
+
+/// One-way delay and RFC 3550 interarrival jitter of timestamped packets.
+/// The delay includes the offset between the peers' clocks, the jitter
+/// doesn't depend on it.
+#[derive(Debug, Default)]
+pub struct JitterEstimator {
+    // Receive minus send time of the last packet, in microseconds
+    last_transit: Option<i64>,
+    // Smoothed in microseconds, as RFC 3550 does in timestamp units
+    jitter: f64
+}
+
+impl JitterEstimator {
+    pub fn observe(&mut self, sent_us: u64, received_us: u64) {
+        let transit = received_us as i64 - sent_us as i64;
+        if let Some(last_transit) = self.last_transit {
+            let difference = (transit - last_transit).unsigned_abs() as f64;
+            self.jitter += (difference - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    /// Transit time of the last packet in microseconds. Negative if the
+    /// sender's clock is ahead.
+    pub fn one_way_delay_us(&self) -> Option<i64> {
+        self.last_transit
+    }
+
+    pub fn jitter(&self) -> Option<Duration> {
+        self.last_transit.map(|_| Duration::from_micros(self.jitter as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Packets sent every 20ms
+    fn feed(estimator: &mut JitterEstimator, transits_us: &[u64]) {
+        for (index, transit) in transits_us.iter().enumerate() {
+            let sent = 1_000_000 + index as u64 * 20_000;
+            estimator.observe(sent, sent + transit);
+        }
+    }
+
+    #[test]
+    fn nothing_is_estimated_before_the_first_packet() {
+        let estimator = JitterEstimator::default();
+        assert_eq!(estimator.one_way_delay_us(), None);
+        assert_eq!(estimator.jitter(), None);
+    }
+
+    #[test]
+    fn steady_transit_has_no_jitter() {
+        let mut estimator = JitterEstimator::default();
+        feed(&mut estimator, &[10_000; 50]);
+        assert_eq!(estimator.one_way_delay_us(), Some(10_000));
+        assert_eq!(estimator.jitter(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn jitter_moves_a_sixteenth_of_the_way_per_packet() {
+        let mut estimator = JitterEstimator::default();
+        // Interarrival gaps of 24ms and 16ms, a transit difference of 4ms each time
+        feed(&mut estimator, &[10_000, 14_000]);
+        assert_eq!(estimator.jitter(), Some(Duration::from_micros(250)));
+        feed(&mut estimator, &[10_000]);
+        // 250 + (4000 - 250) / 16
+        assert_eq!(estimator.jitter(), Some(Duration::from_micros(484)));
+    }
+
+    #[test]
+    fn jitter_converges_to_the_transit_difference() {
+        let mut estimator = JitterEstimator::default();
+        let transits: Vec<u64> = (0..300).map(|index| if index % 2 == 0 { 10_000 } else { 14_000 }).collect();
+        feed(&mut estimator, &transits);
+
+        let jitter = estimator.jitter().unwrap().as_micros();
+        assert!((3990..=4000).contains(&jitter), "{}", jitter);
+        assert_eq!(estimator.one_way_delay_us(), Some(14_000));
+    }
+
+    #[test]
+    fn clock_offset_shifts_the_delay_but_not_the_jitter() {
+        let (mut synced, mut ahead) = (JitterEstimator::default(), JitterEstimator::default());
+        for (index, transit) in [10_000, 14_000, 11_000, 10_500].iter().enumerate() {
+            let sent = 1_000_000 + index as u64 * 20_000;
+            synced.observe(sent, sent + transit);
+            // The sender's clock runs 50ms ahead
+            ahead.observe(sent + 50_000, sent + transit);
+        }
+
+        assert_eq!(synced.one_way_delay_us(), Some(10_500));
+        assert_eq!(ahead.one_way_delay_us(), Some(-39_500));
+        assert_eq!(synced.jitter(), ahead.jitter());
+    }
+}
@@ -0,0 +1,55 @@
+pub mod multipathtunnel;
+pub mod settings;
+pub mod tasks;
+pub mod messages;
+pub mod stats;
+pub mod path;
+pub mod clock;
+pub mod pmtud;
+pub mod events;
+pub mod ipfrag;
+pub mod roaming;
+pub mod handle;
+pub mod ratelimit;
+pub mod nat;
+pub mod resolve;
+pub mod flows;
+pub mod error;
+pub mod crypto;
+pub mod dedup;
+pub mod flowlabel;
+pub mod inbound;
+pub mod reorder;
+pub mod tun;
+pub mod fragment;
+pub mod seqguard;
+pub mod snapshot;
+pub mod liveness;
+pub mod hub;
+pub mod rate;
+pub mod transport;
+pub mod jitter;
+pub mod clients;
+pub mod control;
+pub mod cidr;
+pub mod batch;
+pub mod icmp;
+pub mod flowcontrol;
+pub mod outoforder;
+pub mod capture;
+pub mod pending;
+pub mod snat;
+pub mod pathseq;
+pub mod blocklist;
+pub mod mirror;
+pub mod dispatch;
+pub mod weights;
+pub mod mtu;
+pub mod echo;
+pub mod relay;
+pub mod returnpath;
+pub mod pathqueue;
+pub mod dropinject;
+pub mod affinity;
+pub mod checksum;
+pub mod datagram;
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When each peer address was last heard from, on any path. Refreshed by
+/// every valid datagram, including keep-alives and their replies.
+#[derive(Debug, Default)]
+pub struct LastSeen {
+    seen: Mutex<HashMap<SocketAddr, Instant>>
+}
+
+impl LastSeen {
+    pub fn refresh(&self, addr: SocketAddr, now: Instant) {
+        self.seen.lock().unwrap().insert(addr, now);
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<Instant> {
+        self.seen.lock().unwrap().get(addr).copied()
+    }
+
+    pub fn forget(&self, addr: &SocketAddr) {
+        self.seen.lock().unwrap().remove(addr);
+    }
+
+    /// Whether `addr` hasn't been heard from for longer than `timeout`.
+    /// Addresses never heard from start their timeout now.
+    pub fn is_dead(&self, addr: SocketAddr, now: Instant, timeout: Duration) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let last = *seen.entry(addr).or_insert(now);
+        now.saturating_duration_since(last) > timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_restarts_the_timeout() {
+        let (seen, addr, start) = (LastSeen::default(), "127.0.0.1:4000".parse().unwrap(), Instant::now());
+        let timeout = Duration::from_secs(8);
+        seen.refresh(addr, start);
+        assert!(!seen.is_dead(addr, start + timeout, timeout));
+        assert!(seen.is_dead(addr, start + timeout + Duration::from_secs(1), timeout));
+
+        seen.refresh(addr, start + Duration::from_secs(6));
+        assert_eq!(seen.get(&addr), Some(start + Duration::from_secs(6)));
+        assert!(!seen.is_dead(addr, start + Duration::from_secs(12), timeout));
+    }
+
+    #[test]
+    fn address_never_heard_from_starts_its_timeout_when_first_checked() {
+        let (seen, addr, start) = (LastSeen::default(), "127.0.0.1:4000".parse().unwrap(), Instant::now());
+        let timeout = Duration::from_secs(8);
+        assert!(!seen.is_dead(addr, start, timeout));
+        assert_eq!(seen.get(&addr), Some(start));
+        assert!(seen.is_dead(addr, start + Duration::from_secs(9), timeout));
+
+        seen.forget(&addr);
+        assert_eq!(seen.get(&addr), None);
+    }
+}
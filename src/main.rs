@@ -1,28 +1,94 @@
 use clap::{App, load_yaml};
-use serde_json;
 
-mod multipathtunnel;
-mod settings;
-mod tasks;
-mod messages;
+use tokio::runtime::{Handle, Runtime};
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+use mptun::{affinity, multipathtunnel, settings};
+
+fn main() {
     let yaml = load_yaml!("cli.yaml");
-    let matches = App::from(yaml).get_matches();
+    let matches = App::from_yaml(yaml).get_matches();
 
-    let conf_path = match matches.value_of("config") {
-        Some(value) => value,
-        _ => panic!("Failed to get config file path. Does it point to a valid path?")
+    // MPTUN_ environment variables take precedence over the file
+    let conf_path = matches.value_of("config");
+    let settings = match conf_path {
+        Some(path) => settings::SettingsFile::load_with_env(path),
+        None => settings::SettingsFile::from_env()
+    };
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Failed to read the settings: {}", err);
+            std::process::exit(1);
+        }
     };
-
-    let settings: settings::SettingsFile = serde_json::from_str(
-        std::fs::read_to_string(conf_path)
-            .unwrap()
-            .as_str()
-    ).unwrap();
 
     println!("Using settings: {:?}", settings);
 
-    multipathtunnel::run(settings).await;
+    // Receive workers only run in parallel on a multi-threaded runtime
+    let mut runtime = if settings.send_devices.iter().any(|dev| dev.recv_workers.unwrap_or(1) > 1) {
+        tokio::runtime::Builder::new_multi_thread()
+    } else {
+        tokio::runtime::Builder::new_current_thread()
+    };
+    // Kept until the tunnel is done with them
+    let device_runtimes = match device_runtimes(&settings) {
+        Ok(runtimes) => runtimes,
+        Err(err) => {
+            eprintln!("Failed to start the runtime of a send device: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let handles = device_runtimes.iter().map(|(name, runtime)| (name.clone(), runtime.handle().clone())).collect();
+    match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(run(settings, conf_path, handles)),
+        Err(err) => {
+            eprintln!("Failed to start the runtime: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// A runtime pinned to its cpus for each send device that has them
+fn device_runtimes(settings: &settings::SettingsFile) -> std::io::Result<Vec<(String, Runtime)>> {
+    settings.send_devices.iter()
+        .filter_map(|dev| Some((dev.name(), dev.cpus.as_ref()?)))
+        .map(|(name, cpus)| Ok((name.clone(), affinity::pinned_runtime(&name, cpus)?)))
+        .collect()
+}
+
+async fn run(settings: settings::SettingsFile, conf_path: Option<&str>, device_runtimes: Vec<(String, Handle)>) {
+    let mptun = match multipathtunnel::Multipathtunnel::new(settings) {
+        Ok(mptun) => device_runtimes.into_iter().fold(mptun, |mptun, (name, runtime)| mptun.with_device_runtime(&name, runtime)),
+        Err(err) => {
+            eprintln!("Failed to set up the tunnel: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    tokio::select! {
+        result = mptun.run() => {
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        // Without a file there is nothing to reload
+        _ = async {
+            match conf_path {
+                Some(path) => {
+                    if let Err(err) = mptun.reload_on_sighup(path).await {
+                        eprintln!("Failed to listen for SIGHUP, settings won't be reloaded: {}", err);
+                    }
+                    futures::future::pending::<()>().await
+                },
+                None => futures::future::pending().await
+            }
+        } => {},
+        // Let run drain and return
+        _ = async {
+            let _ = tokio::signal::ctrl_c().await;
+            mptun.shutdown();
+            futures::future::pending::<()>().await
+        } => {}
+    }
 }
@@ -1,18 +1,631 @@
+use std::net::IpAddr;
 use serde::{Serialize, Deserialize};
+use bincode::Options;
+use lz4_flex::block::get_maximum_output_size;
+use bytes::Bytes;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Packet {
+    pub seq: usize,
+    // Reference counted so the TUN reader and the per-socket fan-out share one buffer
+    pub bytes: Bytes
+}
+
+/// Borrowing counterpart of `Messages`, serialized identically by bincode.
+/// Lets the send path encode from a scratch buffer without building a
+/// `Packet`, and the receive path decode without copying the payload out
+/// of the datagram.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum MessagesRef<'a> {
+    Packet(#[serde(borrow)] PacketRef<'a>),
+    Keepalive,
+    KeepaliveReply,
+    Probe(u32),
+    ProbeAck(u32),
+    TimestampedPacket(u64, #[serde(borrow)] PacketRef<'a>),
+    Control(#[serde(with = "serde_bytes")] &'a [u8]),
+    SlowDown(u32),
+    PathStampedPacket(PathStamp, Option<u64>, #[serde(borrow)] PacketRef<'a>),
+    Goodbye,
+    Mtu(u32),
+    Echo(u64),
+    EchoReply(u64),
+    SessionPacket(u32, Option<PathStamp>, Option<u64>, #[serde(borrow)] PacketRef<'a>),
+    ReturnPath(u32, IpAddr)
+}
+
+/// The sending path's id and its own sequence number for the packet, see `pathseq`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct PathStamp {
+    pub path_id: u16,
+    pub path_seq: u64
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct PacketRef<'a> {
     pub seq: usize,
     #[serde(with = "serde_bytes")]
-    pub bytes: Vec<u8>
+    pub bytes: &'a [u8]
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
-pub struct Keepalive {
+impl MessagesRef<'_> {
+    /// Copy the payload out into an owned `Messages`.
+    pub fn into_owned(self) -> Messages {
+        let packet = |packet: PacketRef<'_>| Packet { seq: packet.seq, bytes: Bytes::copy_from_slice(packet.bytes) };
+        match self {
+            MessagesRef::Packet(pkt) => Messages::Packet(packet(pkt)),
+            MessagesRef::Keepalive => Messages::Keepalive,
+            MessagesRef::KeepaliveReply => Messages::KeepaliveReply,
+            MessagesRef::Probe(size) => Messages::Probe(size),
+            MessagesRef::ProbeAck(size) => Messages::ProbeAck(size),
+            MessagesRef::TimestampedPacket(timestamp, pkt) => Messages::TimestampedPacket(timestamp, packet(pkt)),
+            MessagesRef::Control(payload) => Messages::Control(Bytes::copy_from_slice(payload)),
+            MessagesRef::SlowDown(duration_ms) => Messages::SlowDown(duration_ms),
+            MessagesRef::PathStampedPacket(stamp, timestamp, pkt) => Messages::PathStampedPacket(stamp, timestamp, packet(pkt)),
+            MessagesRef::Goodbye => Messages::Goodbye,
+            MessagesRef::Mtu(mtu) => Messages::Mtu(mtu),
+            MessagesRef::Echo(tag) => Messages::Echo(tag),
+            MessagesRef::EchoReply(tag) => Messages::EchoReply(tag),
+            MessagesRef::SessionPacket(epoch, stamp, timestamp, pkt) => Messages::SessionPacket(epoch, stamp, timestamp, packet(pkt)),
+            MessagesRef::ReturnPath(generation, tun_ip) => Messages::ReturnPath(generation, tun_ip)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum Messages {
     Packet(Packet),
-    Keepalive
-}
\ No newline at end of file
+    Keepalive,
+    KeepaliveReply,
+    // Path MTU probe padded to the given datagram size, and its acknowledgement
+    Probe(u32),
+    ProbeAck(u32),
+    // A packet with its send time in microseconds since the Unix epoch
+    TimestampedPacket(u64, Packet),
+    // Application defined message between peers, never written to the TUN
+    Control(Bytes),
+    // Asks the peer to send less for the given milliseconds, because its
+    // packets are being dropped for want of queue space
+    SlowDown(u32),
+    // A packet stamped by the path sending it, optionally with its send time
+    PathStampedPacket(PathStamp, Option<u64>, Packet),
+    // The sender is shutting down, so the peer can forget it right away
+    Goodbye,
+    // The largest packet the sender's TUN now takes, sent when its MTU changes
+    Mtu(u32),
+    // Link check sent on demand, which the peer returns with the same tag
+    // over the link it arrived on
+    Echo(u64),
+    EchoReply(u64),
+    // A packet with the sender's session epoch, which changes each time its
+    // tunnel starts, and optionally the path stamp and send time
+    SessionPacket(u32, Option<PathStamp>, Option<u64>, Packet),
+    // Asks the peer to send to the address this arrived from, and the others
+    // announced with the same generation, rather than to all of ours. With
+    // the sender's TUN IP, as the address may be one it never sends from.
+    ReturnPath(u32, IpAddr)
+}
+
+// bincode framing around a compressed payload, at its largest for a session
+// packet: the versioned prefix if any, enum tag (u32), session epoch (u32),
+// path stamp (u16 and u64) and timestamp (u64) each behind an Option tag
+// (u8), seq (u64), byte length prefix (u64) and the u32 uncompressed size
+// prepended by lz4_flex. The compact format's framing is smaller.
+const PACKET_FRAMING_OVERHEAD: usize = 2 + 4 + 4 + 1 + 10 + 1 + 8 + 8 + 8 + 4;
+
+/// Upper bound on the serialized size of a `Messages::Packet` whose payload
+/// decompresses to at most `max_payload_len` bytes.
+pub fn max_message_len(max_payload_len: usize) -> u64 {
+    (PACKET_FRAMING_OVERHEAD + get_maximum_output_size(max_payload_len)) as u64
+}
+
+/// Deserialize a received datagram, refusing to decode more than `limit` bytes.
+/// Uses the encoding of `wire_options`.
+pub fn deserialize_limited(bytes: &[u8], limit: u64) -> bincode::Result<Messages> {
+    if bytes.len() as u64 > limit {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit))
+    }
+    limited_options(limit).deserialize(bytes)
+}
+
+/// The bincode configuration of the Bincode and Versioned wire formats,
+/// spelled out so that a change of bincode's defaults can't change what
+/// goes on the wire: little endian, integers at their full width, enum
+/// tags as u32 and lengths as u64, trailing bytes ignored. These are the
+/// settings `bincode::serialize` used when the format was first deployed.
+pub fn wire_options() -> impl Options {
+    bincode::options()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_no_limit()
+}
+
+// bincode drops the limit when it deserializes from a slice, the input
+// being bounded already. A datagram over the limit is refused by its length
+// before decoding, which bounds the bytes read too.
+fn limited_options(limit: u64) -> impl Options {
+    wire_options().with_limit(limit)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    // serde/bincode encoding of `Messages`, compatible with older peers
+    #[default]
+    Bincode,
+    // Fixed header followed by the payload, see `encode_packet`
+    Compact,
+    // The Bincode encoding behind a magic byte and a version byte, so
+    // datagrams from peers of another version are dropped instead of misread
+    Versioned
+}
+
+pub const VERSIONED_MAGIC: u8 = 0x4d;
+pub const VERSIONED_VERSION: u8 = 1;
+const VERSIONED_PREFIX_LEN: usize = 2;
+
+pub const COMPACT_VERSION: u8 = 1;
+// version (u8), flags (u8), seq (u64, big endian)
+pub const COMPACT_HEADER_LEN: usize = 10;
+
+const FLAG_KEEPALIVE: u8 = 0x01;
+const FLAG_KEEPALIVE_REPLY: u8 = 0x02;
+// Probes carry their size in the seq field
+const FLAG_PROBE: u8 = 0x04;
+const FLAG_PROBE_ACK: u8 = 0x08;
+// A data packet with an 8 byte big endian timestamp after the header
+const FLAG_TIMESTAMP: u8 = 0x10;
+// A control message, its payload after the header
+const FLAG_CONTROL: u8 = 0x20;
+// A slow down request, its duration in the seq field
+const FLAG_SLOW_DOWN: u8 = 0x40;
+// A data packet with the path id (u16) and path sequence number (u64), big
+// endian, after the header and before the timestamp if FLAG_TIMESTAMP is set too
+const FLAG_PATH_STAMP: u8 = 0x80;
+const PATH_STAMP_LEN: usize = 10;
+// A goodbye, with every flag bit taken marked by a combination no other message uses
+const FLAG_GOODBYE: u8 = FLAG_KEEPALIVE | FLAG_KEEPALIVE_REPLY;
+// An MTU announcement, the MTU in the seq field, marked like the goodbye
+const FLAG_MTU: u8 = FLAG_KEEPALIVE | FLAG_PROBE;
+// Echo requests and replies, their tag in the seq field
+const FLAG_ECHO: u8 = FLAG_KEEPALIVE | FLAG_PROBE_ACK;
+const FLAG_ECHO_REPLY: u8 = FLAG_KEEPALIVE_REPLY | FLAG_PROBE_ACK;
+// A data packet with the session epoch (u32, big endian) after the header and
+// before the path stamp and timestamp, if their flags are set too
+const FLAG_SESSION: u8 = FLAG_CONTROL | FLAG_SLOW_DOWN;
+const SESSION_LEN: usize = 4;
+// A return path announcement, its generation in the seq field and the
+// sender's TUN IP (4 or 16 bytes) after the header
+const FLAG_RETURN_PATH: u8 = FLAG_KEEPALIVE_REPLY | FLAG_PROBE;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Bincode(bincode::Error),
+    SizeLimit,
+    Truncated,
+    // A Versioned datagram not starting with the magic byte
+    BadMagic(u8),
+    UnknownVersion(u8),
+    UnknownFlags(u8)
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Bincode(err) => write!(f, "{}", err),
+            DecodeError::SizeLimit => write!(f, "message exceeds size limit"),
+            DecodeError::Truncated => write!(f, "message shorter than header"),
+            DecodeError::BadMagic(magic) => write!(f, "bad magic byte {:#04x}", magic),
+            DecodeError::UnknownVersion(version) => write!(f, "unknown wire format version {}", version),
+            DecodeError::UnknownFlags(flags) => write!(f, "unknown flags {:#04x}", flags)
+        }
+    }
+}
+
+impl DecodeError {
+    pub fn is_size_limit(&self) -> bool {
+        match self {
+            DecodeError::SizeLimit => true,
+            DecodeError::Bincode(err) => matches!(**err, bincode::ErrorKind::SizeLimit),
+            _ => false
+        }
+    }
+
+    /// Whether the datagram is of another wire format version, or not of this format at all.
+    pub fn is_version_mismatch(&self) -> bool {
+        matches!(self, DecodeError::BadMagic(_) | DecodeError::UnknownVersion(_))
+    }
+}
+
+/// Encode a message for the wire.
+///
+/// The compact format is a 10 byte header (version, flags, 8 byte big endian
+/// seq) followed by the raw packet bytes, with flags marking keep-alives,
+/// probes and control messages. Timestamped packets have the timestamp
+/// between header and bytes, path stamped ones the stamp before that and
+/// session packets the epoch before both.
+/// The versioned format is the bincode one with `VERSIONED_MAGIC` and
+/// `VERSIONED_VERSION` in front. Fails if bincode can't serialize the message.
+pub fn encode_packet(msg: &Messages, format: WireFormat) -> bincode::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match msg {
+        Messages::Packet(pkt) => encode_data_into(pkt.seq, None, None, None, &pkt.bytes, format, &mut buf)?,
+        Messages::TimestampedPacket(timestamp, pkt) => encode_data_into(pkt.seq, None, Some(*timestamp), None, &pkt.bytes, format, &mut buf)?,
+        Messages::PathStampedPacket(stamp, timestamp, pkt) => encode_data_into(pkt.seq, None, *timestamp, Some(*stamp), &pkt.bytes, format, &mut buf)?,
+        Messages::SessionPacket(epoch, stamp, timestamp, pkt) => encode_data_into(pkt.seq, Some(*epoch), *timestamp, *stamp, &pkt.bytes, format, &mut buf)?,
+        _ => match (format, msg) {
+            (WireFormat::Bincode, _) => wire_options().serialize_into(&mut buf, msg)?,
+            (WireFormat::Versioned, _) => {
+                write_versioned_prefix(&mut buf);
+                wire_options().serialize_into(&mut buf, msg)?
+            },
+            (WireFormat::Compact, Messages::Control(payload)) => {
+                write_compact_header(FLAG_CONTROL, 0, &mut buf);
+                buf.extend_from_slice(payload);
+            },
+            (WireFormat::Compact, _) => {
+                let (flags, seq) = match msg {
+                    Messages::Keepalive => (FLAG_KEEPALIVE, 0),
+                    Messages::KeepaliveReply => (FLAG_KEEPALIVE_REPLY, 0),
+                    Messages::Probe(size) => (FLAG_PROBE, *size as usize),
+                    Messages::ProbeAck(size) => (FLAG_PROBE_ACK, *size as usize),
+                    Messages::SlowDown(duration_ms) => (FLAG_SLOW_DOWN, *duration_ms as usize),
+                    Messages::Goodbye => (FLAG_GOODBYE, 0),
+                    Messages::Mtu(mtu) => (FLAG_MTU, *mtu as usize),
+                    Messages::Echo(tag) => (FLAG_ECHO, *tag as usize),
+                    Messages::EchoReply(tag) => (FLAG_ECHO_REPLY, *tag as usize),
+                    Messages::ReturnPath(generation, _) => (FLAG_RETURN_PATH, *generation as usize),
+                    Messages::Packet(_) | Messages::TimestampedPacket(..) | Messages::PathStampedPacket(..) | Messages::SessionPacket(..) | Messages::Control(_) => unreachable!()
+                };
+                write_compact_header(flags, seq, &mut buf);
+                match msg {
+                    Messages::ReturnPath(_, IpAddr::V4(tun_ip)) => buf.extend_from_slice(&tun_ip.octets()),
+                    Messages::ReturnPath(_, IpAddr::V6(tun_ip)) => buf.extend_from_slice(&tun_ip.octets()),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(buf)
+}
+
+/// Encode a data packet, with the session epoch if `epoch` is set,
+/// timestamped if `timestamp` is set and path stamped if `stamp` is, into
+/// `out`, replacing its contents. Reusing `out` across calls avoids allocating once it has grown
+/// to the largest packet. On failure `out` holds a partial encoding.
+pub fn encode_data_into(seq: usize, epoch: Option<u32>, timestamp: Option<u64>, stamp: Option<PathStamp>, payload: &[u8], format: WireFormat, out: &mut Vec<u8>) -> bincode::Result<()> {
+    out.clear();
+    let packet = PacketRef { seq, bytes: payload };
+    if format == WireFormat::Versioned {
+        write_versioned_prefix(out);
+    }
+    match (format, epoch, stamp, timestamp) {
+        (WireFormat::Bincode | WireFormat::Versioned, Some(epoch), stamp, timestamp) => {
+            wire_options().serialize_into(&mut *out, &MessagesRef::SessionPacket(epoch, stamp, timestamp, packet))?
+        },
+        (WireFormat::Bincode | WireFormat::Versioned, None, None, None) => wire_options().serialize_into(&mut *out, &MessagesRef::Packet(packet))?,
+        (WireFormat::Bincode | WireFormat::Versioned, None, None, Some(timestamp)) => {
+            wire_options().serialize_into(&mut *out, &MessagesRef::TimestampedPacket(timestamp, packet))?
+        },
+        (WireFormat::Bincode | WireFormat::Versioned, None, Some(stamp), timestamp) => {
+            wire_options().serialize_into(&mut *out, &MessagesRef::PathStampedPacket(stamp, timestamp, packet))?
+        },
+        (WireFormat::Compact, epoch, stamp, timestamp) => {
+            let flags = epoch.map_or(0, |_| FLAG_SESSION) | stamp.map_or(0, |_| FLAG_PATH_STAMP) | timestamp.map_or(0, |_| FLAG_TIMESTAMP);
+            write_compact_header(flags, seq, out);
+            if let Some(epoch) = epoch {
+                out.extend_from_slice(&epoch.to_be_bytes());
+            }
+            if let Some(stamp) = stamp {
+                out.extend_from_slice(&stamp.path_id.to_be_bytes());
+                out.extend_from_slice(&stamp.path_seq.to_be_bytes());
+            }
+            if let Some(timestamp) = timestamp {
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            out.extend_from_slice(payload);
+        }
+    }
+    Ok(())
+}
+
+fn write_versioned_prefix(out: &mut Vec<u8>) {
+    out.push(VERSIONED_MAGIC);
+    out.push(VERSIONED_VERSION);
+}
+
+fn write_compact_header(flags: u8, seq: usize, out: &mut Vec<u8>) {
+    out.push(COMPACT_VERSION);
+    out.push(flags);
+    out.extend_from_slice(&(seq as u64).to_be_bytes());
+}
+
+/// Decode a datagram, refusing anything larger than `limit` bytes.
+pub fn decode_packet(bytes: &[u8], format: WireFormat, limit: u64) -> Result<Messages, DecodeError> {
+    decode_packet_ref(bytes, format, limit).map(MessagesRef::into_owned)
+}
+
+/// Like `decode_packet`, with the payload borrowed from `bytes`.
+pub fn decode_packet_ref(bytes: &[u8], format: WireFormat, limit: u64) -> Result<MessagesRef<'_>, DecodeError> {
+    if bytes.len() as u64 > limit {
+        return Err(DecodeError::SizeLimit)
+    }
+    match format {
+        WireFormat::Bincode => limited_options(limit).deserialize(bytes).map_err(DecodeError::Bincode),
+        WireFormat::Versioned => {
+            match bytes {
+                [VERSIONED_MAGIC, VERSIONED_VERSION, ..] => {},
+                [VERSIONED_MAGIC, version, ..] => return Err(DecodeError::UnknownVersion(*version)),
+                [magic, _, ..] => return Err(DecodeError::BadMagic(*magic)),
+                _ => return Err(DecodeError::Truncated)
+            }
+            limited_options(limit).deserialize(&bytes[VERSIONED_PREFIX_LEN..]).map_err(DecodeError::Bincode)
+        },
+        WireFormat::Compact => {
+            if bytes.len() < COMPACT_HEADER_LEN {
+                return Err(DecodeError::Truncated)
+            }
+            if bytes[0] != COMPACT_VERSION {
+                return Err(DecodeError::UnknownVersion(bytes[0]))
+            }
+
+            let mut seq = [0u8; 8];
+            seq.copy_from_slice(&bytes[2..COMPACT_HEADER_LEN]);
+
+            let seq = u64::from_be_bytes(seq);
+            match bytes[1] {
+                0 => Ok(MessagesRef::Packet(PacketRef {
+                    seq: seq as usize,
+                    bytes: &bytes[COMPACT_HEADER_LEN..]
+                })),
+                FLAG_TIMESTAMP => {
+                    let payload = bytes.get(COMPACT_HEADER_LEN + 8..).ok_or(DecodeError::Truncated)?;
+                    let mut timestamp = [0u8; 8];
+                    timestamp.copy_from_slice(&bytes[COMPACT_HEADER_LEN..COMPACT_HEADER_LEN + 8]);
+                    Ok(MessagesRef::TimestampedPacket(u64::from_be_bytes(timestamp), PacketRef {
+                        seq: seq as usize,
+                        bytes: payload
+                    }))
+                },
+                flags if flags & !FLAG_TIMESTAMP == FLAG_PATH_STAMP => {
+                    let mut rest = &bytes[COMPACT_HEADER_LEN..];
+                    let stamp = take_stamp(&mut rest)?;
+                    let timestamp = if flags & FLAG_TIMESTAMP != 0 { Some(take_timestamp(&mut rest)?) } else { None };
+                    Ok(MessagesRef::PathStampedPacket(stamp, timestamp, PacketRef { seq: seq as usize, bytes: rest }))
+                },
+                flags if flags & !(FLAG_PATH_STAMP | FLAG_TIMESTAMP) == FLAG_SESSION => {
+                    let epoch = bytes.get(COMPACT_HEADER_LEN..COMPACT_HEADER_LEN + SESSION_LEN).ok_or(DecodeError::Truncated)?;
+                    let epoch = u32::from_be_bytes([epoch[0], epoch[1], epoch[2], epoch[3]]);
+                    let mut rest = &bytes[COMPACT_HEADER_LEN + SESSION_LEN..];
+                    let stamp = if flags & FLAG_PATH_STAMP != 0 { Some(take_stamp(&mut rest)?) } else { None };
+                    let timestamp = if flags & FLAG_TIMESTAMP != 0 { Some(take_timestamp(&mut rest)?) } else { None };
+                    Ok(MessagesRef::SessionPacket(epoch, stamp, timestamp, PacketRef { seq: seq as usize, bytes: rest }))
+                },
+                FLAG_CONTROL => Ok(MessagesRef::Control(&bytes[COMPACT_HEADER_LEN..])),
+                FLAG_KEEPALIVE => Ok(MessagesRef::Keepalive),
+                FLAG_KEEPALIVE_REPLY => Ok(MessagesRef::KeepaliveReply),
+                FLAG_PROBE => Ok(MessagesRef::Probe(seq as u32)),
+                FLAG_PROBE_ACK => Ok(MessagesRef::ProbeAck(seq as u32)),
+                FLAG_SLOW_DOWN => Ok(MessagesRef::SlowDown(seq as u32)),
+                FLAG_GOODBYE => Ok(MessagesRef::Goodbye),
+                FLAG_MTU => Ok(MessagesRef::Mtu(seq as u32)),
+                FLAG_ECHO => Ok(MessagesRef::Echo(seq)),
+                FLAG_ECHO_REPLY => Ok(MessagesRef::EchoReply(seq)),
+                FLAG_RETURN_PATH => {
+                    let tun_ip = match &bytes[COMPACT_HEADER_LEN..] {
+                        octets if octets.len() == 4 => IpAddr::from([octets[0], octets[1], octets[2], octets[3]]),
+                        octets if octets.len() == 16 => {
+                            let mut v6 = [0u8; 16];
+                            v6.copy_from_slice(octets);
+                            IpAddr::from(v6)
+                        },
+                        _ => return Err(DecodeError::Truncated)
+                    };
+                    Ok(MessagesRef::ReturnPath(seq as u32, tun_ip))
+                },
+                flags => Err(DecodeError::UnknownFlags(flags))
+            }
+        }
+    }
+}
+
+// Split a compact path stamp off the front of `rest`
+fn take_stamp(rest: &mut &[u8]) -> Result<PathStamp, DecodeError> {
+    let stamp = rest.get(..PATH_STAMP_LEN).ok_or(DecodeError::Truncated)?;
+    let mut path_seq = [0u8; 8];
+    path_seq.copy_from_slice(&stamp[2..]);
+    let stamp = PathStamp { path_id: u16::from_be_bytes([stamp[0], stamp[1]]), path_seq: u64::from_be_bytes(path_seq) };
+    *rest = &rest[PATH_STAMP_LEN..];
+    Ok(stamp)
+}
+
+// Split a compact timestamp off the front of `rest`
+fn take_timestamp(rest: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(rest.get(..8).ok_or(DecodeError::Truncated)?);
+    *rest = &rest[8..];
+    Ok(u64::from_be_bytes(timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [WireFormat; 3] = [WireFormat::Bincode, WireFormat::Compact, WireFormat::Versioned];
+
+    fn packet(seq: usize, bytes: &'static [u8]) -> Packet {
+        Packet { seq, bytes: Bytes::from_static(bytes) }
+    }
+
+    fn every_message() -> Vec<Messages> {
+        let stamp = PathStamp { path_id: 7, path_seq: 1 << 40 };
+        vec![
+            Messages::Packet(packet(1, b"payload")),
+            Messages::Packet(packet(usize::MAX, b"")),
+            Messages::Keepalive,
+            Messages::KeepaliveReply,
+            Messages::Probe(1400),
+            Messages::ProbeAck(1400),
+            Messages::TimestampedPacket(1_700_000_000_000_000, packet(2, b"timed")),
+            Messages::Control(Bytes::from_static(b"control")),
+            Messages::SlowDown(2000),
+            Messages::PathStampedPacket(stamp, None, packet(3, b"stamped")),
+            Messages::PathStampedPacket(stamp, Some(5), packet(4, b"stamped and timed")),
+            Messages::Goodbye,
+            Messages::Mtu(9000),
+            Messages::Echo(u64::MAX),
+            Messages::EchoReply(42),
+            Messages::SessionPacket(0xdead_beef, None, None, packet(5, b"session")),
+            Messages::SessionPacket(1, Some(stamp), Some(6), packet(6, b"everything")),
+            Messages::ReturnPath(3, IpAddr::from([10, 0, 0, 1])),
+            Messages::ReturnPath(4, "fd00::1".parse().unwrap())
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips_in_every_format() {
+        for format in FORMATS {
+            for msg in every_message() {
+                let encoded = encode_packet(&msg, format).unwrap();
+                let decoded = decode_packet(&encoded, format, u64::MAX).unwrap_or_else(|err| panic!("{:?} in {:?}: {}", msg, format, err));
+                assert_eq!(decoded, msg, "in {:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn borrowed_decoding_points_into_the_datagram() {
+        for format in FORMATS {
+            for msg in every_message() {
+                let encoded = encode_packet(&msg, format).unwrap();
+                let decoded = decode_packet_ref(&encoded, format, u64::MAX).unwrap();
+                let payload = match decoded {
+                    MessagesRef::Packet(pkt)
+                    | MessagesRef::TimestampedPacket(_, pkt)
+                    | MessagesRef::PathStampedPacket(_, _, pkt)
+                    | MessagesRef::SessionPacket(_, _, _, pkt) => Some(pkt.bytes),
+                    MessagesRef::Control(payload) => Some(payload),
+                    _ => None
+                };
+                if let Some(payload) = payload {
+                    assert!(encoded.as_ptr_range().contains(&payload.as_ptr()) || payload.is_empty(), "{:?} in {:?} was copied", msg, format);
+                }
+                assert_eq!(decoded.into_owned(), msg, "in {:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_data_into_matches_encode_packet() {
+        for format in FORMATS {
+            let mut out = vec![0xff; 3];
+            encode_data_into(9, None, Some(11), None, b"data", format, &mut out).unwrap();
+            assert_eq!(out, encode_packet(&Messages::TimestampedPacket(11, packet(9, b"data")), format).unwrap());
+        }
+    }
+
+    #[test]
+    fn compact_format_is_smaller_by_the_bincode_framing() {
+        let msg = Messages::Packet(packet(1, &[0xab; 64]));
+        let bincode = encode_packet(&msg, WireFormat::Bincode).unwrap();
+        let compact = encode_packet(&msg, WireFormat::Compact).unwrap();
+
+        assert_eq!(compact.len(), COMPACT_HEADER_LEN + 64);
+        // Enum tag (u32), seq (u64) and length prefix (u64)
+        assert_eq!(bincode.len(), 4 + 8 + 8 + 64);
+        assert!(compact.len() < bincode.len());
+        assert_eq!(encode_packet(&Messages::Keepalive, WireFormat::Compact).unwrap().len(), COMPACT_HEADER_LEN);
+    }
+
+    #[test]
+    fn compact_datagrams_of_another_version_or_too_short_are_refused() {
+        let mut encoded = encode_packet(&Messages::Packet(packet(1, b"x")), WireFormat::Compact).unwrap();
+        assert!(matches!(decode_packet(&encoded[..COMPACT_HEADER_LEN - 1], WireFormat::Compact, u64::MAX), Err(DecodeError::Truncated)));
+        encoded[0] = COMPACT_VERSION + 1;
+        assert!(matches!(decode_packet(&encoded, WireFormat::Compact, u64::MAX), Err(DecodeError::UnknownVersion(_))));
+
+        let mut flags = encode_packet(&Messages::Keepalive, WireFormat::Compact).unwrap();
+        flags[1] = FLAG_PROBE | FLAG_PROBE_ACK;
+        assert!(matches!(decode_packet(&flags, WireFormat::Compact, u64::MAX), Err(DecodeError::UnknownFlags(_))));
+    }
+
+    #[test]
+    fn versioned_datagrams_of_another_version_or_format_are_refused() {
+        let msg = Messages::Packet(packet(1, b"x"));
+        let encoded = encode_packet(&msg, WireFormat::Versioned).unwrap();
+        assert_eq!(&encoded[..VERSIONED_PREFIX_LEN], &[VERSIONED_MAGIC, VERSIONED_VERSION]);
+        assert_eq!(&encoded[VERSIONED_PREFIX_LEN..], &encode_packet(&msg, WireFormat::Bincode).unwrap()[..]);
+        assert_eq!(decode_packet(&encoded, WireFormat::Versioned, u64::MAX).unwrap(), msg);
+
+        let mut newer = encoded.clone();
+        newer[1] = VERSIONED_VERSION + 1;
+        let err = decode_packet(&newer, WireFormat::Versioned, u64::MAX).unwrap_err();
+        assert!(matches!(err, DecodeError::UnknownVersion(version) if version == VERSIONED_VERSION + 1));
+        assert!(err.is_version_mismatch());
+
+        // A peer still on the unversioned format
+        let unversioned = encode_packet(&msg, WireFormat::Bincode).unwrap();
+        assert!(decode_packet(&unversioned, WireFormat::Versioned, u64::MAX).unwrap_err().is_version_mismatch());
+        assert!(matches!(decode_packet(&[VERSIONED_MAGIC], WireFormat::Versioned, u64::MAX), Err(DecodeError::Truncated)));
+    }
+
+    #[test]
+    fn wire_options_encode_as_first_deployed() {
+        for message in every_message() {
+            assert_eq!(wire_options().serialize(&message).unwrap(), bincode::serialize(&message).unwrap(), "{:?}", message);
+        }
+        // Tag as a little endian u32, then seq and the byte length as little endian u64s
+        let encoded = encode_packet(&Messages::Packet(packet(0x0102, b"ab")), WireFormat::Bincode).unwrap();
+        assert_eq!(encoded, [0, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        assert_eq!(decode_packet(&encoded, WireFormat::Bincode, u64::MAX).unwrap(), Messages::Packet(packet(0x0102, b"ab")));
+    }
+
+    #[test]
+    fn datagrams_over_the_limit_are_refused_in_every_format() {
+        let message = Messages::Packet(Packet { seq: 1, bytes: Bytes::from(vec![0; 320]) });
+        for format in FORMATS {
+            let encoded = encode_packet(&message, format).unwrap();
+            assert!(decode_packet(&encoded, format, 100).unwrap_err().is_size_limit(), "{:?}", format);
+            assert_eq!(decode_packet(&encoded, format, encoded.len() as u64).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn the_largest_packets_fit_in_max_message_len() {
+        const MAX_PAYLOAD_LEN: usize = 1500;
+        // Bytes lz4 can't compress, so it adds its worst case
+        let mut state = 1u32;
+        let payload: Vec<u8> = (0..MAX_PAYLOAD_LEN).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        let compressed = lz4_flex::compress_prepend_size(&payload);
+        let stamp = PathStamp { path_id: u16::MAX, path_seq: u64::MAX };
+        let limit = max_message_len(MAX_PAYLOAD_LEN);
+        for format in FORMATS {
+            let mut out = Vec::new();
+            encode_data_into(usize::MAX, Some(u32::MAX), Some(u64::MAX), Some(stamp), &compressed, format, &mut out).unwrap();
+            assert!(out.len() as u64 <= limit, "{:?}: {} > {}", format, out.len(), limit);
+            assert!(decode_packet(&out, format, limit).is_ok());
+        }
+    }
+
+    #[test]
+    fn datagram_over_the_limit_is_refused_before_decoding() {
+        let msg = Messages::Packet(Packet { seq: 1, bytes: Bytes::from(vec![0; 100]) });
+        let encoded = wire_options().serialize(&msg).unwrap();
+
+        assert!(deserialize_limited(&encoded, encoded.len() as u64 - 1).unwrap_err().to_string().contains("limit"));
+        assert_eq!(deserialize_limited(&encoded, encoded.len() as u64).unwrap(), msg);
+    }
+
+    #[test]
+    fn length_prefix_claiming_more_than_the_limit_is_refused() {
+        let mut encoded = wire_options().serialize(&Messages::Packet(Packet { seq: 1, bytes: Bytes::from_static(b"short") })).unwrap();
+        // The byte length prefix follows the enum tag (u32) and seq (u64)
+        encoded[12..20].copy_from_slice(&(1u64 << 40).to_le_bytes());
+
+        assert!(deserialize_limited(&encoded, 1500).is_err());
+        assert!(decode_packet(&encoded, WireFormat::Bincode, 1500).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// Copies of the packets delivered to the TUN, for monitoring such as an IDS.
+// Sent to a UDP collector, one datagram per packet, and/or appended to a
+// pcap file of raw IP packets. Mirroring never holds up delivery: copies
+// the writer can't keep up with are dropped.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use bytes::Bytes;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+
+use crate::jitter;
+use crate::settings::MirrorSettings;
+
+// Packets buffered for the writer before the oldest are dropped
+pub const MIRROR_CAPACITY: usize = 4096;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+// Packets begin with the IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+
+/// Sender side of the mirror. Copies are only made while it is written.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    sender: broadcast::Sender<Bytes>
+}
+
+impl Mirror {
+    pub fn new(capacity: usize) -> Mirror {
+        let (sender, _) = broadcast::channel(capacity);
+        Mirror { sender }
+    }
+
+    pub fn is_observed(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn record(&self, packet: Bytes) {
+        // Only fails when nothing is mirroring
+        let _ = self.sender.send(packet);
+    }
+}
+
+/// Write the packets of `mirror` to the targets of `settings`, until
+/// `still_wanted` turns false. Checked as packets come in, like the capture.
+pub async fn write_packets(mirror: &Mirror, settings: &MirrorSettings, still_wanted: impl Fn() -> bool) -> io::Result<()> {
+    let socket = match settings.collector {
+        Some(collector) => {
+            let unspecified = match collector.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            };
+            Some((UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?, collector))
+        },
+        None => None
+    };
+    let mut pcap = match &settings.pcap {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path).await?;
+            let is_new = file.metadata().await?.len() == 0;
+            let mut writer = BufWriter::new(file);
+            if is_new {
+                writer.write_all(&pcap_header()).await?;
+            }
+            Some(writer)
+        },
+        None => None
+    };
+
+    let mut packets = mirror.sender.subscribe();
+    loop {
+        let received = match packets.try_recv() {
+            Err(broadcast::error::TryRecvError::Empty) => {
+                if let Some(pcap) = &mut pcap {
+                    pcap.flush().await?;
+                }
+                packets.recv().await
+            },
+            Ok(packet) => Ok(packet),
+            Err(broadcast::error::TryRecvError::Lagged(missed)) => Err(broadcast::error::RecvError::Lagged(missed)),
+            Err(broadcast::error::TryRecvError::Closed) => Err(broadcast::error::RecvError::Closed)
+        };
+        let packet = match received {
+            Ok(packet) => packet,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!("Mirror fell behind, {} packets lost", missed);
+                continue
+            },
+            Err(broadcast::error::RecvError::Closed) => break
+        };
+        if !still_wanted() {
+            break
+        }
+
+        if let Some((socket, collector)) = &socket {
+            // Best effort, a collector that is down doesn't stop the pcap
+            let _ = socket.send_to(&packet, collector).await;
+        }
+        if let Some(pcap) = &mut pcap {
+            pcap.write_all(&pcap_record_header(jitter::timestamp_now(), packet.len())).await?;
+            pcap.write_all(&packet).await?;
+        }
+    }
+
+    if let Some(pcap) = &mut pcap {
+        pcap.flush().await?;
+    }
+    Ok(())
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Time zone offset and timestamp accuracy, both 0 by convention
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+// IP packets are never longer than the snap length, so they are captured whole
+fn pcap_record_header(timestamp_us: u64, len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(&((timestamp_us / 1_000_000) as u32).to_le_bytes());
+    header.extend_from_slice(&((timestamp_us % 1_000_000) as u32).to_le_bytes());
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header
+}
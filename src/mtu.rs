@@ -0,0 +1,71 @@
+// The TUN MTU, which may be changed underneath the tunnel with `ip link set
+// mtu`, and the MTUs peers announced when theirs changed. Packets read from
+// the TUN are only sent whole up to the smaller of ours and the peer's, so
+// the peer doesn't drop them as oversized.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Reads the TUN device's current MTU. `None` while it can't be read.
+pub type MtuReader = Arc<dyn Fn() -> Option<usize> + Send + Sync>;
+
+#[derive(Debug)]
+pub struct Mtus {
+    local: AtomicUsize,
+    peers: RwLock<HashMap<IpAddr, usize>>
+}
+
+impl Mtus {
+    pub fn new(local: usize) -> Mtus {
+        Mtus { local: AtomicUsize::new(local), peers: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn local(&self) -> usize {
+        self.local.load(Ordering::Relaxed)
+    }
+
+    /// Returns the previous MTU.
+    pub fn set_local(&self, mtu: usize) -> usize {
+        self.local.swap(mtu, Ordering::Relaxed)
+    }
+
+    pub fn set_peer(&self, tun_ip: IpAddr, mtu: usize) {
+        self.peers.write().unwrap().insert(tun_ip, mtu);
+    }
+
+    pub fn forget(&self, tun_ip: &IpAddr) {
+        self.peers.write().unwrap().remove(tun_ip);
+    }
+
+    /// The largest packet sent whole to `tun_ip`: our MTU, or the peer's
+    /// if it announced a smaller one.
+    pub fn for_peer(&self, tun_ip: Option<&IpAddr>) -> usize {
+        let peer = tun_ip.and_then(|tun_ip| self.peers.read().unwrap().get(tun_ip).copied());
+        peer.map_or(self.local(), |peer| peer.min(self.local()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_for_a_peer_fit_the_smaller_of_both_mtus() {
+        let mtus = Mtus::new(1424);
+        let (peer, other): (IpAddr, IpAddr) = ([10, 0, 0, 2].into(), [10, 0, 0, 3].into());
+        mtus.set_peer(peer, 1000);
+        assert_eq!(mtus.for_peer(Some(&peer)), 1000);
+        assert_eq!(mtus.for_peer(Some(&other)), 1424);
+        assert_eq!(mtus.for_peer(None), 1424);
+
+        // A peer announcing more than ours still gets ours
+        assert_eq!(mtus.set_local(900), 1424);
+        assert_eq!(mtus.for_peer(Some(&peer)), 900);
+
+        mtus.forget(&peer);
+        assert_eq!(mtus.set_local(1424), 900);
+        assert_eq!(mtus.for_peer(Some(&peer)), 1424);
+    }
+}
@@ -1,166 +1,2301 @@
-use tokio_tun::TunBuilder;
-use std::net::{Ipv4Addr,
-               SocketAddrV4,
-               SocketAddr,
+use std::collections::HashMap;
+use std::net::{SocketAddr,
                IpAddr};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::Ordering;
 use std::os::unix::io::AsRawFd;
-use std::collections::HashMap;
+use std::fs::File;
+use std::time::{Duration, Instant};
+use std::future::Future;
 use tokio::{net::UdpSocket,
-            task};
+            runtime::Handle,
+            signal::unix::{signal, SignalKind},
+            sync::{broadcast, mpsc, watch},
+            task::{self, JoinHandle}};
 use socket2::{Domain, Socket, Type};
+use bytes::Bytes;
 use std::net::UdpSocket as std_udp;
 
-use crate::settings::SettingsFile;
-use crate::tasks;
-use crate::messages::Packet;
+use crate::settings::{DeliveryMode, FastestSettings, PmtudSettings, RelayProtocol, SettingsFile, SendDevice, DEFAULT_TUN_MTU};
+use crate::tasks::{self, DeliveryConfig, HandshakeConfig, KeepAliveConfig, ProbeConfig, RecvState, TaskConfig, TunSequence};
+use crate::pmtud::PmtuSearch;
+use crate::messages::{self, Messages};
+use crate::stats::{PeerTraffic, Stats};
+use crate::path::{self, Health, HealthPolicy, Path, Paths};
+use crate::weights::WeightPolicy;
+use crate::mtu::{MtuReader, Mtus};
+use crate::echo::{Echoes, ProbeResult};
+use crate::returnpath::ReturnPaths;
+use crate::pathqueue::PATH_QUEUE_CAPACITY;
+use crate::dropinject::DropInjector;
+use crate::clock::{Interval, SharedClock, SystemClock};
+use crate::events::{Event, Events, PacketEvent, PacketEvents, EVENTS_CAPACITY, PACKET_EVENTS_CAPACITY};
+use crate::handle::{HealthReport, PathHealth, PeerAddr, PeerInfo, Throughput, TunnelHandle};
+use crate::nat::{self, NatPeers};
+use crate::resolve;
+use crate::snapshot;
+use crate::liveness::LastSeen;
+use crate::hub::Forwarder;
+use crate::pending::{PendingConfig, PendingPackets};
+use crate::dispatch::Dispatcher;
+use crate::blocklist::Blocklist;
+use crate::cidr::Cidr;
+use crate::snat::SourceNat;
+use crate::crypto::{Keys, ENCRYPTION_OVERHEAD};
+use crate::checksum::CHECKSUM_LEN;
+use crate::error::{TaskOutcome, TaskReport, TunnelError};
+use crate::reorder::ReorderConfig;
+use crate::seqguard::{self, SeqGuardConfig};
+use crate::tun::{memory_tun, InboundDelivery, InboundSink, KernelTun, MemoryTunPeer, TunDevice, TunFactory};
+use crate::inbound::{InboundQueues, INBOUND_QUEUE_CAPACITY};
+use crate::transport::{Families, Transport, UnixTransport};
+use crate::clients::Clients;
+use crate::control::{ControlMessage, ControlMessages, CONTROL_CAPACITY};
+use crate::flowcontrol::{FlowControl, SlowDownConfig};
+use crate::capture::{self, Capture, CaptureRecord, CAPTURE_CAPACITY};
+use crate::mirror::{self, Mirror, MIRROR_CAPACITY};
+use crate::relay::{self, RelayTransport};
+
+// Allowance on top of the TUN MTU before a received payload is considered oversized
+const MAX_PAYLOAD_SLACK: usize = 64;
+
+// Seconds between re-resolving the remote host, unless configured
+const DEFAULT_RESOLVE_INTERVAL: u64 = 300;
+
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 10;
+
+// How often to check whether a reload turned capturing or mirroring on
+const CAPTURE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 1000;
+
+const DEFAULT_NAT_REBIND_GRACE: u64 = 30;
+
+const DEFAULT_FASTEST_STAGGER_MS: u64 = 20;
+
+const DEFAULT_AUTO_WEIGHT_INTERVAL_MS: u64 = 5000;
+const DEFAULT_WEIGHT_ADAPT_RATE: f64 = 0.3;
+const DEFAULT_MIN_WEIGHT: f64 = 0.05;
+const DEFAULT_MAX_WEIGHT: f64 = 1.0;
+
+const DEFAULT_PATH_DOWN_AFTER: u32 = 3;
+const DEFAULT_PATH_UP_AFTER: u32 = 2;
+const DEFAULT_FLAP_TRANSITIONS: usize = 4;
+const DEFAULT_FLAP_WINDOW: u64 = 60;
+
+const DEFAULT_HANDSHAKE_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_HANDSHAKE_MAX_BACKOFF_MS: u64 = 30_000;
+
+const DEFAULT_SLOW_DOWN_THRESHOLD: u32 = 32;
+const DEFAULT_SLOW_DOWN_WINDOW_MS: u64 = 1000;
+const DEFAULT_SLOW_DOWN_DURATION_MS: u64 = 2000;
 
-type ClientList = Arc< RwLock< HashMap< IpAddr, Vec< SocketAddr > > > >;
+// Time between bind attempts while waiting for an interface with bind_timeout
+const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(250);
 
-struct Multipathtunnel {
-    sockets: Vec<Arc<UdpSocket>>,
-    client_list: ClientList
+// Datagram size path MTU discovery starts from, fits the IPv6 minimum MTU with headers to spare
+const PMTUD_MIN_DATAGRAM: usize = 1200;
+const DEFAULT_PROBE_INTERVAL_MS: u64 = 1000;
+const DEFAULT_REPROBE_INTERVAL: u64 = 600;
+
+// How often the TUN MTU is read to notice changes
+const TUN_MTU_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long probe_path waits for the echo
+const PATH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How often peer_paths receive devices are announced to their peer, and how
+// many announcements in a row an address may miss before it isn't sent to
+const RETURN_PATH_INTERVAL: Duration = Duration::from_secs(5);
+const RETURN_PATH_MISSED: u32 = 3;
+
+// How often max_idle and max_lifetime are checked
+const LIFETIME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Seconds between checking whether a reload enabled client_timeout
+const DEFAULT_REAP_INTERVAL: u64 = 60;
+
+const DEFAULT_REORDER_PACKETS: usize = 256;
+
+// Peer removals buffered per task. Tasks that fall behind drop all per-peer state.
+const PEER_REMOVALS_CAPACITY: usize = 64;
+// Goodbyes from peers waiting to be acted on. Peers whose goodbye doesn't fit are left to time out.
+const GOODBYES_CAPACITY: usize = 64;
+// How long a peer must stay quiet after its goodbye to be removed. A goodbye
+// isn't authenticated beyond the datagram, so a replayed or forged one mustn't
+// cut off a peer that is still sending.
+const GOODBYE_GRACE: Duration = Duration::from_millis(500);
+const DEFAULT_REORDER_TIMEOUT_MS: u64 = 50;
+
+// Sequence numbers remembered to suppress duplicates from other links. Must
+// cover the largest difference in delay between links, in packets.
+const MIN_DEDUP_WINDOW: usize = 4096;
+
+// Larger than the usual dedup window, so a restarted peer's numbers aren't taken for duplicates
+const DEFAULT_MAX_BACKWARD_JUMP: usize = 16384;
+
+// Packets held per unknown peer, and for how long, until it's discovered
+const DEFAULT_UNKNOWN_PEER_PACKETS: usize = 8;
+const DEFAULT_UNKNOWN_PEER_AGE_MS: u64 = 1000;
+
+pub type ClientList = Arc<Clients>;
+
+// A send device with its socket, path state and, while running, its tasks
+struct Device {
+    settings: SendDevice,
+    socket: Arc<dyn Transport>,
+    path: Arc<Path>,
+    tasks: Option<DeviceTasks>,
+    // Runs over a socket handed to `with_sockets`, which a reload can't bind again
+    provided: bool
 }
 
-pub async fn run(settings: SettingsFile) {
+struct DeviceTasks {
+    send: JoinHandle<()>,
+    // One per receive worker
+    recv: Vec<JoinHandle<()>>,
+    keep_alive: Option<JoinHandle<()>>,
+    pmtud: Option<JoinHandle<()>>,
+    handshake: Option<JoinHandle<()>>
+}
 
-    let settings = Arc::new(settings);
+impl DeviceTasks {
+    fn abort(&self) {
+        self.send.abort();
+        for recv in &self.recv {
+            recv.abort();
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            keep_alive.abort();
+        }
+        if let Some(pmtud) = &self.pmtud {
+            pmtud.abort();
+        }
+        if let Some(handshake) = &self.handshake {
+            handshake.abort();
+        }
+    }
 
-    let mptun = Multipathtunnel{
-        sockets: make_sockets(settings.clone()).await,
-        client_list: Arc::new(RwLock::new(HashMap::new()))
-    };
+    // Abort all but the send task, waiting for the receive tasks to be gone
+    async fn stop_input(&mut self) {
+        for recv in self.recv.iter_mut() {
+            recv.abort();
+            let _ = recv.await;
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            keep_alive.abort();
+        }
+        if let Some(pmtud) = &self.pmtud {
+            pmtud.abort();
+        }
+        if let Some(handshake) = &self.handshake {
+            handshake.abort();
+        }
+    }
+}
 
-    // Insert pre-configured clients
-    match settings.remote_tun_addr {
-        Some(remote) => {
-            println!("Inserting pre-configured remote: {}", remote);
-            let mut cl = mptun.client_list.write().unwrap();
-            let socket = SocketAddr::new(IpAddr::V4(settings.remote_addr), settings.remote_port);
-            cl.insert(IpAddr::V4(remote), vec![socket]);
-        },
-        None => {}
+// Tasks that are let go of, e.g. by a run dropped part way through its drain, end too
+impl Drop for DeviceTasks {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+// Tears down what `run_on` started, however the run ends: returning, or
+// dropped part way. In the reverse of the order it was started in: first
+// the TUN tasks, so nothing more is read or queued, and the TUN device
+// goes with them, then the device tasks, then what they were spawned with.
+struct RunTeardown<'a>(&'a Multipathtunnel);
+
+impl Drop for RunTeardown<'_> {
+    fn drop(&mut self) {
+        for task in self.0.tun_tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        for device in self.0.devices.lock().unwrap().iter_mut() {
+            device.tasks = None;
+        }
+        *self.0.run_context.lock().unwrap() = None;
+    }
+}
+
+// Channels and config the device tasks are spawned with, set once running
+#[derive(Clone)]
+struct RunContext {
+    config: TaskConfig,
+    dispatcher: Arc<Dispatcher>,
+    inbound: Arc<InboundQueues>,
+    // Set in hub mode
+    forwarder: Option<Arc<Forwarder>>,
+    // Set when packets for unknown peers are held
+    pending: Option<Arc<PendingPackets>>,
+    // Peers that said goodbye and when, for the tunnel to remove
+    goodbyes: mpsc::Sender<(IpAddr, Instant)>
+}
+
+/// Called with each send device's UDP socket after the tunnel's own options
+/// are set and before it is bound, to set options the settings don't cover.
+/// An error leaves the device out like a failed bind.
+pub type SocketCustomizer = Arc<dyn Fn(&Socket, &SendDevice) -> std::io::Result<()> + Send + Sync>;
+
+pub struct Multipathtunnel {
+    settings: RwLock<Arc<SettingsFile>>,
+    // Also applied to devices added by a reload
+    socket_customizer: Option<SocketCustomizer>,
+    devices: Mutex<Vec<Device>>,
+    client_list: ClientList,
+    stats: Arc<Stats>,
+    paths: Paths,
+    clock: SharedClock,
+    packet_events: PacketEvents,
+    events: Events,
+    control: ControlMessages,
+    capture: Capture,
+    mirror: Mirror,
+    flow_control: Arc<FlowControl>,
+    nat_peers: Arc<NatPeers>,
+    blocklist: Arc<Blocklist>,
+    mtus: Arc<Mtus>,
+    echoes: Arc<Echoes>,
+    return_paths: Arc<ReturnPaths>,
+    // Tells the tasks to free their state for a removed peer
+    peer_removals: broadcast::Sender<IpAddr>,
+    // Address the pre-configured remote was inserted with, if any
+    remote_addr: Mutex<Option<SocketAddr>>,
+    keys: Keys,
+    last_seen: Arc<LastSeen>,
+    inbound_sink: Option<InboundSink>,
+    // Runtimes given for the tasks of send devices, by name
+    device_runtimes: HashMap<String, Handle>,
+    run_context: Mutex<Option<RunContext>>,
+    reloading: tokio::sync::Mutex<()>,
+    // The TUN tasks of the current run, for health reports
+    tun_tasks: Mutex<Vec<task::AbortHandle>>,
+    shutdown: watch::Sender<bool>
+}
+
+impl Multipathtunnel {
+    /// Validate the settings, bind the send device sockets and register
+    /// pre-configured clients. Must be called from within a tokio runtime.
+    ///
+    /// Nothing outside the process is touched before the settings and key
+    /// check out, and the TUN device is only created by `run`, so a failure
+    /// here never leaves an interface behind. Sockets already bound when it
+    /// fails are closed as they are dropped, and unix sockets remove their
+    /// files.
+    pub fn new(settings: SettingsFile) -> Result<Multipathtunnel, TunnelError> {
+        Multipathtunnel::with_clock(settings, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, with all timers driven by `clock`.
+    pub fn with_clock(settings: SettingsFile, clock: SharedClock) -> Result<Multipathtunnel, TunnelError> {
+        Multipathtunnel::build(settings, clock, None, Vec::new())
+    }
+
+    /// Like `new`, with `customizer` called on every send device socket
+    /// before it is bound, e.g. to attach a socket filter.
+    pub fn with_socket_customizer(settings: SettingsFile, customizer: SocketCustomizer) -> Result<Multipathtunnel, TunnelError> {
+        Multipathtunnel::build(settings, Arc::new(SystemClock), Some(customizer), Vec::new())
+    }
+
+    /// Like `new`, with an extra send device for each of `sockets`, running
+    /// over the given socket instead of one bound by the tunnel, e.g. one a
+    /// coordination server punched through a NAT. The sockets may be
+    /// connected. Nothing of their `SendDevice` that binds or sets socket
+    /// options is applied, only what describes the path, like the priority,
+    /// rate limits and recv_workers. The listen address should be the
+    /// socket's, as it's used to check the remote is reachable. These devices
+    /// are kept across reloads.
+    pub fn with_sockets(settings: SettingsFile, sockets: Vec<(SendDevice, std_udp)>) -> Result<Multipathtunnel, TunnelError> {
+        Multipathtunnel::build(settings, Arc::new(SystemClock), None, sockets)
+    }
+
+    fn build(mut settings: SettingsFile, clock: SharedClock, socket_customizer: Option<SocketCustomizer>, sockets: Vec<(SendDevice, std_udp)>) -> Result<Multipathtunnel, TunnelError> {
+        let bound = settings.send_devices.len();
+        settings.send_devices.extend(sockets.iter().map(|(dev, _)| dev.clone()));
+        settings.validate()?;
+        // Check the key before anything is bound, so a bad key never reaches the data path
+        let keys = Keys::from_settings(settings.encryption.as_ref(), settings.peer_keys.as_ref())?;
+
+        // Devices that fail to bind are left out, as long as one is left to carry traffic
+        let mut devices = Vec::new();
+        let mut bind_errors = Vec::new();
+        let made = settings.send_devices[..bound].iter()
+            .map(|dev| (dev, make_device(dev, socket_customizer.as_ref())))
+            .chain(sockets.into_iter().zip(&settings.send_devices[bound..]).map(|((_, socket), dev)| (dev, provided_device(dev, socket))));
+        for (dev, made) in made {
+            match made {
+                Ok(device) => devices.push(device),
+                Err(err) => {
+                    let err = with_context(err, format!("send device `{}`", dev.name()));
+                    eprintln!("Leaving out {}", err);
+                    bind_errors.push(err);
+                }
+            }
+        }
+        if devices.is_empty() {
+            return Err(TunnelError::NoDeviceBound(bind_errors))
+        }
+
+        let slow_down = settings.slow_down.as_ref().map(|slow_down| SlowDownConfig {
+            threshold: slow_down.drop_threshold.unwrap_or(DEFAULT_SLOW_DOWN_THRESHOLD),
+            window: Duration::from_millis(slow_down.window_ms.unwrap_or(DEFAULT_SLOW_DOWN_WINDOW_MS)),
+            duration: Duration::from_millis(slow_down.duration_ms.unwrap_or(DEFAULT_SLOW_DOWN_DURATION_MS))
+        });
+
+        let mptun = Multipathtunnel{
+            client_list: Arc::new(Clients::default()),
+            stats: Arc::new(Stats::default()),
+            paths: Arc::new(RwLock::new(devices.iter().map(|dev| dev.path.clone()).collect())),
+            devices: Mutex::new(devices),
+            nat_peers: Arc::new(NatPeers::new(flagged_nat_peers(&settings))),
+            blocklist: Arc::new(Blocklist::new(settings.blocked_sources.iter().flatten().copied())),
+            mtus: Arc::new(Mtus::new(settings.tun_mtu.unwrap_or(DEFAULT_TUN_MTU))),
+            echoes: Arc::new(Echoes::default()),
+            return_paths: Arc::new(ReturnPaths::new(RETURN_PATH_INTERVAL * RETURN_PATH_MISSED)),
+            peer_removals: broadcast::channel(PEER_REMOVALS_CAPACITY).0,
+            settings: RwLock::new(Arc::new(settings)),
+            socket_customizer,
+            clock,
+            packet_events: PacketEvents::new(PACKET_EVENTS_CAPACITY),
+            control: ControlMessages::new(CONTROL_CAPACITY),
+            capture: Capture::new(CAPTURE_CAPACITY),
+            mirror: Mirror::new(MIRROR_CAPACITY),
+            flow_control: Arc::new(FlowControl::new(slow_down)),
+            events: Events::new(EVENTS_CAPACITY),
+            remote_addr: Mutex::new(None),
+            keys,
+            last_seen: Arc::new(LastSeen::default()),
+            inbound_sink: None,
+            device_runtimes: HashMap::new(),
+            run_context: Mutex::new(None),
+            reloading: tokio::sync::Mutex::new(()),
+            tun_tasks: Mutex::new(Vec::new()),
+            shutdown: watch::channel(false).0
+        };
+
+        // Insert pre-configured clients
+        let settings = mptun.settings();
+        mptun.insert_preconfigured_remote(&settings, preconfigured_remote_addr(&settings));
+
+        Ok(mptun)
+    }
+
+    /// Deliver received packets to `sink` as well as, or instead of, the TUN.
+    /// Takes effect the next time the tunnel is run.
+    pub fn with_inbound_sink(mut self, sink: mpsc::Sender<Bytes>, delivery: InboundDelivery) -> Multipathtunnel {
+        self.inbound_sink = Some(InboundSink { sender: sink, delivery });
+        self
+    }
+
+    /// Spawn the tasks of the send device named `name` on `runtime` rather
+    /// than the one `run` is called on, e.g. one whose threads are pinned to
+    /// cores near the device's NIC. The runtime needs I/O and timers enabled.
+    /// The device's socket is registered with it too, so its I/O is driven
+    /// there, except over a unix socket. Also applies to a device of that
+    /// name added by a reload.
+    pub fn with_device_runtime(mut self, name: &str, runtime: Handle) -> Multipathtunnel {
+        let _entered = runtime.enter();
+        for device in self.devices.get_mut().unwrap().iter_mut().filter(|device| device.path.iface == name) {
+            match device.socket.reregister() {
+                Ok(socket) => device.socket = socket,
+                Err(err) => eprintln!("The socket of send device `{}` stays with the tunnel's runtime: {}", name, err)
+            }
+        }
+        self.device_runtimes.insert(name.to_string(), runtime.clone());
+        self
+    }
+
+    /// Make `run` stop taking in packets, deliver what is already queued
+    /// within drain_timeout_ms, say goodbye to the peers, then return.
+    /// Later runs stop right away.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    async fn shutdown_requested(&self) {
+        let _ = self.shutdown.subscribe().wait_for(|shutdown| *shutdown).await;
+    }
+
+    fn settings(&self) -> Arc<SettingsFile> {
+        self.settings.read().unwrap().clone()
+    }
+
+    // Insert the pre-configured remote at `primary`, from `preconfigured_remote_addr`, and its other addresses
+    fn insert_preconfigured_remote(&self, settings: &SettingsFile, primary: Option<SocketAddr>) {
+        if let Some(remote) = settings.inserted_remote() {
+            let mut sockets: Vec<SocketAddr> = primary.into_iter()
+                .chain(settings.remote_addrs.iter().flatten().copied())
+                .collect();
+            nat::canonicalize_targets(&mut sockets);
+
+            if sockets.is_empty() {
+                eprintln!("No address for pre-configured remote: {}. Waiting for it to connect", remote);
+                return
+            }
+
+            for socket in &sockets {
+                println!("Inserting pre-configured remote: {} at {}", remote, socket);
+            }
+            let mut remote_addr = self.remote_addr.lock().unwrap();
+            self.client_list.insert(remote, sockets);
+            *remote_addr = primary;
+        }
+    }
+
+    /// Point the pre-configured remote `tun_ip` at `addr` instead of its
+    /// previous address. Addresses learned from the peer are left alone.
+    /// Returns false if `addr` is already in use.
+    pub fn update_remote_addr(&self, tun_ip: IpAddr, addr: SocketAddr) -> bool {
+        let mut remote_addr = self.remote_addr.lock().unwrap();
+        if *remote_addr == Some(addr) {
+            return false
+        }
+
+        // Addresses listed in remote_addrs stay, even if the hostname used to resolve to one
+        let settings = self.settings();
+        let listed = |target: &SocketAddr| settings.remote_addrs.iter().flatten().any(|listed| listed == target);
+
+        self.client_list.upsert(tun_ip, None, |client, _| {
+            if let Some(old) = *remote_addr {
+                client.retain(|target| *target != old || listed(target));
+                println!("Remote {} moved from {} to {}", tun_ip, old, addr);
+            }
+            if !client.contains(&addr) {
+                client.push(addr);
+            }
+        });
+        *remote_addr = Some(addr);
+        true
+    }
+
+    /// Re-resolve `remote_host` every `remote_resolve_interval` and follow
+    /// address changes. When resolution fails the last good address is kept.
+    async fn track_remote_host(&self) {
+        loop {
+            let interval = self.settings().remote_resolve_interval.unwrap_or(DEFAULT_RESOLVE_INTERVAL);
+            self.clock.sleep_until(self.clock.now() + Duration::from_secs(interval)).await;
+
+            // The settings may have been reloaded while sleeping
+            let settings = self.settings();
+            let (host, remote) = match (&settings.remote_host, settings.inserted_remote()) {
+                (Some(host), Some(remote)) => (host, remote),
+                _ => continue
+            };
+
+            match resolve::resolve(host, settings.remote_port).await {
+                Ok(addr) => {
+                    self.update_remote_addr(remote, addr);
+                },
+                Err(err) => eprintln!("Failed to resolve remote host `{}`, keeping the last address: {}", host, err)
+            }
+        }
+    }
+
+    /// Write a snapshot file every `snapshot.interval` while one is configured.
+    async fn export_snapshots(&self) {
+        let handle = self.handle();
+        loop {
+            let settings = self.settings();
+            let interval = settings.snapshot.as_ref().and_then(|snapshot| snapshot.interval).unwrap_or(DEFAULT_SNAPSHOT_INTERVAL);
+            self.clock.sleep_until(self.clock.now() + Duration::from_secs(interval)).await;
+
+            // The settings may have been reloaded while sleeping
+            if let Some(snapshot) = &self.settings().snapshot {
+                if let Err(err) = snapshot::write_snapshot(&handle, &snapshot.path).await {
+                    eprintln!("Failed to write snapshot to {}: {}", snapshot.path.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Write the capture file while `capture` is configured, following reloads.
+    async fn write_capture(&self) {
+        loop {
+            let path = match &self.settings().capture {
+                Some(capture) => capture.path.clone(),
+                None => {
+                    self.clock.sleep_until(self.clock.now() + CAPTURE_CHECK_INTERVAL).await;
+                    continue
+                }
+            };
+            let still_wanted = || self.settings().capture.as_ref().is_some_and(|capture| capture.path == path);
+            if let Err(err) = capture::write_records(&self.capture, &path, still_wanted).await {
+                eprintln!("Failed to write the capture to {}: {}", path.display(), err);
+                self.clock.sleep_until(self.clock.now() + CAPTURE_CHECK_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Mirror delivered packets while `mirror` is configured, following reloads.
+    async fn write_mirror(&self) {
+        loop {
+            let mirror = match &self.settings().mirror {
+                Some(mirror) => mirror.clone(),
+                None => {
+                    self.clock.sleep_until(self.clock.now() + CAPTURE_CHECK_INTERVAL).await;
+                    continue
+                }
+            };
+            let still_wanted = || self.settings().mirror.as_ref() == Some(&mirror);
+            if let Err(err) = mirror::write_packets(&self.mirror, &mirror, still_wanted).await {
+                eprintln!("Failed to mirror packets: {}", err);
+                self.clock.sleep_until(self.clock.now() + CAPTURE_CHECK_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Shut down once the tunnel has carried no data for `max_idle`, or has
+    /// run for `max_lifetime`, following reloads. Returns once it did.
+    async fn watch_lifetime(&self) {
+        let started = self.clock.now();
+        let traffic = || self.stats.tx_goodput_bytes.load(Ordering::Relaxed) + self.stats.rx_goodput_bytes.load(Ordering::Relaxed);
+        let mut last_traffic = (traffic(), started);
+        let mut interval = Interval::new(self.clock.clone(), LIFETIME_CHECK_INTERVAL);
+        loop {
+            let now = interval.tick().await;
+            let bytes = traffic();
+            if bytes != last_traffic.0 {
+                last_traffic = (bytes, now);
+            }
+
+            let settings = self.settings();
+            if let Some(max_lifetime) = settings.max_lifetime.map(Duration::from_secs).filter(|max| now.saturating_duration_since(started) >= *max) {
+                println!("Tunnel reached its max_lifetime of {:?}, shutting down", max_lifetime);
+                break
+            }
+            if let Some(max_idle) = settings.max_idle.map(Duration::from_secs).filter(|max| now.saturating_duration_since(last_traffic.1) >= *max) {
+                println!("No traffic for max_idle of {:?}, shutting down", max_idle);
+                break
+            }
+        }
+        self.shutdown();
+    }
+
+    /// Every `client_timeout / 4` seconds, drop peer addresses not heard from
+    /// within `client_timeout`, and peers left without addresses.
+    async fn reap_dead_clients(&self) {
+        loop {
+            let period = match self.settings().client_timeout {
+                Some(timeout) => (Duration::from_secs(timeout) / 4).max(Duration::from_secs(1)),
+                None => Duration::from_secs(DEFAULT_REAP_INTERVAL)
+            };
+            self.clock.sleep_until(self.clock.now() + period).await;
+
+            // The settings may have been reloaded while sleeping
+            let settings = self.settings();
+            let timeout = match settings.client_timeout {
+                Some(timeout) => Duration::from_secs(timeout),
+                None => continue
+            };
+            let remote = settings.inserted_remote();
+            let now = self.clock.now();
+
+            let mut emptied = Vec::new();
+            self.client_list.for_each_mut(|tun_ip, addrs| {
+                if Some(*tun_ip) == remote {
+                    return
+                }
+                addrs.retain(|addr| {
+                    if !self.last_seen.is_dead(*addr, now, timeout) {
+                        return true
+                    }
+                    println!("Client {} not heard from at {} for {:?}, dropping the address", tun_ip, addr, timeout);
+                    self.last_seen.forget(addr);
+                    self.events.emit(Event::ClientExpired { tun_ip: *tun_ip, addr: *addr });
+                    false
+                });
+                if addrs.is_empty() {
+                    emptied.push(*tun_ip);
+                }
+            });
+
+            for tun_ip in emptied {
+                self.remove_peer(tun_ip);
+            }
+        }
+    }
+
+    /// Remove the peers that said goodbye, unless they were heard from within
+    /// `GOODBYE_GRACE` after it. Like the reaper, this leaves the
+    /// pre-configured remote, so it is still sent to when it comes back.
+    async fn remove_departed(&self, mut goodbyes: mpsc::Receiver<(IpAddr, Instant)>) {
+        while let Some((tun_ip, said_at)) = goodbyes.recv().await {
+            if self.settings().inserted_remote() == Some(tun_ip) {
+                continue
+            }
+            self.clock.sleep_until(said_at + GOODBYE_GRACE).await;
+            let heard_since = self.client_list.with(&tun_ip, |addrs| {
+                addrs.iter().any(|addr| self.last_seen.get(addr).is_some_and(|seen| seen > said_at))
+            });
+            if heard_since == Some(true) {
+                println!("Keeping peer {}, still heard from after its goodbye", tun_ip);
+                continue
+            }
+            self.remove_peer(tun_ip);
+        }
+    }
+
+    /// Tell every known peer, at each of its addresses and on every path that
+    /// can reach it, that this tunnel is going away. Best effort: failures
+    /// are logged and a lost goodbye leaves the peer to time out.
+    async fn say_goodbye(&self) {
+        let mut targets: Vec<(IpAddr, SocketAddr)> = Vec::new();
+        self.client_list.for_each(|tun_ip, addrs| targets.extend(addrs.iter().map(|addr| (*tun_ip, *addr))));
+        if targets.is_empty() {
+            return
+        }
+
+        println!("Saying goodbye to {} peer addresses", targets.len());
+        self.send_to_peers(&Messages::Goodbye, &targets, "goodbye").await;
+    }
+
+    // Send `message` to each of `targets` on every device that reaches it
+    async fn send_to_peers(&self, message: &Messages, targets: &[(IpAddr, SocketAddr)], what: &str) {
+        let wire_format = self.settings().wire_format.unwrap_or_default();
+        let checksum = self.settings().checksum == Some(true);
+        let sockets: Vec<Arc<dyn Transport>> = self.devices.lock().unwrap().iter().map(|device| device.socket.clone()).collect();
+        for socket in sockets {
+            let families = Families::of(&*socket);
+            for (tun_ip, addr) in targets.iter().filter(|(_, addr)| families.reaches(addr)) {
+                let sent = match tasks::encode_control(message, wire_format, checksum, self.keys.for_peer(tun_ip)) {
+                    Ok(message) => tasks::send_to(&*socket, &message, *addr).await.map(drop).map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string())
+                };
+                if let Err(err) = sent {
+                    eprintln!("Failed to send {} to {} at {}: {}", what, tun_ip, addr, err);
+                }
+            }
+        }
+    }
+
+    /// Every `TUN_MTU_CHECK_INTERVAL`, read the TUN MTU with `mtu_reader`.
+    /// Once it changed, packets read from the TUN are sent whole up to the
+    /// new MTU, and the peers are told the largest packet we now take: the
+    /// MTU, but at most `max_payload_len`, which the receive buffers were
+    /// sized for. Runs forever, idle without a reader.
+    async fn watch_tun_mtu(&self, mtu_reader: Option<MtuReader>, max_payload_len: usize) {
+        let mtu_reader = match mtu_reader {
+            Some(mtu_reader) => mtu_reader,
+            None => return futures::future::pending().await
+        };
+        let mut interval = Interval::new(self.clock.clone(), TUN_MTU_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mtu = match mtu_reader() {
+                Some(mtu) if mtu != self.mtus.local() => mtu,
+                _ => continue
+            };
+            let before = self.mtus.set_local(mtu);
+            println!("TUN MTU changed from {} to {}", before, mtu);
+            if mtu > max_payload_len {
+                eprintln!("Warning: the TUN MTU is above max_payload_len, peers are told to send at most {} bytes until a restart", max_payload_len);
+            }
+            self.events.emit(Event::TunMtuChanged { mtu });
+
+            let mut targets: Vec<(IpAddr, SocketAddr)> = Vec::new();
+            self.client_list.for_each(|tun_ip, addrs| targets.extend(addrs.iter().map(|addr| (*tun_ip, *addr))));
+            self.send_to_peers(&Messages::Mtu(mtu.min(max_payload_len) as u32), &targets, "the new MTU").await;
+        }
+    }
+
+    /// Every `RETURN_PATH_INTERVAL`, ask each peer with peer_paths receive
+    /// devices to send to us on those: a return path message from each of
+    /// them, all with this run's generation. Runs forever, idle without
+    /// receive devices.
+    async fn announce_return_paths(&self) {
+        let settings = self.settings();
+        let receive: Vec<(IpAddr, Vec<String>)> = settings.peer_paths.iter().flatten()
+            .filter_map(|(tun_ip, paths)| Some((*tun_ip, paths.receive.clone()?)))
+            .collect();
+        if receive.is_empty() {
+            return futures::future::pending().await
+        }
+        let message = Messages::ReturnPath(seqguard::new_epoch(), settings.tun_ip);
+        let wire_format = settings.wire_format.unwrap_or_default();
+        let checksum = settings.checksum == Some(true);
+        let mut interval = Interval::new(self.clock.clone(), RETURN_PATH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let sockets: Vec<(String, Arc<dyn Transport>)> = self.devices.lock().unwrap().iter()
+                .map(|device| (device.settings.name(), device.socket.clone()))
+                .collect();
+            for (tun_ip, names) in &receive {
+                // Not known yet, the next announcement may reach it
+                let addrs = match self.client_list.with(tun_ip, <[SocketAddr]>::to_vec) {
+                    Some(addrs) => addrs,
+                    None => continue
+                };
+                let encoded = match tasks::encode_control(&message, wire_format, checksum, self.keys.for_peer(tun_ip)) {
+                    Ok(encoded) => encoded,
+                    Err(err) => {
+                        eprintln!("Failed to encode the return paths for {}: {}", tun_ip, err);
+                        continue
+                    }
+                };
+                for (name, socket) in sockets.iter().filter(|(name, _)| names.contains(name)) {
+                    let families = Families::of(&**socket);
+                    for addr in addrs.iter().filter(|addr| families.reaches(addr)) {
+                        if let Err(err) = tasks::send_to(&**socket, &encoded, *addr).await {
+                            eprintln!("Failed to announce return path {} to {} at {}: {}", name, tun_ip, addr, err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Update the rates of all paths every `RATE_SAMPLE_INTERVAL`.
+    async fn sample_rates(&self) {
+        let mut interval = Interval::new(self.clock.clone(), RATE_SAMPLE_INTERVAL);
+        loop {
+            let now = interval.tick().await;
+            for path in self.paths.read().unwrap().iter() {
+                path.sample_rates(now);
+            }
+        }
+    }
+
+    /// Every `auto_weights.interval_ms`, move the paths' weights toward what
+    /// their RTT and loss earn them, following reloads. Without auto_weights
+    /// the devices' weights apply.
+    async fn adapt_weights(&self) {
+        loop {
+            let interval = self.settings().auto_weights.as_ref().and_then(|auto| auto.interval_ms).unwrap_or(DEFAULT_AUTO_WEIGHT_INTERVAL_MS);
+            self.clock.sleep_until(self.clock.now() + Duration::from_millis(interval.max(1))).await;
+
+            // The settings may have been reloaded while sleeping
+            let paths = self.paths.read().unwrap();
+            match &self.settings().auto_weights {
+                Some(auto) => {
+                    let max_weight = auto.max_weight.unwrap_or(DEFAULT_MAX_WEIGHT);
+                    path::adapt_weights(&paths, &WeightPolicy {
+                        adapt_rate: auto.adapt_rate.unwrap_or(DEFAULT_WEIGHT_ADAPT_RATE),
+                        // Only an explicit pair of bounds is checked, the defaults may not fit it
+                        min_weight: auto.min_weight.unwrap_or(DEFAULT_MIN_WEIGHT).min(max_weight),
+                        max_weight
+                    });
+                },
+                None => path::reset_weights(&paths)
+            }
+        }
+    }
+
+    /// Forget the peer with TUN address `tun_ip`: its addresses, queued packets
+    /// and the per-peer state of every task. Returns false if it wasn't known.
+    /// A peer that sends again is learned anew.
+    pub fn remove_peer(&self, tun_ip: IpAddr) -> bool {
+        let removed = self.client_list.remove(&tun_ip);
+        for addr in removed.iter().flatten() {
+            self.last_seen.forget(addr);
+            self.events.emit(Event::ClientExpired { tun_ip, addr: *addr });
+        }
+        if self.settings().remote_tun_addr == Some(tun_ip) {
+            *self.remote_addr.lock().unwrap() = None;
+        }
+
+        self.nat_peers.forget(&tun_ip);
+        self.stats.peers.forget(&tun_ip);
+        self.mtus.forget(&tun_ip);
+        self.return_paths.forget(&tun_ip);
+        for path in self.paths.read().unwrap().iter() {
+            path.forget_peer(&tun_ip);
+        }
+        if let Some(context) = self.run_context.lock().unwrap().as_ref() {
+            context.inbound.remove(&tun_ip);
+            if let Some(forwarder) = &context.forwarder {
+                forwarder.forget(&tun_ip);
+            }
+        }
+        // Only fails when no tasks are running
+        let _ = self.peer_removals.send(tun_ip);
+
+        if removed.is_some() {
+            println!("Removed client: {}", tun_ip);
+        }
+        removed.is_some()
+    }
+
+    /// Snapshot of the known peers, their addresses and when each was last heard from.
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        let clients = self.client_list.snapshot();
+        let now = self.clock.now();
+        let rtt = self.paths.read().unwrap().iter()
+            .filter(|path| path.health() == Health::Up)
+            .filter_map(|path| path.rtt())
+            .min();
+
+        let mut peers: Vec<PeerInfo> = clients.into_iter()
+            .map(|(tun_ip, addrs)| PeerInfo {
+                tun_ip,
+                addrs: addrs.into_iter()
+                    .map(|addr| PeerAddr {
+                        addr,
+                        last_seen: self.last_seen.get(&addr).map(|seen| now.saturating_duration_since(seen))
+                    })
+                    .collect(),
+                rtt
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.tun_ip);
+        peers
+    }
+
+    /// Health of the paths, as judged by keep-alives, and of the TUN tasks.
+    /// Degraded when some paths are down, unhealthy when all are or when
+    /// the TUN tasks aren't running, including before `run` and after it returns.
+    pub async fn health(&self) -> HealthReport {
+        let paths = self.paths.read().unwrap().iter()
+            .map(|path| PathHealth { name: path.iface.clone(), health: path.health() })
+            .collect();
+        let tun_tasks = self.tun_tasks.lock().unwrap();
+        let tun_running = !tun_tasks.is_empty() && tun_tasks.iter().all(|task| !task.is_finished());
+        HealthReport::new(paths, self.client_list.len(), tun_running)
+    }
+
+    /// Data packets and bytes sent to and received from each known peer.
+    pub fn peer_stats(&self) -> HashMap<IpAddr, PeerTraffic> {
+        self.stats.peers.snapshot()
+    }
+
+    /// Drop everything received from addresses in `entry` from now on, on
+    /// every path. Returns false if it was already blocked.
+    pub fn block_source(&self, entry: Cidr) -> bool {
+        let blocked = self.blocklist.block(entry);
+        if blocked {
+            println!("Blocked source: {}", entry);
+        }
+        blocked
+    }
+
+    /// Undo `block_source`, or an entry of blocked_sources. Returns false if
+    /// `entry` wasn't blocked.
+    pub fn unblock_source(&self, entry: &Cidr) -> bool {
+        let unblocked = self.blocklist.unblock(entry);
+        if unblocked {
+            println!("Unblocked source: {}", entry);
+        }
+        unblocked
+    }
+
+    /// The blocked source subnets.
+    pub fn blocked_sources(&self) -> Vec<Cidr> {
+        self.blocklist.entries()
+    }
+
+    /// Goodput against the raw bytes on all paths, see `Throughput`.
+    pub fn throughput(&self) -> Throughput {
+        self.handle().throughput()
+    }
+
+    /// A handle for inspecting the tunnel while it runs.
+    pub fn handle(&self) -> TunnelHandle {
+        TunnelHandle::new(self.paths.clone(), self.client_list.clone(), self.stats.clone())
+    }
+
+    /// Subscribe to a record of every packet sent. The stream is lossy: a
+    /// subscriber that falls behind misses events rather than slowing the tunnel.
+    pub fn subscribe_packet_events(&self) -> broadcast::Receiver<PacketEvent> {
+        self.packet_events.subscribe()
+    }
+
+    /// Subscribe to lifecycle events: peers coming and going, paths changing
+    /// health and dropped packets. Lossy like the packet event stream.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Subscribe to control messages received from peers. Messages received
+    /// while nobody is subscribed are dropped.
+    pub fn subscribe_control(&self) -> broadcast::Receiver<ControlMessage> {
+        self.control.subscribe()
+    }
+
+    /// Subscribe to records of the data packets sent and received, as the
+    /// `capture` setting writes them to a file. Records are only made while
+    /// something is subscribed.
+    pub fn subscribe_capture(&self) -> broadcast::Receiver<CaptureRecord> {
+        self.capture.subscribe()
+    }
+
+    /// Send a control message to the peer with TUN address `tun_ip`. It goes
+    /// out once, on the path failover mode would pick, to the peer's first
+    /// address. Fails with `NotFound` if the peer or a path isn't known, and
+    /// with `InvalidData` if the message can't be encoded.
+    pub async fn send_control(&self, tun_ip: IpAddr, payload: Bytes) -> std::io::Result<()> {
+        let not_found = |what| std::io::Error::new(std::io::ErrorKind::NotFound, what);
+        let target = self.client_list.with(&tun_ip, |addrs| addrs.first().copied())
+            .flatten()
+            .ok_or_else(|| not_found("unknown peer"))?;
+        let socket = {
+            let paths = self.paths.read().unwrap();
+            let chosen = path::active_path(&paths).ok_or_else(|| not_found("no path"))?;
+            let devices = self.devices.lock().unwrap();
+            devices.iter()
+                .find(|device| Arc::ptr_eq(&device.path, chosen))
+                .map(|device| device.socket.clone())
+                .ok_or_else(|| not_found("no path"))?
+        };
+
+        let settings = self.settings();
+        let message = tasks::encode_control(&Messages::Control(payload), settings.wire_format.unwrap_or_default(), settings.checksum == Some(true), self.keys.for_peer(&tun_ip))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        tasks::send_to(&*socket, &message, target).await?;
+        Ok(())
+    }
+
+    /// Stop sending data packets over send device `iface`, e.g. ahead of
+    /// maintenance on its link, while its socket stays open and packets
+    /// from the peers are still received on it. The path modes pick among
+    /// the other links meanwhile. Keep-alives go on unless
+    /// keep_alive_while_paused is false, and replies to the peers' ones
+    /// always do. Returns false if there is no send device of that name.
+    /// A device replaced by a reload starts out resumed.
+    pub fn pause_path(&self, iface: &str) -> bool {
+        self.set_path_paused(iface, true)
     }
 
+    /// Send data packets over send device `iface` again after `pause_path`.
+    /// Returns false if there is no send device of that name.
+    pub fn resume_path(&self, iface: &str) -> bool {
+        self.set_path_paused(iface, false)
+    }
+
+    fn set_path_paused(&self, iface: &str, paused: bool) -> bool {
+        let paths = self.paths.read().unwrap();
+        let path = match paths.iter().find(|path| path.iface == iface) {
+            Some(path) => path,
+            None => return false
+        };
+        if path.set_paused(paused) {
+            println!("{} path {}", if paused { "Paused" } else { "Resumed" }, iface);
+        }
+        true
+    }
+
+    /// Check that the link of send device `iface` carries traffic both ways.
+    /// An echo request goes out over it to every known peer address it
+    /// reaches, and the first echo to come back over the same link within
+    /// `PATH_PROBE_TIMEOUT` is reported with its round trip time. Unlike
+    /// keep-alives this doesn't change the path's health.
+    pub async fn probe_path(&self, iface: &str) -> ProbeResult {
+        let socket = {
+            let devices = self.devices.lock().unwrap();
+            match devices.iter().find(|device| device.path.iface == iface) {
+                Some(device) => device.socket.clone(),
+                None => return ProbeResult::UnknownPath
+            }
+        };
+        let families = Families::of(&*socket);
+        let mut targets: Vec<(IpAddr, SocketAddr)> = Vec::new();
+        self.client_list.for_each(|tun_ip, addrs| {
+            targets.extend(addrs.iter().filter(|addr| families.reaches(addr)).map(|addr| (*tun_ip, *addr)));
+        });
+        if targets.is_empty() {
+            return ProbeResult::NoPeer
+        }
+
+        let wire_format = self.settings().wire_format.unwrap_or_default();
+        let checksum = self.settings().checksum == Some(true);
+        let (tag, reply) = self.echoes.register(iface);
+        let sent_at = self.clock.now();
+        for (tun_ip, target) in targets {
+            let sent = match tasks::encode_control(&Messages::Echo(tag), wire_format, checksum, self.keys.for_peer(&tun_ip)) {
+                Ok(request) => tasks::send_to(&*socket, &request, target).await.map(drop).map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string())
+            };
+            if let Err(err) = sent {
+                eprintln!("Failed to send link check to {} on path {}: {}", target, iface, err);
+            }
+        }
+
+        let result = tokio::select! {
+            received = reply => match received {
+                Ok((from, at)) => ProbeResult::Echoed { from, rtt: at.saturating_duration_since(sent_at) },
+                Err(_) => ProbeResult::TimedOut
+            },
+            _ = self.clock.sleep_until(sent_at + PATH_PROBE_TIMEOUT) => ProbeResult::TimedOut
+        };
+        self.echoes.cancel(tag);
+        result
+    }
+
+    /// Create the TUN device and run the tunnel tasks until one of them stops.
+    /// The others are then cancelled. Returns how each task ended, or an
+    /// error carrying the same reports if any task panicked. The sockets are
+    /// bound by then, and the TUN device is removed when the run ends. A run
+    /// dropped before it ends aborts the tasks it started, and the TUN device
+    /// goes with them.
+    pub async fn run(&self) -> Result<Vec<TaskReport>, TunnelError> {
+        self.run_with(&KernelTun).await
+    }
+
+    /// Like `run`, with the TUN device created by `factory`.
+    pub async fn run_with<F: TunFactory>(&self, factory: &F) -> Result<Vec<TaskReport>, TunnelError> {
+        let settings = self.settings();
+        let mtu = settings.tun_mtu.unwrap_or(DEFAULT_TUN_MTU);
+        let queues = settings.tun_queues.unwrap_or(1).max(1);
+        let tuns = factory.create_queues(&settings, mtu, queues).map_err(TunnelError::Tun)?;
+        let mtu_reader = factory.mtu_reader(&tuns[0]);
+        self.run_on(tuns, mtu_reader).await
+    }
+
+    /// Like `run`, with the application in place of a TUN device. IP packets
+    /// sent on the returned channels' `to_tunnel` go out to the peers, and
+    /// packets from the peers arrive on `from_tunnel`. The tunnel runs while
+    /// the returned future is polled.
+    pub fn run_with_channels(&self) -> (MemoryTunPeer, impl Future<Output = Result<Vec<TaskReport>, TunnelError>> + '_) {
+        let (tun, channels) = memory_tun();
+        let mtu_reader = tun.mtu_reader();
+        (channels, self.run_on(vec![tun], Some(mtu_reader)))
+    }
+
+    /// Like `run`, on the given TUN device, e.g. a `MemoryTun` in tests.
+    pub async fn run_with_tun<T: TunDevice>(&self, tun: T) -> Result<Vec<TaskReport>, TunnelError> {
+        self.run_with_tun_queues(vec![tun]).await
+    }
+
+    /// Like `run_with_tun`, reading each queue of a multi-queue TUN device in
+    /// its own task. Packets from the peers are written to the first queue.
+    pub async fn run_with_tun_queues<T: TunDevice>(&self, tuns: Vec<T>) -> Result<Vec<TaskReport>, TunnelError> {
+        self.run_on(tuns, None).await
+    }
+
+    /// What the tasks of a run are configured with, from `settings`.
+    pub(crate) fn task_config(&self, settings: &SettingsFile) -> TaskConfig {
+        let tun_mtu = settings.tun_mtu.unwrap_or(DEFAULT_TUN_MTU);
+        TaskConfig {
+            path_mode: settings.path_mode.unwrap_or_default(),
+            redundancy: settings.redundancy,
+            recovery_ramp: settings.recovery_ramp_ms.map(Duration::from_millis),
+            backup_delay: match &settings.fastest {
+                Some(FastestSettings { backups: Some(false), .. }) => None,
+                fastest => Some(Duration::from_millis(fastest.as_ref().and_then(|fastest| fastest.stagger_ms).unwrap_or(DEFAULT_FASTEST_STAGGER_MS)))
+            },
+            peer_path_modes: Arc::new(settings.peer_path_modes.iter().flatten()
+                .map(|(tun_ip, mode)| (*tun_ip, *mode))
+                .collect()),
+            peer_send_paths: Arc::new(settings.peer_paths.iter().flatten()
+                .filter_map(|(tun_ip, paths)| Some((*tun_ip, paths.send.clone()?)))
+                .collect()),
+            return_paths: self.return_paths.clone(),
+            new_flow_duplicate_packets: settings.new_flow_duplicate_packets,
+            flow_label: settings.flow_label,
+            dscp_remap: match settings.copy_dscp {
+                Some(true) => Some(Arc::new(settings.dscp_remap.clone().unwrap_or_default())),
+                _ => None
+            },
+            max_payload_len: settings.max_payload_len.unwrap_or(tun_mtu + MAX_PAYLOAD_SLACK),
+            wire_format: settings.wire_format.unwrap_or_default(),
+            checksum: settings.checksum == Some(true),
+            mtus: self.mtus.clone(),
+            echoes: self.echoes.clone(),
+            oversize_policy: settings.oversize_policy.unwrap_or_default(),
+            unparseable_policy: settings.unparseable_policy.unwrap_or_default(),
+            address_change_packets: settings.address_change_packets,
+            keys: self.keys.clone(),
+            reorder: match settings.delivery_mode() {
+                DeliveryMode::InOrder => {
+                    let reorder = settings.reorder.as_ref();
+                    Some(ReorderConfig {
+                        capacity: reorder.and_then(|reorder| reorder.max_packets).unwrap_or(DEFAULT_REORDER_PACKETS),
+                        timeout: Duration::from_millis(reorder.and_then(|reorder| reorder.timeout_ms).unwrap_or(DEFAULT_REORDER_TIMEOUT_MS))
+                    })
+                },
+                DeliveryMode::Immediate | DeliveryMode::LatestOnly => None
+            },
+            max_packet_age: settings.max_packet_age_ms.map(Duration::from_millis),
+            max_datagram_size: settings.max_datagram_size,
+            decrement_ttl: settings.decrement_ttl.unwrap_or(false),
+            allowed_destinations: settings.allowed_destinations.clone().map(Arc::new),
+            source_nat: settings.source_nat.as_ref().map(|mappings| Arc::new(SourceNat::new(
+                mappings.iter().map(|mapping| (mapping.original, mapping.mapped)).collect()
+            ))),
+            send_batch: settings.send_batch,
+            recv_batch: settings.recv_batch,
+            discovery: settings.discovery.unwrap_or_default(),
+            max_clients: settings.max_clients,
+            nat_rebind_grace: Duration::from_secs(settings.nat_rebind_grace.unwrap_or(DEFAULT_NAT_REBIND_GRACE)),
+            timestamps: settings.timestamps.unwrap_or(false),
+            path_sequence: settings.path_sequence.unwrap_or(false),
+            session_epoch: match settings.session_epoch {
+                Some(true) => Some(seqguard::new_epoch()),
+                _ => None
+            },
+            fallback_peer: match settings.via_hub {
+                Some(true) => settings.remote_tun_addr,
+                _ => None
+            },
+            tun_ip: settings.tun_ip,
+            icmp_unreachable: settings.icmp_unreachable.unwrap_or(false)
+        }
+    }
+
+    // Runs on `tuns`, following changes of their MTU if `mtu_reader` is given
+    async fn run_on<T: TunDevice>(&self, tuns: Vec<T>, mtu_reader: Option<MtuReader>) -> Result<Vec<TaskReport>, TunnelError> {
+        let mut tuns = tuns.into_iter();
+        let first = tuns.next().ok_or_else(|| TunnelError::Tun("no TUN queues to run on".into()))?;
+        let settings = self.settings();
+        let tun_mtu = settings.tun_mtu.unwrap_or(DEFAULT_TUN_MTU);
+        self.mtus.set_local(tun_mtu);
+
+        let config = self.task_config(&settings);
 
-    let mut tasks = Vec::new();
 
-    let tun = make_tunnel(settings.clone()).await;
+        let mut tasks = Vec::new();
 
-    let (tun_reader, tun_writer) = tokio::io::split(tun);
+        let (first_reader, tun_writer) = tokio::io::split(first);
+        // The other queues' write halves are dropped, their read halves keep the queues open
+        let tun_readers: Vec<_> = std::iter::once(first_reader).chain(tuns.map(|tun| tokio::io::split(tun).0)).collect();
 
-    let (tx, _) = tokio::sync::broadcast::channel::<Packet>(200);
-    let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel::<Packet>();
+        let dispatcher = Arc::new(Dispatcher::new(PATH_QUEUE_CAPACITY, self.client_list.clone(), self.paths.clone(), self.stats.clone(), self.flow_control.clone(), config.clone()));
+        let inbound = Arc::new(InboundQueues::new(INBOUND_QUEUE_CAPACITY));
+        let (goodbyes, goodbyes_rx) = mpsc::channel(GOODBYES_CAPACITY);
 
-    for socket in mptun.sockets {
-        let soc_send = socket.clone();
+        let context = RunContext {
+            config: config.clone(),
+            dispatcher: dispatcher.clone(),
+            inbound: inbound.clone(),
+            forwarder: match settings.hub {
+                Some(true) => Some(Arc::new(Forwarder::new(dispatcher.clone(), self.clock.clone()))),
+                _ => None
+            },
+            pending: settings.unknown_peer_queue.as_ref().map(|queue| Arc::new(PendingPackets::new(dispatcher.clone(), PendingConfig {
+                max_packets: queue.max_packets.unwrap_or(DEFAULT_UNKNOWN_PEER_PACKETS),
+                max_age: Duration::from_millis(queue.max_age_ms.unwrap_or(DEFAULT_UNKNOWN_PEER_AGE_MS))
+            }))),
+            goodbyes
+        };
+
+        let read_pending = context.pending.clone();
+        let _teardown = RunTeardown(self);
+        {
+            let mut devices = self.devices.lock().unwrap();
+            for device in devices.iter_mut() {
+                device.tasks = Some(self.spawn_device_tasks(device, &context, &settings));
+            }
+            *self.run_context.lock().unwrap() = Some(context);
+        }
+
+        let sequence = Arc::new(TunSequence::default());
+        for tun_reader in tun_readers {
+            let sequence = sequence.clone();
+            let dispatcher = dispatcher.clone();
+            let read_stats = self.stats.clone();
+            let read_config = config.clone();
+            let read_events = self.events.clone();
+            let read_client_list = self.client_list.clone();
+            let read_inbound = inbound.clone();
+            let read_pending = read_pending.clone();
+            let read_clock = self.clock.clone();
+            tasks.push(("read_tun", task::spawn(async move {
+                tasks::read_tun(tun_reader, sequence, dispatcher, read_stats, read_clock, read_config, read_events, read_client_list, read_inbound, read_pending).await
+            })));
+        }
+
+        let tun_stats = self.stats.clone();
+        let tun_clock = self.clock.clone();
+        let path_count = self.paths.read().unwrap().len();
+        let delivery = DeliveryConfig {
+            // Every link may be a full reorder buffer behind the others
+            dedup_window: config.reorder.map_or(0, |reorder| reorder.capacity * path_count).max(MIN_DEDUP_WINDOW),
+            reorder: config.reorder,
+            latest_only: settings.delivery_mode() == DeliveryMode::LatestOnly,
+            seq_guard: settings.backward_jump.as_ref().map(|backward_jump| SeqGuardConfig {
+                max_backward: backward_jump.max_packets.unwrap_or(DEFAULT_MAX_BACKWARD_JUMP),
+                action: backward_jump.action.unwrap_or_default()
+            }),
+            sink: self.inbound_sink.clone(),
+            mirror: self.mirror.clone()
+        };
+        let tun_removals = self.peer_removals.subscribe();
+        let tun_events = self.events.clone();
+        tasks.push(("send_tun", task::spawn(async move {
+            tasks::send_tun(tun_writer, inbound, tun_stats, tun_clock, delivery, tun_removals, tun_events).await
+        })));
+
+        *self.tun_tasks.lock().unwrap() = tasks.iter().map(|(_, handle)| handle.abort_handle()).collect();
+
+        let stopped = tokio::select! {
+            reports = supervise(&mut tasks) => Some(reports),
+            // Run forever
+            _ = futures::future::join(
+                futures::future::join5(self.track_remote_host(), self.export_snapshots(), self.reap_dead_clients(), self.sample_rates(), self.write_capture()),
+                futures::future::join(
+                    futures::future::join5(self.remove_departed(goodbyes_rx), self.write_mirror(), self.watch_lifetime(), self.adapt_weights(), self.watch_tun_mtu(mtu_reader, config.max_payload_len)),
+                    self.announce_return_paths()
+                )
+            ) => Some(Vec::new()),
+            _ = self.shutdown_requested() => None
+        };
+        let reports = match stopped {
+            Some(reports) => reports,
+            None => self.drain(tasks, Duration::from_millis(settings.drain_timeout_ms.unwrap_or(DEFAULT_DRAIN_TIMEOUT_MS))).await
+        };
+
+        // Nothing is left to feed or drain the device tasks, `_teardown` stops them
+        if reports.iter().any(|report| matches!(report.outcome, TaskOutcome::Panicked(_))) {
+            Err(TunnelError::TasksFailed(reports))
+        } else {
+            Ok(reports)
+        }
+    }
+
+    // Stop reading from the TUN and the sockets, give the send tasks `timeout`
+    // to get the queued packets out, then abort whatever is left
+    async fn drain(&self, tasks: Vec<(&'static str, JoinHandle<()>)>, timeout: Duration) -> Vec<TaskReport> {
+        println!("Shutting down, draining queued packets for up to {:?}", timeout);
+        let mut device_tasks: Vec<DeviceTasks> = self.devices.lock().unwrap().iter_mut()
+            .filter_map(|device| device.tasks.take())
+            .collect();
+        let context = self.run_context.lock().unwrap().take();
+
+        let (inputs, outputs): (Vec<_>, Vec<_>) = tasks.into_iter().partition(|(task, _)| *task == "read_tun");
+        let mut reports = Vec::new();
+        for (task, handle) in inputs {
+            handle.abort();
+            reports.push(TaskReport { task, outcome: TaskOutcome::from(handle.await) });
+        }
+        for device in device_tasks.iter_mut() {
+            device.stop_input().await;
+        }
+        // With the readers gone nothing adds to the send tasks' queues, so
+        // they stop once theirs is empty. The dispatcher itself lives on in
+        // `run_on` until the drain is over.
+        if let Some(context) = context {
+            context.dispatcher.close();
+            context.inbound.close();
+        }
+
+        let deadline = self.clock.now() + timeout;
+        for device in device_tasks.iter_mut() {
+            let finished = tokio::select! {
+                _ = &mut device.send => true,
+                _ = self.clock.sleep_until(deadline) => false
+            };
+            if !finished {
+                device.send.abort();
+            }
+        }
+        for (task, mut handle) in outputs {
+            let result = tokio::select! {
+                result = &mut handle => Some(result),
+                _ = self.clock.sleep_until(deadline) => None
+            };
+            let result = match result {
+                Some(result) => result,
+                None => {
+                    eprintln!("Task {} didn't drain in time", task);
+                    handle.abort();
+                    handle.await
+                }
+            };
+            reports.push(TaskReport { task, outcome: TaskOutcome::from(result) });
+        }
+
+        // After the last data packets, so they don't make the peers learn this side anew
+        self.say_goodbye().await;
+        reports
+    }
+
+    fn spawn_device_tasks(&self, device: &Device, context: &RunContext, settings: &SettingsFile) -> DeviceTasks {
+        let soc_send = device.socket.clone();
         let soc_recv = soc_send.clone();
 
-        let rx = tx.subscribe();
+        let queue = context.dispatcher.subscribe(device.path.clone(), Families::of(&*device.socket));
 
-        let send_client_list = mptun.client_list.clone();
+        let send_client_list = self.client_list.clone();
         let recv_client_list = send_client_list.clone();
 
+        let send_path = device.path.clone();
+        let send_clock = self.clock.clone();
+        let send_config = context.config.clone();
+        let packet_events = self.packet_events.clone();
+        let send_capture = self.capture.clone();
+        let send_stats = self.stats.clone();
+        // Validation refuses drop_injection without allow_drop_injection
+        let drop_injector = device.settings.drop_injection.as_ref()
+            .filter(|_| settings.allow_drop_injection == Some(true))
+            .map(DropInjector::new);
+        let send = self.spawn_for(&device.path, async move {
+            tasks::send_udp(soc_send, send_client_list, queue, send_path, send_clock, send_config, send_stats, packet_events, send_capture, drop_injector).await
+        });
 
-        match settings.keep_alive {
-            Some(should_keep_alive) => {
-                if should_keep_alive {
-                    let keep_alive_soc = soc_recv.clone();
-                    let keep_alive_client_list = recv_client_list.clone();
-                    let interval = settings.keep_alive_interval.unwrap();
+        let recv_state = Arc::new(RecvState::new(&context.config));
+        let recv = (0..device.settings.recv_workers.unwrap_or(1).max(1)).map(|_| {
+            let soc_recv = soc_recv.clone();
+            let inbound = context.inbound.clone();
+            let recv_client_list = recv_client_list.clone();
+            let recv_stats = self.stats.clone();
+            let recv_path = device.path.clone();
+            let recv_clock = self.clock.clone();
+            let recv_config = context.config.clone();
+            let recv_nat_peers = self.nat_peers.clone();
+            let recv_events = self.events.clone();
+            let recv_removals = self.peer_removals.subscribe();
+            let recv_last_seen = self.last_seen.clone();
+            let forwarder = context.forwarder.clone();
+            let pending = context.pending.clone();
+            let blocklist = self.blocklist.clone();
+            let goodbyes = context.goodbyes.clone();
+            let recv_state = recv_state.clone();
+            let control = self.control.clone();
+            let flow_control = self.flow_control.clone();
+            let capture = self.capture.clone();
+            self.spawn_for(&device.path, async move {
+                tasks::recv_udp(soc_recv, inbound, recv_client_list, recv_stats, recv_path, recv_clock, recv_config, recv_nat_peers, recv_events, recv_removals, recv_last_seen, forwarder, recv_state, control, flow_control, capture, pending, blocklist, goodbyes).await
+            })
+        }).collect();
 
-                    tasks.push(task::spawn(async move {
-                        tasks::keep_alive(keep_alive_soc, keep_alive_client_list, interval).await
-                    }));
+        DeviceTasks {
+            send,
+            recv,
+            keep_alive: self.spawn_keep_alive(&device.socket, &device.path, context, settings),
+            pmtud: self.spawn_pmtud(device, context, settings),
+            handshake: self.spawn_handshake(device, context, settings)
+        }
+    }
+
+    // Spawn a task of the send device of `path`, on its runtime if it was given one
+    fn spawn_for<F>(&self, path: &Path, future: F) -> JoinHandle<F::Output> where F: Future + Send + 'static, F::Output: Send + 'static {
+        match self.device_runtimes.get(&path.iface) {
+            Some(runtime) => runtime.spawn(future),
+            None => task::spawn(future)
+        }
+    }
+
+    fn spawn_handshake(&self, device: &Device, context: &RunContext, settings: &SettingsFile) -> Option<JoinHandle<()>> {
+        let handshake = settings.handshake.as_ref()?;
+        let config = HandshakeConfig {
+            remote: settings.inserted_remote()?,
+            initial_backoff: Duration::from_millis(handshake.initial_backoff_ms.unwrap_or(DEFAULT_HANDSHAKE_INITIAL_BACKOFF_MS)),
+            max_backoff: Duration::from_millis(handshake.max_backoff_ms.unwrap_or(DEFAULT_HANDSHAKE_MAX_BACKOFF_MS)),
+            max_attempts: handshake.max_attempts,
+            wire_format: context.config.wire_format,
+            checksum: context.config.checksum,
+            keys: context.config.keys.clone()
+        };
+        let socket = device.socket.clone();
+        let client_list = self.client_list.clone();
+        let path = device.path.clone();
+        let clock = self.clock.clone();
+        let events = self.events.clone();
+
+        Some(self.spawn_for(&device.path, async move {
+            tasks::handshake(socket, client_list, path, clock, config, events).await
+        }))
+    }
+
+    fn spawn_pmtud(&self, device: &Device, context: &RunContext, settings: &SettingsFile) -> Option<JoinHandle<()>> {
+        if device.settings.pmtud != Some(true) {
+            return None
+        }
+
+        let pmtud = settings.pmtud.clone().unwrap_or(PmtudSettings { probe_interval_ms: None, reprobe_interval: None, search: None });
+        let overhead = if context.config.keys.is_enabled() { ENCRYPTION_OVERHEAD } else { 0 } + if context.config.checksum { CHECKSUM_LEN } else { 0 };
+        // No datagram is ever larger than a full payload's message
+        let max = messages::max_message_len(context.config.max_payload_len) as usize + overhead;
+        device.path.start_pmtud(PmtuSearch::new(
+            pmtud.search.unwrap_or_default(),
+            PMTUD_MIN_DATAGRAM.min(max),
+            max,
+            Duration::from_secs(pmtud.reprobe_interval.unwrap_or(DEFAULT_REPROBE_INTERVAL))
+        ));
+
+        let config = ProbeConfig {
+            interval: Duration::from_millis(pmtud.probe_interval_ms.unwrap_or(DEFAULT_PROBE_INTERVAL_MS)),
+            wire_format: context.config.wire_format,
+            checksum: context.config.checksum,
+            keys: context.config.keys.clone()
+        };
+        let socket = device.socket.clone();
+        let client_list = self.client_list.clone();
+        let path = device.path.clone();
+        let clock = self.clock.clone();
+
+        Some(self.spawn_for(&device.path, async move {
+            tasks::probe_pmtu(socket, client_list, path, clock, config).await
+        }))
+    }
+
+    fn spawn_keep_alive(&self, socket: &Arc<dyn Transport>, path: &Arc<Path>, context: &RunContext, settings: &SettingsFile) -> Option<JoinHandle<()>> {
+        if settings.keep_alive != Some(true) {
+            return None
+        }
+
+        let keep_alive_soc = socket.clone();
+        let keep_alive_client_list = self.client_list.clone();
+        let interval = settings.keep_alive_interval()?;
+        let config = KeepAliveConfig {
+            interval,
+            timeout: settings.keep_alive_timeout.map_or(3 * interval, Duration::from_secs),
+            wire_format: context.config.wire_format,
+            checksum: context.config.checksum,
+            nat_only: settings.keep_alive_nat_only == Some(true),
+            while_paused: settings.keep_alive_while_paused != Some(false),
+            keys: context.config.keys.clone()
+        };
+        let path_health = settings.path_health.as_ref();
+        path.set_health_policy(HealthPolicy {
+            down_after: path_health.and_then(|health| health.down_after).unwrap_or(DEFAULT_PATH_DOWN_AFTER),
+            up_after: path_health.and_then(|health| health.up_after).unwrap_or(DEFAULT_PATH_UP_AFTER),
+            flap_transitions: path_health.and_then(|health| health.flap_transitions).unwrap_or(DEFAULT_FLAP_TRANSITIONS),
+            flap_window: Duration::from_secs(path_health.and_then(|health| health.flap_window).unwrap_or(DEFAULT_FLAP_WINDOW))
+        });
+        let keep_alive_path = path.clone();
+        let keep_alive_clock = self.clock.clone();
+        let keep_alive_nat_peers = self.nat_peers.clone();
+        let keep_alive_events = self.events.clone();
+
+        Some(self.spawn_for(path, async move {
+            tasks::keep_alive(keep_alive_soc, keep_alive_client_list, keep_alive_path, keep_alive_clock, config, keep_alive_nat_peers, keep_alive_events).await
+        }))
+    }
+
+    /// Apply a changed configuration to the running tunnel.
+    ///
+    /// Send devices are added and removed, keep-alive settings, the blocked
+    /// sources, the mirror, the capture, auto_weights and the idle and
+    /// lifetime limits are updated and the pre-configured remote is replaced.
+    /// Other changes require a restart and are logged and ignored. Blocking
+    /// work, like resolving the remote's host name, runs off the runtime's
+    /// threads so traffic keeps flowing meanwhile.
+    pub async fn reload(&self, mut new_settings: SettingsFile) {
+        // One at a time, so each starts from the settings the last one applied
+        let _reloading = self.reloading.lock().await;
+
+        // Devices over provided sockets aren't in the file, but stay
+        new_settings.send_devices.extend(self.devices.lock().unwrap().iter()
+            .filter(|device| device.provided)
+            .map(|device| device.settings.clone()));
+        if let Err(err) = new_settings.validate() {
+            eprintln!("Ignoring reloaded settings: {}", err);
+            return
+        }
+        let old_settings = self.settings();
+
+        // Everything that can't be applied live must stay the same
+        let mut unchanged = new_settings.clone();
+        unchanged.send_devices = old_settings.send_devices.clone();
+        unchanged.keep_alive = old_settings.keep_alive;
+        unchanged.keep_alive_interval = old_settings.keep_alive_interval;
+        unchanged.keep_alive_interval_ms = old_settings.keep_alive_interval_ms;
+        unchanged.keep_alive_timeout = old_settings.keep_alive_timeout;
+        unchanged.keep_alive_nat_only = old_settings.keep_alive_nat_only;
+        unchanged.keep_alive_while_paused = old_settings.keep_alive_while_paused;
+        unchanged.path_health = old_settings.path_health.clone();
+        unchanged.nat_peers = old_settings.nat_peers.clone();
+        unchanged.blocked_sources = old_settings.blocked_sources.clone();
+        unchanged.remote_addr = old_settings.remote_addr;
+        unchanged.remote_addrs = old_settings.remote_addrs.clone();
+        unchanged.remote_host = old_settings.remote_host.clone();
+        unchanged.remote_resolve_interval = old_settings.remote_resolve_interval;
+        unchanged.remote_port = old_settings.remote_port;
+        unchanged.remote_tun_addr = old_settings.remote_tun_addr;
+        unchanged.insert_remote = old_settings.insert_remote;
+        unchanged.snapshot = old_settings.snapshot.clone();
+        unchanged.client_timeout = old_settings.client_timeout;
+        unchanged.max_idle = old_settings.max_idle;
+        unchanged.max_lifetime = old_settings.max_lifetime;
+        unchanged.mirror = old_settings.mirror.clone();
+        unchanged.capture = old_settings.capture.clone();
+        unchanged.auto_weights = old_settings.auto_weights.clone();
+        if unchanged != *old_settings {
+            eprintln!("Warning: reloaded settings change options that can't be applied without a restart (e.g. tun_ip). Those changes are ignored");
+        }
+
+        let mut applied = (*old_settings).clone();
+        applied.send_devices = new_settings.send_devices.clone();
+        applied.keep_alive = new_settings.keep_alive;
+        applied.keep_alive_interval = new_settings.keep_alive_interval;
+        applied.keep_alive_interval_ms = new_settings.keep_alive_interval_ms;
+        applied.keep_alive_timeout = new_settings.keep_alive_timeout;
+        applied.keep_alive_nat_only = new_settings.keep_alive_nat_only;
+        applied.keep_alive_while_paused = new_settings.keep_alive_while_paused;
+        applied.path_health = new_settings.path_health.clone();
+        applied.nat_peers = new_settings.nat_peers.clone();
+        applied.blocked_sources = new_settings.blocked_sources.clone();
+        applied.remote_addr = new_settings.remote_addr;
+        applied.remote_addrs = new_settings.remote_addrs.clone();
+        applied.remote_host = new_settings.remote_host.clone();
+        applied.remote_resolve_interval = new_settings.remote_resolve_interval;
+        applied.remote_port = new_settings.remote_port;
+        applied.remote_tun_addr = new_settings.remote_tun_addr;
+        applied.insert_remote = new_settings.insert_remote;
+        applied.snapshot = new_settings.snapshot.clone();
+        applied.client_timeout = new_settings.client_timeout;
+        applied.max_idle = new_settings.max_idle;
+        applied.max_lifetime = new_settings.max_lifetime;
+        applied.mirror = new_settings.mirror.clone();
+        applied.capture = new_settings.capture.clone();
+        applied.auto_weights = new_settings.auto_weights.clone();
+
+        // Resolved before anything is locked, the lookup may take a while
+        let remote_changed = (applied.inserted_remote(), applied.remote_addr, &applied.remote_host, applied.remote_port, &applied.remote_addrs)
+            != (old_settings.inserted_remote(), old_settings.remote_addr, &old_settings.remote_host, old_settings.remote_port, &old_settings.remote_addrs);
+        let remote = if remote_changed {
+            let resolving = applied.clone();
+            task::spawn_blocking(move || preconfigured_remote_addr(&resolving)).await.ok().flatten()
+        } else {
+            None
+        };
+
+        // Devices to add, once those gone or changed are removed
+        let adding: Vec<SendDevice> = {
+            let context = self.run_context.lock().unwrap().clone();
+            let mut devices = self.devices.lock().unwrap();
+
+            // Remove devices that are gone or changed
+            devices.retain(|device| {
+                let keep = applied.send_devices.contains(&device.settings);
+                if !keep {
+                    println!("Removing send device {}", device.path.iface);
+                    if let Some(tasks) = &device.tasks {
+                        tasks.abort();
+                    }
                 }
-            },
-            None => {}
+                keep
+            });
+
+            // Restart keep-alives on the remaining devices if their settings changed
+            let keep_alive_changed = applied.keep_alive != old_settings.keep_alive
+                || applied.keep_alive_interval != old_settings.keep_alive_interval
+                || applied.keep_alive_interval_ms != old_settings.keep_alive_interval_ms
+                || applied.keep_alive_timeout != old_settings.keep_alive_timeout
+                || applied.keep_alive_nat_only != old_settings.keep_alive_nat_only
+                || applied.keep_alive_while_paused != old_settings.keep_alive_while_paused
+                || applied.path_health != old_settings.path_health;
+            if let (true, Some(context)) = (keep_alive_changed, &context) {
+                for device in devices.iter_mut() {
+                    if let Some(tasks) = &mut device.tasks {
+                        if let Some(keep_alive) = tasks.keep_alive.take() {
+                            keep_alive.abort();
+                        }
+                        tasks.keep_alive = self.spawn_keep_alive(&device.socket, &device.path, context, &applied);
+                    }
+                }
+            }
+
+            *self.paths.write().unwrap() = devices.iter().map(|device| device.path.clone()).collect();
+            applied.send_devices.iter()
+                .filter(|dev| !devices.iter().any(|device| device.settings == **dev))
+                .cloned()
+                .collect()
+        };
+
+        // Bound without holding the devices, on a blocking thread: binding
+        // waits up to bind_timeout for the interface to come up, and a
+        // SOCKS5 proxy may take seconds to answer the handshake
+        let mut added = Vec::new();
+        for dev in adding {
+            println!("Adding send device {}", dev.name());
+            let name = dev.name();
+            let customizer = self.socket_customizer.clone();
+            // Its socket is registered with the runtime its tasks run on
+            let runtime = self.device_runtimes.get(&name).cloned();
+            let made = task::spawn_blocking(move || {
+                let _entered = runtime.as_ref().map(Handle::enter);
+                make_device(&dev, customizer.as_ref())
+            }).await.unwrap_or_else(|err| Err(std::io::Error::other(err)));
+            match made {
+                Ok(device) => added.push(device),
+                Err(err) => eprintln!("Failed to add send device `{}`: {}", name, err)
+            }
+        }
+
+        // The tunnel may have started or stopped running meanwhile
+        let context = self.run_context.lock().unwrap().clone();
+        let mut devices = self.devices.lock().unwrap();
+        for mut device in added {
+            if let Some(context) = &context {
+                device.tasks = Some(self.spawn_device_tasks(&device, context, &applied));
+            }
+            devices.push(device);
+        }
+
+        self.nat_peers.set_flagged(flagged_nat_peers(&applied));
+        // Entries changed at runtime are kept unless the setting itself changed
+        if applied.blocked_sources != old_settings.blocked_sources {
+            self.blocklist.set(applied.blocked_sources.iter().flatten().copied());
         }
 
-        tasks.push(task::spawn(async move {
-            tasks::send_udp(soc_send, send_client_list, rx).await
-        }));
+        *self.paths.write().unwrap() = devices.iter().map(|device| device.path.clone()).collect();
 
-        let tx = inbound_tx.clone();
-        tasks.push(task::spawn(async move {
-            tasks::recv_udp(soc_recv, tx, recv_client_list).await
-        }));
+        drop(devices);
+
+        // Replace the pre-configured remote
+        if remote_changed {
+            if let Some(old_remote) = old_settings.inserted_remote() {
+                self.client_list.remove(&old_remote);
+            }
+            *self.remote_addr.lock().unwrap() = None;
+            self.insert_preconfigured_remote(&applied, remote);
+        }
+
+        *self.settings.write().unwrap() = Arc::new(applied);
     }
 
-    tasks.push(task::spawn(async move {
-        tasks::read_tun(tun_reader, tx).await
-    }));
+    /// Reload the settings file at `path` every time the process receives
+    /// SIGHUP, with the `MPTUN_` environment variables layered over it
+    /// again as in `SettingsFile::load_with_env`. Fails if the signal
+    /// handler can't be installed.
+    pub async fn reload_on_sighup<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        while hangup.recv().await.is_some() {
+            println!("Received SIGHUP, reloading {}", path.as_ref().display());
+            match SettingsFile::load_with_env(&path) {
+                Ok(settings) => self.reload(settings).await,
+                Err(err) => eprintln!("Failed to reload settings, keeping the current ones: {}", err)
+            }
+        }
+        Ok(())
+    }
+}
 
-    tasks.push(task::spawn(async move {
-        tasks::send_tun(tun_writer, inbound_rx).await
-    }));
+// Wait for the first task to stop, then abort the others. Leaves the
+// handles unpolled if cancelled before any task stops.
+async fn supervise(tasks: &mut [(&'static str, JoinHandle<()>)]) -> Vec<TaskReport> {
+    let (first, index, _) = futures::future::select_all(tasks.iter_mut().map(|(_, handle)| handle)).await;
 
-    for task in &mut tasks {
-        task.await.unwrap();
+    let mut outcomes = vec![(index, TaskOutcome::from(first))];
+    for (i, (_, handle)) in tasks.iter_mut().enumerate().filter(|(i, _)| *i != index) {
+        handle.abort();
+        outcomes.push((i, TaskOutcome::from(handle.await)));
     }
+
+    outcomes.into_iter()
+        .map(|(i, outcome)| {
+            if let TaskOutcome::Panicked(message) = &outcome {
+                eprintln!("Task {} panicked: {}", tasks[i].0, message);
+            }
+            TaskReport { task: tasks[i].0, outcome }
+        })
+        .collect()
 }
 
-async fn make_tunnel(settings: Arc<SettingsFile>) -> tokio_tun::Tun {
-    let tun = TunBuilder::new()
-        .name("")
-        .tap(false)
-        .packet_info(false)
-        .mtu(1424)
-        .up()
-        .address(settings.tun_ip)
-        .broadcast(Ipv4Addr::BROADCAST)
-        .netmask(Ipv4Addr::new(255, 255, 255, 0))
-        .try_build().unwrap();
+// The pre-configured remote's address: remote_host if it resolves, else remote_addr
+fn preconfigured_remote_addr(settings: &SettingsFile) -> Option<SocketAddr> {
+    if let Some(host) = &settings.remote_host {
+        match resolve::resolve_blocking(host, settings.remote_port) {
+            Ok(addr) => return Some(addr),
+            Err(err) => eprintln!("Failed to resolve remote host `{}`: {}", host, err)
+        }
+    }
 
-    println!("-----------");
-    println!("tun created");
-    println!("-----------");
+    settings.remote_addr.map(|addr| SocketAddr::new(addr, settings.remote_port))
+}
 
-    println!(
-        "┌ name: {}\n├ fd: {}\n├ mtu: {}\n├ flags: {}\n├ address: {}\n├ destination: {}\n├ broadcast: {}\n└ netmask: {}",
-        tun.name(),
-        tun.as_raw_fd(),
-        tun.mtu().unwrap(),
-        tun.flags().unwrap(),
-        tun.address().unwrap(),
-        tun.destination().unwrap(),
-        tun.broadcast().unwrap(),
-        tun.netmask().unwrap(),
-    );
+fn flagged_nat_peers(settings: &SettingsFile) -> Vec<IpAddr> {
+    settings.nat_peers.iter().flatten().copied().collect()
+}
 
-    tun
+fn make_device(dev: &SendDevice, customizer: Option<&SocketCustomizer>) -> std::io::Result<Device> {
+    let socket: Arc<dyn Transport> = match &dev.unix_socket_dir {
+        Some(dir) => {
+            let address = SocketAddr::new(dev.udp_listen_addr, dev.udp_listen_port);
+            let transport = UnixTransport::bind(dir, address)
+                .map_err(|err| with_context(err, format!("failed to bind unix socket in {}", dir.display())))?;
+            Arc::new(transport)
+        },
+        None => over_relay(dev, make_socket(dev, customizer)?)?
+    };
+    new_device(dev, socket, false)
 }
 
-fn make_socket(interface: &str, local_address: Ipv4Addr, local_port: u16) -> UdpSocket {
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+// A device over a socket made by the application, used as it is
+fn provided_device(dev: &SendDevice, socket: std_udp) -> std::io::Result<Device> {
+    socket.set_nonblocking(true)?;
+    new_device(dev, over_relay(dev, UdpSocket::from_std(socket)?)?, true)
+}
 
-    if let Err(err) = socket.bind_device(Some(interface.as_bytes())) {
-        if matches!(err.raw_os_error(), Some(libc::ENODEV)) {
-            panic!("error binding to device (`{}`): {}", interface, err);
-        } else {
-            panic!("unexpected error binding device: {}", err);
+// `socket`, or the device's relay over it. A SOCKS5 proxy is asked to relay
+// here, from the device's network namespace.
+fn over_relay(dev: &SendDevice, socket: UdpSocket) -> std::io::Result<Arc<dyn Transport>> {
+    let settings = match &dev.relay {
+        Some(settings) => settings,
+        None => return Ok(Arc::new(socket))
+    };
+    match settings.protocol.unwrap_or_default() {
+        RelayProtocol::Plain => Ok(Arc::new(RelayTransport::new(socket, settings.address, None))),
+        RelayProtocol::Socks5 => {
+            let associate = || {
+                let mut control = relay::connect_proxy(settings.address, dev.udp_listen_addr, dev.udp_iface.as_deref())?;
+                let relay_addr = relay::socks5_associate(&mut control, socket.local_addr()?)?;
+                Ok((control, relay_addr))
+            };
+            let (control, relay_addr) = match &dev.netns {
+                Some(netns) => in_netns(netns, associate),
+                None => associate()
+            }.map_err(|err| with_context(err, format!("SOCKS5 proxy {} didn't relay", settings.address)))?;
+            println!("SOCKS5 proxy {} relays `{}` at {}", settings.address, dev.name(), relay_addr);
+            Ok(Arc::new(RelayTransport::new(socket, relay_addr, Some(control))))
         }
     }
+}
 
-    let address = SocketAddrV4::new(local_address, local_port);
-    socket.bind(&address.into()).unwrap();
+fn new_device(dev: &SendDevice, socket: Arc<dyn Transport>, provided: bool) -> std::io::Result<Device> {
+    let local_addr = socket.local_addr()?;
+    Ok(Device {
+        settings: dev.clone(),
+        socket,
+        path: Arc::new(Path::new(dev.name(), local_addr, dev.priority.unwrap_or(0), dev.weight.unwrap_or(1.0), dev.send_fraction.unwrap_or(1.0), dev.max_bps, dev.pacing_bps)),
+        tasks: None,
+        provided
+    })
+}
+
+// `err` with `what` failed in front of its message
+fn with_context(err: std::io::Error, what: String) -> std::io::Error {
+    std::io::Error::new(err.kind(), format!("{}: {}", what, err))
+}
+
+// Run `f` with the calling thread switched into the network namespace `netns`,
+// either a name under /var/run/netns or a path to a namespace file.
+fn in_netns<T>(netns: &str, f: impl FnOnce() -> std::io::Result<T>) -> std::io::Result<T> {
+    let target_path = if netns.contains('/') {
+        netns.to_string()
+    } else {
+        format!("/var/run/netns/{}", netns)
+    };
+
+    let original = File::open("/proc/thread-self/ns/net")
+        .map_err(|err| with_context(err, "failed to open current network namespace".to_string()))?;
+    let target = File::open(&target_path)
+        .map_err(|err| with_context(err, format!("failed to open network namespace `{}`", target_path)))?;
+
+    if unsafe { libc::setns(target.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(with_context(std::io::Error::last_os_error(), format!("failed to enter network namespace `{}`", target_path)))
+    }
+
+    let result = f();
+
+    // The thread would go on in the wrong namespace
+    if unsafe { libc::setns(original.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        panic!("failed to return to the original network namespace: {}", std::io::Error::last_os_error());
+    }
+
+    result
+}
+
+fn make_socket(dev: &SendDevice, customizer: Option<&SocketCustomizer>) -> std::io::Result<UdpSocket> {
+    // Sockets stay in the namespace they were created in, so only creation
+    // and binding have to happen inside it.
+    let socket = match &dev.netns {
+        Some(netns) => in_netns(netns, || bind_socket(dev, customizer))?,
+        None => bind_socket(dev, customizer)?
+    };
 
     let std_udp: std_udp = socket.into();
-    std_udp.set_nonblocking(true).unwrap();
+    std_udp.set_nonblocking(true)?;
+
+    UdpSocket::from_std(std_udp)
+}
 
-    let udp_socket: UdpSocket = UdpSocket::from_std(std_udp).unwrap();
+fn bind_socket(dev: &SendDevice, customizer: Option<&SocketCustomizer>) -> std::io::Result<Socket> {
+    let address = SocketAddr::new(dev.udp_listen_addr, dev.udp_listen_port);
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, None)?;
 
-    udp_socket
+    if address.is_ipv6() {
+        socket.set_only_v6(dev.dual_stack != Some(true))?;
+    }
+
+    let deadline = dev.bind_timeout.map(|timeout| std::time::Instant::now() + Duration::from_secs(timeout));
+
+    if let Some(interface) = &dev.udp_iface {
+        retry_while_unavailable(dev, deadline, || socket.bind_device(Some(interface.as_bytes())))
+            .map_err(|err| with_context(err, format!("error binding to device (`{}`)", interface)))?;
+    }
+
+    if let Some(dscp) = dev.dscp {
+        set_dscp(&socket, address.is_ipv6(), dscp).map_err(|err| with_context(err, format!("failed to set DSCP {}", dscp)))?;
+    }
+
+    if let Some(mark) = dev.fwmark {
+        match socket.set_mark(mark).and_then(|()| socket.mark()) {
+            Ok(set) => println!("Firewall mark of `{}`: {:#x}", dev.name(), set),
+            Err(err) => eprintln!("Failed to set firewall mark {:#x} on `{}`, sending unmarked: {}", mark, dev.name(), err)
+        }
+    }
+
+    if let Some(size) = dev.so_rcvbuf {
+        match socket.set_recv_buffer_size(size).and_then(|()| socket.recv_buffer_size()) {
+            Ok(granted) => println!("Receive buffer of `{}`: requested {} bytes, got {}", dev.name(), size, granted),
+            Err(err) => eprintln!("Failed to set the receive buffer size of `{}`: {}", dev.name(), err)
+        }
+    }
+    if let Some(size) = dev.so_sndbuf {
+        match socket.set_send_buffer_size(size).and_then(|()| socket.send_buffer_size()) {
+            Ok(granted) => println!("Send buffer of `{}`: requested {} bytes, got {}", dev.name(), size, granted),
+            Err(err) => eprintln!("Failed to set the send buffer size of `{}`: {}", dev.name(), err)
+        }
+    }
+
+    if dev.pmtud == Some(true) {
+        set_pmtu_probe(&socket, address.is_ipv6()).map_err(|err| with_context(err, "failed to set the don't fragment bit".to_string()))?;
+    }
+
+    if dev.reuse_port == Some(true) {
+        socket.set_reuse_address(true).and_then(|()| socket.set_reuse_port(true))
+            .map_err(|err| with_context(err, "failed to set SO_REUSEPORT".to_string()))?;
+    }
+
+    if let Some(customizer) = customizer {
+        customizer(&socket, dev).map_err(|err| with_context(err, "socket customizer failed".to_string()))?;
+    }
+
+    // Try each port of the range in turn, moving on only while they are taken
+    let last_port = dev.udp_listen_port_max.unwrap_or(dev.udp_listen_port);
+    for port in dev.udp_listen_port..=last_port {
+        let address = SocketAddr::new(dev.udp_listen_addr, port);
+        match retry_while_unavailable(dev, deadline, || socket.bind(&address.into())) {
+            Ok(()) => {
+                if dev.udp_listen_port_max.is_some() {
+                    println!("Bound `{}` to {}", dev.name(), address);
+                }
+                break
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse && port < last_port => continue,
+            Err(err) if port > dev.udp_listen_port => {
+                return Err(with_context(err, format!("failed to bind to any port from {} to {}", dev.udp_listen_port, last_port)))
+            },
+            Err(err) => return Err(with_context(err, format!("failed to bind to {}", address)))
+        }
+    }
+
+    // The kernel takes any local address, but with SO_BINDTODEVICE one of
+    // another interface as source makes replies come back elsewhere, or be
+    // dropped by reverse path filtering
+    if let Some(interface) = &dev.udp_iface {
+        if !dev.udp_listen_addr.is_unspecified() {
+            match iface_has_addr(interface, dev.udp_listen_addr) {
+                Ok(true) => {},
+                Ok(false) => eprintln!("Warning: {} is not an address of `{}`, but is the source of what `{}` sends", dev.udp_listen_addr, interface, dev.name()),
+                Err(err) => eprintln!("Failed to list the addresses of `{}`: {}", interface, err)
+            }
+        }
+    }
+
+    Ok(socket)
 }
 
-async fn make_sockets(settings: Arc<SettingsFile>) -> Vec<Arc<UdpSocket>> {
-    let mut sockets: Vec<Arc<UdpSocket>> = Vec::new();
+// Whether `addr` is assigned to the interface `iface`
+fn iface_has_addr(iface: &str, addr: IpAddr) -> std::io::Result<bool> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    let mut found = false;
+    let mut current = addrs;
+    while !current.is_null() {
+        let entry = unsafe { &*current };
+        current = entry.ifa_next;
+        if entry.ifa_addr.is_null() || unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) }.to_bytes() != iface.as_bytes() {
+            continue
+        }
+        let assigned = match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sockaddr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::from(u32::from_be(sockaddr.sin_addr.s_addr).to_be_bytes())
+            },
+            libc::AF_INET6 => {
+                let sockaddr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::from(sockaddr.sin6_addr.s6_addr)
+            },
+            _ => continue
+        };
+        if assigned == addr {
+            found = true;
+            break
+        }
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    Ok(found)
+}
 
-    for dev in &settings.send_devices {
-        let socket = make_socket(dev.udp_iface.as_str(), dev.udp_listen_addr, dev.udp_listen_port);
-        sockets.push(Arc::new(socket));
+// Retry `op` until `deadline` while it fails because the interface or its
+// address isn't there yet
+fn retry_while_unavailable(dev: &SendDevice, deadline: Option<std::time::Instant>, mut op: impl FnMut() -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut waiting = false;
+    loop {
+        match op() {
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENODEV) | Some(libc::EADDRNOTAVAIL))
+                && deadline.is_some_and(|deadline| std::time::Instant::now() < deadline) => {
+                if !waiting {
+                    println!("Waiting for `{}` to come up: {}", dev.name(), err);
+                    waiting = true;
+                }
+                std::thread::sleep(BIND_RETRY_INTERVAL);
+            },
+            result => return result
+        }
     }
+}
 
-    sockets
+// Set DF on everything sent and ignore the kernel's path MTU cache, so
+// probes larger than the current estimate still go out
+fn set_pmtu_probe(socket: &Socket, ipv6: bool) -> std::io::Result<()> {
+    let (level, name, value) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE)
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t
+        )
+    };
+
+    if ret == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
+}
+
+fn set_dscp(socket: &Socket, ipv6: bool, dscp: u8) -> std::io::Result<()> {
+    let tos = u32::from(dscp & 0x3f) << 2;
+    if !ipv6 {
+        return socket.set_tos(tos)
+    }
+
+    // socket2 has no traffic class setter
+    let tclass = tos as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t
+        )
+    };
+
+    if ret == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::os::unix::io::FromRawFd;
+    use crate::clock::Clock;
+    use super::*;
+
+    fn loopback_device() -> SendDevice {
+        SendDevice::new([127, 0, 0, 1].into(), 0)
+    }
+
+    #[test]
+    fn device_without_udp_iface_binds_only_the_address() {
+        let socket = bind_socket(&loopback_device(), None).unwrap();
+
+        assert_eq!(socket.device().unwrap(), None);
+        let local = socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_ne!(local.port(), 0);
+    }
+
+    // Two devices on the same port, bound to an interface each. Only
+    // loopback is sure to exist, so both stand in for different ones on it.
+    fn same_port_devices(reuse_port: Option<bool>) -> (Socket, std::io::Result<Socket>) {
+        let mut device = loopback_device();
+        device.udp_iface = Some("lo".to_string());
+        device.reuse_port = reuse_port;
+        let first = bind_socket(&device, None).unwrap();
+        device.udp_listen_port = first.local_addr().unwrap().as_socket().unwrap().port();
+        let second = bind_socket(&device, None);
+        (first, second)
+    }
+
+    #[test]
+    fn reuse_port_lets_devices_share_a_port() {
+        let (first, second) = same_port_devices(Some(true));
+        assert_eq!(second.unwrap().local_addr().unwrap().as_socket(), first.local_addr().unwrap().as_socket());
+    }
+
+    #[test]
+    fn devices_cannot_share_a_port_by_default() {
+        let (_first, second) = same_port_devices(None);
+        assert_eq!(second.unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn addresses_are_looked_up_on_their_interface() {
+        assert!(iface_has_addr("lo", Ipv4Addr::LOCALHOST.into()).unwrap());
+        assert!(!iface_has_addr("lo", Ipv4Addr::new(192, 0, 2, 1).into()).unwrap());
+        assert!(!iface_has_addr("no-such-iface", Ipv4Addr::LOCALHOST.into()).unwrap());
+    }
+
+    // Three ports in a row, all free but the first, which `taken` holds
+    fn range_with_first_taken() -> (std::net::UdpSocket, SendDevice) {
+        loop {
+            let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let first = taken.local_addr().unwrap().port();
+            let rest_free = (1..3).all(|offset| first.checked_add(offset).is_some_and(|port| std::net::UdpSocket::bind(("127.0.0.1", port)).is_ok()));
+            if rest_free {
+                let mut device = SendDevice::new([127, 0, 0, 1].into(), first);
+                device.udp_listen_port_max = Some(first + 2);
+                return (taken, device)
+            }
+        }
+    }
+
+    #[test]
+    fn a_port_range_binds_its_first_free_port() {
+        let (taken, device) = range_with_first_taken();
+        let socket = bind_socket(&device, None).unwrap();
+        assert_eq!(socket.local_addr().unwrap().as_socket().unwrap().port(), taken.local_addr().unwrap().port() + 1);
+    }
+
+    #[test]
+    fn a_port_range_with_every_port_taken_fails() {
+        let (_taken, mut device) = range_with_first_taken();
+        device.udp_listen_port_max = Some(device.udp_listen_port);
+        let err = bind_socket(&device, None).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        let (_taken, device) = range_with_first_taken();
+        let _second = bind_socket(&device, None).unwrap();
+        let _third = bind_socket(&device, None).unwrap();
+        let err = bind_socket(&device, None).unwrap_err();
+        assert!(err.to_string().contains(&format!("any port from {} to {}", device.udp_listen_port, device.udp_listen_port + 2)), "{}", err);
+    }
+
+    #[test]
+    fn fwmark_is_set_where_permitted() {
+        let mut device = loopback_device();
+        device.fwmark = Some(0x2a);
+        let socket = bind_socket(&device, None).unwrap();
+
+        // Without CAP_NET_ADMIN the socket is still bound, just unmarked
+        match Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap().set_mark(1) {
+            Ok(()) => assert_eq!(socket.mark().unwrap(), 0x2a),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => assert_eq!(socket.mark().unwrap(), 0),
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    #[test]
+    fn ipv4_and_ipv6_devices_get_sockets_of_their_family() {
+        let v4 = bind_socket(&loopback_device(), None).unwrap();
+        assert!(v4.local_addr().unwrap().as_socket().unwrap().is_ipv4());
+
+        let v6 = bind_socket(&SendDevice::new(Ipv6Addr::LOCALHOST.into(), 0), None).unwrap();
+        let local = v6.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::from(Ipv6Addr::LOCALHOST));
+        assert!(v6.only_v6().unwrap());
+    }
+
+    #[test]
+    fn dual_stack_device_accepts_ipv4() {
+        let mut dev = SendDevice::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+        dev.dual_stack = Some(true);
+        let socket: std_udp = bind_socket(&dev, None).unwrap().into();
+        assert!(!socket2::SockRef::from(&socket).only_v6().unwrap());
+
+        let sender = std_udp::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"hi", ("127.0.0.1", socket.local_addr().unwrap().port())).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buf = [0; 2];
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hi");
+        // Seen in its IPv4-mapped form
+        assert_eq!(from.ip(), IpAddr::from(Ipv4Addr::LOCALHOST.to_ipv6_mapped()));
+        assert_eq!(from.port(), sender.local_addr().unwrap().port());
+    }
+
+    #[test]
+    fn device_buffer_sizes_are_set_on_its_socket() {
+        // Small enough for the default rmem_max and wmem_max
+        let size = 64 * 1024;
+        let mut dev = loopback_device();
+        dev.so_rcvbuf = Some(size);
+        dev.so_sndbuf = Some(size);
+        let socket = bind_socket(&dev, None).unwrap();
+
+        // Linux doubles the size asked for, to leave room for its bookkeeping
+        let (recv, send) = (socket.recv_buffer_size().unwrap(), socket.send_buffer_size().unwrap());
+        assert!((size..=2 * size).contains(&recv), "receive buffer of {} bytes", recv);
+        assert!((size..=2 * size).contains(&send), "send buffer of {} bytes", send);
+    }
+
+    #[test]
+    fn device_dscp_is_set_on_its_socket() {
+        let mut dev = loopback_device();
+        dev.dscp = Some(46);
+        assert_eq!(bind_socket(&dev, None).unwrap().tos().unwrap(), 46 << 2);
+
+        // The traffic class for IPv6
+        dev.udp_listen_addr = Ipv6Addr::LOCALHOST.into();
+        let socket = bind_socket(&dev, None).unwrap();
+        let mut tclass: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &mut tclass as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+        assert_eq!(ret, 0, "{}", std::io::Error::last_os_error());
+        assert_eq!(tclass, 46 << 2);
+    }
+
+    #[test]
+    fn dscp_beyond_six_bits_is_masked() {
+        let mut dev = loopback_device();
+        dev.dscp = Some(0xff);
+        assert_eq!(bind_socket(&dev, None).unwrap().tos().unwrap(), 0xfc);
+    }
+
+    #[test]
+    fn device_with_udp_iface_is_bound_to_it() {
+        let mut dev = loopback_device();
+        dev.udp_iface = Some("lo".to_string());
+        // SO_BINDTODEVICE needs CAP_NET_RAW
+        let socket = match bind_socket(&dev, None) {
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => return,
+            socket => socket.unwrap()
+        };
+
+        assert_eq!(socket.device().unwrap().as_deref(), Some(&b"lo"[..]));
+    }
+
+    // Inode of the network namespace of `fd`, a namespace file or a socket
+    fn netns_inode(fd: &impl AsRawFd, socket: bool) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        if !socket {
+            let file = unsafe { std::mem::ManuallyDrop::new(File::from_raw_fd(fd.as_raw_fd())) };
+            return file.metadata().unwrap().ino()
+        }
+        // SIOCGSKNS opens the namespace the socket was created in
+        let ns = unsafe { libc::ioctl(fd.as_raw_fd(), 0x894c) };
+        assert!(ns >= 0, "{}", std::io::Error::last_os_error());
+        let ns = unsafe { File::from_raw_fd(ns) };
+        ns.metadata().unwrap().ino()
+    }
+
+    #[test]
+    fn device_with_netns_gets_its_socket_created_there() {
+        // setns switches only the calling thread, keep it off the test harness's
+        std::thread::spawn(|| {
+            let original = File::open("/proc/thread-self/ns/net").unwrap();
+            // Creating a namespace needs CAP_SYS_ADMIN
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                eprintln!("Skipping, can't create a network namespace: {}", std::io::Error::last_os_error());
+                return
+            }
+            let created = File::open("/proc/thread-self/ns/net").unwrap();
+            assert_eq!(unsafe { libc::setns(original.as_raw_fd(), libc::CLONE_NEWNET) }, 0);
+
+            let mut dev = SendDevice::new([0, 0, 0, 0].into(), 0);
+            dev.netns = Some(format!("/proc/self/fd/{}", created.as_raw_fd()));
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let _entered = runtime.enter();
+            let socket = make_socket(&dev, None).unwrap();
+
+            assert_eq!(netns_inode(&socket, true), netns_inode(&created, false));
+            assert_ne!(netns_inode(&socket, true), netns_inode(&original, false));
+            // And the thread is back where it was
+            assert_eq!(netns_inode(&File::open("/proc/thread-self/ns/net").unwrap(), false), netns_inode(&original, false));
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn missing_netns_fails_the_device() {
+        let mut dev = loopback_device();
+        dev.netns = Some("mptun-test-no-such-namespace".to_string());
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let _entered = runtime.enter();
+
+        let err = make_socket(&dev, None).unwrap_err();
+        assert!(err.to_string().contains("/var/run/netns/mptun-test-no-such-namespace"), "{}", err);
+    }
+
+    fn unavailable() -> std::io::Error {
+        std::io::Error::from_raw_os_error(libc::EADDRNOTAVAIL)
+    }
+
+    #[test]
+    fn bind_is_retried_until_the_address_appears() {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let mut attempts = 0;
+        // The address shows up on the third attempt
+        let result = retry_while_unavailable(&loopback_device(), Some(deadline), || {
+            attempts += 1;
+            if attempts < 3 { Err(unavailable()) } else { Ok(()) }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn bind_fails_at_once_without_a_timeout() {
+        let mut attempts = 0;
+        let err = retry_while_unavailable(&loopback_device(), None, || {
+            attempts += 1;
+            Err(unavailable())
+        }).unwrap_err();
+
+        assert_eq!(err.raw_os_error(), Some(libc::EADDRNOTAVAIL));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn bind_gives_up_at_the_deadline() {
+        let start = std::time::Instant::now();
+        let deadline = start + BIND_RETRY_INTERVAL * 2;
+        let err = retry_while_unavailable(&loopback_device(), Some(deadline), || Err(unavailable())).unwrap_err();
+
+        assert_eq!(err.raw_os_error(), Some(libc::EADDRNOTAVAIL));
+        assert!(start.elapsed() >= BIND_RETRY_INTERVAL * 2);
+        assert!(start.elapsed() < BIND_RETRY_INTERVAL * 4);
+    }
+
+    #[test]
+    fn other_bind_errors_are_not_retried() {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let mut attempts = 0;
+        let err = retry_while_unavailable(&loopback_device(), Some(deadline), || {
+            attempts += 1;
+            Err(std::io::Error::from_raw_os_error(libc::EADDRINUSE))
+        }).unwrap_err();
+
+        assert_eq!(err.raw_os_error(), Some(libc::EADDRINUSE));
+        assert_eq!(attempts, 1);
+    }
+
+    // Paused, so the sleep after each advance only ends once the reaper is idle
+    #[tokio::test(start_paused = true)]
+    async fn the_reaper_expires_clients_on_the_ticks_of_the_clock() {
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let settings = crate::settings::SettingsFileBuilder::new([10, 0, 0, 1].into()).add_send_device(loopback_device()).client_timeout(4).build().unwrap();
+        let tunnel = Arc::new(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+        let (quiet, chatty): (IpAddr, IpAddr) = ([10, 0, 0, 2].into(), [10, 0, 0, 3].into());
+        let (quiet_addr, chatty_addr): (SocketAddr, SocketAddr) = ("127.0.0.1:5002".parse().unwrap(), "127.0.0.1:5003".parse().unwrap());
+        for (tun_ip, addr) in [(quiet, quiet_addr), (chatty, chatty_addr)] {
+            tunnel.client_list.upsert(tun_ip, None, |client, _| client.push(addr));
+            tunnel.last_seen.refresh(addr, clock.now());
+        }
+        let mut events = tunnel.subscribe_events();
+        let reaping = tokio::spawn({
+            let tunnel = tunnel.clone();
+            async move { tunnel.reap_dead_clients().await }
+        });
+
+        // With a 4 s timeout the reaper looks every second, and drops
+        // addresses once more than 4 s have passed without a word from them
+        let mut known_after = Vec::new();
+        for second in 1..=8 {
+            clock.advance(Duration::from_secs(1));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if second == 3 {
+                tunnel.last_seen.refresh(chatty_addr, clock.now());
+            }
+            known_after.push((tunnel.client_list.contains(&quiet), tunnel.client_list.contains(&chatty)));
+        }
+        assert_eq!(known_after, [(true, true), (true, true), (true, true), (true, true), (false, true), (false, true), (false, true), (false, false)]);
+        assert_eq!(events.try_recv().unwrap(), Event::ClientExpired { tun_ip: quiet, addr: quiet_addr });
+        assert_eq!(events.try_recv().unwrap(), Event::ClientExpired { tun_ip: chatty, addr: chatty_addr });
+        reaping.abort();
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::liveness::LastSeen;
+
+/// Peers, by TUN IP, that are behind a NAT: either flagged in the settings
+/// or detected from their source ports being rewritten.
+#[derive(Debug, Default)]
+pub struct NatPeers {
+    flagged: RwLock<HashSet<IpAddr>>,
+    detected: RwLock<HashSet<IpAddr>>
+}
+
+impl NatPeers {
+    pub fn new(flagged: impl IntoIterator<Item = IpAddr>) -> NatPeers {
+        NatPeers {
+            flagged: RwLock::new(flagged.into_iter().collect()),
+            detected: RwLock::new(HashSet::new())
+        }
+    }
+
+    /// Replace the peers flagged in the settings. Detected peers are kept.
+    pub fn set_flagged(&self, flagged: impl IntoIterator<Item = IpAddr>) {
+        *self.flagged.write().unwrap() = flagged.into_iter().collect();
+    }
+
+    /// Record that `tun_ip` is behind a NAT. Returns true if it wasn't known to be.
+    pub fn detected(&self, tun_ip: IpAddr) -> bool {
+        !self.flagged.read().unwrap().contains(&tun_ip) && self.detected.write().unwrap().insert(tun_ip)
+    }
+
+    /// Forget that `tun_ip` was detected behind a NAT. Flagged peers stay flagged.
+    pub fn forget(&self, tun_ip: &IpAddr) {
+        self.detected.write().unwrap().remove(tun_ip);
+    }
+
+    pub fn contains(&self, tun_ip: &IpAddr) -> bool {
+        self.flagged.read().unwrap().contains(tun_ip) || self.detected.read().unwrap().contains(tun_ip)
+    }
+}
+
+/// Whether `addr` looks like a NAT rewrote the source port of one of the
+/// `known` addresses: same IP, different port.
+pub fn is_port_rewrite(known: &[SocketAddr], addr: SocketAddr) -> bool {
+    known.iter().any(|known| known.ip() == addr.ip() && known.port() != addr.port())
+}
+
+/// Targets that look like an earlier NAT mapping of `addr`, which was just
+/// heard from: same IP, another port, and silent for longer than `grace`.
+/// Targets never heard from, like a pre-configured address, start their
+/// grace period now.
+pub fn stale_mappings(targets: &[SocketAddr], addr: SocketAddr, last_seen: &LastSeen, now: Instant, grace: Duration) -> Vec<SocketAddr> {
+    targets.iter().copied()
+        .filter(|target| target.ip() == addr.ip() && target.port() != addr.port())
+        .filter(|target| last_seen.is_dead(*target, now, grace))
+        .collect()
+}
+
+/// Remove repeated targets, keeping the first of each.
+pub fn canonicalize_targets(targets: &mut Vec<SocketAddr>) {
+    let mut seen = HashSet::new();
+    targets.retain(|target| seen.insert(*target));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 3));
+
+    #[test]
+    fn peers_are_natted_when_flagged_or_detected() {
+        let peers = NatPeers::new([PEER]);
+        assert!(peers.contains(&PEER));
+        assert!(!peers.contains(&OTHER));
+        // Already flagged, so nothing new is learned
+        assert!(!peers.detected(PEER));
+
+        assert!(peers.detected(OTHER));
+        assert!(!peers.detected(OTHER));
+        assert!(peers.contains(&OTHER));
+
+        peers.forget(&OTHER);
+        peers.forget(&PEER);
+        assert!(!peers.contains(&OTHER));
+        assert!(peers.contains(&PEER));
+    }
+
+    #[test]
+    fn reflagging_keeps_detected_peers() {
+        let peers = NatPeers::new([PEER]);
+        peers.detected(OTHER);
+        peers.set_flagged([]);
+        assert!(!peers.contains(&PEER));
+        assert!(peers.contains(&OTHER));
+    }
+
+    #[test]
+    fn only_a_changed_port_on_a_known_ip_is_a_rewrite() {
+        let known: Vec<SocketAddr> = vec!["192.0.2.1:4000".parse().unwrap()];
+        assert!(is_port_rewrite(&known, "192.0.2.1:4001".parse().unwrap()));
+        assert!(!is_port_rewrite(&known, "192.0.2.1:4000".parse().unwrap()));
+        assert!(!is_port_rewrite(&known, "192.0.2.2:4001".parse().unwrap()));
+        assert!(!is_port_rewrite(&[], "192.0.2.1:4001".parse().unwrap()));
+    }
+
+    #[test]
+    fn silent_mappings_on_the_same_ip_go_stale_after_the_grace() {
+        let (old, new, elsewhere): (SocketAddr, SocketAddr, SocketAddr) =
+            ("192.0.2.1:4000".parse().unwrap(), "192.0.2.1:4001".parse().unwrap(), "192.0.2.2:4000".parse().unwrap());
+        let (last_seen, start, grace) = (LastSeen::default(), Instant::now(), Duration::from_secs(30));
+        last_seen.refresh(old, start);
+        last_seen.refresh(elsewhere, start);
+        let targets = [old, new, elsewhere];
+
+        assert!(stale_mappings(&targets, new, &last_seen, start + grace, grace).is_empty());
+        // Only the other port on the new mapping's IP
+        assert_eq!(stale_mappings(&targets, new, &last_seen, start + grace + Duration::from_secs(1), grace), vec![old]);
+    }
+
+    #[test]
+    fn never_heard_mappings_start_their_grace_at_the_rebinding() {
+        let (configured, new): (SocketAddr, SocketAddr) = ("192.0.2.1:4000".parse().unwrap(), "192.0.2.1:4001".parse().unwrap());
+        let (last_seen, start, grace) = (LastSeen::default(), Instant::now(), Duration::from_secs(30));
+
+        assert!(stale_mappings(&[configured, new], new, &last_seen, start, grace).is_empty());
+        assert_eq!(stale_mappings(&[configured, new], new, &last_seen, start + grace * 2, grace), vec![configured]);
+    }
+
+    #[test]
+    fn canonical_targets_keep_the_first_of_each() {
+        let (a, b): (SocketAddr, SocketAddr) = ("192.0.2.1:4000".parse().unwrap(), "192.0.2.1:4001".parse().unwrap());
+        let mut targets = vec![a, b, a, b, a];
+        canonicalize_targets(&mut targets);
+        assert_eq!(targets, vec![a, b]);
+    }
+}
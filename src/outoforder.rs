@@ -0,0 +1,111 @@
+// Depths beyond this are counted together, their exact value only feeds the maximum
+const MAX_TRACKED_DEPTH: usize = 1024;
+
+/// How far out of order packets arrive on one path: for each packet
+/// delivered, how many sequence numbers it is behind the highest one already
+/// delivered from its sender, over any path. 0 for packets in order. Packets
+/// more than the p99 depth behind would need a reorder buffer at least that
+/// deep to be put back in order.
+#[derive(Debug)]
+pub struct OutOfOrder {
+    // Packets by depth, the last entry holding everything deeper
+    depths: Vec<u64>,
+    delivered: u64,
+    max_depth: usize
+}
+
+impl Default for OutOfOrder {
+    fn default() -> OutOfOrder {
+        OutOfOrder {
+            depths: vec![0; MAX_TRACKED_DEPTH + 2],
+            delivered: 0,
+            max_depth: 0
+        }
+    }
+}
+
+impl OutOfOrder {
+    pub fn record(&mut self, depth: usize) {
+        self.depths[depth.min(MAX_TRACKED_DEPTH + 1)] += 1;
+        self.delivered += 1;
+        self.max_depth = self.max_depth.max(depth);
+    }
+
+    /// Fraction of the packets delivered that were out of order, if any were delivered.
+    pub fn fraction(&self) -> Option<f64> {
+        if self.delivered == 0 {
+            return None
+        }
+        Some((self.delivered - self.depths[0]) as f64 / self.delivered as f64)
+    }
+
+    /// The smallest depth at least `percentile` percent of the packets
+    /// delivered were within. Depths beyond the tracked range read as the
+    /// maximum seen.
+    pub fn percentile(&self, percentile: f64) -> Option<usize> {
+        if self.delivered == 0 {
+            return None
+        }
+        let wanted = (self.delivered as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (depth, count) in self.depths.iter().enumerate().take(MAX_TRACKED_DEPTH + 1) {
+            seen += count;
+            if seen >= wanted {
+                return Some(depth)
+            }
+        }
+        Some(self.max_depth)
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        if self.delivered == 0 { None } else { Some(self.max_depth) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(depths: &[usize]) -> OutOfOrder {
+        let mut out_of_order = OutOfOrder::default();
+        for &depth in depths {
+            out_of_order.record(depth);
+        }
+        out_of_order
+    }
+
+    #[test]
+    fn nothing_delivered_has_no_statistics() {
+        let out_of_order = OutOfOrder::default();
+        assert_eq!((out_of_order.fraction(), out_of_order.percentile(99.0), out_of_order.max_depth()), (None, None, None));
+    }
+
+    #[test]
+    fn in_order_delivery_has_depth_zero() {
+        let out_of_order = recorded(&[0; 50]);
+        assert_eq!((out_of_order.fraction(), out_of_order.percentile(99.0), out_of_order.max_depth()), (Some(0.0), Some(0), Some(0)));
+    }
+
+    #[test]
+    fn percentiles_are_the_depth_that_many_packets_were_within() {
+        // 90 in order, 8 one behind, one 3 behind and one 20 behind
+        let mut depths = vec![0; 90];
+        depths.extend([1; 8]);
+        depths.extend([3, 20]);
+        let out_of_order = recorded(&depths);
+
+        assert_eq!(out_of_order.fraction(), Some(0.1));
+        assert_eq!(out_of_order.percentile(90.0), Some(0));
+        assert_eq!(out_of_order.percentile(98.0), Some(1));
+        assert_eq!(out_of_order.percentile(99.0), Some(3));
+        assert_eq!(out_of_order.percentile(100.0), Some(20));
+        assert_eq!(out_of_order.max_depth(), Some(20));
+    }
+
+    #[test]
+    fn depths_beyond_the_tracked_range_read_as_the_maximum() {
+        let out_of_order = recorded(&[0, 5000]);
+        assert_eq!(out_of_order.percentile(99.0), Some(5000));
+        assert_eq!(out_of_order.max_depth(), Some(5000));
+    }
+}
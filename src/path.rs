@@ -0,0 +1,879 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, SocketAddr};
+use serde::Serialize;
+
+use crate::stats::PathCounters;
+use crate::ratelimit::{Pacer, TokenBucket};
+use crate::rate::{Rate, RateMeter};
+use crate::pmtud::PmtuSearch;
+use crate::flows::FlowKey;
+use crate::jitter::JitterEstimator;
+use crate::outoforder::OutOfOrder;
+use crate::messages::PathStamp;
+use crate::pathseq::{self, LinkLoss, LinkLosses, PathSeqs};
+use crate::weights::{self, PathWeight, WeightPolicy};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Health {
+    Up,
+    Down
+}
+
+/// How many keep-alives decide a path's health, and how many changes of
+/// health make it flapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthPolicy {
+    // Consecutive keep-alives gone unanswered, on top of the timeout, before the path is marked down
+    pub down_after: u32,
+    // Consecutive keep-alives answered before a down path is marked up
+    pub up_after: u32,
+    // Changes of health within flap_window that make the path flapping
+    pub flap_transitions: usize,
+    pub flap_window: Duration
+}
+
+impl Default for HealthPolicy {
+    // Down at the timeout, up at the first reply, never flapping
+    fn default() -> HealthPolicy {
+        HealthPolicy { down_after: 1, up_after: 1, flap_transitions: usize::MAX, flap_window: Duration::ZERO }
+    }
+}
+
+#[derive(Debug)]
+struct PathState {
+    health: Health,
+    policy: HealthPolicy,
+    // When the oldest unanswered keep-alive was sent
+    awaiting_reply_since: Option<Instant>,
+    last_ping: Option<Instant>,
+    rtt: Option<Duration>,
+    // Smoothed RTT and its variation, as TCP keeps them (RFC 6298)
+    srtt: Option<Duration>,
+    rttvar: Option<Duration>,
+    // Keep-alives gone unanswered in a row, and answered in a row while
+    // down. The ping last counted toward the latter, so several replies to
+    // one round count once.
+    missed: u32,
+    answered: u32,
+    answered_ping: Option<Instant>,
+    // When the health changed within the flap window
+    transitions: VecDeque<Instant>,
+    // When the path last came back up
+    recovered_at: Option<Instant>
+}
+
+impl PathState {
+    fn transition(&mut self, health: Health, now: Instant) {
+        self.health = health;
+        self.missed = 0;
+        self.answered = 0;
+        self.transitions.push_back(now);
+        self.forget_transitions(now);
+    }
+
+    fn forget_transitions(&mut self, now: Instant) {
+        while self.transitions.front().is_some_and(|at| now.saturating_duration_since(*at) > self.policy.flap_window) {
+            self.transitions.pop_front();
+        }
+    }
+}
+
+/// Per send device state shared between the send, receive and keep-alive tasks.
+#[derive(Debug)]
+pub struct Path {
+    // Stamped on packets with path_sequence, so the peer can tell the paths apart
+    pub id: u16,
+    pub iface: String,
+    pub local_addr: SocketAddr,
+    // Lower values are preferred in failover mode
+    pub priority: u8,
+    // Share of the packets sent in redundant mode, see `sends_seq`
+    pub send_fraction: f64,
+    pub counters: PathCounters,
+    // Set by `Multipathtunnel::pause_path`, see `sendable`
+    paused: AtomicBool,
+    state: Mutex<PathState>,
+    // Transmit and receive rates, sampled from the counters
+    rates: Mutex<(RateMeter, RateMeter)>,
+    // Path MTU discovery, when enabled for the device
+    pmtu: Mutex<Option<PmtuSearch>>,
+    // Delay and jitter of timestamped packets received
+    delay: Mutex<JitterEstimator>,
+    // How far behind other paths the packets delivered from this one were
+    out_of_order: Mutex<OutOfOrder>,
+    // Per-path sequence numbers sent to each peer, and loss seen in the peers'
+    path_seqs: Mutex<PathSeqs>,
+    link_losses: Mutex<LinkLosses>,
+    // Share of the packets in weighted path mode
+    weight: Mutex<PathWeight>,
+    rate_limit: Option<Mutex<TokenBucket>>,
+    pacer: Option<Mutex<Pacer>>
+}
+
+// Paths of the current send devices, updated when devices are added or removed
+pub type Paths = Arc<RwLock<Vec<Arc<Path>>>>;
+
+impl Path {
+    pub fn new(iface: String, local_addr: SocketAddr, priority: u8, weight: f64, send_fraction: f64, max_bps: Option<u64>, pacing_bps: Option<u64>) -> Path {
+        Path {
+            id: pathseq::next_path_id(),
+            iface,
+            local_addr,
+            priority,
+            send_fraction,
+            counters: PathCounters::default(),
+            paused: AtomicBool::new(false),
+            state: Mutex::new(PathState {
+                health: Health::Up,
+                policy: HealthPolicy::default(),
+                awaiting_reply_since: None,
+                last_ping: None,
+                rtt: None,
+                srtt: None,
+                rttvar: None,
+                missed: 0,
+                answered: 0,
+                answered_ping: None,
+                transitions: VecDeque::new(),
+                recovered_at: None
+            }),
+            rates: Mutex::new((RateMeter::default(), RateMeter::default())),
+            pmtu: Mutex::new(None),
+            delay: Mutex::new(JitterEstimator::default()),
+            out_of_order: Mutex::new(OutOfOrder::default()),
+            path_seqs: Mutex::new(PathSeqs::default()),
+            link_losses: Mutex::new(LinkLosses::default()),
+            weight: Mutex::new(PathWeight::new(weight)),
+            rate_limit: max_bps.map(|max_bps| Mutex::new(TokenBucket::new(max_bps))),
+            pacer: pacing_bps.map(|pacing_bps| Mutex::new(Pacer::new(pacing_bps)))
+        }
+    }
+
+    /// Whether the rate limit would let `len` bytes out now. Always true without a limit.
+    pub fn has_budget(&self, len: usize, now: Instant) -> bool {
+        match &self.rate_limit {
+            Some(bucket) => bucket.lock().unwrap().has_tokens(len, now),
+            None => true
+        }
+    }
+
+    /// Charge `len` bytes against the rate limit. Returns false if the
+    /// datagram must not be sent. Always true without a limit.
+    pub fn consume_budget(&self, len: usize, now: Instant) -> bool {
+        match &self.rate_limit {
+            Some(bucket) => bucket.lock().unwrap().try_consume(len, now),
+            None => true
+        }
+    }
+
+    pub fn is_paced(&self) -> bool {
+        self.pacer.is_some()
+    }
+
+    /// When `len` bytes may be sent under pacing, reserving their slot.
+    /// `None` without pacing.
+    pub fn pace(&self, len: usize, now: Instant) -> Option<Instant> {
+        self.pacer.as_ref().map(|pacer| pacer.lock().unwrap().reserve(len, now))
+    }
+
+    /// Update the transmit and receive rates from the counters.
+    pub fn sample_rates(&self, now: Instant) {
+        let mut rates = self.rates.lock().unwrap();
+        rates.0.sample(now, self.counters.tx_packets.load(Ordering::Relaxed), self.counters.tx_bytes.load(Ordering::Relaxed));
+        rates.1.sample(now, self.counters.rx_packets.load(Ordering::Relaxed), self.counters.rx_bytes.load(Ordering::Relaxed));
+    }
+
+    /// Transmit and receive rates as of the last sample.
+    pub fn rates(&self) -> (Rate, Rate) {
+        let rates = self.rates.lock().unwrap();
+        (rates.0.rate(), rates.1.rate())
+    }
+
+    /// Start path MTU discovery, replacing any search in progress.
+    pub fn start_pmtud(&self, search: PmtuSearch) {
+        *self.pmtu.lock().unwrap() = Some(search);
+    }
+
+    /// Largest datagram known to get through, while path MTU discovery runs.
+    pub fn pmtu(&self) -> Option<usize> {
+        self.pmtu.lock().unwrap().as_ref().map(PmtuSearch::mtu)
+    }
+
+    /// Size of the next probe to send, if any. A probe still unanswered
+    /// from the previous round is counted as lost first.
+    pub fn next_probe(&self, now: Instant) -> Option<usize> {
+        let mut pmtu = self.pmtu.lock().unwrap();
+        let search = pmtu.as_mut()?;
+        if let Some(lost) = search.in_flight() {
+            search.probe_failed(lost, now);
+        }
+        search.next_probe(now)
+    }
+
+    pub fn probe_succeeded(&self, size: usize, now: Instant) {
+        if let Some(search) = self.pmtu.lock().unwrap().as_mut() {
+            search.probe_succeeded(size, now);
+        }
+    }
+
+    pub fn probe_failed(&self, size: usize, now: Instant) {
+        if let Some(search) = self.pmtu.lock().unwrap().as_mut() {
+            search.probe_failed(size, now);
+        }
+    }
+
+    /// Record a timestamped packet received on this path.
+    pub fn timestamp_received(&self, sent_us: u64, received_us: u64) {
+        self.delay.lock().unwrap().observe(sent_us, received_us);
+    }
+
+    /// One-way delay in microseconds of the last timestamped packet, including any clock offset.
+    pub fn one_way_delay_us(&self) -> Option<i64> {
+        self.delay.lock().unwrap().one_way_delay_us()
+    }
+
+    pub fn jitter(&self) -> Option<Duration> {
+        self.delay.lock().unwrap().jitter()
+    }
+
+    /// Record a packet from this path delivered `depth` sequence numbers
+    /// behind the highest delivered from its sender.
+    pub fn delivered(&self, depth: usize) {
+        self.out_of_order.lock().unwrap().record(depth);
+    }
+
+    /// Fraction of the packets delivered out of order, and the 99th
+    /// percentile and maximum of how far behind they were.
+    pub fn out_of_order(&self) -> (Option<f64>, Option<usize>, Option<usize>) {
+        let out_of_order = self.out_of_order.lock().unwrap();
+        (out_of_order.fraction(), out_of_order.percentile(99.0), out_of_order.max_depth())
+    }
+
+    /// Stamp for the next packet to `peer` at `target`.
+    pub fn stamp(&self, peer: IpAddr, target: SocketAddr) -> PathStamp {
+        PathStamp { path_id: self.id, path_seq: self.path_seqs.lock().unwrap().next(peer, target) }
+    }
+
+    /// Account for a packet from `tun_ip` stamped by one of its paths.
+    pub fn link_received(&self, tun_ip: IpAddr, stamp: PathStamp) {
+        self.link_losses.lock().unwrap().record(tun_ip, stamp.path_id, stamp.path_seq);
+    }
+
+    /// Loss on each of the peers' paths to this one, for peers sending path stamped packets.
+    pub fn link_losses(&self) -> Vec<LinkLoss> {
+        self.link_losses.lock().unwrap().snapshot()
+    }
+
+    /// Drop the per-path sequence state of a removed peer.
+    pub fn forget_peer(&self, tun_ip: &IpAddr) {
+        self.path_seqs.lock().unwrap().forget(tun_ip);
+        self.link_losses.lock().unwrap().forget(tun_ip);
+    }
+
+    pub fn health(&self) -> Health {
+        self.state.lock().unwrap().health
+    }
+
+    /// Pause or resume sending data packets on the path. Returns false if it
+    /// already was.
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.paused.swap(paused, Ordering::Relaxed) != paused
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// RTT of the last keep-alive reply.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().rtt
+    }
+
+    /// Smoothed keep-alive RTT, which one slow reply moves only by an eighth.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().srtt
+    }
+
+    /// How far the RTTs vary around the smoothed RTT.
+    pub fn rttvar(&self) -> Option<Duration> {
+        self.state.lock().unwrap().rttvar
+    }
+
+    /// Whether the health changed at least `flap_transitions` times within
+    /// the flap window, as of the last keep-alive check.
+    pub fn is_flapping(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.transitions.len() >= state.policy.flap_transitions
+    }
+
+    /// Weight in weighted path mode, configured or derived with auto_weights.
+    pub fn weight(&self) -> f64 {
+        self.weight.lock().unwrap().weight()
+    }
+
+    pub fn set_health_policy(&self, policy: HealthPolicy) {
+        self.state.lock().unwrap().policy = policy;
+    }
+
+    pub fn ping_sent(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        // The previous keep-alive is still unanswered
+        if state.last_ping.is_some() && state.awaiting_reply_since.is_some() {
+            state.missed = state.missed.saturating_add(1);
+            state.answered = 0;
+        }
+        state.last_ping = Some(now);
+        if state.awaiting_reply_since.is_none() {
+            state.awaiting_reply_since = Some(now);
+        }
+    }
+
+    /// Record a keep-alive reply. Returns true if this brought the path back
+    /// up, which takes `up_after` keep-alives answered in a row.
+    pub fn reply_received(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(sent) = state.last_ping {
+            let rtt = now.saturating_duration_since(sent);
+            state.rtt = Some(rtt);
+            let (srtt, rttvar) = match (state.srtt, state.rttvar) {
+                (Some(srtt), Some(rttvar)) => {
+                    (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4)
+                },
+                _ => (rtt, rtt / 2)
+            };
+            state.srtt = Some(srtt);
+            state.rttvar = Some(rttvar);
+        }
+        state.awaiting_reply_since = None;
+        state.missed = 0;
+
+        if state.health == Health::Up {
+            return false
+        }
+        if state.answered_ping != state.last_ping {
+            state.answered_ping = state.last_ping;
+            state.answered += 1;
+        }
+        if state.answered < state.policy.up_after {
+            return false
+        }
+        state.transition(Health::Up, now);
+        state.recovered_at = Some(now);
+        true
+    }
+
+    /// Share of redundant traffic to send while ramping up over `window`
+    /// after the path came back up, from 0 right after to 1 once `window`
+    /// has passed. 1 for a path that never went down.
+    pub fn ramp_share(&self, window: Duration, now: Instant) -> f64 {
+        match self.state.lock().unwrap().recovered_at {
+            Some(at) if !window.is_zero() => (now.saturating_duration_since(at).as_secs_f64() / window.as_secs_f64()).min(1.0),
+            _ => 1.0
+        }
+    }
+
+    /// Whether the path sends a copy of the packet with sequence number `seq`
+    /// in redundant mode. With a `send_fraction` below 1 that is one in every
+    /// 1 / `send_fraction` packets, spread evenly over the sequence numbers,
+    /// so the paths' choices don't depend on what else they were sent. The
+    /// sequence numbers are offset by the path's id, so paths with the same
+    /// fraction take turns instead of all copying the same packets.
+    pub fn sends_seq(&self, seq: usize) -> bool {
+        let seq = seq.wrapping_add(self.id as usize) as f64;
+        self.send_fraction >= 1.0 || ((seq + 1.0) * self.send_fraction).floor() > (seq * self.send_fraction).floor()
+    }
+
+    /// Mark the path down if a keep-alive has gone unanswered for longer than
+    /// `timeout` and `down_after` of them in a row have. Returns true if this
+    /// took the path down.
+    pub fn check_timeout(&self, now: Instant, timeout: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.forget_transitions(now);
+        match state.awaiting_reply_since {
+            Some(since) if state.health == Health::Up
+                // Counting the one timing out now
+                && state.missed + 1 >= state.policy.down_after
+                && now.saturating_duration_since(since) > timeout => {
+                state.transition(Health::Down, now);
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+/// The path failover mode should send on: the highest priority path that
+/// is up. If every path is down, the highest priority path is used anyway.
+pub fn active_path(paths: &[Arc<Path>]) -> Option<&Arc<Path>> {
+    fn by_priority(candidates: Vec<(usize, &Arc<Path>)>) -> Option<&Arc<Path>> {
+        candidates.into_iter()
+            .min_by_key(|(idx, path)| (path.priority, *idx))
+            .map(|(_, path)| path)
+    }
+
+    let up: Vec<_> = paths.iter().enumerate()
+        .filter(|(_, path)| path.health() == Health::Up)
+        .collect();
+
+    if up.is_empty() {
+        by_priority(paths.iter().enumerate().collect())
+    } else {
+        by_priority(up)
+    }
+}
+
+/// The path flow hash mode pins `flow` to, picked by hashing the 5-tuple
+/// over the paths that are up. The choice only moves when the set of up
+/// paths changes. If every path is down, all of them are hashed over.
+pub fn flow_path<'a>(paths: &'a [Arc<Path>], flow: &FlowKey) -> Option<&'a Arc<Path>> {
+    let up: Vec<_> = paths.iter().filter(|path| path.health() == Health::Up).collect();
+    let candidates: Vec<_> = if up.is_empty() { paths.iter().collect() } else { up };
+    if candidates.is_empty() {
+        return None
+    }
+
+    let mut hasher = DefaultHasher::new();
+    flow.hash(&mut hasher);
+    Some(candidates[(hasher.finish() % candidates.len() as u64) as usize])
+}
+
+/// The path weighted mode sends the next packet on, spreading packets over
+/// the paths that are up in proportion to their weights. If every path is
+/// down, all of them share the packets.
+pub fn weighted_path(paths: &[Arc<Path>]) -> Option<&Arc<Path>> {
+    let up: Vec<_> = paths.iter().filter(|path| path.health() == Health::Up).collect();
+    let candidates: Vec<_> = if up.is_empty() { paths.iter().collect() } else { up };
+
+    let mut guards: Vec<_> = candidates.iter().map(|path| path.weight.lock().unwrap()).collect();
+    let mut weights: Vec<&mut PathWeight> = guards.iter_mut().map(|guard| &mut **guard).collect();
+    weights::pick(&mut weights).map(|chosen| candidates[chosen])
+}
+
+/// Move each path's weight toward the target its keep-alive RTT and loss
+/// since the last call earn it under `policy`.
+pub fn adapt_weights(paths: &[Arc<Path>], policy: &WeightPolicy) {
+    let observed: Vec<_> = paths.iter()
+        .map(|path| {
+            let sent = path.counters.keepalives_sent.load(Ordering::Relaxed);
+            let replies = path.counters.keepalive_replies.load(Ordering::Relaxed);
+            (path.srtt(), path.weight.lock().unwrap().observe_loss(sent, replies))
+        })
+        .collect();
+    for (path, target) in paths.iter().zip(weights::targets(&observed, policy)) {
+        path.weight.lock().unwrap().adapt(target, policy);
+    }
+}
+
+/// Put every path back at its configured weight.
+pub fn reset_weights(paths: &[Arc<Path>]) {
+    for path in paths {
+        path.weight.lock().unwrap().reset();
+    }
+}
+
+/// The paths data packets may go out on, picked from by the path modes:
+/// those not paused, and only those named in `names` if given.
+pub fn sendable<'a>(paths: &'a [Arc<Path>], names: Option<&[String]>) -> Cow<'a, [Arc<Path>]> {
+    if names.is_none() && !paths.iter().any(|path| path.is_paused()) {
+        return Cow::Borrowed(paths)
+    }
+    Cow::Owned(paths.iter()
+        .filter(|path| !path.is_paused() && names.is_none_or(|names| names.contains(&path.iface)))
+        .cloned()
+        .collect())
+}
+
+/// Whether a path other than `path` is up.
+pub fn has_other_up(paths: &[Arc<Path>], path: &Arc<Path>) -> bool {
+    paths.iter().any(|other| !Arc::ptr_eq(other, path) && other.health() == Health::Up)
+}
+
+/// Whether `path` is among the `count` paths a limited redundant mode sends
+/// on: up paths before down ones, then the lowest keep-alive RTT first,
+/// paths without one last, then by priority. The smoothed RTT is used, so
+/// one slow reply doesn't reorder the paths.
+pub fn is_among_best(paths: &[Arc<Path>], path: &Arc<Path>, count: usize) -> bool {
+    if paths.len() <= count {
+        return true
+    }
+
+    let mut ranked: Vec<_> = paths.iter().enumerate()
+        .map(|(idx, candidate)| {
+            let rtt = candidate.srtt();
+            ((candidate.health() != Health::Up, rtt.is_none(), rtt, candidate.priority, idx), candidate)
+        })
+        .collect();
+    ranked.sort_unstable_by_key(|(rank, _)| *rank);
+    ranked.iter().take(count).any(|(_, candidate)| Arc::ptr_eq(candidate, path))
+}
+
+/// Like `active_path`, but skips up paths whose rate limit can't take a
+/// `len` byte datagram right now, so traffic spills over to the next link.
+pub fn active_path_with_budget(paths: &[Arc<Path>], len: usize, now: Instant) -> Option<&Arc<Path>> {
+    let with_budget: Vec<_> = paths.iter()
+        .filter(|path| path.health() == Health::Up && path.has_budget(len, now))
+        .cloned()
+        .collect();
+
+    match active_path(&with_budget) {
+        Some(chosen) => paths.iter().find(|path| Arc::ptr_eq(path, chosen)),
+        None => active_path(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    fn path(iface: &str, priority: u8) -> Arc<Path> {
+        Arc::new(Path::new(iface.to_string(), "127.0.0.1:0".parse().unwrap(), priority, 1.0, 1.0, None, None))
+    }
+
+    // A path with `id` that sends `send_fraction` of the packets in redundant mode
+    fn fractional(id: u16, send_fraction: f64) -> Path {
+        let mut path = Path::new(format!("link{}", id), "127.0.0.1:0".parse().unwrap(), 0, 1.0, send_fraction, None, None);
+        path.id = id;
+        path
+    }
+
+    fn fail(path: &Path, at: Instant) {
+        path.ping_sent(at);
+        assert!(path.check_timeout(at + TIMEOUT + Duration::from_millis(1), TIMEOUT));
+    }
+
+    #[test]
+    fn a_send_fraction_picks_evenly_spread_packets() {
+        let quarter = fractional(0, 0.25);
+        let picked: Vec<usize> = (0..40).filter(|seq| quarter.sends_seq(*seq)).collect();
+        assert_eq!(picked.len(), 10);
+        assert!(picked.windows(2).all(|pair| pair[1] - pair[0] == 4), "{:?}", picked);
+        assert!((0..40).all(|seq| fractional(0, 1.0).sends_seq(seq)));
+    }
+
+    #[test]
+    fn paths_with_the_same_fraction_take_turns() {
+        let halves = [fractional(6, 0.5), fractional(7, 0.5)];
+        for seq in 0..20 {
+            assert_eq!(halves.iter().filter(|path| path.sends_seq(seq)).count(), 1, "seq {}", seq);
+        }
+
+        let quarters: Vec<Path> = (0..4).map(|id| fractional(id, 0.25)).collect();
+        for seq in 0..40 {
+            assert_eq!(quarters.iter().filter(|path| path.sends_seq(seq)).count(), 1, "seq {}", seq);
+        }
+    }
+
+    #[test]
+    fn failover_moves_to_the_backup_when_the_primary_goes_down() {
+        let (fiber, lte) = (path("fiber", 0), path("lte", 1));
+        let paths = vec![lte.clone(), fiber.clone()];
+        let start = Instant::now();
+        assert!(Arc::ptr_eq(active_path(&paths).unwrap(), &fiber));
+
+        fail(&fiber, start);
+        assert_eq!(fiber.health(), Health::Down);
+        assert!(Arc::ptr_eq(active_path(&paths).unwrap(), &lte));
+    }
+
+    #[test]
+    fn failover_returns_to_the_primary_once_it_recovers() {
+        let (fiber, lte) = (path("fiber", 0), path("lte", 1));
+        let paths = vec![fiber.clone(), lte.clone()];
+        let start = Instant::now();
+        fail(&fiber, start);
+
+        let later = start + Duration::from_secs(10);
+        fiber.ping_sent(later);
+        assert!(fiber.reply_received(later + Duration::from_millis(20)));
+        assert_eq!(fiber.health(), Health::Up);
+        assert!(Arc::ptr_eq(active_path(&paths).unwrap(), &fiber));
+    }
+
+    #[test]
+    fn answered_keep_alives_keep_the_primary_up() {
+        let fiber = path("fiber", 0);
+        let start = Instant::now();
+        for round in 0..5 {
+            let sent = start + TIMEOUT * round;
+            fiber.ping_sent(sent);
+            assert!(!fiber.reply_received(sent + Duration::from_millis(20)));
+            assert!(!fiber.check_timeout(sent + TIMEOUT * 2, TIMEOUT));
+        }
+        assert_eq!(fiber.health(), Health::Up);
+    }
+
+    // `paths` that `is_among_best` picks for `count`
+    fn best(paths: &[Arc<Path>], count: usize) -> Vec<&str> {
+        paths.iter().filter(|path| is_among_best(paths, path, count)).map(|path| path.iface.as_str()).collect()
+    }
+
+    fn answered_in(path: &Path, rtt: Duration, at: Instant) {
+        path.ping_sent(at);
+        path.reply_received(at + rtt);
+    }
+
+    #[test]
+    fn limited_redundancy_picks_the_lowest_rtt_paths_that_are_up() {
+        let paths = vec![path("dsl", 0), path("fiber", 1), path("lte", 2), path("sat", 3)];
+        let start = Instant::now();
+        answered_in(&paths[0], Duration::from_millis(30), start);
+        answered_in(&paths[1], Duration::from_millis(5), start);
+        answered_in(&paths[2], Duration::from_millis(50), start);
+        answered_in(&paths[3], Duration::from_millis(600), start);
+        assert_eq!(best(&paths, 2), ["dsl", "fiber"]);
+
+        fail(&paths[1], start + Duration::from_secs(1));
+        assert_eq!(best(&paths, 2), ["dsl", "lte"]);
+    }
+
+    #[test]
+    fn without_rtts_limited_redundancy_goes_by_priority() {
+        let paths = vec![path("sat", 3), path("fiber", 0), path("lte", 1), path("dsl", 2)];
+        assert_eq!(best(&paths, 2), ["fiber", "lte"]);
+        answered_in(&paths[0], Duration::from_millis(600), Instant::now());
+        // A measured path comes before the unmeasured ones
+        assert_eq!(best(&paths, 2), ["sat", "fiber"]);
+    }
+
+    #[test]
+    fn redundancy_of_at_least_the_path_count_uses_every_path() {
+        let paths = vec![path("fiber", 0), path("lte", 1)];
+        fail(&paths[0], Instant::now());
+        assert_eq!(best(&paths, 2), ["fiber", "lte"]);
+        assert_eq!(best(&paths, 5), ["fiber", "lte"]);
+    }
+
+    #[test]
+    fn a_recovered_path_ramps_its_share_up_over_the_window() {
+        let (fiber, lte) = (path("fiber", 0), path("lte", 1));
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        assert_eq!(fiber.ramp_share(window, start), 1.0);
+
+        fail(&fiber, start);
+        // Answered until the policy takes it back up
+        let recovered = start + Duration::from_secs(10);
+        let up = (0..10).map(|answered| recovered + Duration::from_millis(answered * 100))
+            .find(|&sent| {
+                fiber.ping_sent(sent);
+                fiber.reply_received(sent)
+            })
+            .unwrap();
+        assert_eq!(fiber.ramp_share(window, up), 0.0);
+        assert_eq!(fiber.ramp_share(window, up + window / 4), 0.25);
+        assert_eq!(fiber.ramp_share(window, up + window), 1.0);
+        assert_eq!(fiber.ramp_share(window, up + window * 3), 1.0);
+        assert_eq!(fiber.ramp_share(Duration::ZERO, up), 1.0);
+        assert_eq!(lte.ramp_share(window, up), 1.0);
+    }
+
+    #[test]
+    fn has_other_up_ignores_the_path_itself() {
+        let (fiber, lte) = (path("fiber", 0), path("lte", 1));
+        let paths = vec![fiber.clone(), lte.clone()];
+        assert!(has_other_up(&paths, &fiber));
+        fail(&lte, Instant::now());
+        assert!(!has_other_up(&paths, &fiber));
+        assert!(has_other_up(&paths, &lte));
+    }
+
+    #[test]
+    fn with_every_path_down_the_highest_priority_one_is_used() {
+        let (fiber, lte) = (path("fiber", 0), path("lte", 1));
+        let start = Instant::now();
+        fail(&fiber, start);
+        fail(&lte, start);
+        assert!(Arc::ptr_eq(active_path(&[lte, fiber.clone()]).unwrap(), &fiber));
+    }
+
+    fn flow(source_port: u16) -> FlowKey {
+        FlowKey {
+            source: [10, 0, 0, 1].into(),
+            destination: [10, 0, 0, 2].into(),
+            protocol: 6,
+            source_port,
+            destination_port: 443
+        }
+    }
+
+    #[test]
+    fn a_flow_always_hashes_to_the_same_path() {
+        let paths = vec![path("fiber", 0), path("lte", 1)];
+        for port in 1000..1100 {
+            let first = flow_path(&paths, &flow(port)).unwrap();
+            for _ in 0..10 {
+                assert!(Arc::ptr_eq(flow_path(&paths, &flow(port)).unwrap(), first));
+            }
+        }
+    }
+
+    #[test]
+    fn different_flows_spread_over_the_paths() {
+        let paths = vec![path("fiber", 0), path("lte", 1)];
+        let on_fiber = (1000..1100)
+            .filter(|port| Arc::ptr_eq(flow_path(&paths, &flow(*port)).unwrap(), &paths[0]))
+            .count();
+        assert!((25..=75).contains(&on_fiber), "{} of 100 flows on fiber", on_fiber);
+    }
+
+    #[test]
+    fn flows_are_only_hashed_over_paths_that_are_up() {
+        let (fiber, lte) = (path("fiber", 0), path("lte", 1));
+        let paths = vec![fiber.clone(), lte.clone()];
+        fail(&fiber, Instant::now());
+        for port in 1000..1100 {
+            assert!(Arc::ptr_eq(flow_path(&paths, &flow(port)).unwrap(), &lte));
+        }
+
+        fail(&lte, Instant::now());
+        assert!(flow_path(&paths, &flow(1000)).is_some());
+        assert!(flow_path(&[], &flow(1000)).is_none());
+    }
+
+    #[test]
+    fn failover_spills_over_to_the_next_link_with_budget() {
+        let lte = Arc::new(Path::new("lte".to_string(), "127.0.0.1:0".parse().unwrap(), 0, 1.0, 1.0, Some(8 * 65535), None));
+        let wifi = path("wifi", 1);
+        let paths = vec![lte.clone(), wifi.clone()];
+        let now = Instant::now();
+
+        assert!(Arc::ptr_eq(active_path_with_budget(&paths, 60000, now).unwrap(), &lte));
+        assert!(lte.consume_budget(60000, now));
+        assert!(Arc::ptr_eq(active_path_with_budget(&paths, 60000, now).unwrap(), &wifi));
+        // The budget refills with time
+        assert!(Arc::ptr_eq(active_path_with_budget(&paths, 60000, now + Duration::from_secs(1)).unwrap(), &lte));
+    }
+
+    #[test]
+    fn without_any_budget_failover_keeps_the_active_path() {
+        let lte = Arc::new(Path::new("lte".to_string(), "127.0.0.1:0".parse().unwrap(), 0, 1.0, 1.0, Some(8 * 65535), None));
+        let now = Instant::now();
+        assert!(lte.consume_budget(65535, now));
+        assert!(!lte.has_budget(1, now));
+        assert!(Arc::ptr_eq(active_path_with_budget(std::slice::from_ref(&lte), 1, now).unwrap(), &lte));
+    }
+
+    #[test]
+    fn one_slow_reply_moves_the_smoothed_rtt_by_an_eighth() {
+        let fiber = path("fiber", 0);
+        let start = Instant::now();
+        for round in 0..4 {
+            answered_in(&fiber, Duration::from_millis(20), start + TIMEOUT * round);
+        }
+        assert_eq!(fiber.srtt(), Some(Duration::from_millis(20)));
+
+        answered_in(&fiber, Duration::from_millis(500), start + TIMEOUT * 4);
+        assert_eq!(fiber.rtt(), Some(Duration::from_millis(500)));
+        assert_eq!(fiber.srtt(), Some(Duration::from_millis(20) * 7 / 8 + Duration::from_millis(500) / 8));
+        assert!(fiber.rttvar().unwrap() > Duration::from_millis(100));
+    }
+
+    // Keep-alive rounds far enough apart for each to time out, every other
+    // one unanswered. The health after each round.
+    fn intermittent(path: &Path, start: Instant, rounds: u32) -> Vec<Health> {
+        (0..rounds).map(|round| {
+            let sent = start + TIMEOUT * 2 * round;
+            path.ping_sent(sent);
+            if round % 2 == 1 {
+                path.reply_received(sent + Duration::from_millis(20));
+            }
+            path.check_timeout(sent + TIMEOUT + Duration::from_millis(1), TIMEOUT);
+            path.health()
+        }).collect()
+    }
+
+    // A round of `sent` keep-alives, `answered` of them replied to in `rtt`
+    fn keep_alive_round(path: &Path, at: Instant, rtt: Duration, sent: u64, answered: u64) {
+        answered_in(path, rtt, at);
+        path.counters.keepalives_sent.fetch_add(sent, Ordering::Relaxed);
+        path.counters.keepalive_replies.fetch_add(answered, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn a_lossy_path_loses_weight_to_a_clean_one() {
+        let (clean, lossy) = (path("fiber", 0), path("lte", 1));
+        let paths = vec![clean.clone(), lossy.clone()];
+        let policy = WeightPolicy { adapt_rate: 0.3, min_weight: 0.05, max_weight: 1.0 };
+        let start = Instant::now();
+        let mut weights = Vec::new();
+        for round in 0..10 {
+            let at = start + TIMEOUT * round;
+            keep_alive_round(&clean, at, Duration::from_millis(20), 5, 5);
+            keep_alive_round(&lossy, at, Duration::from_millis(20), 5, 3);
+            adapt_weights(&paths, &policy);
+            weights.push(lossy.weight());
+        }
+        assert_eq!(clean.weight(), 1.0);
+        // Down toward the 0.6 its replies earn it, a little further each round
+        assert!(weights.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", weights);
+        assert!((lossy.weight() - 0.6).abs() < 0.02, "{}", lossy.weight());
+
+        // Its share of the packets follows
+        let picks = (0..1000).filter(|_| Arc::ptr_eq(weighted_path(&paths).unwrap(), &lossy)).count();
+        assert!((picks as f64 / 1000.0 - lossy.weight() / (1.0 + lossy.weight())).abs() < 0.01, "{} picks", picks);
+
+        reset_weights(&paths);
+        assert_eq!(lossy.weight(), 1.0);
+    }
+
+    const FLAPPING: HealthPolicy = HealthPolicy { down_after: 1, up_after: 1, flap_transitions: 4, flap_window: Duration::from_secs(60) };
+
+    #[test]
+    fn an_intermittent_path_flips_with_every_round_and_is_flapping() {
+        let lte = path("lte", 0);
+        lte.set_health_policy(FLAPPING);
+        let start = Instant::now();
+        let healths = intermittent(&lte, start, 6);
+        assert_eq!(healths, [Health::Down, Health::Up, Health::Down, Health::Up, Health::Down, Health::Up]);
+        assert!(lte.is_flapping());
+
+        // Once it settles, the changes age out of the window
+        let settled = start + TIMEOUT * 12;
+        for round in 0..30 {
+            answered_in(&lte, Duration::from_millis(20), settled + TIMEOUT * round);
+            lte.check_timeout(settled + TIMEOUT * round + Duration::from_millis(30), TIMEOUT);
+        }
+        assert_eq!(lte.health(), Health::Up);
+        assert!(!lte.is_flapping());
+    }
+
+    #[test]
+    fn with_hysteresis_an_intermittent_path_stays_up() {
+        let lte = path("lte", 0);
+        lte.set_health_policy(HealthPolicy { down_after: 3, up_after: 2, ..FLAPPING });
+        let healths = intermittent(&lte, Instant::now(), 10);
+        assert!(healths.iter().all(|health| *health == Health::Up), "{:?}", healths);
+        assert!(!lte.is_flapping());
+    }
+
+    #[test]
+    fn a_down_path_needs_up_after_replies_in_a_row() {
+        let lte = path("lte", 0);
+        lte.set_health_policy(HealthPolicy { down_after: 2, up_after: 3, ..FLAPPING });
+        let start = Instant::now();
+        // The first miss isn't enough, the second is
+        lte.ping_sent(start);
+        assert!(!lte.check_timeout(start + TIMEOUT + Duration::from_millis(1), TIMEOUT));
+        lte.ping_sent(start + TIMEOUT * 2);
+        assert!(lte.check_timeout(start + TIMEOUT * 3, TIMEOUT));
+        assert_eq!(lte.health(), Health::Down);
+
+        let recovering = start + TIMEOUT * 4;
+        let brought_up: Vec<bool> = (0..3).map(|round| {
+            let sent = recovering + TIMEOUT * round;
+            lte.ping_sent(sent);
+            // Several replies to one keep-alive count once
+            lte.reply_received(sent + Duration::from_millis(10)) | lte.reply_received(sent + Duration::from_millis(20))
+        }).collect();
+        assert_eq!(brought_up, [false, false, true]);
+        assert_eq!(lte.health(), Health::Up);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+use crate::clock::Clock;
+use crate::path::Path;
+use crate::tasks::TunPacket;
+
+// Packets queued per send path before the oldest are dropped
+pub const PATH_QUEUE_CAPACITY: usize = 200;
+
+/// Packets from the dispatcher waiting for one path's send task. When full
+/// the oldest packet makes room, counted on the path, so a slow link loses
+/// its own backlog while the others keep up.
+#[derive(Debug)]
+pub struct PathQueue {
+    capacity: usize,
+    path: Arc<Path>,
+    packets: Mutex<VecDeque<TunPacket>>,
+    notify: Notify,
+    closed: AtomicBool
+}
+
+impl PathQueue {
+    pub fn new(capacity: usize, path: Arc<Path>) -> PathQueue {
+        PathQueue {
+            capacity,
+            path,
+            packets: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false)
+        }
+    }
+
+    /// Queue `packet`, dropping the oldest one if the queue is full.
+    pub fn push(&self, packet: TunPacket) {
+        {
+            let mut packets = self.packets.lock().unwrap();
+            if packets.len() >= self.capacity.max(1) {
+                packets.pop_front();
+                self.path.counters.tx_queue_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            packets.push_back(packet);
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Let `pop` return `None` once everything queued has been taken.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    /// The oldest queued packet. `None` once closed and empty.
+    pub async fn pop(&self) -> Option<TunPacket> {
+        loop {
+            if let Some(packet) = self.try_pop() {
+                return Some(packet)
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// The oldest queued packet, without waiting for one.
+    pub fn try_pop(&self) -> Option<TunPacket> {
+        self.packets.lock().unwrap().pop_front()
+    }
+}
+
+/// Backup copies a send task holds in fastest mode until the fastest path
+/// has had its head start, so the packets queued behind them aren't held
+/// up. All are held for the same stagger, so they come due in the order
+/// they were held. When full the oldest is dropped, as in a `PathQueue`.
+#[derive(Debug)]
+pub struct BackupQueue {
+    capacity: usize,
+    path: Arc<Path>,
+    backups: VecDeque<(Instant, TunPacket)>
+}
+
+impl BackupQueue {
+    pub fn new(capacity: usize, path: Arc<Path>) -> BackupQueue {
+        BackupQueue { capacity, path, backups: VecDeque::new() }
+    }
+
+    /// Hold `packet` until `due`.
+    pub fn push(&mut self, due: Instant, packet: TunPacket) {
+        if self.backups.len() >= self.capacity.max(1) {
+            self.backups.pop_front();
+            self.path.counters.tx_queue_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.backups.push_back((due, packet));
+    }
+
+    /// The oldest held packet, if it is due at `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<TunPacket> {
+        match self.backups.front() {
+            Some((due, _)) if *due <= now => self.backups.pop_front().map(|(_, packet)| packet),
+            _ => None
+        }
+    }
+
+    /// Resolves once the oldest held packet is due by `clock`. Never while none are held.
+    pub async fn due(&self, clock: &dyn Clock) {
+        match self.backups.front() {
+            Some((due, _)) => clock.sleep_until(*due).await,
+            None => std::future::pending().await
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backups.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use bytes::Bytes;
+    use crate::clock::MockClock;
+    use crate::dispatch::Route;
+    use crate::messages::Packet;
+
+    fn link() -> Arc<Path> {
+        Arc::new(Path::new("link0".to_string(), "127.0.0.1:0".parse().unwrap(), 0, 1.0, 1.0, None, None))
+    }
+
+    fn packet(seq: usize) -> TunPacket {
+        TunPacket {
+            packet: Packet { seq, bytes: Bytes::from_static(b"payload") },
+            read_at: Instant::now(),
+            route: Route { destination: None, peer: None, inner_tos: None, flow: None, session_epoch: None },
+            backup_at: None
+        }
+    }
+
+    fn seqs(queue: &PathQueue) -> Vec<usize> {
+        std::iter::from_fn(|| queue.try_pop()).map(|packet| packet.packet.seq).collect()
+    }
+
+    #[test]
+    fn a_full_queue_drops_its_oldest_packets_on_its_own_path() {
+        let (slow, fast) = (link(), link());
+        let (congested, keeping_up) = (PathQueue::new(3, slow.clone()), PathQueue::new(3, fast.clone()));
+        for seq in 0..5 {
+            congested.push(packet(seq));
+            keeping_up.push(packet(seq));
+            assert_eq!(keeping_up.try_pop().unwrap().packet.seq, seq);
+        }
+        assert_eq!(seqs(&congested), [2, 3, 4]);
+        assert_eq!(slow.counters.tx_queue_dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(fast.counters.tx_queue_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_a_packet_and_ends_once_closed_and_empty() {
+        let queue = Arc::new(PathQueue::new(8, link()));
+        let popping = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await.map(|packet| packet.packet.seq) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        queue.push(packet(7));
+        assert_eq!(popping.await.unwrap(), Some(7));
+
+        queue.push(packet(8));
+        queue.close();
+        assert_eq!(queue.pop().await.map(|packet| packet.packet.seq), Some(8));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn backups_come_due_in_order_and_the_oldest_make_room() {
+        let path = link();
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut backups = BackupQueue::new(2, path.clone());
+        assert!(backups.is_empty());
+        for seq in 0..3 {
+            backups.push(start + Duration::from_millis(10 * (seq as u64 + 1)), packet(seq));
+        }
+        assert_eq!(path.counters.tx_queue_dropped.load(Ordering::Relaxed), 1);
+
+        assert!(backups.pop_due(start + Duration::from_millis(15)).is_none());
+        clock.advance(Duration::from_millis(20));
+        backups.due(&clock).await;
+        assert_eq!(backups.pop_due(clock.now()).unwrap().packet.seq, 1);
+        assert!(backups.pop_due(clock.now()).is_none());
+        assert_eq!(backups.pop_due(start + Duration::from_millis(30)).unwrap().packet.seq, 2);
+        assert!(backups.is_empty());
+    }
+}
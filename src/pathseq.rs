@@ -0,0 +1,125 @@
+// Per-path sequence numbers, stamped on data packets next to the global one
+// when path_sequence is on. The global number is shared by the copies sent
+// over every path and deduplicated on, so a gap in it says nothing about
+// one path. Each path numbering its own packets to each address of each
+// peer lets the peer count what was lost between that path and each of its own.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use serde::Serialize;
+
+static NEXT_PATH_ID: AtomicU16 = AtomicU16::new(0);
+
+/// An id for a new path, unique among the paths of this process.
+pub fn next_path_id() -> u16 {
+    NEXT_PATH_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Next per-path sequence number for each peer address a path sends to.
+#[derive(Debug, Default)]
+pub struct PathSeqs {
+    next: HashMap<(IpAddr, SocketAddr), u64>
+}
+
+impl PathSeqs {
+    pub fn next(&mut self, peer: IpAddr, target: SocketAddr) -> u64 {
+        let next = self.next.entry((peer, target)).or_default();
+        let seq = *next;
+        *next += 1;
+        seq
+    }
+
+    pub fn forget(&mut self, peer: &IpAddr) {
+        self.next.retain(|(numbered, _), _| numbered != peer);
+    }
+}
+
+/// Packets received over one of a peer's paths on one of ours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LinkLoss {
+    pub tun_ip: IpAddr,
+    // The peer's id for its path
+    pub path_id: u16,
+    pub received: u64,
+    // Sequence numbers skipped and not received since, late ones taken back
+    pub lost: u64
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    highest: u64,
+    received: u64,
+    lost: u64
+}
+
+/// Loss on each of the peers' paths to one of ours, from the gaps in their
+/// per-path sequence numbers.
+#[derive(Debug, Default)]
+pub struct LinkLosses {
+    links: HashMap<(IpAddr, u16), Link>
+}
+
+impl LinkLosses {
+    pub fn record(&mut self, tun_ip: IpAddr, path_id: u16, path_seq: u64) {
+        let link = match self.links.get_mut(&(tun_ip, path_id)) {
+            // Numbering from 0 again means the peer restarted
+            Some(link) if path_seq > 0 || link.highest == 0 => link,
+            _ => {
+                self.links.insert((tun_ip, path_id), Link { highest: path_seq, received: 1, lost: 0 });
+                return
+            }
+        };
+
+        link.received += 1;
+        if path_seq > link.highest {
+            link.lost += path_seq - link.highest - 1;
+            link.highest = path_seq;
+        } else {
+            // Reordered, and counted as lost when the later one arrived
+            link.lost = link.lost.saturating_sub(1);
+        }
+    }
+
+    pub fn forget(&mut self, tun_ip: &IpAddr) {
+        self.links.retain(|(peer, _), _| peer != tun_ip);
+    }
+
+    /// Every link heard from, ordered by peer and path id.
+    pub fn snapshot(&self) -> Vec<LinkLoss> {
+        let mut links: Vec<LinkLoss> = self.links.iter()
+            .map(|((tun_ip, path_id), link)| LinkLoss { tun_ip: *tun_ip, path_id: *path_id, received: link.received, lost: link.lost })
+            .collect();
+        links.sort_by_key(|link| (link.tun_ip, link.path_id));
+        links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_peer_address_is_numbered_from_zero() {
+        let (mut seqs, a, b): (PathSeqs, IpAddr, IpAddr) = (PathSeqs::default(), [10, 0, 0, 2].into(), [10, 0, 0, 3].into());
+        let (first, second): (SocketAddr, SocketAddr) = ("192.0.2.1:4000".parse().unwrap(), "192.0.2.2:4000".parse().unwrap());
+        assert_eq!([seqs.next(a, first), seqs.next(a, first), seqs.next(b, first), seqs.next(a, second), seqs.next(a, first)], [0, 1, 0, 0, 2]);
+        seqs.forget(&a);
+        assert_eq!([seqs.next(a, first), seqs.next(a, second), seqs.next(b, first)], [0, 0, 1]);
+    }
+
+    #[test]
+    fn numbering_from_zero_again_starts_the_link_over() {
+        let (mut losses, peer): (LinkLosses, IpAddr) = (LinkLosses::default(), [10, 0, 0, 2].into());
+        for path_seq in [0, 1, 4] {
+            losses.record(peer, 1, path_seq);
+        }
+        assert_eq!(losses.snapshot(), vec![LinkLoss { tun_ip: peer, path_id: 1, received: 3, lost: 2 }]);
+        for path_seq in [0, 1] {
+            losses.record(peer, 1, path_seq);
+        }
+        assert_eq!(losses.snapshot(), vec![LinkLoss { tun_ip: peer, path_id: 1, received: 2, lost: 0 }]);
+        losses.forget(&peer);
+        assert!(losses.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+
+use crate::dispatch::Dispatcher;
+use crate::messages::Packet;
+
+// Peers packets are held for at once. Packets for further unknown peers are dropped.
+const MAX_PENDING_PEERS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingConfig {
+    pub max_packets: usize,
+    pub max_age: Duration
+}
+
+/// Packets read from the TUN for peers not discovered yet, held until they
+/// are instead of being dropped, so the first packets of a connection get
+/// through. Up to `max_packets` are held per peer, the oldest making room
+/// for newer ones, and none for longer than `max_age`. Released packets
+/// are handed to the send tasks as if just read from the TUN.
+#[derive(Debug)]
+pub struct PendingPackets {
+    dispatcher: Arc<Dispatcher>,
+    config: PendingConfig,
+    // With the time each packet was held
+    held: Mutex<HashMap<IpAddr, VecDeque<(Instant, Packet)>>>
+}
+
+impl PendingPackets {
+    pub fn new(dispatcher: Arc<Dispatcher>, config: PendingConfig) -> PendingPackets {
+        PendingPackets {
+            dispatcher,
+            config: PendingConfig { max_packets: config.max_packets.max(1), ..config },
+            held: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Hold `packet` until `tun_ip` is discovered. Returns false, dropping
+    /// it, if packets are already held for too many other peers.
+    pub fn hold(&self, tun_ip: IpAddr, packet: Packet, now: Instant) -> bool {
+        let mut held = self.held.lock().unwrap();
+        expire(&mut held, self.config.max_age, now);
+        if !held.contains_key(&tun_ip) && held.len() >= MAX_PENDING_PEERS {
+            return false
+        }
+
+        let queue = held.entry(tun_ip).or_default();
+        if queue.len() >= self.config.max_packets {
+            queue.pop_front();
+        }
+        queue.push_back((now, packet));
+        true
+    }
+
+    /// Send the packets held for `tun_ip`, which was just discovered, that
+    /// haven't expired. Returns how many were sent.
+    pub fn release(&self, tun_ip: &IpAddr, now: Instant) -> usize {
+        let queue = match self.held.lock().unwrap().remove(tun_ip) {
+            Some(queue) => queue,
+            None => return 0
+        };
+
+        let mut released = 0;
+        for (_, packet) in queue.into_iter().filter(|(held_at, _)| now.saturating_duration_since(*held_at) <= self.config.max_age) {
+            // Dropped, and not counted, if the dispatcher can't route it
+            self.dispatcher.dispatch(packet, now);
+            released += 1;
+        }
+        released
+    }
+}
+
+fn expire(held: &mut HashMap<IpAddr, VecDeque<(Instant, Packet)>>, max_age: Duration, now: Instant) {
+    held.retain(|_, queue| {
+        while queue.front().is_some_and(|(held_at, _)| now.saturating_duration_since(*held_at) > max_age) {
+            queue.pop_front();
+        }
+        !queue.is_empty()
+    });
+}
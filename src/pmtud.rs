@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PmtuSearchMode {
+    // Grow the probe size by a fixed number of bytes until a probe is lost
+    Linear(usize),
+    // Bisect between the largest confirmed and the smallest failed size
+    #[default]
+    Binary
+}
+
+/// Path MTU search for a single path. The caller sends a probe of the size
+/// returned by `next_probe` and reports back whether it made it.
+#[derive(Debug)]
+pub struct PmtuSearch {
+    mode: PmtuSearchMode,
+    max: usize,
+    // Largest size known to get through
+    confirmed: usize,
+    // Smallest size known not to get through (exclusive upper bound)
+    ceiling: usize,
+    in_flight: Option<usize>,
+    reprobe_interval: Duration,
+    converged_at: Option<Instant>
+}
+
+impl PmtuSearch {
+    /// Search between `min` (assumed to always work) and `max`. Once converged,
+    /// the search is restarted upward after `reprobe_interval` in case the path improved.
+    pub fn new(mode: PmtuSearchMode, min: usize, max: usize, reprobe_interval: Duration) -> PmtuSearch {
+        PmtuSearch {
+            mode,
+            max,
+            confirmed: min,
+            ceiling: max + 1,
+            in_flight: None,
+            reprobe_interval,
+            converged_at: None
+        }
+    }
+
+    /// The current path MTU estimate.
+    pub fn mtu(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Size of the probe awaiting a result, if any.
+    pub fn in_flight(&self) -> Option<usize> {
+        self.in_flight
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.converged_at.is_some()
+    }
+
+    /// Size of the next probe to send, if any should be sent now.
+    pub fn next_probe(&mut self, now: Instant) -> Option<usize> {
+        if self.in_flight.is_some() {
+            return None
+        }
+
+        if let Some(converged_at) = self.converged_at {
+            if now.saturating_duration_since(converged_at) < self.reprobe_interval {
+                return None
+            }
+            // Re-probe upward, the limit may have been raised
+            self.ceiling = self.max + 1;
+            self.converged_at = None;
+        }
+
+        let candidate = match self.mode {
+            PmtuSearchMode::Linear(step) => (self.confirmed + step.max(1)).min(self.ceiling - 1),
+            PmtuSearchMode::Binary => self.confirmed + (self.ceiling - self.confirmed) / 2
+        };
+
+        if candidate <= self.confirmed {
+            self.converged_at = Some(now);
+            return None
+        }
+
+        self.in_flight = Some(candidate);
+        Some(candidate)
+    }
+
+    pub fn probe_succeeded(&mut self, size: usize, now: Instant) {
+        if self.in_flight == Some(size) {
+            self.in_flight = None;
+        }
+        self.confirmed = self.confirmed.max(size);
+        self.check_converged(now);
+    }
+
+    pub fn probe_failed(&mut self, size: usize, now: Instant) {
+        if self.in_flight == Some(size) {
+            self.in_flight = None;
+        }
+        if size > self.confirmed {
+            self.ceiling = self.ceiling.min(size);
+        }
+        self.check_converged(now);
+    }
+
+    fn check_converged(&mut self, now: Instant) {
+        if self.confirmed + 1 >= self.ceiling {
+            self.converged_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPROBE: Duration = Duration::from_secs(600);
+
+    // Probe until converged against a path that carries up to `limit`
+    // bytes, returning the number of probes sent
+    fn run(search: &mut PmtuSearch, limit: usize, now: Instant) -> usize {
+        let mut probes = 0;
+        while let Some(size) = search.next_probe(now) {
+            probes += 1;
+            if size <= limit {
+                search.probe_succeeded(size, now);
+            } else {
+                search.probe_failed(size, now);
+            }
+            assert!(probes < 1000, "the search doesn't converge");
+        }
+        probes
+    }
+
+    #[test]
+    fn binary_search_converges_on_the_path_mtu() {
+        let now = Instant::now();
+        let mut search = PmtuSearch::new(PmtuSearchMode::Binary, 1200, 9000, REPROBE);
+        let probes = run(&mut search, 1472, now);
+
+        assert!(search.is_converged());
+        assert_eq!(search.mtu(), 1472);
+        // log2(7800) rounds to 13
+        assert!(probes <= 14, "{} probes", probes);
+    }
+
+    #[test]
+    fn linear_search_steps_up_then_closes_in_below_the_lost_probe() {
+        let now = Instant::now();
+        let mut search = PmtuSearch::new(PmtuSearchMode::Linear(100), 1200, 1600, REPROBE);
+        assert_eq!(search.next_probe(now), Some(1300));
+        search.probe_succeeded(1300, now);
+        run(&mut search, 1472, now);
+
+        assert!(search.is_converged());
+        assert_eq!(search.mtu(), 1472);
+    }
+
+    #[test]
+    fn converged_search_waits_for_the_reprobe_interval() {
+        let now = Instant::now();
+        let mut search = PmtuSearch::new(PmtuSearchMode::Binary, 1200, 9000, REPROBE);
+        run(&mut search, 1472, now);
+
+        assert_eq!(search.next_probe(now + REPROBE - Duration::from_secs(1)), None);
+        assert!(search.next_probe(now + REPROBE).is_some());
+    }
+
+    #[test]
+    fn reprobing_finds_a_raised_limit() {
+        let now = Instant::now();
+        let mut search = PmtuSearch::new(PmtuSearchMode::Binary, 1200, 9000, REPROBE);
+        run(&mut search, 1472, now);
+
+        let later = now + REPROBE;
+        run(&mut search, 8000, later);
+        assert!(search.is_converged());
+        assert_eq!(search.mtu(), 8000);
+    }
+
+    #[test]
+    fn lost_probe_counts_as_failed() {
+        let now = Instant::now();
+        let mut search = PmtuSearch::new(PmtuSearchMode::Binary, 1200, 1400, REPROBE);
+        let size = search.next_probe(now).unwrap();
+        assert_eq!(search.in_flight(), Some(size));
+        // Nothing else goes out while a probe is unanswered
+        assert_eq!(search.next_probe(now), None);
+
+        search.probe_failed(size, now);
+        assert!(search.next_probe(now).unwrap() < size);
+        assert_eq!(search.mtu(), 1200);
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Instant;
+use serde::Serialize;
+
+// Weight of the newest sample in the moving average
+const EWMA_WEIGHT: f64 = 0.5;
+
+/// A smoothed packet and bit rate.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct Rate {
+    pub pps: f64,
+    pub bps: f64
+}
+
+/// Turns cumulative packet and byte counters into an exponentially weighted
+/// moving average rate. Fed by sampling the counters on a timer.
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    // Time and counter values of the previous sample
+    last: Option<(Instant, u64, u64)>,
+    rate: Rate
+}
+
+impl RateMeter {
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    pub fn sample(&mut self, now: Instant, packets: u64, bytes: u64) {
+        if let Some((at, last_packets, last_bytes)) = self.last {
+            let elapsed = now.saturating_duration_since(at).as_secs_f64();
+            if elapsed <= 0.0 {
+                return
+            }
+            let pps = packets.saturating_sub(last_packets) as f64 / elapsed;
+            let bps = bytes.saturating_sub(last_bytes) as f64 * 8.0 / elapsed;
+            self.rate = Rate {
+                pps: EWMA_WEIGHT * pps + (1.0 - EWMA_WEIGHT) * self.rate.pps,
+                bps: EWMA_WEIGHT * bps + (1.0 - EWMA_WEIGHT) * self.rate.bps
+            };
+        }
+        self.last = Some((now, packets, bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn constant_rate_is_converged_on() {
+        let (mut meter, start) = (RateMeter::default(), Instant::now());
+        for second in 0..10u64 {
+            meter.sample(start + Duration::from_secs(second), second * 100, second * 100_000);
+        }
+        let rate = meter.rate();
+        assert!((rate.pps - 100.0).abs() < 1.0, "{:?}", rate);
+        assert!((rate.bps - 800_000.0).abs() < 8_000.0, "{:?}", rate);
+    }
+
+    #[test]
+    fn rate_is_per_second_of_elapsed_time() {
+        let (mut meter, start) = (RateMeter::default(), Instant::now());
+        meter.sample(start, 0, 0);
+        assert_eq!(meter.rate(), Rate::default());
+        // 100 packets a second, averaged with the 0 the rate starts from
+        meter.sample(start + Duration::from_secs(2), 200, 1000);
+        assert_eq!(meter.rate(), Rate { pps: 50.0, bps: 2000.0 });
+        // A sample at the same instant changes nothing
+        meter.sample(start + Duration::from_secs(2), 300, 2000);
+        assert_eq!(meter.rate(), Rate { pps: 50.0, bps: 2000.0 });
+    }
+
+    #[test]
+    fn rate_decays_once_traffic_stops() {
+        let (mut meter, start) = (RateMeter::default(), Instant::now());
+        meter.sample(start, 0, 0);
+        meter.sample(start + Duration::from_secs(1), 100, 0);
+        meter.sample(start + Duration::from_secs(2), 100, 0);
+        meter.sample(start + Duration::from_secs(3), 100, 0);
+        assert_eq!(meter.rate().pps, 12.5);
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+// Smallest burst a bucket allows, so a maximum sized datagram can always get through eventually
+const MIN_BURST_BYTES: f64 = 65535.0;
+
+/// Token bucket limiting the bytes sent on a link to `max_bps` bits per
+/// second. Tokens refill with time, up to one second's worth of traffic.
+#[derive(Debug)]
+pub struct TokenBucket {
+    // Refill rate in bytes per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    // Refilled from the first time it is used
+    last_refill: Option<Instant>
+}
+
+impl TokenBucket {
+    /// A full bucket for `max_bps` bits per second.
+    pub fn new(max_bps: u64) -> TokenBucket {
+        let rate = max_bps as f64 / 8.0;
+        let capacity = rate.max(MIN_BURST_BYTES);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: None
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        }
+        self.last_refill = Some(now);
+    }
+
+    /// Whether `len` bytes could be sent now, without consuming any tokens.
+    pub fn has_tokens(&mut self, len: usize, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= len as f64
+    }
+
+    /// Take tokens for `len` bytes. Returns false, taking nothing, if there aren't enough.
+    pub fn try_consume(&mut self, len: usize, now: Instant) -> bool {
+        if !self.has_tokens(len, now) {
+            return false
+        }
+        self.tokens -= len as f64;
+        true
+    }
+}
+
+/// Spaces the datagrams sent on a link evenly at `pacing_bps` bits per
+/// second, so they go out smoothly instead of as fast as they arrive. Each
+/// datagram is released as long after the previous one as the previous one
+/// takes at the rate. An idle link builds up no credit for a burst.
+#[derive(Debug)]
+pub struct Pacer {
+    // Bytes per second
+    rate: f64,
+    // When the link is free again after everything released so far
+    next_release: Option<Instant>
+}
+
+impl Pacer {
+    pub fn new(pacing_bps: u64) -> Pacer {
+        Pacer {
+            rate: pacing_bps.max(1) as f64 / 8.0,
+            next_release: None
+        }
+    }
+
+    /// When `len` bytes may go out, reserving the link for them from then on.
+    pub fn reserve(&mut self, len: usize, now: Instant) -> Instant {
+        let release = self.next_release.map_or(now, |next| next.max(now));
+        self.next_release = Some(release + Duration::from_secs_f64(len as f64 / self.rate));
+        release
+    }
+}
+
+/// Limits a log message to one per `interval`. The messages held back in
+/// between are counted, and the count is handed out with the next message
+/// let through, or by `summary` once the interval is over, so a flood shows
+/// up as one line with a summary per interval.
+#[derive(Debug)]
+pub struct LogThrottle {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed: u64
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> LogThrottle {
+        LogThrottle {
+            interval,
+            last_logged: None,
+            suppressed: 0
+        }
+    }
+
+    /// Whether to log a message now. `Some` with the number of messages
+    /// suppressed since the last one logged, `None` to suppress this one.
+    pub fn allow(&mut self, now: Instant) -> Option<u64> {
+        match self.last_logged {
+            Some(last) if now.saturating_duration_since(last) < self.interval => {
+                self.suppressed += 1;
+                None
+            },
+            _ => {
+                self.last_logged = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+    /// The number of messages suppressed since the last one logged, once
+    /// `interval` has passed since it, to log in its place. `None` if there
+    /// is nothing to sum up yet.
+    pub fn summary(&mut self, now: Instant) -> Option<u64> {
+        match self.last_logged {
+            Some(last) if self.suppressed > 0 && now.saturating_duration_since(last) >= self.interval => {
+                self.last_logged = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            },
+            _ => None
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_starts_full_and_refuses_what_it_does_not_have() {
+        let start = Instant::now();
+        // 100 KB/s, with a second's worth to start with
+        let mut bucket = TokenBucket::new(800_000);
+        assert!(bucket.try_consume(100_000, start));
+        assert!(!bucket.has_tokens(1, start));
+        assert!(!bucket.try_consume(1000, start));
+
+        // Refused datagrams take nothing
+        assert!(bucket.try_consume(1000, start + Duration::from_millis(10)));
+        assert!(!bucket.try_consume(1, start + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn refill_is_capped_at_one_second_of_traffic() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(800_000);
+        assert!(!bucket.try_consume(100_001, start + Duration::from_secs(60)));
+        assert!(bucket.try_consume(100_000, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn low_rates_still_pass_a_maximum_sized_datagram() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(8);
+        assert!(bucket.try_consume(65535, start));
+        assert!(!bucket.try_consume(1, start));
+    }
+
+    #[test]
+    fn pacer_spaces_datagrams_at_the_rate() {
+        let start = Instant::now();
+        // 1000 bytes take 10ms at 100 KB/s
+        let mut pacer = Pacer::new(800_000);
+        assert_eq!(pacer.reserve(1000, start), start);
+        assert_eq!(pacer.reserve(1000, start), start + Duration::from_millis(10));
+        // An idle link builds up no credit
+        let later = start + Duration::from_secs(1);
+        assert_eq!(pacer.reserve(1000, later), later);
+        assert_eq!(pacer.reserve(1000, later), later + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn a_flood_is_logged_once_per_interval() {
+        let start = Instant::now();
+        let mut throttle = LogThrottle::new(Duration::from_secs(10));
+        let logged: Vec<u64> = (0..1000u64)
+            .filter_map(|ms| throttle.allow(start + Duration::from_millis(ms * 25)))
+            .collect();
+        // Once at the start, and once more every 10 s with the count held back since
+        assert_eq!(logged, vec![0, 399, 399]);
+        // Nothing is summed up before the interval is over
+        assert_eq!(throttle.summary(start + Duration::from_secs(21)), None);
+        assert_eq!(throttle.summary(start + Duration::from_secs(30)), Some(199));
+        assert_eq!(throttle.summary(start + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn throughput_over_a_window_stays_under_the_rate() {
+        let start = Instant::now();
+        // 10 KB/s, offered 1 KB every millisecond for 10 seconds
+        let mut bucket = TokenBucket::new(80_000);
+        let sent: usize = (0..10_000u64)
+            .filter(|ms| bucket.try_consume(1000, start + Duration::from_millis(*ms)))
+            .count() * 1000;
+        assert!(sent <= 65535 + 10 * 10_000, "{} bytes", sent);
+        assert!(sent >= 10 * 10_000, "{} bytes", sent);
+    }
+}
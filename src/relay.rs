@@ -0,0 +1,303 @@
+// Send devices whose link only reaches the peers through a UDP relay. Each
+// datagram is wrapped in the SOCKS5 UDP request header (RFC 1928, section 7)
+// naming the peer and sent to the relay, which unwraps and forwards it.
+// Datagrams from peers come back wrapped the same way, with the peer as the
+// address. A SOCKS5 proxy is first asked for its relay address over TCP
+// (UDP ASSOCIATE) and relays for as long as that connection stays open. A
+// plain relay takes the framing without a handshake.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use socket2::{Domain, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::transport::{self, Transport, TransportFuture};
+
+// Longest header, for an IPv6 address: reserved (2), fragment (1), address type (1), address (16), port (2)
+pub const MAX_RELAY_HEADER_LEN: usize = 22;
+
+// How long the SOCKS5 proxy has to answer each step of the handshake
+const SOCKS5_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_UDP_ASSOCIATE: u8 = 3;
+const SOCKS5_SUCCEEDED: u8 = 0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// Datagrams sent and received through a relay on a UDP socket.
+#[derive(Debug)]
+pub struct RelayTransport {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    // A SOCKS5 proxy ends the association when this closes
+    control: Option<TcpStream>
+}
+
+impl RelayTransport {
+    /// Relay through `relay`, a plain relay or the UDP address a SOCKS5
+    /// proxy handed out over `control`.
+    pub fn new(socket: UdpSocket, relay: SocketAddr, control: Option<TcpStream>) -> RelayTransport {
+        RelayTransport { socket, relay, control }
+    }
+}
+
+impl Transport for RelayTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn reregister(&self) -> io::Result<Arc<dyn Transport>> {
+        let control = self.control.as_ref().map(TcpStream::try_clone).transpose()?;
+        Ok(Arc::new(RelayTransport::new(transport::reregister_udp(&self.socket)?, self.relay, control)))
+    }
+
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(async move {
+            let mut datagram = Vec::with_capacity(MAX_RELAY_HEADER_LEN + buf.len());
+            write_header(target, &mut datagram);
+            datagram.extend_from_slice(buf);
+            let sent = self.socket.send_to(&datagram, self.relay).await?;
+            Ok(sent.saturating_sub(datagram.len() - buf.len()))
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            // Room for the header on top of a datagram filling `buf`
+            let mut datagram = vec![0; MAX_RELAY_HEADER_LEN + buf.len()];
+            loop {
+                let (len, source) = self.socket.recv_from(&mut datagram).await?;
+                // Anyone could send to the socket, only the relay speaks for peers
+                if source != self.relay {
+                    continue
+                }
+                match read_header(&datagram[..len]) {
+                    Some((peer, header_len)) => {
+                        // Cut short like a datagram too large for `buf` would be
+                        let copied = (len - header_len).min(buf.len());
+                        buf[..copied].copy_from_slice(&datagram[header_len..header_len + copied]);
+                        return Ok((copied, peer))
+                    },
+                    None => eprintln!("Dropping datagram from relay {} without a usable header", self.relay)
+                }
+            }
+        })
+    }
+}
+
+/// Connect to the SOCKS5 proxy at `proxy` from `source` if it is of the
+/// proxy's family, and over `iface` if set, so the connection takes the
+/// link the device's datagrams take.
+pub fn connect_proxy(proxy: SocketAddr, source: IpAddr, iface: Option<&str>) -> io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(proxy), Type::STREAM, None)?;
+    if let Some(iface) = iface {
+        socket.bind_device(Some(iface.as_bytes()))?;
+    }
+    if source.is_ipv4() == proxy.is_ipv4() && !source.is_unspecified() {
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+    }
+    socket.connect_timeout(&proxy.into(), SOCKS5_TIMEOUT)?;
+    Ok(socket.into())
+}
+
+/// Ask the SOCKS5 proxy at the other end of `control` to relay for the UDP
+/// socket bound to `local`, returning the address to send to. The proxy
+/// has to accept connecting without authentication.
+pub fn socks5_associate(control: &mut TcpStream, local: SocketAddr) -> io::Result<SocketAddr> {
+    control.set_read_timeout(Some(SOCKS5_TIMEOUT))?;
+    control.set_write_timeout(Some(SOCKS5_TIMEOUT))?;
+
+    control.write_all(&[SOCKS5_VERSION, 1, SOCKS5_NO_AUTH])?;
+    let mut choice = [0u8; 2];
+    control.read_exact(&mut choice)?;
+    if choice != [SOCKS5_VERSION, SOCKS5_NO_AUTH] {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the SOCKS5 proxy requires authentication"))
+    }
+
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_UDP_ASSOCIATE, 0];
+    write_addr(local, &mut request);
+    control.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    control.read_exact(&mut reply)?;
+    if reply[0] != SOCKS5_VERSION || reply[1] != SOCKS5_SUCCEEDED {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("the SOCKS5 proxy refused to relay UDP, reply {}", reply[1])))
+    }
+    let ip = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets)?;
+            IpAddr::from(octets)
+        },
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets)?;
+            IpAddr::from(octets)
+        },
+        atyp => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the SOCKS5 proxy answered with address type {}", atyp)))
+    };
+    let mut port = [0u8; 2];
+    control.read_exact(&mut port)?;
+
+    // An unspecified address means the proxy's own
+    let ip = if ip.is_unspecified() { control.peer_addr()?.ip() } else { ip };
+    control.set_read_timeout(None)?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+fn write_header(target: SocketAddr, out: &mut Vec<u8>) {
+    // Reserved, and fragment 0 for a whole datagram
+    out.extend_from_slice(&[0, 0, 0]);
+    write_addr(target, out);
+}
+
+fn write_addr(addr: SocketAddr, out: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+// The peer a relayed datagram is from and the length of its header. None
+// for fragments, which relays rarely send and we don't reassemble, and for
+// domain names, which can't be answered by socket address.
+fn read_header(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    match datagram {
+        [0, 0, 0, ATYP_IPV4, rest @ ..] if rest.len() >= 6 => {
+            let ip = Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]);
+            Some((SocketAddr::new(ip.into(), u16::from_be_bytes([rest[4], rest[5]])), 10))
+        },
+        [0, 0, 0, ATYP_IPV6, rest @ ..] if rest.len() >= 18 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&rest[..16]);
+            Some((SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([rest[16], rest[17]])), MAX_RELAY_HEADER_LEN))
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use super::*;
+
+    #[test]
+    fn headers_name_the_peer_in_either_family() {
+        for peer in ["192.0.2.7:4000", "[2001:db8::7]:4000"] {
+            let peer: SocketAddr = peer.parse().unwrap();
+            let mut datagram = Vec::new();
+            write_header(peer, &mut datagram);
+            let header_len = datagram.len();
+            datagram.extend_from_slice(b"payload");
+            assert_eq!(read_header(&datagram), Some((peer, header_len)));
+        }
+        let mut longest = Vec::new();
+        write_header("[::1]:1".parse().unwrap(), &mut longest);
+        assert_eq!(longest.len(), MAX_RELAY_HEADER_LEN);
+
+        // Fragments, domain names and short headers
+        assert_eq!(read_header(&[0, 0, 1, ATYP_IPV4, 127, 0, 0, 1, 0, 80]), None);
+        assert_eq!(read_header(&[0, 0, 0, 3, 9, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't', 0, 80]), None);
+        assert_eq!(read_header(&[0, 0, 0, ATYP_IPV4, 127, 0, 0]), None);
+    }
+
+    #[tokio::test]
+    async fn datagrams_go_through_the_relay_and_only_its_are_taken() {
+        let relay = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transport = RelayTransport::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), relay.local_addr().unwrap(), None);
+        let peer: SocketAddr = "192.0.2.7:4000".parse().unwrap();
+
+        assert_eq!(transport.send_to(b"payload", peer).await.unwrap(), 7);
+        let mut buf = [0u8; 64];
+        let (len, from) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(from, transport.local_addr().unwrap());
+        assert_eq!(read_header(&buf[..len]), Some((peer, 10)));
+        assert_eq!(&buf[10..len], b"payload");
+
+        // A stranger's datagram is skipped, the relay's is unwrapped
+        let stranger = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.send_to(&buf[..len], from).unwrap();
+        let mut reply = Vec::new();
+        write_header(peer, &mut reply);
+        reply.extend_from_slice(b"reply");
+        relay.send_to(&reply, from).unwrap();
+        let mut received = [0u8; 64];
+        assert_eq!(transport.recv_from(&mut received).await.unwrap(), (5, peer));
+        assert_eq!(&received[..5], b"reply");
+    }
+
+    #[tokio::test]
+    async fn a_relayed_datagram_filling_the_buffer_is_not_cut_short() {
+        let relay = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transport = RelayTransport::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), relay.local_addr().unwrap(), None);
+        let peer: SocketAddr = "[2001:db8::7]:4000".parse().unwrap();
+        let payload: Vec<u8> = (0..64).collect();
+
+        for len in [64, 65] {
+            let mut datagram = Vec::new();
+            write_header(peer, &mut datagram);
+            datagram.extend((0..len).map(|byte| byte as u8));
+            relay.send_to(&datagram, transport.local_addr().unwrap()).unwrap();
+            let mut received = [0u8; 64];
+            // A longer one is cut to the buffer, as a plain socket would
+            assert_eq!(transport.recv_from(&mut received).await.unwrap(), (64, peer));
+            assert_eq!(&received[..], &payload[..]);
+        }
+    }
+
+    // A SOCKS5 proxy that answers one UDP ASSOCIATE with `bound`, and the request it got
+    fn proxy(methods_reply: [u8; 2], bound: SocketAddr) -> (SocketAddr, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&methods_reply).unwrap();
+            if methods_reply[1] != SOCKS5_NO_AUTH {
+                return Vec::new()
+            }
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            let mut reply = vec![SOCKS5_VERSION, SOCKS5_SUCCEEDED, 0];
+            write_addr(bound, &mut reply);
+            stream.write_all(&reply).unwrap();
+            request.to_vec()
+        });
+        (addr, serving)
+    }
+
+    #[test]
+    fn socks5_associate_asks_for_the_relay_of_the_socket() {
+        let local: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+        let (addr, serving) = proxy([SOCKS5_VERSION, SOCKS5_NO_AUTH], "127.0.0.1:7000".parse().unwrap());
+        let mut control = connect_proxy(addr, [127, 0, 0, 1].into(), None).unwrap();
+        assert_eq!(socks5_associate(&mut control, local).unwrap(), "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(serving.join().unwrap(), [SOCKS5_VERSION, SOCKS5_UDP_ASSOCIATE, 0, ATYP_IPV4, 127, 0, 0, 1, 0x15, 0xb3]);
+
+        // An unspecified relay address is the proxy's own
+        let (addr, serving) = proxy([SOCKS5_VERSION, SOCKS5_NO_AUTH], "0.0.0.0:7001".parse().unwrap());
+        let mut control = connect_proxy(addr, [127, 0, 0, 1].into(), None).unwrap();
+        assert_eq!(socks5_associate(&mut control, local).unwrap(), SocketAddr::new(addr.ip(), 7001));
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn a_proxy_requiring_authentication_is_refused() {
+        let (addr, serving) = proxy([SOCKS5_VERSION, 0xff], "127.0.0.1:7000".parse().unwrap());
+        let mut control = connect_proxy(addr, [127, 0, 0, 1].into(), None).unwrap();
+        assert_eq!(socks5_associate(&mut control, "127.0.0.1:5555".parse().unwrap()).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        serving.join().unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::messages::Packet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderConfig {
+    pub capacity: usize,
+    pub timeout: Duration
+}
+
+/// Puts packets from one peer back in sequence order before they're written
+/// to the TUN. A missing packet holds back later ones until it arrives, the
+/// gap is older than `timeout`, or more than `capacity` packets are held. The
+/// latter two give up on it and move on.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    capacity: usize,
+    timeout: Duration,
+    // Next sequence number to deliver
+    next: Option<usize>,
+    pending: BTreeMap<usize, Packet>,
+    // When the current gap opened
+    gap_since: Option<Instant>
+}
+
+impl ReorderBuffer {
+    pub fn new(config: ReorderConfig) -> ReorderBuffer {
+        ReorderBuffer {
+            capacity: config.capacity.max(1),
+            timeout: config.timeout,
+            next: None,
+            pending: BTreeMap::new(),
+            gap_since: None
+        }
+    }
+
+    /// Packets held back waiting for a gap to fill.
+    pub fn depth(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// When the current gap will be given up on, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.gap_since.map(|since| since + self.timeout)
+    }
+
+    /// Add a packet and push the packets now ready, in order, to `ready`.
+    /// Returns true if the buffer was over capacity and skipped a gap.
+    pub fn push(&mut self, packet: Packet, now: Instant, ready: &mut Vec<Packet>) -> bool {
+        let next = *self.next.get_or_insert(packet.seq);
+
+        // Late packets whose gap was already given up on go straight through
+        if packet.seq < next {
+            ready.push(packet);
+            return false
+        }
+
+        self.pending.insert(packet.seq, packet);
+        if self.gap_since.is_none() {
+            self.gap_since = Some(now);
+        }
+
+        let forced = self.pending.len() > self.capacity;
+        if forced {
+            self.skip_gap();
+        }
+        self.drain(now, ready);
+        forced
+    }
+
+    /// Give up on the current gap if it's past the deadline. Returns true if it was skipped.
+    pub fn expire(&mut self, now: Instant, ready: &mut Vec<Packet>) -> bool {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => {
+                self.skip_gap();
+                self.drain(now, ready);
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Give up on all gaps and push every held packet, in order, to `ready`.
+    pub fn flush(&mut self, ready: &mut Vec<Packet>) {
+        ready.extend(std::mem::take(&mut self.pending).into_values());
+        self.next = None;
+        self.gap_since = None;
+    }
+
+    fn skip_gap(&mut self) {
+        if let Some(first) = self.pending.keys().next() {
+            self.next = Some(*first);
+        }
+    }
+
+    // Deliver everything contiguous from `next`
+    fn drain(&mut self, now: Instant, ready: &mut Vec<Packet>) {
+        let before = ready.len();
+        if let Some(next) = self.next.as_mut() {
+            while let Some(packet) = self.pending.remove(next) {
+                *next += 1;
+                ready.push(packet);
+            }
+        }
+
+        self.gap_since = if self.pending.is_empty() {
+            None
+        } else if ready.len() > before {
+            // What's left is held back by a new gap
+            Some(now)
+        } else {
+            self.gap_since
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn buffer(capacity: usize) -> ReorderBuffer {
+        ReorderBuffer::new(ReorderConfig { capacity, timeout: Duration::from_millis(50) })
+    }
+
+    // Push `seqs` and return the seqs released, in order
+    fn push(buffer: &mut ReorderBuffer, seqs: &[usize], now: Instant) -> Vec<usize> {
+        let mut ready = Vec::new();
+        for seq in seqs {
+            buffer.push(Packet { seq: *seq, bytes: Bytes::new() }, now, &mut ready);
+        }
+        ready.iter().map(|packet| packet.seq).collect()
+    }
+
+    #[test]
+    fn out_of_order_packets_are_released_in_order() {
+        let mut buffer = buffer(16);
+        let now = Instant::now();
+        assert_eq!(push(&mut buffer, &[1, 2], now), vec![1, 2]);
+        assert_eq!(push(&mut buffer, &[5, 4], now), Vec::<usize>::new());
+        assert_eq!(buffer.depth(), 2);
+        assert_eq!(push(&mut buffer, &[3], now), vec![3, 4, 5]);
+        assert_eq!(buffer.depth(), 0);
+        assert_eq!(buffer.deadline(), None);
+    }
+
+    #[test]
+    fn flood_past_the_cap_skips_the_gap_instead_of_growing() {
+        let mut buffer = buffer(8);
+        let now = Instant::now();
+        push(&mut buffer, &[1], now);
+
+        // 2 is lost on every path
+        let mut ready = Vec::new();
+        let mut forced = 0;
+        for seq in 3..1000 {
+            if buffer.push(Packet { seq, bytes: Bytes::new() }, now, &mut ready) {
+                forced += 1;
+            }
+            assert!(buffer.depth() <= 8);
+        }
+        assert_eq!(forced, 1);
+        let released: Vec<usize> = ready.iter().map(|packet| packet.seq).collect();
+        assert_eq!(released, (3..1000).collect::<Vec<_>>());
+        assert_eq!(buffer.depth(), 0);
+    }
+
+    #[test]
+    fn gap_is_given_up_on_after_the_timeout() {
+        let mut buffer = buffer(16);
+        let start = Instant::now();
+        push(&mut buffer, &[1, 3, 4], start);
+        assert_eq!(buffer.deadline(), Some(start + Duration::from_millis(50)));
+
+        let mut ready = Vec::new();
+        assert!(!buffer.expire(start + Duration::from_millis(49), &mut ready));
+        assert!(buffer.expire(start + Duration::from_millis(50), &mut ready));
+        assert_eq!(ready.iter().map(|packet| packet.seq).collect::<Vec<_>>(), vec![3, 4]);
+
+        // The lost packet, arriving after all, goes straight through
+        assert_eq!(push(&mut buffer, &[2], start), vec![2]);
+    }
+
+    #[test]
+    fn new_gap_restarts_the_timeout() {
+        let mut buffer = buffer(16);
+        let start = Instant::now();
+        push(&mut buffer, &[1, 3, 5], start);
+        let later = start + Duration::from_millis(30);
+        assert_eq!(push(&mut buffer, &[2], later), vec![2, 3]);
+        assert_eq!(buffer.deadline(), Some(later + Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn flush_releases_everything_held() {
+        let mut buffer = buffer(16);
+        let now = Instant::now();
+        push(&mut buffer, &[1, 4, 3], now);
+        let mut ready = Vec::new();
+        buffer.flush(&mut ready);
+        assert_eq!(ready.iter().map(|packet| packet.seq).collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(buffer.depth(), 0);
+        // Starts over from whatever comes next
+        assert_eq!(push(&mut buffer, &[10], now), vec![10]);
+    }
+}
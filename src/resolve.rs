@@ -0,0 +1,40 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+// Send devices are usually IPv4, so IPv4 results are preferred
+fn preferred(addrs: impl Iterator<Item = SocketAddr>, host: &str) -> io::Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs.collect();
+    addrs.iter().find(|addr| addr.is_ipv4()).or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no address found for `{}`", host)))
+}
+
+/// Resolve `host` to a socket address, blocking the calling thread.
+pub fn resolve_blocking(host: &str, port: u16) -> io::Result<SocketAddr> {
+    preferred((host, port).to_socket_addrs()?, host)
+}
+
+/// Resolve `host` to a socket address.
+pub async fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    preferred(tokio::net::lookup_host((host, port)).await?, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_results_are_preferred() {
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        assert_eq!(preferred(vec![v6, v4].into_iter(), "host").unwrap(), v4);
+        assert_eq!(preferred(vec![v6].into_iter(), "host").unwrap(), v6);
+        assert_eq!(preferred(std::iter::empty(), "host").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn literals_resolve_to_themselves() {
+        assert_eq!(resolve("127.0.0.1", 4000).await.unwrap(), "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(resolve_blocking("::1", 4000).unwrap(), "[::1]:4000".parse().unwrap());
+    }
+}
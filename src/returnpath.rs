@@ -0,0 +1,135 @@
+// Links that are only good one way. Each peer may pick the send devices it
+// wants its traffic to come back over, and announces them by sending a
+// return path message from each, all with one generation. We then send that
+// peer's packets to the addresses the announcements came from instead of
+// those we learned, which may not include a link the peer only receives on.
+// A new generation, e.g. after the peer restarted, replaces the addresses of
+// the old one. Announcements stop counting once none came for `max_age`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Announced {
+    generation: u32,
+    // With the time each was last announced from
+    addrs: Vec<SocketAddr>,
+    heard: Vec<Instant>
+}
+
+/// The addresses each peer asked to be sent to.
+#[derive(Debug)]
+pub struct ReturnPaths {
+    max_age: Duration,
+    peers: RwLock<HashMap<IpAddr, Announced>>
+}
+
+impl ReturnPaths {
+    pub fn new(max_age: Duration) -> ReturnPaths {
+        ReturnPaths { max_age, peers: RwLock::new(HashMap::new()) }
+    }
+
+    /// Record the announcement of `generation` from `tun_ip` at `addr`,
+    /// dropping addresses of its generation not announced from for
+    /// `max_age`. Returns true if that changed where the peer is sent to.
+    pub fn announced(&self, tun_ip: IpAddr, generation: u32, addr: SocketAddr, now: Instant) -> bool {
+        let mut peers = self.peers.write().unwrap();
+        let announced = match peers.get_mut(&tun_ip) {
+            Some(announced) if announced.generation == generation => announced,
+            _ => {
+                peers.insert(tun_ip, Announced { generation, addrs: vec![addr], heard: vec![now] });
+                return true
+            }
+        };
+        let before = announced.addrs.len();
+        let mut index = 0;
+        while index < announced.addrs.len() {
+            if announced.addrs[index] != addr && now.saturating_duration_since(announced.heard[index]) > self.max_age {
+                announced.addrs.swap_remove(index);
+                announced.heard.swap_remove(index);
+            } else {
+                index += 1;
+            }
+        }
+        let pruned = announced.addrs.len() != before;
+        match announced.addrs.iter().position(|known| *known == addr) {
+            Some(index) => {
+                announced.heard[index] = now;
+                pruned
+            },
+            None => {
+                announced.addrs.push(addr);
+                announced.heard.push(now);
+                true
+            }
+        }
+    }
+
+    /// Call `f` with the addresses `tun_ip` asked to be sent to. `None`
+    /// while it asked for none, or not within `max_age`.
+    pub fn with<R>(&self, tun_ip: &IpAddr, now: Instant, f: impl FnOnce(&[SocketAddr]) -> R) -> Option<R> {
+        let peers = self.peers.read().unwrap();
+        let announced = peers.get(tun_ip)?;
+        if announced.heard.iter().all(|heard| now.saturating_duration_since(*heard) > self.max_age) {
+            return None
+        }
+        Some(f(&announced.addrs))
+    }
+
+    pub fn forget(&self, tun_ip: &IpAddr) {
+        self.peers.write().unwrap().remove(tun_ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(15);
+
+    fn addrs(paths: &ReturnPaths, tun_ip: &IpAddr, now: Instant) -> Option<Vec<SocketAddr>> {
+        paths.with(tun_ip, now, |addrs| addrs.to_vec())
+    }
+
+    #[test]
+    fn announcements_of_a_generation_add_up_and_a_new_one_replaces_them() {
+        let paths = ReturnPaths::new(MAX_AGE);
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let (fiber, lte): (SocketAddr, SocketAddr) = ("192.0.2.1:4000".parse().unwrap(), "198.51.100.1:4000".parse().unwrap());
+        let start = Instant::now();
+        assert_eq!(addrs(&paths, &peer, start), None);
+
+        assert!(paths.announced(peer, 1, fiber, start));
+        assert!(paths.announced(peer, 1, lte, start));
+        // Heard again, nothing changes
+        assert!(!paths.announced(peer, 1, fiber, start + Duration::from_secs(5)));
+        assert_eq!(addrs(&paths, &peer, start), Some(vec![fiber, lte]));
+
+        // The peer restarted and only asks for one link now
+        assert!(paths.announced(peer, 2, lte, start + Duration::from_secs(6)));
+        assert_eq!(addrs(&paths, &peer, start + Duration::from_secs(6)), Some(vec![lte]));
+
+        paths.forget(&peer);
+        assert_eq!(addrs(&paths, &peer, start), None);
+    }
+
+    #[test]
+    fn addresses_no_longer_announced_age_out() {
+        let paths = ReturnPaths::new(MAX_AGE);
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let (fiber, lte): (SocketAddr, SocketAddr) = ("192.0.2.1:4000".parse().unwrap(), "198.51.100.1:4000".parse().unwrap());
+        let start = Instant::now();
+        paths.announced(peer, 1, fiber, start);
+        paths.announced(peer, 1, lte, start);
+
+        // Only the fiber link is announced from after that
+        let later = start + MAX_AGE + Duration::from_secs(1);
+        assert!(paths.announced(peer, 1, fiber, later));
+        assert_eq!(addrs(&paths, &peer, later), Some(vec![fiber]));
+
+        // And once it stops too, the peer is sent to where it was learned
+        assert_eq!(addrs(&paths, &peer, later + MAX_AGE + Duration::from_secs(1)), None);
+    }
+}
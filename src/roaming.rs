@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// What `AddressTracker::observe` decided about a packet's source address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressUpdate {
+    // Nothing changed
+    Unchanged,
+    // First address seen from this peer
+    Learned(SocketAddr),
+    // A new address showed up. Both it and the stable address are kept
+    // until it proves stable. Any earlier candidate is dropped.
+    Candidate { addr: SocketAddr, replaced: Option<SocketAddr> },
+    // The candidate proved stable and replaces the old address
+    Committed { old: SocketAddr, new: SocketAddr },
+    // The stable address was seen again, so the candidate is dropped
+    Reverted(SocketAddr)
+}
+
+#[derive(Debug)]
+struct TrackedAddr {
+    stable: SocketAddr,
+    // Candidate address and how many consecutive packets came from it
+    candidate: Option<(SocketAddr, u32)>
+}
+
+/// Debounces peer source address changes on one path, e.g. a flapping NAT.
+/// An address change is only committed after `threshold` consecutive packets
+/// from the new address.
+#[derive(Debug)]
+pub struct AddressTracker {
+    threshold: u32,
+    peers: HashMap<IpAddr, TrackedAddr>
+}
+
+impl AddressTracker {
+    pub fn new(threshold: u32) -> AddressTracker {
+        AddressTracker {
+            threshold: threshold.max(1),
+            peers: HashMap::new()
+        }
+    }
+
+    /// Drop what is known about `tun_ip`. Returns true if anything was.
+    pub fn forget(&mut self, tun_ip: &IpAddr) -> bool {
+        self.peers.remove(tun_ip).is_some()
+    }
+
+    /// Drop all peers, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let peers = self.peers.len();
+        self.peers.clear();
+        peers
+    }
+
+    pub fn observe(&mut self, tun_ip: IpAddr, addr: SocketAddr) -> AddressUpdate {
+        let tracked = match self.peers.get_mut(&tun_ip) {
+            Some(tracked) => tracked,
+            None => {
+                self.peers.insert(tun_ip, TrackedAddr { stable: addr, candidate: None });
+                return AddressUpdate::Learned(addr)
+            }
+        };
+
+        if addr == tracked.stable {
+            return match tracked.candidate.take() {
+                Some((candidate, _)) => AddressUpdate::Reverted(candidate),
+                None => AddressUpdate::Unchanged
+            }
+        }
+
+        let (replaced, count) = match tracked.candidate {
+            Some((candidate, count)) if candidate == addr => (None, count + 1),
+            Some((candidate, _)) => (Some(candidate), 1),
+            None => (None, 1)
+        };
+
+        // With a threshold of 1 there are never candidates, so nothing is replaced here
+        if count >= self.threshold {
+            let old = tracked.stable;
+            tracked.stable = addr;
+            tracked.candidate = None;
+            return AddressUpdate::Committed { old, new: addr }
+        }
+
+        tracked.candidate = Some((addr, count));
+        if count == 1 {
+            AddressUpdate::Candidate { addr, replaced }
+        } else {
+            AddressUpdate::Unchanged
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([192, 0, 2, 1], port).into()
+    }
+
+    const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn one_off_change_is_reverted_when_the_stable_address_returns() {
+        let mut tracker = AddressTracker::new(3);
+        assert_eq!(tracker.observe(PEER, addr(1)), AddressUpdate::Learned(addr(1)));
+        assert_eq!(tracker.observe(PEER, addr(1)), AddressUpdate::Unchanged);
+
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Candidate { addr: addr(2), replaced: None });
+        assert_eq!(tracker.observe(PEER, addr(1)), AddressUpdate::Reverted(addr(2)));
+        // The count starts over
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Candidate { addr: addr(2), replaced: None });
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Unchanged);
+        assert_eq!(tracker.observe(PEER, addr(1)), AddressUpdate::Reverted(addr(2)));
+    }
+
+    #[test]
+    fn sustained_change_is_committed_after_the_threshold() {
+        let mut tracker = AddressTracker::new(3);
+        tracker.observe(PEER, addr(1));
+
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Candidate { addr: addr(2), replaced: None });
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Unchanged);
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Committed { old: addr(1), new: addr(2) });
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Unchanged);
+        // The old address is now the new one
+        assert_eq!(tracker.observe(PEER, addr(1)), AddressUpdate::Candidate { addr: addr(1), replaced: None });
+    }
+
+    #[test]
+    fn switching_candidates_starts_the_count_over() {
+        let mut tracker = AddressTracker::new(2);
+        tracker.observe(PEER, addr(1));
+
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Candidate { addr: addr(2), replaced: None });
+        assert_eq!(tracker.observe(PEER, addr(3)), AddressUpdate::Candidate { addr: addr(3), replaced: Some(addr(2)) });
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Candidate { addr: addr(2), replaced: Some(addr(3)) });
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Committed { old: addr(1), new: addr(2) });
+    }
+
+    #[test]
+    fn threshold_of_one_commits_at_once() {
+        let mut tracker = AddressTracker::new(0);
+        tracker.observe(PEER, addr(1));
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Committed { old: addr(1), new: addr(2) });
+    }
+
+    #[test]
+    fn forgotten_peers_are_learned_again() {
+        let mut tracker = AddressTracker::new(3);
+        tracker.observe(PEER, addr(1));
+        assert!(tracker.forget(&PEER));
+        assert!(!tracker.forget(&PEER));
+        assert_eq!(tracker.observe(PEER, addr(2)), AddressUpdate::Learned(addr(2)));
+        assert_eq!(tracker.clear(), 1);
+    }
+}
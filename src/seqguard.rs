@@ -0,0 +1,223 @@
+use crate::jitter;
+use crate::settings::BackwardJumpAction;
+
+// A peer that restarts numbers its packets from 0 again. A jump back to a
+// sequence number below this starts a new epoch instead of being anomalous.
+pub const EPOCH_RESET_WINDOW: usize = 64;
+
+/// Epoch for the packets of a tunnel starting now, with session_epoch: the
+/// low 32 bits of the time in milliseconds, so it differs from run to run
+/// without being stored anywhere.
+pub fn new_epoch() -> u32 {
+    (jitter::timestamp_now() / 1000) as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqGuardConfig {
+    // Largest backward step accepted as ordinary reordering
+    pub max_backward: usize,
+    pub action: BackwardJumpAction
+}
+
+/// What `SeqGuard::observe` made of a packet's sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqCheck {
+    Accepted,
+    // The peer restarted, its sequence numbers start over
+    EpochReset,
+    // Far behind the highest sequence number seen, but not a restart.
+    // The highest sequence number is left as it was.
+    BackwardJump { highest: usize }
+}
+
+/// Flags sequence numbers from one peer that fall back much further than
+/// reordering across links explains, e.g. injected or corrupted datagrams.
+#[derive(Debug)]
+pub struct SeqGuard {
+    max_backward: usize,
+    highest: Option<usize>
+}
+
+impl SeqGuard {
+    pub fn new(max_backward: usize) -> SeqGuard {
+        SeqGuard {
+            max_backward,
+            highest: None
+        }
+    }
+
+    pub fn observe(&mut self, seq: usize) -> SeqCheck {
+        let highest = match self.highest {
+            Some(highest) if seq < highest => highest,
+            _ => {
+                self.highest = Some(seq);
+                return SeqCheck::Accepted
+            }
+        };
+
+        if highest - seq <= self.max_backward {
+            SeqCheck::Accepted
+        } else if seq < EPOCH_RESET_WINDOW {
+            self.highest = Some(seq);
+            SeqCheck::EpochReset
+        } else {
+            SeqCheck::BackwardJump { highest }
+        }
+    }
+}
+
+// Packets of an epoch other than the current one, in a row, after which
+// it is taken for the peer's session. A single spoofed, replayed or
+// corrupted datagram can't switch sessions on its own.
+pub const EPOCH_CONFIRMATIONS: usize = 8;
+
+/// What `SessionEpochs::observe` made of a packet's session epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EpochCheck {
+    Current,
+    // The peer restarted, what was kept about its old session is stale
+    New,
+    // From the session before the peer's restart, delayed on a slower link
+    Stale,
+    // Of an epoch not confirmed yet, see `EPOCH_CONFIRMATIONS`
+    Unconfirmed
+}
+
+/// The session epochs of one peer, to tell a restart from packets of the
+/// old session still arriving. Epochs are only compared for equality, so
+/// a clock stepping back doesn't matter.
+#[derive(Debug, Default)]
+pub struct SessionEpochs {
+    current: Option<u32>,
+    previous: Option<u32>,
+    // Another epoch and how many of its packets arrived since the last of the current one
+    contender: Option<(u32, usize)>
+}
+
+impl SessionEpochs {
+    /// Observe the epoch of packet `seq`. A new epoch is adopted once
+    /// `EPOCH_CONFIRMATIONS` of its packets arrive in a row, or right away
+    /// if it starts with sequence numbers reset, as a restarted peer's do.
+    /// The previous epoch wins back the same way if it keeps arriving.
+    pub fn observe(&mut self, epoch: u32, seq: usize) -> EpochCheck {
+        let current = match self.current {
+            Some(current) if current == epoch => {
+                self.contender = None;
+                return EpochCheck::Current
+            },
+            Some(current) => current,
+            None => {
+                self.current = Some(epoch);
+                return EpochCheck::Current
+            }
+        };
+
+        let count = match self.contender {
+            Some((contender, count)) if contender == epoch => count + 1,
+            _ => 1
+        };
+        let previous = self.previous == Some(epoch);
+        if count >= EPOCH_CONFIRMATIONS || (seq < EPOCH_RESET_WINDOW && !previous) {
+            self.previous = Some(current);
+            self.current = Some(epoch);
+            self.contender = None;
+            EpochCheck::New
+        } else {
+            self.contender = Some((epoch, count));
+            if previous { EpochCheck::Stale } else { EpochCheck::Unconfirmed }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_backward_steps_are_reordering() {
+        let mut guard = SeqGuard::new(100);
+        assert_eq!(guard.observe(1000), SeqCheck::Accepted);
+        assert_eq!(guard.observe(900), SeqCheck::Accepted);
+        assert_eq!(guard.observe(1001), SeqCheck::Accepted);
+    }
+
+    #[test]
+    fn lone_far_backward_jump_is_flagged_and_leaves_the_highest_alone() {
+        let mut guard = SeqGuard::new(100);
+        assert_eq!(guard.observe(1000), SeqCheck::Accepted);
+        assert_eq!(guard.observe(500), SeqCheck::BackwardJump { highest: 1000 });
+        assert_eq!(guard.observe(950), SeqCheck::Accepted);
+        assert_eq!(guard.observe(500), SeqCheck::BackwardJump { highest: 1000 });
+    }
+
+    #[test]
+    fn falling_back_near_zero_is_a_restart() {
+        let mut guard = SeqGuard::new(100);
+        assert_eq!(guard.observe(1000), SeqCheck::Accepted);
+        assert_eq!(guard.observe(0), SeqCheck::EpochReset);
+        // Numbering carries on from the restart
+        assert_eq!(guard.observe(1), SeqCheck::Accepted);
+        assert_eq!(guard.observe(EPOCH_RESET_WINDOW + 200), SeqCheck::Accepted);
+    }
+
+    #[test]
+    fn a_new_epoch_is_a_restart_and_the_one_before_is_stale() {
+        let mut epochs = SessionEpochs::default();
+        assert_eq!(epochs.observe(7, 500), EpochCheck::Current);
+        assert_eq!(epochs.observe(7, 501), EpochCheck::Current);
+        assert_eq!(epochs.observe(9, 0), EpochCheck::New);
+        assert_eq!(epochs.observe(7, 502), EpochCheck::Stale);
+        assert_eq!(epochs.observe(9, 1), EpochCheck::Current);
+        // Only the session right before is told apart
+        assert_eq!(epochs.observe(3, 0), EpochCheck::New);
+        assert_eq!(epochs.observe(9, 2), EpochCheck::Stale);
+        assert_eq!(epochs.observe(7, 0), EpochCheck::New);
+    }
+
+    #[test]
+    fn a_new_epoch_without_a_seq_reset_needs_confirming() {
+        let mut epochs = SessionEpochs::default();
+        assert_eq!(epochs.observe(7, 500), EpochCheck::Current);
+        for seq in 1000..1000 + EPOCH_CONFIRMATIONS - 1 {
+            assert_eq!(epochs.observe(9, seq), EpochCheck::Unconfirmed);
+        }
+        assert_eq!(epochs.observe(9, 2000), EpochCheck::New);
+        assert_eq!(epochs.observe(9, 2001), EpochCheck::Current);
+    }
+
+    #[test]
+    fn a_packet_of_the_current_epoch_breaks_the_run() {
+        let mut epochs = SessionEpochs::default();
+        assert_eq!(epochs.observe(7, 500), EpochCheck::Current);
+        for round in 0..3 {
+            for seq in 0..EPOCH_CONFIRMATIONS - 1 {
+                assert_eq!(epochs.observe(9, 1000 + round * 100 + seq), EpochCheck::Unconfirmed);
+            }
+            assert_eq!(epochs.observe(7, 501 + round), EpochCheck::Current);
+        }
+    }
+
+    #[test]
+    fn the_previous_epoch_wins_back_if_it_keeps_arriving() {
+        let mut epochs = SessionEpochs::default();
+        assert_eq!(epochs.observe(7, 500), EpochCheck::Current);
+        // A spoofed datagram of another epoch, with a reset sequence number
+        assert_eq!(epochs.observe(0xdead, 0), EpochCheck::New);
+        for seq in 501..500 + EPOCH_CONFIRMATIONS {
+            assert_eq!(epochs.observe(7, seq), EpochCheck::Stale);
+        }
+        assert_eq!(epochs.observe(7, 600), EpochCheck::New);
+        assert_eq!(epochs.observe(7, 601), EpochCheck::Current);
+    }
+
+    #[test]
+    fn stragglers_of_the_old_session_dont_win_back() {
+        let mut epochs = SessionEpochs::default();
+        assert_eq!(epochs.observe(7, 500), EpochCheck::Current);
+        assert_eq!(epochs.observe(9, 0), EpochCheck::New);
+        for seq in 0..20 {
+            assert_eq!(epochs.observe(7, 501 + seq), EpochCheck::Stale);
+            assert_eq!(epochs.observe(9, 1 + seq), EpochCheck::Current);
+        }
+    }
+}
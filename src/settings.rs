@@ -1,20 +1,1242 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize};
 
-#[derive(Deserialize, Debug)]
+use crate::pmtud::PmtuSearchMode;
+use crate::messages::WireFormat;
+use crate::flowlabel::FlowLabelMode;
+use crate::error::SettingsError;
+use crate::cidr::Cidr;
+
+// Prefix of the environment variables that set SettingsFile fields
+const ENV_PREFIX: &str = "MPTUN_";
+
+// MTU of the TUN device unless tun_mtu is set
+pub const DEFAULT_TUN_MTU: usize = 1424;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct SendDevice {
-    pub udp_iface: String,
-    pub udp_listen_addr: Ipv4Addr,
-    pub udp_listen_port: u16
+    // Interface to bind the socket to with SO_BINDTODEVICE (needs CAP_NET_RAW).
+    // When unset the socket is only bound to udp_listen_addr, e.g. for source based policy routing.
+    pub udp_iface: Option<String>,
+    // IPv4 or IPv6 address, the socket's family follows it. Unless unspecified
+    // it is the source of what the device sends, also with udp_iface, so on
+    // an interface with several addresses it picks one. A warning is logged
+    // if it isn't one of udp_iface's.
+    pub udp_listen_addr: IpAddr,
+    // 0 lets the OS pick a free port
+    pub udp_listen_port: u16,
+    // Last port of a range starting at udp_listen_port. The ports are tried in
+    // order and the first free one is bound. The bound port is reported by
+    // TunnelHandle::local_addrs.
+    pub udp_listen_port_max: Option<u16>,
+    // For IPv6 listen addresses: also accept IPv4 (IPV6_V6ONLY off). Defaults to false.
+    pub dual_stack: Option<bool>,
+    // Failover preference. Lower values are preferred, defaults to 0.
+    pub priority: Option<u8>,
+    // Share of the packets in weighted path mode, relative to the other
+    // devices. Defaults to 1.
+    pub weight: Option<f64>,
+    // Share of the packets, from 0 to 1, this device sends a copy of in
+    // redundant path mode while another device is up, e.g. 0.25 for every
+    // 4th, for cheap links that only add some protection. Defaults to 1.
+    pub send_fraction: Option<f64>,
+    // Network namespace (name under /var/run/netns or a path) to create the socket in
+    pub netns: Option<String>,
+    // Cap on the bits per second sent on this device, including tunnel overhead.
+    // Over the cap datagrams are skipped in redundant mode and moved to the next link in failover mode.
+    pub max_bps: Option<u64>,
+    // Spread the datagrams sent on this device evenly at this many bits per
+    // second, including tunnel overhead, instead of sending bursts as fast as
+    // they come. Unlike max_bps nothing is skipped, datagrams wait their turn.
+    // Turns off send_batch for the device.
+    pub pacing_bps: Option<u64>,
+    // DSCP (0-63) marked on all datagrams sent on this device, as the IPv4 ToS
+    // or IPv6 traffic class. copy_dscp takes precedence for IPv4 devices.
+    pub dscp: Option<u8>,
+    // Firewall mark (SO_MARK) set on the socket, e.g. for fwmark based ip rules.
+    // Needs CAP_NET_ADMIN. Without it the error is logged and nothing is marked.
+    pub fwmark: Option<u32>,
+    // Socket receive and send buffer sizes in bytes. The kernel doubles them
+    // and caps them at net.core.rmem_max / wmem_max. Kernel defaults when unset.
+    pub so_rcvbuf: Option<usize>,
+    pub so_sndbuf: Option<usize>,
+    // Probe the path MTU of this device, tuned by the global pmtud settings, and
+    // fragment datagrams to it. Sets the don't fragment bit on everything sent.
+    pub pmtud: Option<bool>,
+    // Set SO_REUSEADDR and SO_REUSEPORT before binding, so devices bound to
+    // different interfaces can share a port. Any process of the same user can
+    // then bind the port as well and take a share of its traffic. Defaults to false.
+    pub reuse_port: Option<bool>,
+    // Seconds to wait for udp_iface to exist and udp_listen_addr to be assigned
+    // before binding fails, e.g. while the network comes up at boot. Blocks
+    // while waiting. Fails at once when unset.
+    pub bind_timeout: Option<u64>,
+    // Run the device over Unix datagram sockets in this directory instead of
+    // UDP, e.g. between processes on one host. Sockets are named after their
+    // listen address and port, so peers are configured as for UDP. The
+    // socket options above don't apply.
+    pub unix_socket_dir: Option<PathBuf>,
+    // Tasks receiving on this device's socket concurrently, for fast links on
+    // many cores. Defaults to 1. The binary runs multi-threaded when any
+    // device has more than one.
+    pub recv_workers: Option<usize>,
+    // CPUs, by number, for the binary to run this device's tasks on, in a
+    // runtime of its own with a worker per CPU, e.g. those of the NUMA node
+    // of its NIC. Read at startup only, changes take a restart. Library users
+    // pass a runtime to Multipathtunnel::with_device_runtime instead.
+    pub cpus: Option<Vec<usize>>,
+    // Reach the peers through a UDP relay instead of sending to them directly,
+    // for links that only get out through a proxy. Adds up to 22 bytes to
+    // each datagram. send_batch, recv_batch, flow labels and copy_dscp don't
+    // apply to the device.
+    pub relay: Option<RelaySettings>,
+    // Drop data packets on purpose before they are sent, to see how the
+    // peers cope with loss on this link. Only with allow_drop_injection.
+    pub drop_injection: Option<DropInjectionSettings>
 }
 
-#[derive(Deserialize, Debug)]
+impl SendDevice {
+    /// A device bound to `udp_listen_addr:udp_listen_port`, with everything else at its default.
+    pub fn new(udp_listen_addr: IpAddr, udp_listen_port: u16) -> SendDevice {
+        SendDevice {
+            udp_iface: None,
+            udp_listen_addr,
+            udp_listen_port,
+            udp_listen_port_max: None,
+            dual_stack: None,
+            priority: None,
+            weight: None,
+            send_fraction: None,
+            netns: None,
+            max_bps: None,
+            pacing_bps: None,
+            dscp: None,
+            fwmark: None,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            pmtud: None,
+            reuse_port: None,
+            bind_timeout: None,
+            unix_socket_dir: None,
+            recv_workers: None,
+            cpus: None,
+            relay: None,
+            drop_injection: None
+        }
+    }
+
+    /// Name used for the device in logs: the interface, or the listen address if unbound.
+    pub fn name(&self) -> String {
+        match &self.udp_iface {
+            Some(iface) => iface.clone(),
+            None => self.udp_listen_addr.to_string()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathMode {
+    // Send every packet over all links
+    #[default]
+    Redundant,
+    // Send only over the highest priority link that answers keep-alives
+    Failover,
+    // Spread inner flows across the links that answer keep-alives, keeping
+    // each flow on one link so it isn't reordered
+    FlowHash,
+    // Send over the link that's up with the lowest keep-alive RTT at once,
+    // and over the others as backups after a short delay, see `fastest`.
+    // Without keep-alives the highest priority link goes first.
+    Fastest,
+    // Spread packets over the links that are up in proportion to their
+    // devices' weight, or to weights derived from their quality, see
+    // `auto_weights`. Packets of one flow may be reordered.
+    Weighted
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct SettingsFile {
-    pub tun_ip: Ipv4Addr,
+    pub tun_ip: IpAddr,
     pub send_devices: Vec<SendDevice>,
-    pub remote_addr: Ipv4Addr,
+    // Address of the pre-configured remote. Used as a fallback when remote_host is set.
+    pub remote_addr: Option<IpAddr>,
+    // Hostname of the pre-configured remote, re-resolved periodically (e.g. for dynamic DNS)
+    pub remote_host: Option<String>,
     pub remote_port: u16,
-    pub remote_tun_addr: Option<Ipv4Addr>,
+    // Seconds between re-resolving remote_host. Defaults to 300.
+    pub remote_resolve_interval: Option<u64>,
+    // Further addresses of the pre-configured remote, e.g. one per underlay link,
+    // so every link carries traffic before the remote has sent anything
+    pub remote_addrs: Option<Vec<SocketAddr>>,
+    pub remote_tun_addr: Option<IpAddr>,
+    // Insert the pre-configured remote as a peer at startup, so it is sent to
+    // before it has sent anything. A server that should only learn its clients
+    // can turn this off and keep remote_tun_addr for e.g. via_hub. Defaults to true.
+    pub insert_remote: Option<bool>,
     pub keep_alive: Option<bool>,
-    pub keep_alive_interval: Option<u64>
-}
\ No newline at end of file
+    // Seconds between keep-alives, at least 1
+    pub keep_alive_interval: Option<u64>,
+    // Milliseconds between keep-alives, at least 1, for finer control on
+    // low latency links. Takes precedence over keep_alive_interval.
+    pub keep_alive_interval_ms: Option<u64>,
+    // Seconds without a keep-alive reply before a link is marked down. Defaults to three intervals.
+    pub keep_alive_timeout: Option<u64>,
+    // Only send keep-alives to peers behind a NAT, detected from source port
+    // rewriting or listed in nat_peers. Saves battery toward public peers.
+    pub keep_alive_nat_only: Option<bool>,
+    // Keep sending keep-alives on paths paused with pause_path, so their
+    // health stays current for when they resume. Defaults to true.
+    pub keep_alive_while_paused: Option<bool>,
+    // How many keep-alives in a row decide that a link is down or up again,
+    // and how many changes make it flapping
+    pub path_health: Option<PathHealthSettings>,
+    // Until the pre-configured remote answers, greet it on every link with a
+    // keep-alive, retried with exponential backoff. A PathUp event is emitted
+    // for each link it answers on. Off when unset.
+    pub handshake: Option<HandshakeSettings>,
+    // TUN IPs of peers known to be behind a NAT
+    pub nat_peers: Option<Vec<IpAddr>>,
+    // Seconds a peer address may stay silent while the peer is heard from
+    // another port on the same IP, before it's taken for an old NAT mapping
+    // and dropped. Defaults to 30.
+    pub nat_rebind_grace: Option<u64>,
+    pub path_mode: Option<PathMode>,
+    // In redundant mode, send each packet over only this many links: those up
+    // with the lowest keep-alive RTT, then by priority. Without keep-alives
+    // links rank by priority alone. All links when unset or at least their number.
+    pub redundancy: Option<usize>,
+    // Milliseconds over which a link that came back up ramps from none to all
+    // of the redundant traffic, so a link still stabilizing isn't flooded.
+    // Only while another link is up. Off when unset.
+    pub recovery_ramp_ms: Option<u64>,
+    // Stagger of the backup copies in fastest path mode
+    pub fastest: Option<FastestSettings>,
+    // In weighted path mode, derive the links' weights from their keep-alive
+    // RTT and loss instead of the devices' weight. Off when unset.
+    pub auto_weights: Option<AutoWeightSettings>,
+    // path_mode for individual peers, by TUN IP
+    pub peer_path_modes: Option<HashMap<IpAddr, PathMode>>,
+    // Send devices, by name, to send to and receive from individual peers,
+    // by TUN IP, for links that are only good one way
+    pub peer_paths: Option<HashMap<IpAddr, PeerPathSettings>>,
+    // Flow label for datagrams sent to IPv6 peers. Unset leaves it to the kernel.
+    pub flow_label: Option<FlowLabelMode>,
+    // In failover mode, send the first this many packets of each new inner flow
+    // (by 5-tuple) over all links, e.g. to protect TCP handshakes from loss
+    pub new_flow_duplicate_packets: Option<u32>,
+    // Largest decompressed payload accepted from a peer, at least the TUN MTU.
+    // Defaults to the TUN MTU plus a small slack.
+    pub max_payload_len: Option<usize>,
+    // Copy the inner packet's DSCP/ECN bits to the outer datagram
+    pub copy_dscp: Option<bool>,
+    // Inner DSCP -> outer DSCP applied when copying. Unlisted values are copied as is.
+    // Only the outer header is remapped: the inner packet is carried unchanged,
+    // so it reaches the peer's TUN with its original DSCP and there is nothing
+    // to map back on receive.
+    pub dscp_remap: Option<HashMap<u8, u8>>,
+    // Tuning of path MTU discovery, which is enabled per send device
+    pub pmtud: Option<PmtudSettings>,
+    // Encoding of datagrams on the wire. Both peers must agree. Defaults to Bincode,
+    // which Versioned supersedes once all peers understand it.
+    pub wire_format: Option<WireFormat>,
+    pub oversize_policy: Option<OversizePolicy>,
+    pub unparseable_policy: Option<UnparseablePolicy>,
+    // When set, a peer address learned on a link is replaced once this many consecutive
+    // packets arrive from a new address on that link. Until then both are used.
+    // When unset, new addresses are added and old ones are never replaced.
+    pub address_change_packets: Option<u32>,
+    // Encrypt all datagrams with a pre-shared key. Both peers must use the same key.
+    pub encryption: Option<EncryptionSettings>,
+    // Keys of peers that have their own, by TUN IP, in the same format. A peer's
+    // key is used instead of the one above for everything to and from it, and
+    // it's dropped when it uses another. Receiving from an address not yet
+    // known takes a try of each key.
+    pub peer_keys: Option<HashMap<IpAddr, EncryptionSettings>>,
+    // Reorder buffer of InOrder delivery. Setting it picks InOrder when delivery
+    // is unset.
+    pub reorder: Option<ReorderSettings>,
+    // How received packets are written to the TUN. Defaults to InOrder when
+    // reorder is set, else Immediate.
+    pub delivery: Option<DeliveryMode>,
+    // Milliseconds a shutdown waits for queued packets to be delivered before
+    // stopping the tasks. Defaults to 1000.
+    pub drain_timeout_ms: Option<u64>,
+    // Shut down gracefully once no data packet was sent or received for this
+    // many seconds, or this many seconds after the tunnel started running,
+    // e.g. for ephemeral tunnels. Keep-alives don't count as traffic. Off when unset.
+    pub max_idle: Option<u64>,
+    pub max_lifetime: Option<u64>,
+    // Drop packets read from the TUN that waited longer than this many milliseconds
+    // to be sent, favouring fresh data for realtime traffic. Off when unset.
+    pub max_packet_age_ms: Option<u64>,
+    // Ask a peer to slow down when its packets are dropped for want of inbound
+    // queue space. While asked, the peer sends traffic it would send over all
+    // links over the active link only. Peers without this change can't decode
+    // the request. Off when unset.
+    pub slow_down: Option<SlowDownSettings>,
+    // Decrement the inner TTL / hop limit of every packet sent, dropping packets
+    // it would take to zero, so a forwarding loop between tunnels dies out
+    pub decrement_ttl: Option<bool>,
+    // Only carry packets whose destination is in one of these subnets, e.g.
+    // ["10.1.0.0/16", "fd00::/64"]. Others are dropped and counted. Unset carries everything.
+    pub allowed_destinations: Option<Vec<Cidr>>,
+    // Drop every datagram from a source address in one of these subnets, e.g.
+    // ["203.0.113.7/32"], before it is decrypted or can add a peer. Entries
+    // can also be added and removed while the tunnel runs.
+    pub blocked_sources: Option<Vec<Cidr>>,
+    // Rewrite the source of packets sent from an original subnet to the same
+    // host in its mapped one, and the destination of packets received for the
+    // mapped subnet back, fixing up checksums. Lets subnets that overlap on the
+    // two ends be routed. Off when unset.
+    pub source_nat: Option<Vec<SourceNatMapping>>,
+    // Send packets that are ready back to back with one sendmmsg call, up to
+    // this many per call. A batch goes out as soon as no further packet is
+    // waiting, so it adds no delay. UDP send devices only. Off when unset or 1.
+    pub send_batch: Option<usize>,
+    // Receive up to this many datagrams per recvmmsg call. Each receive task
+    // then holds this many buffers sized for the largest datagram a full payload
+    // encodes to. UDP send devices only. Off when unset or 1.
+    pub recv_batch: Option<usize>,
+    // Stamp data packets with the send time, adding 8 bytes to each, so the peer
+    // can estimate one-way delay and jitter per path. The delay is only
+    // meaningful with synced clocks. Peers without this change can't decode them.
+    pub timestamps: Option<bool>,
+    // Stamp data packets with the sending path's id and its own sequence number,
+    // adding 10 bytes to each, so the peer can count each path's loss. Peers
+    // without this change can't decode them. Defaults to false.
+    pub path_sequence: Option<bool>,
+    // Stamp data packets with an epoch that changes each time the tunnel
+    // starts, adding 4 bytes to each. The peer then forgets the sequence
+    // numbers it delivered from us when we restart, rather than dropping our
+    // packets as duplicates until the numbers pass the old ones. Peers
+    // without this change can't decode them. Defaults to false.
+    pub session_epoch: Option<bool>,
+    // Append a CRC-32 to each datagram, adding 4 bytes, and drop received
+    // ones without a matching one, to catch corruption the UDP checksum
+    // misses. Cheaper than encryption, but no protection against tampering.
+    // Peers without this set can't decode ours. Defaults to false.
+    pub checksum: Option<bool>,
+    // MTU of the TUN device, e.g. 9000 for jumbo frames. Defaults to 1424.
+    pub tun_mtu: Option<usize>,
+    // Open the TUN with this many queues (IFF_MULTI_QUEUE) and read each in its
+    // own task, for hosts where one reader can't keep up. Defaults to 1.
+    pub tun_queues: Option<usize>,
+    // Largest datagram sent on the underlay. Larger messages are split into fragments
+    // the peer reassembles. Unset sends every message as one datagram.
+    pub max_datagram_size: Option<usize>,
+    // Flag packets whose sequence number falls far behind the peer's highest,
+    // other than a peer restarting from 0. Off when unset.
+    pub backward_jump: Option<BackwardJumpSettings>,
+    // Periodically write a JSON snapshot of the peers, paths and stats to a file
+    pub snapshot: Option<SnapshotSettings>,
+    // Append a record of every data packet each link sends and receives to a
+    // file, for debugging. Off when unset.
+    pub capture: Option<CaptureSettings>,
+    // Let send devices' drop_injection take effect, for testing. Settings
+    // with drop_injection are refused without it, so a test setting can't
+    // drop traffic in production by accident. Defaults to false.
+    pub allow_drop_injection: Option<bool>,
+    // Send a copy of every packet delivered to the TUN to a UDP collector
+    // and/or a pcap file, e.g. for an IDS. Copies are dropped rather than
+    // hold up delivery. Off when unset.
+    pub mirror: Option<MirrorSettings>,
+    // Seconds without hearing from a peer address before it's dropped, and the
+    // peer with it once it has none left. The pre-configured remote is never
+    // dropped. Should be a few keep-alive intervals. Off when unset.
+    pub client_timeout: Option<u64>,
+    // Most peers learned at once. Packets from further peers are dropped without
+    // learning them, so spoofed sources can't grow the client list without bound.
+    // Unlimited when unset.
+    pub max_clients: Option<usize>,
+    // Whether peers are learned from the source of the packets they send.
+    // Defaults to Learn.
+    pub discovery: Option<DiscoveryMode>,
+    // Answer packets from the TUN for TUN IPs without a known peer with an ICMP
+    // destination unreachable, so applications fail fast. Defaults to false.
+    pub icmp_unreachable: Option<bool>,
+    // Hold packets from the TUN for TUN IPs without a known peer, and send them
+    // once the peer is discovered, instead of dropping them. Off when unset.
+    pub unknown_peer_queue: Option<UnknownPeerQueueSettings>,
+    // Relay packets between peers: a packet for another known peer's TUN IP
+    // is sent on to that peer instead of the TUN. Defaults to false.
+    pub hub: Option<bool>,
+    // Send packets for TUN IPs without a known peer to the pre-configured
+    // remote, e.g. the hub of a hub and spoke setup. Defaults to false.
+    pub via_hub: Option<bool>,
+    // Broadcast address of the TUN device. Defaults to 255.255.255.255.
+    pub tun_broadcast: Option<TunBroadcast>
+}
+
+impl SettingsFile {
+    /// Read and parse a JSON settings file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SettingsFile> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Like `load`, with fields overridden by `MPTUN_` environment variables
+    /// as described in `from_env_vars`. Environment variables take
+    /// precedence over the file, which takes precedence over the defaults.
+    pub fn load_with_env<P: AsRef<Path>>(path: P) -> io::Result<SettingsFile> {
+        let contents = std::fs::read_to_string(path)?;
+        SettingsFile::from_env_vars(Some(serde_json::from_str(&contents)?), std::env::vars())
+    }
+
+    /// Settings from `MPTUN_` environment variables alone, for deployments
+    /// without a settings file. At least `MPTUN_TUN_IP`, `MPTUN_SEND_DEVICES`
+    /// and `MPTUN_REMOTE_PORT` must be set.
+    pub fn from_env() -> io::Result<SettingsFile> {
+        SettingsFile::from_env_vars(None, std::env::vars())
+    }
+
+    /// Layer the `MPTUN_` variables of `vars` over the parsed settings
+    /// file `file`, if any. Each variable sets the top-level field named by
+    /// the rest of its name in lowercase, e.g. `MPTUN_KEEP_ALIVE_INTERVAL`
+    /// sets `keep_alive_interval`, and replaces the file's value of it
+    /// outright: nested settings like `reorder` aren't merged field by field.
+    ///
+    /// Values are read as JSON where they parse, e.g. numbers, booleans,
+    /// lists and objects, and as a string otherwise, so `MPTUN_TUN_IP=10.0.0.1`
+    /// needs no quotes. A string that would parse as JSON must be quoted.
+    /// `MPTUN_SEND_DEVICES` also takes a comma separated list of listen
+    /// addresses such as `0.0.0.0:5000,[::]:5001`, one device each with
+    /// everything else at its defaults. Variables naming no field are ignored.
+    pub fn from_env_vars(file: Option<serde_json::Value>, vars: impl IntoIterator<Item = (String, String)>) -> io::Result<SettingsFile> {
+        let mut fields = match file {
+            Some(serde_json::Value::Object(fields)) => fields,
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "settings file is not a JSON object")),
+            None => serde_json::Map::new()
+        };
+
+        for (name, value) in vars {
+            let field = match name.strip_prefix(ENV_PREFIX) {
+                Some(field) if !field.is_empty() => field.to_lowercase(),
+                _ => continue
+            };
+            let parsed = match serde_json::from_str(&value) {
+                Ok(parsed) => parsed,
+                Err(_) if field == "send_devices" => send_devices_from_env(&name, &value)?,
+                Err(_) => serde_json::Value::String(value)
+            };
+            fields.insert(field, parsed);
+        }
+
+        Ok(serde_json::from_value(serde_json::Value::Object(fields))?)
+    }
+
+    /// The broadcast address to give the TUN device, if any.
+    pub fn tun_broadcast_addr(&self) -> Option<Ipv4Addr> {
+        match self.tun_broadcast {
+            Some(TunBroadcast::Address(addr)) => Some(addr),
+            Some(TunBroadcast::None) => None,
+            None => Some(Ipv4Addr::BROADCAST)
+        }
+    }
+
+    /// The delivery mode, from delivery if set, else from whether reorder is.
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        match (self.delivery, &self.reorder) {
+            (Some(delivery), _) => delivery,
+            (None, Some(_)) => DeliveryMode::InOrder,
+            (None, None) => DeliveryMode::Immediate
+        }
+    }
+
+    /// Time between keep-alives, from keep_alive_interval_ms if set, else keep_alive_interval.
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        self.keep_alive_interval_ms.map(Duration::from_millis)
+            .or_else(|| self.keep_alive_interval.map(Duration::from_secs))
+    }
+
+    /// TUN address of the pre-configured remote, if it is inserted as a peer.
+    pub fn inserted_remote(&self) -> Option<IpAddr> {
+        self.remote_tun_addr.filter(|_| self.insert_remote.unwrap_or(true))
+    }
+
+    /// Check invariants between fields that parsing can't.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.send_devices.is_empty() {
+            return Err(SettingsError::NoSendDevices)
+        }
+
+        if self.keep_alive == Some(true) {
+            match self.keep_alive_interval() {
+                None => return Err(SettingsError::MissingKeepAliveInterval),
+                Some(interval) if interval.is_zero() => return Err(SettingsError::ZeroKeepAliveInterval),
+                Some(_) => {}
+            }
+        }
+
+        if self.remote_resolve_interval == Some(0) {
+            return Err(SettingsError::ZeroResolveInterval)
+        }
+
+        if self.snapshot.as_ref().is_some_and(|snapshot| snapshot.interval == Some(0)) {
+            return Err(SettingsError::ZeroSnapshotInterval)
+        }
+
+        if self.mirror.as_ref().is_some_and(|mirror| mirror.collector.is_none() && mirror.pcap.is_none()) {
+            return Err(SettingsError::EmptyMirror)
+        }
+
+        if self.send_devices.iter().any(|dev| dev.weight.is_some_and(|weight| weight <= 0.0)) {
+            return Err(SettingsError::BadWeight("weight"))
+        }
+        if let Some(auto_weights) = &self.auto_weights {
+            if auto_weights.adapt_rate.is_some_and(|rate| rate <= 0.0 || rate > 1.0) {
+                return Err(SettingsError::BadWeight("auto_weights.adapt_rate"))
+            }
+            if auto_weights.min_weight.is_some_and(|min| min <= 0.0) {
+                return Err(SettingsError::BadWeight("auto_weights.min_weight"))
+            }
+            if let (Some(min), Some(max)) = (auto_weights.min_weight, auto_weights.max_weight) {
+                if max < min {
+                    return Err(SettingsError::BadWeight("auto_weights.max_weight"))
+                }
+            }
+        }
+
+        if let Some(path_health) = &self.path_health {
+            if path_health.down_after == Some(0) {
+                return Err(SettingsError::ZeroPathHealthCount("down_after"))
+            }
+            if path_health.up_after == Some(0) {
+                return Err(SettingsError::ZeroPathHealthCount("up_after"))
+            }
+        }
+
+        // Packets as large as the TUN MTU would be dropped by the peer
+        let tun_mtu = self.tun_mtu.unwrap_or(DEFAULT_TUN_MTU);
+        if let Some(max) = self.max_payload_len.filter(|max| *max < tun_mtu) {
+            return Err(SettingsError::MaxPayloadBelowMtu(max, tun_mtu))
+        }
+
+        for dev in &self.send_devices {
+            if let Some(drop_injection) = &dev.drop_injection {
+                if self.allow_drop_injection != Some(true) {
+                    return Err(SettingsError::DropInjectionNotAllowed(dev.name()))
+                }
+                if drop_injection.every == Some(0) || drop_injection.probability.is_some_and(|probability| !(0.0..=1.0).contains(&probability)) {
+                    return Err(SettingsError::BadDropInjection(dev.name()))
+                }
+            }
+            if dev.cpus.as_ref().is_some_and(Vec::is_empty) {
+                return Err(SettingsError::NoCpus(dev.name()))
+            }
+            if dev.send_fraction.is_some_and(|fraction| !(0.0..=1.0).contains(&fraction)) {
+                return Err(SettingsError::BadSendFraction(dev.name()))
+            }
+            if let Some(max) = dev.udp_listen_port_max {
+                if dev.udp_listen_port == 0 || max < dev.udp_listen_port {
+                    return Err(SettingsError::BadPortRange(dev.udp_listen_port, max))
+                }
+            }
+        }
+
+        for paths in self.peer_paths.iter().flat_map(HashMap::values) {
+            for name in paths.send.iter().chain(&paths.receive).flatten() {
+                if !self.send_devices.iter().any(|dev| dev.name() == *name) {
+                    return Err(SettingsError::UnknownDevice(name.clone()))
+                }
+            }
+        }
+
+        if self.redundancy == Some(0) {
+            return Err(SettingsError::ZeroRedundancy)
+        }
+
+        for mapping in self.source_nat.iter().flatten() {
+            if mapping.original.translate(&mapping.original.addr(), &mapping.mapped).is_none() {
+                return Err(SettingsError::MismatchedSourceNat(mapping.original, mapping.mapped))
+            }
+        }
+
+        // Listen ports may be 0 to pick any free port, remote ports can't
+        let has_remote = self.remote_addr.is_some() || self.remote_host.is_some();
+        if has_remote && self.remote_port == 0 {
+            return Err(SettingsError::ZeroPort("remote_port"))
+        }
+
+        let remotes = self.remote_addr.map(|addr| SocketAddr::new(addr, self.remote_port))
+            .into_iter()
+            .chain(self.remote_addrs.iter().flatten().copied());
+        for remote in remotes {
+            if remote.port() == 0 {
+                return Err(SettingsError::ZeroPort("remote_addrs"))
+            }
+            // The relay reaches peers of either family
+            let reachable = self.send_devices.iter().any(|dev| dev.relay.is_some() || match (dev.udp_listen_addr, remote) {
+                (IpAddr::V4(_), SocketAddr::V4(_)) | (IpAddr::V6(_), SocketAddr::V6(_)) => true,
+                (IpAddr::V6(_), SocketAddr::V4(_)) => dev.dual_stack == Some(true),
+                (IpAddr::V4(_), SocketAddr::V6(_)) => false
+            });
+            if !reachable {
+                return Err(SettingsError::UnreachableFamily(remote))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Send devices listening on each of a comma separated list of socket addresses
+fn send_devices_from_env(name: &str, value: &str) -> io::Result<serde_json::Value> {
+    let devices = value.split(',')
+        .map(|addr| {
+            let addr: SocketAddr = addr.trim().parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: `{}`: {}", name, addr, err)))?;
+            Ok(serde_json::json!({ "udp_listen_addr": addr.ip(), "udp_listen_port": addr.port() }))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(serde_json::Value::Array(devices))
+}
+
+/// Builds a `SettingsFile` in code instead of parsing one. Anything not set
+/// is left at its default, and the fields can still be changed on the
+/// result.
+#[derive(Debug, Clone)]
+pub struct SettingsFileBuilder {
+    settings: SettingsFile
+}
+
+impl SettingsFileBuilder {
+    pub fn new(tun_ip: IpAddr) -> SettingsFileBuilder {
+        SettingsFileBuilder {
+            settings: SettingsFile {
+                tun_ip,
+                send_devices: Vec::new(),
+                remote_addr: None,
+                remote_host: None,
+                remote_port: 0,
+                remote_resolve_interval: None,
+                remote_addrs: None,
+                remote_tun_addr: None,
+                insert_remote: None,
+                keep_alive: None,
+                keep_alive_interval: None,
+                keep_alive_interval_ms: None,
+                keep_alive_timeout: None,
+                keep_alive_nat_only: None,
+                keep_alive_while_paused: None,
+                path_health: None,
+                handshake: None,
+                nat_peers: None,
+                nat_rebind_grace: None,
+                path_mode: None,
+                redundancy: None,
+                recovery_ramp_ms: None,
+                fastest: None,
+                auto_weights: None,
+                peer_path_modes: None,
+                peer_paths: None,
+                flow_label: None,
+                new_flow_duplicate_packets: None,
+                max_payload_len: None,
+                copy_dscp: None,
+                dscp_remap: None,
+                pmtud: None,
+                wire_format: None,
+                oversize_policy: None,
+                unparseable_policy: None,
+                address_change_packets: None,
+                encryption: None,
+                peer_keys: None,
+                reorder: None,
+                delivery: None,
+                drain_timeout_ms: None,
+                max_idle: None,
+                max_lifetime: None,
+                max_packet_age_ms: None,
+                slow_down: None,
+                decrement_ttl: None,
+                allowed_destinations: None,
+                blocked_sources: None,
+                source_nat: None,
+                send_batch: None,
+                recv_batch: None,
+                timestamps: None,
+                path_sequence: None,
+                session_epoch: None,
+                checksum: None,
+                tun_mtu: None,
+                tun_queues: None,
+                max_datagram_size: None,
+                backward_jump: None,
+                snapshot: None,
+                capture: None,
+                allow_drop_injection: None,
+                mirror: None,
+                client_timeout: None,
+                max_clients: None,
+                discovery: None,
+                icmp_unreachable: None,
+                unknown_peer_queue: None,
+                hub: None,
+                via_hub: None,
+                tun_broadcast: None
+            }
+        }
+    }
+
+    pub fn tun_ip(mut self, tun_ip: IpAddr) -> SettingsFileBuilder {
+        self.settings.tun_ip = tun_ip;
+        self
+    }
+
+    pub fn add_send_device(mut self, device: SendDevice) -> SettingsFileBuilder {
+        self.settings.send_devices.push(device);
+        self
+    }
+
+    /// Pre-configure the remote at `addr:port`, reached at `tun_addr` inside the tunnel.
+    pub fn remote(mut self, addr: IpAddr, port: u16, tun_addr: IpAddr) -> SettingsFileBuilder {
+        self.settings.remote_addr = Some(addr);
+        self.settings.remote_port = port;
+        self.settings.remote_tun_addr = Some(tun_addr);
+        self
+    }
+
+    /// Whether the pre-configured remote is inserted as a peer at startup.
+    pub fn insert_remote(mut self, insert: bool) -> SettingsFileBuilder {
+        self.settings.insert_remote = Some(insert);
+        self
+    }
+
+    /// Resolve the pre-configured remote's address from `host`.
+    pub fn remote_host(mut self, host: &str) -> SettingsFileBuilder {
+        self.settings.remote_host = Some(host.to_string());
+        self
+    }
+
+    /// Another address of the pre-configured remote, e.g. for a second link.
+    pub fn add_remote_addr(mut self, addr: SocketAddr) -> SettingsFileBuilder {
+        self.settings.remote_addrs.get_or_insert_with(Vec::new).push(addr);
+        self
+    }
+
+    /// Send keep-alives every `interval` seconds.
+    pub fn keep_alive(mut self, interval: u64) -> SettingsFileBuilder {
+        self.settings.keep_alive = Some(true);
+        self.settings.keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Send keep-alives every `interval_ms` milliseconds.
+    pub fn keep_alive_ms(mut self, interval_ms: u64) -> SettingsFileBuilder {
+        self.settings.keep_alive = Some(true);
+        self.settings.keep_alive_interval_ms = Some(interval_ms);
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: u64) -> SettingsFileBuilder {
+        self.settings.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    pub fn path_mode(mut self, mode: PathMode) -> SettingsFileBuilder {
+        self.settings.path_mode = Some(mode);
+        self
+    }
+
+    pub fn redundancy(mut self, links: usize) -> SettingsFileBuilder {
+        self.settings.redundancy = Some(links);
+        self
+    }
+
+    pub fn peer_path_mode(mut self, tun_ip: IpAddr, mode: PathMode) -> SettingsFileBuilder {
+        self.settings.peer_path_modes.get_or_insert_with(HashMap::new).insert(tun_ip, mode);
+        self
+    }
+
+    pub fn peer_paths(mut self, tun_ip: IpAddr, paths: PeerPathSettings) -> SettingsFileBuilder {
+        self.settings.peer_paths.get_or_insert_with(HashMap::new).insert(tun_ip, paths);
+        self
+    }
+
+    pub fn wire_format(mut self, wire_format: WireFormat) -> SettingsFileBuilder {
+        self.settings.wire_format = Some(wire_format);
+        self
+    }
+
+    /// Encrypt with `key`, 64 hex digits.
+    pub fn encryption_key(mut self, key: &str) -> SettingsFileBuilder {
+        self.settings.encryption = Some(EncryptionSettings { key: Some(key.to_string()), key_file: None });
+        self
+    }
+
+    pub fn reorder(mut self, reorder: ReorderSettings) -> SettingsFileBuilder {
+        self.settings.reorder = Some(reorder);
+        self
+    }
+
+    pub fn tun_mtu(mut self, mtu: usize) -> SettingsFileBuilder {
+        self.settings.tun_mtu = Some(mtu);
+        self
+    }
+
+    pub fn tun_queues(mut self, queues: usize) -> SettingsFileBuilder {
+        self.settings.tun_queues = Some(queues);
+        self
+    }
+
+    pub fn max_datagram_size(mut self, size: usize) -> SettingsFileBuilder {
+        self.settings.max_datagram_size = Some(size);
+        self
+    }
+
+    pub fn client_timeout(mut self, timeout: u64) -> SettingsFileBuilder {
+        self.settings.client_timeout = Some(timeout);
+        self
+    }
+
+    pub fn hub(mut self, hub: bool) -> SettingsFileBuilder {
+        self.settings.hub = Some(hub);
+        self
+    }
+
+    pub fn via_hub(mut self, via_hub: bool) -> SettingsFileBuilder {
+        self.settings.via_hub = Some(via_hub);
+        self
+    }
+
+    pub fn allow_drop_injection(mut self, allow: bool) -> SettingsFileBuilder {
+        self.settings.allow_drop_injection = Some(allow);
+        self
+    }
+
+    /// The settings, if they pass `SettingsFile::validate`.
+    pub fn build(self) -> Result<SettingsFile, SettingsError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunBroadcast {
+    Address(Ipv4Addr),
+    // No broadcast address, e.g. for a point-to-point tunnel
+    None
+}
+
+/// How received packets are written to the TUN. Copies of a packet from
+/// other links are dropped in every mode.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    // Held in a reorder buffer, tuned by the reorder settings, and written in sequence order
+    InOrder,
+    // Written as they arrive, out of order if the links reorder them
+    Immediate,
+    // Written only if newer than every packet already written from their
+    // sender, dropping late ones, e.g. for real-time media
+    LatestOnly
+}
+
+/// What to do with packets read from the TUN that are larger than its MTU.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizePolicy {
+    // IPv4 fragment them to the MTU, dropping packets that can't be fragmented
+    #[default]
+    Fragment,
+    Drop
+}
+
+/// What to do with packets read from the TUN that aren't IP packets, or
+/// whose destination can't be parsed out of them.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnparseablePolicy {
+    DropSilently,
+    // Log and count them in tx_unparseable
+    #[default]
+    DropAndCount,
+    // Send them to every known peer, e.g. for ARP-like discovery. Peers with
+    // this policy also deliver such packets, from addresses they know.
+    BroadcastToAllPeers
+}
+
+/// How the receive tasks treat the source addresses of received packets.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiscoveryMode {
+    // Add unknown peers and addresses to the client list
+    #[default]
+    Learn,
+    // Never change the client list, only the pre-configured peers are sent to.
+    // Packets are still delivered whatever their source.
+    Static
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PmtudSettings {
+    // Milliseconds between probes while searching. Defaults to 1000.
+    pub probe_interval_ms: Option<u64>,
+    // Seconds after converging before probing upward again. Defaults to 600.
+    pub reprobe_interval: Option<u64>,
+    pub search: Option<PmtuSearchMode>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PathHealthSettings {
+    // Keep-alives unanswered in a row, on top of keep_alive_timeout, before a link is marked down. Defaults to 3.
+    pub down_after: Option<u32>,
+    // Keep-alives answered in a row before a down link is marked up. Defaults to 2.
+    pub up_after: Option<u32>,
+    // Changes between up and down within flap_window seconds that mark a link flapping. Defaults to 4 in 60.
+    pub flap_transitions: Option<usize>,
+    pub flap_window: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AutoWeightSettings {
+    // Milliseconds between updates of the weights. Defaults to 5000.
+    pub interval_ms: Option<u64>,
+    // Fraction of the way to its target a weight moves per update, above 0
+    // and at most 1. Lower values adapt slower but oscillate less. Defaults to 0.3.
+    pub adapt_rate: Option<f64>,
+    // Bounds of a derived weight, the best link tends to max_weight. Default to 0.05 and 1.
+    pub min_weight: Option<f64>,
+    pub max_weight: Option<f64>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DropInjectionSettings {
+    // Drop every this many-th packet the device sends, e.g. 3 for the 3rd,
+    // 6th and so on. A packet sent to several peer addresses counts once per address.
+    pub every: Option<u64>,
+    // Drop each other packet with this probability, from 0 to 1
+    pub probability: Option<f64>,
+    // Seed of the random drops, so a run can be repeated. Defaults to 0.
+    pub seed: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RelaySettings {
+    // TCP address of the SOCKS5 proxy, or UDP address of the plain relay
+    pub address: SocketAddr,
+    pub protocol: Option<RelayProtocol>
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelayProtocol {
+    // Ask a SOCKS5 proxy without authentication for UDP ASSOCIATE, and keep
+    // its TCP connection open for as long as the device runs
+    #[default]
+    Socks5,
+    // Send datagrams with the SOCKS5 UDP header straight to the relay
+    Plain
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct MirrorSettings {
+    // Address sent one datagram per packet. At least one of collector and pcap must be set.
+    pub collector: Option<SocketAddr>,
+    // Appended to as a pcap file of raw IP packets, with the header written when it is created
+    pub pcap: Option<PathBuf>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HandshakeSettings {
+    // Milliseconds before the first retry, doubled after every unanswered one. Defaults to 500.
+    pub initial_backoff_ms: Option<u64>,
+    // Longest wait between retries in milliseconds. Defaults to 30000.
+    pub max_backoff_ms: Option<u64>,
+    // Hellos sent on a link before giving up on it. Retries forever when unset.
+    pub max_attempts: Option<u32>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotSettings {
+    // Replaced atomically on every write
+    pub path: PathBuf,
+    // Seconds between snapshots. Defaults to 10.
+    pub interval: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CaptureSettings {
+    // Appended to as JSON lines, one record per packet and peer address with
+    // the path, direction, sequence number, timestamp and the packet in hex
+    pub path: PathBuf
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceNatMapping {
+    // Subnets of one family and prefix length, e.g. "10.0.0.0/24" to "10.99.0.0/24"
+    pub original: Cidr,
+    pub mapped: Cidr
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct UnknownPeerQueueSettings {
+    // Packets held per peer, the oldest dropped for newer ones. Defaults to 8.
+    pub max_packets: Option<usize>,
+    // How long a packet is held before it's dropped. Defaults to 1000.
+    pub max_age_ms: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BackwardJumpSettings {
+    // Largest backward step in sequence numbers treated as reordering. Defaults to 16384.
+    pub max_packets: Option<usize>,
+    pub action: Option<BackwardJumpAction>
+}
+
+/// What to do with a packet whose sequence number jumped backward.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackwardJumpAction {
+    // Drop and count it
+    #[default]
+    Drop,
+    // Log it, count it and deliver it anyway
+    Alert
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ReorderSettings {
+    // Most out of order packets held per peer before a gap is skipped. Defaults to 256.
+    pub max_packets: Option<usize>,
+    // Milliseconds to wait for a missing packet before skipping it. Defaults to 50.
+    pub timeout_ms: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerPathSettings {
+    // Devices that carry our packets to the peer. The path mode picks among
+    // these only. All devices when unset.
+    pub send: Option<Vec<String>>,
+    // Devices the peer should send its packets to us on. We announce them to
+    // the peer from each, and it sends to the addresses it heard them from.
+    // All devices when unset.
+    pub receive: Option<Vec<String>>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FastestSettings {
+    // Milliseconds after the fastest link sent a packet that the others send
+    // their copies. Defaults to 20.
+    pub stagger_ms: Option<u64>,
+    // Whether the other links send backup copies at all. Defaults to true.
+    pub backups: Option<bool>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SlowDownSettings {
+    // Drops of one peer's packets within a window that trigger a request. Defaults to 32.
+    pub drop_threshold: Option<u32>,
+    // Length of the window in milliseconds, at most one request per peer is sent in each. Defaults to 1000.
+    pub window_ms: Option<u64>,
+    // Milliseconds the peer is asked to slow down for, at most 60000. Defaults to 2000.
+    pub duration_ms: Option<u64>
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct EncryptionSettings {
+    // 32 byte key as 64 hex digits. Exactly one of key and key_file must be set.
+    pub key: Option<String>,
+    // File containing the key in the same format
+    pub key_file: Option<PathBuf>
+}
+
+// The settings are logged at startup, so the key itself is left out
+impl std::fmt::Debug for EncryptionSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionSettings")
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use super::*;
+
+    fn builder() -> SettingsFileBuilder {
+        SettingsFileBuilder::new([10, 0, 0, 1].into()).add_send_device(SendDevice::new(Ipv4Addr::LOCALHOST.into(), 0))
+    }
+
+    #[test]
+    fn consistent_settings_are_valid() {
+        assert!(builder().remote(Ipv4Addr::LOCALHOST.into(), 5000, [10, 0, 0, 2].into()).keep_alive(5).build().is_ok());
+    }
+
+    #[test]
+    fn a_send_device_is_required() {
+        assert_eq!(SettingsFileBuilder::new([10, 0, 0, 1].into()).build().unwrap_err(), SettingsError::NoSendDevices);
+    }
+
+    #[test]
+    fn keep_alive_requires_a_non_zero_interval() {
+        let mut settings = builder().build().unwrap();
+        settings.keep_alive = Some(true);
+        assert_eq!(settings.validate(), Err(SettingsError::MissingKeepAliveInterval));
+        settings.keep_alive_interval = Some(0);
+        assert_eq!(settings.validate(), Err(SettingsError::ZeroKeepAliveInterval));
+        // Without keep_alive the interval isn't used
+        settings.keep_alive = None;
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn remote_resolve_interval_must_not_be_zero() {
+        let mut settings = builder().build().unwrap();
+        settings.remote_resolve_interval = Some(0);
+        assert_eq!(settings.validate(), Err(SettingsError::ZeroResolveInterval));
+        settings.remote_resolve_interval = Some(1);
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn snapshots_need_a_non_zero_interval() {
+        let mut settings = builder().build().unwrap();
+        settings.snapshot = Some(SnapshotSettings { path: "snapshot.json".into(), interval: Some(0) });
+        assert_eq!(settings.validate(), Err(SettingsError::ZeroSnapshotInterval));
+        settings.snapshot = Some(SnapshotSettings { path: "snapshot.json".into(), interval: None });
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let file = serde_json::json!({
+            "tun_ip": "10.0.0.1",
+            "send_devices": [{ "udp_listen_addr": "127.0.0.1", "udp_listen_port": 5000 }],
+            "remote_port": 5000,
+            "keep_alive": true,
+            "keep_alive_interval": 10
+        });
+        let settings = SettingsFile::from_env_vars(Some(file), vars(&[
+            ("MPTUN_KEEP_ALIVE_INTERVAL", "2"),
+            ("MPTUN_TUN_IP", "10.0.0.9"),
+            ("OTHER_KEEP_ALIVE_INTERVAL", "7")
+        ])).unwrap();
+
+        assert_eq!(settings.keep_alive_interval, Some(2));
+        assert_eq!(settings.tun_ip, IpAddr::from([10, 0, 0, 9]));
+        // Left as the file has them
+        assert_eq!(settings.keep_alive, Some(true));
+        assert_eq!(settings.send_devices[0].udp_listen_port, 5000);
+    }
+
+    #[test]
+    fn environment_variables_alone_make_valid_settings() {
+        let settings = SettingsFile::from_env_vars(None, vars(&[
+            ("MPTUN_TUN_IP", "10.0.0.1"),
+            ("MPTUN_SEND_DEVICES", "127.0.0.1:5000, [::1]:5001"),
+            ("MPTUN_REMOTE_PORT", "6000"),
+            ("MPTUN_REMOTE_ADDR", "192.0.2.1"),
+            ("MPTUN_REMOTE_TUN_ADDR", "10.0.0.2")
+        ])).unwrap();
+
+        assert_eq!(settings.validate(), Ok(()));
+        let devices: Vec<_> = settings.send_devices.iter().map(|device| SocketAddr::new(device.udp_listen_addr, device.udp_listen_port)).collect();
+        assert_eq!(devices, ["127.0.0.1:5000".parse::<SocketAddr>().unwrap(), "[::1]:5001".parse().unwrap()]);
+        assert_eq!((settings.remote_addr, settings.remote_port), (Some([192, 0, 2, 1].into()), 6000));
+        assert_eq!(settings.remote_tun_addr, Some([10, 0, 0, 2].into()));
+    }
+
+    #[test]
+    fn send_devices_may_also_be_json() {
+        let settings = SettingsFile::from_env_vars(None, vars(&[
+            ("MPTUN_TUN_IP", "10.0.0.1"),
+            ("MPTUN_SEND_DEVICES", r#"[{"udp_listen_addr": "127.0.0.1", "udp_listen_port": 5000, "priority": 2}]"#),
+            ("MPTUN_REMOTE_PORT", "6000")
+        ])).unwrap();
+        assert_eq!(settings.send_devices[0].priority, Some(2));
+    }
+
+    #[test]
+    fn malformed_environment_variables_are_refused() {
+        let err = SettingsFile::from_env_vars(None, vars(&[("MPTUN_SEND_DEVICES", "127.0.0.1")])).unwrap_err();
+        assert!(err.to_string().starts_with("MPTUN_SEND_DEVICES: `127.0.0.1`"), "{}", err);
+        // Missing the required fields
+        assert!(SettingsFile::from_env_vars(None, vars(&[("MPTUN_TUN_IP", "10.0.0.1")])).is_err());
+        assert!(SettingsFile::from_env_vars(Some(serde_json::json!([])), vec![]).is_err());
+    }
+
+    #[test]
+    fn listen_port_ranges_must_start_at_one_and_go_up() {
+        let mut settings = builder().build().unwrap();
+        settings.send_devices[0].udp_listen_port = 5000;
+        settings.send_devices[0].udp_listen_port_max = Some(5000);
+        assert_eq!(settings.validate(), Ok(()));
+        settings.send_devices[0].udp_listen_port_max = Some(4999);
+        assert_eq!(settings.validate(), Err(SettingsError::BadPortRange(5000, 4999)));
+        settings.send_devices[0].udp_listen_port = 0;
+        settings.send_devices[0].udp_listen_port_max = Some(10);
+        assert_eq!(settings.validate(), Err(SettingsError::BadPortRange(0, 10)));
+    }
+
+    #[test]
+    fn source_nat_subnets_must_be_alike() {
+        let mut settings = builder().build().unwrap();
+        let mapping = |original: &str, mapped: &str| SourceNatMapping { original: original.parse().unwrap(), mapped: mapped.parse().unwrap() };
+        settings.source_nat = Some(vec![mapping("10.0.0.0/24", "10.99.0.0/24")]);
+        assert_eq!(settings.validate(), Ok(()));
+        settings.source_nat = Some(vec![mapping("10.0.0.0/24", "10.99.0.0/16")]);
+        assert_eq!(settings.validate(), Err(SettingsError::MismatchedSourceNat("10.0.0.0/24".parse().unwrap(), "10.99.0.0/16".parse().unwrap())));
+        settings.source_nat = Some(vec![mapping("10.0.0.0/24", "fd99::/24")]);
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn delivery_follows_reorder_unless_set() {
+        assert_eq!(builder().build().unwrap().delivery_mode(), DeliveryMode::Immediate);
+        let mut settings = builder().reorder(ReorderSettings { max_packets: None, timeout_ms: None }).build().unwrap();
+        assert_eq!(settings.delivery_mode(), DeliveryMode::InOrder);
+        settings.delivery = Some(DeliveryMode::LatestOnly);
+        assert_eq!(settings.delivery_mode(), DeliveryMode::LatestOnly);
+    }
+
+    #[test]
+    fn redundancy_must_be_at_least_one() {
+        assert_eq!(builder().redundancy(0).build().unwrap_err(), SettingsError::ZeroRedundancy);
+        assert!(builder().redundancy(1).build().is_ok());
+    }
+
+    #[test]
+    fn keep_alive_interval_ms_must_not_be_zero_either() {
+        let mut settings = builder().build().unwrap();
+        settings.keep_alive = Some(true);
+        settings.keep_alive_interval_ms = Some(0);
+        assert_eq!(settings.validate(), Err(SettingsError::ZeroKeepAliveInterval));
+        settings.keep_alive_interval_ms = Some(250);
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn keep_alive_interval_ms_takes_precedence_over_seconds() {
+        let settings = builder().keep_alive(5).build().unwrap();
+        assert_eq!(settings.keep_alive_interval(), Some(Duration::from_secs(5)));
+        let settings = builder().keep_alive(5).keep_alive_ms(250).build().unwrap();
+        assert_eq!(settings.keep_alive_interval(), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn remote_ports_must_not_be_zero() {
+        assert_eq!(builder().remote(Ipv4Addr::LOCALHOST.into(), 0, [10, 0, 0, 2].into()).build().unwrap_err(), SettingsError::ZeroPort("remote_port"));
+        let unbound = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        assert_eq!(builder().add_remote_addr(unbound).build().unwrap_err(), SettingsError::ZeroPort("remote_addrs"));
+    }
+
+    #[test]
+    fn every_remote_needs_a_device_of_its_family() {
+        let v6_remote = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 5000);
+        assert_eq!(builder().add_remote_addr(v6_remote).build().unwrap_err(), SettingsError::UnreachableFamily(v6_remote));
+
+        let v4_remote = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5000);
+        let mut v6_device = SendDevice::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+        let v6_only = SettingsFileBuilder::new([10, 0, 0, 1].into()).add_send_device(v6_device.clone());
+        assert_eq!(v6_only.add_remote_addr(v4_remote).build().unwrap_err(), SettingsError::UnreachableFamily(v4_remote));
+        // A dual-stack IPv6 device also reaches IPv4
+        v6_device.dual_stack = Some(true);
+        let dual_stack = SettingsFileBuilder::new([10, 0, 0, 1].into()).add_send_device(v6_device);
+        assert!(dual_stack.add_remote_addr(v4_remote).add_remote_addr(v6_remote).build().is_ok());
+    }
+}
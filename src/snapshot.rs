@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use serde::Serialize;
+
+use crate::handle::{PathInfo, TunnelHandle};
+use crate::stats::Stats;
+
+/// Point in time view of a tunnel, as written to the snapshot file.
+#[derive(Serialize, Debug)]
+pub struct Snapshot<'a> {
+    // Known peer addresses by TUN address
+    pub clients: BTreeMap<IpAddr, Vec<SocketAddr>>,
+    pub paths: Vec<PathInfo>,
+    pub stats: &'a Stats
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn capture(handle: &'a TunnelHandle) -> Snapshot<'a> {
+        Snapshot {
+            clients: handle.clients().into_iter().collect(),
+            paths: handle.paths(),
+            stats: handle.stats()
+        }
+    }
+}
+
+/// Write a JSON snapshot of the tunnel to `path`. The file is written next to
+/// it first and renamed into place, so readers never see a partial snapshot.
+pub async fn write_snapshot(handle: &TunnelHandle, path: &Path) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(&Snapshot::capture(handle))?;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    tokio::fs::write(&temp, json).await?;
+    tokio::fs::rename(&temp, path).await
+}
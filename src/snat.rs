@@ -0,0 +1,223 @@
+// 1:1 source address translation of the inner packets, so subnets that
+// overlap on the two ends can be carried: sources in an original subnet
+// leave as the same host in the mapped one, and packets coming back to the
+// mapped subnet have their destination put back.
+
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+use crate::cidr::Cidr;
+use crate::ipfrag::header_checksum;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMPV6: u8 = 58;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Mappings from original to mapped subnets, of one family and prefix length each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceNat {
+    mappings: Vec<(Cidr, Cidr)>
+}
+
+impl SourceNat {
+    /// Pairs whose subnets differ in family or prefix length never match.
+    pub fn new(mappings: Vec<(Cidr, Cidr)>) -> SourceNat {
+        SourceNat { mappings }
+    }
+
+    /// A copy of `packet`, sent to the tunnel, with its source mapped, or
+    /// `None` if no mapping covers its source.
+    pub fn outbound(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let source = address_at(packet, Address::Source)?;
+        let mapped = self.mappings.iter().find_map(|(original, mapped)| original.translate(&source, mapped))?;
+        Some(rewrite(packet, Address::Source, mapped))
+    }
+
+    /// A copy of `packet`, received from the tunnel, with its destination
+    /// mapped back, or `None` if it isn't for a mapped subnet.
+    pub fn inbound(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let destination = address_at(packet, Address::Destination)?;
+        let original = self.mappings.iter().find_map(|(original, mapped)| mapped.translate(&destination, original))?;
+        Some(rewrite(packet, Address::Destination, original))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Address {
+    Source,
+    Destination
+}
+
+// Offset of the address in the IP header, and its length
+fn address_range(packet: &[u8], address: Address) -> Option<(usize, usize)> {
+    match (packet.first()? >> 4, address) {
+        (4, Address::Source) if packet.len() >= IPV4_HEADER_LEN => Some((12, 4)),
+        (4, Address::Destination) if packet.len() >= IPV4_HEADER_LEN => Some((16, 4)),
+        (6, Address::Source) if packet.len() >= IPV6_HEADER_LEN => Some((8, 16)),
+        (6, Address::Destination) if packet.len() >= IPV6_HEADER_LEN => Some((24, 16)),
+        _ => None
+    }
+}
+
+fn address_at(packet: &[u8], address: Address) -> Option<IpAddr> {
+    let (offset, len) = address_range(packet, address)?;
+    let address = &packet[offset..offset + len];
+    Some(match len {
+        4 => IpAddr::from(<[u8; 4]>::try_from(address).ok()?),
+        _ => IpAddr::from(<[u8; 16]>::try_from(address).ok()?)
+    })
+}
+
+// Replace the address, fixing up the IPv4 header checksum and the TCP, UDP or
+// ICMPv6 checksum, which covers the addresses in its pseudo header. IPv6
+// extension headers aren't looked through, so a transport checksum after
+// one is left as is.
+fn rewrite(packet: &[u8], address: Address, to: IpAddr) -> Vec<u8> {
+    let mut packet = packet.to_vec();
+    let (offset, len) = match address_range(&packet, address) {
+        Some(range) => range,
+        None => return packet
+    };
+    let octets = match to {
+        IpAddr::V4(to) => to.octets().to_vec(),
+        IpAddr::V6(to) => to.octets().to_vec()
+    };
+    let old = packet[offset..offset + len].to_vec();
+    packet[offset..offset + len].copy_from_slice(&octets);
+
+    let transport = match packet[0] >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            if header_len < IPV4_HEADER_LEN || packet.len() < header_len {
+                return packet
+            }
+            packet[10..12].copy_from_slice(&[0, 0]);
+            let checksum = header_checksum(&packet[..header_len]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            // Only the first fragment carries the transport header
+            let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+            Some((packet[9], header_len)).filter(|_| fragment_offset == 0)
+        },
+        _ => Some((packet[6], IPV6_HEADER_LEN))
+    };
+
+    let checksum_at = match transport {
+        Some((TCP, header_len)) => header_len + 16,
+        Some((UDP, header_len)) => header_len + 6,
+        Some((ICMPV6, header_len)) if len == 16 => header_len + 2,
+        _ => return packet
+    };
+    if packet.len() < checksum_at + 2 {
+        return packet
+    }
+    let checksum = u16::from_be_bytes([packet[checksum_at], packet[checksum_at + 1]]);
+    // A zero UDP checksum over IPv4 means there is none
+    let is_udp = transport.is_some_and(|(protocol, _)| protocol == UDP);
+    if is_udp && len == 4 && checksum == 0 {
+        return packet
+    }
+    let mut updated = adjust_checksum(checksum, &old, &octets);
+    if is_udp && updated == 0 {
+        updated = 0xffff;
+    }
+    packet[checksum_at..checksum_at + 2].copy_from_slice(&updated.to_be_bytes());
+    packet
+}
+
+// Incremental update of a ones' complement checksum for `old` replaced by `new` (RFC 1624)
+fn adjust_checksum(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = u32::from(!checksum);
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        sum += u32::from(!u16::from_be_bytes([old[0], old[1]]));
+        sum += u32::from(u16::from_be_bytes([new[0], new[1]]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn nat() -> SourceNat {
+        SourceNat::new(vec![(cidr("10.0.0.0/24"), cidr("10.99.0.0/24")), (cidr("fd00::/64"), cidr("fd99::/64"))])
+    }
+
+    fn udp_v4(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4(source, destination, 64).udp(4000, 5000).write(&mut packet, &[7; 101]).unwrap();
+        packet
+    }
+
+    fn tcp_v4(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4(source, destination, 64).tcp(4000, 5000, 1234, 4096).write(&mut packet, &[7; 100]).unwrap();
+        packet
+    }
+
+    fn udp_v6(source: &str, destination: &str) -> Vec<u8> {
+        let (source, destination): (std::net::Ipv6Addr, std::net::Ipv6Addr) = (source.parse().unwrap(), destination.parse().unwrap());
+        let mut packet = Vec::new();
+        PacketBuilder::ipv6(source.octets(), destination.octets(), 64).udp(4000, 5000).write(&mut packet, &[7; 100]).unwrap();
+        packet
+    }
+
+    // Rewritten packets must equal the ones etherparse builds with the
+    // translated addresses, checksums included
+    #[test]
+    fn ipv4_sources_are_mapped_out_and_destinations_back_in() {
+        let nat = nat();
+        for build in [udp_v4, tcp_v4] {
+            let sent = nat.outbound(&build([10, 0, 0, 5], [192, 168, 1, 1])).unwrap();
+            assert_eq!(sent, build([10, 99, 0, 5], [192, 168, 1, 1]));
+            let received = nat.inbound(&build([192, 168, 1, 1], [10, 99, 0, 5])).unwrap();
+            assert_eq!(received, build([192, 168, 1, 1], [10, 0, 0, 5]));
+            assert!(header_checksum(&received[..20]) == 0);
+        }
+    }
+
+    #[test]
+    fn ipv6_sources_are_mapped_out_and_destinations_back_in() {
+        let nat = nat();
+        assert_eq!(nat.outbound(&udp_v6("fd00::5", "fd42::1")), Some(udp_v6("fd99::5", "fd42::1")));
+        assert_eq!(nat.inbound(&udp_v6("fd42::1", "fd99::5")), Some(udp_v6("fd42::1", "fd00::5")));
+    }
+
+    #[test]
+    fn packets_outside_the_mappings_are_left_alone() {
+        let nat = nat();
+        assert_eq!(nat.outbound(&udp_v4([10, 0, 1, 5], [192, 168, 1, 1])), None);
+        // Only sources are mapped on the way out, destinations on the way in
+        assert_eq!(nat.outbound(&udp_v4([192, 168, 1, 1], [10, 0, 0, 5])), None);
+        assert_eq!(nat.inbound(&udp_v4([10, 99, 0, 5], [192, 168, 1, 1])), None);
+        assert_eq!(nat.outbound(&udp_v6("fd01::5", "fd42::1")), None);
+        assert_eq!(nat.outbound(&[0x45, 0, 0]), None);
+    }
+
+    #[test]
+    fn a_missing_ipv4_udp_checksum_stays_missing() {
+        let mut packet = udp_v4([10, 0, 0, 5], [192, 168, 1, 1]);
+        packet[26..28].copy_from_slice(&[0, 0]);
+        let sent = nat().outbound(&packet).unwrap();
+        assert_eq!(&sent[12..16], &[10, 99, 0, 5]);
+        assert_eq!(&sent[26..28], &[0, 0]);
+        assert!(header_checksum(&sent[..20]) == 0);
+    }
+
+    #[test]
+    fn checksums_are_patched_as_if_recomputed() {
+        let (old, new) = ([10, 0, 0, 5, 1, 2], [10, 99, 0, 5, 3, 4]);
+        let data = |address: &[u8]| [address, &[0x12, 0x34, 0xab, 0xcd][..]].concat();
+        assert_eq!(adjust_checksum(header_checksum(&data(&old)), &old, &new), header_checksum(&data(&new)));
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Serialize, Serializer};
+
+#[derive(Default, Debug, Serialize)]
+pub struct Stats {
+    // Datagrams whose decoded payload exceeded the configured max payload length
+    pub rx_oversized: AtomicU64,
+    // Datagrams that failed decryption, and decrypted ones that couldn't be
+    // deserialized, oversized and version mismatched ones included
+    pub rx_undecryptable: AtomicU64,
+    pub rx_undecodable: AtomicU64,
+    // Encrypted datagrams dropped because they were received before, or are too old to tell
+    pub rx_replayed: AtomicU64,
+    // Datagrams dropped because their checksum didn't match, with checksum set
+    pub rx_bad_checksum: AtomicU64,
+    // Datagrams of another wire format version, or of no version with the versioned format
+    pub rx_version_mismatch: AtomicU64,
+    // Packets read from the TUN that exceeded its MTU
+    pub tun_oversized_fragmented: AtomicU64,
+    pub tun_oversized_dropped: AtomicU64,
+    // Packets the TUN took only part of in one write. The rest was written
+    // separately, which a real TUN would take as another packet.
+    pub tun_short_writes: AtomicU64,
+    // Received packets dropped because their sender's inbound queue was full
+    pub rx_queue_full: AtomicU64,
+    // Requests to slow down sent to peers whose packets were dropped, and received from peers
+    pub slow_downs_sent: AtomicU64,
+    pub slow_downs_received: AtomicU64,
+    // Received packets from unknown peers dropped because max_clients peers were known
+    pub rx_clients_rejected: AtomicU64,
+    // Datagrams dropped because their source address is blocked
+    pub rx_blocked: AtomicU64,
+    // Destination unreachable messages written back to the TUN for packets without a peer
+    pub tun_unreachable: AtomicU64,
+    // Destination unreachables left out over their rate limit
+    pub tun_unreachable_limited: AtomicU64,
+    // Packets from the TUN held until their peer was discovered, and those sent once it was
+    pub tun_held: AtomicU64,
+    pub tun_held_released: AtomicU64,
+    // Packets currently held in reorder buffers
+    pub reorder_depth: AtomicU64,
+    // Gaps skipped because a reorder buffer was full, or waited too long
+    pub reorder_overflows: AtomicU64,
+    pub reorder_timeouts: AtomicU64,
+    // Packets dropped with LatestOnly delivery for arriving after a newer one from their sender
+    pub rx_late_dropped: AtomicU64,
+    // Per-peer entries held by the tunnel tasks (dedup windows, reorder buffers, sequence guards, session epochs and roaming trackers)
+    pub peer_states: AtomicU64,
+    // Received packets dropped because the inbound sink was full or gone
+    pub sink_dropped: AtomicU64,
+    // Fragmented messages dropped before all their fragments arrived
+    pub rx_reassembly_timeouts: AtomicU64,
+    // Received packets whose sequence number jumped far backward
+    pub rx_backward_jumps: AtomicU64,
+    // Received packets from a peer's session before its restart, with session_epoch
+    pub rx_stale_epoch: AtomicU64,
+    // Received packets of a session epoch not confirmed yet, see `SessionEpochs`
+    pub rx_unconfirmed_epoch: AtomicU64,
+    // Packets to send dropped because their destination couldn't be parsed,
+    // decrementing their TTL / hop limit took it to zero, or their
+    // destination isn't in allowed_destinations. Counted once, not per path.
+    pub tx_unparseable: AtomicU64,
+    pub tx_ttl_expired: AtomicU64,
+    pub tx_filtered: AtomicU64,
+    // Packets relayed between peers in hub mode
+    pub forwarded: AtomicU64,
+    // Bytes of the packets read from the TUN and handed to the send tasks,
+    // and of those delivered after dropping the copies from other paths.
+    // Each packet counts once, however many paths carry it.
+    pub tx_goodput_bytes: AtomicU64,
+    pub rx_goodput_bytes: AtomicU64,
+    // Data packets and their bytes exchanged with each peer
+    pub peers: PeerStats,
+}
+
+impl Stats {
+    /// The counts since `last` was last passed in, whose counters then
+    /// catch up to these. Every increment counts in exactly one call, also
+    /// one made while the call reads. The reorder_depth and peer_states
+    /// gauges are their current values.
+    pub fn since(&self, last: &Stats) -> Stats {
+        Stats {
+            rx_oversized: since(&self.rx_oversized, &last.rx_oversized),
+            rx_undecryptable: since(&self.rx_undecryptable, &last.rx_undecryptable),
+            rx_undecodable: since(&self.rx_undecodable, &last.rx_undecodable),
+            rx_replayed: since(&self.rx_replayed, &last.rx_replayed),
+            rx_bad_checksum: since(&self.rx_bad_checksum, &last.rx_bad_checksum),
+            rx_version_mismatch: since(&self.rx_version_mismatch, &last.rx_version_mismatch),
+            tun_oversized_fragmented: since(&self.tun_oversized_fragmented, &last.tun_oversized_fragmented),
+            tun_oversized_dropped: since(&self.tun_oversized_dropped, &last.tun_oversized_dropped),
+            tun_short_writes: since(&self.tun_short_writes, &last.tun_short_writes),
+            rx_queue_full: since(&self.rx_queue_full, &last.rx_queue_full),
+            slow_downs_sent: since(&self.slow_downs_sent, &last.slow_downs_sent),
+            slow_downs_received: since(&self.slow_downs_received, &last.slow_downs_received),
+            rx_clients_rejected: since(&self.rx_clients_rejected, &last.rx_clients_rejected),
+            rx_blocked: since(&self.rx_blocked, &last.rx_blocked),
+            tun_unreachable: since(&self.tun_unreachable, &last.tun_unreachable),
+            tun_unreachable_limited: since(&self.tun_unreachable_limited, &last.tun_unreachable_limited),
+            tun_held: since(&self.tun_held, &last.tun_held),
+            tun_held_released: since(&self.tun_held_released, &last.tun_held_released),
+            reorder_depth: AtomicU64::new(self.reorder_depth.load(Ordering::Relaxed)),
+            reorder_overflows: since(&self.reorder_overflows, &last.reorder_overflows),
+            reorder_timeouts: since(&self.reorder_timeouts, &last.reorder_timeouts),
+            rx_late_dropped: since(&self.rx_late_dropped, &last.rx_late_dropped),
+            peer_states: AtomicU64::new(self.peer_states.load(Ordering::Relaxed)),
+            sink_dropped: since(&self.sink_dropped, &last.sink_dropped),
+            rx_reassembly_timeouts: since(&self.rx_reassembly_timeouts, &last.rx_reassembly_timeouts),
+            rx_backward_jumps: since(&self.rx_backward_jumps, &last.rx_backward_jumps),
+            rx_stale_epoch: since(&self.rx_stale_epoch, &last.rx_stale_epoch),
+            rx_unconfirmed_epoch: since(&self.rx_unconfirmed_epoch, &last.rx_unconfirmed_epoch),
+            tx_unparseable: since(&self.tx_unparseable, &last.tx_unparseable),
+            tx_ttl_expired: since(&self.tx_ttl_expired, &last.tx_ttl_expired),
+            tx_filtered: since(&self.tx_filtered, &last.tx_filtered),
+            forwarded: since(&self.forwarded, &last.forwarded),
+            tx_goodput_bytes: since(&self.tx_goodput_bytes, &last.tx_goodput_bytes),
+            rx_goodput_bytes: since(&self.rx_goodput_bytes, &last.rx_goodput_bytes),
+            peers: self.peers.since(&last.peers)
+        }
+    }
+}
+
+/// Data packets sent to and received from one peer over all paths. Bytes
+/// are of the inner packets, without the tunnel's overhead.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PeerTraffic {
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64
+}
+
+#[derive(Default, Debug)]
+struct PeerCounters {
+    // Tells counters of a peer that was removed and came back from the old ones
+    id: u64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64
+}
+
+/// Counters kept per peer TUN IP. A copy sent on each of several paths
+/// counts once per copy, like the path counters. Packets sent to every
+/// peer at once, without a destination to parse, aren't counted. A peer's
+/// counters go with it when it's removed.
+#[derive(Default, Debug)]
+pub struct PeerStats {
+    peers: RwLock<HashMap<IpAddr, PeerCounters>>,
+    next_id: AtomicU64
+}
+
+impl PeerStats {
+    pub fn sent(&self, tun_ip: IpAddr, bytes: usize) {
+        self.with(tun_ip, |counters| {
+            counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+            counters.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        });
+    }
+
+    pub fn received(&self, tun_ip: IpAddr, bytes: usize) {
+        self.with(tun_ip, |counters| {
+            counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+            counters.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        });
+    }
+
+    // Known peers are counted under the read lock, only new ones take the write lock
+    fn with(&self, tun_ip: IpAddr, f: impl FnOnce(&PeerCounters)) {
+        if let Some(counters) = self.peers.read().unwrap().get(&tun_ip) {
+            return f(counters)
+        }
+        f(self.peers.write().unwrap().entry(tun_ip).or_insert_with(|| PeerCounters { id: self.next_id.fetch_add(1, Ordering::Relaxed), ..PeerCounters::default() }))
+    }
+
+    pub fn forget(&self, tun_ip: &IpAddr) {
+        self.peers.write().unwrap().remove(tun_ip);
+    }
+
+    /// The counts of each known peer since `last` was last passed in, see
+    /// `Stats::since`. Peers gone since are left out of `last` too, and
+    /// one that came back since counts from zero.
+    pub fn since(&self, last: &PeerStats) -> PeerStats {
+        let peers = self.peers.read().unwrap();
+        let mut last = last.peers.write().unwrap();
+        last.retain(|tun_ip, last| peers.get(tun_ip).is_some_and(|counters| counters.id == last.id));
+        let since = peers.iter()
+            .map(|(tun_ip, counters)| {
+                let last = last.entry(*tun_ip).or_insert_with(|| PeerCounters { id: counters.id, ..PeerCounters::default() });
+                (*tun_ip, PeerCounters {
+                    id: counters.id,
+                    tx_packets: since(&counters.tx_packets, &last.tx_packets),
+                    tx_bytes: since(&counters.tx_bytes, &last.tx_bytes),
+                    rx_packets: since(&counters.rx_packets, &last.rx_packets),
+                    rx_bytes: since(&counters.rx_bytes, &last.rx_bytes)
+                })
+            })
+            .collect();
+        PeerStats { peers: RwLock::new(since), next_id: AtomicU64::new(0) }
+    }
+
+    pub fn snapshot(&self) -> HashMap<IpAddr, PeerTraffic> {
+        self.peers.read().unwrap().iter()
+            .map(|(tun_ip, counters)| (*tun_ip, PeerTraffic {
+                tx_packets: counters.tx_packets.load(Ordering::Relaxed),
+                tx_bytes: counters.tx_bytes.load(Ordering::Relaxed),
+                rx_packets: counters.rx_packets.load(Ordering::Relaxed),
+                rx_bytes: counters.rx_bytes.load(Ordering::Relaxed)
+            }))
+            .collect()
+    }
+}
+
+impl Serialize for PeerStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+/// Counters kept per send device.
+#[derive(Default, Debug)]
+pub struct PathCounters {
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub keepalives_sent: AtomicU64,
+    pub keepalive_replies: AtomicU64,
+    // Packets dropped for waiting longer than max_packet_age_ms to be sent
+    pub tx_stale: AtomicU64,
+    // Packets sent as several fragments because of max_datagram_size
+    pub tx_fragmented: AtomicU64,
+    // Packets dropped because they couldn't be serialized for the wire
+    pub tx_encode_errors: AtomicU64,
+    // Packets dropped from the front of the path's send queue to make room,
+    // because the link couldn't keep up
+    pub tx_queue_dropped: AtomicU64,
+    // Packets dropped by drop_injection instead of being sent
+    pub tx_injected_drops: AtomicU64,
+}
+
+impl PathCounters {
+    /// Fraction of keep-alives that went unanswered, if any were sent.
+    pub fn keepalive_loss(&self) -> Option<f64> {
+        let sent = self.keepalives_sent.load(Ordering::Relaxed);
+        let replies = self.keepalive_replies.load(Ordering::Relaxed);
+        if sent == 0 {
+            return None
+        }
+        Some(1.0 - (replies.min(sent) as f64 / sent as f64))
+    }
+}
+
+// The increase of `now` since `last`, which moves up to it. Of two calls
+// racing on one `last`, the one that read less counts nothing.
+fn since(now: &AtomicU64, last: &AtomicU64) -> AtomicU64 {
+    let now = now.load(Ordering::Relaxed);
+    let last = last.fetch_max(now, Ordering::Relaxed);
+    AtomicU64::new(now.saturating_sub(last))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+
+    fn peer(last: u8) -> IpAddr {
+        [10, 0, 0, last].into()
+    }
+
+    #[test]
+    fn counts_since_the_last_call_leave_the_totals_alone() {
+        let (stats, last) = (Stats::default(), Stats::default());
+        stats.rx_blocked.fetch_add(3, Ordering::Relaxed);
+        stats.reorder_depth.store(7, Ordering::Relaxed);
+        stats.peers.sent(peer(2), 100);
+
+        let first = stats.since(&last);
+        assert_eq!(first.rx_blocked.load(Ordering::Relaxed), 3);
+        assert_eq!(first.reorder_depth.load(Ordering::Relaxed), 7);
+        assert_eq!(first.peers.snapshot()[&peer(2)], PeerTraffic { tx_packets: 1, tx_bytes: 100, rx_packets: 0, rx_bytes: 0 });
+
+        stats.rx_blocked.fetch_add(1, Ordering::Relaxed);
+        let second = stats.since(&last);
+        assert_eq!(second.rx_blocked.load(Ordering::Relaxed), 1);
+        // Gauges are what they are now, not a change
+        assert_eq!(second.reorder_depth.load(Ordering::Relaxed), 7);
+        assert_eq!(second.peers.snapshot()[&peer(2)], PeerTraffic::default());
+        assert_eq!(stats.rx_blocked.load(Ordering::Relaxed), 4);
+
+        // Another reader starts from the totals
+        assert_eq!(stats.since(&Stats::default()).rx_blocked.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn a_peer_that_came_back_counts_from_zero() {
+        let (stats, last) = (Stats::default(), Stats::default());
+        for _ in 0..5 {
+            stats.peers.received(peer(2), 10);
+        }
+        stats.since(&last);
+
+        stats.peers.forget(&peer(2));
+        stats.peers.received(peer(2), 10);
+        assert_eq!(stats.since(&last).peers.snapshot()[&peer(2)], PeerTraffic { tx_packets: 0, tx_bytes: 0, rx_packets: 1, rx_bytes: 10 });
+    }
+
+    #[test]
+    fn increments_made_while_reading_count_once() {
+        const THREADS: u64 = 4;
+        const INCREMENTS: u64 = 20_000;
+        let (stats, last) = (Arc::new(Stats::default()), Stats::default());
+        let counters: Vec<_> = (0..THREADS).map(|_| {
+            let stats = stats.clone();
+            std::thread::spawn(move || for _ in 0..INCREMENTS {
+                stats.forwarded.fetch_add(1, Ordering::Relaxed);
+                stats.peers.sent(peer(2), 1);
+            })
+        }).collect();
+
+        let (mut forwarded, mut sent) = (0, 0);
+        let mut read = || {
+            let since = stats.since(&last);
+            forwarded += since.forwarded.load(Ordering::Relaxed);
+            sent += since.peers.snapshot().get(&peer(2)).map_or(0, |traffic| traffic.tx_packets);
+        };
+        while !counters.iter().all(|counter| counter.is_finished()) {
+            read();
+        }
+        read();
+        assert_eq!((forwarded, sent), (THREADS * INCREMENTS, THREADS * INCREMENTS));
+    }
+}
@@ -1,207 +1,2117 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
-use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::net::{SocketAddr,
-               IpAddr};
+               IpAddr,
+               Ipv4Addr,
+               Ipv6Addr};
 use etherparse::{SlicedPacket, InternetSlice};
-use std::time::Duration;
-use tokio::time;
-use tokio::{net::UdpSocket};
-use lz4_flex::{compress_prepend_size, decompress_size_prepended};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio::net::UdpSocket;
+use socket2::SockRef;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::ErrorKind;
+use lz4_flex::decompress_size_prepended;
+use lz4_flex::block::uncompressed_size;
+use bytes::{Bytes, BytesMut};
+use futures::future::join_all;
 
-use crate::messages::{Packet, Keepalive, Messages};
+use crate::messages::{self, Packet, Messages, MessagesRef, PathStamp, WireFormat};
+use crate::stats::Stats;
+use crate::path::Path;
+use crate::settings::{DiscoveryMode, OversizePolicy, PathMode, UnparseablePolicy};
+use crate::ipfrag;
+use crate::icmp;
+use crate::flowcontrol::FlowControl;
+use crate::jitter;
+use crate::transport::{Families, Transport};
+use crate::clients::{self, Clients};
+use crate::cidr::Cidr;
+use crate::batch::{RecvBatch, SendBatch, SendRecord};
+use crate::control::{ControlMessage, ControlMessages};
+use crate::roaming::{AddressTracker, AddressUpdate};
+use crate::clock::{Interval, SharedClock};
+use crate::events::{DropReason, Event, Events, PacketEvent, PacketEvents, SendResult};
+use crate::nat::{self, NatPeers};
+use crate::liveness::LastSeen;
+use crate::hub::Forwarder;
+use crate::crypto::{Cipher, KeyId, Keys, ReplayWindow, ENCRYPTION_OVERHEAD, NONCE_LEN};
+use crate::checksum::{self, CHECKSUM_LEN};
+use crate::datagram::{compress_prepend_size_into, DatagramEncoder, EncodeError, Framing};
+use crate::fragment::{self, FragmentError, Reassembler};
+use crate::dedup::DedupWindow;
+use crate::inbound::InboundQueues;
+use crate::capture::{Capture, CaptureRecord, Direction};
+use crate::mirror::Mirror;
+use crate::pending::PendingPackets;
+use crate::dispatch::{Dispatcher, Route};
+use crate::mtu::Mtus;
+use crate::echo::Echoes;
+use crate::returnpath::ReturnPaths;
+use crate::pathqueue::{BackupQueue, PathQueue, PATH_QUEUE_CAPACITY};
+use crate::dropinject::DropInjector;
+use crate::blocklist::Blocklist;
+use crate::snat::SourceNat;
+use crate::reorder::{ReorderBuffer, ReorderConfig};
+use crate::tun::{InboundDelivery, InboundSink};
+use crate::seqguard::{EpochCheck, SeqCheck, SeqGuard, SeqGuardConfig, SessionEpochs};
+use crate::settings::BackwardJumpAction;
+use crate::flowlabel::{self, FlowLabelMode};
+use crate::ratelimit::{LogThrottle, TokenBucket};
 
-// This must always be large enough to:
-// 1. Receive a full IP packet from the tun
-// 2. Receive a full UDP datagram
-const RECV_BUFFER_SIZE: usize = 65535;
+// Room left for every TUN read: the largest possible IP packet, since the
+// host may hand over packets larger than the MTU (e.g. with GSO)
+const TUN_READ_SIZE: usize = 65535;
 
-pub async fn read_tun(mut tun_reader: ReadHalf<tokio_tun::Tun>, chan_sender: tokio::sync::broadcast::Sender<Packet>) {
+// TUN packets are read into chunks of this size and split off as `Bytes`.
+// A chunk's allocation is reused once all packets cut from it are dropped.
+const TUN_READ_CHUNK_SIZE: usize = 16 * TUN_READ_SIZE;
+
+// Largest UDP payload
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+// Flow label leases remembered per send socket before the cache is reset
+const MAX_FLOW_LABEL_LEASES: usize = 4096;
+
+// Garbage datagrams are logged at most this often per path, with a count of those left out
+const GARBAGE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// Destination unreachables written back to the TUN at most, in bits per
+// second, as a router limits its ICMP errors
+const UNREACHABLE_BPS: u64 = 1_000_000;
+
+/// Settings used by the per-socket tasks, derived once from the `SettingsFile`.
+#[derive(Debug, Clone)]
+pub struct TaskConfig {
+    pub path_mode: PathMode,
+    // Links redundant mode sends each packet over, all when unset
+    pub redundancy: Option<usize>,
+    // Time a recovered link takes to carry all of the redundant traffic again
+    pub recovery_ramp: Option<Duration>,
+    // Time the other links give the fastest one in fastest mode before
+    // sending their copies. None sends no copies.
+    pub backup_delay: Option<Duration>,
+    // Overrides of path_mode by destination TUN IP
+    pub peer_path_modes: Arc<HashMap<IpAddr, PathMode>>,
+    // Names of the only paths that send to these peers, by TUN IP
+    pub peer_send_paths: Arc<HashMap<IpAddr, Vec<String>>>,
+    // Addresses peers asked their packets to be sent to
+    pub return_paths: Arc<ReturnPaths>,
+    // Leading packets of each new flow sent on all links in failover mode
+    pub new_flow_duplicate_packets: Option<u32>,
+    pub flow_label: Option<FlowLabelMode>,
+    // Inner to outer DSCP table, when copying DSCP is enabled
+    pub dscp_remap: Option<Arc<HashMap<u8, u8>>>,
+    pub max_payload_len: usize,
+    pub wire_format: WireFormat,
+    // Append a CRC-32 to each datagram, and drop those received without a matching one
+    pub checksum: bool,
+    // Our TUN MTU, following changes, and the MTUs peers announced
+    pub mtus: Arc<Mtus>,
+    // Echo requests of `probe_path` waiting for their reply
+    pub echoes: Arc<Echoes>,
+    pub oversize_policy: OversizePolicy,
+    pub unparseable_policy: UnparseablePolicy,
+    // Consecutive packets from a new source address before it replaces the old one
+    pub address_change_packets: Option<u32>,
+    pub keys: Keys,
+    // Put received packets back in order before writing them to the TUN
+    pub reorder: Option<ReorderConfig>,
+    // Packets queued longer than this are dropped instead of sent
+    pub max_packet_age: Option<Duration>,
+    // Messages encoding to more than this many bytes are sent as fragments
+    pub max_datagram_size: Option<usize>,
+    pub decrement_ttl: bool,
+    // Destination subnets packets are carried for, all when unset
+    pub allowed_destinations: Option<Arc<Vec<Cidr>>>,
+    // Source addresses rewritten on the way out, and destinations back on the way in
+    pub source_nat: Option<Arc<SourceNat>>,
+    // Packets sent per sendmmsg call, when batching
+    pub send_batch: Option<usize>,
+    // Datagrams received per recvmmsg call, when batching
+    pub recv_batch: Option<usize>,
+    pub discovery: DiscoveryMode,
+    // Peers learned at most, further ones are rejected
+    pub max_clients: Option<usize>,
+    // Silence after which a peer address is dropped for a newer NAT mapping on the same IP
+    pub nat_rebind_grace: Duration,
+    // Stamp data packets with the send time
+    pub timestamps: bool,
+    // Stamp data packets with the path id and path sequence number
+    pub path_sequence: bool,
+    // Stamp data packets with this epoch of the current run
+    pub session_epoch: Option<u32>,
+    // Peer to send to when the destination TUN IP has no known peer
+    pub fallback_peer: Option<IpAddr>,
+    // Our own TUN address
+    pub tun_ip: IpAddr,
+    // Answer packets without a known peer with a destination unreachable
+    pub icmp_unreachable: bool
+}
+
+/// Settings for a keep-alive task.
+#[derive(Debug, Clone)]
+pub struct KeepAliveConfig {
+    pub interval: Duration,
+    // Time without a reply before the path is marked down
+    pub timeout: Duration,
+    pub wire_format: WireFormat,
+    pub checksum: bool,
+    // Only ping peers behind a NAT
+    pub nat_only: bool,
+    // Keep pinging while the path is paused
+    pub while_paused: bool,
+    pub keys: Keys
+}
+
+/// Settings for a handshake task greeting the pre-configured remote.
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub remote: IpAddr,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: Option<u32>,
+    pub wire_format: WireFormat,
+    pub checksum: bool,
+    pub keys: Keys
+}
+
+/// Settings for a path MTU probing task.
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub interval: Duration,
+    pub wire_format: WireFormat,
+    pub checksum: bool,
+    pub keys: Keys
+}
+
+/// Settings for the task writing received packets to the TUN.
+#[derive(Debug, Clone)]
+pub struct DeliveryConfig {
+    // Sequence numbers remembered per peer to suppress copies from other links
+    pub dedup_window: usize,
+    pub reorder: Option<ReorderConfig>,
+    // Drop packets behind the newest delivered from their sender
+    pub latest_only: bool,
+    pub seq_guard: Option<SeqGuardConfig>,
+    pub sink: Option<InboundSink>,
+    pub mirror: Mirror
+}
+
+/// A packet read from the TUN, on its way to the send tasks.
+#[derive(Debug, Clone)]
+pub struct TunPacket {
+    pub packet: Packet,
+    pub read_at: Instant,
+    pub route: Route,
+    // When a backup copy in fastest mode is due, once the fastest link has had its head start
+    pub backup_at: Option<Instant>
+}
+
+/// Sequence numbers for packets read from the TUN, shared by the readers of
+/// a multi-queue TUN so each number is used once.
+#[derive(Debug, Default)]
+pub struct TunSequence {
+    next: AtomicUsize,
+    // Destination unreachables are queued as if from our own TUN IP, numbered on their own
+    next_unreachable: AtomicUsize
+}
+
+impl TunSequence {
+    pub fn next(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn next_unreachable(&self) -> usize {
+        self.next_unreachable.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Reads packets from the TUN, each split off a shared chunk, see `TUN_READ_CHUNK_SIZE`.
+#[derive(Debug)]
+pub struct PacketReader {
+    chunk: BytesMut
+}
+
+impl Default for PacketReader {
+    fn default() -> PacketReader {
+        PacketReader { chunk: BytesMut::with_capacity(TUN_READ_CHUNK_SIZE) }
+    }
+}
+
+impl PacketReader {
+    /// The next packet, or `None` once the device is closed.
+    pub async fn read(&mut self, tun_reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Bytes>> {
+        loop {
+            // Always leave room for the largest possible IP packet, so reads are never truncated
+            self.chunk.reserve(TUN_READ_SIZE);
+            match tun_reader.read_buf(&mut self.chunk).await {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(self.chunk.split().freeze())),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn read_tun(mut tun_reader: impl AsyncRead + Unpin, sequence: Arc<TunSequence>, dispatcher: Arc<Dispatcher>, stats: Arc<Stats>, clock: SharedClock, config: TaskConfig, events: Events, client_list: Arc<Clients>, inbound: Arc<InboundQueues>, pending: Option<Arc<PendingPackets>>) {
     println!("Started [read_tun task]");
-    let mut seq: usize = 0;
-    let mut buf = [0u8; RECV_BUFFER_SIZE];
+    let mut reader = PacketReader::default();
+    let mut unreachables = TokenBucket::new(UNREACHABLE_BPS);
 
     loop {
+        let bytes = match reader.read(&mut tun_reader).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => {
+                println!("TUN device closed, stopping [read_tun task]");
+                return
+            },
+            Err(err) => {
+                eprintln!("Failed to read from the TUN, stopping [read_tun task]: {}", err);
+                events.emit(Event::TunDown { error: err.to_string() });
+                return
+            }
+        };
+        let n = bytes.len();
+        let read_at = clock.now();
+
+        let destination = icmp::destination(&bytes);
+        // Only looked up when unreachables are answered or packets held
+        let known = |tun_ip: &IpAddr| client_list.contains(tun_ip);
+        let unknown_destination = destination
+            .filter(|_| config.icmp_unreachable || pending.is_some())
+            .filter(|destination| !known(destination) && !config.fallback_peer.as_ref().is_some_and(known));
+
+        // Over the limit, the packet is dropped without an answer
+        if config.icmp_unreachable && unknown_destination.is_some() {
+            if !unreachables.has_tokens(icmp::MAX_UNREACHABLE_LEN, read_at) {
+                stats.tun_unreachable_limited.fetch_add(1, Ordering::Relaxed);
+                continue
+            }
+            if let Some(reply) = icmp::unreachable(&bytes, config.tun_ip) {
+                unreachables.try_consume(reply.len(), read_at);
+                stats.tun_unreachable.fetch_add(1, Ordering::Relaxed);
+                inbound.push(config.tun_ip, Packet { seq: sequence.next_unreachable(), bytes: Bytes::from(reply) }, None, None);
+                continue
+            }
+        }
 
-        let n = tun_reader.read(&mut buf).await.unwrap();
+        // The host may hand us packets larger than the TUN MTU (e.g. with GSO),
+        // and a peer may take less than that
+        let mtu = config.mtus.for_peer(destination.as_ref());
+        if n > mtu {
+            let fragments = match config.oversize_policy {
+                OversizePolicy::Fragment => ipfrag::fragment_ipv4(&bytes, mtu),
+                OversizePolicy::Drop => None
+            };
+
+            match fragments {
+                Some(fragments) => {
+                    stats.tun_oversized_fragmented.fetch_add(1, Ordering::Relaxed);
+                    for fragment in fragments {
+                        // See below for why a failed send is ignored
+                        let len = fragment.len();
+                        if dispatcher.dispatch(Packet{ seq: sequence.next(), bytes: Bytes::from(fragment) }, read_at) {
+                            stats.tx_goodput_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                        }
+                    }
+                },
+                None => {
+                    stats.tun_oversized_dropped.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("Dropping {} byte packet from the TUN, larger than the MTU of {}", n, mtu);
+                    events.emit(Event::PacketDropped { reason: DropReason::TunOversized });
+                }
+            }
+            continue
+        }
+
+        // Held until the peer is discovered. Group destinations have no single peer to wait for.
+        let held_for = pending.as_ref()
+            .and_then(|pending| unknown_destination.map(|destination| (pending, destination)))
+            .filter(|(_, destination)| !destination.is_multicast() && *destination != IpAddr::V4(Ipv4Addr::BROADCAST));
+        if let Some((pending, destination)) = held_for {
+            if pending.hold(destination, Packet { seq: sequence.next(), bytes }, read_at) {
+                stats.tun_held.fetch_add(1, Ordering::Relaxed);
+            }
+            continue
+        }
 
         let pkt = Packet{
-            seq,
-            bytes: buf[..n].to_vec()
+            seq: sequence.next(),
+            bytes
         };
-        seq = seq + 1;
 
         //println!("Tunnel bytes: {:?}", pkt.bytes);
 
-        chan_sender.send(pkt).unwrap();
+        // Also fails while no send task is subscribed, e.g. a reload removed
+        // every device. New devices subscribe to the same channel, so keep
+        // reading and drop the packet like a link that's down would.
+        let len = pkt.bytes.len();
+        if dispatcher.dispatch(pkt, read_at) {
+            stats.tx_goodput_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        }
     }
 }
 
-pub async fn send_tun(mut tun_sender: WriteHalf<tokio_tun::Tun>, mut chan_receiver: tokio::sync::mpsc::UnboundedReceiver::<Packet>) {
+pub async fn send_tun(mut tun_sender: impl AsyncWrite + Unpin, inbound: Arc<InboundQueues>, stats: Arc<Stats>, clock: SharedClock, config: DeliveryConfig, mut peer_removals: broadcast::Receiver<IpAddr>, events: Events) {
     println!("Started [send_tun task]");
-    let mut seq: usize = 0;
+    // Sequence numbers are per sender, so are the windows
+    let mut delivered: HashMap<IpAddr, DedupWindow> = HashMap::new();
+    // One buffer per peer, sequence numbers are per sender
+    let mut buffers: HashMap<IpAddr, ReorderBuffer> = HashMap::new();
+    let mut guards: HashMap<IpAddr, SeqGuard> = HashMap::new();
+    let mut epochs: HashMap<IpAddr, SessionEpochs> = HashMap::new();
+    let mut ready: Vec<Packet> = Vec::new();
+    // Set once the inbound queues are closed and empty
+    let mut closed = false;
     loop {
-        let packet = chan_receiver.recv().await.unwrap();
+        let deadline = buffers.values().filter_map(ReorderBuffer::deadline).min();
+        let gap_timeout = async {
+            match deadline {
+                Some(deadline) => clock.sleep_until(deadline).await,
+                None => futures::future::pending().await
+            }
+        };
+        let (received, epoch, via) = match tokio::select! {
+            received = inbound.pop() => {
+                closed = received.is_none();
+                received
+            },
+            _ = gap_timeout => None,
+            removed = next_peer_removal(&mut peer_removals) => {
+                let freed = match removed {
+                    Some(tun_ip) => {
+                        buffers.remove(&tun_ip).map_or(0, |_| 1)
+                            + guards.remove(&tun_ip).map_or(0, |_| 1)
+                            + delivered.remove(&tun_ip).map_or(0, |_| 1)
+                            + epochs.remove(&tun_ip).map_or(0, |_| 1)
+                    },
+                    None => buffers.drain().count() + guards.drain().count() + delivered.drain().count() + epochs.drain().count()
+                };
+                stats.peer_states.fetch_sub(freed as u64, Ordering::Relaxed);
+                continue
+            }
+        } {
+            Some((source, packet, epoch, via)) => (Some((source, packet)), epoch, via),
+            None => (None, None, None)
+        };
+
+        // A restarted peer numbers its packets from 0 again, which its old
+        // session's dedup window and reorder buffer would hold back
+        if let (Some((source, packet)), Some(epoch)) = (&received, epoch) {
+            let epochs = epochs.entry(*source).or_insert_with(|| {
+                stats.peer_states.fetch_add(1, Ordering::Relaxed);
+                SessionEpochs::default()
+            });
+            match epochs.observe(epoch, packet.seq) {
+                EpochCheck::Current => {},
+                EpochCheck::New => {
+                    println!("Peer {} started a new session", source);
+                    let mut freed = guards.remove(source).map_or(0, |_| 1) + delivered.remove(source).map_or(0, |_| 1);
+                    if let Some(mut buffer) = buffers.remove(source) {
+                        buffer.flush(&mut ready);
+                        freed += 1;
+                    }
+                    stats.peer_states.fetch_sub(freed, Ordering::Relaxed);
+                },
+                EpochCheck::Stale => {
+                    stats.rx_stale_epoch.fetch_add(1, Ordering::Relaxed);
+                    continue
+                },
+                EpochCheck::Unconfirmed => {
+                    stats.rx_unconfirmed_epoch.fetch_add(1, Ordering::Relaxed);
+                    continue
+                }
+            }
+        }
+
+        if let (Some((source, packet)), Some(guard_config)) = (&received, config.seq_guard) {
+            let guard = guards.entry(*source).or_insert_with(|| {
+                stats.peer_states.fetch_add(1, Ordering::Relaxed);
+                SeqGuard::new(guard_config.max_backward)
+            });
+            match guard.observe(packet.seq) {
+                SeqCheck::Accepted => {},
+                SeqCheck::EpochReset => {
+                    println!("Peer {} restarted its sequence numbers", source);
+                    // Its reorder buffer would hold the new numbers back behind the old ones
+                    if let Some(mut buffer) = buffers.remove(source) {
+                        buffer.flush(&mut ready);
+                        stats.peer_states.fetch_sub(1, Ordering::Relaxed);
+                    }
+                },
+                SeqCheck::BackwardJump { highest } => {
+                    stats.rx_backward_jumps.fetch_add(1, Ordering::Relaxed);
+                    match guard_config.action {
+                        BackwardJumpAction::Drop => continue,
+                        BackwardJumpAction::Alert => {
+                            eprintln!("Alert: packet {} from {} is far behind its highest sequence number {}", packet.seq, source, highest);
+                        }
+                    }
+                }
+            }
+        }
+
+        // How far behind the highest delivered from its sender a first copy is, None for later copies
+        let depth = received.as_ref().and_then(|(source, packet)| first_copy_depth(&mut delivered, *source, packet.seq, config.dedup_window, &stats));
+        if let (Some(depth), Some(via)) = (depth, &via) {
+            via.delivered(depth);
+        }
+
+        // A packet further behind than reordering could put it is taken for
+        // one from a restarted sender, and numbering starts over from it
+        if let (true, Some((source, packet)), Some(behind)) = (config.latest_only, &received, depth.filter(|depth| *depth > 0)) {
+            if behind <= config.dedup_window {
+                stats.rx_late_dropped.fetch_add(1, Ordering::Relaxed);
+                continue
+            }
+            let mut window = DedupWindow::new(config.dedup_window);
+            window.insert(packet.seq);
+            delivered.insert(*source, window);
+        }
+
+        match (received, config.reorder) {
+            // In redundant mode every link delivers a copy, only write the first
+            (Some(_), _) if depth.is_none() => {},
+            (Some((source, packet)), Some(reorder)) => {
+                let buffer = buffers.entry(source).or_insert_with(|| {
+                    stats.peer_states.fetch_add(1, Ordering::Relaxed);
+                    ReorderBuffer::new(reorder)
+                });
+                if buffer.push(packet, clock.now(), &mut ready) {
+                    stats.reorder_overflows.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            (Some((_, packet)), None) => ready.push(packet),
+            (None, _) => {
+                let now = clock.now();
+                for buffer in buffers.values_mut() {
+                    if buffer.expire(now, &mut ready) {
+                        stats.reorder_timeouts.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        // Nothing more is coming to fill the gaps
+        if closed {
+            for (_, mut buffer) in buffers.drain() {
+                buffer.flush(&mut ready);
+                stats.peer_states.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
 
-        if packet.seq > seq {
-            seq = packet.seq;
-            tun_sender.write(&packet.bytes).await.unwrap();
+        if config.reorder.is_some() {
+            let depth: usize = buffers.values().map(ReorderBuffer::depth).sum();
+            stats.reorder_depth.store(depth as u64, Ordering::Relaxed);
+        }
+
+        for packet in ready.drain(..) {
+            stats.rx_goodput_bytes.fetch_add(packet.bytes.len() as u64, Ordering::Relaxed);
+            if config.mirror.is_observed() {
+                config.mirror.record(packet.bytes.clone());
+            }
+            if let Some(sink) = &config.sink {
+                if sink.sender.try_send(packet.bytes.clone()).is_err() {
+                    stats.sink_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                if sink.delivery == InboundDelivery::SinkOnly {
+                    continue
+                }
+            }
+            if let Err(err) = write_packet(&mut tun_sender, &packet.bytes, &stats).await {
+                eprintln!("Failed to write to the TUN, stopping [send_tun task]: {}", err);
+                events.emit(Event::TunDown { error: err.to_string() });
+                return
+            }
+        }
+
+        if closed {
+            println!("Inbound queues closed, stopping [send_tun task]");
+            return
         }
     }
 }
 
-pub async fn send_udp(socket: Arc<UdpSocket>, client_list: Arc<RwLock<HashMap<IpAddr, Vec<SocketAddr>>>>, mut chan_receiver: tokio::sync::broadcast::Receiver<Packet>) {
+#[allow(clippy::too_many_arguments)]
+pub async fn send_udp<T: Transport + ?Sized>(socket: Arc<T>, client_list: Arc<Clients>, queue: Arc<PathQueue>, path: Arc<Path>, clock: SharedClock, config: TaskConfig, stats: Arc<Stats>, packet_events: PacketEvents, capture: Capture, mut drop_injector: Option<DropInjector>) {
     println!("Started [send_udp task]");
+    // ToS currently set on the socket, to avoid a setsockopt per packet
+    let mut current_tos: Option<u8> = None;
+    // Scratch buffers reused for every packet
+    let mut compressed: Vec<u8> = Vec::new();
+    let mut encoder = DatagramEncoder::default();
+    let mut targets: Vec<Target> = Vec::new();
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    // One per target when path stamped, as each target's stamp is its own
+    let mut stamped: Vec<DatagramEncoder> = Vec::new();
+    // Packets for peers with no address of these families aren't for this path
+    let families = Families::of(&*socket);
+    // Socket options only apply to UDP
+    let udp = socket.udp_socket();
+    // Packets waiting for one sendmmsg call, when batching
+    // Batches go out at once, which pacing is there to avoid
+    let mut batch = config.send_batch.filter(|size| *size > 1 && !path.is_paced()).and_then(|size| udp.map(|udp| (SendBatch::new(size), udp)));
+    // Flow labels only apply to IPv6 sockets, and need the kernel's permission
+    let mut flow_label = config.flow_label.filter(|_| socket.local_addr().is_ok_and(|addr| addr.is_ipv6()));
+    if let (Some(_), Some(udp)) = (flow_label, udp) {
+        if let Err(err) = flowlabel::enable_flowinfo_send(udp) {
+            eprintln!("Failed to enable flow labels on path {}: {}", path.iface, err);
+            flow_label = None;
+        }
+    }
+    // Destination and label pairs leased, and whether the lease was granted
+    let mut leases: HashMap<(Ipv6Addr, u32), bool> = HashMap::new();
+    // Backup copies waiting out the fastest link's head start
+    let mut backups = BackupQueue::new(PATH_QUEUE_CAPACITY, path.clone());
     loop {
-        let pkt: Packet = match chan_receiver.recv().await {
-            Ok(pkt) => pkt,
-            Err(e) => {
-                eprintln!("send_udp task channel overrun. Dropping packets!: {}", e);
+        let (received, is_backup) = match backups.pop_due(clock.now()) {
+            Some(backup) => (Some(backup), true),
+            None => {
+                let ready = queue.try_pop();
+                // Send the batch once no further packet is ready, so batching never holds one back
+                if let (None, Some((pending, udp))) = (&ready, &mut batch) {
+                    if !pending.is_empty() {
+                        pending.flush(udp, |record, result| record_send(&path, &stats, &packet_events, record, result)).await;
+                    }
+                }
+                let received = match ready {
+                    Some(packet) => Some(packet),
+                    None if backups.is_empty() => queue.pop().await,
+                    None => tokio::select! {
+                        packet = queue.pop() => packet,
+                        _ = backups.due(&*clock) => continue
+                    }
+                };
+                (received, false)
+            }
+        };
+        let (pkt, read_at, route) = match received {
+            // Held until due, and the packets behind it go on
+            Some(packet @ TunPacket { backup_at: Some(due), .. }) if !is_backup => {
+                backups.push(due, packet);
                 continue
+            },
+            Some(TunPacket { packet, read_at, route, .. }) => (packet, read_at, route),
+            // The backups still go out once due
+            None if !backups.is_empty() => {
+                backups.due(&*clock).await;
+                continue
+            },
+            None => {
+                if let Some((pending, udp)) = &mut batch {
+                    pending.flush(udp, |record, result| record_send(&path, &stats, &packet_events, record, result)).await;
+                }
+                println!("Nothing left to send, stopping [send_udp task]");
+                return
             }
         };
+        // When backed up, skip packets too old to be useful so fresher ones get out sooner
+        if config.max_packet_age.is_some_and(|max_age| clock.now().saturating_duration_since(read_at) > max_age) {
+            path.counters.tx_stale.fetch_add(1, Ordering::Relaxed);
+            continue
+        }
 
-        // Decode IP packet and extract destination TUN IP
-        let tun_ip = match SlicedPacket::from_ip(pkt.bytes.as_slice()) {
-            Err(value) => {
-                eprintln!("Error extracting senders TUN IP: {:?}", value);
-                continue;
-            },
-            Ok(value) => {
-                match value.ip {
-                    Some(InternetSlice::Ipv4(ipheader)) => {
-                        IpAddr::V4(ipheader.destination_addr())
-                    },
-                    Some(InternetSlice::Ipv6(_, _)) => {
-                        eprintln!("TODO: Handle receiving IPv6");
-                        continue
-                    }
-                    None => {continue}
+        let Route { destination: destination_ip, peer, inner_tos, flow, session_epoch } = route;
+        let tun_ip = destination_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
 
+        if let (Some(dscp_remap), Some(inner_tos), Some(udp)) = (&config.dscp_remap, inner_tos, udp) {
+            let tos = outer_tos(inner_tos, dscp_remap);
+            if current_tos != Some(tos) {
+                // Batched packets were meant to leave with the ToS they were queued under
+                if let Some((pending, udp)) = &mut batch {
+                    pending.flush(udp, |record, result| record_send(&path, &stats, &packet_events, record, result)).await;
+                }
+                match SockRef::from(udp).set_tos(tos as u32) {
+                    Ok(()) => current_tos = Some(tos),
+                    Err(err) => eprintln!("Failed to set outer ToS {:#04x}: {}", tos, err)
                 }
             }
+        }
+
+        let cipher = match peer {
+            Some(peer) if config.keys.has_peer_keys() => config.keys.for_peer(&peer),
+            _ => config.keys.global()
         };
+        let peer = peer.unwrap_or(tun_ip);
 
         //println!("Pkt should be sent to: {}", tun_ip);
-        let compressed_pkt = Packet{
-            seq: pkt.seq,
-            bytes: compress_prepend_size(&pkt.bytes)
+        compress_prepend_size_into(&pkt.bytes, &mut compressed);
+        let timestamp = if config.timestamps { Some(jitter::timestamp_now()) } else { None };
+        // Sized like the real stamps, which are taken once the targets are known
+        let stamp = if config.path_sequence { Some(PathStamp { path_id: path.id, path_seq: 0 }) } else { None };
+        let framing = Framing {
+            wire_format: config.wire_format,
+            session_epoch,
+            cipher,
+            checksum: config.checksum,
+            // The path MTU, once probed, caps the configured datagram size
+            max_datagram_size: match (config.max_datagram_size, path.pmtu()) {
+                (Some(configured), Some(pmtu)) => Some(configured.min(pmtu)),
+                (configured, pmtu) => configured.or(pmtu)
+            }
         };
+        match encoder.encode(pkt.seq, timestamp, stamp, &compressed, &framing) {
+            Ok(()) => {},
+            Err(EncodeError::Encode(err)) => {
+                drop_unencodable(&path, pkt.bytes.len(), tun_ip, &err);
+                continue
+            },
+            Err(EncodeError::TooSmallToFragment(max)) => {
+                eprintln!("Dropping {} byte packet, max_datagram_size of {} is too small to fragment it", pkt.bytes.len(), max);
+                continue
+            }
+        }
+        let fragmented = encoder.is_fragmented();
+        let wire_len = encoder.wire_len();
+        targets.clear();
 
-        let encoded = bincode::serialize(&Messages::Packet(compressed_pkt)).unwrap();
-        let mut targets: Vec<SocketAddr> = Vec::new();
+        let now = clock.now();
+
+        if destination_ip.is_none() {
+            client_list.for_each(|peer, destinations| {
+                // Sealed with the global key, which peers with their own don't accept
+                if config.keys.id_for(peer) == KeyId::Peer(*peer) {
+                    return
+                }
+                if config.peer_send_paths.get(peer).is_some_and(|names| !names.contains(&path.iface)) {
+                    return
+                }
+                // Peers that announced return paths are sent to there instead
+                let mut push = |destinations: &[SocketAddr]| {
+                    for target in destinations.iter().filter(|target| families.reaches(target)) {
+                        if !targets.iter().any(|pushed| pushed.addr == *target) && path.consume_budget(wire_len, now) {
+                            targets.push(Target { addr: *target, peer: *peer, stamp: None });
+                        }
+                    }
+                };
+                if config.return_paths.with(peer, now, &mut push).is_none() {
+                    push(destinations);
+                }
+            });
+        } else {
+            // The peer may have gone since the packet was dispatched
+            client_list.with(&peer, |destinations| {
+                let mut push = |destinations: &[SocketAddr]| {
+                    for target in destinations.iter().filter(|target| families.reaches(target)) {
+                        // Datagrams over the device's rate limit are skipped
+                        if path.consume_budget(wire_len, now) {
+                            targets.push(Target { addr: *target, peer, stamp: None });
+                        }
+                    }
+                };
+                if config.return_paths.with(&peer, now, &mut push).is_none() {
+                    push(destinations);
+                }
+            });
+        }
 
-        {
-            let cl = client_list.read().unwrap();
+        // Numbered only now the packet is sent to them, so a link shows no loss
+        // for packets its path skipped. Datagrams dropped on purpose below
+        // are numbered too, and show up as the loss they stand in for.
+        if config.path_sequence {
+            for target in targets.iter_mut() {
+                target.stamp = Some(path.stamp(target.peer, target.addr));
+            }
+        }
 
-            if let Some(destination) = cl.get(&tun_ip) {
-                for target in destination {
-                    targets.push(target.clone());
+        if let (Some(label), Some(udp)) = (flow_label.and_then(|mode| flowlabel::label_for(mode, flow.as_ref())), udp) {
+            if leases.len() >= MAX_FLOW_LABEL_LEASES {
+                leases.clear();
+            }
+            for target in targets.iter_mut() {
+                if let SocketAddr::V6(v6) = &mut target.addr {
+                    let leased = *leases.entry((*v6.ip(), label)).or_insert_with(|| {
+                        flowlabel::lease(udp, *v6.ip(), label)
+                            .map_err(|err| eprintln!("Failed to lease flow label {:#x} toward {}: {}", label, v6.ip(), err))
+                            .is_ok()
+                    });
+                    if leased {
+                        target.addr = flowlabel::with_label(target.addr, label);
+                    }
                 }
-            } else {
-                eprintln!("I don't know any destinations for: {}. Perhaps it has not been discovered yet?", tun_ip);
             }
         }
 
-        for target in targets {
-            //println!("Sending to: {}", target);
-            socket.send_to(&encoded, target).await.unwrap();
+        if fragmented && !targets.is_empty() {
+            path.counters.tx_fragmented.fetch_add(1, Ordering::Relaxed);
         }
+        let peer = destination_ip.map(|_| peer);
+        let record = |target: SocketAddr| SendRecord { seq: pkt.seq, size: pkt.bytes.len(), tun_ip, peer, target };
 
+        // On a paced link, wait for this packet's turn
+        if !targets.is_empty() {
+            if let Some(release) = path.pace(wire_len * targets.len(), clock.now()) {
+                clock.sleep_until(release).await;
+            }
+        }
+
+        if capture.is_observed() {
+            let timestamp_us = jitter::timestamp_now();
+            for target in &targets {
+                capture.record(CaptureRecord { timestamp_us, path: path.iface.clone(), direction: Direction::Sent, seq: pkt.seq, tun_ip, peer: target.addr, packet: pkt.bytes.clone() });
+            }
+        }
+
+        if let Some(injector) = &mut drop_injector {
+            targets.retain(|_| {
+                let dropped = injector.drop_next();
+                if dropped {
+                    path.counters.tx_injected_drops.fetch_add(1, Ordering::Relaxed);
+                }
+                !dropped
+            });
+        }
+
+        if config.path_sequence {
+            if stamped.len() < targets.len() {
+                stamped.resize_with(targets.len(), DatagramEncoder::default);
+            }
+            // The same size as encoded above, so it only fails as that would have
+            let encoded = targets.iter().zip(stamped.iter_mut())
+                .try_for_each(|(target, encoder)| encoder.encode(pkt.seq, timestamp, target.stamp, &compressed, &framing));
+            if encoded.is_err() {
+                continue
+            }
+        }
+        // The encoding sent to the `index`th target
+        let datagrams = |index: usize| if config.path_sequence { stamped[index].datagrams() } else { encoder.datagrams() };
+
+        if let Some((pending, udp)) = &mut batch {
+            for (index, target) in targets.iter().enumerate() {
+                pending.push(record(target.addr), &datagrams(index));
+            }
+            if pending.is_full() {
+                pending.flush(udp, |record, result| record_send(&path, &stats, &packet_events, record, result)).await;
+            }
+            continue
+        }
+
+        let results = if config.path_sequence {
+            join_all(targets.iter().enumerate().map(|(index, target)| {
+                let (socket, datagrams) = (&*socket, datagrams(index));
+                async move { send_all(socket, &datagrams, target.addr).await }
+            })).await
+        } else {
+            addrs.clear();
+            addrs.extend(targets.iter().map(|target| target.addr));
+            send_to_targets(&*socket, &encoder.datagrams(), &addrs).await
+        };
+        for (target, result) in targets.iter().zip(results) {
+            record_send(&path, &stats, &packet_events, &record(target.addr), result);
+        }
+    }
+}
+
+// Where send_udp sends a packet: an address of `peer`, and the path stamp
+// numbering the packet on the link to it
+#[derive(Debug, Clone, Copy)]
+struct Target {
+    addr: SocketAddr,
+    peer: IpAddr,
+    stamp: Option<PathStamp>
+}
+
+// Count a packet sent to one target, or log its failure, and report it to packet event subscribers
+fn record_send(path: &Path, stats: &Stats, packet_events: &PacketEvents, record: &SendRecord, result: std::io::Result<usize>) {
+    let result = match result {
+        Ok(n) => {
+            path.counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+            path.counters.tx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(peer) = record.peer {
+                stats.peers.sent(peer, record.size);
+            }
+            SendResult::Sent(n)
+        },
+        Err(err) => {
+            eprintln!("Failed to send to {}: {}", record.target, err);
+            SendResult::Failed(err.kind())
+        }
+    };
+
+    if packet_events.is_observed() {
+        packet_events.emit(PacketEvent {
+            seq: record.seq,
+            size: record.size,
+            tun_ip: record.tun_ip,
+            destination: record.target,
+            path: path.iface.clone(),
+            result
+        });
     }
 }
 
-pub async fn recv_udp(socket: Arc<UdpSocket>, chan_sender: tokio::sync::mpsc::UnboundedSender::<Packet>, client_list: Arc<RwLock<HashMap<IpAddr, Vec<SocketAddr>>>>) {
+// Write one packet to the TUN. A TUN takes whole packets, but other devices
+// may take part of one, so the rest is written after it and counted.
+async fn write_packet(tun_sender: &mut (impl AsyncWrite + Unpin), packet: &[u8], stats: &Stats) -> std::io::Result<()> {
+    let written = loop {
+        match tun_sender.write(packet).await {
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => break result?
+        }
+    };
+    if written < packet.len() {
+        stats.tun_short_writes.fetch_add(1, Ordering::Relaxed);
+        eprintln!("Short write to the TUN: {} of {} bytes", written, packet.len());
+        // write_all retries on EINTR itself
+        tun_sender.write_all(&packet[written..]).await?;
+    }
+    Ok(())
+}
+
+// How far `seq` is behind the highest sequence number delivered from
+// `source`, 0 if ahead. None if it was already delivered.
+fn first_copy_depth(delivered: &mut HashMap<IpAddr, DedupWindow>, source: IpAddr, seq: usize, window: usize, stats: &Stats) -> Option<usize> {
+    let window = delivered.entry(source)
+        .or_insert_with(|| {
+            stats.peer_states.fetch_add(1, Ordering::Relaxed);
+            DedupWindow::new(window)
+        });
+    let depth = window.highest().map_or(0, |highest| highest.saturating_sub(seq));
+    if window.insert(seq) { Some(depth) } else { None }
+}
+
+// Send the datagrams making up one packet to `target`, returning the bytes sent
+async fn send_all<T: Transport + ?Sized>(socket: &T, datagrams: &[&[u8]], target: SocketAddr) -> std::io::Result<usize> {
+    let mut sent = 0;
+    for datagram in datagrams {
+        sent += send_to(socket, datagram, target).await?;
+    }
+    Ok(sent)
+}
+
+// Send to all targets at once, so one target with a full socket buffer
+// doesn't hold up the others. Results are in the order of `targets`. A
+// single target, the usual case, is sent to directly without join_all.
+async fn send_to_targets<T: Transport + ?Sized>(socket: &T, datagrams: &[&[u8]], targets: &[SocketAddr]) -> Vec<std::io::Result<usize>> {
+    match targets {
+        [target] => vec![send_all(socket, datagrams, *target).await],
+        _ => join_all(targets.iter().map(|target| send_all(socket, datagrams, *target))).await
+    }
+}
+
+// The next datagram, from the current recvmmsg batch when batching. IPv4
+// senders on a dual-stack socket come with their address in IPv4 form.
+async fn receive<T: Transport + ?Sized>(socket: &T, batch: &mut Option<(RecvBatch, &UdpSocket)>, buf: &mut Vec<u8>) -> std::io::Result<(usize, SocketAddr)> {
+    let (len, addr) = match batch {
+        Some((batch, udp)) => batch.recv_into(udp, buf).await?,
+        None => socket.recv_from(buf).await?
+    };
+    Ok((len, clients::canonical_addr(addr)))
+}
+
+// The next peer removed from the tunnel, or `None` if some were missed and all
+// per-peer state should be dropped. Never resolves once the tunnel is gone.
+async fn next_peer_removal(removals: &mut broadcast::Receiver<IpAddr>) -> Option<IpAddr> {
+    match removals.recv().await {
+        Ok(tun_ip) => Some(tun_ip),
+        Err(broadcast::error::RecvError::Lagged(_)) => None,
+        Err(broadcast::error::RecvError::Closed) => futures::future::pending().await
+    }
+}
+
+// Log and count a packet send_udp drops because it couldn't be encoded
+fn drop_unencodable(path: &Path, len: usize, tun_ip: IpAddr, err: &bincode::Error) {
+    eprintln!("Dropping {} byte packet for {}, it couldn't be encoded: {}", len, tun_ip, err);
+    path.counters.tx_encode_errors.fetch_add(1, Ordering::Relaxed);
+}
+
+// Encode a keep-alive or reply, encrypted if encryption is enabled and
+// checksummed if `checksum` is set
+pub(crate) fn encode_control(msg: &Messages, wire_format: WireFormat, checksum: bool, cipher: Option<&Cipher>) -> bincode::Result<Vec<u8>> {
+    let encoded = messages::encode_packet(msg, wire_format)?;
+    let mut datagram = match cipher {
+        Some(cipher) => {
+            let mut sealed = Vec::new();
+            cipher.seal_into(&encoded, &mut sealed);
+            sealed
+        },
+        None => encoded
+    };
+    if checksum {
+        checksum::append(&mut datagram);
+    }
+    Ok(datagram)
+}
+
+// Attempts at a send that keeps failing with WouldBlock or Interrupted
+const SEND_ATTEMPTS: usize = 4;
+
+/// Send `buf` to `target`, retrying a few times on `WouldBlock` and
+/// `Interrupted`. Tokio normally retries those itself, but a transport need not.
+pub(crate) async fn send_to<T: Transport + ?Sized>(socket: &T, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+    let mut attempt = 1;
+    loop {
+        match socket.send_to(buf, target).await {
+            Err(err) if is_retryable(&err) && attempt < SEND_ATTEMPTS => {
+                attempt += 1;
+                tokio::task::yield_now().await;
+            },
+            result => return result
+        }
+    }
+}
+
+fn is_retryable(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock)
+}
+
+// Errors a socket can keep working after. On Linux a UDP socket reports an
+// ICMP port or host unreachable from an earlier send on its next receive.
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(err.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable | ErrorKind::TimedOut) || is_retryable(err)
+}
+
+// Refresh `addr` for a keep-alive. Unlike data packets these don't teach us
+// a peer, so unknown addresses aren't remembered.
+fn refresh_if_known(last_seen: &LastSeen, client_list: &Clients, addr: SocketAddr, now: Instant) {
+    if client_list.contains_addr(&addr) {
+        last_seen.refresh(addr, now);
+    }
+}
+
+// Outer ToS for an inner ToS byte: the DSCP is mapped through the table
+// (unlisted values are copied as is) and the ECN bits are copied unchanged.
+fn outer_tos(inner_tos: u8, dscp_remap: &HashMap<u8, u8>) -> u8 {
+    let dscp = inner_tos >> 2;
+    let outer_dscp = dscp_remap.get(&dscp).copied().unwrap_or(dscp) & 0x3f;
+    (outer_dscp << 2) | (inner_tos & 0x3)
+}
+
+/// Size of the buffers datagrams are received into for payloads of up to
+/// `max_payload_len` bytes: the largest message one encodes to, sealed and
+/// checksummed, or a fragment of it, plus a byte so a longer datagram shows up as filling the
+/// buffer instead of being silently cut short.
+pub fn recv_buffer_len(max_payload_len: usize) -> usize {
+    let max_datagram = messages::max_message_len(max_payload_len) as usize + ENCRYPTION_OVERHEAD + CHECKSUM_LEN + fragment::FRAGMENT_HEADER_LEN;
+    (max_datagram + 1).min(MAX_DATAGRAM_LEN)
+}
+
+/// State shared by the `recv_udp` workers of one socket, since consecutive
+/// datagrams from a peer may be read by different workers.
+#[derive(Debug)]
+pub struct RecvState {
+    // Fragments are reassembled whether or not this side fragments
+    reassembler: Mutex<Reassembler>,
+    address_tracker: Option<Mutex<AddressTracker>>,
+    // Per socket, as the same datagram may come once over each path it was sent on
+    replay_window: Mutex<ReplayWindow>,
+    // Shared by the workers, so a flood is logged once per path however it's spread
+    undecryptable_log: Mutex<LogThrottle>,
+    replayed_log: Mutex<LogThrottle>,
+    undecodable_log: Mutex<LogThrottle>,
+    bad_checksum_log: Mutex<LogThrottle>,
+    oversized_log: Mutex<LogThrottle>,
+    unknown_control_log: Mutex<LogThrottle>,
+    unparseable_log: Mutex<LogThrottle>,
+    wrong_key_log: Mutex<LogThrottle>
+}
+
+impl RecvState {
+    pub fn new(config: &TaskConfig) -> RecvState {
+        RecvState {
+            reassembler: Mutex::new(Reassembler::new(messages::max_message_len(config.max_payload_len) as usize)),
+            address_tracker: config.address_change_packets.map(|packets| Mutex::new(AddressTracker::new(packets))),
+            replay_window: Mutex::default(),
+            undecryptable_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            replayed_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            undecodable_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            bad_checksum_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            oversized_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            unknown_control_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            unparseable_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL)),
+            wrong_key_log: Mutex::new(LogThrottle::new(GARBAGE_LOG_INTERVAL))
+        }
+    }
+
+    // Sum up the messages held back since the last one of each kind, for
+    // floods that stopped before another message was let through
+    fn log_summaries(&self, iface: &str, now: Instant) {
+        let throttles = [
+            (&self.undecryptable_log, "that failed decryption"),
+            (&self.replayed_log, "received before"),
+            (&self.undecodable_log, "that couldn't be decoded"),
+            (&self.bad_checksum_log, "with a bad checksum"),
+            (&self.oversized_log, "too large to take"),
+            (&self.unknown_control_log, "with control messages from unknown addresses"),
+            (&self.unparseable_log, "without a sender's TUN IP"),
+            (&self.wrong_key_log, "not encrypted with their sender's key")
+        ];
+        for (throttle, kind) in throttles {
+            if let Some(suppressed) = throttle.lock().unwrap().summary(now) {
+                println!("Dropped {} more datagrams on {} {} since the last message", suppressed, iface, kind);
+            }
+        }
+    }
+}
+
+// Count and log a data packet whose payload failed to decompress
+fn drop_undecompressable(stats: &Stats, state: &RecvState, events: &Events, addr: SocketAddr, err: &dyn std::fmt::Display, now: Instant) {
+    stats.rx_undecodable.fetch_add(1, Ordering::Relaxed);
+    let allowed = state.undecodable_log.lock().unwrap().allow(now);
+    if let Some(suppressed) = allowed {
+        println!("Unable to decompress packet from {}. Got error: {}{}", addr, err, suppressed_note(suppressed));
+    }
+    events.emit(Event::PacketDropped { reason: DropReason::Decompress });
+}
+
+// Tail for a throttled log message, counting the ones left out since the last
+fn suppressed_note(suppressed: u64) -> String {
+    if suppressed == 0 {
+        String::new()
+    } else {
+        format!(" ({} more since the last message)", suppressed)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn recv_udp<T: Transport + ?Sized>(socket: Arc<T>, inbound: Arc<InboundQueues>, client_list: Arc<Clients>, stats: Arc<Stats>, path: Arc<Path>, clock: SharedClock, config: TaskConfig, nat_peers: Arc<NatPeers>, events: Events, mut peer_removals: broadcast::Receiver<IpAddr>, last_seen: Arc<LastSeen>, forwarder: Option<Arc<Forwarder>>, state: Arc<RecvState>, control: ControlMessages, flow_control: Arc<FlowControl>, capture: Capture, pending: Option<Arc<PendingPackets>>, blocklist: Arc<Blocklist>, goodbyes: mpsc::Sender<(IpAddr, Instant)>) {
     println!("Started [recv_udp task]");
-    let mut buf = [0; RECV_BUFFER_SIZE];
+    let max_payload_len = config.max_payload_len;
+    let max_message_len = messages::max_message_len(max_payload_len);
+    let buffer_len = recv_buffer_len(max_payload_len);
+    let mut buf = vec![0; buffer_len];
+    // Datagrams from the last recvmmsg call still to be handled, when batching
+    let mut batch = config.recv_batch.filter(|size| *size > 1)
+        .and_then(|size| socket.udp_socket().map(|udp| (RecvBatch::new(size, buffer_len), udp)));
+    let mut summaries = Interval::new(clock.clone(), GARBAGE_LOG_INTERVAL);
 
     loop {
 
-        let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+        let (len, addr) = tokio::select! {
+            received = receive(&*socket, &mut batch, &mut buf) => match received {
+                Ok(received) => received,
+                Err(err) if is_transient(&err) => {
+                    eprintln!("Receiving on {} failed, continuing: {}", path.iface, err);
+                    continue
+                },
+                Err(err) => {
+                    eprintln!("Receiving on {} failed, stopping its receive task: {}", path.iface, err);
+                    return
+                }
+            },
+            _ = summaries.tick() => {
+                state.log_summaries(&path.iface, clock.now());
+                continue
+            },
+            removed = next_peer_removal(&mut peer_removals) => {
+                if let Some(tracker) = &state.address_tracker {
+                    // Only the first worker to see a removal finds anything to free
+                    let mut tracker = tracker.lock().unwrap();
+                    let freed = match removed {
+                        Some(tun_ip) => tracker.forget(&tun_ip) as usize,
+                        None => tracker.clear()
+                    };
+                    stats.peer_states.fetch_sub(freed as u64, Ordering::Relaxed);
+                }
+                continue
+            }
+        };
+        path.counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+        path.counters.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+
+        // Before anything is decrypted or learned from the sender
+        if blocklist.contains(&addr.ip()) {
+            stats.rx_blocked.fetch_add(1, Ordering::Relaxed);
+            events.emit(Event::PacketDropped { reason: DropReason::Blocked });
+            continue
+        }
+
+        // The buffer has a byte to spare over the largest datagram, so a full one was cut short
+        if len >= buffer_len && buffer_len < MAX_DATAGRAM_LEN {
+            stats.rx_oversized.fetch_add(1, Ordering::Relaxed);
+            let allowed = state.oversized_log.lock().unwrap().allow(clock.now());
+            if let Some(suppressed) = allowed {
+                eprintln!("Dropping datagram from {}: larger than the {} bytes a full payload encodes to{}", addr, buffer_len - 1, suppressed_note(suppressed));
+            }
+            events.emit(Event::PacketDropped { reason: DropReason::Oversized });
+            continue
+        }
+
+        // Before decrypting, the checksum covers the datagram as sent
+        let len = if config.checksum {
+            match checksum::verify(&buf[..len]) {
+                Some(len) => len,
+                None => {
+                    stats.rx_bad_checksum.fetch_add(1, Ordering::Relaxed);
+                    let allowed = state.bad_checksum_log.lock().unwrap().allow(clock.now());
+                    if let Some(suppressed) = allowed {
+                        println!("Dropping datagram from {} with a bad checksum{}", addr, suppressed_note(suppressed));
+                    }
+                    events.emit(Event::PacketDropped { reason: DropReason::BadChecksum });
+                    continue
+                }
+            }
+        } else {
+            len
+        };
+
+        // With per-peer keys, the key depends on who the datagram is from
+        let sender = if config.keys.has_peer_keys() { client_list.tun_ip_of(&addr) } else { None };
+        // Left in place by opening, ahead of the plaintext
+        let mut nonce = [0; NONCE_LEN];
+        if len >= NONCE_LEN {
+            nonce.copy_from_slice(&buf[..NONCE_LEN]);
+        }
+        let (datagram, key_id): (&[u8], KeyId) = match config.keys.open_in_place(&mut buf[..len], sender) {
+            Some(opened) => opened,
+            None => {
+                stats.rx_undecryptable.fetch_add(1, Ordering::Relaxed);
+                let allowed = state.undecryptable_log.lock().unwrap().allow(clock.now());
+                if let Some(suppressed) = allowed {
+                    println!("Dropping datagram from {} that failed decryption{}", addr, suppressed_note(suppressed));
+                }
+                events.emit(Event::PacketDropped { reason: DropReason::Malformed });
+                continue
+            }
+        };
+        if key_id != KeyId::Plaintext && !state.replay_window.lock().unwrap().accept(key_id, &nonce) {
+            stats.rx_replayed.fetch_add(1, Ordering::Relaxed);
+            let allowed = state.replayed_log.lock().unwrap().allow(clock.now());
+            if let Some(suppressed) = allowed {
+                println!("Dropping datagram from {} received before{}", addr, suppressed_note(suppressed));
+            }
+            events.emit(Event::PacketDropped { reason: DropReason::Replayed });
+            continue
+        }
+        // Replies go back under the key the datagram came with
+        let reply_cipher = config.keys.cipher(key_id);
 
-        let decoded: Packet = match bincode::deserialize::<Messages>(&buf[..len]) {
+        let reassembled: Vec<u8>;
+        let datagram: &[u8] = if fragment::is_fragment(datagram) {
+            let now = clock.now();
+            let mut reassembler = state.reassembler.lock().unwrap();
+            let expired = reassembler.expire(now);
+            stats.rx_reassembly_timeouts.fetch_add(expired as u64, Ordering::Relaxed);
+            let pushed = reassembler.push(addr, datagram, now);
+            drop(reassembler);
+            match pushed {
+                Ok(Some(message)) => {
+                    reassembled = message;
+                    &reassembled
+                },
+                Ok(None) => continue,
+                Err(err) => {
+                    stats.rx_undecodable.fetch_add(1, Ordering::Relaxed);
+                    let reason = if err == FragmentError::SizeLimit {
+                        stats.rx_oversized.fetch_add(1, Ordering::Relaxed);
+                        DropReason::Oversized
+                    } else {
+                        DropReason::Malformed
+                    };
+                    events.emit(Event::PacketDropped { reason });
+                    let allowed = state.undecodable_log.lock().unwrap().allow(clock.now());
+                    if let Some(suppressed) = allowed {
+                        println!("Dropping fragment from {}: {}{}", addr, err, suppressed_note(suppressed));
+                    }
+                    continue
+                }
+            }
+        } else {
+            datagram
+        };
+
+        // The payload is decompressed straight out of the datagram, without copying it first
+        let mut path_stamp = None;
+        let mut epoch = None;
+        let mut decoded: Packet = match messages::decode_packet_ref(datagram, config.wire_format, max_message_len) {
             Ok(decoded) => {
+                if let MessagesRef::TimestampedPacket(sent_us, _) | MessagesRef::PathStampedPacket(_, Some(sent_us), _) | MessagesRef::SessionPacket(_, _, Some(sent_us), _) = &decoded {
+                    path.timestamp_received(*sent_us, jitter::timestamp_now());
+                }
+                if let MessagesRef::PathStampedPacket(stamp, ..) | MessagesRef::SessionPacket(_, Some(stamp), ..) = &decoded {
+                    path_stamp = Some(*stamp);
+                }
+                if let MessagesRef::SessionPacket(session, ..) = &decoded {
+                    epoch = Some(*session);
+                }
                 match decoded {
-                    Messages::Packet(pkt) => {
-                        Packet{
-                            seq: pkt.seq,
-                            bytes: decompress_size_prepended(&pkt.bytes).unwrap()
+                    MessagesRef::Packet(pkt) | MessagesRef::TimestampedPacket(_, pkt) | MessagesRef::PathStampedPacket(_, _, pkt) | MessagesRef::SessionPacket(_, _, _, pkt) => {
+                        // Check the size claimed by the lz4 header before decompressing,
+                        // so a peer can't make us allocate more than a TUN packet.
+                        match uncompressed_size(pkt.bytes) {
+                            Ok((size, _)) if size <= max_payload_len => {},
+                            Ok((size, _)) => {
+                                stats.rx_oversized.fetch_add(1, Ordering::Relaxed);
+                                let allowed = state.oversized_log.lock().unwrap().allow(clock.now());
+                                if let Some(suppressed) = allowed {
+                                    eprintln!("Dropping packet from {}: payload of {} bytes exceeds max of {}{}", addr, size, max_payload_len, suppressed_note(suppressed));
+                                }
+                                events.emit(Event::PacketDropped { reason: DropReason::Oversized });
+                                continue
+                            },
+                            Err(err) => {
+                                drop_undecompressable(&stats, &state, &events, addr, &err, clock.now());
+                                continue
+                            }
+                        }
+
+                        match decompress_size_prepended(pkt.bytes) {
+                            Ok(bytes) => Packet{
+                                seq: pkt.seq,
+                                bytes: Bytes::from(bytes)
+                            },
+                            Err(err) => {
+                                drop_undecompressable(&stats, &state, &events, addr, &err, clock.now());
+                                continue
+                            }
                         }
                     },
-                    Messages::Keepalive => {
+                    MessagesRef::Keepalive => {
                         println!("Received keepalive msg.");
+                        refresh_if_known(&last_seen, &client_list, addr, clock.now());
+                        let sent = match encode_control(&Messages::KeepaliveReply, config.wire_format, config.checksum, reply_cipher) {
+                            Ok(reply) => send_to(&*socket, reply.as_slice(), addr).await.map(drop).map_err(|err| err.to_string()),
+                            Err(err) => Err(err.to_string())
+                        };
+                        if let Err(err) = sent {
+                            eprintln!("Failed to reply to keepalive from {}: {}", addr, err);
+                        }
+                        continue
+                    },
+                    MessagesRef::Probe(size) => {
+                        let sent = match encode_control(&Messages::ProbeAck(size), config.wire_format, config.checksum, reply_cipher) {
+                            Ok(ack) => send_to(&*socket, ack.as_slice(), addr).await.map(drop).map_err(|err| err.to_string()),
+                            Err(err) => Err(err.to_string())
+                        };
+                        if let Err(err) = sent {
+                            eprintln!("Failed to acknowledge probe from {}: {}", addr, err);
+                        }
+                        continue
+                    },
+                    MessagesRef::ProbeAck(size) => {
+                        let before = path.pmtu();
+                        path.probe_succeeded(size as usize, clock.now());
+                        if path.pmtu() != before {
+                            println!("Path MTU of {} is at least {} bytes", path.iface, size);
+                        }
+                        continue
+                    },
+                    MessagesRef::Control(payload) => {
+                        // Like keep-alives these don't teach us a peer, so only known peers are heard
+                        match client_list.tun_ip_of(&addr) {
+                            Some(tun_ip) => {
+                                last_seen.refresh(addr, clock.now());
+                                control.deliver(ControlMessage { tun_ip, from: addr, path: path.iface.clone(), payload: Bytes::copy_from_slice(payload) });
+                            },
+                            None => {
+                                let allowed = state.unknown_control_log.lock().unwrap().allow(clock.now());
+                                if let Some(suppressed) = allowed {
+                                    println!("Dropping control message from unknown address {}{}", addr, suppressed_note(suppressed));
+                                }
+                            }
+                        }
+                        continue
+                    },
+                    MessagesRef::SlowDown(duration_ms) => {
+                        // Only known peers can hold back what we send
+                        if let Some(tun_ip) = client_list.tun_ip_of(&addr) {
+                            stats.slow_downs_received.fetch_add(1, Ordering::Relaxed);
+                            println!("Peer {} asked to slow down for {} ms", tun_ip, duration_ms);
+                            flow_control.throttle(tun_ip, Duration::from_millis(duration_ms.into()), clock.now());
+                        }
+                        continue
+                    },
+                    MessagesRef::Goodbye => {
+                        // The tunnel removes the peer, as if it had timed out
+                        if config.discovery == DiscoveryMode::Learn {
+                            if let Some(tun_ip) = client_list.tun_ip_of(&addr) {
+                                println!("Peer {} said goodbye from {}", tun_ip, addr);
+                                let _ = goodbyes.try_send((tun_ip, clock.now()));
+                            }
+                        }
+                        continue
+                    },
+                    MessagesRef::Mtu(mtu) => {
+                        if let Some(tun_ip) = client_list.tun_ip_of(&addr) {
+                            println!("Peer {} takes packets of up to {} bytes now", tun_ip, mtu);
+                            config.mtus.set_peer(tun_ip, mtu as usize);
+                        }
+                        continue
+                    },
+                    MessagesRef::Echo(tag) => {
+                        let sent = match encode_control(&Messages::EchoReply(tag), config.wire_format, config.checksum, reply_cipher) {
+                            Ok(reply) => send_to(&*socket, reply.as_slice(), addr).await.map(drop).map_err(|err| err.to_string()),
+                            Err(err) => Err(err.to_string())
+                        };
+                        if let Err(err) = sent {
+                            eprintln!("Failed to echo link check from {}: {}", addr, err);
+                        }
+                        continue
+                    },
+                    MessagesRef::EchoReply(tag) => {
+                        refresh_if_known(&last_seen, &client_list, addr, clock.now());
+                        if !config.echoes.answered(tag, &path.iface, addr, clock.now()) {
+                            println!("Ignoring unexpected echo {} from {} on path {}", tag, addr, path.iface);
+                        }
+                        continue
+                    },
+                    MessagesRef::ReturnPath(generation, tun_ip) => {
+                        // Only known peers can choose where they are sent to, from
+                        // addresses that are new or already theirs
+                        let allowed = match client_list.tun_ip_of(&addr) {
+                            Some(owner) => owner == tun_ip,
+                            None => client_list.contains(&tun_ip)
+                        };
+                        if !allowed {
+                            println!("Ignoring return path from {} for {}", addr, tun_ip);
+                        } else if config.return_paths.announced(tun_ip, generation, addr, clock.now()) {
+                            println!("Peer {} asked to be sent to at {}", tun_ip, addr);
+                        }
+                        continue
+                    },
+                    MessagesRef::KeepaliveReply => {
+                        path.counters.keepalive_replies.fetch_add(1, Ordering::Relaxed);
+                        refresh_if_known(&last_seen, &client_list, addr, clock.now());
+                        if path.reply_received(clock.now()) {
+                            println!("Path {} is up again", path.iface);
+                            events.emit(Event::PathUp { iface: path.iface.clone() });
+                        }
+                        println!("Received keepalive reply on path {}. RTT: {:?}", path.iface, path.rtt());
                         continue
                     }
                 }
             },
             Err(err) => {
                 // If we receive garbage, simply throw it away and continue.
-                println!("Unable do deserialize packet. Got error: {}", err);
+                // This includes datagrams exceeding the size limit.
+                stats.rx_undecodable.fetch_add(1, Ordering::Relaxed);
+                let reason = if err.is_size_limit() {
+                    stats.rx_oversized.fetch_add(1, Ordering::Relaxed);
+                    DropReason::Oversized
+                } else if err.is_version_mismatch() {
+                    stats.rx_version_mismatch.fetch_add(1, Ordering::Relaxed);
+                    DropReason::Malformed
+                } else {
+                    DropReason::Malformed
+                };
+                events.emit(Event::PacketDropped { reason });
+                let allowed = state.undecodable_log.lock().unwrap().allow(clock.now());
+                if let Some(suppressed) = allowed {
+                    println!("Unable to deserialize packet from {}. Got error: {}{}", addr, err, suppressed_note(suppressed));
+                }
                 continue
             }
         };
 
-        // Decode IP packet and extract sender's TUN IP
-        let tun_ip = match SlicedPacket::from_ip(decoded.bytes.as_slice()) {
+        // Decode IP packet and extract sender's and receiver's TUN IP
+        let (tun_ip, destination) = match SlicedPacket::from_ip(&decoded.bytes) {
             Err(value) => {
-                eprintln!("Error extracting senders TUN IP: {:?}", value);
-                continue;
+                // Broadcast by a known peer, delivered as coming from it
+                match client_list.tun_ip_of(&addr).filter(|_| config.unparseable_policy == UnparseablePolicy::BroadcastToAllPeers) {
+                    Some(peer) => (peer, peer),
+                    None => {
+                        let allowed = state.unparseable_log.lock().unwrap().allow(clock.now());
+                        if let Some(suppressed) = allowed {
+                            eprintln!("Error extracting senders TUN IP: {:?}{}", value, suppressed_note(suppressed));
+                        }
+                        continue;
+                    }
+                }
             },
             Ok(value) => {
                 match value.ip {
                     Some(InternetSlice::Ipv4(ipheader)) => {
-                        IpAddr::V4(ipheader.source_addr())
+                        (IpAddr::V4(ipheader.source_addr()), IpAddr::V4(ipheader.destination_addr()))
+                    },
+                    Some(InternetSlice::Ipv6(ipheader, _)) => {
+                        (IpAddr::V6(ipheader.source_addr()), IpAddr::V6(ipheader.destination_addr()))
                     },
-                    Some(InternetSlice::Ipv6(_, _)) => {
-                        eprintln!("TODO: Handle receiving IPv6");
-                        continue
-                    }
                     None => {continue}
 
                 }
             }
         };
 
-        let mut cl = client_list.write().unwrap();
-
-        if let Some(client) = cl.get_mut(&tun_ip) {
-            if  !client.contains(&addr) {
-                client.push(addr);
-                println!("Added: IP: {} to existing client: {}.", addr, tun_ip);
+        // Holding one peer's key mustn't let a sender pass for another peer.
+        // A hub relays everyone's packets under its own.
+        let relayed = config.fallback_peer.is_some_and(|hub| key_id == config.keys.id_for(&hub));
+        if key_id != config.keys.id_for(&tun_ip) && !relayed {
+            let allowed = state.wrong_key_log.lock().unwrap().allow(clock.now());
+            if let Some(suppressed) = allowed {
+                println!("Dropping packet from {} for {}, not encrypted with its key{}", addr, tun_ip, suppressed_note(suppressed));
             }
+            events.emit(Event::PacketDropped { reason: DropReason::Malformed });
+            continue
+        }
+
+        if config.discovery == DiscoveryMode::Static {
+            refresh_if_known(&last_seen, &client_list, addr, clock.now());
         } else {
-            cl.insert(tun_ip, vec!(addr) );
-            println!("Added new client: {} with IP: {}", tun_ip, addr);
+            // A known peer at a known address, with no other mappings of it, only needs the read lock
+            let settled = match client_list.with(&tun_ip, |client| client.contains(&addr) && !nat::is_port_rewrite(client, addr)) {
+                Some(settled) => settled,
+                None if config.max_clients.is_some_and(|max_clients| client_list.len() >= max_clients) => {
+                    stats.rx_clients_rejected.fetch_add(1, Ordering::Relaxed);
+                    events.emit(Event::PacketDropped { reason: DropReason::ClientLimit });
+                    continue
+                },
+                None => false
+            };
+            last_seen.refresh(addr, clock.now());
+
+            // Drop addresses the tracker has decided are no longer in use
+            let stale = match state.address_tracker.as_ref().map(|tracker| tracker.lock().unwrap().observe(tun_ip, addr)) {
+                Some(AddressUpdate::Learned(_)) => {
+                    stats.peer_states.fetch_add(1, Ordering::Relaxed);
+                    None
+                },
+                Some(AddressUpdate::Candidate { replaced, .. }) => replaced,
+                Some(AddressUpdate::Committed { old, new }) => {
+                    println!("Client {} moved from {} to {}", tun_ip, old, new);
+                    Some(old)
+                },
+                Some(AddressUpdate::Reverted(candidate)) => Some(candidate),
+                _ => None
+            };
+            if !settled || stale.is_some() {
+                let mut discovered = false;
+                let added = client_list.upsert(tun_ip, config.max_clients, |client, new| {
+                    discovered = new;
+                    if let Some(stale) = stale {
+                        client.retain(|target| *target != stale);
+                        last_seen.forget(&stale);
+                        events.emit(Event::ClientExpired { tun_ip, addr: stale });
+                    }
+
+                    if nat::is_port_rewrite(client, addr) {
+                        if nat_peers.detected(tun_ip) {
+                            println!("Client {} is behind a NAT, source port rewritten to {}", tun_ip, addr);
+                        }
+                        // Sending to the old mapping as well would only duplicate packets
+                        for stale in nat::stale_mappings(client, addr, &last_seen, clock.now(), config.nat_rebind_grace) {
+                            println!("Dropping {} of client {}, its NAT mapping moved to {}", stale, tun_ip, addr);
+                            client.retain(|target| *target != stale);
+                            last_seen.forget(&stale);
+                            events.emit(Event::ClientExpired { tun_ip, addr: stale });
+                        }
+                    }
+
+                    if !client.contains(&addr) {
+                        client.push(addr);
+                        if new {
+                            println!("Added new client: {} with IP: {}", tun_ip, addr);
+                        } else {
+                            println!("Added: IP: {} to existing client: {}.", addr, tun_ip);
+                        }
+                        events.emit(Event::ClientDiscovered { tun_ip, addr });
+                    }
+                });
+                // Another receive task took the last free slot first
+                if added.is_none() {
+                    stats.rx_clients_rejected.fetch_add(1, Ordering::Relaxed);
+                    events.emit(Event::PacketDropped { reason: DropReason::ClientLimit });
+                    continue
+                }
+                if let Some(pending) = pending.as_ref().filter(|_| discovered) {
+                    let released = pending.release(&tun_ip, clock.now());
+                    stats.tun_held_released.fetch_add(released as u64, Ordering::Relaxed);
+                }
+            }
+        }
+
+        if let Some(stamp) = path_stamp {
+            path.link_received(tun_ip, stamp);
+        }
+        stats.peers.received(tun_ip, decoded.bytes.len());
+
+        if capture.is_observed() {
+            capture.record(CaptureRecord { timestamp_us: jitter::timestamp_now(), path: path.iface.clone(), direction: Direction::Received, seq: decoded.seq, tun_ip, peer: addr, packet: decoded.bytes.clone() });
+        }
+
+        // In hub mode, packets between peers are passed on instead of delivered
+        let relay = forwarder.as_ref().filter(|_| destination != tun_ip && client_list.contains(&destination));
+
+        if let Some(forwarder) = relay {
+            if forwarder.forward(tun_ip, decoded, epoch) {
+                stats.forwarded.fetch_add(1, Ordering::Relaxed);
+            }
+            continue
         }
 
-        chan_sender.send(decoded).unwrap();
+        if let Some(rewritten) = config.source_nat.as_ref().and_then(|source_nat| source_nat.inbound(&decoded.bytes)) {
+            decoded.bytes = Bytes::from(rewritten);
+        }
+
+        if !inbound.push(tun_ip, decoded, epoch, Some(path.clone())) {
+            stats.rx_queue_full.fetch_add(1, Ordering::Relaxed);
+            events.emit(Event::PacketDropped { reason: DropReason::InboundQueueFull });
+            if let Some(duration) = flow_control.dropped(tun_ip, clock.now()) {
+                let duration_ms = duration.as_millis().min(u32::MAX.into()) as u32;
+                let sent = match encode_control(&Messages::SlowDown(duration_ms), config.wire_format, config.checksum, config.keys.for_peer(&tun_ip)) {
+                    Ok(request) => send_to(&*socket, request.as_slice(), addr).await.map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string())
+                };
+                match sent {
+                    Ok(_) => {
+                        stats.slow_downs_sent.fetch_add(1, Ordering::Relaxed);
+                        println!("Asked {} to slow down for {} ms", tun_ip, duration_ms);
+                    },
+                    Err(err) => eprintln!("Failed to ask {} to slow down: {}", addr, err)
+                }
+            }
+        }
     }
 }
 
-pub async fn keep_alive(socket: Arc<UdpSocket>, client_list: Arc<RwLock<HashMap<IpAddr, Vec<SocketAddr>>>>, interval: u64) {
-    let mut interval = time::interval(Duration::from_secs(interval));
+/// Probe the path MTU of `path`, one probe per `config.interval`, sent to every known peer.
+pub async fn probe_pmtu<T: Transport + ?Sized>(socket: Arc<T>, client_list: Arc<Clients>, path: Arc<Path>, clock: SharedClock, config: ProbeConfig) {
+    let mut interval = Interval::new(clock.clone(), config.interval);
+    let overhead = if config.keys.is_enabled() { ENCRYPTION_OVERHEAD } else { 0 } + if config.checksum { CHECKSUM_LEN } else { 0 };
 
     loop {
         interval.tick().await;
 
-        let mut hosts_to_ping: Vec<SocketAddr> = Vec::new();
+        let mut destinations: Vec<(IpAddr, SocketAddr)> = Vec::new();
+        client_list.for_each(|tun_ip, addrs| destinations.extend(addrs.iter().map(|addr| (*tun_ip, *addr))));
+        if destinations.is_empty() {
+            continue
+        }
+        let size = match path.next_probe(clock.now()) {
+            Some(size) => size,
+            None => continue
+        };
 
-        {
-            let cl = client_list.read().unwrap();
-            for ip in cl.keys() {
-                for destinations in cl.get(ip) {
-                    for destination in destinations {
-                        hosts_to_ping.push(destination.clone());
-                    }
+        // Trailing padding is ignored by the decoder in both wire formats
+        let mut probe = match messages::encode_packet(&Messages::Probe(size as u32), config.wire_format) {
+            Ok(probe) => probe,
+            Err(err) => {
+                eprintln!("Failed to encode probe on path {}: {}", path.iface, err);
+                continue
+            }
+        };
+        probe.resize(size.saturating_sub(overhead).max(probe.len()), 0);
+
+        for (tun_ip, destination) in destinations {
+            let mut probe = match config.keys.for_peer(&tun_ip) {
+                Some(cipher) => {
+                    let mut sealed = Vec::new();
+                    cipher.seal_into(&probe, &mut sealed);
+                    sealed
+                },
+                None => probe.clone()
+            };
+            if config.checksum {
+                checksum::append(&mut probe);
+            }
+            if let Err(err) = send_to(&*socket, &probe, destination).await {
+                // Larger than the local interface MTU, no need to wait for it to be lost
+                if err.raw_os_error() == Some(libc::EMSGSIZE) {
+                    path.probe_failed(size, clock.now());
+                    break
                 }
+                eprintln!("Failed to send {} byte path MTU probe to {}: {}", size, destination, err);
+            }
+        }
+    }
+}
+
+// How often a handshake checks for a reply while backing off
+const HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Send keep-alives to the pre-configured remote on `path`, backing off
+/// exponentially, until one is answered or `max_attempts` went unanswered.
+/// Any keep-alive reply received on the path counts as an answer.
+pub async fn handshake<T: Transport + ?Sized>(socket: Arc<T>, client_list: Arc<Clients>, path: Arc<Path>, clock: SharedClock, config: HandshakeConfig, events: Events) {
+    let replies = path.counters.keepalive_replies.load(Ordering::Relaxed);
+    let answered = || path.counters.keepalive_replies.load(Ordering::Relaxed) != replies;
+    let mut backoff = config.initial_backoff;
+    let mut attempts = 0;
+
+    loop {
+        let targets = client_list.with(&config.remote, |addrs| addrs.to_vec()).unwrap_or_default();
+        let hello = match encode_control(&Messages::Keepalive, config.wire_format, config.checksum, config.keys.for_peer(&config.remote)) {
+            Ok(hello) => hello,
+            Err(err) => {
+                eprintln!("Failed to encode handshake on path {}, stopping [handshake task]: {}", path.iface, err);
+                return
+            }
+        };
+        for target in targets {
+            if let Err(err) = send_to(&*socket, hello.as_slice(), target).await {
+                eprintln!("Failed to send handshake to {} on path {}: {}", target, path.iface, err);
+            }
+            path.counters.keepalives_sent.fetch_add(1, Ordering::Relaxed);
+        }
+        attempts += 1;
+
+        let deadline = clock.now() + backoff;
+        while !answered() && clock.now() < deadline {
+            clock.sleep_until(deadline.min(clock.now() + HANDSHAKE_POLL_INTERVAL)).await;
+        }
+        if answered() {
+            println!("Pre-configured remote {} answered on path {} after {} attempts", config.remote, path.iface, attempts);
+            events.emit(Event::PathUp { iface: path.iface.clone() });
+            return
+        }
+        if config.max_attempts.is_some_and(|max_attempts| attempts >= max_attempts) {
+            eprintln!("Pre-configured remote {} didn't answer on path {} after {} attempts. Giving up", config.remote, path.iface, attempts);
+            return
+        }
+        backoff = (backoff * 2).min(config.max_backoff);
+    }
+}
+
+pub async fn keep_alive<T: Transport + ?Sized>(socket: Arc<T>, client_list: Arc<Clients>, path: Arc<Path>, clock: SharedClock, config: KeepAliveConfig, nat_peers: Arc<NatPeers>, events: Events) {
+    let mut interval = Interval::new(clock.clone(), config.interval);
+    // Addresses of another family are pinged by the paths whose sockets have it
+    let families = Families::of(&*socket);
+
+    loop {
+        interval.tick().await;
+
+        if path.check_timeout(clock.now(), config.timeout) {
+            eprintln!("No keep-alive reply on path {} for {:?}. Marking it down", path.iface, config.timeout);
+            events.emit(Event::PathDown { iface: path.iface.clone() });
+        }
+
+        // Without pings nothing awaits a reply, so the path isn't marked down meanwhile
+        if path.is_paused() && !config.while_paused {
+            continue
+        }
+
+        let mut hosts_to_ping: Vec<(IpAddr, SocketAddr)> = Vec::new();
+
+        client_list.for_each(|tun_ip, destinations| {
+            // Peers with direct reachability don't need their mappings kept open
+            if !config.nat_only || nat_peers.contains(tun_ip) {
+                hosts_to_ping.extend(destinations.iter().filter(|destination| families.reaches(destination)).map(|destination| (*tun_ip, *destination)));
             }
+        });
+
+        if !hosts_to_ping.is_empty() {
+            path.ping_sent(clock.now());
         }
 
-        for destination in hosts_to_ping {
+        for (tun_ip, destination) in hosts_to_ping {
             println!("Sending keep-alive packet to: {}", destination);
 
-            let keepalive_msg = bincode::serialize(&Messages::Keepalive).unwrap();
-            socket.send_to(keepalive_msg.as_slice(), destination).await.unwrap();
+            let keepalive_msg = match encode_control(&Messages::Keepalive, config.wire_format, config.checksum, config.keys.for_peer(&tun_ip)) {
+                Ok(keepalive_msg) => keepalive_msg,
+                Err(err) => {
+                    eprintln!("Failed to encode keep-alive for {}: {}", destination, err);
+                    continue
+                }
+            };
+            if let Err(err) = send_to(&*socket, keepalive_msg.as_slice(), destination).await {
+                eprintln!("Failed to send keep-alive to {} on path {}: {}", destination, path.iface, err);
+            }
+            path.counters.keepalives_sent.fetch_add(1, Ordering::Relaxed);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TransportFuture;
+    use crate::clock::SystemClock;
+    use crate::flowcontrol::SlowDownConfig;
+
+    // Sends to `slow` take 300ms, others complete at once
+    #[derive(Debug)]
+    struct SlowTarget {
+        slow: SocketAddr,
+        sent: std::sync::Mutex<Vec<(SocketAddr, Instant)>>
+    }
+
+    impl Transport for SlowTarget {
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Ok("127.0.0.1:1".parse().unwrap())
+        }
+
+        fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+            Box::pin(async move {
+                if target == self.slow {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                }
+                self.sent.lock().unwrap().push((target, Instant::now()));
+                Ok(buf.len())
+            })
+        }
+
+        fn recv_from<'a>(&'a self, _buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_target_does_not_delay_the_others() {
+        let (slow, fast): (SocketAddr, SocketAddr) = ("127.0.0.1:1000".parse().unwrap(), "127.0.0.1:2000".parse().unwrap());
+        let socket = SlowTarget { slow, sent: Default::default() };
+        let started = Instant::now();
+
+        // The slow target is first, so a sequential send would reach the fast one after it
+        let results = send_to_targets(&socket, &[b"one", b"three"], &[slow, fast]).await;
+
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![8, 8]);
+        let sent = socket.sent.into_inner().unwrap();
+        let fast_at = sent.iter().filter(|(target, _)| *target == fast).map(|(_, at)| *at).max().unwrap();
+        assert!(fast_at - started < Duration::from_millis(100), "fast target took {:?}", fast_at - started);
+        assert!(started.elapsed() >= Duration::from_millis(600));
+    }
+
+    #[tokio::test]
+    async fn a_single_target_is_sent_every_datagram() {
+        let fast: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let socket = SlowTarget { slow: "127.0.0.1:1000".parse().unwrap(), sent: Default::default() };
+
+        let results = send_to_targets(&socket, &[b"one", b"three"], &[fast]).await;
+
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![8]);
+        assert_eq!(socket.sent.into_inner().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn read_tun_keeps_reading_while_no_send_task_runs() {
+        let settings = crate::settings::SettingsFileBuilder::new([10, 0, 0, 1].into())
+            .add_send_device(crate::settings::SendDevice::new([127, 0, 0, 1].into(), 0))
+            .build()
+            .unwrap();
+        let config = crate::multipathtunnel::Multipathtunnel::new(settings.clone()).unwrap().task_config(&settings);
+        let client_list = Arc::new(Clients::default());
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        client_list.upsert(peer, None, |addrs, _| addrs.push("127.0.0.1:5000".parse().unwrap()));
+        let (stats, paths) = (Arc::new(Stats::default()), crate::path::Paths::default());
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        paths.write().unwrap().push(path.clone());
+        let dispatcher = Arc::new(Dispatcher::new(16, client_list.clone(), paths, stats.clone(), Arc::new(FlowControl::new(None)), config.clone()));
+        let (tun, tun_peer) = crate::tun::memory_tun();
+        let reading = tokio::spawn(read_tun(tokio::io::split(tun).0, Arc::default(), dispatcher.clone(), stats, Arc::new(SystemClock), config, Events::new(16), client_list, Arc::new(InboundQueues::new(16)), None));
+        let packet = |payload: &[u8]| {
+            let mut packet = Vec::new();
+            etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).udp(4000, 5000).write(&mut packet, payload).unwrap();
+            Bytes::from(packet)
+        };
+
+        // Nobody to hand them to, so they are dropped
+        tun_peer.to_tunnel.send(packet(b"dropped")).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // A send task started later, e.g. for a device added by a reload, gets what comes next
+        let queue = dispatcher.subscribe(path, Families { v4: true, v6: false });
+        tun_peer.to_tunnel.send(packet(b"sent")).unwrap();
+        let sent = tokio::time::timeout(Duration::from_secs(1), queue.pop()).await.unwrap().unwrap();
+        assert_eq!(sent.packet.bytes, packet(b"sent"));
+        assert!(queue.try_pop().is_none());
+
+        // Once the TUN is gone the loop ends, without a panic
+        drop(tun_peer);
+        tokio::time::timeout(Duration::from_secs(1), reading).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn packets_that_fail_to_encode_are_counted_not_fatal() {
+        let path = Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None);
+        let err: bincode::Error = Box::new(bincode::ErrorKind::Custom("no room".to_string()));
+        drop_unencodable(&path, 1400, [10, 0, 0, 2].into(), &err);
+        drop_unencodable(&path, 60, [10, 0, 0, 2].into(), &err);
+        assert_eq!(path.counters.tx_encode_errors.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn receive_buffers_fit_a_full_payload_with_every_header() {
+        let cipher = Cipher::new(&[7; crate::crypto::KEY_LEN]);
+        for mtu in [1424, 9000, 60000] {
+            let mut state = 1u32;
+            let noise: Vec<u8> = (0..mtu).map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            }).collect();
+            let mut compressed = Vec::new();
+            compress_prepend_size_into(&noise, &mut compressed);
+            for format in [WireFormat::Bincode, WireFormat::Versioned, WireFormat::Compact] {
+                let (mut encoded, mut sealed) = (Vec::new(), Vec::new());
+                let stamp = crate::messages::PathStamp { path_id: u16::MAX, path_seq: u64::MAX };
+                messages::encode_data_into(usize::MAX, Some(u32::MAX), Some(u64::MAX), Some(stamp), &compressed, format, &mut encoded).unwrap();
+                cipher.seal_into(&encoded, &mut sealed);
+                crate::checksum::append(&mut sealed);
+                assert!(sealed.len() < recv_buffer_len(mtu), "{} byte {:?} datagram for MTU {}", sealed.len(), format, mtu);
+            }
+        }
+        assert_eq!(recv_buffer_len(65535), MAX_DATAGRAM_LEN);
+    }
+
+    #[test]
+    fn outer_tos_maps_the_dscp_and_keeps_the_ecn_bits() {
+        let remap = HashMap::from([(46, 10), (10, 63)]);
+        assert_eq!(outer_tos((46 << 2) | 3, &remap), (10 << 2) | 3);
+        assert_eq!(outer_tos(10 << 2, &remap), 63 << 2);
+        // Unlisted values pass through
+        assert_eq!(outer_tos((18 << 2) | 1, &remap), (18 << 2) | 1);
+    }
+
+    #[test]
+    fn outer_tos_masks_remapped_values_to_six_bits() {
+        let remap = HashMap::from([(1, 0xff)]);
+        assert_eq!(outer_tos(1 << 2, &remap), 0x3f << 2);
+    }
+
+    type Received = std::io::Result<(Vec<u8>, SocketAddr)>;
+
+    // Hands out scripted receive results in order, then waits forever.
+    // Keeps what is sent, after failing sends with the scripted errors.
+    #[derive(Debug, Default)]
+    struct ScriptedReceives {
+        script: std::sync::Mutex<std::collections::VecDeque<Received>>,
+        send_errors: std::sync::Mutex<std::collections::VecDeque<ErrorKind>>,
+        sent: std::sync::Mutex<Vec<(Vec<u8>, SocketAddr)>>
+    }
+
+    impl Transport for ScriptedReceives {
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Ok("127.0.0.1:1".parse().unwrap())
+        }
+
+        fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+            let error = self.send_errors.lock().unwrap().pop_front();
+            if error.is_none() {
+                self.sent.lock().unwrap().push((buf.to_vec(), target));
+            }
+            Box::pin(async move { error.map_or(Ok(buf.len()), |kind| Err(kind.into())) })
+        }
+
+        fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+            Box::pin(async move {
+                let next = self.script.lock().unwrap().pop_front();
+                match next {
+                    Some(Ok((datagram, from))) => {
+                        buf[..datagram.len()].copy_from_slice(&datagram);
+                        Ok((datagram.len(), from))
+                    },
+                    Some(Err(err)) => Err(err),
+                    None => std::future::pending().await
+                }
+            })
+        }
+    }
+
+    fn spawn_recv_udp(socket: Arc<ScriptedReceives>, path: Arc<Path>, flow_control: FlowControl) -> tokio::task::JoinHandle<()> {
+        spawn_recv_udp_with_state(socket, path, flow_control).0
+    }
+
+    fn spawn_recv_udp_with_state(socket: Arc<ScriptedReceives>, path: Arc<Path>, flow_control: FlowControl) -> (tokio::task::JoinHandle<()>, Arc<RecvState>) {
+        let settings = crate::settings::SettingsFileBuilder::new([10, 0, 0, 2].into())
+            .add_send_device(crate::settings::SendDevice::new([127, 0, 0, 1].into(), 0))
+            .build()
+            .unwrap();
+        let config = crate::multipathtunnel::Multipathtunnel::new(settings.clone()).unwrap().task_config(&settings);
+        let clock: SharedClock = Arc::new(crate::clock::SystemClock);
+        let (removals, goodbyes) = (broadcast::channel(1).1, mpsc::channel(1).0);
+        let state = Arc::new(RecvState::new(&config));
+        let receiving = tokio::spawn(recv_udp(socket, Arc::new(InboundQueues::new(16)), Arc::default(), Arc::default(), path, clock, config.clone(), Arc::default(),
+            Events::new(16), removals, Arc::default(), None, state.clone(), ControlMessages::new(16),
+            Arc::new(flow_control), Capture::new(16), None, Arc::default(), goodbyes));
+        (receiving, state)
+    }
+
+    fn data_datagram(seq: usize) -> Vec<u8> {
+        let mut packet = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).udp(4000, 5000).write(&mut packet, b"data").unwrap();
+        let mut datagram = Vec::new();
+        messages::encode_data_into(seq, None, None, None, &lz4_flex::compress_prepend_size(&packet), WireFormat::Bincode, &mut datagram).unwrap();
+        datagram
+    }
+
+    #[tokio::test]
+    async fn recv_udp_survives_connection_refused() {
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let socket = Arc::new(ScriptedReceives::default());
+        socket.script.lock().unwrap().extend([
+            Err(std::io::ErrorKind::ConnectionRefused.into()),
+            Ok((data_datagram(1), peer)),
+            Err(std::io::ErrorKind::ConnectionReset.into()),
+            Ok((data_datagram(2), peer))
+        ]);
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        let receiving = spawn_recv_udp(socket, path.clone(), FlowControl::new(None));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Both datagrams after the errors were received, and the task goes on
+        assert_eq!(path.counters.rx_packets.load(Ordering::Relaxed), 2);
+        assert!(!receiving.is_finished());
+        receiving.abort();
+    }
+
+    #[tokio::test]
+    async fn floods_from_unknown_or_unparseable_senders_are_logged_once() {
+        let stranger: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let control = encode_control(&Messages::Control(Bytes::from_static(b"hello")), WireFormat::Bincode, false, None).unwrap();
+        let mut unparseable = Vec::new();
+        messages::encode_data_into(1, None, None, None, &lz4_flex::compress_prepend_size(b"not an IP packet"), WireFormat::Bincode, &mut unparseable).unwrap();
+        let socket = Arc::new(ScriptedReceives::default());
+        for _ in 0..1000 {
+            socket.script.lock().unwrap().extend([Ok((control.clone(), stranger)), Ok((unparseable.clone(), stranger))]);
+        }
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        let (receiving, state) = spawn_recv_udp_with_state(socket.clone(), path, FlowControl::new(None));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !socket.script.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        }).await.unwrap();
+        tokio::task::yield_now().await;
+        // One line each was printed, the rest only counted for the summary
+        let later = Instant::now() + GARBAGE_LOG_INTERVAL;
+        assert_eq!(state.unknown_control_log.lock().unwrap().summary(later), Some(999));
+        assert_eq!(state.unparseable_log.lock().unwrap().summary(later), Some(999));
+        receiving.abort();
+    }
+
+    #[tokio::test]
+    async fn transient_send_errors_are_retried() {
+        let target: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let socket = ScriptedReceives::default();
+        socket.send_errors.lock().unwrap().extend([ErrorKind::WouldBlock, ErrorKind::Interrupted, ErrorKind::WouldBlock]);
+
+        assert_eq!(send_to(&socket, b"datagram", target).await.unwrap(), 8);
+        assert_eq!(*socket.sent.lock().unwrap(), [(b"datagram".to_vec(), target)]);
+    }
+
+    #[tokio::test]
+    async fn sends_that_keep_failing_or_fail_for_good_return_the_error() {
+        let target: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let socket = ScriptedReceives::default();
+        socket.send_errors.lock().unwrap().extend([ErrorKind::WouldBlock; SEND_ATTEMPTS]);
+        assert_eq!(send_to(&socket, b"datagram", target).await.unwrap_err().kind(), ErrorKind::WouldBlock);
+        // The attempts are used up on the errors, none is left over
+        assert!(socket.send_errors.lock().unwrap().is_empty());
+
+        socket.send_errors.lock().unwrap().extend([ErrorKind::PermissionDenied, ErrorKind::WouldBlock]);
+        assert_eq!(send_to(&socket, b"datagram", target).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(socket.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_alive_goes_on_after_a_failed_send() {
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let socket = Arc::new(ScriptedReceives::default());
+        socket.send_errors.lock().unwrap().extend([ErrorKind::PermissionDenied, ErrorKind::WouldBlock]);
+        let client_list = Arc::new(Clients::default());
+        client_list.upsert([10, 0, 0, 1].into(), None, |client, _| client.push(peer));
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        let config = KeepAliveConfig {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_secs(1),
+            wire_format: WireFormat::Bincode,
+            checksum: false,
+            nat_only: false,
+            while_paused: false,
+            keys: Keys::default()
+        };
+        let pinging = tokio::spawn(keep_alive(socket.clone(), client_list, path, Arc::new(crate::clock::SystemClock), config, Arc::default(), Events::new(16)));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pinging.is_finished());
+        let sent = socket.sent.lock().unwrap().len();
+        assert!(sent >= 2, "{} keep-alives sent", sent);
+        pinging.abort();
+    }
+
+    // Paused, so the sleep after each advance only ends once the task is idle
+    #[tokio::test(start_paused = true)]
+    async fn keep_alives_go_out_on_the_ticks_of_the_clock() {
+        let socket = Arc::new(ScriptedReceives::default());
+        let client_list = Arc::new(Clients::default());
+        client_list.upsert([10, 0, 0, 1].into(), None, |client, _| client.push("127.0.0.1:5000".parse().unwrap()));
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let config = KeepAliveConfig {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(60),
+            wire_format: WireFormat::Bincode,
+            checksum: false,
+            nat_only: false,
+            while_paused: false,
+            keys: Keys::default()
+        };
+        let pinging = tokio::spawn(keep_alive(socket.clone(), client_list, path.clone(), clock.clone(), config, Arc::default(), Events::new(16)));
+        let sent_after = |advance: u64| {
+            let (clock, socket) = (clock.clone(), socket.clone());
+            async move {
+                clock.advance(Duration::from_secs(advance));
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                socket.sent.lock().unwrap().len()
+            }
+        };
+
+        // The first tick is straight away, the others every 5 s of clock time
+        assert_eq!(sent_after(0).await, 1);
+        assert_eq!(sent_after(4).await, 1);
+        assert_eq!(sent_after(1).await, 2);
+        assert_eq!(sent_after(2).await, 2);
+        assert_eq!(sent_after(3).await, 3);
+        assert_eq!(path.counters.keepalives_sent.load(Ordering::Relaxed), 3);
+        pinging.abort();
+    }
+
+    #[tokio::test]
+    async fn recv_udp_survives_would_block_and_interrupted() {
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let socket = Arc::new(ScriptedReceives::default());
+        socket.script.lock().unwrap().extend([
+            Err(ErrorKind::WouldBlock.into()),
+            Err(ErrorKind::Interrupted.into()),
+            Ok((data_datagram(1), peer))
+        ]);
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        let receiving = spawn_recv_udp(socket, path.clone(), FlowControl::new(None));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(path.counters.rx_packets.load(Ordering::Relaxed), 1);
+        assert!(!receiving.is_finished());
+        receiving.abort();
+    }
+
+    #[tokio::test]
+    async fn recv_udp_stops_on_a_fatal_error() {
+        let socket = Arc::new(ScriptedReceives::default());
+        socket.script.lock().unwrap().push_back(Err(std::io::ErrorKind::PermissionDenied.into()));
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+
+        tokio::time::timeout(Duration::from_secs(1), spawn_recv_udp(socket, path.clone(), FlowControl::new(None))).await.unwrap().unwrap();
+        assert_eq!(path.counters.rx_packets.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn sustained_inbound_drops_ask_the_peer_to_slow_down() {
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let socket = Arc::new(ScriptedReceives::default());
+        // Nothing takes from the inbound queue, so all past its 16 packets are dropped
+        socket.script.lock().unwrap().extend((1..=16 + 8).map(|seq| Ok((data_datagram(seq), peer))));
+        let path = Arc::new(Path::new("lo".to_string(), "127.0.0.1:1".parse().unwrap(), 0, 1.0, 1.0, None, None));
+        let slow_down = SlowDownConfig { threshold: 4, window: Duration::from_secs(10), duration: Duration::from_millis(1500) };
+        let receiving = spawn_recv_udp(socket.clone(), path, FlowControl::new(Some(slow_down)));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let sent: Vec<_> = socket.sent.lock().unwrap().iter()
+            .map(|(datagram, target)| (messages::decode_packet(datagram, WireFormat::Bincode, u64::MAX).unwrap(), *target))
+            .filter(|(message, _)| matches!(message, Messages::SlowDown(_)))
+            .collect();
+        // Once for the window, not for each of the drops past the threshold
+        assert_eq!(sent, [(Messages::SlowDown(1500), peer)]);
+        receiving.abort();
+    }
+
+    #[tokio::test]
+    async fn short_tun_writes_are_finished_and_counted() {
+        let (mut tun, mut peer) = crate::tun::memory_tun();
+        let stats = Stats::default();
+        peer.inject_short_write(10);
+
+        let packet: Vec<u8> = (0..33).collect();
+        write_packet(&mut tun, &packet, &stats).await.unwrap();
+        // The device took 10 bytes, then the rest in another write
+        assert_eq!(peer.from_tunnel.recv().await.unwrap(), &packet[..10]);
+        assert_eq!(peer.from_tunnel.recv().await.unwrap(), &packet[10..]);
+        assert_eq!(stats.tun_short_writes.load(Ordering::Relaxed), 1);
+
+        write_packet(&mut tun, &packet, &stats).await.unwrap();
+        assert_eq!(peer.from_tunnel.recv().await.unwrap(), packet);
+        assert_eq!(stats.tun_short_writes.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,224 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::{UdpSocket, UnixDatagram};
+
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// Datagram socket a send device runs over. Peers are addressed by socket
+/// address whatever the transport.
+pub trait Transport: Send + Sync + std::fmt::Debug {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize>;
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)>;
+
+    /// The UDP socket underneath, for socket options like the ToS. `None` for other transports.
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        None
+    }
+
+    /// The same transport over the same socket, registered with the
+    /// runtime of the calling context instead, so its I/O is driven there.
+    fn reregister(&self) -> io::Result<Arc<dyn Transport>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the transport can't move to another runtime"))
+    }
+}
+
+impl Transport for UdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(UdpSocket::send_to(self, buf, target))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+
+    fn reregister(&self) -> io::Result<Arc<dyn Transport>> {
+        Ok(Arc::new(reregister_udp(self)?))
+    }
+}
+
+/// A socket for the same file as `socket`, registered with the runtime of the calling context.
+pub fn reregister_udp(socket: &UdpSocket) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(std::net::UdpSocket::from(socket.as_fd().try_clone_to_owned()?))
+}
+
+/// Address families a transport can send to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Families {
+    pub v4: bool,
+    pub v6: bool
+}
+
+impl Families {
+    pub const NONE: Families = Families { v4: false, v6: false };
+
+    /// The families of `addrs`.
+    pub fn of_addrs(addrs: &[SocketAddr]) -> Families {
+        Families { v4: addrs.iter().any(SocketAddr::is_ipv4), v6: addrs.iter().any(SocketAddr::is_ipv6) }
+    }
+
+    pub fn union(self, other: Families) -> Families {
+        Families { v4: self.v4 || other.v4, v6: self.v6 || other.v6 }
+    }
+
+    /// Whether a family is in both.
+    pub fn overlaps(&self, other: &Families) -> bool {
+        (self.v4 && other.v4) || (self.v6 && other.v6)
+    }
+
+    /// An IPv4 UDP socket reaches IPv4 addresses, an IPv6 one IPv6 addresses
+    /// and, unless it is IPv6 only, IPv4 ones too. Other transports reach any.
+    pub fn of<T: Transport + ?Sized>(socket: &T) -> Families {
+        let udp = match socket.udp_socket() {
+            Some(udp) => udp,
+            None => return Families { v4: true, v6: true }
+        };
+        match udp.local_addr() {
+            Ok(SocketAddr::V4(_)) => Families { v4: true, v6: false },
+            Ok(SocketAddr::V6(_)) => Families { v4: !socket2::SockRef::from(udp).only_v6().unwrap_or(true), v6: true },
+            Err(_) => Families { v4: true, v6: true }
+        }
+    }
+
+    pub fn reaches(&self, target: &SocketAddr) -> bool {
+        match target {
+            SocketAddr::V4(_) => self.v4,
+            SocketAddr::V6(_) => self.v6
+        }
+    }
+}
+
+/// Unix datagram sockets in a directory, in place of UDP between processes
+/// on one host. Each socket file is named after the socket address it
+/// stands for, e.g. `127.0.0.1:4000`, so peers are addressed as with UDP.
+#[derive(Debug)]
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    dir: PathBuf,
+    local_addr: SocketAddr
+}
+
+impl UnixTransport {
+    /// Bind the socket standing for `local_addr` in `dir`, replacing a stale socket file.
+    pub fn bind(dir: &Path, local_addr: SocketAddr) -> io::Result<UnixTransport> {
+        let path = socket_path(dir, local_addr);
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        Ok(UnixTransport {
+            socket: UnixDatagram::bind(&path)?,
+            dir: dir.to_path_buf(),
+            local_addr
+        })
+    }
+}
+
+impl Transport for UnixTransport {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(async move { self.socket.send_to(buf, socket_path(&self.dir, target)).await })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            loop {
+                let (len, source) = self.socket.recv_from(buf).await?;
+                // Datagrams from sockets that don't stand for an address can't be answered
+                match source.as_pathname().and_then(Path::file_name).and_then(|name| name.to_str()?.parse().ok()) {
+                    Some(source) => return Ok((len, source)),
+                    None => eprintln!("Dropping datagram from unaddressable unix socket {:?}", source)
+                }
+            }
+        })
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(socket_path(&self.dir, self.local_addr));
+    }
+}
+
+fn socket_path(dir: &Path, addr: SocketAddr) -> PathBuf {
+    dir.join(addr.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mptun-unix-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn datagrams_round_trip_between_unix_transports() {
+        let dir = socket_dir("round-trip");
+        let (a_addr, b_addr): (SocketAddr, SocketAddr) = ("127.0.0.1:4000".parse().unwrap(), "127.0.0.1:4001".parse().unwrap());
+        let (a, b) = (UnixTransport::bind(&dir, a_addr).unwrap(), UnixTransport::bind(&dir, b_addr).unwrap());
+        assert_eq!(a.local_addr().unwrap(), a_addr);
+        assert!(a.udp_socket().is_none());
+
+        let mut buf = [0; 64];
+        assert_eq!(a.send_to(b"ping", b_addr).await.unwrap(), 4);
+        assert_eq!(b.recv_from(&mut buf).await.unwrap(), (4, a_addr));
+        assert_eq!(&buf[..4], b"ping");
+        // And back to the address it came from
+        b.send_to(b"pong", a_addr).await.unwrap();
+        assert_eq!(a.recv_from(&mut buf).await.unwrap(), (4, b_addr));
+        assert_eq!(&buf[..4], b"pong");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale_socket_files_are_replaced_and_removed_on_drop() {
+        let dir = socket_dir("stale");
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        std::fs::write(socket_path(&dir, addr), b"left over").unwrap();
+
+        let transport = UnixTransport::bind(&dir, addr).unwrap();
+        assert!(socket_path(&dir, addr).exists());
+        drop(transport);
+        assert!(!socket_path(&dir, addr).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn datagrams_from_unaddressable_sockets_are_skipped() {
+        let dir = socket_dir("unaddressable");
+        let (addr, peer_addr): (SocketAddr, SocketAddr) = ("127.0.0.1:4000".parse().unwrap(), "127.0.0.1:4001".parse().unwrap());
+        let transport = UnixTransport::bind(&dir, addr).unwrap();
+        let peer = UnixTransport::bind(&dir, peer_addr).unwrap();
+        let stranger = UnixDatagram::unbound().unwrap();
+
+        stranger.send_to(b"who", socket_path(&dir, addr)).await.unwrap();
+        peer.send_to(b"peer", addr).await.unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(transport.recv_from(&mut buf).await.unwrap(), (4, peer_addr));
+        assert_eq!(&buf[..4], b"peer");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
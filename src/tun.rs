@@ -0,0 +1,317 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_tun::TunBuilder;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use socket2::{Domain, Socket, Type};
+
+use crate::mtu::MtuReader;
+use crate::settings::SettingsFile;
+
+/// What the tunnel needs from its TUN device: each read returns one packet
+/// and each write takes one packet. Implemented by `tokio_tun::Tun` and by
+/// `MemoryTun`, which lets tunnels run without root.
+pub trait TunDevice: AsyncRead + AsyncWrite + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + 'static> TunDevice for T {}
+
+/// Creates the TUN device when the tunnel starts.
+pub trait TunFactory {
+    type Device: TunDevice;
+
+    fn create(&self, settings: &SettingsFile, mtu: usize) -> Result<Self::Device, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Create the device with `queues` queues, each read by its own task.
+    /// Devices without multiple queues can only be created with one.
+    fn create_queues(&self, settings: &SettingsFile, mtu: usize, queues: usize) -> Result<Vec<Self::Device>, Box<dyn std::error::Error + Send + Sync>> {
+        if queues > 1 {
+            return Err(format!("{} TUN queues requested, but this TUN device has only one", queues).into())
+        }
+        Ok(vec![self.create(settings, mtu)?])
+    }
+
+    /// Reads `device`'s MTU while the tunnel runs, so a change is noticed.
+    /// `None` for devices whose MTU can't change.
+    fn mtu_reader(&self, _device: &Self::Device) -> Option<MtuReader> {
+        None
+    }
+}
+
+/// A kernel TUN device with the tunnel's IPv4 address on a /24.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KernelTun;
+
+impl TunFactory for KernelTun {
+    type Device = tokio_tun::Tun;
+
+    fn create(&self, settings: &SettingsFile, mtu: usize) -> Result<tokio_tun::Tun, Box<dyn std::error::Error + Send + Sync>> {
+        let mut queues = self.create_queues(settings, mtu, 1)?;
+        Ok(queues.remove(0))
+    }
+
+    fn create_queues(&self, settings: &SettingsFile, mtu: usize, queues: usize) -> Result<Vec<tokio_tun::Tun>, Box<dyn std::error::Error + Send + Sync>> {
+        // tokio_tun can only assign IPv4 addresses
+        let address = match settings.tun_ip {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(address) => return Err(format!("IPv6 TUN address {} is not supported by the kernel TUN device", address).into())
+        };
+        let mut builder = TunBuilder::new()
+            .name("")
+            .tap(false)
+            .packet_info(false)
+            .mtu(mtu as i32)
+            .up()
+            .address(address)
+            .netmask(Ipv4Addr::new(255, 255, 255, 0));
+        if let Some(broadcast) = settings.tun_broadcast_addr() {
+            builder = builder.broadcast(broadcast);
+        }
+        // One queue is opened without IFF_MULTI_QUEUE, like before it was supported
+        let tuns = if queues > 1 { builder.try_build_mq(queues)? } else { vec![builder.try_build()?] };
+        let tun = &tuns[0];
+
+        println!("-----------");
+        println!("tun created");
+        println!("-----------");
+
+        println!(
+            "┌ name: {}\n├ fd: {}\n├ mtu: {}\n├ flags: {}\n├ address: {}\n├ destination: {}\n├ broadcast: {}\n├ netmask: {}\n└ queues: {}",
+            tun.name(),
+            tun.as_raw_fd(),
+            tun.mtu()?,
+            tun.flags()?,
+            tun.address()?,
+            tun.destination()?,
+            // Fails when the device has none
+            tun.broadcast().map_or_else(|_| "none".to_string(), |broadcast| broadcast.to_string()),
+            tun.netmask()?,
+            tuns.len()
+        );
+
+        Ok(tuns)
+    }
+
+    fn mtu_reader(&self, device: &tokio_tun::Tun) -> Option<MtuReader> {
+        let name = device.name().to_string();
+        Some(Arc::new(move || interface_mtu(&name).ok()))
+    }
+}
+
+/// MTU of the network interface `name`, as `ip link` shows it.
+pub fn interface_mtu(name: &str) -> io::Result<usize> {
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    // Leaves room for the terminating nul
+    if name.len() >= request.ifr_name.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("interface name `{}` is too long", name)))
+    }
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU, &mut request) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(unsafe { request.ifr_ifru.ifru_mtu } as usize)
+}
+
+/// Hands out one prepared device, e.g. a `MemoryTun`. Fails if asked twice.
+#[derive(Debug)]
+pub struct PreparedTun<T>(Mutex<Option<T>>);
+
+impl<T> PreparedTun<T> {
+    pub fn new(device: T) -> PreparedTun<T> {
+        PreparedTun(Mutex::new(Some(device)))
+    }
+}
+
+impl<T: TunDevice> TunFactory for PreparedTun<T> {
+    type Device = T;
+
+    fn create(&self, _settings: &SettingsFile, _mtu: usize) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        self.0.lock().unwrap().take().ok_or_else(|| "the prepared TUN device was already used".into())
+    }
+}
+
+/// An in-memory TUN device. Packets sent on the `MemoryTunPeer` are read
+/// by the tunnel, and packets the tunnel writes show up on the peer. Lets
+/// an application, e.g. a userspace network stack, take the place of a
+/// kernel TUN.
+#[derive(Debug)]
+pub struct MemoryTun {
+    incoming: mpsc::UnboundedReceiver<Bytes>,
+    outgoing: mpsc::UnboundedSender<Bytes>,
+    read_errors: mpsc::UnboundedReceiver<io::ErrorKind>,
+    short_writes: mpsc::UnboundedReceiver<usize>,
+    // Set by the peer, 0 until it is
+    mtu: Arc<AtomicUsize>
+}
+
+impl MemoryTun {
+    /// Reads the MTU set with `MemoryTunPeer::set_mtu`.
+    pub fn mtu_reader(&self) -> MtuReader {
+        let mtu = self.mtu.clone();
+        Arc::new(move || Some(mtu.load(Ordering::Relaxed)).filter(|mtu| *mtu > 0))
+    }
+}
+
+/// The host side of a `MemoryTun`.
+#[derive(Debug)]
+pub struct MemoryTunPeer {
+    pub to_tunnel: mpsc::UnboundedSender<Bytes>,
+    pub from_tunnel: mpsc::UnboundedReceiver<Bytes>,
+    read_errors: mpsc::UnboundedSender<io::ErrorKind>,
+    short_writes: mpsc::UnboundedSender<usize>,
+    mtu: Arc<AtomicUsize>
+}
+
+impl MemoryTunPeer {
+    /// Make the tunnel's next read fail with `kind`, e.g. to simulate the device being removed.
+    pub fn inject_read_error(&self, kind: io::ErrorKind) {
+        let _ = self.read_errors.send(kind);
+    }
+
+    /// Make the tunnel's next write take at most `len` bytes. The rest arrives
+    /// as a separate packet, if the tunnel writes it.
+    pub fn inject_short_write(&self, len: usize) {
+        let _ = self.short_writes.send(len);
+    }
+
+    /// Change the device's MTU, as `ip link set mtu` would a kernel TUN's.
+    /// Until it is set, the tunnel's configured MTU is assumed.
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+}
+
+pub fn memory_tun() -> (MemoryTun, MemoryTunPeer) {
+    let (to_tunnel, incoming) = mpsc::unbounded_channel();
+    let (outgoing, from_tunnel) = mpsc::unbounded_channel();
+    let (read_errors, injected_errors) = mpsc::unbounded_channel();
+    let (short_writes, injected_short_writes) = mpsc::unbounded_channel();
+    let mtu = Arc::new(AtomicUsize::new(0));
+    (MemoryTun { incoming, outgoing, read_errors: injected_errors, short_writes: injected_short_writes, mtu: mtu.clone() },
+     MemoryTunPeer { to_tunnel, from_tunnel, read_errors, short_writes, mtu })
+}
+
+impl AsyncRead for MemoryTun {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Poll::Ready(Some(kind)) = self.read_errors.poll_recv(cx) {
+            return Poll::Ready(Err(io::Error::from(kind)))
+        }
+        match self.incoming.poll_recv(cx) {
+            // Like a real TUN, a packet larger than the buffer is truncated
+            Poll::Ready(Some(packet)) => {
+                let len = packet.len().min(buf.remaining());
+                buf.put_slice(&packet[..len]);
+                Poll::Ready(Ok(()))
+            },
+            // The peer is gone, report end of file
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+impl AsyncWrite for MemoryTun {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let len = self.short_writes.try_recv().map_or(buf.len(), |limit| limit.min(buf.len()));
+        match self.outgoing.send(Bytes::copy_from_slice(&buf[..len])) {
+            Ok(()) => Poll::Ready(Ok(len)),
+            Err(_) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "memory TUN peer dropped")))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Where packets received from peers are delivered besides, or instead of, the TUN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundDelivery {
+    // Write to the TUN and hand a copy to the sink
+    TunAndSink,
+    // Only hand packets to the sink
+    SinkOnly
+}
+
+/// An in-process consumer of received packets, e.g. a userspace network stack.
+/// Packets are dropped, and counted in `Stats::sink_dropped`, while the channel is full.
+#[derive(Debug, Clone)]
+pub struct InboundSink {
+    pub sender: mpsc::Sender<Bytes>,
+    pub delivery: InboundDelivery
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::settings::{SendDevice, SettingsFileBuilder, TunBroadcast};
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_tun_passes_one_packet_per_read_and_write() {
+        let (mut tun, mut peer) = memory_tun();
+        peer.to_tunnel.send(Bytes::from_static(b"first")).unwrap();
+        peer.to_tunnel.send(Bytes::from_static(b"second")).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(tun.read(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(tun.read(&mut buf).await.unwrap(), 6);
+
+        tun.write_all(b"reply").await.unwrap();
+        assert_eq!(peer.from_tunnel.recv().await.unwrap(), Bytes::from_static(b"reply"));
+
+        drop(peer);
+        assert_eq!(tun.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(tun.write(b"nobody").await.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn prepared_tun_hands_out_its_device_once() {
+        let settings = SettingsFileBuilder::new([10, 0, 0, 1].into())
+            .add_send_device(SendDevice::new([127, 0, 0, 1].into(), 0))
+            .build()
+            .unwrap();
+        let (tun, _peer) = memory_tun();
+        let factory = PreparedTun::new(tun);
+        assert!(factory.create(&settings, 1500).is_ok());
+        assert!(factory.create(&settings, 1500).is_err());
+        assert!(PreparedTun::new(memory_tun().0).create_queues(&settings, 1500, 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn kernel_tun_gets_the_configured_broadcast_address() {
+        let mut settings = SettingsFileBuilder::new([10, 9, 0, 1].into())
+            .add_send_device(SendDevice::new([127, 0, 0, 1].into(), 0))
+            .build()
+            .unwrap();
+        let cases = vec![
+            (Some(TunBroadcast::Address([10, 9, 0, 255].into())), Ipv4Addr::new(10, 9, 0, 255)),
+            // The kernel reports no broadcast address as 0.0.0.0
+            (Some(TunBroadcast::None), Ipv4Addr::UNSPECIFIED),
+            (None, Ipv4Addr::BROADCAST)
+        ];
+        for (broadcast, expected) in cases {
+            settings.tun_broadcast = broadcast;
+            let tun = match KernelTun.create(&settings, 1400) {
+                Ok(tun) => tun,
+                Err(err) => {
+                    eprintln!("Skipping, no kernel TUN device: {}", err);
+                    return
+                }
+            };
+            assert_eq!(tun.broadcast().unwrap(), expected, "{:?}", broadcast);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Send weights for the weighted path mode. Each path starts at its device's
+// configured weight. With auto_weights the weights follow the paths' quality
+// instead: the path with the lowest keep-alive RTT and loss tends to
+// max_weight, the others to their share of its quality.
+
+use std::time::Duration;
+
+// RTTs below this count as equal, so a near zero RTT can't take every packet
+const MIN_RTT: Duration = Duration::from_millis(1);
+
+/// How derived weights follow the paths' quality.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeightPolicy {
+    // Fraction of the way to its target a weight moves per update. Lower
+    // values damp oscillation between paths that trade places.
+    pub adapt_rate: f64,
+    pub min_weight: f64,
+    pub max_weight: f64
+}
+
+#[derive(Debug)]
+pub struct PathWeight {
+    configured: f64,
+    weight: f64,
+    // Smooth weighted round robin credit, see `pick`
+    credit: f64,
+    // Keep-alive counters at the last update, and the loss since
+    sent: u64,
+    replies: u64,
+    loss: f64
+}
+
+impl PathWeight {
+    pub fn new(configured: f64) -> PathWeight {
+        PathWeight { configured, weight: configured, credit: 0.0, sent: 0, replies: 0, loss: 0.0 }
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// Go back to the configured weight, e.g. once auto_weights is turned off.
+    pub fn reset(&mut self) {
+        self.weight = self.configured;
+    }
+
+    /// Loss of the keep-alives sent since the last call, from the path's
+    /// cumulative counters. Unchanged while none were sent.
+    pub fn observe_loss(&mut self, sent: u64, replies: u64) -> f64 {
+        let new_sent = sent.saturating_sub(self.sent);
+        let new_replies = replies.saturating_sub(self.replies);
+        if new_sent > 0 {
+            self.loss = 1.0 - (new_replies.min(new_sent) as f64 / new_sent as f64);
+        }
+        self.sent = sent;
+        self.replies = replies;
+        self.loss
+    }
+
+    /// Move the weight `policy.adapt_rate` of the way toward `target`.
+    pub fn adapt(&mut self, target: f64, policy: &WeightPolicy) {
+        self.weight = (self.weight + policy.adapt_rate * (target - self.weight)).clamp(policy.min_weight, policy.max_weight);
+    }
+}
+
+/// Higher for a lower RTT and lower loss: the share of keep-alives
+/// answered per second of RTT.
+pub fn quality(srtt: Duration, loss: f64) -> f64 {
+    (1.0 - loss) / srtt.max(MIN_RTT).as_secs_f64()
+}
+
+/// Target weights for paths of the given RTT and loss, the best at
+/// `max_weight`. Paths without an RTT yet count as the slowest.
+pub fn targets(observed: &[(Option<Duration>, f64)], policy: &WeightPolicy) -> Vec<f64> {
+    let slowest = observed.iter().filter_map(|(srtt, _)| *srtt).max().unwrap_or(MIN_RTT);
+    let qualities: Vec<f64> = observed.iter().map(|(srtt, loss)| quality(srtt.unwrap_or(slowest), *loss)).collect();
+    let best = qualities.iter().copied().fold(0.0, f64::max);
+    qualities.into_iter()
+        .map(|quality| if best > 0.0 { policy.max_weight * quality / best } else { policy.max_weight })
+        .collect()
+}
+
+/// Smooth weighted round robin: the index of the weight to send the next
+/// packet with. Over any run of packets each gets a share close to its
+/// weight, spread evenly rather than in bursts.
+pub fn pick(weights: &mut [&mut PathWeight]) -> Option<usize> {
+    let total: f64 = weights.iter().map(|weight| weight.weight).sum();
+    for weight in weights.iter_mut() {
+        weight.credit += weight.weight;
+    }
+    let (chosen, _) = weights.iter().enumerate().max_by(|(_, a), (_, b)| a.credit.total_cmp(&b.credit))?;
+    weights[chosen].credit -= total;
+    Some(chosen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: WeightPolicy = WeightPolicy { adapt_rate: 0.5, min_weight: 0.1, max_weight: 1.0 };
+
+    #[test]
+    fn picks_follow_the_weights_spread_evenly() {
+        let (mut heavy, mut light) = (PathWeight::new(3.0), PathWeight::new(1.0));
+        let picks: Vec<_> = (0..400).map(|_| pick(&mut [&mut heavy, &mut light]).unwrap()).collect();
+        assert_eq!(picks.iter().filter(|chosen| **chosen == 0).count(), 300);
+        // Never two of the light one's packets in a row
+        assert!(picks.windows(2).all(|pair| pair != [1, 1]));
+        assert_eq!(pick(&mut []), None);
+    }
+
+    #[test]
+    fn loss_is_measured_since_the_last_observation() {
+        let mut weight = PathWeight::new(1.0);
+        assert_eq!(weight.observe_loss(10, 10), 0.0);
+        assert_eq!(weight.observe_loss(20, 15), 0.5);
+        // Nothing sent since, so the last loss stands
+        assert_eq!(weight.observe_loss(20, 15), 0.5);
+        assert_eq!(weight.observe_loss(24, 18), 0.25);
+    }
+
+    #[test]
+    fn targets_scale_with_quality_relative_to_the_best_path() {
+        let observed = [
+            (Some(Duration::from_millis(10)), 0.0),
+            (Some(Duration::from_millis(40)), 0.0),
+            (Some(Duration::from_millis(10)), 0.5),
+            // Counts as the slowest
+            (None, 0.0)
+        ];
+        assert_eq!(targets(&observed, &POLICY), [1.0, 0.25, 0.5, 0.25]);
+        // Below MIN_RTT every RTT is as good as another
+        assert_eq!(targets(&[(Some(Duration::from_micros(10)), 0.0), (Some(Duration::from_micros(900)), 0.0)], &POLICY), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn weights_move_part_of_the_way_within_the_bounds() {
+        let mut weight = PathWeight::new(1.0);
+        weight.adapt(0.5, &POLICY);
+        assert_eq!(weight.weight(), 0.75);
+        for _ in 0..20 {
+            weight.adapt(0.0, &POLICY);
+        }
+        assert_eq!(weight.weight(), POLICY.min_weight);
+
+        weight.reset();
+        assert_eq!(weight.weight(), 1.0);
+    }
+}
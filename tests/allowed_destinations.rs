@@ -0,0 +1,25 @@
+mod common;
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{left_ip, pair_settings, right_ip, udp_packet, Running};
+
+#[tokio::test]
+async fn only_packets_to_allowed_subnets_are_carried() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.allowed_destinations = Some(vec!["10.0.0.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()]);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let allowed = udp_packet(left_ip(), right_ip(), b"allowed");
+    left.send(allowed.clone());
+    let other: IpAddr = [10, 1, 0, 2].into();
+    left.send(udp_packet(left_ip(), other, b"another subnet"));
+    left.send(udp_packet("fd00::1".parse().unwrap(), "fd01::2".parse().unwrap(), b"another IPv6 subnet"));
+
+    assert_eq!(right.drain(Duration::from_millis(200)).await, vec![allowed]);
+    assert_eq!(left.tunnel.handle().stats().tx_filtered.load(Ordering::Relaxed), 2);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
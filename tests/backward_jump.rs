@@ -0,0 +1,51 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use mptun::settings::{BackwardJumpAction, BackwardJumpSettings, SettingsFileBuilder};
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running};
+
+fn start(action: BackwardJumpAction) -> Running {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.backward_jump = Some(BackwardJumpSettings { max_packets: Some(100), action: Some(action) });
+    Running::start(settings)
+}
+
+fn datagram(seq: usize) -> Vec<u8> {
+    data_datagram(seq, &udp_packet(left_ip(), right_ip(), &(seq as u32).to_be_bytes()))
+}
+
+#[tokio::test]
+async fn lone_backward_jump_is_dropped_but_a_restart_is_not() {
+    let mut tunnel = start(BackwardJumpAction::Drop);
+    let (peer, addr) = (raw_socket(), tunnel.addr());
+    let handle = tunnel.tunnel.handle();
+
+    peer.send_to(&datagram(1000), addr).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    peer.send_to(&datagram(500), addr).unwrap();
+    assert_eq!(tunnel.recv_within(Duration::from_millis(200)).await, None);
+    assert_eq!(handle.stats().rx_backward_jumps.load(Ordering::Relaxed), 1);
+
+    // The peer restarted, its packets are delivered from the new numbering on
+    peer.send_to(&datagram(0), addr).unwrap();
+    peer.send_to(&datagram(1), addr).unwrap();
+    assert_eq!(tunnel.recv().await, Some(udp_packet(left_ip(), right_ip(), &0u32.to_be_bytes())));
+    assert_eq!(tunnel.recv().await, Some(udp_packet(left_ip(), right_ip(), &1u32.to_be_bytes())));
+    assert_eq!(handle.stats().rx_backward_jumps.load(Ordering::Relaxed), 1);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn alert_delivers_the_backward_jump() {
+    let mut tunnel = start(BackwardJumpAction::Alert);
+    let (peer, addr) = (raw_socket(), tunnel.addr());
+
+    peer.send_to(&datagram(1000), addr).unwrap();
+    peer.send_to(&datagram(500), addr).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    assert_eq!(tunnel.recv().await, Some(udp_packet(left_ip(), right_ip(), &500u32.to_be_bytes())));
+    let handle = tunnel.tunnel.handle();
+    assert_eq!(handle.stats().rx_backward_jumps.load(Ordering::Relaxed), 1);
+    tunnel.stop().await.unwrap();
+}
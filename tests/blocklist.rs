@@ -0,0 +1,55 @@
+mod common;
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, single, udp_packet, Running};
+use mptun::cidr::Cidr;
+use mptun::settings::SettingsFileBuilder;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+// A socket sending from another loopback address than `raw_socket`
+fn socket_at(ip: [u8; 4]) -> UdpSocket {
+    UdpSocket::bind((Ipv4Addr::from(ip), 0)).unwrap()
+}
+
+#[tokio::test]
+async fn datagrams_from_a_blocked_source_are_dropped_and_others_pass() {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.blocked_sources = Some(vec!["127.0.0.3/32".parse().unwrap()]);
+    let mut tunnel = Running::start(settings);
+    let handle = tunnel.tunnel.handle();
+
+    socket_at([127, 0, 0, 3]).send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"blocked")), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv_within(QUIET).await, None);
+    assert_eq!(handle.stats().rx_blocked.load(Ordering::Relaxed), 1);
+    // Nor was the sender learned as a peer
+    assert!(handle.clients().is_empty());
+
+    let packet = udp_packet(left_ip(), right_ip(), b"allowed");
+    raw_socket().send_to(&data_datagram(2, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet));
+    assert_eq!(handle.stats().rx_blocked.load(Ordering::Relaxed), 1);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn sources_can_be_blocked_and_unblocked_while_running() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    let entry: Cidr = "127.0.0.0/8".parse().unwrap();
+
+    assert!(tunnel.tunnel.block_source(entry));
+    assert!(!tunnel.tunnel.block_source(entry));
+    assert_eq!(tunnel.tunnel.blocked_sources(), vec![entry]);
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"blocked")), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv_within(QUIET).await, None);
+
+    assert!(tunnel.tunnel.unblock_source(&entry));
+    assert!(!tunnel.tunnel.unblock_source(&entry));
+    let packet = udp_packet(left_ip(), right_ip(), b"unblocked");
+    peer.send_to(&data_datagram(2, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet));
+    tunnel.stop().await.unwrap();
+}
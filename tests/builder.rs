@@ -0,0 +1,50 @@
+mod common;
+
+use std::net::IpAddr;
+use common::{device, free_port, left_ip, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::messages::WireFormat;
+use mptun::settings::{PathMode, ReorderSettings, SettingsFile, SettingsFileBuilder};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn full(tun_ip: IpAddr, port: u16, peer_port: u16, peer_tun_ip: IpAddr) -> SettingsFile {
+    SettingsFileBuilder::new(tun_ip)
+        .add_send_device(device(port))
+        .remote(LOCALHOST.into(), peer_port, peer_tun_ip)
+        .keep_alive(1)
+        .keep_alive_timeout(3)
+        .path_mode(PathMode::Failover)
+        .wire_format(WireFormat::Versioned)
+        .encryption_key(KEY)
+        .reorder(ReorderSettings { max_packets: Some(64), timeout_ms: Some(20) })
+        .tun_mtu(1400)
+        .max_datagram_size(1472)
+        .client_timeout(30)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_tunnel_runs_from_settings_built_in_code() {
+    let (left_port, right_port) = (free_port(), free_port());
+    let left = full(left_ip(), left_port, right_port, right_ip());
+
+    assert_eq!(left.tun_ip, left_ip());
+    assert_eq!(left.send_devices.len(), 1);
+    assert_eq!((left.remote_addr, left.remote_port, left.remote_tun_addr), (Some(LOCALHOST.into()), right_port, Some(right_ip())));
+    assert_eq!((left.keep_alive, left.keep_alive_interval, left.keep_alive_timeout), (Some(true), Some(1), Some(3)));
+    assert_eq!((left.path_mode, left.wire_format), (Some(PathMode::Failover), Some(WireFormat::Versioned)));
+    assert_eq!(left.encryption.as_ref().and_then(|encryption| encryption.key.as_deref()), Some(KEY));
+    assert_eq!((left.tun_mtu, left.max_datagram_size, left.client_timeout), (Some(1400), Some(1472), Some(30)));
+
+    let (mut left, mut right) = (Running::start(left), Running::start(full(right_ip(), right_port, left_port, left_ip())));
+    let packet = udp_packet(left_ip(), right_ip(), b"built in code");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+    let reply = udp_packet(right_ip(), left_ip(), b"reply");
+    right.send(reply.clone());
+    assert_eq!(left.recv().await, Some(reply));
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
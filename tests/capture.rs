@@ -0,0 +1,74 @@
+mod common;
+
+use std::time::Duration;
+use common::{eventually, left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::capture::Direction;
+use mptun::settings::CaptureSettings;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[tokio::test]
+async fn sent_and_received_packets_are_captured() {
+    let path = std::env::temp_dir().join(format!("mptun-capture-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.capture = Some(CaptureSettings { path: path.clone() });
+    let right = Running::start(right);
+    let mut received = right.tunnel.subscribe_capture();
+    let (left, mut right) = (Running::start(left), right);
+
+    let packets: Vec<_> = (0..3u8).map(|index| udp_packet(left_ip(), right_ip(), &[index])).collect();
+    // Once the capture file is being written
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for packet in &packets {
+        left.send(packet.clone());
+        assert_eq!(right.recv().await.as_ref(), Some(packet));
+    }
+
+    for packet in &packets {
+        let record = received.recv().await.unwrap();
+        assert_eq!((record.direction, record.tun_ip, record.peer.ip()), (Direction::Received, left_ip(), std::net::IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(&record.packet, packet);
+    }
+
+    let lines = || std::fs::read_to_string(&path).unwrap_or_default().lines().map(str::to_string).collect::<Vec<_>>();
+    assert!(eventually(Duration::from_secs(2), || lines().len() == 3).await, "{:?}", lines());
+    for (line, (seq, packet)) in lines().iter().zip(packets.iter().enumerate()) {
+        let record: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(record["direction"], "Sent");
+        assert_eq!(record["path"], "127.0.0.1");
+        assert_eq!(record["seq"], seq);
+        assert_eq!(record["tun_ip"], right_ip().to_string());
+        assert_eq!(record["packet"], hex(packet));
+        assert!(record["timestamp_us"].as_u64().unwrap() > 0);
+    }
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn a_reload_turns_the_capture_on() {
+    let path = std::env::temp_dir().join(format!("mptun-capture-reload-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (left, right) = pair_settings(|left| left, |right| right);
+    let (mut reloaded, left, mut right) = (left.clone(), Running::start(left), Running::start(right));
+
+    reloaded.capture = Some(CaptureSettings { path: path.clone() });
+    left.tunnel.reload(reloaded).await;
+    // Packets go on being sent until the capture picks them up
+    let packet = udp_packet(left_ip(), right_ip(), b"captured");
+    let lines = || std::fs::read_to_string(&path).unwrap_or_default().lines().count();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while lines() == 0 {
+        assert!(tokio::time::Instant::now() < deadline, "nothing was captured");
+        left.send(packet.clone());
+        assert_eq!(right.recv().await.as_ref(), Some(&packet));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::time::Duration;
+use common::{left_ip, pair_settings, right_ip, udp_packet};
+use mptun::multipathtunnel::Multipathtunnel;
+
+#[tokio::test]
+async fn packets_injected_on_one_side_come_out_of_the_other() {
+    let (left, right) = pair_settings(|left| left, |right| right);
+    let (left, right) = (Multipathtunnel::new(left).unwrap(), Multipathtunnel::new(right).unwrap());
+    let (mut left_channels, left_run) = left.run_with_channels();
+    let (mut right_channels, right_run) = right.run_with_channels();
+
+    let exchange = async {
+        let packet = udp_packet(left_ip(), right_ip(), b"from the application");
+        left_channels.to_tunnel.send(packet.clone()).unwrap();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), right_channels.from_tunnel.recv()).await.unwrap(), Some(packet));
+
+        let reply = udp_packet(right_ip(), left_ip(), b"reply");
+        right_channels.to_tunnel.send(reply.clone()).unwrap();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), left_channels.from_tunnel.recv()).await.unwrap(), Some(reply));
+    };
+
+    tokio::select! {
+        _ = exchange => {},
+        result = left_run => panic!("left tunnel stopped: {:?}", result),
+        result = right_run => panic!("right tunnel stopped: {:?}", result)
+    }
+}
+
+#[tokio::test]
+async fn dropping_the_channels_stops_the_tunnel() {
+    let (left, _) = pair_settings(|left| left, |right| right);
+    let tunnel = Multipathtunnel::new(left).unwrap();
+    let (channels, run) = tunnel.run_with_channels();
+    drop(channels);
+
+    assert!(tokio::time::timeout(Duration::from_secs(5), run).await.unwrap().is_ok());
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, pair_settings, raw_socket, right_ip, udp_packet, Running};
+use mptun::checksum;
+use mptun::settings::SettingsFileBuilder;
+
+// A tunnel at `right_ip` checking checksums, for a raw socket to play its peer
+fn checking() -> Running {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.checksum = Some(true);
+    Running::start(settings)
+}
+
+#[tokio::test]
+async fn checksummed_packets_go_both_ways() {
+    let (mut left, mut right) = pair_settings(|left| left, |right| right);
+    left.checksum = Some(true);
+    right.checksum = Some(true);
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+
+    let there = udp_packet(left_ip(), right_ip(), b"there");
+    left.send(there.clone());
+    assert_eq!(right.recv().await, Some(there));
+    let back = udp_packet(right_ip(), left_ip(), b"and back");
+    right.send(back.clone());
+    assert_eq!(left.recv().await, Some(back));
+    assert_eq!(right.tunnel.handle().stats().rx_bad_checksum.load(Ordering::Relaxed), 0);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_corrupted_datagram_is_dropped_and_counted() {
+    let mut tunnel = checking();
+    let stats = tunnel.tunnel.handle();
+    let peer = raw_socket();
+
+    let packet = udp_packet(left_ip(), right_ip(), b"intact");
+    let mut datagram = data_datagram(1, &packet);
+    checksum::append(&mut datagram);
+    peer.send_to(&datagram, tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet));
+
+    // A bit flipped on the way, and a datagram with no checksum at all
+    let mut flipped = data_datagram(2, &udp_packet(left_ip(), right_ip(), b"flipped"));
+    checksum::append(&mut flipped);
+    let middle = flipped.len() / 2;
+    flipped[middle] ^= 0x10;
+    peer.send_to(&flipped, tunnel.addr()).unwrap();
+    peer.send_to(&data_datagram(3, &udp_packet(left_ip(), right_ip(), b"unsummed")), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv_within(Duration::from_millis(300)).await, None);
+    assert_eq!(stats.stats().rx_bad_checksum.load(Ordering::Relaxed), 2);
+    assert_eq!(stats.stats().rx_undecodable.load(Ordering::Relaxed), 0);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{advance_settled, data_datagram, device, eventually, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::clock::{Clock, MockClock};
+use mptun::messages::Messages;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SettingsFileBuilder, UnknownPeerQueueSettings};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_alives_go_out_when_the_mock_clock_passes_the_interval() {
+    let clock = Arc::new(MockClock::new());
+    let peer = raw_socket();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .keep_alive(10)
+        .build()
+        .unwrap();
+    let tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+
+    // The first round goes out right away, the next only once the interval passed
+    assert!(matches!(recv_message(&peer, Duration::from_secs(2)), Some((Messages::Keepalive, _))));
+    assert!(recv_message(&peer, QUIET).is_none());
+    clock.advance(Duration::from_secs(9));
+    assert!(recv_message(&peer, QUIET).is_none());
+    clock.advance(Duration::from_secs(1));
+    assert!(matches!(recv_message(&peer, Duration::from_secs(2)), Some((Messages::Keepalive, _))));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_client_is_evicted_once_the_mock_clock_passes_client_timeout() {
+    let clock = Arc::new(MockClock::new());
+    let settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .client_timeout(8)
+        .build()
+        .unwrap();
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    let peer = raw_socket();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    let handle = tunnel.tunnel.handle();
+    assert!(handle.clients().contains_key(&left_ip()));
+
+    // The reaper runs every 2 s of mock time, the client is still in time
+    assert!(advance_settled(&clock, Duration::from_secs(4)).await);
+    assert!(handle.clients().contains_key(&left_ip()));
+
+    clock.advance(Duration::from_secs(6));
+    assert!(eventually(Duration::from_secs(2), || !handle.clients().contains_key(&left_ip())).await);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn sleepers_wake_only_once_the_mock_clock_reaches_their_deadline() {
+    let clock = MockClock::new();
+    let deadline = clock.now() + Duration::from_secs(5);
+    let mut sleep = clock.sleep_until(deadline);
+
+    clock.advance(Duration::from_secs(4));
+    assert!(tokio::time::timeout(QUIET, &mut sleep).await.is_err());
+    clock.advance(Duration::from_secs(1));
+    assert!(tokio::time::timeout(QUIET, &mut sleep).await.is_ok());
+}
+
+// Packets held for 100 ms of mock time at most, released after `advance`
+// once their peer is discovered. How many of them were released.
+async fn held_packets_released_after(advance: Duration) -> u64 {
+    let clock = Arc::new(MockClock::new());
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.unknown_peer_queue = Some(UnknownPeerQueueSettings { max_packets: None, max_age_ms: Some(100) });
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    let handle = tunnel.tunnel.handle();
+    tunnel.send(udp_packet(right_ip(), left_ip(), b"held"));
+    assert!(eventually(Duration::from_secs(2), || handle.stats().tun_held.load(Ordering::Relaxed) == 1).await);
+
+    // Longer than the packets may be held, in real time
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    clock.advance(advance);
+    let peer = raw_socket();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    // Done waiting as soon as the packet is released, if it is
+    let released = || handle.stats().tun_held_released.load(Ordering::Relaxed);
+    eventually(QUIET, || released() > 0).await;
+    let released = released();
+    tunnel.stop().await.unwrap();
+    released
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn held_packets_expire_by_the_mock_clock() {
+    assert_eq!(held_packets_released_after(Duration::ZERO).await, 1);
+    assert_eq!(held_packets_released_after(Duration::from_millis(200)).await, 0);
+}
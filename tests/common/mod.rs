@@ -0,0 +1,251 @@
+// Shared by the integration tests: tunnels run over `MemoryTun` on loopback,
+// each in its own task, and IP packets to feed them.
+
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use etherparse::PacketBuilder;
+use tokio::task::JoinHandle;
+use mptun::clock::MockClock;
+use mptun::error::{TaskReport, TunnelError};
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SendDevice, SettingsFile, SettingsFileBuilder};
+use mptun::tun::{memory_tun, MemoryTunPeer};
+
+pub const LOCALHOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+/// A UDP port on loopback nothing is bound to right now.
+pub fn free_port() -> u16 {
+    UdpSocket::bind((LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+}
+
+/// A send device on loopback at `port`.
+pub fn device(port: u16) -> SendDevice {
+    SendDevice::new(LOCALHOST.into(), port)
+}
+
+/// A tunnel with the TUN address `tun_ip`, sending and receiving on
+/// `tunnel.tun`, running in a task of its own until shut down.
+pub struct Running {
+    pub tunnel: Arc<Multipathtunnel>,
+    pub tun: MemoryTunPeer,
+    pub run: JoinHandle<Result<Vec<TaskReport>, TunnelError>>
+}
+
+impl Running {
+    pub fn start(settings: SettingsFile) -> Running {
+        Running::start_tunnel(Multipathtunnel::new(settings).unwrap())
+    }
+
+    pub fn start_tunnel(tunnel: Multipathtunnel) -> Running {
+        let tunnel = Arc::new(tunnel);
+        let (tun, peer) = memory_tun();
+        let running = tunnel.clone();
+        let run = tokio::spawn(async move { running.run_with_tun(tun).await });
+        Running { tunnel, tun: peer, run }
+    }
+
+    pub fn send(&self, packet: Bytes) {
+        self.tun.to_tunnel.send(packet).unwrap();
+    }
+
+    /// The next packet the tunnel writes to its TUN, if one comes within `timeout`.
+    pub async fn recv_within(&mut self, timeout: Duration) -> Option<Bytes> {
+        tokio::time::timeout(timeout, self.tun.from_tunnel.recv()).await.ok().flatten()
+    }
+
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.recv_within(Duration::from_secs(2)).await
+    }
+
+    /// Every packet written to the TUN until none came for `quiet`.
+    pub async fn drain(&mut self, quiet: Duration) -> Vec<Bytes> {
+        let mut packets = Vec::new();
+        while let Some(packet) = self.recv_within(quiet).await {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// The address of the tunnel's first send device.
+    pub fn addr(&self) -> SocketAddr {
+        self.tunnel.handle().local_addrs()[0]
+    }
+
+    pub async fn stop(self) -> Result<Vec<TaskReport>, TunnelError> {
+        self.tunnel.shutdown();
+        self.run.await.unwrap()
+    }
+}
+
+/// The TUN address of the left tunnel of a `pair`.
+pub fn left_ip() -> IpAddr {
+    [10, 0, 0, 1].into()
+}
+
+/// The TUN address of the right tunnel of a `pair`.
+pub fn right_ip() -> IpAddr {
+    [10, 0, 0, 2].into()
+}
+
+/// Settings for two tunnels on loopback with one device each, each with
+/// the other configured as its remote. `left` and `right` can add to them.
+pub fn pair_settings(
+    left: impl FnOnce(SettingsFileBuilder) -> SettingsFileBuilder,
+    right: impl FnOnce(SettingsFileBuilder) -> SettingsFileBuilder
+) -> (SettingsFile, SettingsFile) {
+    let (left_port, right_port) = (free_port(), free_port());
+    let left = left(SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(left_port))
+        .remote(LOCALHOST.into(), right_port, right_ip()))
+        .build()
+        .unwrap();
+    let right = right(SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(right_port))
+        .remote(LOCALHOST.into(), left_port, left_ip()))
+        .build()
+        .unwrap();
+    (left, right)
+}
+
+/// A tunnel at `right_ip` with one device on loopback and no remote, for
+/// tests that play its peer over `raw_socket`.
+pub fn single(settings: impl FnOnce(SettingsFileBuilder) -> SettingsFileBuilder) -> Running {
+    Running::start(settings(SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port()))).build().unwrap())
+}
+
+/// A plain UDP socket on loopback, to send the tunnel hand-made datagrams.
+pub fn raw_socket() -> UdpSocket {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    socket
+}
+
+/// The next message on a `raw_socket`, in the Bincode wire format, if one
+/// comes within `timeout`. Blocks, like `recv_with_tos`.
+pub fn recv_message(socket: &UdpSocket, timeout: Duration) -> Option<(Messages, SocketAddr)> {
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    let mut buf = vec![0u8; 65536];
+    let received = socket.recv_from(&mut buf).ok().map(|(len, from)| {
+        (messages::decode_packet(&buf[..len], WireFormat::Bincode, u64::MAX).unwrap(), from)
+    });
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    received
+}
+
+/// A data packet carrying `payload` as the Bincode wire format encodes it.
+pub fn data_datagram(seq: usize, payload: &[u8]) -> Vec<u8> {
+    let compressed = lz4_flex::compress_prepend_size(payload);
+    let mut datagram = Vec::new();
+    messages::encode_data_into(seq, None, None, None, &compressed, WireFormat::Bincode, &mut datagram).unwrap();
+    datagram
+}
+
+/// Two tunnels started from `pair_settings`.
+pub fn pair(
+    left: impl FnOnce(SettingsFileBuilder) -> SettingsFileBuilder,
+    right: impl FnOnce(SettingsFileBuilder) -> SettingsFileBuilder
+) -> (Running, Running) {
+    let (left, right) = pair_settings(left, right);
+    (Running::start(left), Running::start(right))
+}
+
+/// `len` bytes that don't compress, the same for the same `seed`.
+pub fn noise(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (state >> 16) as u8
+    }).collect()
+}
+
+/// An IPv4 UDP packet from `source` to `destination` carrying `payload`.
+pub fn udp_packet(source: IpAddr, destination: IpAddr, payload: &[u8]) -> Bytes {
+    udp_packet_with_ttl(source, destination, 64, payload)
+}
+
+pub fn udp_packet_with_ttl(source: IpAddr, destination: IpAddr, ttl: u8, payload: &[u8]) -> Bytes {
+    let mut packet = Vec::new();
+    let builder = match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => PacketBuilder::ipv4(source.octets(), destination.octets(), ttl),
+        (IpAddr::V6(source), IpAddr::V6(destination)) => PacketBuilder::ipv6(source.octets(), destination.octets(), ttl),
+        _ => panic!("{} and {} are of different families", source, destination)
+    };
+    builder.udp(4000, 5000).write(&mut packet, payload).unwrap();
+    Bytes::from(packet)
+}
+
+/// `packet`, an IPv4 packet, with its ToS byte set to `tos`.
+pub fn with_tos(packet: &[u8], tos: u8) -> Bytes {
+    let mut packet = packet.to_vec();
+    packet[1] = tos;
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let checksum = mptun::ipfrag::header_checksum(&packet[..header_len]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    Bytes::from(packet)
+}
+
+/// A `raw_socket` that reports the ToS of each datagram it receives, see `recv_with_tos`.
+pub fn tos_socket() -> UdpSocket {
+    let socket = raw_socket();
+    let on: libc::c_int = 1;
+    let set = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_RECVTOS, &on as *const _ as *const libc::c_void, std::mem::size_of_val(&on) as libc::socklen_t)
+    };
+    assert_eq!(set, 0, "{}", std::io::Error::last_os_error());
+    socket
+}
+
+/// The next datagram on a `tos_socket`, with the ToS it arrived with.
+/// Blocks, so tests using it run on the multi-threaded runtime.
+pub fn recv_with_tos(socket: &UdpSocket) -> (Vec<u8>, Option<u8>) {
+    let mut buf = vec![0u8; 65536];
+    let mut control = [0u8; 64];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len();
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    assert!(len >= 0, "{}", std::io::Error::last_os_error());
+    buf.truncate(len as usize);
+
+    let mut tos = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_TOS {
+            tos = Some(unsafe { *libc::CMSG_DATA(cmsg) });
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    (buf, tos)
+}
+
+/// Polls `condition` until it holds, for up to `timeout`.
+pub async fn eventually(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+        if condition() {
+            return true
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    condition()
+}
+
+/// Advances `clock` by `by`, then waits for up to 2 s until the tasks it woke
+/// are asleep on it again, rather than for a fixed while. False if they
+/// aren't, e.g. because the tunnel shut down.
+pub async fn advance_settled(clock: &MockClock, by: Duration) -> bool {
+    let sleepers = clock.sleepers();
+    clock.advance(by);
+    eventually(Duration::from_secs(2), || clock.sleepers() >= sleepers).await
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::{device, free_port, left_ip, right_ip, udp_packet};
+use mptun::error::{SettingsError, TunnelError};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+fn settings(port: u16, peer_port: u16, tun_ip: std::net::IpAddr, peer_tun_ip: std::net::IpAddr) -> mptun::settings::SettingsFile {
+    SettingsFileBuilder::new(tun_ip)
+        .add_send_device(device(port))
+        .remote(common::LOCALHOST.into(), peer_port, peer_tun_ip)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn tunnels_on_the_stack_run_while_borrowed() {
+    let (left_port, right_port) = (free_port(), free_port());
+    // Locals, not leaked to 'static
+    let left = Multipathtunnel::new(settings(left_port, right_port, left_ip(), right_ip())).unwrap();
+    let right = Multipathtunnel::new(settings(right_port, left_port, right_ip(), left_ip())).unwrap();
+    let (left_tun, left_run) = left.run_with_channels();
+    let (mut right_tun, right_run) = right.run_with_channels();
+
+    let packet = udp_packet(left_ip(), right_ip(), b"borrowed");
+    left_tun.to_tunnel.send(packet.clone()).unwrap();
+    tokio::select! {
+        received = right_tun.from_tunnel.recv() => assert_eq!(received, Some(packet)),
+        result = left_run => panic!("left stopped: {:?}", result),
+        result = right_run => panic!("right stopped: {:?}", result)
+    }
+}
+
+#[tokio::test]
+async fn dropping_a_tunnel_releases_its_sockets() {
+    let port = free_port();
+    for _ in 0..3 {
+        // Binding the same port again fails if the last tunnel's socket lived on
+        let tunnel = Multipathtunnel::new(settings(port, free_port(), left_ip(), right_ip())).unwrap();
+        assert_eq!(tunnel.handle().local_addrs()[0].port(), port);
+        drop(tunnel);
+    }
+}
+
+#[test]
+fn no_send_devices_fails_setup() {
+    let mut settings = settings(free_port(), free_port(), left_ip(), right_ip());
+    settings.send_devices.clear();
+    let err = Multipathtunnel::new(settings).err().expect("setup succeeded without send devices");
+    assert!(matches!(err, TunnelError::Settings(SettingsError::NoSendDevices)), "{:?}", err);
+}
+
+#[test]
+fn setup_fails_when_no_device_can_be_bound() {
+    let taken = std::net::UdpSocket::bind((common::LOCALHOST, 0)).unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let mut settings = settings(port, free_port(), left_ip(), right_ip());
+    settings.send_devices.push(SendDevice::new([127, 0, 0, 1].into(), port));
+
+    match Multipathtunnel::new(settings) {
+        Err(TunnelError::NoDeviceBound(errors)) => {
+            assert_eq!(errors.len(), 2);
+            assert!(errors.iter().all(|err| err.kind() == std::io::ErrorKind::AddrInUse), "{:?}", errors);
+        },
+        other => panic!("expected no device to be bound, got {:?}", other.err())
+    }
+}
+
+#[tokio::test]
+async fn devices_that_fail_to_bind_are_left_out() {
+    let taken = std::net::UdpSocket::bind((common::LOCALHOST, 0)).unwrap();
+    let mut settings = settings(free_port(), free_port(), left_ip(), right_ip());
+    settings.send_devices.push(SendDevice::new([127, 0, 0, 1].into(), taken.local_addr().unwrap().port()));
+
+    let tunnel = Multipathtunnel::new(settings).unwrap();
+    assert_eq!(tunnel.handle().local_addrs().len(), 1);
+}
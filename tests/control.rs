@@ -0,0 +1,31 @@
+mod common;
+
+use std::time::Duration;
+use bytes::Bytes;
+use common::{left_ip, pair, right_ip};
+
+#[tokio::test]
+async fn control_messages_reach_the_application_and_not_the_tun() {
+    let (left, mut right) = pair(|left| left, |right| right);
+    let mut control = right.tunnel.subscribe_control();
+
+    left.tunnel.send_control(right_ip(), Bytes::from_static(b"mtu 1400?")).await.unwrap();
+    let message = tokio::time::timeout(Duration::from_secs(1), control.recv()).await.unwrap().unwrap();
+    assert_eq!(message.tun_ip, left_ip());
+    assert_eq!(message.from, left.addr());
+    assert_eq!(message.payload, Bytes::from_static(b"mtu 1400?"));
+    assert!(right.drain(Duration::from_millis(100)).await.is_empty());
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn control_messages_to_unknown_peers_fail() {
+    let (left, right) = pair(|left| left, |right| right);
+    let err = left.tunnel.send_control([10, 0, 0, 9].into(), Bytes::from_static(b"hello")).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
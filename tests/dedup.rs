@@ -0,0 +1,39 @@
+mod common;
+
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, single, udp_packet, LOCALHOST};
+
+#[tokio::test]
+async fn copies_over_three_links_are_written_once() {
+    let ports = [free_port(), free_port()];
+    let mut tunnel = single(|settings| settings.add_send_device(device(ports[0])).add_send_device(device(ports[1])));
+    let links = [raw_socket(), raw_socket(), raw_socket()];
+    let targets = [tunnel.addr().port(), ports[0], ports[1]];
+
+    for seq in 1..4 {
+        let packet = udp_packet(left_ip(), right_ip(), &[seq as u8; 10]);
+        for (link, port) in links.iter().zip(targets) {
+            link.send_to(&data_datagram(seq, &packet), (LOCALHOST, port)).unwrap();
+        }
+    }
+
+    let written = tunnel.drain(Duration::from_millis(300)).await;
+    let payloads: Vec<u8> = written.iter().map(|packet| packet[packet.len() - 1]).collect();
+    assert_eq!(payloads, vec![1, 2, 3]);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn late_packet_is_still_delivered() {
+    let mut tunnel = single(|settings| settings);
+    let link = raw_socket();
+    for seq in [3, 2, 3] {
+        let packet = udp_packet(left_ip(), right_ip(), &[seq as u8; 10]);
+        link.send_to(&data_datagram(seq, &packet), tunnel.addr()).unwrap();
+    }
+
+    let written = tunnel.drain(Duration::from_millis(300)).await;
+    let payloads: Vec<u8> = written.iter().map(|packet| packet[packet.len() - 1]).collect();
+    assert_eq!(payloads, vec![3, 2]);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running};
+use mptun::settings::{DeliveryMode, SettingsFileBuilder};
+
+// Reordered, with 3 and 2 coming in a second time
+const STREAM: [u8; 9] = [1, 3, 2, 3, 4, 6, 5, 2, 7];
+
+// What the TUN gets from STREAM under `mode`, and how many were too late for it
+async fn delivered(mode: DeliveryMode) -> (Vec<u8>, u64) {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.delivery = Some(mode);
+    let mut tunnel = Running::start(settings);
+    let peer = raw_socket();
+    for seq in STREAM {
+        peer.send_to(&data_datagram(seq.into(), &udp_packet(left_ip(), right_ip(), &[seq])), tunnel.addr()).unwrap();
+    }
+    let written = tunnel.drain(Duration::from_millis(300)).await.iter().map(|packet| packet[packet.len() - 1]).collect();
+    let late = tunnel.tunnel.handle().stats().rx_late_dropped.load(Ordering::Relaxed);
+    tunnel.stop().await.unwrap();
+    (written, late)
+}
+
+#[tokio::test]
+async fn in_order_delivery_writes_in_sequence() {
+    assert_eq!(delivered(DeliveryMode::InOrder).await, (vec![1, 2, 3, 4, 5, 6, 7], 0));
+}
+
+#[tokio::test]
+async fn immediate_delivery_writes_first_copies_as_they_come() {
+    assert_eq!(delivered(DeliveryMode::Immediate).await, (vec![1, 3, 2, 4, 6, 5, 7], 0));
+}
+
+#[tokio::test]
+async fn latest_only_delivery_drops_late_packets() {
+    assert_eq!(delivered(DeliveryMode::LatestOnly).await, (vec![1, 3, 4, 6, 7], 2));
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use common::{data_datagram, device, eventually, free_port, left_ip, pair_settings, raw_socket, right_ip, udp_packet, Running};
+use mptun::affinity::pinned_runtime;
+use mptun::error::SettingsError;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn each_device_runs_its_tasks_on_its_own_runtime() {
+    let (left, right) = pair_settings(|left| left.add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port())), |right| right);
+    let runtimes = [pinned_runtime("first", &[0]).unwrap(), pinned_runtime("second", &[0]).unwrap()];
+    let tunnel = Multipathtunnel::new(left).unwrap()
+        .with_device_runtime("127.0.0.1", runtimes[0].handle().clone())
+        .with_device_runtime("127.0.0.2", runtimes[1].handle().clone());
+    let (left, mut right) = (Running::start_tunnel(tunnel), Running::start(right));
+
+    // At least a send and a receive task each
+    let alive = |index: usize| runtimes[index].metrics().num_alive_tasks();
+    assert!(eventually(Duration::from_secs(1), || alive(0) >= 2 && alive(1) >= 2).await);
+
+    for index in 0..20u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 20);
+    assert_eq!(left.tunnel.handle().paths().iter().map(|path| path.tx_packets).collect::<Vec<_>>(), [20, 20]);
+
+    left.stop().await.unwrap();
+    assert!(eventually(Duration::from_secs(1), || alive(0) == 0 && alive(1) == 0).await);
+    right.stop().await.unwrap();
+    for runtime in runtimes {
+        runtime.shutdown_background();
+    }
+}
+
+#[tokio::test]
+async fn a_devices_socket_is_driven_by_its_runtime() {
+    let runtime = pinned_runtime("device", &[0]).unwrap();
+    let settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    let tunnel = Multipathtunnel::new(settings).unwrap().with_device_runtime("127.0.0.1", runtime.handle().clone());
+    let tunnel = Running::start_tunnel(tunnel);
+    let handle = tunnel.tunnel.handle();
+    assert!(eventually(Duration::from_secs(1), || runtime.metrics().num_alive_tasks() >= 2).await);
+
+    // The test's only runtime thread is blocked from here on, so only the
+    // device's runtime can wake its receive task
+    raw_socket().send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while handle.paths()[0].rx_packets == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(handle.paths()[0].rx_packets, 1);
+
+    tunnel.stop().await.unwrap();
+    runtime.shutdown_background();
+}
+
+#[test]
+fn a_device_with_no_cpus_is_refused() {
+    let build = |cpus: Vec<usize>| {
+        let mut dev = device(free_port());
+        dev.cpus = Some(cpus);
+        SettingsFileBuilder::new(left_ip()).add_send_device(dev).build()
+    };
+    assert!(build(vec![0]).is_ok());
+    assert!(matches!(build(vec![]), Err(SettingsError::NoCpus(_))));
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use common::{device, eventually, free_port, left_ip, pair_settings, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::SettingsFileBuilder;
+
+#[tokio::test]
+async fn shutdown_with_nothing_queued_ends_before_the_drain_timeout() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.drain_timeout_ms = Some(5000);
+    let (left, right) = (Running::start(left), Running::start(right));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let started = Instant::now();
+    left.stop().await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1), "shutdown took {:?}", started.elapsed());
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn queued_packets_are_delivered_during_the_drain() {
+    let (left_port, right_port) = (free_port(), free_port());
+    let mut paced = device(left_port);
+    // About 10 ms per packet, so most of them are still queued at shutdown
+    paced.pacing_bps = Some(80_000);
+    let mut left = SettingsFileBuilder::new(left_ip())
+        .add_send_device(paced)
+        .remote(LOCALHOST.into(), right_port, right_ip())
+        .build()
+        .unwrap();
+    left.drain_timeout_ms = Some(3000);
+    let right = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(right_port))
+        .remote(LOCALHOST.into(), left_port, left_ip())
+        .build()
+        .unwrap();
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let packets: Vec<_> = (0..50u8).map(|index| udp_packet(left_ip(), right_ip(), &[index; 32])).collect();
+    let total: usize = packets.iter().map(|packet| packet.len()).sum();
+    for packet in &packets {
+        left.send(packet.clone());
+    }
+    // Every packet was read from the TUN and queued for the send task
+    let stats = left.tunnel.handle();
+    assert!(eventually(Duration::from_secs(1), || stats.stats().tx_goodput_bytes.load(Ordering::Relaxed) == total as u64).await);
+
+    let started = Instant::now();
+    left.stop().await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(3), "the drain ran into its timeout");
+    assert_eq!(right.drain(Duration::from_millis(300)).await, packets);
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+use common::{device, free_port, left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::error::SettingsError;
+use mptun::settings::{DropInjectionSettings, SendDevice, SettingsFileBuilder};
+
+fn every(every: u64) -> Option<DropInjectionSettings> {
+    Some(DropInjectionSettings { every: Some(every), probability: None, seed: None })
+}
+
+// Send packets 1 to 30 from left to right, returning the ones that arrived
+async fn numbered(left: &Running, right: &mut Running) -> Vec<u8> {
+    for index in 1..=30u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    let mut arrived: Vec<u8> = right.drain(Duration::from_millis(300)).await.iter().map(|packet| packet[packet.len() - 1]).collect();
+    arrived.sort_unstable();
+    arrived
+}
+
+#[tokio::test]
+async fn every_third_packet_is_dropped_on_the_link() {
+    let (mut left, right) = pair_settings(|left| left.allow_drop_injection(true), |right| right);
+    left.send_devices[0].drop_injection = every(3);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let expected: Vec<u8> = (1..=30).filter(|index| index % 3 != 0).collect();
+    assert_eq!(numbered(&left, &mut right).await, expected);
+    assert_eq!(left.tunnel.handle().paths()[0].tx_injected_drops, 10);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn redundant_links_deliver_what_one_of_them_drops() {
+    let (mut left, right) = pair_settings(
+        |left| left.allow_drop_injection(true).add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port())),
+        |right| right
+    );
+    left.send_devices[0].drop_injection = every(3);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    assert_eq!(numbered(&left, &mut right).await, (1..=30).collect::<Vec<u8>>());
+    assert_eq!(left.tunnel.handle().paths().iter().map(|path| path.tx_injected_drops).collect::<Vec<_>>(), [10, 0]);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+
+    // With the other link dropping every 2nd, only what both drop is lost
+    let (mut left, right) = pair_settings(
+        |left| left.allow_drop_injection(true).add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port())),
+        |right| right
+    );
+    left.send_devices[0].drop_injection = every(3);
+    left.send_devices[1].drop_injection = every(2);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    let expected: Vec<u8> = (1..=30).filter(|index| index % 6 != 0).collect();
+    assert_eq!(numbered(&left, &mut right).await, expected);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[test]
+fn drop_injection_needs_to_be_allowed() {
+    let mut dropping = device(free_port());
+    dropping.drop_injection = every(3);
+    let build = |allow: bool| SettingsFileBuilder::new(left_ip()).add_send_device(dropping.clone()).allow_drop_injection(allow).build();
+    assert!(matches!(build(false), Err(SettingsError::DropInjectionNotAllowed(_))));
+    assert!(build(true).is_ok());
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use std::collections::HashMap;
+use common::{device, free_port, left_ip, pair_settings, recv_with_tos, right_ip, tos_socket, udp_packet, with_tos, Running, LOCALHOST};
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::settings::SettingsFileBuilder;
+
+// The ToS of the first data packet `peer` receives
+fn data_tos(peer: &std::net::UdpSocket) -> Option<u8> {
+    loop {
+        let (datagram, tos) = recv_with_tos(peer);
+        if let Ok(Messages::Packet(_)) = messages::decode_packet(&datagram, WireFormat::Bincode, u64::MAX) {
+            return tos
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inner_dscp_is_remapped_on_the_outer_datagram() {
+    let peer = tos_socket();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    settings.copy_dscp = Some(true);
+    // EF to AF11
+    settings.dscp_remap = Some(HashMap::from([(46, 10)]));
+    let tunnel = Running::start(settings);
+
+    let packet = udp_packet(left_ip(), right_ip(), b"voice");
+    // DSCP 46 with ECN ECT(1)
+    tunnel.send(with_tos(&packet, (46 << 2) | 1));
+    assert_eq!(data_tos(&peer), Some((10 << 2) | 1));
+
+    // Not in the table, copied as is
+    tunnel.send(with_tos(&packet, 18 << 2));
+    assert_eq!(data_tos(&peer), Some(18 << 2));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn outer_tos_is_left_alone_without_copy_dscp() {
+    let peer = tos_socket();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+
+    tunnel.send(with_tos(&udp_packet(left_ip(), right_ip(), b"voice"), 46 << 2));
+    assert_eq!(data_tos(&peer), Some(0));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn device_dscp_marks_its_datagrams() {
+    let peer = tos_socket();
+    let mut marked = device(free_port());
+    marked.dscp = Some(34);
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(marked)
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"video"));
+    assert_eq!(data_tos(&peer), Some(34 << 2));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn remapping_leaves_the_inner_dscp_for_the_peers_tun() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.copy_dscp = Some(true);
+    left.dscp_remap = Some(HashMap::from([(46, 10)]));
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let packet = with_tos(&udp_packet(left_ip(), right_ip(), b"voice"), 46 << 2);
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
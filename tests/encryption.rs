@@ -0,0 +1,100 @@
+mod common;
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, pair, pair_settings, raw_socket, right_ip, single, udp_packet, Running};
+use mptun::crypto::Cipher;
+use mptun::error::TunnelError;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const OTHER_KEY: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+#[test]
+fn malformed_key_fails_setup() {
+    let settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .encryption_key("not a key")
+        .build()
+        .unwrap();
+
+    let err = Multipathtunnel::new(settings).err().expect("setup succeeded with a malformed key");
+    assert!(matches!(err, TunnelError::InvalidKey(_)), "{:?}", err);
+    assert_eq!(err.to_string(), "invalid encryption key: expected 64 hex digits, got 9");
+}
+
+#[test]
+fn missing_key_file_fails_setup() {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.encryption = Some(mptun::settings::EncryptionSettings { key: None, key_file: Some("/nonexistent/mptun.key".into()) });
+
+    let err = Multipathtunnel::new(settings).err().expect("setup succeeded without a key file");
+    assert!(err.to_string().starts_with("failed to read key file `/nonexistent/mptun.key`"), "{}", err);
+}
+
+#[tokio::test]
+async fn peers_with_the_same_key_exchange_packets() {
+    let (left, mut right) = pair(|left| left.encryption_key(KEY), |right| right.encryption_key(KEY));
+    let packet = udp_packet(left_ip(), right_ip(), b"secret");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn packets_under_another_key_are_dropped() {
+    let (left, mut right) = pair(|left| left.encryption_key(OTHER_KEY), |right| right.encryption_key(KEY));
+    left.send(udp_packet(left_ip(), right_ip(), b"secret"));
+    assert_eq!(right.recv_within(Duration::from_millis(300)).await, None);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+fn key(key: &str) -> mptun::settings::EncryptionSettings {
+    mptun::settings::EncryptionSettings { key: Some(key.to_string()), key_file: None }
+}
+
+#[tokio::test]
+async fn a_peer_key_replaces_the_global_key_for_that_peer() {
+    let (mut left, mut right) = pair_settings(|left| left.encryption_key(OTHER_KEY), |right| right.encryption_key(KEY));
+    left.peer_keys = Some(HashMap::from([(right_ip(), key(KEY))]));
+    right.peer_keys = Some(HashMap::from([(left_ip(), key(KEY))]));
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let packet = udp_packet(left_ip(), right_ip(), b"secret");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn packets_under_the_global_key_are_dropped_from_a_peer_with_its_own() {
+    let (left, mut right) = pair_settings(|left| left.encryption_key(KEY), |right| right.encryption_key(KEY));
+    right.peer_keys = Some(HashMap::from([(left_ip(), key(OTHER_KEY))]));
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    left.send(udp_packet(left_ip(), right_ip(), b"secret"));
+    assert_eq!(right.recv_within(Duration::from_millis(300)).await, None);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_replayed_datagram_is_dropped() {
+    let mut tunnel = single(|settings| settings.encryption_key(KEY));
+    let peer = raw_socket();
+    let cipher = Cipher::from_settings(&key(KEY)).unwrap();
+    let mut sealed = Vec::new();
+    cipher.seal_into(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"once")), &mut sealed);
+
+    peer.send_to(&sealed, tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    peer.send_to(&sealed, tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv_within(Duration::from_millis(300)).await, None);
+    assert_eq!(tunnel.tunnel.handle().stats().rx_replayed.load(Ordering::Relaxed), 1);
+    tunnel.stop().await.unwrap();
+}
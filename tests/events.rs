@@ -0,0 +1,108 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, recv_message, right_ip, single, udp_packet, Running, LOCALHOST};
+use mptun::clock::MockClock;
+use mptun::events::{DropReason, Event};
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+// The first event matching `wanted` within two seconds, skipping others
+async fn wait_for(events: &mut broadcast::Receiver<Event>, wanted: impl Fn(&Event) -> bool) -> Option<Event> {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match events.recv().await {
+                Ok(event) if wanted(&event) => return Some(event),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    }).await.ok().flatten()
+}
+
+#[tokio::test]
+async fn new_peer_is_announced_once() {
+    let mut tunnel = single(|settings| settings);
+    let mut events = tunnel.tunnel.subscribe_events();
+    let peer = raw_socket();
+
+    for seq in 1..3 {
+        peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+        assert!(tunnel.recv().await.is_some());
+    }
+
+    let discovered = Event::ClientDiscovered { tun_ip: left_ip(), addr: peer.local_addr().unwrap() };
+    assert_eq!(wait_for(&mut events, |event| matches!(event, Event::ClientDiscovered { .. })).await, Some(discovered));
+    // Not again for the second packet
+    while let Ok(event) = events.try_recv() {
+        assert!(!matches!(event, Event::ClientDiscovered { .. }), "{:?}", event);
+    }
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn undecodable_datagram_is_reported_dropped() {
+    let tunnel = single(|settings| settings);
+    let mut events = tunnel.tunnel.subscribe_events();
+
+    raw_socket().send_to(b"not a datagram", tunnel.addr()).unwrap();
+    let dropped = wait_for(&mut events, |event| matches!(event, Event::PacketDropped { .. })).await;
+    assert_eq!(dropped, Some(Event::PacketDropped { reason: DropReason::Malformed }));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_peer_expires() {
+    let clock = Arc::new(MockClock::new());
+    let settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .client_timeout(8)
+        .build()
+        .unwrap();
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    let mut events = tunnel.tunnel.subscribe_events();
+    let peer = raw_socket();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+
+    clock.advance(Duration::from_secs(10));
+    let expired = wait_for(&mut events, |event| matches!(event, Event::ClientExpired { .. })).await;
+    assert_eq!(expired, Some(Event::ClientExpired { tun_ip: left_ip(), addr: peer.local_addr().unwrap() }));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unanswered_keep_alives_take_the_path_down_and_answers_bring_it_up() {
+    // The remote never answers
+    let remote = raw_socket();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), remote.local_addr().unwrap().port(), right_ip())
+        .keep_alive_ms(100)
+        .keep_alive_timeout(1)
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+    let mut events = tunnel.tunnel.subscribe_events();
+    let iface = tunnel.tunnel.handle().paths()[0].name.clone();
+
+    let down = wait_for(&mut events, |event| matches!(event, Event::PathDown { .. })).await;
+    assert_eq!(down, Some(Event::PathDown { iface: iface.clone() }));
+
+    // Answer keep-alives from now on, until the tunnel stops sending them
+    let answering = tokio::task::spawn_blocking(move || {
+        while let Some((message, from)) = recv_message(&remote, Duration::from_millis(300)) {
+            if let Messages::Keepalive = message {
+                let reply = messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap();
+                remote.send_to(&reply, from).unwrap();
+            }
+        }
+    });
+    let up = wait_for(&mut events, |event| matches!(event, Event::PathUp { .. })).await;
+    assert_eq!(up, Some(Event::PathUp { iface }));
+    tunnel.stop().await.unwrap();
+    answering.await.unwrap();
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use std::time::Duration;
+use common::{device, free_port, left_ip, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{PathMode, SendDevice, SettingsFileBuilder};
+
+#[tokio::test]
+async fn failover_mode_sends_only_over_the_highest_priority_link() {
+    let right_port = free_port();
+    let mut fiber = device(free_port());
+    fiber.priority = Some(0);
+    // A second loopback address, so the devices have different names
+    let mut lte = SendDevice::new([127, 0, 0, 2].into(), free_port());
+    lte.priority = Some(1);
+    let left = SettingsFileBuilder::new(left_ip())
+        .add_send_device(lte)
+        .add_send_device(fiber)
+        .remote(LOCALHOST.into(), right_port, right_ip())
+        .path_mode(PathMode::Failover)
+        .build()
+        .unwrap();
+    let right = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(right_port))
+        .build()
+        .unwrap();
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    for index in 0..10u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 10);
+
+    let sent: Vec<_> = left.tunnel.handle().paths().into_iter().map(|path| (path.name, path.tx_packets)).collect();
+    assert!(sent.contains(&("127.0.0.1".to_string(), 10)), "{:?}", sent);
+    assert!(sent.contains(&("127.0.0.2".to_string(), 0)), "{:?}", sent);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
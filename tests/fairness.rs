@@ -0,0 +1,40 @@
+mod common;
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running};
+use mptun::settings::SettingsFileBuilder;
+
+const QUIET: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 3));
+
+#[tokio::test(flavor = "multi_thread")]
+async fn quiet_peer_gets_through_a_flood() {
+    // Room for the whole flood, so the kernel doesn't drop the quiet peer's packets
+    let mut dev = device(free_port());
+    dev.so_rcvbuf = Some(4 << 20);
+    let mut tunnel = Running::start(SettingsFileBuilder::new(right_ip()).add_send_device(dev).build().unwrap());
+    let (loud, quiet) = (raw_socket(), raw_socket());
+    let addr = tunnel.addr();
+
+    let flood = std::thread::spawn(move || {
+        for seq in 1..=5000 {
+            let _ = loud.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), &[0; 200])), addr);
+        }
+    });
+    std::thread::sleep(Duration::from_millis(5));
+    let started = Instant::now();
+    for seq in 1..=5 {
+        quiet.send_to(&data_datagram(seq, &udp_packet(QUIET, right_ip(), &[seq as u8])), addr).unwrap();
+    }
+
+    let mut quiet_received = 0;
+    while quiet_received < 5 {
+        let packet = tunnel.recv().await.expect("the quiet peer's packets didn't come");
+        if packet[12..16] == [10, 0, 0, 3] {
+            quiet_received += 1;
+        }
+    }
+    assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+    flood.join().unwrap();
+    tunnel.stop().await.unwrap();
+}
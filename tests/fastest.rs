@@ -0,0 +1,101 @@
+mod common;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use common::{data_datagram, eventually, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::clock::MockClock;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{FastestSettings, PathMode, SendDevice, SettingsFileBuilder};
+
+const STAGGER: Duration = Duration::from_millis(50);
+const QUIET: Duration = Duration::from_millis(200);
+
+// A tunnel at `left_ip` in fastest mode toward `peer`, over a link on
+// 127.0.0.1 and one on 127.0.0.2, with the mock clock making the second
+// the faster: its keep-alive is answered right away, the first one's 30 ms
+// later. Returns the tunnel and the links' addresses, slow one first.
+async fn with_a_faster_second_link(clock: &Arc<MockClock>, peer: &UdpSocket, settings: impl FnOnce(SettingsFileBuilder) -> SettingsFileBuilder) -> (Running, SocketAddr, SocketAddr) {
+    let mut builder = settings(SettingsFileBuilder::new(left_ip())
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .path_mode(PathMode::Fastest)
+        .keep_alive(10));
+    for last_octet in [1, 2] {
+        builder = builder.add_send_device(SendDevice::new([127, 0, 0, last_octet].into(), free_port()));
+    }
+    let mut settings = builder.build().unwrap();
+    settings.fastest = Some(FastestSettings { stagger_ms: Some(STAGGER.as_millis() as u64), backups: None });
+    let tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    let addrs = tunnel.tunnel.handle().local_addrs();
+    let (slow, fast) = (addrs[0], addrs[1]);
+
+    let mut pinged = Vec::new();
+    while pinged.len() < 2 {
+        match recv_message(peer, Duration::from_secs(2)) {
+            Some((Messages::Keepalive, from)) => pinged.push(from),
+            Some(_) => continue,
+            None => panic!("keep-alives from {:?} only", pinged)
+        }
+    }
+    let reply = messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap();
+    peer.send_to(&reply, fast).unwrap();
+    let handle = tunnel.tunnel.handle();
+    let rtt_of = |addr: SocketAddr| handle.paths().into_iter().find(|path| path.local_addr == addr).and_then(|path| path.srtt);
+    assert!(eventually(Duration::from_secs(2), || rtt_of(fast).is_some()).await);
+    clock.advance(Duration::from_millis(30));
+    peer.send_to(&reply, slow).unwrap();
+    assert!(eventually(Duration::from_secs(2), || rtt_of(slow).is_some()).await);
+    assert!(rtt_of(fast) < rtt_of(slow));
+    (tunnel, slow, fast)
+}
+
+// Where the next data packet on `peer` came from, if one comes within `timeout`
+fn next_packet_from(peer: &UdpSocket, timeout: Duration) -> Option<SocketAddr> {
+    loop {
+        match recv_message(peer, timeout)? {
+            (Messages::Packet(_), from) => return Some(from),
+            _ => continue
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_lowest_rtt_link_sends_first_and_the_others_after_the_stagger() {
+    let clock = Arc::new(MockClock::new());
+    let peer = raw_socket();
+    let (tunnel, slow, fast) = with_a_faster_second_link(&clock, &peer, |settings| settings).await;
+
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"fastest"));
+    assert_eq!(next_packet_from(&peer, Duration::from_secs(2)), Some(fast));
+    assert_eq!(next_packet_from(&peer, QUIET), None);
+    clock.advance(STAGGER);
+    assert_eq!(next_packet_from(&peer, Duration::from_secs(2)), Some(slow));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn held_backups_do_not_hold_up_the_packets_behind_them() {
+    let clock = Arc::new(MockClock::new());
+    let peer = raw_socket();
+    let other_ip = [10, 0, 0, 3].into();
+    let (tunnel, slow, fast) = with_a_faster_second_link(&clock, &peer, |settings| settings.peer_path_mode(other_ip, PathMode::Redundant)).await;
+    // A second peer, sent a copy on every link
+    let other = raw_socket();
+    other.send_to(&data_datagram(1, &udp_packet(other_ip, left_ip(), b"hello")), fast).unwrap();
+    let handle = tunnel.tunnel.handle();
+    assert!(eventually(Duration::from_secs(2), || handle.clients().contains_key(&other_ip)).await);
+
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"fastest"));
+    tunnel.send(udp_packet(left_ip(), other_ip, b"redundant"));
+    assert_eq!(next_packet_from(&peer, Duration::from_secs(2)), Some(fast));
+    // The slow link holds its backup for the first peer, and sends to the other meanwhile
+    let mut copies = vec![next_packet_from(&other, Duration::from_secs(2)), next_packet_from(&other, Duration::from_secs(2))];
+    copies.sort();
+    assert_eq!(copies, [Some(slow), Some(fast)]);
+    assert_eq!(next_packet_from(&peer, QUIET), None);
+
+    clock.advance(STAGGER);
+    assert_eq!(next_packet_from(&peer, Duration::from_secs(2)), Some(slow));
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::time::Duration;
+use bytes::Bytes;
+use etherparse::PacketBuilder;
+use common::{free_port, pair_settings, Running};
+use mptun::settings::{PathMode, SendDevice};
+
+fn tcp_packet(source_port: u16, payload: &[u8]) -> Bytes {
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+        .tcp(source_port, 443, 1, 65535)
+        .write(&mut packet, payload)
+        .unwrap();
+    Bytes::from(packet)
+}
+
+#[tokio::test]
+async fn flows_stick_to_one_link_and_spread_over_both() {
+    let (left, right) = pair_settings(
+        |left| left.add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port())).path_mode(PathMode::FlowHash),
+        |right| right
+    );
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    let sent = |left: &Running| -> Vec<u64> { left.tunnel.handle().paths().iter().map(|path| path.tx_packets).collect() };
+
+    let mut before = sent(&left);
+    for port in 1000..1020 {
+        for index in 0..5u8 {
+            left.send(tcp_packet(port, &[index]));
+        }
+        assert_eq!(right.drain(Duration::from_millis(100)).await.len(), 5);
+        // All five went out on the same link
+        let after = sent(&left);
+        let grown: Vec<u64> = after.iter().zip(&before).map(|(after, before)| after - before).collect();
+        assert!(grown == [5, 0] || grown == [0, 5], "flow {} sent {:?}", port, grown);
+        before = after;
+    }
+    // And the twenty flows didn't all take the same one
+    assert!(before.iter().all(|&sent| sent > 0), "{:?}", before);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
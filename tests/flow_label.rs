@@ -0,0 +1,99 @@
+mod common;
+
+use std::net::{Ipv6Addr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use bytes::Bytes;
+use etherparse::{PacketBuilder, SlicedPacket};
+use common::{free_port, left_ip, right_ip, Running};
+use mptun::flowlabel::{self, FlowLabelMode};
+use mptun::flows::FlowKey;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+// An IPv6 socket on loopback that reports the flow info datagrams arrive with,
+// or `None` without IPv6
+fn flowinfo_socket() -> Option<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).ok()?;
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let on: libc::c_int = 1;
+    let set = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_FLOWINFO, &on as *const _ as *const libc::c_void, std::mem::size_of_val(&on) as libc::socklen_t)
+    };
+    assert_eq!(set, 0, "{}", std::io::Error::last_os_error());
+    Some(socket)
+}
+
+// The next message on a `flowinfo_socket`, with the flow label it arrived with. Blocks.
+fn recv_with_label(socket: &UdpSocket) -> Option<(Messages, u32)> {
+    let mut buf = vec![0u8; 65536];
+    let mut control = [0u8; 64];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len();
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return None
+    }
+
+    let mut label = 0;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_FLOWINFO {
+            let flowinfo = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const u32) };
+            label = u32::from_be(flowinfo) & 0xfffff;
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Some((messages::decode_packet(&buf[..len as usize], WireFormat::Bincode, u64::MAX).unwrap(), label))
+}
+
+fn packet(source_port: u16) -> Bytes {
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).udp(source_port, 53).write(&mut packet, b"query").unwrap();
+    Bytes::from(packet)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn outer_label_is_set_and_stable_per_inner_flow() {
+    let peer = match flowinfo_socket() {
+        Some(peer) => peer,
+        None => return
+    };
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(SendDevice::new(Ipv6Addr::LOCALHOST.into(), free_port()))
+        .remote(Ipv6Addr::LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    settings.flow_label = Some(FlowLabelMode::InnerFlow);
+    let tunnel = Running::start(settings);
+
+    let sent = [packet(1000), packet(1000), packet(2000)];
+    for packet in &sent {
+        tunnel.send(packet.clone());
+    }
+    let labels: Vec<u32> = tokio::task::spawn_blocking(move || {
+        let mut labels = Vec::new();
+        while labels.len() < 3 {
+            match recv_with_label(&peer) {
+                Some((Messages::Packet(_), label)) => labels.push(label),
+                Some(_) => continue,
+                None => break
+            }
+        }
+        labels
+    }).await.unwrap();
+
+    let expected: Vec<u32> = sent.iter()
+        .map(|packet| FlowKey::from_packet(&SlicedPacket::from_ip(packet).unwrap()).unwrap())
+        .map(|flow| flowlabel::label_for(FlowLabelMode::InnerFlow, Some(&flow)).unwrap())
+        .collect();
+    assert_eq!(labels, expected);
+    assert_eq!(labels[0], labels[1]);
+    assert_ne!(labels[0], labels[2]);
+    tunnel.stop().await.unwrap();
+}
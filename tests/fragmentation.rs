@@ -0,0 +1,59 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use mptun::clock::MockClock;
+use mptun::fragment;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+use common::{data_datagram, device, free_port, left_ip, noise, pair, raw_socket, right_ip, udp_packet, Running};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test]
+async fn packet_larger_than_a_datagram_arrives_whole() {
+    let (left, mut right) = pair(|left| left.max_datagram_size(400), |right| right);
+    let packet = udp_packet(left_ip(), right_ip(), &noise(1200, 1));
+    left.send(packet.clone());
+
+    assert_eq!(right.recv().await, Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn message_with_a_lost_fragment_is_dropped() {
+    let clock = Arc::new(MockClock::new());
+    let settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    tokio::time::sleep(QUIET).await;
+    let (peer, addr) = (raw_socket(), tunnel.addr());
+    let fragments = |seq: usize| {
+        let mut fragments = Vec::new();
+        let datagram = data_datagram(seq, &udp_packet(left_ip(), right_ip(), &noise(1200, seq as u32)));
+        assert!(fragment::split_into(&datagram, seq as u64, 400, &mut fragments));
+        fragments
+    };
+
+    let lossy = fragments(1);
+    assert!(lossy.len() > 2);
+    for fragment in lossy.iter().filter(|fragment| fragment[1] != 1) {
+        peer.send_to(fragment, addr).unwrap();
+    }
+    assert_eq!(tunnel.recv_within(QUIET).await, None);
+
+    // Expired once the reassembly timeout passed, the next message gets through
+    clock.advance(fragment::REASSEMBLY_TIMEOUT + Duration::from_secs(1));
+    for fragment in fragments(2) {
+        peer.send_to(&fragment, addr).unwrap();
+    }
+    assert_eq!(tunnel.recv().await, Some(udp_packet(left_ip(), right_ip(), &noise(1200, 2))));
+    let handle = tunnel.tunnel.handle();
+    assert_eq!(handle.stats().rx_reassembly_timeouts.load(Ordering::Relaxed), 1);
+
+    // Its missing fragment arriving late completes nothing
+    peer.send_to(&lossy[1], addr).unwrap();
+    assert_eq!(tunnel.recv_within(QUIET).await, None);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use std::time::Duration;
+use common::{left_ip, noise, pair_settings, right_ip, udp_packet, Running};
+
+#[tokio::test]
+async fn stale_packets_make_way_for_fresh_ones() {
+    const BURST: u32 = 20;
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    // About 100ms per packet, so the burst backs up far past the deadline
+    left.send_devices[0].pacing_bps = Some(80_000);
+    left.max_packet_age_ms = Some(300);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let packets: Vec<_> = (0..BURST).map(|i| udp_packet(left_ip(), right_ip(), &noise(1000, i))).collect();
+    for packet in &packets {
+        left.send(packet.clone());
+    }
+    let received = right.drain(Duration::from_millis(500)).await;
+    // The oldest packets went out before the deadline, the rest were skipped
+    assert!(!received.is_empty() && received.len() < 8, "{} of {} delivered", received.len(), BURST);
+    assert_eq!(received[..], packets[..received.len()]);
+    let handle = left.tunnel.handle();
+    assert_eq!(handle.paths()[0].tx_stale, (BURST as usize - received.len()) as u64);
+
+    // With the backlog gone, a fresh packet isn't held up by the stale ones
+    let fresh = udp_packet(left_ip(), right_ip(), &noise(1000, BURST));
+    left.send(fresh.clone());
+    assert_eq!(right.recv_within(Duration::from_millis(300)).await, Some(fresh));
+    assert_eq!(handle.paths()[0].tx_stale, (BURST as usize - received.len()) as u64);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, eventually, left_ip, noise, raw_socket, right_ip, single, udp_packet};
+use mptun::messages::{self, WireFormat};
+
+const FLOOD: usize = 500;
+
+// Let the tunnel keep up, so none of the flood is dropped by the socket buffer
+async fn pace(sent: usize) {
+    if sent.is_multiple_of(50) {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn every_garbage_datagram_is_counted_and_the_tunnel_keeps_delivering() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    for seed in 0..FLOOD as u32 {
+        // No message has this tag, whatever follows it
+        let garbage = [u32::MAX.to_le_bytes().as_slice(), &noise(100, seed)].concat();
+        peer.send_to(&garbage, tunnel.addr()).unwrap();
+        pace(seed as usize).await;
+    }
+    let handle = tunnel.tunnel.handle();
+    let undecodable = || handle.stats().rx_undecodable.load(Ordering::Relaxed);
+    assert!(eventually(Duration::from_secs(2), || undecodable() == FLOOD as u64).await, "{} of {}", undecodable(), FLOOD);
+
+    let packet = udp_packet(left_ip(), right_ip(), b"after the flood");
+    peer.send_to(&data_datagram(1, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn payloads_that_fail_to_decompress_are_counted_as_undecodable() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    // Claims 100 bytes, then copies from before the start of the output
+    let corrupt = [100u32.to_le_bytes().as_slice(), &[0x0f, 0xff, 0xff, 0x00]].concat();
+    let mut datagram = Vec::new();
+    for seq in 1..=FLOOD {
+        messages::encode_data_into(seq, None, None, None, &corrupt, WireFormat::Bincode, &mut datagram).unwrap();
+        peer.send_to(&datagram, tunnel.addr()).unwrap();
+        pace(seq).await;
+    }
+    let handle = tunnel.tunnel.handle();
+    let undecodable = || handle.stats().rx_undecodable.load(Ordering::Relaxed);
+    assert!(eventually(Duration::from_secs(2), || undecodable() == FLOOD as u64).await, "{} of {}", undecodable(), FLOOD);
+    assert_eq!(tunnel.recv_within(Duration::from_millis(100)).await, None);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::time::Duration;
+use common::{data_datagram, device, eventually, free_port, left_ip, pair, raw_socket, right_ip, single, udp_packet, Running, LOCALHOST};
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::settings::SettingsFileBuilder;
+
+#[tokio::test]
+async fn a_peer_that_shuts_down_is_removed_at_once() {
+    let mut right = single(|settings| settings);
+    let left = Running::start(SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), right.addr().port(), right_ip())
+        .build()
+        .unwrap());
+    left.send(udp_packet(left_ip(), right_ip(), b"hello"));
+    assert!(right.recv().await.is_some());
+    let handle = right.tunnel.handle();
+    assert!(handle.clients().contains_key(&left_ip()));
+
+    left.stop().await.unwrap();
+    // Long before the reaper would get to it
+    assert!(eventually(Duration::from_secs(1), || !handle.clients().contains_key(&left_ip())).await);
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn the_configured_remote_is_kept_after_its_goodbye() {
+    let (left, mut right) = pair(|left| left, |right| right);
+    left.send(udp_packet(left_ip(), right_ip(), b"hello"));
+    assert!(right.recv().await.is_some());
+    left.stop().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(right.tunnel.handle().clients().contains_key(&left_ip()));
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_replayed_goodbye_doesnt_remove_a_peer_still_sending() {
+    let peer = raw_socket();
+    let mut tunnel = single(|settings| settings);
+    let handle = tunnel.tunnel.handle();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+
+    peer.send_to(&messages::encode_packet(&Messages::Goodbye, WireFormat::Bincode).unwrap(), tunnel.addr()).unwrap();
+    for seq in 2..22 {
+        peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), b"still here")), tunnel.addr()).unwrap();
+        assert!(tunnel.recv().await.is_some());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(handle.clients().contains_key(&left_ip()));
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use common::{device, free_port, left_ip, raw_socket, recv_message, right_ip, Running, LOCALHOST};
+use mptun::events::Event;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{HandshakeSettings, SettingsFileBuilder};
+
+const ANSWERED_HELLO: usize = 4;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hellos_back_off_until_the_remote_answers() {
+    let peer = raw_socket();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    settings.handshake = Some(HandshakeSettings { initial_backoff_ms: Some(40), max_backoff_ms: Some(1000), max_attempts: None });
+    let tunnel = Multipathtunnel::new(settings).unwrap();
+    let mut events = tunnel.subscribe_events();
+    let tunnel = Running::start_tunnel(tunnel);
+
+    // Up before its first hellos: they go unanswered, then one is answered
+    let hellos = tokio::task::spawn_blocking(move || {
+        let mut sent_at = Vec::new();
+        while sent_at.len() < ANSWERED_HELLO {
+            let (message, from) = recv_message(&peer, Duration::from_secs(2)).expect("no hello");
+            assert!(matches!(message, Messages::Keepalive), "{:?}", message);
+            sent_at.push(Instant::now());
+            if sent_at.len() == ANSWERED_HELLO {
+                peer.send_to(&messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap(), from).unwrap();
+            }
+        }
+        // Nothing more once answered
+        assert!(recv_message(&peer, Duration::from_millis(700)).is_none());
+        sent_at
+    }).await.unwrap();
+
+    let gaps: Vec<_> = hellos.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    for (gap, backoff) in gaps.iter().zip([40, 80, 160]) {
+        let backoff = Duration::from_millis(backoff);
+        assert!(*gap >= backoff - Duration::from_millis(10) && *gap < backoff + Duration::from_millis(150), "{:?}", gaps);
+    }
+    let path_up = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match events.recv().await {
+                Ok(Event::PathUp { iface }) => return Some(iface),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    }).await.ok().flatten();
+    assert_eq!(path_up.as_deref(), Some("127.0.0.1"));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hellos_stop_after_max_attempts() {
+    let peer = raw_socket();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    settings.handshake = Some(HandshakeSettings { initial_backoff_ms: Some(20), max_backoff_ms: Some(20), max_attempts: Some(3) });
+    let tunnel = Running::start(settings);
+
+    let hellos = tokio::task::spawn_blocking(move || {
+        let mut hellos = 0;
+        while recv_message(&peer, Duration::from_millis(300)).is_some() {
+            hellos += 1;
+        }
+        hellos
+    }).await.unwrap();
+    assert_eq!(hellos, 3);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use common::{device, free_port, left_ip, raw_socket, right_ip, Running, LOCALHOST};
+use mptun::handle::HealthStatus;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::path::Health;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+async fn status_within(tunnel: &Multipathtunnel, timeout: Duration, status: HealthStatus) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tunnel.health().await.status != status {
+        if tokio::time::Instant::now() >= deadline {
+            return false
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    true
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_path_whose_keep_alives_go_unanswered_degrades_the_tunnel() {
+    let peer = raw_socket();
+    let (fiber, lte) = (device(free_port()), SendDevice::new([127, 0, 0, 2].into(), free_port()));
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(fiber)
+        .add_send_device(lte)
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .keep_alive_ms(20)
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+    let answered = tunnel.addr();
+
+    // Answer the keep-alives of the first path only
+    let stop = Arc::new(AtomicBool::new(false));
+    let responder = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            peer.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            let reply = messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap();
+            let mut buf = vec![0; 65536];
+            while !stop.load(Ordering::Relaxed) {
+                if let Ok((len, from)) = peer.recv_from(&mut buf) {
+                    let keep_alive = matches!(messages::decode_packet(&buf[..len], WireFormat::Bincode, u64::MAX), Ok(Messages::Keepalive));
+                    if keep_alive && from == answered {
+                        peer.send_to(&reply, from).unwrap();
+                    }
+                }
+            }
+        })
+    };
+
+    assert!(status_within(&tunnel.tunnel, Duration::from_secs(3), HealthStatus::Degraded).await);
+    let report = tunnel.tunnel.health().await;
+    let health: Vec<_> = report.paths.iter().map(|path| (path.name.as_str(), path.health)).collect();
+    assert_eq!(health, [("127.0.0.1", Health::Up), ("127.0.0.2", Health::Down)]);
+    assert!(report.tun_running);
+
+    stop.store(true, Ordering::Relaxed);
+    responder.join().unwrap();
+    let mptun = tunnel.tunnel.clone();
+    tunnel.stop().await.unwrap();
+    // Once run returns the TUN tasks are gone
+    assert_eq!(mptun.health().await.status, HealthStatus::Unhealthy);
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use bytes::Bytes;
+use mptun::messages::{self, Messages, Packet, WireFormat};
+use mptun::settings::SettingsFileBuilder;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+
+fn hub_ip() -> IpAddr {
+    [10, 0, 0, 254].into()
+}
+
+// A spoke at `tun_ip` sending what it has no peer for to the hub
+fn spoke(tun_ip: IpAddr, hub_port: u16) -> Running {
+    Running::start(SettingsFileBuilder::new(tun_ip)
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), hub_port, hub_ip())
+        .via_hub(true)
+        .build()
+        .unwrap())
+}
+
+#[tokio::test]
+async fn spokes_reach_each_other_through_the_hub() {
+    let hub_port = free_port();
+    let mut hub = Running::start(SettingsFileBuilder::new(hub_ip()).add_send_device(device(hub_port)).hub(true).build().unwrap());
+    let (a, mut b) = (spoke(left_ip(), hub_port), spoke(right_ip(), hub_port));
+
+    // The hub learns of b once b sends
+    let hello = udp_packet(right_ip(), hub_ip(), b"hello hub");
+    b.send(hello.clone());
+    assert_eq!(hub.recv().await, Some(hello));
+
+    // a knows only the hub, which relays the packet instead of taking it
+    let relayed = udp_packet(left_ip(), right_ip(), b"via the hub");
+    a.send(relayed.clone());
+    assert_eq!(b.recv().await, Some(relayed));
+    assert_eq!(hub.recv_within(Duration::from_millis(200)).await, None);
+    assert_eq!(b.recv_within(Duration::from_millis(200)).await, None);
+
+    for tunnel in [hub, a, b] {
+        tunnel.stop().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwarded_packets_keep_the_senders_session_epoch() {
+    let hub_port = free_port();
+    let mut settings = SettingsFileBuilder::new(hub_ip()).add_send_device(device(hub_port)).hub(true).build().unwrap();
+    settings.session_epoch = Some(true);
+    let mut hub = Running::start(settings);
+    let (a, b) = (raw_socket(), raw_socket());
+
+    b.send_to(&data_datagram(1, &udp_packet(right_ip(), hub_ip(), b"hello hub")), hub.addr()).unwrap();
+    assert!(hub.recv().await.is_some());
+
+    let relayed = udp_packet(left_ip(), right_ip(), b"via the hub");
+    let packet = Packet { seq: 1, bytes: Bytes::from(lz4_flex::compress_prepend_size(&relayed)) };
+    a.send_to(&messages::encode_packet(&Messages::SessionPacket(42, None, None, packet), WireFormat::Bincode).unwrap(), hub.addr()).unwrap();
+    match recv_message(&b, Duration::from_secs(2)) {
+        Some((Messages::SessionPacket(epoch, ..), _)) => assert_eq!(epoch, 42),
+        other => panic!("expected a session packet, got {:?}", other)
+    }
+
+    // A sender without an epoch isn't given the hub's
+    a.send_to(&data_datagram(2, &relayed), hub.addr()).unwrap();
+    assert!(matches!(recv_message(&b, Duration::from_secs(2)), Some((Messages::Packet(_), _))));
+    hub.stop().await.unwrap();
+}
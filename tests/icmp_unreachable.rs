@@ -0,0 +1,74 @@
+mod common;
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{eventually, left_ip, pair_settings, right_ip, udp_packet, Running};
+
+fn unknown_ip() -> IpAddr {
+    [10, 0, 0, 9].into()
+}
+
+#[tokio::test]
+async fn unknown_destinations_are_answered_on_the_tun() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.icmp_unreachable = Some(true);
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+
+    let packet = udp_packet(left_ip(), unknown_ip(), b"nobody home");
+    left.send(packet.clone());
+    let reply = left.recv().await.unwrap();
+    // From the tunnel's own address back to the sender, quoting the packet
+    assert_eq!(&reply[12..16], &[10, 0, 0, 1]);
+    assert_eq!(&reply[16..20], &[10, 0, 0, 1]);
+    assert_eq!(&reply[20..22], &[3, 1]);
+    assert_eq!(&reply[28..], &packet[..28]);
+    assert_eq!(left.tunnel.handle().stats().tun_unreachable.load(Ordering::Relaxed), 1);
+    assert_eq!(right.recv_within(Duration::from_millis(200)).await, None);
+
+    // Known peers are still reached as before
+    let packet = udp_packet(left_ip(), right_ip(), b"hello");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_destinations_get_no_answer_by_default() {
+    let (left, right) = pair_settings(|left| left, |right| right);
+    let (mut left, right) = (Running::start(left), Running::start(right));
+
+    left.send(udp_packet(left_ip(), unknown_ip(), b"nobody home"));
+    assert_eq!(left.recv_within(Duration::from_millis(200)).await, None);
+    assert_eq!(left.tunnel.handle().stats().tun_unreachable.load(Ordering::Relaxed), 0);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_flood_to_unknown_destinations_is_answered_at_a_limited_rate() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.icmp_unreachable = Some(true);
+    let (left, right) = (Running::start(left), Running::start(right));
+
+    // Each answered with a 1280 byte ICMPv6 error
+    let (source, unknown): (IpAddr, IpAddr) = ("fd00::1".parse().unwrap(), "fd00::9".parse().unwrap());
+    for _ in 0..1000 {
+        left.send(udp_packet(source, unknown, &[0; 1200]));
+    }
+    let handle = left.tunnel.handle();
+    let counts = || {
+        let stats = handle.stats();
+        (stats.tun_unreachable.load(Ordering::Relaxed), stats.tun_unreachable_limited.load(Ordering::Relaxed))
+    };
+    assert!(eventually(Duration::from_secs(2), || { let (answered, limited) = counts(); answered + limited == 1000 }).await, "{:?}", counts());
+    // A burst is answered, most of the flood isn't
+    let (answered, limited) = counts();
+    assert!(answered >= 40 && limited >= 500, "{:?}", counts());
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
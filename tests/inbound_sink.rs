@@ -0,0 +1,57 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::tun::InboundDelivery;
+use common::{left_ip, pair_settings, right_ip, udp_packet, Running};
+
+// `left` as is, and `right` delivering to a sink with room for `capacity` packets
+fn start(delivery: InboundDelivery, capacity: usize) -> (Running, Running, mpsc::Receiver<bytes::Bytes>) {
+    let (left, right) = pair_settings(|left| left, |right| right);
+    let (sink, received) = mpsc::channel(capacity);
+    let right = Multipathtunnel::new(right).unwrap().with_inbound_sink(sink, delivery);
+    (Running::start(left), Running::start_tunnel(right), received)
+}
+
+#[tokio::test]
+async fn sink_gets_a_copy_of_what_the_tun_gets() {
+    let (left, mut right, mut sink) = start(InboundDelivery::TunAndSink, 16);
+    let packet = udp_packet(left_ip(), right_ip(), b"to both");
+    left.send(packet.clone());
+
+    assert_eq!(right.recv().await, Some(packet.clone()));
+    assert_eq!(tokio::time::timeout(Duration::from_secs(1), sink.recv()).await.unwrap(), Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn sink_only_bypasses_the_tun() {
+    let (left, mut right, mut sink) = start(InboundDelivery::SinkOnly, 16);
+    let packet = udp_packet(left_ip(), right_ip(), b"to the sink");
+    left.send(packet.clone());
+
+    assert_eq!(tokio::time::timeout(Duration::from_secs(1), sink.recv()).await.unwrap(), Some(packet));
+    assert_eq!(right.recv_within(Duration::from_millis(200)).await, None);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn packets_are_dropped_while_the_sink_is_full() {
+    let (left, mut right, mut sink) = start(InboundDelivery::SinkOnly, 2);
+    for i in 0..5u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[i]));
+    }
+    right.drain(Duration::from_millis(200)).await;
+
+    let handle = right.tunnel.handle();
+    assert_eq!(handle.stats().sink_dropped.load(Ordering::Relaxed), 3);
+    assert!(sink.recv().await.is_some());
+    assert!(sink.recv().await.is_some());
+    assert!(sink.try_recv().is_err());
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::time::Duration;
+use common::{data_datagram, device, eventually, free_port, left_ip, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SettingsFile, SettingsFileBuilder};
+
+// Settings with `left_ip` at `port` as the remote
+fn with_remote(port: u16, insert: bool) -> SettingsFile {
+    SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), port, left_ip())
+        .insert_remote(insert)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_remote_left_out_of_the_client_list_is_not_a_peer_at_startup() {
+    let port = free_port();
+    let inserted = Multipathtunnel::new(with_remote(port, true)).unwrap();
+    assert_eq!(inserted.handle().clients().keys().collect::<Vec<_>>(), [&left_ip()]);
+
+    let left_out = Multipathtunnel::new(with_remote(port, false)).unwrap();
+    assert!(left_out.handle().clients().is_empty());
+}
+
+#[tokio::test]
+async fn a_reload_inserts_and_removes_the_remote() {
+    let settings = with_remote(free_port(), false);
+    let tunnel = Running::start(settings.clone());
+    let handle = tunnel.tunnel.handle();
+
+    let mut inserted = settings.clone();
+    inserted.insert_remote = Some(true);
+    tunnel.tunnel.reload(inserted).await;
+    assert!(handle.clients().contains_key(&left_ip()));
+
+    tunnel.tunnel.reload(settings).await;
+    assert!(handle.clients().is_empty());
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_remote_left_out_is_learned_and_forgotten_like_any_client() {
+    let peer = raw_socket();
+    let mut tunnel = Running::start(with_remote(peer.local_addr().unwrap().port(), false));
+    let handle = tunnel.tunnel.handle();
+
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    assert!(handle.clients().contains_key(&left_ip()));
+
+    peer.send_to(&messages::encode_packet(&Messages::Goodbye, WireFormat::Bincode).unwrap(), tunnel.addr()).unwrap();
+    assert!(eventually(Duration::from_secs(1), || !handle.clients().contains_key(&left_ip())).await);
+    tunnel.stop().await.unwrap();
+}
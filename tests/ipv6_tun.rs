@@ -0,0 +1,38 @@
+mod common;
+
+use std::net::{IpAddr, Ipv6Addr};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+use common::{device, free_port, udp_packet, Running, LOCALHOST};
+
+fn v6(last: u16) -> IpAddr {
+    Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, last).into()
+}
+
+#[tokio::test]
+async fn peer_with_an_ipv6_tun_address_over_an_ipv4_underlay() {
+    let (left_port, right_port) = (free_port(), free_port());
+    let left = Multipathtunnel::new(SettingsFileBuilder::new(v6(1))
+        .add_send_device(device(left_port))
+        .remote(LOCALHOST.into(), right_port, v6(2))
+        .build()
+        .unwrap()).unwrap();
+    // The pre-configured remote is keyed by its IPv6 TUN address
+    let clients = left.handle().clients();
+    assert_eq!(clients.get(&v6(2)).map(Vec::len), Some(1), "{:?}", clients);
+
+    let (mut left, mut right) = (Running::start_tunnel(left), Running::start(SettingsFileBuilder::new(v6(2))
+        .add_send_device(device(right_port))
+        .build()
+        .unwrap()));
+    let packet = udp_packet(v6(1), v6(2), b"over IPv4");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+    // The right side learned its peer from that packet, and can answer
+    let reply = udp_packet(v6(2), v6(1), b"reply");
+    right.send(reply.clone());
+    assert_eq!(left.recv().await, Some(reply));
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use std::net::{Ipv6Addr, UdpSocket};
+use common::{device, free_port, left_ip, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+fn free_v6_port() -> u16 {
+    UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn tunnels_carry_packets_over_an_ipv6_underlay() {
+    let (left_port, right_port) = (free_v6_port(), free_v6_port());
+    let left = SettingsFileBuilder::new(left_ip())
+        .add_send_device(SendDevice::new(Ipv6Addr::LOCALHOST.into(), left_port))
+        .remote(Ipv6Addr::LOCALHOST.into(), right_port, right_ip())
+        .build()
+        .unwrap();
+    let right = SettingsFileBuilder::new(right_ip())
+        .add_send_device(SendDevice::new(Ipv6Addr::LOCALHOST.into(), right_port))
+        .build()
+        .unwrap();
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    assert!(left.addr().is_ipv6());
+
+    let packet = udp_packet(left_ip(), right_ip(), b"over v6");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+
+    // The peer was learned at its IPv6 address
+    assert_eq!(right.tunnel.handle().clients()[&left_ip()], vec![left.addr()]);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn dual_stack_device_serves_an_ipv4_peer() {
+    let right_port = free_v6_port();
+    let mut dual = SendDevice::new(Ipv6Addr::UNSPECIFIED.into(), right_port);
+    dual.dual_stack = Some(true);
+    let right = SettingsFileBuilder::new(right_ip()).add_send_device(dual).build().unwrap();
+    let left = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), right_port, right_ip())
+        .build()
+        .unwrap();
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+
+    let packet = udp_packet(left_ip(), right_ip(), b"over v4");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet.clone()));
+    // Learned in the IPv4 form, not as ::ffff:127.0.0.1
+    assert_eq!(right.tunnel.handle().clients()[&left_ip()], vec![left.addr()]);
+
+    // And back, to the IPv4 peer
+    let reply = udp_packet(right_ip(), left_ip(), b"reply");
+    right.send(reply.clone());
+    assert_eq!(left.recv().await, Some(reply));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
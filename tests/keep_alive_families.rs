@@ -0,0 +1,47 @@
+mod common;
+
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use common::{device, free_port, left_ip, recv_message, right_ip, Running, LOCALHOST};
+use mptun::messages::Messages;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+// Addresses keep-alives came from within `within`
+fn keep_alive_senders(socket: UdpSocket, within: Duration) -> Vec<SocketAddr> {
+    let deadline = std::time::Instant::now() + within;
+    let mut senders = Vec::new();
+    while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+        match recv_message(&socket, remaining.max(Duration::from_millis(1))) {
+            Some((Messages::Keepalive, from)) if !senders.contains(&from) => senders.push(from),
+            Some(_) => {},
+            None => break
+        }
+    }
+    senders
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn each_family_is_pinged_from_the_socket_of_that_family() {
+    let v4_peer = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    let v6_peer = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .add_send_device(SendDevice::new(Ipv6Addr::LOCALHOST.into(), 0))
+        .remote(LOCALHOST.into(), v4_peer.local_addr().unwrap().port(), right_ip())
+        .add_remote_addr(v6_peer.local_addr().unwrap())
+        .keep_alive_ms(20)
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+    let addrs = tunnel.tunnel.handle().local_addrs();
+
+    let (v4_senders, v6_senders) = tokio::task::spawn_blocking(move || {
+        let v6 = std::thread::spawn(move || keep_alive_senders(v6_peer, Duration::from_millis(300)));
+        (keep_alive_senders(v4_peer, Duration::from_millis(300)), v6.join().unwrap())
+    }).await.unwrap();
+    assert_eq!(v4_senders, [addrs[0]]);
+    assert_eq!(v6_senders, [addrs[1]]);
+    // Still running, the mismatched family was skipped rather than failed on
+    assert!(tunnel.tunnel.health().await.tun_running);
+    tunnel.stop().await.unwrap();
+}
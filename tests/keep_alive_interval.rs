@@ -0,0 +1,55 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use common::{device, free_port, left_ip, raw_socket, recv_message, right_ip, Running, LOCALHOST};
+use mptun::messages::Messages;
+use mptun::settings::{SettingsFile, SettingsFileBuilder};
+
+fn settings(port: u16, peer: &UdpSocket, interval_ms: u64) -> SettingsFile {
+    SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(port))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), left_ip())
+        .keep_alive_ms(interval_ms)
+        .build()
+        .unwrap()
+}
+
+// Keep-alives `peer` receives over `window`
+async fn keep_alives(peer: UdpSocket, window: Duration) -> (UdpSocket, usize) {
+    tokio::task::spawn_blocking(move || {
+        let (start, mut count) = (Instant::now(), 0);
+        while let Some(left) = window.checked_sub(start.elapsed()) {
+            match recv_message(&peer, left) {
+                Some((Messages::Keepalive, _)) => count += 1,
+                Some(_) => {},
+                None => break
+            }
+        }
+        (peer, count)
+    }).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sub_second_keep_alive_intervals_work_in_milliseconds() {
+    let peer = raw_socket();
+    let tunnel = Running::start(settings(free_port(), &peer, 50));
+
+    let (_, count) = keep_alives(peer, Duration::from_millis(500)).await;
+    // About ten, allowing for a slow start
+    assert!((6..=12).contains(&count), "{} keep-alives", count);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_applies_a_changed_millisecond_interval() {
+    let (port, peer) = (free_port(), raw_socket());
+    let tunnel = Running::start(settings(port, &peer, 5000));
+    let (peer, count) = keep_alives(peer, Duration::from_millis(300)).await;
+    assert!(count <= 1, "{} keep-alives", count);
+
+    tunnel.tunnel.reload(settings(port, &peer, 50)).await;
+    let (_, count) = keep_alives(peer, Duration::from_millis(500)).await;
+    assert!((6..=12).contains(&count), "{} keep-alives", count);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use std::net::{IpAddr, UdpSocket};
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running};
+use mptun::messages::Messages;
+use mptun::settings::SettingsFileBuilder;
+
+const FLAGGED: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 3));
+const REWRITTEN: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 4));
+
+// Introduce `tun_ip` to the tunnel from `socket` with a data packet
+async fn introduce(tunnel: &mut Running, socket: &UdpSocket, tun_ip: IpAddr, seq: usize) {
+    let packet = udp_packet(tun_ip, right_ip(), b"hello");
+    socket.send_to(&data_datagram(seq, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet));
+}
+
+// Whether a keep-alive arrives on `socket` within a few keep-alive intervals
+async fn gets_keep_alive(socket: UdpSocket) -> bool {
+    tokio::task::spawn_blocking(move || {
+        while let Some((message, _)) = recv_message(&socket, Duration::from_millis(500)) {
+            if let Messages::Keepalive = message {
+                return true
+            }
+        }
+        false
+    }).await.unwrap()
+}
+
+#[tokio::test]
+async fn only_natted_peers_get_keep_alives() {
+    let mut settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .keep_alive_ms(100)
+        .build()
+        .unwrap();
+    settings.keep_alive_nat_only = Some(true);
+    settings.nat_peers = Some(vec![FLAGGED]);
+    let mut tunnel = Running::start(settings);
+
+    let (direct, flagged) = (raw_socket(), raw_socket());
+    introduce(&mut tunnel, &direct, left_ip(), 1).await;
+    introduce(&mut tunnel, &flagged, FLAGGED, 2).await;
+    // Heard from a second port of the same IP, as if a NAT rewrote it
+    let (before, rewritten) = (raw_socket(), raw_socket());
+    introduce(&mut tunnel, &before, REWRITTEN, 3).await;
+    introduce(&mut tunnel, &rewritten, REWRITTEN, 4).await;
+
+    let (direct, flagged, rewritten) = tokio::join!(gets_keep_alive(direct), gets_keep_alive(flagged), gets_keep_alive(rewritten));
+    assert!(!direct);
+    assert!(flagged);
+    assert!(rewritten);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use common::{advance_settled, data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running};
+use mptun::clock::MockClock;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SettingsFile, SettingsFileBuilder};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+fn settings(max_idle: Option<u64>, max_lifetime: Option<u64>) -> SettingsFile {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.max_idle = max_idle;
+    settings.max_lifetime = max_lifetime;
+    settings
+}
+
+// Move the mock clock on a second at a time, letting the watcher see each one
+async fn advance(clock: &MockClock, seconds: u64) {
+    for _ in 0..seconds {
+        assert!(advance_settled(clock, Duration::from_secs(1)).await);
+    }
+}
+
+// A data packet from the left peer, delivered to the tunnel's TUN
+async fn traffic(tunnel: &mut Running, peer: &std::net::UdpSocket, seq: usize) {
+    peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_tunnel_shuts_down_after_max_idle_without_traffic() {
+    let clock = Arc::new(MockClock::new());
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings(Some(5), None), clock.clone()).unwrap());
+    let peer = raw_socket();
+
+    advance(&clock, 3).await;
+    traffic(&mut tunnel, &peer, 1).await;
+    // The idle period starts over with the packet
+    advance(&clock, 3).await;
+    tokio::time::sleep(QUIET).await;
+    assert!(!tunnel.run.is_finished());
+
+    // The watcher catches up on the ticks it missed
+    clock.advance(Duration::from_secs(3));
+    let reports = tokio::time::timeout(Duration::from_secs(2), tunnel.run).await.unwrap().unwrap();
+    assert!(reports.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_tunnel_shuts_down_after_max_lifetime_despite_traffic() {
+    let clock = Arc::new(MockClock::new());
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings(None, Some(10)), clock.clone()).unwrap());
+    let peer = raw_socket();
+
+    for seq in 1..=8 {
+        traffic(&mut tunnel, &peer, seq).await;
+        advance(&clock, 1).await;
+    }
+    tokio::time::sleep(QUIET).await;
+    assert!(!tunnel.run.is_finished());
+
+    clock.advance(Duration::from_secs(3));
+    let reports = tokio::time::timeout(Duration::from_secs(2), tunnel.run).await.unwrap().unwrap();
+    assert!(reports.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reload_can_set_max_idle() {
+    let clock = Arc::new(MockClock::new());
+    let unlimited = settings(None, None);
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(unlimited.clone(), clock.clone()).unwrap());
+    // Running by the time a packet gets through, and so is the watcher
+    traffic(&mut tunnel, &raw_socket(), 1).await;
+
+    // Without limits the tunnel runs on however long it sits idle
+    advance(&clock, 10).await;
+    tokio::time::sleep(QUIET).await;
+    assert!(!tunnel.run.is_finished());
+
+    let mut reloaded = unlimited;
+    reloaded.max_idle = Some(5);
+    tunnel.tunnel.reload(reloaded).await;
+    clock.advance(Duration::from_secs(1));
+    let reports = tokio::time::timeout(Duration::from_secs(2), tunnel.run).await.unwrap().unwrap();
+    assert!(reports.is_ok());
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use common::{advance_settled, data_datagram, device, eventually, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running};
+use mptun::clock::MockClock;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+fn keep_alive() -> Vec<u8> {
+    messages::encode_packet(&Messages::Keepalive, WireFormat::Bincode).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_alive_is_answered_at_once() {
+    let tunnel = Running::start(SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap());
+    let peer = raw_socket();
+    peer.send_to(&keep_alive(), tunnel.addr()).unwrap();
+
+    let reply = tokio::task::spawn_blocking(move || recv_message(&peer, Duration::from_secs(2))).await.unwrap();
+    assert!(matches!(reply, Some((Messages::KeepaliveReply, from)) if from == tunnel.addr()), "{:?}", reply);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_alives_refresh_last_seen_and_hold_off_the_reaper() {
+    let clock = Arc::new(MockClock::new());
+    let settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .client_timeout(8)
+        .build()
+        .unwrap();
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    tokio::time::sleep(QUIET).await;
+    let peer = raw_socket();
+    let mptun = tunnel.tunnel.clone();
+    let last_seen = || async {
+        let peers = mptun.peers().await;
+        peers.iter().find(|info| info.tun_ip == left_ip()).map(|info| info.addrs[0].last_seen)
+    };
+    // Polls until the peer counts as heard from just now
+    let heard_from_now = || async {
+        for _ in 0..200 {
+            if last_seen().await == Some(Some(Duration::ZERO)) {
+                return true
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    };
+
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(last_seen().await, Some(Some(Duration::from_secs(6))));
+
+    // Only a keep-alive comes, and the peer counts as heard from again
+    peer.send_to(&keep_alive(), tunnel.addr()).unwrap();
+    assert!(heard_from_now().await);
+    // As does a reply to one of ours
+    clock.advance(Duration::from_secs(3));
+    peer.send_to(&messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap(), tunnel.addr()).unwrap();
+    assert!(heard_from_now().await);
+
+    // Past the timeout since its data packet, not since it was last heard from
+    assert!(advance_settled(&clock, Duration::from_secs(6)).await);
+    let handle = tunnel.tunnel.handle();
+    assert!(handle.clients().contains_key(&left_ip()));
+    clock.advance(Duration::from_secs(4));
+    assert!(eventually(Duration::from_secs(2), || !handle.clients().contains_key(&left_ip())).await);
+    tunnel.stop().await.unwrap();
+}
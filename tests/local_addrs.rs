@@ -0,0 +1,23 @@
+mod common;
+
+use common::{right_ip, Running};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+#[tokio::test]
+async fn port_zero_reports_the_port_the_os_picked() {
+    let settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(SendDevice::new([127, 0, 0, 1].into(), 0))
+        .add_send_device(SendDevice::new([127, 0, 0, 2].into(), 0))
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+
+    let addrs = tunnel.tunnel.handle().local_addrs();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.iter().all(|addr| addr.port() != 0), "{:?}", addrs);
+    assert_eq!(addrs[0], tunnel.addr());
+    assert_eq!(addrs[1].ip(), std::net::IpAddr::from([127, 0, 0, 2]));
+    // Taken by the tunnel
+    assert!(std::net::UdpSocket::bind(addrs[1]).is_err());
+    tunnel.stop().await.unwrap();
+}
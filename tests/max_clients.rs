@@ -0,0 +1,47 @@
+mod common;
+
+use std::net::{IpAddr, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, device, free_port, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::SettingsFileBuilder;
+
+fn peer_ip(last: u8) -> IpAddr {
+    [10, 0, 0, last].into()
+}
+
+fn send(socket: &UdpSocket, port: u16, seq: usize, from: IpAddr) {
+    socket.send_to(&data_datagram(seq, &udp_packet(from, right_ip(), b"hello")), (LOCALHOST, port)).unwrap();
+}
+
+#[tokio::test]
+async fn peers_past_the_cap_are_rejected_while_known_ones_update() {
+    let port = free_port();
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(port)).build().unwrap();
+    settings.max_clients = Some(2);
+    let mut tunnel = Running::start(settings);
+    let (first, second, third) = (raw_socket(), raw_socket(), raw_socket());
+
+    send(&first, port, 1, peer_ip(1));
+    send(&second, port, 1, peer_ip(3));
+    assert_eq!(tunnel.drain(Duration::from_millis(100)).await.len(), 2);
+    // The list is full, so a third peer is turned away
+    send(&third, port, 1, peer_ip(4));
+    assert!(tunnel.drain(Duration::from_millis(100)).await.is_empty());
+    let handle = tunnel.tunnel.handle();
+    assert_eq!(handle.stats().rx_clients_rejected.load(Ordering::Relaxed), 1);
+    assert!(!handle.clients().contains_key(&peer_ip(4)));
+
+    // A known peer heard from a new address still gets it added
+    send(&third, port, 2, peer_ip(1));
+    assert_eq!(tunnel.drain(Duration::from_millis(100)).await.len(), 1);
+    let mut addrs = handle.clients()[&peer_ip(1)].clone();
+    addrs.sort();
+    let mut expected = vec![first.local_addr().unwrap(), third.local_addr().unwrap()];
+    expected.sort();
+    assert_eq!(addrs, expected);
+    assert_eq!(handle.clients().len(), 2);
+    assert_eq!(handle.stats().rx_clients_rejected.load(Ordering::Relaxed), 1);
+
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use common::{device, free_port, left_ip, noise, pair_settings, right_ip, udp_packet, Running};
+use mptun::error::SettingsError;
+use mptun::messages::WireFormat;
+use mptun::settings::{SettingsFileBuilder, DEFAULT_TUN_MTU};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+// IPv4 and UDP headers of `udp_packet`
+const HEADERS_LEN: usize = 28;
+
+// Incompressible packets as large as the TUN MTU, from left to right in
+// `format`, with every optional header and encryption if `everything`
+async fn full_sized_packets_arrive_intact(format: WireFormat, everything: bool) {
+    let configure = |settings: SettingsFileBuilder| match everything {
+        true => settings.wire_format(format).encryption_key(KEY),
+        false => settings.wire_format(format)
+    };
+    let (mut left, mut right) = pair_settings(configure, configure);
+    for settings in [&mut left, &mut right] {
+        if everything {
+            settings.session_epoch = Some(true);
+            settings.path_sequence = Some(true);
+            settings.timestamps = Some(true);
+        }
+    }
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    for seed in 0..5 {
+        let packet = udp_packet(left_ip(), right_ip(), &noise(DEFAULT_TUN_MTU - HEADERS_LEN, seed));
+        assert_eq!(packet.len(), DEFAULT_TUN_MTU);
+        left.send(packet.clone());
+        assert_eq!(right.recv().await, Some(packet), "{:?}", format);
+    }
+    assert_eq!(right.tunnel.handle().stats().rx_oversized.load(Ordering::Relaxed), 0);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn packets_of_the_tun_mtu_arrive_in_every_wire_format() {
+    for format in [WireFormat::Bincode, WireFormat::Compact, WireFormat::Versioned] {
+        full_sized_packets_arrive_intact(format, false).await;
+    }
+}
+
+#[tokio::test]
+async fn packets_of_the_tun_mtu_arrive_with_every_header_and_encryption() {
+    for format in [WireFormat::Bincode, WireFormat::Compact, WireFormat::Versioned] {
+        full_sized_packets_arrive_intact(format, true).await;
+    }
+}
+
+#[test]
+fn max_payload_len_below_the_tun_mtu_is_refused() {
+    let build = |tun_mtu: Option<usize>| {
+        let mut settings = SettingsFileBuilder::new(left_ip()).add_send_device(device(free_port())).build().unwrap();
+        settings.max_payload_len = Some(1000);
+        settings.tun_mtu = tun_mtu;
+        settings.validate()
+    };
+    assert!(matches!(build(None), Err(SettingsError::MaxPayloadBelowMtu(1000, DEFAULT_TUN_MTU))));
+    assert!(build(Some(900)).is_ok());
+}
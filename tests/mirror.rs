@@ -0,0 +1,48 @@
+mod common;
+
+use std::convert::TryInto;
+use std::time::Duration;
+use common::{eventually, left_ip, pair_settings, raw_socket, right_ip, udp_packet, Running};
+use mptun::settings::MirrorSettings;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivered_packets_are_mirrored_to_the_collector_and_pcap() {
+    let pcap = std::env::temp_dir().join(format!("mptun-mirror-{}.pcap", std::process::id()));
+    let _ = std::fs::remove_file(&pcap);
+    let collector = raw_socket();
+    let (left, mut right) = pair_settings(|left| left, |right| right);
+    right.mirror = Some(MirrorSettings { collector: Some(collector.local_addr().unwrap()), pcap: Some(pcap.clone()) });
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    let packets: Vec<_> = (0..3u8).map(|index| udp_packet(left_ip(), right_ip(), &[index; 10])).collect();
+    // Once the mirror is being written
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for packet in &packets {
+        left.send(packet.clone());
+        assert_eq!(right.recv().await.as_ref(), Some(packet));
+    }
+
+    let mut buf = vec![0u8; 65536];
+    for packet in &packets {
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &packet[..]);
+    }
+
+    // The file header, then a record header before each packet
+    let expected_len = 24 + packets.iter().map(|packet| 16 + packet.len()).sum::<usize>();
+    let written = || std::fs::read(&pcap).unwrap_or_default();
+    assert!(eventually(Duration::from_secs(2), || written().len() == expected_len).await, "{} bytes", written().len());
+    let written = written();
+    assert_eq!(written[..4], 0xa1b2_c3d4u32.to_le_bytes());
+    // Raw IP link type
+    assert_eq!(written[20..24], 101u32.to_le_bytes());
+    let mut offset = 24;
+    for packet in &packets {
+        let len = u32::from_le_bytes(written[offset + 8..offset + 12].try_into().unwrap()) as usize;
+        assert_eq!(&written[offset + 16..offset + 16 + len], &packet[..]);
+        offset += 16 + len;
+    }
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+    std::fs::remove_file(&pcap).unwrap();
+}
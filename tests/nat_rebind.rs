@@ -0,0 +1,42 @@
+mod common;
+
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::messages::Messages;
+use mptun::settings::SettingsFileBuilder;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_peer_rebound_by_its_nat_is_left_with_one_target() {
+    let port = free_port();
+    let mut settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(port))
+        // Where the peer was configured, before its NAT picked another port
+        .remote(LOCALHOST.into(), free_port(), left_ip())
+        .build()
+        .unwrap();
+    settings.nat_rebind_grace = Some(0);
+    let mut tunnel = Running::start(settings);
+    let peer = raw_socket();
+
+    for seq in 1..=2 {
+        peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), b"rebound")), (LOCALHOST, port)).unwrap();
+        assert!(tunnel.recv().await.is_some());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(tunnel.tunnel.handle().clients().get(&left_ip()), Some(&vec![peer.local_addr().unwrap()]));
+
+    // And the peer gets a single copy of what's sent to it
+    tunnel.send(udp_packet(right_ip(), left_ip(), b"reply"));
+    let received = tokio::task::spawn_blocking(move || {
+        let mut data = 0;
+        while let Some((message, _)) = recv_message(&peer, Duration::from_millis(200)) {
+            if matches!(message, Messages::Packet(_)) {
+                data += 1;
+            }
+        }
+        data
+    }).await.unwrap();
+    assert_eq!(received, 1);
+
+    tunnel.stop().await.unwrap();
+}
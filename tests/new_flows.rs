@@ -0,0 +1,59 @@
+mod common;
+
+use std::time::Duration;
+use bytes::Bytes;
+use etherparse::PacketBuilder;
+use common::{device, eventually, free_port, pair_settings, Running};
+use mptun::settings::PathMode;
+
+// A UDP packet from `left_ip` to `right_ip` in the flow from `source_port`
+fn packet(source_port: u16, index: u8) -> Bytes {
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+        .udp(source_port, 5000).write(&mut packet, &[index; 20]).unwrap();
+    Bytes::from(packet)
+}
+
+#[tokio::test]
+async fn first_packets_of_new_flows_go_over_every_link() {
+    let mut backup = device(free_port());
+    backup.priority = Some(1);
+    let (mut left, right) = pair_settings(|left| left.add_send_device(backup).path_mode(PathMode::Failover), |right| right);
+    left.send_devices[0].priority = Some(0);
+    left.new_flow_duplicate_packets = Some(2);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    for index in 0..5 {
+        left.send(packet(1000, index));
+    }
+    for index in 0..3 {
+        left.send(packet(1001, index));
+    }
+    // Copies are deduplicated, every packet comes out once
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 8);
+
+    let handle = left.tunnel.handle();
+    let tx_packets = || handle.paths().iter().map(|path| path.tx_packets).collect::<Vec<_>>();
+    // The active link takes everything, the backup the first two of each flow
+    assert!(eventually(Duration::from_secs(1), || tx_packets() == vec![8, 4]).await, "{:?}", tx_packets());
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn without_the_setting_failover_uses_one_link() {
+    let mut backup = device(free_port());
+    backup.priority = Some(1);
+    let (mut left, right) = pair_settings(|left| left.add_send_device(backup).path_mode(PathMode::Failover), |right| right);
+    left.send_devices[0].priority = Some(0);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    for index in 0..5 {
+        left.send(packet(1000, index));
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 5);
+    let tx_packets: Vec<u64> = left.tunnel.handle().paths().iter().map(|path| path.tx_packets).collect();
+    assert_eq!(tx_packets, vec![5, 0]);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,22 @@
+mod common;
+
+use std::time::Duration;
+use common::{data_datagram, left_ip, raw_socket, right_ip, single, udp_packet};
+
+#[tokio::test]
+async fn reorder_depths_of_a_known_pattern() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+
+    // 5 overtakes 3 and 4, which arrive 2 and 1 behind it
+    for seq in [1, 2, 5, 3, 4, 6] {
+        peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), &[seq as u8])), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 6);
+
+    let path = &tunnel.tunnel.handle().paths()[0];
+    assert_eq!(path.out_of_order, Some(2.0 / 6.0));
+    assert_eq!(path.reorder_depth_p99, Some(2));
+    assert_eq!(path.reorder_depth_max, Some(2));
+    tunnel.stop().await.unwrap();
+}
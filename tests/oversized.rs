@@ -0,0 +1,67 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, left_ip, pair_settings, raw_socket, right_ip, single, udp_packet, Running};
+use mptun::messages::WireFormat;
+use mptun::settings::SettingsFileBuilder;
+
+#[tokio::test]
+async fn payload_claiming_more_than_the_tun_mtu_is_dropped() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+
+    // Compresses to a small datagram, but decompresses beyond the TUN MTU
+    let oversized = udp_packet(left_ip(), right_ip(), &[0; 8000]);
+    peer.send_to(&data_datagram(1, &oversized), tunnel.addr()).unwrap();
+    let fits = udp_packet(left_ip(), right_ip(), b"fits");
+    peer.send_to(&data_datagram(2, &fits), tunnel.addr()).unwrap();
+
+    assert_eq!(tunnel.recv().await, Some(fits));
+    assert_eq!(tunnel.recv_within(Duration::from_millis(200)).await, None);
+    assert_eq!(tunnel.tunnel.handle().stats().rx_oversized.load(Ordering::Relaxed), 1);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn datagram_longer_than_a_full_payload_encodes_to_is_dropped() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+
+    // Random bytes don't compress, so the datagram itself is too long
+    let mut state = 1u32;
+    let noise: Vec<u8> = (0..3000).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (state >> 16) as u8
+    }).collect();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), &noise)), tunnel.addr()).unwrap();
+
+    let stats = tunnel.tunnel.handle();
+    assert!(common::eventually(Duration::from_secs(1), || stats.stats().rx_oversized.load(Ordering::Relaxed) == 1).await);
+    assert_eq!(tunnel.recv_within(Duration::from_millis(100)).await, None);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn full_size_packets_with_every_header_fit_the_receive_buffer() {
+    let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    for format in [WireFormat::Bincode, WireFormat::Versioned, WireFormat::Compact] {
+        let all_headers = |settings: SettingsFileBuilder| settings.tun_mtu(60000).wire_format(format).encryption_key(key);
+        let (mut left, mut right) = pair_settings(all_headers, all_headers);
+        for settings in [&mut left, &mut right] {
+            settings.timestamps = Some(true);
+            settings.path_sequence = Some(true);
+            settings.session_epoch = Some(true);
+            settings.checksum = Some(true);
+        }
+        let (left, mut right) = (Running::start(left), Running::start(right));
+
+        // Random bytes don't compress, so the datagram is as long as it gets
+        let packet = udp_packet(left_ip(), right_ip(), &common::noise(60000 - 28, 7));
+        left.send(packet.clone());
+        assert_eq!(right.recv().await, Some(packet), "{:?}", format);
+        assert_eq!(right.tunnel.handle().stats().rx_oversized.load(Ordering::Relaxed), 0);
+        left.stop().await.unwrap();
+        right.stop().await.unwrap();
+    }
+}
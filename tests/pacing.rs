@@ -0,0 +1,41 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use common::{device, free_port, left_ip, noise, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::SettingsFileBuilder;
+
+const PACKETS: usize = 10;
+const PACING_BPS: u64 = 800_000;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paced_datagrams_are_spaced_at_the_rate() {
+    let peer = raw_socket();
+    let mut link = device(free_port());
+    link.pacing_bps = Some(PACING_BPS);
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(link)
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+
+    // All at once, so only pacing spreads them out
+    for seed in 0..PACKETS as u32 {
+        tunnel.send(udp_packet(left_ip(), right_ip(), &noise(1000, seed)));
+    }
+    let arrivals = tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0; 65536];
+        (0..PACKETS).map(|_| {
+            let len = peer.recv(&mut buf).unwrap();
+            (Instant::now(), len)
+        }).collect::<Vec<_>>()
+    }).await.unwrap();
+
+    // Each datagram goes out once the previous one would have at the rate
+    for pair in arrivals.windows(2) {
+        let ((sent, len), (next, _)) = (pair[0], pair[1]);
+        let spacing = Duration::from_secs_f64(len as f64 * 8.0 / PACING_BPS as f64);
+        assert!(next - sent >= spacing - Duration::from_millis(2), "{:?} apart, expected {:?}", next - sent, spacing);
+    }
+    tunnel.stop().await.unwrap();
+}
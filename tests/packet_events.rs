@@ -0,0 +1,57 @@
+mod common;
+
+use std::time::Duration;
+use common::{eventually, left_ip, pair, right_ip, udp_packet};
+use mptun::events::SendResult;
+use tokio::sync::broadcast::error::TryRecvError;
+
+#[tokio::test]
+async fn stream_emits_an_event_per_packet_sent() {
+    let (left, mut right) = pair(|left| left, |right| right);
+    let mut events = left.tunnel.subscribe_packet_events();
+
+    let packets: Vec<_> = (0..3u8).map(|index| udp_packet(left_ip(), right_ip(), &[index; 10])).collect();
+    for packet in &packets {
+        left.send(packet.clone());
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 3);
+
+    let path = left.tunnel.handle().paths()[0].name.clone();
+    let peer = right.addr();
+    let mut seqs = Vec::new();
+    for packet in &packets {
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.size, packet.len());
+        assert_eq!(event.tun_ip, right_ip());
+        assert_eq!(event.destination, peer);
+        assert_eq!(event.path, path);
+        assert!(matches!(event.result, SendResult::Sent(len) if len > 0), "{:?}", event.result);
+        seqs.push(event.seq);
+    }
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn slow_subscriber_loses_events_instead_of_stalling_sends() {
+    let (left, right) = pair(|left| left, |right| right);
+    // Subscribed, but never read until the end
+    let mut events = left.tunnel.subscribe_packet_events();
+
+    const PACKETS: u64 = 3000;
+    let handle = left.tunnel.handle();
+    for index in 0..PACKETS {
+        left.send(udp_packet(left_ip(), right_ip(), &index.to_be_bytes()));
+        if index % 500 == 499 {
+            // Let the send task catch up, so its queue doesn't overflow
+            let sent = index + 1;
+            assert!(eventually(Duration::from_secs(2), || handle.paths()[0].tx_packets >= sent).await);
+        }
+    }
+    assert!(eventually(Duration::from_secs(2), || handle.paths()[0].tx_packets == PACKETS).await);
+
+    assert!(matches!(events.try_recv(), Err(TryRecvError::Lagged(lost)) if lost > 0));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
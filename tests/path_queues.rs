@@ -0,0 +1,37 @@
+mod common;
+
+use std::time::Duration;
+use common::{free_port, left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::settings::SendDevice;
+
+#[tokio::test]
+async fn a_congested_link_drops_from_its_own_queue_only() {
+    let (left, right) = pair_settings(
+        |left| {
+            let mut slow = SendDevice::new([127, 0, 0, 2].into(), free_port());
+            // About 10 ms per packet, far behind the packets read
+            slow.pacing_bps = Some(80_000);
+            left.add_send_device(slow)
+        },
+        |right| right
+    );
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    let handle = left.tunnel.handle();
+
+    let packets: Vec<_> = (0..1000u32).map(|index| udp_packet(left_ip(), right_ip(), &index.to_be_bytes())).collect();
+    for (index, packet) in packets.iter().enumerate() {
+        left.send(packet.clone());
+        if index % 50 == 49 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await, packets);
+
+    let paths = handle.paths();
+    assert_eq!((paths[0].tx_packets, paths[0].tx_queue_dropped), (1000, 0));
+    assert!(paths[1].tx_queue_dropped > 0, "{:?}", paths[1]);
+    assert!(paths[1].tx_packets < 1000);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use std::time::Duration;
+use bytes::Bytes;
+use etherparse::PacketBuilder;
+use common::{device, eventually, free_port, left_ip, pair_settings, raw_socket, right_ip, single, udp_packet, Running};
+use mptun::messages::{self, PathStamp, WireFormat};
+use mptun::pathseq::LinkLoss;
+use mptun::settings::{PathMode, SendDevice};
+
+fn stamped_datagram(seq: usize, path_id: u16, path_seq: u64) -> Vec<u8> {
+    let compressed = lz4_flex::compress_prepend_size(&udp_packet(left_ip(), right_ip(), &[seq as u8]));
+    let mut datagram = Vec::new();
+    messages::encode_data_into(seq, None, None, Some(PathStamp { path_id, path_seq }), &compressed, WireFormat::Bincode, &mut datagram).unwrap();
+    datagram
+}
+
+#[tokio::test]
+async fn gaps_in_a_paths_own_sequence_are_counted_as_its_loss() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+
+    // Path 7 loses 3 and 4 and has 6 come in late. Path 9 loses nothing,
+    // though both skip global sequence numbers the other one carried.
+    let sent = [(1, 7, 0), (2, 9, 0), (3, 7, 1), (4, 7, 2), (5, 9, 1), (6, 7, 5), (7, 7, 3), (8, 9, 2)];
+    for (seq, path_id, path_seq) in sent {
+        peer.send_to(&stamped_datagram(seq, path_id, path_seq), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), sent.len());
+
+    let peer_ip = left_ip();
+    assert_eq!(tunnel.tunnel.handle().paths()[0].link_losses, vec![
+        LinkLoss { tun_ip: peer_ip, path_id: 7, received: 5, lost: 1 },
+        LinkLoss { tun_ip: peer_ip, path_id: 9, received: 3, lost: 0 }
+    ]);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn stamped_packets_from_a_tunnel_are_accounted_to_its_path() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.path_sequence = Some(true);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    for index in 0..4 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 4);
+
+    let path_id = left.tunnel.handle().paths()[0].path_id;
+    assert_eq!(right.tunnel.handle().paths()[0].link_losses, vec![LinkLoss { tun_ip: left_ip(), path_id, received: 4, lost: 0 }]);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+// A UDP packet from `left_ip` to `right_ip` in the flow from `source_port`
+fn flow_packet(source_port: u16, index: u8) -> Bytes {
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+        .udp(source_port, 5000).write(&mut packet, &[index; 20]).unwrap();
+    Bytes::from(packet)
+}
+
+// Waits for the right tunnel to have heard `received` packets in all over
+// `links` of the left one's paths, and returns what it counted
+async fn link_losses_once_received(right: &Running, links: usize, received: u64) -> Vec<LinkLoss> {
+    let handle = right.tunnel.handle();
+    let link_losses = || handle.paths()[0].link_losses.clone();
+    let heard = || link_losses().len() == links && link_losses().iter().map(|link| link.received).sum::<u64>() == received;
+    assert!(eventually(Duration::from_secs(2), heard).await, "{:?}", link_losses());
+    link_losses()
+}
+
+#[tokio::test]
+async fn packets_a_failover_backup_skips_are_not_lost_on_it() {
+    let mut backup = device(free_port());
+    backup.priority = Some(1);
+    let (mut left, right) = pair_settings(|left| left.add_send_device(backup).path_mode(PathMode::Failover), |right| right);
+    left.send_devices[0].priority = Some(0);
+    left.new_flow_duplicate_packets = Some(2);
+    left.path_sequence = Some(true);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    // The backup sends the first two packets of each flow and skips the rest
+    for index in 0..5 {
+        left.send(flow_packet(1000, index));
+    }
+    for index in 0..3 {
+        left.send(flow_packet(1001, index));
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 8);
+
+    let link_losses = link_losses_once_received(&right, 2, 8 + 4).await;
+    assert!(link_losses.iter().all(|link| link.lost == 0), "{:?}", link_losses);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn flows_hashed_to_other_links_are_not_lost_on_this_one() {
+    let (mut left, right) = pair_settings(
+        |left| left.add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port())).path_mode(PathMode::FlowHash),
+        |right| right
+    );
+    left.path_sequence = Some(true);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    // Twenty flows are spread over both links, each link skipping the others'
+    for port in 1000..1020 {
+        for index in 0..5 {
+            left.send(flow_packet(port, index));
+        }
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 100);
+
+    let link_losses = link_losses_once_received(&right, 2, 100).await;
+    assert!(link_losses.iter().all(|link| link.lost == 0), "{:?}", link_losses);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use common::{device, eventually, free_port, left_ip, pair_settings, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::path::Health;
+
+#[tokio::test]
+async fn paths_match_the_devices_and_their_measurements() {
+    let second_port = free_port();
+    let mut second = device(second_port);
+    second.priority = Some(3);
+    second.weight = Some(2.5);
+    let (left, right) = pair_settings(|left| left.add_send_device(second).keep_alive(1), |right| right);
+    let first_port = left.send_devices[0].udp_listen_port;
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    for index in 0..5u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index; 100]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 5);
+
+    let handle = left.tunnel.handle();
+    // Both have had a keep-alive answered
+    assert!(eventually(Duration::from_secs(2), || handle.paths().iter().all(|path| path.rtt.is_some())).await);
+
+    let paths = handle.paths();
+    assert_eq!(paths.len(), 2);
+    let local_addrs: Vec<SocketAddr> = paths.iter().map(|path| path.local_addr).collect();
+    assert_eq!(local_addrs, vec![(LOCALHOST, first_port).into(), (LOCALHOST, second_port).into()]);
+    assert_eq!(paths[1].name, LOCALHOST.to_string());
+    assert_eq!(paths[1].priority, 3);
+    assert_eq!(paths[1].weight, 2.5);
+    for path in &paths {
+        assert_eq!(path.remote_addrs, vec![right.addr()]);
+        assert_eq!(path.health, Health::Up);
+        assert!(path.rtt.unwrap() < Duration::from_secs(1));
+        assert_eq!(path.loss, Some(0.0));
+        assert!(!path.paused);
+    }
+
+    // Every data packet went out on both, keep-alives aren't counted
+    for path in &paths {
+        assert_eq!(path.tx_packets, 5);
+        assert!(path.tx_bytes > 0);
+        assert!(path.rx_packets > 0);
+    }
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
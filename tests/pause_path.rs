@@ -0,0 +1,97 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use common::{device, free_port, left_ip, pair_settings, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::messages::Messages;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+// Packets sent and received by each of the tunnel's paths so far
+fn counts(tunnel: &Running) -> (Vec<u64>, Vec<u64>) {
+    let paths = tunnel.tunnel.handle().paths();
+    (paths.iter().map(|path| path.tx_packets).collect(), paths.iter().map(|path| path.rx_packets).collect())
+}
+
+async fn exchange(left: &mut Running, right: &mut Running) {
+    for index in 0..20u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+        right.send(udp_packet(right_ip(), left_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 20);
+    assert_eq!(left.drain(Duration::from_millis(200)).await.len(), 20);
+}
+
+#[tokio::test]
+async fn a_paused_path_sends_nothing_but_still_receives_until_resumed() {
+    let (left, right) = pair_settings(
+        |left| left.add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port())),
+        |right| right
+    );
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+    // Let the right side learn both of the left side's addresses
+    exchange(&mut left, &mut right).await;
+
+    assert!(left.tunnel.pause_path("127.0.0.2"));
+    assert_eq!(left.tunnel.handle().paths().iter().map(|path| path.paused).collect::<Vec<_>>(), [false, true]);
+    let (sent_before, received_before) = counts(&left);
+    exchange(&mut left, &mut right).await;
+    let (sent, received) = counts(&left);
+    assert_eq!([sent[0] - sent_before[0], sent[1] - sent_before[1]], [20, 0]);
+    assert!(received[1] > received_before[1], "the paused path stopped receiving");
+
+    assert!(left.tunnel.resume_path("127.0.0.2"));
+    exchange(&mut left, &mut right).await;
+    assert_eq!(counts(&left).0[1] - sent[1], 20);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_paths_cannot_be_paused() {
+    let (left, right) = pair_settings(|left| left, |right| right);
+    let (left, right) = (Running::start(left), Running::start(right));
+    assert!(!left.tunnel.pause_path("eth9"));
+    assert!(!left.tunnel.resume_path("eth9"));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+// Keep-alives `peer` receives over `window`
+async fn keep_alives(peer: UdpSocket, window: Duration) -> (UdpSocket, usize) {
+    tokio::task::spawn_blocking(move || {
+        let (start, mut count) = (Instant::now(), 0);
+        while let Some(left) = window.checked_sub(start.elapsed()) {
+            match recv_message(&peer, left) {
+                Some((Messages::Keepalive, _)) => count += 1,
+                Some(_) => {},
+                None => break
+            }
+        }
+        (peer, count)
+    }).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_alives_continue_while_paused_unless_turned_off() {
+    for while_paused in [None, Some(false)] {
+        let peer = raw_socket();
+        let mut settings = SettingsFileBuilder::new(right_ip())
+            .add_send_device(device(free_port()))
+            .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), left_ip())
+            .keep_alive_ms(50)
+            .build()
+            .unwrap();
+        settings.keep_alive_while_paused = while_paused;
+        let tunnel = Running::start(settings);
+        assert!(tunnel.tunnel.pause_path("127.0.0.1"));
+
+        let (_, count) = keep_alives(peer, Duration::from_millis(500)).await;
+        match while_paused {
+            None => assert!(count >= 5, "{} keep-alives while paused", count),
+            // The first may have gone out before the pause
+            _ => assert!(count <= 1, "{} keep-alives with keep_alive_while_paused off", count)
+        }
+        tunnel.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use common::{device, free_port, left_ip, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{PathMode, SendDevice, SettingsFileBuilder};
+
+fn third_ip() -> IpAddr {
+    [10, 0, 0, 3].into()
+}
+
+#[tokio::test]
+async fn each_peer_gets_its_own_path_mode() {
+    let fiber_port = free_port();
+    let mut fiber = device(fiber_port);
+    fiber.priority = Some(0);
+    let mut lte = SendDevice::new([127, 0, 0, 2].into(), free_port());
+    lte.priority = Some(1);
+    // Redundant for everyone but right_ip's peer
+    let mut left = Running::start(SettingsFileBuilder::new(left_ip())
+        .add_send_device(fiber)
+        .add_send_device(lte)
+        .peer_path_mode(right_ip(), PathMode::Failover)
+        .build()
+        .unwrap());
+    let peer = |tun_ip: IpAddr| Running::start(SettingsFileBuilder::new(tun_ip)
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), fiber_port, left_ip())
+        .build()
+        .unwrap());
+    let (mut failover, mut redundant) = (peer(right_ip()), peer(third_ip()));
+
+    // Both introduce themselves
+    for (tunnel, tun_ip) in [(&failover, right_ip()), (&redundant, third_ip())] {
+        tunnel.send(udp_packet(tun_ip, left_ip(), b"hello"));
+    }
+    assert_eq!(left.drain(Duration::from_millis(200)).await.len(), 2);
+
+    let sent = |left: &Running| -> Vec<u64> { left.tunnel.handle().paths().iter().map(|path| path.tx_packets).collect() };
+    for index in 0..10u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(failover.drain(Duration::from_millis(200)).await.len(), 10);
+    // Only the highest priority link carried them
+    assert_eq!(sent(&left), vec![10, 0]);
+
+    for index in 0..10u8 {
+        left.send(udp_packet(left_ip(), third_ip(), &[index]));
+    }
+    assert_eq!(redundant.drain(Duration::from_millis(200)).await.len(), 10);
+    // Every link carried a copy
+    assert_eq!(sent(&left), vec![20, 10]);
+
+    for tunnel in [left, failover, redundant] {
+        tunnel.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::time::Duration;
+use common::{free_port, left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::error::SettingsError;
+use mptun::settings::{PeerPathSettings, SendDevice};
+
+// Packets sent and received by each of the tunnel's paths so far
+fn counts(tunnel: &Running) -> (Vec<u64>, Vec<u64>) {
+    let paths = tunnel.tunnel.handle().paths();
+    (paths.iter().map(|path| path.tx_packets).collect(), paths.iter().map(|path| path.rx_packets).collect())
+}
+
+fn grown(after: &[u64], before: &[u64]) -> Vec<u64> {
+    after.iter().zip(before).map(|(after, before)| after - before).collect()
+}
+
+#[tokio::test]
+async fn the_left_side_sends_on_one_link_and_is_sent_to_on_the_other() {
+    let (left, right) = pair_settings(
+        |left| left
+            .add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port()))
+            .peer_paths(right_ip(), PeerPathSettings { send: Some(vec!["127.0.0.1".to_string()]), receive: Some(vec!["127.0.0.2".to_string()]) }),
+        |right| right.add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port()))
+    );
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+    // Time for the return path announcements to arrive
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let (sent_before, received_before) = counts(&left);
+    for index in 0..20u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 20);
+    for index in 0..20u8 {
+        right.send(udp_packet(right_ip(), left_ip(), &[index]));
+    }
+    assert_eq!(left.drain(Duration::from_millis(200)).await.len(), 20);
+
+    let (sent, received) = counts(&left);
+    assert_eq!(grown(&sent, &sent_before), [20, 0]);
+    let received = grown(&received, &received_before);
+    assert!(received[0] == 0 && received[1] >= 20, "{:?}", received);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[test]
+fn peer_paths_naming_an_unknown_device_are_refused() {
+    let (mut left, _) = pair_settings(|left| left, |right| right);
+    left.peer_paths = Some([(right_ip(), PeerPathSettings { send: None, receive: Some(vec!["eth9".to_string()]) })].iter().cloned().collect());
+    assert!(matches!(left.validate(), Err(SettingsError::UnknownDevice(name)) if name == "eth9"));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use common::{data_datagram, eventually, left_ip, raw_socket, right_ip, single, udp_packet};
+use mptun::stats::PeerTraffic;
+
+#[tokio::test]
+async fn traffic_is_counted_for_each_peer_separately() {
+    let mut tunnel = single(|settings| settings);
+    let (first, second): (IpAddr, IpAddr) = (left_ip(), [10, 0, 0, 3].into());
+    let (first_socket, second_socket) = (raw_socket(), raw_socket());
+
+    for seq in [1, 2] {
+        first_socket.send_to(&data_datagram(seq, &udp_packet(first, right_ip(), &[1; 10])), tunnel.addr()).unwrap();
+    }
+    second_socket.send_to(&data_datagram(3, &udp_packet(second, right_ip(), &[2; 100])), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 3);
+
+    // Both are known now, so packets from the TUN go out to them
+    tunnel.send(udp_packet(right_ip(), first, &[3; 50]));
+    tunnel.send(udp_packet(right_ip(), second, &[4; 5]));
+    let handle = tunnel.tunnel.handle();
+    assert!(eventually(Duration::from_secs(1), || handle.peer_stats().values().map(|peer| peer.tx_packets).sum::<u64>() == 2).await);
+
+    let stats = handle.peer_stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[&first], PeerTraffic { tx_packets: 1, tx_bytes: 78, rx_packets: 2, rx_bytes: 76 });
+    assert_eq!(stats[&second], PeerTraffic { tx_packets: 1, tx_bytes: 33, rx_packets: 1, rx_bytes: 128 });
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::clock::MockClock;
+use mptun::handle::{PeerAddr, PeerInfo};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test]
+async fn peers_lists_discovered_peers_with_last_seen() {
+    let clock = Arc::new(MockClock::new());
+    let settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    tokio::time::sleep(QUIET).await;
+    assert!(tunnel.tunnel.peers().await.is_empty());
+
+    let peer = raw_socket();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hello")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    clock.advance(Duration::from_secs(3));
+
+    assert_eq!(tunnel.tunnel.peers().await, vec![PeerInfo {
+        tun_ip: left_ip(),
+        addrs: vec![PeerAddr { addr: peer.local_addr().unwrap(), last_seen: Some(Duration::from_secs(3)) }],
+        rtt: None
+    }]);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_configured_remote_never_heard_from_has_no_last_seen() {
+    let remote: SocketAddr = (LOCALHOST, free_port()).into();
+    let tunnel = Running::start(SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), remote.port(), left_ip())
+        .build()
+        .unwrap());
+
+    let peers = tunnel.tunnel.peers().await;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].tun_ip, left_ip());
+    assert_eq!(peers[0].addrs, vec![PeerAddr { addr: remote, last_seen: None }]);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use bytes::Bytes;
+use common::{device, free_port, left_ip, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{SettingsFileBuilder, UnknownPeerQueueSettings};
+
+// A tunnel that doesn't know its peer yet, with `held` packets sent from its
+// TUN to it, and then the peer, which sends it `hello` once `wait` is over.
+// What the peer gets from the TUN within a while after.
+async fn held_then_discovered(queue: UnknownPeerQueueSettings, held: &[Bytes], wait: Duration) -> (Vec<Bytes>, u64, u64) {
+    let right_port = free_port();
+    let mut right = SettingsFileBuilder::new(right_ip()).add_send_device(device(right_port)).build().unwrap();
+    right.unknown_peer_queue = Some(queue);
+    let right = Running::start(right);
+    for packet in held {
+        right.send(packet.clone());
+    }
+    tokio::time::sleep(wait).await;
+
+    let left = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), right_port, right_ip())
+        .build()
+        .unwrap();
+    let mut left = Running::start(left);
+    left.send(udp_packet(left_ip(), right_ip(), b"hello"));
+    let delivered = left.drain(Duration::from_millis(300)).await;
+
+    let handle = right.tunnel.handle();
+    let stats = handle.stats();
+    let counts = (stats.tun_held.load(Ordering::Relaxed), stats.tun_held_released.load(Ordering::Relaxed));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+    (delivered, counts.0, counts.1)
+}
+
+fn packets(count: u8) -> Vec<Bytes> {
+    (0..count).map(|index| udp_packet(right_ip(), left_ip(), &[index])).collect()
+}
+
+#[tokio::test]
+async fn packets_for_an_unknown_peer_are_delivered_once_it_is_discovered() {
+    let held = packets(3);
+    let queue = UnknownPeerQueueSettings { max_packets: None, max_age_ms: None };
+    assert_eq!(held_then_discovered(queue, &held, Duration::from_millis(100)).await, (held, 3, 3));
+}
+
+#[tokio::test]
+async fn only_the_newest_packets_are_held() {
+    let held = packets(5);
+    let queue = UnknownPeerQueueSettings { max_packets: Some(2), max_age_ms: None };
+    assert_eq!(held_then_discovered(queue, &held, Duration::from_millis(100)).await, (held[3..].to_vec(), 5, 2));
+}
+
+#[tokio::test]
+async fn packets_held_too_long_are_dropped() {
+    let queue = UnknownPeerQueueSettings { max_packets: None, max_age_ms: Some(100) };
+    assert_eq!(held_then_discovered(queue, &packets(3), Duration::from_millis(400)).await, (vec![], 3, 0));
+}
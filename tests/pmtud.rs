@@ -0,0 +1,65 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::settings::{PmtudSettings, SettingsFileBuilder};
+use common::{device, eventually, free_port, left_ip, noise, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+
+// The largest datagram the simulated path carries
+const PATH_MTU: usize = 1300;
+
+#[tokio::test]
+async fn probing_converges_on_the_path_mtu() {
+    let peer = raw_socket();
+    let mut dev = device(free_port());
+    dev.pmtud = Some(true);
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(dev)
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    settings.pmtud = Some(PmtudSettings { probe_interval_ms: Some(20), reprobe_interval: None, search: None });
+    let tunnel = Running::start(settings);
+
+    // The peer's end of the path: larger probes are lost on the way, the rest are answered
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let path = tokio::task::spawn_blocking(move || {
+        peer.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut buf = vec![0u8; 65536];
+        let (mut largest_probe, mut largest_data) = (0, 0);
+        while !stopped.load(Ordering::Relaxed) {
+            let (len, from) = match peer.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue
+            };
+            match messages::decode_packet(&buf[..len], WireFormat::Bincode, u64::MAX) {
+                Ok(Messages::Probe(size)) => {
+                    assert_eq!(len, size as usize);
+                    largest_probe = largest_probe.max(len);
+                    if len <= PATH_MTU {
+                        peer.send_to(&messages::encode_packet(&Messages::ProbeAck(size), WireFormat::Bincode).unwrap(), from).unwrap();
+                    }
+                },
+                // Data, whole or a fragment
+                _ => largest_data = largest_data.max(len)
+            }
+        }
+        (largest_probe, largest_data)
+    });
+
+    let handle = tunnel.tunnel.handle();
+    assert!(eventually(Duration::from_secs(5), || handle.paths()[0].pmtu == Some(PATH_MTU)).await, "{:?}", handle.paths()[0].pmtu);
+    // Packets are fragmented to fit the probed size
+    tunnel.send(udp_packet(left_ip(), right_ip(), &noise(1300, 1)));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    stop.store(true, Ordering::Relaxed);
+
+    let (largest_probe, largest_data) = path.await.unwrap();
+    // The search went past the limit to find it
+    assert!(largest_probe > PATH_MTU);
+    assert!(largest_data > 0 && largest_data <= PATH_MTU, "{} byte datagram", largest_data);
+    tunnel.stop().await.unwrap();
+}
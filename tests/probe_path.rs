@@ -0,0 +1,80 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use common::{device, free_port, left_ip, pair, raw_socket, recv_message, right_ip, single, LOCALHOST};
+use mptun::clock::MockClock;
+use mptun::echo::ProbeResult;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+#[tokio::test]
+async fn the_peer_echoes_a_probe_back_over_the_link() {
+    let (left, right) = pair(|left| left, |right| right);
+    let iface = left.tunnel.handle().paths()[0].name.clone();
+
+    match left.tunnel.probe_path(&iface).await {
+        ProbeResult::Echoed { from, rtt } => {
+            assert_eq!(from, right.addr());
+            assert!(rtt < Duration::from_secs(1), "{:?}", rtt);
+        },
+        result => panic!("{:?}", result)
+    }
+    assert_eq!(left.tunnel.probe_path("nonexistent").await, ProbeResult::UnknownPath);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_tunnel_without_peers_has_nobody_to_probe() {
+    let tunnel = single(|settings| settings);
+    let iface = tunnel.tunnel.handle().paths()[0].name.clone();
+    assert_eq!(tunnel.tunnel.probe_path(&iface).await, ProbeResult::NoPeer);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_round_trip_is_timed_by_the_tunnel_clock() {
+    let clock = Arc::new(MockClock::new());
+    let peer = raw_socket();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    let tunnel = Arc::new(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    let running = tunnel.clone();
+    tokio::spawn(async move { running.run_with_tun(mptun::tun::memory_tun().0).await });
+    let iface = tunnel.handle().paths()[0].name.clone();
+    let next_echo = || loop {
+        match recv_message(&peer, Duration::from_secs(2)) {
+            Some((Messages::Echo(tag), from)) => return (tag, from),
+            Some(_) => continue,
+            None => panic!("no echo request arrived")
+        }
+    };
+
+    // Answered 30 ms later
+    let probing = tokio::spawn({
+        let (tunnel, iface) = (tunnel.clone(), iface.clone());
+        async move { tunnel.probe_path(&iface).await }
+    });
+    let (tag, from) = next_echo();
+    clock.advance(Duration::from_millis(30));
+    peer.send_to(&messages::encode_packet(&Messages::EchoReply(tag), WireFormat::Bincode).unwrap(), from).unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(2), probing).await.unwrap().unwrap();
+    assert_eq!(result, ProbeResult::Echoed { from: peer.local_addr().unwrap(), rtt: Duration::from_millis(30) });
+
+    // Not answered
+    let probing = tokio::spawn({
+        let tunnel = tunnel.clone();
+        async move { tunnel.probe_path(&iface).await }
+    });
+    next_echo();
+    clock.advance(Duration::from_secs(2));
+    let result = tokio::time::timeout(Duration::from_secs(2), probing).await.unwrap().unwrap();
+    assert_eq!(result, ProbeResult::TimedOut);
+    tunnel.shutdown();
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::time::Duration;
+use common::{device, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::messages::Messages;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_provided_socket_is_used_as_it_is() {
+    let peer = raw_socket();
+    let provided = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    provided.connect(peer.local_addr().unwrap()).unwrap();
+    let provided_addr = provided.local_addr().unwrap();
+    // The device's own port, which the tunnel must leave alone
+    let unbound_port = free_port();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(unbound_port))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    // The builder wants a device, the tunnel runs over the provided one alone
+    settings.send_devices.clear();
+    let tunnel = Running::start_tunnel(Multipathtunnel::with_sockets(settings.clone(), vec![(device(unbound_port), provided)]).unwrap());
+
+    assert_eq!(tunnel.tunnel.handle().local_addrs(), vec![provided_addr]);
+    assert!(UdpSocket::bind((LOCALHOST, unbound_port)).is_ok());
+
+    // Kept by a reload, since the settings file doesn't have it
+    tunnel.tunnel.reload(settings).await;
+    assert_eq!(tunnel.tunnel.handle().local_addrs(), vec![provided_addr]);
+
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"through the hole"));
+    loop {
+        match recv_message(&peer, Duration::from_secs(2)) {
+            Some((Messages::Packet(_), from)) => {
+                assert_eq!(from, provided_addr);
+                break
+            },
+            Some(_) => continue,
+            None => panic!("nothing reached the peer")
+        }
+    }
+    tunnel.stop().await.unwrap();
+}
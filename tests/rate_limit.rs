@@ -0,0 +1,35 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use common::{left_ip, noise, pair_settings, right_ip, udp_packet, Running};
+
+#[tokio::test]
+async fn throughput_stays_under_max_bps() {
+    // 40 KB/s, with the 64 KiB minimum burst
+    const RATE: f64 = 40_000.0;
+    const BURST: f64 = 65535.0;
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.send_devices[0].max_bps = Some(RATE as u64 * 8);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    // Offer about 100 KB/s for two seconds
+    let start = Instant::now();
+    let mut offered = 0;
+    while start.elapsed() < Duration::from_secs(2) {
+        left.send(udp_packet(left_ip(), right_ip(), &noise(1000, offered)));
+        offered += 1;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let received = right.drain(Duration::from_millis(200)).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let tx_bytes = left.tunnel.handle().paths()[0].tx_bytes as f64;
+    assert!(tx_bytes <= BURST + RATE * elapsed, "{} bytes in {:.2}s", tx_bytes, elapsed);
+    let received_bytes: usize = received.iter().map(|packet| packet.len()).sum();
+    assert!((received_bytes as f64) <= BURST + RATE * elapsed, "{} bytes in {:.2}s", received_bytes, elapsed);
+    // Limited, not stopped
+    assert!((received.len() as f64) > RATE * 1.5 / 1100.0, "{} of {} delivered", received.len(), offered);
+    assert!(received.len() < offered as usize);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use mptun::clock::MockClock;
+use mptun::multipathtunnel::Multipathtunnel;
+use common::{advance_settled, left_ip, noise, pair_settings, right_ip, udp_packet, Running};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn path_rate_follows_a_constant_send_rate() {
+    const PER_SECOND: u64 = 50;
+    let clock = Arc::new(MockClock::new());
+    let (left, right) = pair_settings(|left| left, |right| right);
+    let left = Running::start_tunnel(Multipathtunnel::with_clock(left, clock.clone()).unwrap());
+    let mut right = Running::start(right);
+    tokio::time::sleep(QUIET).await;
+    let handle = left.tunnel.handle();
+
+    // Each second of mock time carries the same number of packets
+    for second in 0..8 {
+        for i in 0..PER_SECOND {
+            left.send(udp_packet(left_ip(), right_ip(), &noise(500, (second * PER_SECOND + i) as u32)));
+        }
+        assert_eq!(right.drain(QUIET).await.len() as u64, PER_SECOND);
+        assert!(advance_settled(&clock, Duration::from_secs(1)).await);
+    }
+
+    let path = &handle.paths()[0];
+    let bytes_per_packet = path.tx_bytes as f64 / path.tx_packets as f64;
+    let rate = path.tx_rate;
+    assert!((rate.pps - PER_SECOND as f64).abs() < PER_SECOND as f64 * 0.1, "{:?}", rate);
+    let bps = PER_SECOND as f64 * bytes_per_packet * 8.0;
+    assert!((rate.bps - bps).abs() < bps * 0.1, "{:?}, expected {} bps", rate, bps);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use common::{device, eventually, free_port, left_ip, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::clock::MockClock;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::path::Health;
+use mptun::settings::{PathHealthSettings, SendDevice, SettingsFileBuilder};
+
+const RAMP: Duration = Duration::from_millis(1000);
+
+async fn lte_health_within(tunnel: &Multipathtunnel, health: Health) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while Instant::now() < deadline {
+        if tunnel.health().await.paths[1].health == health {
+            return true
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    false
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_link_that_comes_back_up_takes_a_growing_share() {
+    let peer = raw_socket();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .keep_alive_ms(20)
+        .build()
+        .unwrap();
+    settings.recovery_ramp_ms = Some(RAMP.as_millis() as u64);
+    let tunnel = Running::start(settings);
+    let lte = tunnel.tunnel.handle().local_addrs()[1];
+
+    // Answers keep-alives, those of the LTE link only while asked to, and
+    // notes when each copy of a data packet came over the LTE link
+    let (answer_lte, stop) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+    let copies: Arc<Mutex<Vec<(Instant, SocketAddr)>>> = Arc::default();
+    let responder = {
+        let (answer_lte, stop, copies) = (answer_lte.clone(), stop.clone(), copies.clone());
+        std::thread::spawn(move || {
+            peer.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+            let reply = messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap();
+            let mut buf = vec![0; 65536];
+            while !stop.load(Ordering::Relaxed) {
+                let (len, from) = match peer.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue
+                };
+                match messages::decode_packet(&buf[..len], WireFormat::Bincode, u64::MAX) {
+                    Ok(Messages::Keepalive) if from != lte || answer_lte.load(Ordering::Relaxed) => {
+                        peer.send_to(&reply, from).unwrap();
+                    },
+                    Ok(Messages::Packet(_)) => copies.lock().unwrap().push((Instant::now(), from)),
+                    _ => {}
+                }
+            }
+        })
+    };
+
+    assert!(lte_health_within(&tunnel.tunnel, Health::Down).await);
+    answer_lte.store(true, Ordering::Relaxed);
+    assert!(lte_health_within(&tunnel.tunnel, Health::Up).await);
+    let up = Instant::now();
+    copies.lock().unwrap().clear();
+    while up.elapsed() < RAMP + Duration::from_millis(300) {
+        tunnel.send(udp_packet(left_ip(), right_ip(), b"ramp"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stop.store(true, Ordering::Relaxed);
+    responder.join().unwrap();
+
+    // Copies over LTE against those over the other link, within a span after it came up
+    let share = |from: Duration, to: Duration| {
+        let copies = copies.lock().unwrap();
+        let within: Vec<_> = copies.iter().filter(|(at, _)| (up + from..up + to).contains(at)).collect();
+        let over_lte = within.iter().filter(|(_, addr)| *addr == lte).count();
+        over_lte as f64 / (within.len() - over_lte) as f64
+    };
+    let (early, late) = (share(Duration::ZERO, RAMP / 4), share(RAMP + Duration::from_millis(50), RAMP + Duration::from_millis(300)));
+    assert!(early < 0.4, "{} of the copies early on", early);
+    assert!(late > 0.9, "{} of the copies after the ramp", late);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_ramp_follows_the_tunnel_clock() {
+    const MOCK_RAMP: Duration = Duration::from_secs(10);
+    let clock = Arc::new(MockClock::new());
+    let peer = raw_socket();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .keep_alive(1)
+        .build()
+        .unwrap();
+    settings.keep_alive_timeout = Some(1);
+    settings.path_health = Some(PathHealthSettings { down_after: Some(1), up_after: Some(1), flap_transitions: None, flap_window: None });
+    settings.recovery_ramp_ms = Some(MOCK_RAMP.as_millis() as u64);
+    let tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    let lte = tunnel.tunnel.handle().local_addrs()[1];
+
+    let (answer_lte, stop) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+    let copies: Arc<Mutex<Vec<SocketAddr>>> = Arc::default();
+    let responder = {
+        let (answer_lte, stop, copies) = (answer_lte.clone(), stop.clone(), copies.clone());
+        std::thread::spawn(move || {
+            peer.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+            let reply = messages::encode_packet(&Messages::KeepaliveReply, WireFormat::Bincode).unwrap();
+            let mut buf = vec![0; 65536];
+            while !stop.load(Ordering::Relaxed) {
+                let (len, from) = match peer.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue
+                };
+                match messages::decode_packet(&buf[..len], WireFormat::Bincode, u64::MAX) {
+                    Ok(Messages::Keepalive) if from != lte || answer_lte.load(Ordering::Relaxed) => {
+                        peer.send_to(&reply, from).unwrap();
+                    },
+                    Ok(Messages::Packet(_)) => copies.lock().unwrap().push(from),
+                    _ => {}
+                }
+            }
+        })
+    };
+
+    // The LTE link misses a round of keep-alives, then answers the next one
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    clock.advance(Duration::from_secs(2));
+    assert!(lte_health_within(&tunnel.tunnel, Health::Down).await);
+    answer_lte.store(true, Ordering::Relaxed);
+    clock.advance(Duration::from_secs(1));
+    assert!(lte_health_within(&tunnel.tunnel, Health::Up).await);
+
+    // Copies over LTE of 20 packets, sent without the mock clock moving
+    let over_lte = || async {
+        copies.lock().unwrap().clear();
+        for _ in 0..20 {
+            tunnel.send(udp_packet(left_ip(), right_ip(), b"ramp"));
+        }
+        assert!(eventually(Duration::from_secs(2), || copies.lock().unwrap().iter().filter(|from| **from != lte).count() == 20).await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        copies.lock().unwrap().iter().filter(|from| **from == lte).count()
+    };
+    assert_eq!(over_lte().await, 0);
+    clock.advance(MOCK_RAMP / 2);
+    let halfway = over_lte().await;
+    assert!((8..=12).contains(&halfway), "{} of 20 copies halfway through the ramp", halfway);
+    clock.advance(MOCK_RAMP / 2);
+    assert_eq!(over_lte().await, 20);
+
+    stop.store(true, Ordering::Relaxed);
+    responder.join().unwrap();
+    tunnel.stop().await.unwrap();
+}
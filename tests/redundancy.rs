@@ -0,0 +1,33 @@
+mod common;
+
+use std::time::Duration;
+use common::{device, free_port, left_ip, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+#[tokio::test]
+async fn with_redundancy_two_each_packet_goes_over_two_of_four_links() {
+    let right_port = free_port();
+    let mut left = SettingsFileBuilder::new(left_ip())
+        .remote(LOCALHOST.into(), right_port, right_ip())
+        .redundancy(2);
+    // Loopback addresses of their own, so the devices have different names
+    for (priority, last_octet) in [(3, 1), (0, 2), (2, 3), (1, 4)] {
+        let mut link = SendDevice::new([127, 0, 0, last_octet].into(), free_port());
+        link.priority = Some(priority);
+        left = left.add_send_device(link);
+    }
+    let right = SettingsFileBuilder::new(right_ip()).add_send_device(device(right_port)).build().unwrap();
+    let (left, mut right) = (Running::start(left.build().unwrap()), Running::start(right));
+
+    for index in 0..10u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), 10);
+
+    // Without keep-alives there are no RTTs, so the two of highest priority
+    let mut sent: Vec<_> = left.tunnel.handle().paths().into_iter().map(|path| (path.name, path.tx_packets)).collect();
+    sent.sort();
+    assert_eq!(sent, [("127.0.0.1".to_string(), 0), ("127.0.0.2".to_string(), 10), ("127.0.0.3".to_string(), 0), ("127.0.0.4".to_string(), 10)]);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
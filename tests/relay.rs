@@ -0,0 +1,123 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::time::Duration;
+use common::{device, free_port, left_ip, pair_settings, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{RelayProtocol, RelaySettings};
+
+// A UDP relay on loopback for one client: datagrams from it are unwrapped
+// and forwarded to the peer their SOCKS5 header names, datagrams from
+// peers are wrapped and sent back to it. Runs until the process ends.
+fn start_relay() -> SocketAddr {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut client = None;
+        let mut buf = [0u8; 65536];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            match (client, &buf[..len]) {
+                (Some(client), _) if from != client => {
+                    let IpAddr::V4(ip) = from.ip() else { continue };
+                    let mut wrapped = vec![0, 0, 0, 1];
+                    wrapped.extend_from_slice(&ip.octets());
+                    wrapped.extend_from_slice(&from.port().to_be_bytes());
+                    wrapped.extend_from_slice(&buf[..len]);
+                    socket.send_to(&wrapped, client).unwrap();
+                },
+                (_, [0, 0, 0, 1, a, b, c, d, high, low, payload @ ..]) => {
+                    client = Some(from);
+                    let peer = SocketAddr::from(([*a, *b, *c, *d], u16::from_be_bytes([*high, *low])));
+                    socket.send_to(payload, peer).unwrap();
+                },
+                _ => {}
+            }
+        }
+    });
+    addr
+}
+
+// A SOCKS5 proxy handing out a relay from `start_relay` for each UDP ASSOCIATE
+fn start_proxy() -> SocketAddr {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], [5, 3, 0, 1]);
+            let relay = start_relay();
+            let mut reply = vec![5, 0, 0, 1, 127, 0, 0, 1];
+            reply.extend_from_slice(&relay.port().to_be_bytes());
+            stream.write_all(&reply).unwrap();
+            // The association lasts as long as the connection
+            std::thread::spawn(move || stream.read(&mut [0u8; 1]));
+        }
+    });
+    addr
+}
+
+// Packets each way between a pair whose left device goes through `relay`
+async fn delivered_through(relay: RelaySettings) {
+    // The right side only knows where the left side's packets came from
+    let (mut left, right) = pair_settings(|left| left, |right| right.insert_remote(false));
+    left.send_devices[0].relay = Some(relay);
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+
+    for index in 0..20u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 20);
+    // The right side learned the relay's address, and answers through it
+    let learned = right.tunnel.handle().clients()[&left_ip()].clone();
+    assert!(!learned.is_empty() && !learned.contains(&left.addr()), "{:?}", learned);
+    for index in 0..20u8 {
+        right.send(udp_packet(right_ip(), left_ip(), &[index]));
+    }
+    assert_eq!(left.drain(Duration::from_millis(200)).await.len(), 20);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn packets_cross_a_plain_relay_both_ways() {
+    delivered_through(RelaySettings { address: start_relay(), protocol: Some(RelayProtocol::Plain) }).await;
+}
+
+#[tokio::test]
+async fn packets_cross_a_socks5_proxy_both_ways() {
+    delivered_through(RelaySettings { address: start_proxy(), protocol: None }).await;
+}
+
+#[tokio::test]
+async fn a_reload_waiting_on_a_silent_proxy_doesnt_hold_up_traffic() {
+    // Takes the connection but never answers the greeting
+    let silent = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let (settings, right) = pair_settings(|left| left, |right| right);
+    let (left, mut right) = (Running::start(settings.clone()), Running::start(right));
+
+    let mut reloaded = settings;
+    let mut proxied = device(free_port());
+    proxied.relay = Some(RelaySettings { address: silent.local_addr().unwrap(), protocol: Some(RelayProtocol::Socks5) });
+    reloaded.send_devices.push(proxied);
+    let reloader = left.tunnel.clone();
+    let reloading = tokio::spawn(async move { reloader.reload(reloaded).await });
+
+    // The single runtime thread goes on while the handshake waits
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    left.send(udp_packet(left_ip(), right_ip(), b"meanwhile"));
+    assert!(right.recv().await.is_some());
+    assert!(!reloading.is_finished());
+
+    // Left out once the proxy times out
+    reloading.await.unwrap();
+    assert_eq!(left.tunnel.handle().paths().len(), 1);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+use common::{data_datagram, eventually, free_port, left_ip, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::SettingsFile;
+
+// The JSON settings file of a tunnel at `right_ip` with a device on each of `ports`
+fn settings_json(ports: &[u16]) -> String {
+    let devices: Vec<String> = ports.iter()
+        .map(|port| format!(r#"{{"udp_listen_addr": "127.0.0.1", "udp_listen_port": {}}}"#, port))
+        .collect();
+    format!(r#"{{"tun_ip": "{}", "remote_port": 1, "send_devices": [{}]}}"#, right_ip(), devices.join(", "))
+}
+
+// Whether a packet sent to `port` comes out of the tunnel's TUN
+async fn delivered_via(tunnel: &mut Running, port: u16) -> bool {
+    let packet = udp_packet(left_ip(), right_ip(), &port.to_be_bytes());
+    raw_socket().send_to(&data_datagram(port.into(), &packet), (LOCALHOST, port)).unwrap();
+    tunnel.recv_within(Duration::from_secs(1)).await == Some(packet)
+}
+
+#[tokio::test]
+async fn sighup_reload_starts_an_added_device() {
+    let (first, added) = (free_port(), free_port());
+    let path = std::env::temp_dir().join(format!("mptun-reload-{}.json", std::process::id()));
+    std::fs::write(&path, settings_json(&[first])).unwrap();
+
+    let mut tunnel = Running::start(SettingsFile::load(&path).unwrap());
+    let reloader = tunnel.tunnel.clone();
+    let reload_path = path.clone();
+    let reloading = tokio::spawn(async move { reloader.reload_on_sighup(reload_path).await });
+    // Let the handler install before the signal is raised
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    std::fs::write(&path, settings_json(&[first, added])).unwrap();
+    assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+
+    let handle = tunnel.tunnel.handle();
+    assert!(eventually(Duration::from_secs(2), || handle.paths().len() == 2).await);
+    let local_addrs: Vec<SocketAddr> = handle.paths().iter().map(|path| path.local_addr).collect();
+    assert!(local_addrs.contains(&(LOCALHOST, added).into()), "{:?}", local_addrs);
+    // Its receive task runs, and the original device is untouched
+    assert!(delivered_via(&mut tunnel, added).await);
+    assert!(delivered_via(&mut tunnel, first).await);
+
+    reloading.abort();
+    std::fs::remove_file(&path).unwrap();
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn reload_removes_a_device_and_frees_its_port() {
+    let (first, extra) = (free_port(), free_port());
+    let mut tunnel = Running::start(serde_json::from_str(&settings_json(&[first, extra])).unwrap());
+    assert!(delivered_via(&mut tunnel, extra).await);
+
+    tunnel.tunnel.reload(serde_json::from_str(&settings_json(&[first])).unwrap()).await;
+
+    assert_eq!(tunnel.tunnel.handle().paths().len(), 1);
+    assert!(eventually(Duration::from_secs(1), || UdpSocket::bind((LOCALHOST, extra)).is_ok()).await);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_device_waiting_for_its_interface_doesnt_hold_up_traffic() {
+    let first = free_port();
+    let mut tunnel = Running::start(serde_json::from_str(&settings_json(&[first])).unwrap());
+    let waiting = format!(r#"{{"udp_listen_addr": "127.0.0.1", "udp_listen_port": {}, "udp_iface": "mptun-absent0", "bind_timeout": 1}}"#, free_port());
+    let settings = format!(r#"{{"tun_ip": "{}", "remote_port": 1, "send_devices": [{{"udp_listen_addr": "127.0.0.1", "udp_listen_port": {}}}, {}]}}"#, right_ip(), first, waiting);
+
+    let reloader = tunnel.tunnel.clone();
+    let reloading = tokio::spawn(async move { reloader.reload(serde_json::from_str(&settings).unwrap()).await });
+    // The single runtime thread isn't blocked while the device waits
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(delivered_via(&mut tunnel, first).await);
+    assert!(!reloading.is_finished());
+
+    reloading.await.unwrap();
+    assert_eq!(tunnel.tunnel.handle().paths().len(), 1);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::time::Duration;
+use mptun::messages::Messages;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+use common::{device, free_port, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_configured_remote_is_a_peer_from_the_start() {
+    let links: Vec<_> = (0..3).map(|_| raw_socket()).collect();
+    let addrs: Vec<_> = links.iter().map(|link| link.local_addr().unwrap()).collect();
+    let settings = SettingsFileBuilder::new([10, 0, 0, 1].into())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), addrs[0].port(), right_ip())
+        .add_remote_addr(addrs[1])
+        .add_remote_addr(addrs[2])
+        .build()
+        .unwrap();
+    let tunnel = Multipathtunnel::new(settings).unwrap();
+
+    let mut clients = tunnel.handle().clients().remove(&right_ip()).unwrap();
+    clients.sort();
+    let mut expected = addrs.clone();
+    expected.sort();
+    assert_eq!(clients, expected);
+
+    // Nothing was heard from the remote, yet each of its links gets the packet
+    let tunnel = Running::start_tunnel(tunnel);
+    tunnel.send(udp_packet([10, 0, 0, 1].into(), right_ip(), b"first"));
+    for link in links {
+        let received = tokio::task::spawn_blocking(move || recv_message(&link, Duration::from_secs(2))).await.unwrap();
+        assert!(matches!(received, Some((Messages::Packet(_), _))), "{:?}", received);
+    }
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use mptun::settings::ReorderSettings;
+use common::{eventually, left_ip, pair_settings, right_ip, udp_packet, Running};
+
+#[tokio::test]
+async fn removing_a_peer_frees_its_state() {
+    let (left, mut right) = pair_settings(|left| left, |right| right.reorder(ReorderSettings { max_packets: Some(8), timeout_ms: Some(50) }));
+    // Also tracks each peer's address in recv_udp
+    right.address_change_packets = Some(3);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    let handle = right.tunnel.handle();
+    let peer_states = || handle.stats().peer_states.load(Ordering::Relaxed);
+
+    left.send(udp_packet(left_ip(), right_ip(), b"hello"));
+    assert!(right.recv().await.is_some());
+    let held = peer_states();
+    assert!(held > 0);
+
+    assert!(right.tunnel.remove_peer(left_ip()));
+    assert!(eventually(Duration::from_secs(1), || peer_states() == 0).await, "{} entries left", peer_states());
+    assert!(!handle.clients().contains_key(&left_ip()));
+    assert!(!right.tunnel.remove_peer(left_ip()));
+
+    // A peer that sends again is learned anew
+    left.send(udp_packet(left_ip(), right_ip(), b"again"));
+    assert!(right.recv().await.is_some());
+    assert_eq!(peer_states(), held);
+    assert!(handle.clients().contains_key(&left_ip()));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, left_ip, raw_socket, right_ip, single, udp_packet};
+use mptun::settings::ReorderSettings;
+
+fn payload_of(packet: &[u8]) -> u16 {
+    u16::from_be_bytes([packet[packet.len() - 2], packet[packet.len() - 1]])
+}
+
+#[tokio::test]
+async fn permanently_lost_packet_is_skipped_once_the_buffer_is_full() {
+    // A timeout long enough that only the cap can skip the gap
+    let mut tunnel = single(|settings| settings.reorder(ReorderSettings { max_packets: Some(8), timeout_ms: Some(60_000) }));
+    let (peer, addr) = (raw_socket(), tunnel.addr());
+    let send = |seq: u16| {
+        let packet = udp_packet(left_ip(), right_ip(), &seq.to_be_bytes());
+        peer.send_to(&data_datagram(seq.into(), &packet), addr).unwrap();
+    };
+
+    send(1);
+    assert_eq!(tunnel.recv().await.map(|packet| payload_of(&packet)), Some(1));
+    // 2 never comes
+    for seq in 3..=8 {
+        send(seq);
+    }
+    assert_eq!(tunnel.recv_within(Duration::from_millis(200)).await, None);
+    let handle = tunnel.tunnel.handle();
+    assert_eq!(handle.stats().reorder_depth.load(Ordering::Relaxed), 6);
+
+    for seq in 9..=200 {
+        send(seq);
+    }
+    let released: Vec<u16> = tunnel.drain(Duration::from_millis(300)).await.iter().map(|packet| payload_of(packet)).collect();
+    assert_eq!(released, (3..=200).collect::<Vec<_>>());
+    assert_eq!(handle.stats().reorder_overflows.load(Ordering::Relaxed), 1);
+    assert_eq!(handle.stats().reorder_depth.load(Ordering::Relaxed), 0);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use common::{advance_settled, device, eventually, free_port, left_ip, right_ip, Running, LOCALHOST};
+use mptun::clock::MockClock;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+fn remote_addrs(tunnel: &Running) -> Vec<SocketAddr> {
+    tunnel.tunnel.handle().clients().remove(&right_ip()).unwrap_or_default()
+}
+
+// A tunnel with the remote at `host`, re-resolved every 10 s of `clock` time
+fn start(host: &str, port: u16, clock: Arc<MockClock>) -> Running {
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), port, right_ip())
+        .remote_host(host)
+        .build()
+        .unwrap();
+    settings.remote_resolve_interval = Some(10);
+    Running::start_tunnel(Multipathtunnel::with_clock(settings, clock).unwrap())
+}
+
+#[tokio::test]
+async fn remote_follows_the_address_its_host_resolves_to() {
+    let clock = Arc::new(MockClock::new());
+    let port = free_port();
+    let tunnel = start("127.0.0.1", port, clock.clone());
+    // Let the tunnel start counting the interval from now
+    tokio::time::sleep(QUIET).await;
+    let resolved: SocketAddr = (LOCALHOST, port).into();
+    assert_eq!(remote_addrs(&tunnel), vec![resolved]);
+
+    // As if the host had resolved elsewhere before
+    let moved: SocketAddr = (LOCALHOST, free_port()).into();
+    assert!(tunnel.tunnel.update_remote_addr(right_ip(), moved));
+    assert!(!tunnel.tunnel.update_remote_addr(right_ip(), moved));
+    assert_eq!(remote_addrs(&tunnel), vec![moved]);
+
+    // Re-resolved only once the interval passed
+    assert!(advance_settled(&clock, Duration::from_secs(9)).await);
+    assert_eq!(remote_addrs(&tunnel), vec![moved]);
+    clock.advance(Duration::from_secs(1));
+    assert!(eventually(Duration::from_secs(2), || remote_addrs(&tunnel) == vec![resolved]).await);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn failed_resolution_keeps_the_last_address() {
+    let clock = Arc::new(MockClock::new());
+    let port = free_port();
+    let tunnel = start("mptun-test.invalid", port, clock.clone());
+    tokio::time::sleep(QUIET).await;
+    // Falls back to remote_addr at startup
+    let addr: SocketAddr = (LOCALHOST, port).into();
+    assert_eq!(remote_addrs(&tunnel), vec![addr]);
+
+    for _ in 0..3 {
+        assert!(advance_settled(&clock, Duration::from_secs(10)).await);
+    }
+    assert_eq!(remote_addrs(&tunnel), vec![addr]);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn listed_remote_addrs_stay_when_the_host_moves() {
+    let port = free_port();
+    let listed: SocketAddr = (LOCALHOST, port).into();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), port, right_ip())
+        .remote_host("127.0.0.1")
+        .add_remote_addr(listed)
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+
+    let moved: SocketAddr = (LOCALHOST, free_port()).into();
+    assert!(tunnel.tunnel.update_remote_addr(right_ip(), moved));
+    let mut addrs = remote_addrs(&tunnel);
+    addrs.sort();
+    let mut expected = vec![listed, moved];
+    expected.sort();
+    assert_eq!(addrs, expected);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_reload_resolves_the_new_host() {
+    let clock = Arc::new(MockClock::new());
+    let port = free_port();
+    let tunnel = start("mptun-test.invalid", port, clock);
+    let moved = free_port();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(tunnel.addr().port()))
+        // Only used if the host doesn't resolve
+        .remote([127, 0, 0, 9].into(), moved, right_ip())
+        .remote_host("127.0.0.1")
+        .build()
+        .unwrap();
+    settings.remote_resolve_interval = Some(10);
+    tunnel.tunnel.reload(settings).await;
+    assert_eq!(remote_addrs(&tunnel), vec![(LOCALHOST, moved).into()]);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use std::net::{SocketAddr, UdpSocket};
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running};
+use mptun::settings::SettingsFileBuilder;
+
+// Send the tunnel a packet from `left_ip` over `socket`, and wait for it on the TUN
+async fn send_from(tunnel: &mut Running, socket: &UdpSocket, seq: usize) {
+    let packet = udp_packet(left_ip(), right_ip(), &seq.to_be_bytes());
+    socket.send_to(&data_datagram(seq, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet));
+}
+
+fn peer_addrs(tunnel: &Running) -> Vec<SocketAddr> {
+    let mut addrs = tunnel.tunnel.handle().clients().remove(&left_ip()).unwrap_or_default();
+    addrs.sort();
+    addrs
+}
+
+#[tokio::test]
+async fn brief_source_change_keeps_the_stable_address_but_a_sustained_one_replaces_it() {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.address_change_packets = Some(3);
+    let mut tunnel = Running::start(settings);
+    let (stable, moved) = (raw_socket(), raw_socket());
+    let (stable_addr, moved_addr) = (stable.local_addr().unwrap(), moved.local_addr().unwrap());
+
+    send_from(&mut tunnel, &stable, 1).await;
+    assert_eq!(peer_addrs(&tunnel), vec![stable_addr]);
+
+    // A single packet from elsewhere, then back
+    send_from(&mut tunnel, &moved, 2).await;
+    assert!(peer_addrs(&tunnel).contains(&stable_addr));
+    send_from(&mut tunnel, &stable, 3).await;
+    assert_eq!(peer_addrs(&tunnel), vec![stable_addr]);
+
+    for seq in 4..7 {
+        send_from(&mut tunnel, &moved, seq).await;
+        assert!(peer_addrs(&tunnel).contains(&moved_addr));
+    }
+    assert_eq!(peer_addrs(&tunnel), vec![moved_addr]);
+    tunnel.stop().await.unwrap();
+}
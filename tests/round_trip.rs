@@ -0,0 +1,44 @@
+mod common;
+
+use common::{left_ip, pair, right_ip, udp_packet};
+use mptun::messages::WireFormat;
+
+#[tokio::test]
+async fn packet_written_into_one_tunnel_comes_out_of_the_other() {
+    let (left, mut right) = pair(|left| left, |right| right);
+
+    let packet = udp_packet(left_ip(), right_ip(), b"hello");
+    left.send(packet.clone());
+
+    assert_eq!(right.recv().await, Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn tunnels_carry_packets_both_ways() {
+    let (mut left, mut right) = pair(|left| left, |right| right);
+
+    let there = udp_packet(left_ip(), right_ip(), b"there");
+    left.send(there.clone());
+    assert_eq!(right.recv().await, Some(there));
+
+    let back = udp_packet(right_ip(), left_ip(), b"and back");
+    right.send(back.clone());
+    assert_eq!(left.recv().await, Some(back));
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn tunnels_agreeing_on_the_compact_wire_format_exchange_packets() {
+    let (left, mut right) = pair(|left| left.wire_format(WireFormat::Compact), |right| right.wire_format(WireFormat::Compact));
+
+    let packet = udp_packet(left_ip(), right_ip(), b"compact");
+    left.send(packet.clone());
+
+    assert_eq!(right.recv().await, Some(packet));
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::time::Duration;
+use common::{device, free_port, left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::error::SettingsError;
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+#[tokio::test]
+async fn a_link_with_a_quarter_fraction_copies_every_fourth_packet() {
+    let (left, right) = pair_settings(
+        |left| {
+            let mut cheap = SendDevice::new([127, 0, 0, 2].into(), free_port());
+            cheap.send_fraction = Some(0.25);
+            left.add_send_device(cheap)
+        },
+        |right| right
+    );
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    let handle = left.tunnel.handle();
+
+    for index in 0..400u32 {
+        left.send(udp_packet(left_ip(), right_ip(), &index.to_be_bytes()));
+        if index % 50 == 49 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 400);
+    assert_eq!(handle.paths().iter().map(|path| path.tx_packets).collect::<Vec<_>>(), [400, 100]);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[test]
+fn send_fractions_outside_zero_to_one_are_refused() {
+    let build = |send_fraction: f64| {
+        let mut dev = device(free_port());
+        dev.send_fraction = Some(send_fraction);
+        SettingsFileBuilder::new(left_ip()).add_send_device(dev).build()
+    };
+    assert!(build(0.25).is_ok());
+    assert!(matches!(build(1.5), Err(SettingsError::BadSendFraction(_))));
+    assert!(matches!(build(-0.1), Err(SettingsError::BadSendFraction(_))));
+}
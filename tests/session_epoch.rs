@@ -0,0 +1,121 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use bytes::Bytes;
+use common::{data_datagram, left_ip, pair_settings, raw_socket, right_ip, single, udp_packet, Running};
+use mptun::messages::{self, Messages, Packet, WireFormat};
+use mptun::seqguard::EPOCH_CONFIRMATIONS;
+
+// A data packet of session `epoch` carrying `payload`
+fn session_datagram(epoch: u32, seq: usize, payload: &[u8]) -> Vec<u8> {
+    let packet = Packet { seq, bytes: Bytes::from(lz4_flex::compress_prepend_size(payload)) };
+    messages::encode_packet(&Messages::SessionPacket(epoch, None, None, packet), WireFormat::Bincode).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_restarted_peer_is_delivered_from_its_first_packet() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    let packet = |index: u8| udp_packet(left_ip(), right_ip(), &[index]);
+
+    for seq in 1..=100 {
+        peer.send_to(&session_datagram(1, seq, &packet(seq as u8)), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 100);
+
+    // Numbered from the start again, in a new session
+    for seq in 1..=10 {
+        peer.send_to(&session_datagram(2, seq, &packet(seq as u8)), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 10);
+
+    // The old session's packets, still in flight, are left out
+    peer.send_to(&session_datagram(1, 101, &packet(101)), tunnel.addr()).unwrap();
+    assert!(tunnel.recv_within(Duration::from_millis(200)).await.is_none());
+    assert_eq!(tunnel.tunnel.handle().stats().rx_stale_epoch.load(Ordering::Relaxed), 1);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_lone_datagram_of_another_epoch_doesnt_cut_the_peer_off() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    let packet = |index: u8| udp_packet(left_ip(), right_ip(), &[index]);
+    for seq in 1..=100 {
+        peer.send_to(&session_datagram(1, seq, &packet(seq as u8)), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 100);
+
+    // Spoofed or corrupted, and not numbered like a restart
+    peer.send_to(&session_datagram(0xdead, 5000, &packet(0)), tunnel.addr()).unwrap();
+    assert!(tunnel.recv_within(Duration::from_millis(200)).await.is_none());
+    assert_eq!(tunnel.tunnel.handle().stats().rx_unconfirmed_epoch.load(Ordering::Relaxed), 1);
+
+    for seq in 101..=110 {
+        peer.send_to(&session_datagram(1, seq, &packet(seq as u8)), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 10);
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_old_session_wins_back_from_a_spoofed_restart() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    let packet = |index: u8| udp_packet(left_ip(), right_ip(), &[index]);
+    for seq in 1..=100 {
+        peer.send_to(&session_datagram(1, seq, &packet(seq as u8)), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 100);
+
+    // Numbered like a restart, so taken for one
+    peer.send_to(&session_datagram(0xdead, 0, &packet(0)), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 1);
+
+    // The real session keeps going, and is taken back after a few packets
+    for seq in 101..=150 {
+        peer.send_to(&session_datagram(1, seq, &packet(seq as u8)), tunnel.addr()).unwrap();
+    }
+    let delivered = tunnel.drain(Duration::from_millis(200)).await.len();
+    assert_eq!(delivered, 50 - (EPOCH_CONFIRMATIONS - 1));
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn without_an_epoch_a_restarted_peer_is_taken_for_copies() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    for seq in 1..=100 {
+        peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), &[seq as u8])), tunnel.addr()).unwrap();
+    }
+    assert_eq!(tunnel.drain(Duration::from_millis(200)).await.len(), 100);
+
+    for seq in 1..=10 {
+        peer.send_to(&data_datagram(seq, &udp_packet(left_ip(), right_ip(), &[seq as u8])), tunnel.addr()).unwrap();
+    }
+    assert!(tunnel.recv_within(Duration::from_millis(200)).await.is_none());
+    tunnel.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn packets_flow_right_after_a_tunnel_restarts() {
+    let (mut left_settings, mut right_settings) = pair_settings(|left| left, |right| right);
+    left_settings.session_epoch = Some(true);
+    right_settings.session_epoch = Some(true);
+    let (left, mut right) = (Running::start(left_settings.clone()), Running::start(right_settings));
+    for index in 0..100u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 100);
+
+    left.stop().await.unwrap();
+    let left = Running::start(left_settings);
+    for index in 0..10u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 10);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{left_ip, pair, right_ip, udp_packet};
+
+#[tokio::test]
+async fn a_short_tun_write_still_delivers_the_whole_packet() {
+    let (left, mut right) = pair(|left| left, |right| right);
+    right.tun.inject_short_write(10);
+
+    let packet = udp_packet(left_ip(), right_ip(), b"written in two parts");
+    left.send(packet.clone());
+    let written = right.drain(Duration::from_millis(200)).await;
+    assert_eq!(written.len(), 2);
+    assert_eq!(written.concat(), packet);
+    assert_eq!(right.tunnel.handle().stats().tun_short_writes.load(Ordering::Relaxed), 1);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{device, eventually, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+// Copies of the next packet the peer gets
+async fn copies(peer: UdpSocket) -> (UdpSocket, usize) {
+    tokio::task::spawn_blocking(move || {
+        let mut copies = 0;
+        while let Some((message, _)) = recv_message(&peer, Duration::from_millis(200)) {
+            assert!(matches!(message, Messages::Packet(_)), "{:?}", message);
+            copies += 1;
+        }
+        (peer, copies)
+    }).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_peer_that_asks_to_slow_down_gets_one_copy() {
+    let peer = raw_socket();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    let tunnel = Running::start(settings);
+
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"redundant"));
+    let (peer, redundant) = copies(peer).await;
+    assert_eq!(redundant, 2);
+
+    let slow_down = messages::encode_packet(&Messages::SlowDown(500), WireFormat::Bincode).unwrap();
+    peer.send_to(&slow_down, tunnel.addr()).unwrap();
+    assert!(eventually(Duration::from_secs(2), || tunnel.tunnel.handle().stats().slow_downs_received.load(Ordering::Relaxed) == 1).await);
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"throttled"));
+    let (peer, throttled) = copies(peer).await;
+    assert_eq!(throttled, 1);
+
+    // Until the time asked for is up
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"redundant again"));
+    assert_eq!(copies(peer).await.1, 2);
+    tunnel.stop().await.unwrap();
+}
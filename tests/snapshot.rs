@@ -0,0 +1,41 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use mptun::clock::MockClock;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SettingsFileBuilder, SnapshotSettings};
+use common::{data_datagram, device, eventually, free_port, left_ip, raw_socket, right_ip, udp_packet, Running};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_is_replaced_whole_and_shows_the_traffic() {
+    let path = std::env::temp_dir().join(format!("mptun-snapshot-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let clock = Arc::new(MockClock::new());
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.snapshot = Some(SnapshotSettings { path: path.clone(), interval: Some(5) });
+    let mut tunnel = Running::start_tunnel(Multipathtunnel::with_clock(settings, clock.clone()).unwrap());
+    tokio::time::sleep(QUIET).await;
+
+    let peer = raw_socket();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+    assert!(!path.exists());
+
+    let mut temp = path.clone().into_os_string();
+    temp.push(".tmp");
+    // The file only ever shows up whole, with the temporary file renamed away
+    for _ in 0..10 {
+        clock.advance(Duration::from_secs(5));
+        assert!(eventually(Duration::from_secs(2), || path.exists()).await);
+        assert!(!std::path::Path::new(&temp).exists());
+        let snapshot: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(snapshot["clients"][left_ip().to_string()][0], peer.local_addr().unwrap().to_string());
+        assert_eq!(snapshot["paths"][0]["rx_packets"], 1);
+        assert!(snapshot["stats"].is_object());
+        std::fs::remove_file(&path).unwrap();
+    }
+    tunnel.stop().await.unwrap();
+}
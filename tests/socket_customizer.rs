@@ -0,0 +1,52 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use common::{device, free_port, right_ip};
+use mptun::error::TunnelError;
+use mptun::multipathtunnel::{Multipathtunnel, SocketCustomizer};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+fn two_devices() -> mptun::settings::SettingsFile {
+    SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(free_port()))
+        .add_send_device(SendDevice::new([127, 0, 0, 2].into(), free_port()))
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_customizer_sees_every_socket_before_it_is_bound() {
+    let seen: Arc<Mutex<Vec<(String, u16)>>> = Arc::default();
+    let customizer: SocketCustomizer = {
+        let seen = seen.clone();
+        Arc::new(move |socket, device| {
+            let bound_port = socket.local_addr()?.as_socket().map_or(0, |addr| addr.port());
+            seen.lock().unwrap().push((device.name(), bound_port));
+            socket.set_broadcast(true)
+        })
+    };
+    let tunnel = Multipathtunnel::with_socket_customizer(two_devices(), customizer).unwrap();
+
+    assert_eq!(*seen.lock().unwrap(), [("127.0.0.1".to_string(), 0), ("127.0.0.2".to_string(), 0)]);
+    // Each is bound afterwards
+    for addr in tunnel.handle().local_addrs() {
+        assert_ne!(addr.port(), 0);
+    }
+}
+
+#[tokio::test]
+async fn a_failing_customizer_leaves_the_device_out() {
+    let customizer: SocketCustomizer = Arc::new(|_, device| match device.udp_listen_addr.to_string().as_str() {
+        "127.0.0.2" => Err(std::io::Error::other("no filter for this one")),
+        _ => Ok(())
+    });
+    let tunnel = Multipathtunnel::with_socket_customizer(two_devices(), customizer).unwrap();
+    let addrs = tunnel.handle().local_addrs();
+    assert_eq!(addrs.len(), 1);
+    assert_eq!(addrs[0].ip(), std::net::IpAddr::from([127, 0, 0, 1]));
+
+    let refuse_all: SocketCustomizer = Arc::new(|_, _| Err(std::io::Error::other("no")));
+    let err = Multipathtunnel::with_socket_customizer(two_devices(), refuse_all).err().unwrap();
+    assert!(matches!(err, TunnelError::NoDeviceBound(_)), "{:?}", err);
+    assert!(err.to_string().contains("socket customizer failed"), "{}", err);
+}
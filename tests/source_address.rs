@@ -0,0 +1,24 @@
+mod common;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use common::{free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_device_sends_from_its_listen_address_rather_than_the_interfaces_first() {
+    let source: IpAddr = [127, 0, 0, 5].into();
+    let peer = raw_socket();
+    let mut device = SendDevice::new(source, free_port());
+    device.udp_iface = Some("lo".to_string());
+    let tunnel = Running::start(SettingsFileBuilder::new(left_ip())
+        .add_send_device(device)
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap());
+
+    tunnel.send(udp_packet(left_ip(), right_ip(), b"hi"));
+    let (_, from) = recv_message(&peer, Duration::from_secs(2)).expect("nothing arrived");
+    assert_eq!(from.ip(), source);
+    tunnel.stop().await.unwrap();
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use common::{device, free_port, left_ip, right_ip, LOCALHOST};
+use mptun::error::{SettingsError, TunnelError};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{EncryptionSettings, SettingsFileBuilder};
+
+fn builder(port: u16) -> SettingsFileBuilder {
+    SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(port))
+        .remote(LOCALHOST.into(), free_port(), right_ip())
+}
+
+#[test]
+fn a_taken_port_is_reported_with_the_device_and_its_cause() {
+    let taken = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let err = Multipathtunnel::new(builder(port).build().unwrap()).err().expect("bound a taken port");
+
+    match &err {
+        TunnelError::NoDeviceBound(errors) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].kind(), ErrorKind::AddrInUse);
+        },
+        other => panic!("expected no device to be bound, got {:?}", other)
+    }
+    let message = err.to_string();
+    assert!(message.starts_with("no send device could be bound"), "{}", message);
+    assert!(message.contains(&device(port).name()), "{}", message);
+}
+
+#[test]
+fn no_devices_is_a_settings_error_with_it_as_the_source() {
+    let mut settings = builder(free_port()).build().unwrap();
+    settings.send_devices.clear();
+    let err = Multipathtunnel::new(settings).err().expect("started without send devices");
+
+    assert!(matches!(err, TunnelError::Settings(SettingsError::NoSendDevices)), "{:?}", err);
+    let source = err.source().expect("no source");
+    assert_eq!(source.to_string(), SettingsError::NoSendDevices.to_string());
+}
+
+#[test]
+fn bad_keys_are_typed_errors() {
+    let settings = builder(free_port()).encryption_key("not hex").build().unwrap();
+    assert!(matches!(Multipathtunnel::new(settings), Err(TunnelError::InvalidKey(_))));
+
+    let missing = std::env::temp_dir().join(format!("mptun-missing-key-{}", free_port()));
+    let mut settings = builder(free_port()).build().unwrap();
+    settings.encryption = Some(EncryptionSettings { key: None, key_file: Some(missing.clone()) });
+    match Multipathtunnel::new(settings) {
+        Err(TunnelError::KeyFile { path, source }) => {
+            assert_eq!(path, missing);
+            assert_eq!(source.kind(), ErrorKind::NotFound);
+        },
+        other => panic!("expected a key file error, got {:?}", other.err())
+    }
+}
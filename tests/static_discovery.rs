@@ -0,0 +1,32 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use common::{data_datagram, device, free_port, left_ip, raw_socket, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{DiscoveryMode, SettingsFileBuilder};
+
+#[tokio::test]
+async fn static_discovery_never_learns_sources() {
+    let (port, configured) = (free_port(), free_port());
+    let mut settings = SettingsFileBuilder::new(right_ip())
+        .add_send_device(device(port))
+        .remote(LOCALHOST.into(), configured, left_ip())
+        .build()
+        .unwrap();
+    settings.discovery = Some(DiscoveryMode::Static);
+    let mut tunnel = Running::start(settings);
+    let expected: HashMap<IpAddr, Vec<SocketAddr>> = HashMap::from([(left_ip(), vec![(LOCALHOST, configured).into()])]);
+    assert_eq!(tunnel.tunnel.handle().clients(), expected);
+
+    // An unknown peer, and the configured one from an address it wasn't configured at
+    let stranger: IpAddr = [10, 0, 0, 3].into();
+    for (seq, source) in [(1, stranger), (2, left_ip())] {
+        let packet = udp_packet(source, right_ip(), b"hello");
+        raw_socket().send_to(&data_datagram(seq, &packet), (LOCALHOST, port)).unwrap();
+        // Delivered all the same
+        assert_eq!(tunnel.recv().await, Some(packet));
+    }
+    assert_eq!(tunnel.tunnel.handle().clients(), expected);
+
+    tunnel.stop().await.unwrap();
+}
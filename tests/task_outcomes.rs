@@ -0,0 +1,51 @@
+mod common;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use common::{device, free_port, right_ip};
+use mptun::error::{TaskOutcome, TunnelError};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+
+// A TUN device whose reads panic
+struct PanickingTun;
+
+impl AsyncRead for PanickingTun {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        panic!("TUN read exploded")
+    }
+}
+
+impl AsyncWrite for PanickingTun {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn panicking_task_is_reported_instead_of_propagating() {
+    let settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    let tunnel = Multipathtunnel::new(settings).unwrap();
+
+    let reports = match tunnel.run_with_tun(PanickingTun).await {
+        Err(TunnelError::TasksFailed(reports)) => reports,
+        other => panic!("expected failed tasks, got {:?}", other)
+    };
+
+    let panicked: Vec<_> = reports.iter().filter(|report| matches!(report.outcome, TaskOutcome::Panicked(_))).collect();
+    assert_eq!(panicked.len(), 1, "{:?}", reports);
+    assert_eq!(panicked[0].outcome, TaskOutcome::Panicked("TUN read exploded".to_string()));
+    // The others were stopped and reported too
+    assert!(reports.len() > 1);
+    assert!(reports.iter().any(|report| report.outcome == TaskOutcome::Cancelled), "{:?}", reports);
+    assert!(TunnelError::TasksFailed(reports.clone()).to_string().contains(&format!("{}: panicked: TUN read exploded", panicked[0].task)));
+}
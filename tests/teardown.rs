@@ -0,0 +1,76 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use common::{device, eventually, free_port, left_ip, right_ip, udp_packet, LOCALHOST};
+use mptun::error::TunnelError;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SettingsFile, SettingsFileBuilder};
+use mptun::tun::{MemoryTun, TunFactory};
+
+fn settings(ports: &[u16]) -> SettingsFile {
+    let mut builder = SettingsFileBuilder::new(left_ip()).remote(LOCALHOST.into(), free_port(), right_ip());
+    for port in ports {
+        builder = builder.add_send_device(device(*port));
+    }
+    builder.build().unwrap()
+}
+
+fn alive_tasks() -> usize {
+    Handle::current().metrics().num_alive_tasks()
+}
+
+// Fails to create the TUN device, counting the attempts
+#[derive(Default)]
+struct FailingTun {
+    attempts: AtomicUsize
+}
+
+impl TunFactory for FailingTun {
+    type Device = MemoryTun;
+
+    fn create(&self, _settings: &SettingsFile, _mtu: usize) -> Result<MemoryTun, Box<dyn std::error::Error + Send + Sync>> {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        Err("no TUN for this test".into())
+    }
+}
+
+#[tokio::test]
+async fn a_bind_failure_leaves_no_tun_or_tasks_behind() {
+    let taken = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    let before = alive_tasks();
+    // The TUN device is only created by running a tunnel that has been set up
+    let result = Multipathtunnel::new(settings(&[taken.local_addr().unwrap().port()]));
+    assert!(matches!(result, Err(TunnelError::NoDeviceBound(_))), "{:?}", result.err());
+    assert_eq!(alive_tasks(), before);
+}
+
+#[tokio::test]
+async fn a_tun_failure_starts_no_tasks_and_keeps_the_tunnel_runnable() {
+    let before = alive_tasks();
+    let tunnel = Multipathtunnel::new(settings(&[free_port()])).unwrap();
+    let factory = FailingTun::default();
+    assert!(matches!(tunnel.run_with(&factory).await, Err(TunnelError::Tun(_))));
+    assert_eq!(factory.attempts.load(Ordering::Relaxed), 1);
+    assert_eq!(alive_tasks(), before);
+
+    let (_tun, run) = tunnel.run_with_channels();
+    tunnel.shutdown();
+    assert!(run.await.is_ok());
+}
+
+#[tokio::test]
+async fn a_run_dropped_part_way_leaves_no_tun_or_tasks_behind() {
+    let before = alive_tasks();
+    let tunnel = Multipathtunnel::new(settings(&[free_port(), free_port()])).unwrap();
+    let (mut tun, run) = tunnel.run_with_channels();
+    // Long enough for every task to be started
+    assert!(tokio::time::timeout(Duration::from_millis(100), run).await.is_err());
+
+    assert!(eventually(Duration::from_secs(1), || alive_tasks() == before).await, "{} tasks left of {}", alive_tasks(), before);
+    // Nothing holds the TUN anymore
+    assert_eq!(tun.from_tunnel.recv().await, None);
+    assert!(tun.to_tunnel.send(udp_packet(left_ip(), right_ip(), b"too late")).is_err());
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use std::time::Duration;
+use common::{device, free_port, left_ip, noise, right_ip, udp_packet, Running, LOCALHOST};
+use mptun::settings::{SendDevice, SettingsFileBuilder};
+
+const PATHS: u8 = 3;
+const PACKETS: u32 = 50;
+
+#[tokio::test]
+async fn redundant_mode_puts_a_copy_per_path_on_the_wire() {
+    let right_port = free_port();
+    let mut left = SettingsFileBuilder::new(left_ip()).remote(LOCALHOST.into(), right_port, right_ip());
+    for last_octet in 1..=PATHS {
+        left = left.add_send_device(SendDevice::new([127, 0, 0, last_octet].into(), free_port()));
+    }
+    let right = SettingsFileBuilder::new(right_ip()).add_send_device(device(right_port)).build().unwrap();
+    let (left, mut right) = (Running::start(left.build().unwrap()), Running::start(right));
+
+    let mut sent = 0;
+    for seed in 0..PACKETS {
+        let packet = udp_packet(left_ip(), right_ip(), &noise(1000, seed));
+        sent += packet.len() as u64;
+        left.send(packet);
+        // So no copy is lost to the receive buffer
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    assert_eq!(right.drain(Duration::from_millis(300)).await.len(), PACKETS as usize);
+
+    // Noise doesn't compress, so only the framing adds to the copies
+    let expected = PATHS as f64;
+    let tx = left.tunnel.handle().throughput();
+    assert_eq!(tx.tx_goodput_bytes, sent);
+    let tx_overhead = tx.tx_overhead.unwrap();
+    assert!(tx_overhead >= expected && tx_overhead < expected * 1.1, "{}", tx_overhead);
+    let rx = right.tunnel.handle().throughput();
+    assert_eq!(rx.rx_goodput_bytes, sent);
+    let rx_overhead = rx.rx_overhead.unwrap();
+    assert!(rx_overhead >= expected && rx_overhead < expected * 1.1, "{}", rx_overhead);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
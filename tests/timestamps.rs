@@ -0,0 +1,28 @@
+mod common;
+
+use std::time::Duration;
+use common::{left_ip, pair_settings, right_ip, udp_packet, Running};
+
+#[tokio::test]
+async fn timestamped_packets_give_delay_and_jitter_per_path() {
+    let (mut left, mut right) = pair_settings(|left| left, |right| right);
+    left.timestamps = Some(true);
+    right.timestamps = Some(true);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    for index in 0..5u8 {
+        left.send(udp_packet(left_ip(), right_ip(), &[index]));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 5);
+    let path = right.tunnel.handle().paths().remove(0);
+    // Both ends share a clock, so loopback transit is small and positive
+    let delay = path.one_way_delay_us.unwrap();
+    assert!((0..100_000).contains(&delay), "{}", delay);
+    assert!(path.jitter.unwrap() < Duration::from_millis(100));
+    // Nothing timestamped went the other way
+    assert_eq!(left.tunnel.handle().paths()[0].one_way_delay_us, None);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
@@ -0,0 +1,20 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{left_ip, pair_settings, right_ip, udp_packet_with_ttl, Running};
+
+#[tokio::test]
+async fn packets_are_forwarded_with_one_hop_less_until_none_are_left() {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.decrement_ttl = Some(true);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    left.send(udp_packet_with_ttl(left_ip(), right_ip(), 1, b"looping"));
+    left.send(udp_packet_with_ttl(left_ip(), right_ip(), 10, b"forwarded"));
+    assert_eq!(right.drain(Duration::from_millis(200)).await, vec![udp_packet_with_ttl(left_ip(), right_ip(), 9, b"forwarded")]);
+    assert_eq!(left.tunnel.handle().stats().tx_ttl_expired.load(Ordering::Relaxed), 1);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
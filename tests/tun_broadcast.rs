@@ -0,0 +1,46 @@
+mod common;
+
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::{SettingsFile, SettingsFileBuilder, TunBroadcast};
+use mptun::tun::{memory_tun, MemoryTun, MemoryTunPeer, TunFactory};
+use common::{device, free_port, right_ip};
+
+// Hands out a MemoryTun, keeping the broadcast address the TUN was asked to get
+#[derive(Default)]
+struct RecordingTun {
+    broadcast: Mutex<Option<Option<Ipv4Addr>>>,
+    peer: Mutex<Option<MemoryTunPeer>>
+}
+
+impl TunFactory for RecordingTun {
+    type Device = MemoryTun;
+
+    fn create(&self, settings: &SettingsFile, _mtu: usize) -> Result<MemoryTun, Box<dyn std::error::Error + Send + Sync>> {
+        *self.broadcast.lock().unwrap() = Some(settings.tun_broadcast_addr());
+        let (tun, peer) = memory_tun();
+        *self.peer.lock().unwrap() = Some(peer);
+        Ok(tun)
+    }
+}
+
+// The broadcast address the TUN is created with, with `tun_broadcast` as given in JSON
+async fn created_with(tun_broadcast: Option<serde_json::Value>) -> Option<Option<Ipv4Addr>> {
+    let mut settings = SettingsFileBuilder::new(right_ip()).add_send_device(device(free_port())).build().unwrap();
+    settings.tun_broadcast = tun_broadcast.map(|json| serde_json::from_value::<TunBroadcast>(json).unwrap());
+    let tunnel = Multipathtunnel::new(settings).unwrap();
+    let factory = RecordingTun::default();
+    tunnel.shutdown();
+    tunnel.run_with(&factory).await.unwrap();
+    let broadcast = *factory.broadcast.lock().unwrap();
+    broadcast
+}
+
+#[tokio::test]
+async fn tun_is_created_with_the_configured_broadcast_address() {
+    assert_eq!(created_with(Some(serde_json::json!({ "Address": "10.0.0.255" }))).await, Some(Some(Ipv4Addr::new(10, 0, 0, 255))));
+    // A point-to-point tunnel without one
+    assert_eq!(created_with(Some(serde_json::json!("None"))).await, Some(None));
+    assert_eq!(created_with(None).await, Some(Some(Ipv4Addr::BROADCAST)));
+}
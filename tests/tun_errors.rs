@@ -0,0 +1,33 @@
+mod common;
+
+use std::io;
+use std::time::Duration;
+use mptun::events::Event;
+use common::{left_ip, pair, right_ip, udp_packet};
+
+#[tokio::test]
+async fn failing_tun_shuts_the_tunnel_down_cleanly() {
+    let (left, mut right) = pair(|left| left, |right| right);
+    let mut events = left.tunnel.subscribe_events();
+
+    // Interrupted reads are retried
+    left.tun.inject_read_error(io::ErrorKind::Interrupted);
+    let packet = udp_packet(left_ip(), right_ip(), b"still up");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+
+    left.tun.inject_read_error(io::ErrorKind::BrokenPipe);
+    let result = tokio::time::timeout(Duration::from_secs(2), left.run).await
+        .expect("the tunnel kept running")
+        .expect("the tunnel panicked");
+    assert!(result.is_ok(), "{:?}", result);
+    let down = loop {
+        match events.try_recv() {
+            Ok(Event::TunDown { error }) => break error,
+            Ok(_) => continue,
+            Err(err) => panic!("no TunDown event: {}", err)
+        }
+    };
+    assert_eq!(down, io::Error::from(io::ErrorKind::BrokenPipe).to_string());
+    right.stop().await.unwrap();
+}
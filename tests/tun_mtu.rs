@@ -0,0 +1,111 @@
+mod common;
+
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::sync::oneshot;
+use common::{data_datagram, device, free_port, left_ip, raw_socket, recv_message, right_ip, single, udp_packet, LOCALHOST};
+use mptun::events::Event;
+use mptun::messages::{self, Messages, WireFormat};
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+use mptun::tun::MemoryTunPeer;
+
+// A tunnel at `left_ip` run with channels, whose MTU follows `set_mtu`, with
+// a raw socket as its peer
+async fn start() -> (Arc<Multipathtunnel>, MemoryTunPeer, UdpSocket) {
+    let peer = raw_socket();
+    let mut settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .build()
+        .unwrap();
+    settings.max_payload_len = Some(2000);
+    let tunnel = Arc::new(Multipathtunnel::new(settings).unwrap());
+    let (channels_tx, channels_rx) = oneshot::channel();
+    let running = tunnel.clone();
+    tokio::spawn(async move {
+        let (channels, run) = running.run_with_channels();
+        let _ = channels_tx.send(channels);
+        run.await
+    });
+    (tunnel, channels_rx.await.unwrap(), peer)
+}
+
+// The next MTU announcement the peer receives, skipping keep-alives
+fn next_announcement(peer: &UdpSocket) -> u32 {
+    loop {
+        match recv_message(peer, Duration::from_secs(3)) {
+            Some((Messages::Mtu(mtu), _)) => return mtu,
+            Some(_) => continue,
+            None => panic!("no MTU announcement arrived")
+        }
+    }
+}
+
+// The inner packets of the data messages the peer receives until it's quiet
+fn received_packets(peer: &UdpSocket) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| recv_message(peer, Duration::from_millis(300)))
+        .filter_map(|(message, _)| match message {
+            Messages::Packet(packet) => Some(lz4_flex::decompress_size_prepended(&packet.bytes).unwrap()),
+            _ => None
+        })
+        .collect()
+}
+
+// A UDP packet of `len` bytes with DF cleared, so it may be fragmented
+fn fragmentable(source: IpAddr, destination: IpAddr, len: usize) -> Bytes {
+    let mut packet = udp_packet(source, destination, &vec![7; len - 28]).to_vec();
+    packet[6] &= !0x40;
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let checksum = mptun::ipfrag::header_checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    Bytes::from(packet)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_changed_tun_mtu_is_followed_and_announced() {
+    let (tunnel, channels, peer) = start().await;
+    let mut events = tunnel.subscribe_events();
+
+    channels.to_tunnel.send(fragmentable(left_ip(), right_ip(), 1200)).unwrap();
+    assert_eq!(received_packets(&peer).iter().map(Vec::len).collect::<Vec<_>>(), [1200]);
+
+    channels.set_mtu(1000);
+    assert_eq!(next_announcement(&peer), 1000);
+    let changed = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            if let Ok(Event::TunMtuChanged { mtu }) = events.recv().await {
+                return mtu
+            }
+        }
+    }).await.unwrap();
+    assert_eq!(changed, 1000);
+    // Packets read from the TUN are only sent whole up to the new MTU
+    channels.to_tunnel.send(fragmentable(left_ip(), right_ip(), 1200)).unwrap();
+    let fragments = received_packets(&peer);
+    assert!(fragments.len() > 1 && fragments.iter().all(|fragment| fragment.len() <= 1000), "{:?}", fragments.iter().map(Vec::len).collect::<Vec<_>>());
+
+    // Peers are never told more than the receive buffers were sized for
+    channels.set_mtu(9000);
+    assert_eq!(next_announcement(&peer), 2000);
+    tunnel.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn packets_for_a_peer_fit_the_mtu_it_announced() {
+    let mut tunnel = single(|settings| settings);
+    let peer = raw_socket();
+    peer.send_to(&data_datagram(1, &udp_packet(left_ip(), right_ip(), b"hi")), tunnel.addr()).unwrap();
+    assert!(tunnel.recv().await.is_some());
+
+    let announcement = messages::encode_packet(&Messages::Mtu(1000), WireFormat::Bincode).unwrap();
+    peer.send_to(&announcement, tunnel.addr()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    tunnel.send(fragmentable(right_ip(), left_ip(), 1200));
+
+    let fragments = received_packets(&peer);
+    assert!(fragments.len() > 1 && fragments.iter().all(|fragment| fragment.len() <= 1000), "{:?}", fragments.iter().map(Vec::len).collect::<Vec<_>>());
+    tunnel.stop().await.unwrap();
+}
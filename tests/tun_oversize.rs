@@ -0,0 +1,78 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use bytes::Bytes;
+use common::{left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::settings::{OversizePolicy, DEFAULT_TUN_MTU};
+
+// A UDP packet that the TUN MTU can't hold, with DF cleared unless `dont_fragment`
+fn oversized_packet(dont_fragment: bool) -> Bytes {
+    let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+    let mut packet = udp_packet(left_ip(), right_ip(), &payload).to_vec();
+    assert!(packet.len() > DEFAULT_TUN_MTU);
+    if !dont_fragment {
+        packet[6] &= !0x40;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let checksum = mptun::ipfrag::header_checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+    Bytes::from(packet)
+}
+
+fn start(policy: Option<OversizePolicy>) -> (Running, Running) {
+    let (mut left, right) = pair_settings(|left| left, |right| right);
+    left.oversize_policy = policy;
+    (Running::start(left), Running::start(right))
+}
+
+#[tokio::test]
+async fn oversize_read_is_split_into_fragments() {
+    let (left, mut right) = start(None);
+    let packet = oversized_packet(false);
+    left.send(packet.clone());
+
+    let fragments = right.drain(Duration::from_millis(300)).await;
+    assert!(fragments.len() > 1, "{} fragments", fragments.len());
+    let mut payload = Vec::new();
+    for fragment in &fragments {
+        assert!(fragment.len() <= DEFAULT_TUN_MTU, "{} byte fragment", fragment.len());
+        payload.extend_from_slice(&fragment[20..]);
+    }
+    assert_eq!(payload, packet[20..]);
+
+    let handle = left.tunnel.handle();
+    let stats = handle.stats();
+    assert_eq!(stats.tun_oversized_fragmented.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.tun_oversized_dropped.load(Ordering::Relaxed), 0);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn oversize_read_with_dont_fragment_is_rejected() {
+    let (left, mut right) = start(None);
+    left.send(oversized_packet(true));
+    let fits = udp_packet(left_ip(), right_ip(), b"fits");
+    left.send(fits.clone());
+
+    // Never delivered cut down to the MTU
+    assert_eq!(right.drain(Duration::from_millis(300)).await, vec![fits]);
+    assert_eq!(left.tunnel.handle().stats().tun_oversized_dropped.load(Ordering::Relaxed), 1);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn drop_policy_rejects_fragmentable_reads() {
+    let (left, mut right) = start(Some(OversizePolicy::Drop));
+    left.send(oversized_packet(false));
+
+    assert_eq!(right.drain(Duration::from_millis(300)).await, Vec::<Bytes>::new());
+    let handle = left.tunnel.handle();
+    let stats = handle.stats();
+    assert_eq!(stats.tun_oversized_dropped.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.tun_oversized_fragmented.load(Ordering::Relaxed), 0);
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
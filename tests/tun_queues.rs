@@ -0,0 +1,64 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use common::{device, free_port, left_ip, raw_socket, recv_message, right_ip, udp_packet, LOCALHOST};
+use mptun::messages::Messages;
+use mptun::multipathtunnel::Multipathtunnel;
+use mptun::settings::SettingsFileBuilder;
+use mptun::tun::{memory_tun, MemoryTunPeer};
+
+// A tunnel reading `count` TUN queues, with a raw socket as its peer
+fn start(count: usize) -> (Arc<Multipathtunnel>, Vec<MemoryTunPeer>, std::net::UdpSocket) {
+    let peer = raw_socket();
+    let settings = SettingsFileBuilder::new(left_ip())
+        .add_send_device(device(free_port()))
+        .remote(LOCALHOST.into(), peer.local_addr().unwrap().port(), right_ip())
+        .tun_queues(count)
+        .build()
+        .unwrap();
+    let tunnel = Arc::new(Multipathtunnel::new(settings).unwrap());
+    let (tuns, queues): (Vec<_>, Vec<_>) = (0..count).map(|_| memory_tun()).unzip();
+    let running = tunnel.clone();
+    tokio::spawn(async move { running.run_with_tun_queues(tuns).await });
+    (tunnel, queues, peer)
+}
+
+// The sequence number of the next data packet the peer receives
+fn next_seq(peer: &std::net::UdpSocket) -> usize {
+    loop {
+        match recv_message(peer, Duration::from_secs(2)) {
+            Some((Messages::Packet(packet), _)) => return packet.seq,
+            Some(_) => continue,
+            None => panic!("no data packet arrived")
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn packets_read_one_after_another_are_numbered_in_order_across_queues() {
+    let (tunnel, queues, peer) = start(3);
+    let mut last = None;
+    for index in 0..60 {
+        queues[index % queues.len()].to_tunnel.send(udp_packet(left_ip(), right_ip(), &[index as u8])).unwrap();
+        let seq = next_seq(&peer);
+        assert!(last.is_none_or(|last| seq > last), "packet {} got {} after {:?}", index, seq, last);
+        last = Some(seq);
+    }
+    tunnel.shutdown();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queues_read_at_once_never_share_a_sequence_number() {
+    const PACKETS: usize = 200;
+    let (tunnel, queues, peer) = start(4);
+    for index in 0..PACKETS {
+        queues[index % queues.len()].to_tunnel.send(udp_packet(left_ip(), right_ip(), &[index as u8])).unwrap();
+    }
+
+    let mut seqs: Vec<_> = (0..PACKETS).map(|_| next_seq(&peer)).collect();
+    seqs.sort_unstable();
+    seqs.dedup();
+    assert_eq!(seqs.len(), PACKETS);
+    tunnel.shutdown();
+}
@@ -0,0 +1,25 @@
+mod common;
+
+use common::{left_ip, pair_settings, right_ip, udp_packet, Running};
+
+#[tokio::test]
+async fn tunnels_exchange_packets_over_unix_sockets() {
+    let dir = std::env::temp_dir().join(format!("mptun-unix-tunnels-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (mut left, mut right) = pair_settings(|left| left, |right| right);
+    for settings in [&mut left, &mut right] {
+        settings.send_devices[0].unix_socket_dir = Some(dir.clone());
+    }
+    let (mut left, mut right) = (Running::start(left), Running::start(right));
+
+    let packet = udp_packet(left_ip(), right_ip(), b"over a unix socket");
+    left.send(packet.clone());
+    assert_eq!(right.recv().await, Some(packet));
+    let reply = udp_packet(right_ip(), left_ip(), b"reply");
+    right.send(reply.clone());
+    assert_eq!(left.recv().await, Some(reply));
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
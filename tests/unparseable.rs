@@ -0,0 +1,40 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use bytes::Bytes;
+use common::{pair_settings, Running};
+use mptun::settings::UnparseablePolicy;
+
+// Not an IP packet: the version nibble is 0
+const GARBAGE: &[u8] = &[0x00, 0xde, 0xad, 0xbe, 0xef, 0x00, 0x01, 0x02];
+
+async fn send_garbage(policy: UnparseablePolicy) -> (u64, Vec<Bytes>) {
+    let (mut left, mut right) = pair_settings(|left| left, |right| right);
+    left.unparseable_policy = Some(policy);
+    right.unparseable_policy = Some(policy);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+
+    left.send(Bytes::from_static(GARBAGE));
+    let received = right.drain(Duration::from_millis(200)).await;
+    let counted = left.tunnel.handle().stats().tx_unparseable.load(Ordering::Relaxed);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+    (counted, received)
+}
+
+#[tokio::test]
+async fn drop_silently_neither_sends_nor_counts() {
+    assert_eq!(send_garbage(UnparseablePolicy::DropSilently).await, (0, vec![]));
+}
+
+#[tokio::test]
+async fn drop_and_count_counts_the_packet() {
+    assert_eq!(send_garbage(UnparseablePolicy::DropAndCount).await, (1, vec![]));
+}
+
+#[tokio::test]
+async fn broadcast_sends_the_packet_to_every_peer() {
+    assert_eq!(send_garbage(UnparseablePolicy::BroadcastToAllPeers).await, (0, vec![Bytes::from_static(GARBAGE)]));
+}
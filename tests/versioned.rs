@@ -0,0 +1,31 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use common::{data_datagram, left_ip, raw_socket, right_ip, single, udp_packet};
+use mptun::messages::{self, WireFormat, VERSIONED_VERSION};
+
+fn versioned_datagram(seq: usize, payload: &[u8]) -> Vec<u8> {
+    let compressed = lz4_flex::compress_prepend_size(payload);
+    let mut datagram = Vec::new();
+    messages::encode_data_into(seq, None, None, None, &compressed, WireFormat::Versioned, &mut datagram).unwrap();
+    datagram
+}
+
+#[tokio::test]
+async fn only_datagrams_of_the_same_version_are_delivered() {
+    let mut tunnel = single(|settings| settings.wire_format(WireFormat::Versioned));
+    let peer = raw_socket();
+    let packet = udp_packet(left_ip(), right_ip(), b"hello");
+
+    peer.send_to(&versioned_datagram(1, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv().await, Some(packet.clone()));
+
+    let mut newer = versioned_datagram(2, &packet);
+    newer[1] = VERSIONED_VERSION + 1;
+    peer.send_to(&newer, tunnel.addr()).unwrap();
+    peer.send_to(&data_datagram(3, &packet), tunnel.addr()).unwrap();
+    assert_eq!(tunnel.recv_within(Duration::from_millis(200)).await, None);
+    assert_eq!(tunnel.tunnel.handle().stats().rx_version_mismatch.load(Ordering::Relaxed), 2);
+    tunnel.stop().await.unwrap();
+}
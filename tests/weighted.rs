@@ -0,0 +1,48 @@
+mod common;
+
+use std::time::Duration;
+use common::{device, free_port, left_ip, pair_settings, right_ip, udp_packet, Running};
+use mptun::error::SettingsError;
+use mptun::settings::{AutoWeightSettings, PathMode, SendDevice, SettingsFileBuilder};
+
+#[tokio::test]
+async fn packets_are_spread_over_the_links_by_their_weights() {
+    let (mut left, right) = pair_settings(
+        |left| {
+            let mut light = SendDevice::new([127, 0, 0, 2].into(), free_port());
+            light.weight = Some(1.0);
+            left.add_send_device(light).path_mode(PathMode::Weighted)
+        },
+        |right| right
+    );
+    left.send_devices[0].weight = Some(3.0);
+    let (left, mut right) = (Running::start(left), Running::start(right));
+    let handle = left.tunnel.handle();
+    assert_eq!(handle.paths().iter().map(|path| path.weight).collect::<Vec<_>>(), [3.0, 1.0]);
+
+    for index in 0..400u32 {
+        left.send(udp_packet(left_ip(), right_ip(), &index.to_be_bytes()));
+        if index % 50 == 49 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+    assert_eq!(right.drain(Duration::from_millis(200)).await.len(), 400);
+    assert_eq!(handle.paths().iter().map(|path| path.tx_packets).collect::<Vec<_>>(), [300, 100]);
+
+    left.stop().await.unwrap();
+    right.stop().await.unwrap();
+}
+
+#[test]
+fn auto_weights_out_of_range_are_refused() {
+    let build = |auto_weights: AutoWeightSettings| {
+        let mut settings = SettingsFileBuilder::new(left_ip()).add_send_device(device(free_port())).build().unwrap();
+        settings.auto_weights = Some(auto_weights);
+        settings.validate()
+    };
+    let unset = AutoWeightSettings { interval_ms: None, adapt_rate: None, min_weight: None, max_weight: None };
+    assert!(build(unset.clone()).is_ok());
+    assert!(matches!(build(AutoWeightSettings { adapt_rate: Some(0.0), ..unset.clone() }), Err(SettingsError::BadWeight("auto_weights.adapt_rate"))));
+    assert!(matches!(build(AutoWeightSettings { min_weight: Some(0.0), ..unset.clone() }), Err(SettingsError::BadWeight("auto_weights.min_weight"))));
+    assert!(matches!(build(AutoWeightSettings { min_weight: Some(2.0), max_weight: Some(1.0), ..unset }), Err(SettingsError::BadWeight("auto_weights.max_weight"))));
+}